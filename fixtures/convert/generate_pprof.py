@@ -0,0 +1,125 @@
+#!/usr/bin/env python3
+"""Writes server.pb.gz, the pprof profile used by the conversion tests.
+
+The file looks like a CPU profile from Go's runtime/pprof: the sample types
+are samples/count and cpu/nanoseconds, with a period of 10ms, and each
+location has a mapping, an address and one line per function, from the
+innermost inlined function outwards. main.readLine is inlined into
+main.parse. The samples are the same as the stacks in server.collapsed and
+server.speedscope.json.
+
+The gzip header has no timestamp, so running this script again produces the
+same file.
+"""
+
+import gzip
+import os
+
+PERIOD_NS = 10_000_000
+
+
+def varint(value):
+    out = bytearray()
+    while value >= 0x80:
+        out.append((value & 0x7F) | 0x80)
+        value >>= 7
+    out.append(value)
+    return bytes(out)
+
+
+def varint_field(number, value):
+    return varint(number << 3) + varint(value)
+
+
+def bytes_field(number, data):
+    return varint((number << 3) | 2) + varint(len(data)) + data
+
+
+def packed_field(number, values):
+    return bytes_field(number, b"".join(varint(v) for v in values))
+
+
+strings = [""]
+
+
+def string(s):
+    if s not in strings:
+        strings.append(s)
+    return strings.index(s)
+
+
+def value_type(kind, unit):
+    return varint_field(1, string(kind)) + varint_field(2, string(unit))
+
+
+# Function ID -> name. Go writes the same name as the system name.
+functions = {
+    1: ("main.main", "/src/server/main.go"),
+    2: ("main.parse", "/src/server/parse.go"),
+    3: ("main.readLine", "/src/server/parse.go"),
+    4: ("main.render", "/src/server/render.go"),
+    5: ("runtime.mallocgc", "/usr/local/go/src/runtime/malloc.go"),
+    6: ("runtime.goexit", "/usr/local/go/src/runtime/asm_amd64.s"),
+    7: ("main.worker", "/src/server/worker.go"),
+}
+
+# Location ID -> address and (function ID, line) from the innermost outwards.
+locations = {
+    1: (0x4A1F20, [(1, 12)]),
+    2: (0x4A2310, [(3, 40), (2, 21)]),  # main.readLine inlined into main.parse
+    3: (0x4A2388, [(2, 25)]),
+    4: (0x4A2A04, [(4, 33)]),
+    5: (0x40F1C0, [(5, 1018)]),
+    6: (0x46B3E1, [(6, 1651)]),
+    7: (0x4A3050, [(7, 9)]),
+}
+
+# Location IDs from the leaf to the root, and the number of samples.
+samples = [
+    ([2, 1], 5),
+    ([3, 1], 2),
+    ([5, 4, 1], 7),
+    ([7, 6], 3),
+]
+
+message = bytearray()
+message += bytes_field(1, value_type("samples", "count"))
+message += bytes_field(1, value_type("cpu", "nanoseconds"))
+for location_ids, count in samples:
+    sample = packed_field(1, location_ids) + packed_field(2, [count, count * PERIOD_NS])
+    message += bytes_field(2, sample)
+mapping = (
+    varint_field(1, 1)
+    + varint_field(2, 0x400000)
+    + varint_field(3, 0x4D0000)
+    + varint_field(5, string("/usr/local/bin/server"))
+    + varint_field(7, 1)
+)
+message += bytes_field(3, mapping)
+for location_id, (address, lines) in locations.items():
+    location = varint_field(1, location_id) + varint_field(2, 1) + varint_field(3, address)
+    for function_id, line in lines:
+        location += bytes_field(4, varint_field(1, function_id) + varint_field(2, line))
+    message += bytes_field(4, location)
+for function_id, (name, filename) in functions.items():
+    function = (
+        varint_field(1, function_id)
+        + varint_field(2, string(name))
+        + varint_field(3, string(name))
+        + varint_field(4, string(filename))
+    )
+    message += bytes_field(5, function)
+period_type = value_type("cpu", "nanoseconds")
+time_nanos = 1_700_000_000_000_000_000
+duration_nanos = 200_000_000
+for s in strings:
+    message += bytes_field(6, s.encode())
+message += varint_field(9, time_nanos)
+message += varint_field(10, duration_nanos)
+message += bytes_field(11, period_type)
+message += varint_field(12, PERIOD_NS)
+
+path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "server.pb.gz")
+with open(path, "wb") as f:
+    with gzip.GzipFile(fileobj=f, mode="wb", mtime=0, filename="") as gz:
+        gz.write(bytes(message))
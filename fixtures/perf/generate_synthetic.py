@@ -0,0 +1,196 @@
+#!/usr/bin/env python3
+"""Writes synthetic-fp.perf.data, the perf.data file used by `samply bench import`.
+
+The file looks like the output of
+`perf record -e cpu-clock -c 1000000 -g` for a process with four threads
+which spend their time in a few hundred functions spread over an executable
+and a shared library. The stacks are generated with a fixed seed, so running
+this script again produces the same file.
+
+With --big-endian, it writes synthetic-fp-be.perf.data instead, which has the
+same contents but looks like it was recorded on a big-endian s390x machine.
+"""
+
+import os
+import struct
+import sys
+
+# The byte order of the file, "<" or ">".
+E = "<"
+
+PERF_RECORD_MMAP = 1
+PERF_RECORD_COMM = 3
+PERF_RECORD_EXIT = 4
+PERF_RECORD_FORK = 7
+PERF_RECORD_SAMPLE = 9
+PERF_RECORD_MISC_USER = 2
+PERF_CONTEXT_USER = (1 << 64) - 512
+
+SAMPLE_TYPE = 0x1 | 0x2 | 0x4 | 0x20 | 0x80 | 0x100  # IP TID TIME CALLCHAIN CPU PERIOD
+ATTR_FLAGS = (1 << 8) | (1 << 9) | (1 << 13) | (1 << 18)  # mmap comm task sample_id_all
+
+FEATURE_HOSTNAME = 3
+FEATURE_OSRELEASE = 4
+FEATURE_VERSION = 5
+FEATURE_ARCH = 6
+FEATURE_NRCPUS = 7
+FEATURE_CMDLINE = 11
+FEATURE_EVENT_DESC = 12
+
+PID = 4242
+THREAD_COUNT = 4
+SAMPLE_COUNT = 2500
+PERIOD_NS = 1_000_000
+START_NS = 1_000_000_000
+EXE_START = 0x55_5555_0000
+EXE_SIZE = 0x20_0000
+LIB_START = 0x7f00_0000_0000
+LIB_SIZE = 0x80_0000
+FUNCTION_COUNT = 300
+
+
+class Lcg:
+    def __init__(self, seed):
+        self.state = seed
+
+    def next(self, bound):
+        self.state = (self.state * 6364136223846793005 + 1442695040888963407) % (1 << 64)
+        return (self.state >> 33) % bound
+
+
+def reverse_bits64(value):
+    return int(f"{value:064b}"[::-1], 2)
+
+
+def padded_string(s, align=8):
+    data = s.encode() + b"\0"
+    return data + b"\0" * (-len(data) % align)
+
+
+def sample_id(tid, time):
+    return struct.pack(E + "IIQII", PID, tid, time, 0, 0)
+
+
+def record(record_type, body, misc=PERF_RECORD_MISC_USER):
+    return struct.pack(E + "IHH", record_type, misc, 8 + len(body)) + body
+
+
+def perf_string(s):
+    data = padded_string(s, 64)
+    return struct.pack(E + "I", len(data)) + data
+
+
+def main():
+    global E
+    big_endian = "--big-endian" in sys.argv[1:]
+    E = ">" if big_endian else "<"
+    rng = Lcg(0x5A4D_504C_59)
+    # Function start addresses, half of them in the executable and half in the library.
+    functions = []
+    for i in range(FUNCTION_COUNT):
+        if i % 2 == 0:
+            functions.append(EXE_START + 0x1000 + rng.next(EXE_SIZE - 0x2000))
+        else:
+            functions.append(LIB_START + 0x1000 + rng.next(LIB_SIZE - 0x2000))
+    # A handful of hot call paths per thread, with varying leaf frames on top.
+    hot_paths = [
+        [functions[rng.next(FUNCTION_COUNT)] + rng.next(0x100) for _ in range(5 + rng.next(25))]
+        for _ in range(12)
+    ]
+
+    data = bytearray()
+    time = START_NS
+    data += record(PERF_RECORD_COMM, struct.pack(E + "II", PID, PID) + padded_string("bench-app") + sample_id(PID, time))
+    data += record(
+        PERF_RECORD_MMAP,
+        struct.pack(E + "IIQQQ", PID, PID, EXE_START, EXE_SIZE, 0) + padded_string("/usr/bin/bench-app") + sample_id(PID, time),
+    )
+    data += record(
+        PERF_RECORD_MMAP,
+        struct.pack(E + "IIQQQ", PID, PID, LIB_START, LIB_SIZE, 0) + padded_string("/usr/lib/libbench.so") + sample_id(PID, time),
+    )
+    tids = [PID + i for i in range(THREAD_COUNT)]
+    for tid in tids[1:]:
+        data += record(PERF_RECORD_FORK, struct.pack(E + "IIIIQ", PID, PID, tid, PID, time) + sample_id(tid, time), misc=0)
+        data += record(PERF_RECORD_COMM, struct.pack(E + "II", PID, tid) + padded_string(f"worker {tid - PID}") + sample_id(tid, time))
+
+    for i in range(SAMPLE_COUNT):
+        tid = tids[i % THREAD_COUNT]
+        time = START_NS + (i // THREAD_COUNT) * PERIOD_NS + i % THREAD_COUNT
+        path = hot_paths[(tid * 3 + rng.next(3)) % len(hot_paths)]
+        depth = max(1, len(path) - rng.next(4))
+        leaf = functions[rng.next(FUNCTION_COUNT)] + rng.next(0x200)
+        ips = [leaf] + [address + 5 for address in reversed(path[:depth])]
+        callchain = struct.pack(E + "Q", 1 + len(ips)) + struct.pack(E + "Q", PERF_CONTEXT_USER)
+        callchain += b"".join(struct.pack(E + "Q", ip) for ip in ips)
+        body = struct.pack(E + "QIIQIIQ", leaf, PID, tid, time, 0, 0, PERIOD_NS) + callchain
+        data += record(PERF_RECORD_SAMPLE, body)
+
+    time += PERIOD_NS
+    for tid in reversed(tids):
+        data += record(PERF_RECORD_EXIT, struct.pack(E + "IIIIQ", PID, PID, tid, PID, time) + sample_id(tid, time), misc=0)
+
+    # struct perf_event_attr, PERF_ATTR_SIZE_VER5
+    attr = struct.pack(
+        E + "IIQQQQQIIQQQQIiQIHH",
+        1,  # PERF_TYPE_SOFTWARE
+        112,
+        0,  # PERF_COUNT_SW_CPU_CLOCK
+        PERIOD_NS,
+        SAMPLE_TYPE,
+        0,
+        # Big-endian compilers allocate bitfields from the most significant bit.
+        reverse_bits64(ATTR_FLAGS) if big_endian else ATTR_FLAGS,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    )
+    assert len(attr) == 112
+
+    features = [
+        (FEATURE_HOSTNAME, perf_string("bench-host")),
+        (FEATURE_OSRELEASE, perf_string("6.1.0-synthetic")),
+        (FEATURE_VERSION, perf_string("6.1")),
+        (FEATURE_ARCH, perf_string("s390x" if big_endian else "x86_64")),
+        (FEATURE_NRCPUS, struct.pack(E + "II", 4, 4)),
+        (FEATURE_CMDLINE, struct.pack(E + "I", 2) + perf_string("perf") + perf_string("record")),
+        (
+            FEATURE_EVENT_DESC,
+            struct.pack(E + "II", 1, 112) + attr + struct.pack(E + "I", 0) + perf_string("cpu-clock"),
+        ),
+    ]
+
+    header_size = 104
+    attrs_offset = header_size
+    attrs = attr + struct.pack(E + "QQ", 0, 0)
+    data_offset = attrs_offset + len(attrs)
+    feature_sections_offset = data_offset + len(data)
+    feature_data_offset = feature_sections_offset + 16 * len(features)
+
+    feature_bits = 0
+    feature_sections = bytearray()
+    feature_data = bytearray()
+    for feature, contents in features:
+        feature_bits |= 1 << feature
+        feature_sections += struct.pack(E + "QQ", feature_data_offset + len(feature_data), len(contents))
+        feature_data += contents
+
+    header = struct.pack(E + "Q", int.from_bytes(b"PERFILE2", "little")) + struct.pack(
+        E + "QQQQQQQQ",
+        header_size,
+        len(attrs),
+        attrs_offset,
+        len(attrs),
+        data_offset,
+        len(data),
+        0,
+        0,
+    ) + struct.pack(E + "QQQQ", feature_bits, 0, 0, 0)
+    assert len(header) == header_size
+
+    file_name = "synthetic-fp-be.perf.data" if big_endian else "synthetic-fp.perf.data"
+    out_path = os.path.join(os.path.dirname(os.path.abspath(__file__)), file_name)
+    with open(out_path, "wb") as f:
+        f.write(header + attrs + data + feature_sections + feature_data)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,178 @@
+//! Benchmarks for the work which importers and samply's recorders do for every
+//! sample: interning stacks, adding samples, and serializing the result.
+//!
+//! Run with `cargo bench -p fxprof-processed-profile`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use debugid::DebugId;
+
+use fxprof_processed_profile::{
+    CategoryColor, CategoryPairHandle, CpuDelta, Frame, FrameFlags, FrameInfo, LibraryHandle,
+    LibraryInfo, Profile, ReferenceTimestamp, SamplingInterval, Symbol, SymbolTable, ThreadHandle,
+    Timestamp,
+};
+
+use std::sync::Arc;
+
+const THREAD_COUNT: usize = 4;
+const SAMPLE_COUNT: usize = 20_000;
+const FUNCTION_COUNT: u32 = 500;
+
+/// A deterministic random number generator, so that each run sees the same stacks.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: u32) -> u32 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) % u64::from(bound)) as u32
+    }
+}
+
+/// The stacks of the samples, as relative addresses in the library, ordered from
+/// caller-most to callee-most. Like in a real profile, most samples share one of
+/// a few hot call paths and differ in their leaf frame.
+fn generate_stacks() -> Vec<Vec<u32>> {
+    let mut rng = Lcg(0x5a4d504c59);
+    let function_addresses: Vec<u32> = (0..FUNCTION_COUNT)
+        .map(|i| 0x1000 + i * 0x400 + rng.next(0x100))
+        .collect();
+    let hot_paths: Vec<Vec<u32>> = (0..16)
+        .map(|_| {
+            let depth = 5 + rng.next(30);
+            (0..depth)
+                .map(|_| function_addresses[rng.next(FUNCTION_COUNT) as usize] + 5)
+                .collect()
+        })
+        .collect();
+    (0..SAMPLE_COUNT)
+        .map(|_| {
+            let path = &hot_paths[rng.next(hot_paths.len() as u32) as usize];
+            let depth = path.len() - rng.next(4).min(path.len() as u32 - 1) as usize;
+            let mut stack = path[..depth].to_vec();
+            stack.push(function_addresses[rng.next(FUNCTION_COUNT) as usize] + rng.next(0x200));
+            stack
+        })
+        .collect()
+}
+
+struct ProfileSetup {
+    profile: Profile,
+    threads: Vec<ThreadHandle>,
+    lib: LibraryHandle,
+    category: CategoryPairHandle,
+}
+
+fn create_profile() -> ProfileSetup {
+    let mut profile = Profile::new(
+        "bench",
+        ReferenceTimestamp::from_millis_since_unix_epoch(1636162232627.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("bench", 123, Timestamp::from_millis_since_reference(0.0));
+    let threads = (0..THREAD_COUNT)
+        .map(|i| {
+            profile.add_thread(
+                process,
+                123 + i as u32,
+                Timestamp::from_millis_since_reference(0.0),
+                i == 0,
+            )
+        })
+        .collect();
+    let symbols = (0..FUNCTION_COUNT)
+        .map(|i| Symbol {
+            address: 0x1000 + i * 0x400,
+            size: Some(0x400),
+            name: format!("function_{i}"),
+        })
+        .collect();
+    let lib = profile.add_lib(LibraryInfo {
+        name: "libbench.so".to_string(),
+        debug_name: "libbench.so".to_string(),
+        path: "/usr/lib/libbench.so".to_string(),
+        debug_path: "/usr/lib/libbench.so".to_string(),
+        code_id: None,
+        debug_id: DebugId::nil(),
+        arch: None,
+        symbol_table: Some(Arc::new(SymbolTable::new(symbols))),
+    });
+    let category = profile.add_category("Regular", CategoryColor::Blue).into();
+    ProfileSetup {
+        profile,
+        threads,
+        lib,
+        category,
+    }
+}
+
+fn frames<'a>(
+    stack: &'a [u32],
+    lib: LibraryHandle,
+    category: CategoryPairHandle,
+) -> impl Iterator<Item = FrameInfo> + 'a {
+    let leaf_index = stack.len() - 1;
+    stack.iter().enumerate().map(move |(i, address)| FrameInfo {
+        frame: if i == leaf_index {
+            Frame::RelativeAddressFromInstructionPointer(lib, *address)
+        } else {
+            Frame::RelativeAddressFromReturnAddress(lib, *address)
+        },
+        category_pair: category,
+        flags: FrameFlags::empty(),
+    })
+}
+
+fn add_samples(setup: &mut ProfileSetup, stacks: &[Vec<u32>]) {
+    for (i, stack) in stacks.iter().enumerate() {
+        let thread = setup.threads[i % THREAD_COUNT];
+        let timestamp = Timestamp::from_millis_since_reference((i / THREAD_COUNT) as f64);
+        setup.profile.add_sample(
+            thread,
+            timestamp,
+            frames(stack, setup.lib, setup.category),
+            CpuDelta::from_millis(1.0),
+            1,
+        );
+    }
+}
+
+fn bench_add_samples(c: &mut Criterion) {
+    let stacks = generate_stacks();
+    let mut group = c.benchmark_group("add_sample");
+    group.throughput(Throughput::Elements(stacks.len() as u64));
+    group.bench_function("hot_paths", |b| {
+        b.iter_batched(
+            create_profile,
+            |mut setup| {
+                add_samples(&mut setup, &stacks);
+                setup
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn bench_serialize(c: &mut Criterion) {
+    let stacks = generate_stacks();
+    let mut setup = create_profile();
+    add_samples(&mut setup, &stacks);
+    let mut buffer = Vec::new();
+    serde_json::to_writer(&mut buffer, &setup.profile).unwrap();
+
+    let mut group = c.benchmark_group("serialize");
+    group.throughput(Throughput::Bytes(buffer.len() as u64));
+    group.bench_function("hot_paths", |b| {
+        b.iter(|| {
+            buffer.clear();
+            serde_json::to_writer(&mut buffer, &setup.profile).unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_add_samples, bench_serialize);
+criterion_main!(benches);
@@ -48,6 +48,10 @@ impl CpuDelta {
     pub fn is_zero(&self) -> bool {
         self.micros == 0
     }
+
+    pub(crate) fn as_micros(&self) -> u64 {
+        self.micros
+    }
 }
 
 impl Serialize for CpuDelta {
@@ -10,6 +10,7 @@ use crate::global_lib_table::{GlobalLibIndex, GlobalLibTable};
 use crate::native_symbols::{NativeSymbolIndex, NativeSymbols};
 use crate::resource_table::ResourceTable;
 use crate::serialization_helpers::SerializableSingleValueColumn;
+use crate::string_table::GlobalStringTable;
 use crate::thread_string_table::{ThreadInternalStringIndex, ThreadStringTable};
 
 #[derive(Debug, Clone, Default)]
@@ -27,9 +28,11 @@ impl FrameTable {
         Default::default()
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn index_for_frame(
         &mut self,
         string_table: &mut ThreadStringTable,
+        global_string_table: &mut GlobalStringTable,
         resource_table: &mut ResourceTable,
         func_table: &mut FuncTable,
         native_symbol_table: &mut NativeSymbols,
@@ -50,12 +53,17 @@ impl FrameTable {
                 {
                     InternalFrameLocation::UnknownAddress(address) => {
                         let location_string = format!("0x{address:x}");
-                        let s = string_table.index_for_string(&location_string);
+                        let s =
+                            string_table.index_for_string(&location_string, global_string_table);
                         (None, s, None, None)
                     }
                     InternalFrameLocation::AddressInLib(address, lib_index) => {
-                        let res =
-                            resource_table.resource_for_lib(lib_index, global_libs, string_table);
+                        let res = resource_table.resource_for_lib(
+                            lib_index,
+                            global_libs,
+                            string_table,
+                            global_string_table,
+                        );
                         let lib = global_libs.get_lib(lib_index).unwrap();
                         let native_symbol_and_name =
                             lib.symbol_table.as_deref().and_then(|symbol_table| {
@@ -65,6 +73,7 @@ impl FrameTable {
                                         lib_index,
                                         symbol,
                                         string_table,
+                                        global_string_table,
                                     ),
                                 )
                             });
@@ -74,7 +83,11 @@ impl FrameTable {
                             }
                             None => {
                                 let location_string = format!("0x{address:x}");
-                                (None, string_table.index_for_string(&location_string))
+                                (
+                                    None,
+                                    string_table
+                                        .index_for_string(&location_string, global_string_table),
+                                )
                             }
                         };
                         (Some(address), s, native_symbol, Some(res))
@@ -14,6 +14,7 @@ pub struct GlobalLibTable {
     used_libs: Vec<LibraryHandle>, // append-only for stable GlobalLibIndexes
     lib_map: FastHashMap<LibraryInfo, LibraryHandle>,
     used_lib_map: FastHashMap<LibraryHandle, GlobalLibIndex>,
+    file_sizes: FastHashMap<LibraryHandle, u64>,
 }
 
 impl GlobalLibTable {
@@ -23,6 +24,7 @@ impl GlobalLibTable {
             used_libs: Vec::new(),
             lib_map: FastHashMap::default(),
             used_lib_map: FastHashMap::default(),
+            file_sizes: FastHashMap::default(),
         }
     }
 
@@ -39,6 +41,14 @@ impl GlobalLibTable {
         self.all_libs[library.0].symbol_table = Some(symbol_table);
     }
 
+    pub fn set_lib_file_size(&mut self, library: LibraryHandle, file_size: u64) {
+        self.file_sizes.insert(library, file_size);
+    }
+
+    pub fn lib(&self, library: LibraryHandle) -> &LibraryInfo {
+        &self.all_libs[library.0]
+    }
+
     pub fn index_for_used_lib(&mut self, lib_handle: LibraryHandle) -> GlobalLibIndex {
         let used_libs = &mut self.used_libs;
         *self.used_lib_map.entry(lib_handle).or_insert_with(|| {
@@ -56,7 +66,22 @@ impl GlobalLibTable {
 
 impl Serialize for GlobalLibTable {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.collect_seq(self.used_libs.iter().map(|handle| &self.all_libs[handle.0]))
+        serializer.collect_seq(self.used_libs.iter().map(|handle| SerializableGlobalLib {
+            lib: &self.all_libs[handle.0],
+            file_size: self.file_sizes.get(handle).copied(),
+        }))
+    }
+}
+
+struct SerializableGlobalLib<'a> {
+    lib: &'a LibraryInfo,
+    file_size: Option<u64>,
+}
+
+impl<'a> Serialize for SerializableGlobalLib<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.lib
+            .serialize_with_file_size(self.file_size, serializer)
     }
 }
 
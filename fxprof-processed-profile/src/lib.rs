@@ -45,6 +45,7 @@ mod lib_mappings;
 mod library_info;
 mod marker_table;
 mod markers;
+mod meta_info;
 mod native_symbols;
 mod process;
 mod profile;
@@ -68,7 +69,8 @@ pub use lib_mappings::LibMappings;
 pub use library_info::{LibraryInfo, Symbol, SymbolTable};
 pub use markers::*;
 pub use process::ThreadHandle;
-pub use profile::{Profile, SamplingInterval, StringHandle};
+pub use profile::{Profile, ProfileProcessSubset, SamplingInterval, StackHandle, StringHandle};
 pub use reference_timestamp::ReferenceTimestamp;
+pub use sample_table::WeightType;
 pub use thread::ProcessHandle;
 pub use timestamp::*;
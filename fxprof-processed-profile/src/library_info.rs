@@ -61,6 +61,18 @@ pub struct LibraryInfo {
 
 impl Serialize for LibraryInfo {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.serialize_with_file_size(None, serializer)
+    }
+}
+
+impl LibraryInfo {
+    /// Serializes the library, with the file size from [`Profile::set_lib_file_size`](crate::Profile::set_lib_file_size)
+    /// if there is one.
+    pub(crate) fn serialize_with_file_size<S: Serializer>(
+        &self,
+        file_size: Option<u64>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
         let breakpad_id = self.debug_id.breakpad().to_string();
         let code_id = self.code_id.as_ref().map(|cid| cid.to_string());
         let mut map = serializer.serialize_map(None)?;
@@ -71,6 +83,9 @@ impl Serialize for LibraryInfo {
         map.serialize_entry("breakpadId", &breakpad_id)?;
         map.serialize_entry("codeId", &code_id)?;
         map.serialize_entry("arch", &self.arch)?;
+        if let Some(file_size) = file_size {
+            map.serialize_entry("fileSize", &file_size)?;
+        }
         map.end()
     }
 }
@@ -107,6 +107,77 @@ pub struct MarkerSchema {
     pub fields: Vec<MarkerSchemaField>,
 }
 
+/// Describes a marker type which is only known at runtime, for example because
+/// its fields are supplied by the user. Unlike [`MarkerSchema`], this owns its
+/// strings. It is used with [`Profile::add_marker_with_schema`](crate::Profile::add_marker_with_schema).
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RuntimeMarkerSchema {
+    /// The name of this marker type.
+    #[serde(rename = "name")]
+    pub type_name: String,
+
+    /// List of marker display locations.
+    #[serde(rename = "display")]
+    pub locations: Vec<MarkerLocation>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chart_label: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tooltip_label: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub table_label: Option<String>,
+
+    /// The marker fields. These can be specified on each marker.
+    #[serde(rename = "data")]
+    pub fields: Vec<RuntimeMarkerField>,
+}
+
+/// A field of a [`RuntimeMarkerSchema`], which can have a different value for
+/// each marker.
+#[derive(Debug, Clone, Serialize)]
+pub struct RuntimeMarkerField {
+    /// The field key.
+    pub key: String,
+
+    /// The user-visible label of this field.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub label: String,
+
+    /// The format of this field.
+    pub format: MarkerFieldFormat,
+
+    /// Whether this field's value should be matched against search terms.
+    pub searchable: bool,
+}
+
+impl RuntimeMarkerSchema {
+    /// Adds the fields of `other` which this schema doesn't have yet. Fields
+    /// which both schemas have, but with different formats, become decimal
+    /// fields if both formats are numbers, and string fields otherwise.
+    pub(crate) fn merge(&mut self, other: &RuntimeMarkerSchema) {
+        for other_field in &other.fields {
+            match self.fields.iter_mut().find(|f| f.key == other_field.key) {
+                Some(field) => {
+                    if field.format != other_field.format {
+                        field.format = match (&field.format, &other_field.format) {
+                            (
+                                MarkerFieldFormat::Integer | MarkerFieldFormat::Decimal,
+                                MarkerFieldFormat::Integer | MarkerFieldFormat::Decimal,
+                            ) => MarkerFieldFormat::Decimal,
+                            _ => MarkerFieldFormat::String,
+                        };
+                    }
+                    field.searchable |= other_field.searchable;
+                }
+                None => self.fields.push(other_field.clone()),
+            }
+        }
+    }
+}
+
 /// The location of markers with this type.
 ///
 /// Markers can be shown in different parts of the Firefox Profiler UI.
@@ -171,7 +242,7 @@ pub struct MarkerDynamicField {
 }
 
 /// The field format of a marker field.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum MarkerFieldFormat {
     // ----------------------------------------------------
@@ -0,0 +1,74 @@
+use serde::ser::{SerializeMap, Serializer};
+use serde_json::json;
+
+/// Information about the machine and the invocation which produced the profile.
+/// All of it is optional and ends up in the profile's `meta` object.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProfileMetaInfo {
+    pub os_name: Option<String>,
+    pub cpu_name: Option<String>,
+    pub physical_cpu_count: Option<u32>,
+    pub logical_cpu_count: Option<u32>,
+    pub main_memory_bytes: Option<u64>,
+    pub arguments: Option<String>,
+    /// Sections of extra key-value information, shown in the profile info panel.
+    pub extra_sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl ProfileMetaInfo {
+    pub fn add_extra_info(&mut self, section: &str, label: &str, value: &str) {
+        let entries = match self
+            .extra_sections
+            .iter_mut()
+            .position(|(name, _)| name == section)
+        {
+            Some(index) => &mut self.extra_sections[index].1,
+            None => {
+                self.extra_sections.push((section.to_string(), Vec::new()));
+                &mut self.extra_sections.last_mut().unwrap().1
+            }
+        };
+        entries.push((label.to_string(), value.to_string()));
+    }
+
+    pub fn serialize_entries<S: Serializer>(
+        &self,
+        map: &mut S::SerializeMap,
+    ) -> Result<(), S::Error> {
+        if let Some(os_name) = &self.os_name {
+            map.serialize_entry("oscpu", os_name)?;
+        }
+        if let Some(cpu_name) = &self.cpu_name {
+            map.serialize_entry("CPUName", cpu_name)?;
+        }
+        if let Some(physical_cpu_count) = self.physical_cpu_count {
+            map.serialize_entry("physicalCPUs", &physical_cpu_count)?;
+        }
+        if let Some(logical_cpu_count) = self.logical_cpu_count {
+            map.serialize_entry("logicalCPUs", &logical_cpu_count)?;
+        }
+        if let Some(main_memory_bytes) = self.main_memory_bytes {
+            map.serialize_entry("mainMemory", &main_memory_bytes)?;
+        }
+        if let Some(arguments) = &self.arguments {
+            map.serialize_entry("arguments", arguments)?;
+        }
+        if !self.extra_sections.is_empty() {
+            let extra: Vec<_> = self
+                .extra_sections
+                .iter()
+                .map(|(label, entries)| {
+                    let entries: Vec<_> = entries
+                        .iter()
+                        .map(|(label, value)| {
+                            json!({ "label": label, "format": "string", "value": value })
+                        })
+                        .collect();
+                    json!({ "label": label, "entries": entries })
+                })
+                .collect();
+            map.serialize_entry("extra", &extra)?;
+        }
+        Ok(())
+    }
+}
@@ -4,6 +4,7 @@ use crate::{
     fast_hash_map::FastHashMap,
     global_lib_table::GlobalLibIndex,
     library_info::Symbol,
+    string_table::GlobalStringTable,
     thread_string_table::{ThreadInternalStringIndex, ThreadStringTable},
 };
 
@@ -29,6 +30,7 @@ impl NativeSymbols {
         lib_index: GlobalLibIndex,
         symbol: &Symbol,
         string_table: &mut ThreadStringTable,
+        global_string_table: &mut GlobalStringTable,
     ) -> (NativeSymbolIndex, ThreadInternalStringIndex) {
         let addresses = &mut self.addresses;
         let function_sizes = &mut self.function_sizes;
@@ -42,7 +44,7 @@ impl NativeSymbols {
                 addresses.push(symbol.address);
                 function_sizes.push(symbol.size);
                 lib_indexes.push(lib_index);
-                names.push(string_table.index_for_string(&symbol.name));
+                names.push(string_table.index_for_string(&symbol.name, global_string_table));
                 native_symbol_index
             });
         let name_string_index = names[symbol_index];
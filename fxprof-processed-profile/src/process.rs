@@ -4,7 +4,7 @@ use std::hash::Hash;
 use crate::frame_table::InternalFrameLocation;
 use crate::global_lib_table::{GlobalLibTable, LibraryHandle};
 use crate::lib_mappings::LibMappings;
-use crate::Timestamp;
+use crate::{ProcessHandle, Timestamp};
 
 /// A thread. Can be created with [`Profile::add_thread`](crate::Profile::add_thread).
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
@@ -18,6 +18,7 @@ pub struct Process {
     start_time: Timestamp,
     end_time: Option<Timestamp>,
     libs: LibMappings<LibraryHandle>,
+    parent: Option<ProcessHandle>,
 }
 
 impl Process {
@@ -29,9 +30,18 @@ impl Process {
             start_time,
             end_time: None,
             name: name.to_owned(),
+            parent: None,
         }
     }
 
+    pub fn set_parent(&mut self, parent: ProcessHandle) {
+        self.parent = Some(parent);
+    }
+
+    pub fn parent(&self) -> Option<ProcessHandle> {
+        self.parent
+    }
+
     pub fn set_start_time(&mut self, start_time: Timestamp) {
         self.start_time = start_time;
     }
@@ -1,8 +1,12 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
 use std::sync::Arc;
 use std::time::Duration;
 
-use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use serde::ser::{Error, Serialize, SerializeMap, SerializeSeq, Serializer};
 use serde_json::json;
+use serde_json::value::RawValue;
 
 use crate::category::{Category, CategoryHandle, CategoryPairHandle};
 use crate::category_color::CategoryColor;
@@ -14,11 +18,15 @@ use crate::frame_table::{InternalFrame, InternalFrameLocation};
 use crate::global_lib_table::{GlobalLibTable, LibraryHandle};
 use crate::lib_mappings::LibMappings;
 use crate::library_info::LibraryInfo;
+use crate::meta_info::ProfileMetaInfo;
 use crate::process::{Process, ThreadHandle};
 use crate::reference_timestamp::ReferenceTimestamp;
+use crate::sample_table::WeightType;
 use crate::string_table::{GlobalStringIndex, GlobalStringTable};
-use crate::thread::{ProcessHandle, Thread};
-use crate::{MarkerSchema, MarkerTiming, ProfilerMarker, SymbolTable, Timestamp};
+use crate::thread::{ProcessHandle, SpilledThread, Thread};
+use crate::{
+    MarkerSchema, MarkerTiming, ProfilerMarker, RuntimeMarkerSchema, SymbolTable, Timestamp,
+};
 
 /// The sampling interval used during profile recording.
 ///
@@ -73,6 +81,12 @@ impl From<Duration> for SamplingInterval {
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub struct StringHandle(GlobalStringIndex);
 
+/// A handle for a stack in a thread's stack table, returned from
+/// [`Profile::intern_stack_frames`]. It can only be used with the thread it was
+/// created for.
+#[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct StackHandle(ThreadHandle, usize);
+
 /// Stores the profile data and can be serialized as JSON, via [`serde::Serialize`].
 ///
 /// The profile data is organized into a list of processes with threads.
@@ -111,8 +125,12 @@ pub struct Profile {
     pub(crate) reference_timestamp: ReferenceTimestamp,
     pub(crate) string_table: GlobalStringTable,
     pub(crate) marker_schemas: FastHashMap<&'static str, MarkerSchema>,
+    pub(crate) runtime_marker_schemas: FastHashMap<String, RuntimeMarkerSchema>,
     used_pids: FastHashMap<u32, u32>,
     used_tids: FastHashMap<u32, u32>,
+    spill_file: Option<File>,
+    meta_info: ProfileMetaInfo,
+    weight_type: WeightType,
 }
 
 impl Profile {
@@ -137,6 +155,7 @@ impl Profile {
             processes: Vec::new(),
             string_table: GlobalStringTable::new(),
             marker_schemas: FastHashMap::default(),
+            runtime_marker_schemas: FastHashMap::default(),
             categories: vec![Category {
                 name: "Other".to_string(),
                 color: CategoryColor::Gray,
@@ -145,6 +164,9 @@ impl Profile {
             used_pids: FastHashMap::default(),
             used_tids: FastHashMap::default(),
             counters: Vec::new(),
+            spill_file: None,
+            meta_info: ProfileMetaInfo::default(),
+            weight_type: WeightType::Samples,
         }
     }
 
@@ -153,6 +175,17 @@ impl Profile {
         self.interval = interval;
     }
 
+    /// Change what the sample weights mean, for all threads. By default, the
+    /// weight of a sample is a sample count. With [`WeightType::TracingMicroseconds`],
+    /// the weight is the duration which the sample stands for, e.g. how long a
+    /// thread was blocked, and the call tree sums up these durations.
+    pub fn set_weight_type(&mut self, weight_type: WeightType) {
+        self.weight_type = weight_type;
+        for thread in &mut self.threads {
+            thread.set_weight_type(weight_type);
+        }
+    }
+
     /// Change the reference timestamp.
     pub fn set_reference_timestamp(&mut self, reference_timestamp: ReferenceTimestamp) {
         self.reference_timestamp = reference_timestamp;
@@ -163,6 +196,40 @@ impl Profile {
         self.product = product.to_string();
     }
 
+    /// Set the name and version of the operating system the profile was recorded on,
+    /// for example "Ubuntu 22.04.4 LTS (Linux 6.5.0-35-generic)".
+    pub fn set_os_name(&mut self, os_name: &str) {
+        self.meta_info.os_name = Some(os_name.to_string());
+    }
+
+    /// Set the CPU model name, for example "AMD Ryzen 9 7950X 16-Core Processor".
+    pub fn set_cpu_name(&mut self, cpu_name: &str) {
+        self.meta_info.cpu_name = Some(cpu_name.to_string());
+    }
+
+    /// Set the number of physical CPU cores and the number of logical CPUs, i.e.
+    /// hardware threads.
+    pub fn set_cpu_counts(&mut self, physical: Option<u32>, logical: Option<u32>) {
+        self.meta_info.physical_cpu_count = physical;
+        self.meta_info.logical_cpu_count = logical;
+    }
+
+    /// Set the total amount of memory of the machine, in bytes.
+    pub fn set_main_memory(&mut self, bytes: u64) {
+        self.meta_info.main_memory_bytes = Some(bytes);
+    }
+
+    /// Set the command line which produced the profile.
+    pub fn set_arguments(&mut self, arguments: &str) {
+        self.meta_info.arguments = Some(arguments.to_string());
+    }
+
+    /// Add a line of free-form information to the profile metadata. Entries are
+    /// grouped into sections, which are created on first use.
+    pub fn add_extra_info(&mut self, section: &str, label: &str, value: &str) {
+        self.meta_info.add_extra_info(section, label, value);
+    }
+
     /// Add a category and return its handle.
     ///
     /// Categories are used for stack frames and markers, as part of a "category pair".
@@ -266,6 +333,67 @@ impl Profile {
         self.processes[process.0].set_name(name);
     }
 
+    /// Set the process which started this process. The parent's pid is stored in
+    /// the profile, and the process name is displayed with the names of its
+    /// ancestors, like "make>cc1", so that process trees are easy to follow.
+    pub fn set_process_parent(&mut self, process: ProcessHandle, parent: ProcessHandle) {
+        if process != parent {
+            self.processes[process.0].set_parent(parent);
+        }
+    }
+
+    /// Get the process which started this process, if it was set with
+    /// [`Profile::set_process_parent`].
+    pub fn process_parent(&self, process: ProcessHandle) -> Option<ProcessHandle> {
+        self.processes[process.0].parent()
+    }
+
+    /// Get the time at which a process ended, if it was set with
+    /// [`Profile::set_process_end_time`].
+    pub fn process_end_time(&self, process: ProcessHandle) -> Option<Timestamp> {
+        self.processes[process.0].end_time()
+    }
+
+    /// Get the timestamp of the latest sample on any of the threads of a process,
+    /// or `None` if the process has no samples.
+    pub fn process_latest_sample_time(&self, process: ProcessHandle) -> Option<Timestamp> {
+        self.threads
+            .iter()
+            .filter(|thread| thread.process() == process)
+            .filter_map(|thread| thread.latest_sample_time())
+            .max()
+    }
+
+    /// Returns the handles of all processes, in the order in which they were added.
+    pub fn process_handles(&self) -> impl Iterator<Item = ProcessHandle> {
+        (0..self.processes.len()).map(ProcessHandle)
+    }
+
+    /// Get the name of a process.
+    pub fn process_name(&self, process: ProcessHandle) -> &str {
+        self.processes[process.0].name()
+    }
+
+    /// Get the pid of a process, as it appears in the profile JSON. This can have
+    /// a suffix, such as "1234.1", if the same pid was used by multiple processes.
+    pub fn process_pid(&self, process: ProcessHandle) -> &str {
+        self.processes[process.0].pid()
+    }
+
+    /// Returns a serializable view of the profile which only contains the threads
+    /// and counters of the given processes. This can be used to write one profile
+    /// file per process. The libraries, categories and marker schemas are always
+    /// written in full, so that the files can be combined again easily.
+    pub fn process_subset<'a>(
+        &'a self,
+        processes: &'a [ProcessHandle],
+    ) -> ProfileProcessSubset<'a> {
+        ProfileProcessSubset {
+            profile: self,
+            processes,
+        }
+    }
+
     /// Get the `LibraryHandle` for a library. This handle is used in [`Profile::add_lib_mapping`]
     /// and in the pre-resolved [`Frame`] variants.
     ///
@@ -275,6 +403,11 @@ impl Profile {
         self.global_libs.handle_for_lib(library)
     }
 
+    /// Get the information which was given to [`Profile::add_lib`] for a library.
+    pub fn lib_info(&self, library: LibraryHandle) -> &LibraryInfo {
+        self.global_libs.lib(library)
+    }
+
     /// Set the symbol table for a library.
     ///
     /// This symbol table can also be specified in the [`LibraryInfo`] which is given to
@@ -288,6 +421,13 @@ impl Profile {
         self.global_libs.set_lib_symbol_table(library, symbol_table);
     }
 
+    /// Set the size of a library's binary file, in bytes. Together with the code ID, this
+    /// identifies the exact file, for example when fetching it from a symbol server later.
+    /// It's stored as `fileSize` in the profile's library list.
+    pub fn set_lib_file_size(&mut self, library: LibraryHandle, file_size: u64) {
+        self.global_libs.set_lib_file_size(library, file_size);
+    }
+
     /// For a given process, define where in the virtual memory of this process the given library
     /// is mapped.
     ///
@@ -358,8 +498,9 @@ impl Profile {
     ) -> ThreadHandle {
         let tid = self.make_unique_tid(tid);
         let handle = ThreadHandle(self.threads.len());
-        self.threads
-            .push(Thread::new(process, tid, start_time, is_main));
+        let mut thread = Thread::new(process, tid, start_time, is_main);
+        thread.set_weight_type(self.weight_type);
+        self.threads.push(thread);
         self.processes[process.0].add_thread(handle);
         handle
     }
@@ -369,6 +510,13 @@ impl Profile {
         self.threads[thread.0].set_name(name);
     }
 
+    /// Hide a thread's track when the profile is opened. The user can still show
+    /// it from the track list. Useful for service threads which are rarely
+    /// interesting, e.g. a runtime's GC or compiler threads.
+    pub fn set_thread_hidden_by_default(&mut self, thread: ThreadHandle, hidden: bool) {
+        self.threads[thread.0].set_hidden_by_default(hidden);
+    }
+
     /// Change the start time of a thread.
     pub fn set_thread_start_time(&mut self, thread: ThreadHandle, start_time: Timestamp) {
         self.threads[thread.0].set_start_time(start_time);
@@ -379,6 +527,74 @@ impl Profile {
         self.threads[thread.0].set_end_time(end_time);
     }
 
+    /// Set a file which [`Profile::spill_process_threads`] can write thread data to.
+    ///
+    /// The file should be an empty temporary file which is readable and writable.
+    /// It is read back when the profile is serialized.
+    pub fn set_spill_file(&mut self, file: File) {
+        self.spill_file = Some(file);
+    }
+
+    /// Serialize the threads of this process into the spill file and free their
+    /// sample, stack, frame and marker tables. This keeps the memory usage bounded
+    /// when profiling many short-lived processes.
+    ///
+    /// Call this once the process and its threads are complete: any data which
+    /// is added to a spilled thread afterwards is not included in the profile.
+    /// This does nothing if no spill file has been set with [`Profile::set_spill_file`].
+    pub fn spill_process_threads(&mut self, process: ProcessHandle) -> std::io::Result<()> {
+        let file = match &self.spill_file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let process_data = &self.processes[process.0];
+        for &thread_handle in process_data.threads() {
+            let thread = &self.threads[thread_handle.0];
+            if thread.spilled().is_some() {
+                continue;
+            }
+            let mut file_ref = file;
+            let offset = file_ref.seek(SeekFrom::End(0))?;
+            let mut writer = BufWriter::new(file_ref);
+            serde_json::to_writer(
+                &mut writer,
+                &SerializableProfileThread(
+                    &self.processes,
+                    thread,
+                    &self.categories,
+                    &self.string_table,
+                    Some(file),
+                ),
+            )?;
+            writer.flush()?;
+            drop(writer);
+            let len = file_ref.stream_position()? - offset;
+            self.threads[thread_handle.0].set_spilled(SpilledThread { offset, len });
+        }
+        Ok(())
+    }
+
+    /// Write the sample and stack tables of this process's threads to the spill
+    /// file and free them. Unlike [`Profile::spill_process_threads`], this can
+    /// be called for processes which are still running, or whose threads may
+    /// be reused: the threads can receive more samples afterwards, and the
+    /// spilled tables are read back when the profile is serialized.
+    ///
+    /// This does nothing if no spill file has been set with [`Profile::set_spill_file`].
+    pub fn spill_process_thread_tables(&mut self, process: ProcessHandle) -> std::io::Result<()> {
+        let file = match &self.spill_file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        for &thread_handle in self.processes[process.0].threads() {
+            let thread = &mut self.threads[thread_handle.0];
+            if thread.spilled().is_none() {
+                thread.spill_tables(file)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Turn the string into in a [`StringHandle`], for use in [`Frame::Label`].
     pub fn intern_string(&mut self, s: &str) -> StringHandle {
         StringHandle(self.string_table.index_for_string(s))
@@ -427,6 +643,45 @@ impl Profile {
         self.threads[thread.0].add_sample(timestamp, stack_index, cpu_delta, weight);
     }
 
+    /// Get the stack handle for a stack whose frames are given by an iterator,
+    /// ordered from caller-most to callee-most, on the given thread.
+    ///
+    /// Converting a stack walks and interns all of its frames. Callers which see
+    /// the same stack many times can keep the returned handle and pass it to
+    /// [`Profile::add_sample_with_stack`] instead. Returns `None` if the stack has
+    /// no frames.
+    pub fn intern_stack_frames(
+        &mut self,
+        thread: ThreadHandle,
+        frames: impl Iterator<Item = FrameInfo>,
+    ) -> Option<StackHandle> {
+        let stack_index = self.stack_index_for_frames(thread, frames)?;
+        Some(StackHandle(thread, stack_index))
+    }
+
+    /// Add a sample to the given thread, with a stack which was interned with
+    /// [`Profile::intern_stack_frames`]. See [`Profile::add_sample`] for the
+    /// meaning of the other arguments.
+    ///
+    /// Panics if the stack handle was created for a different thread.
+    pub fn add_sample_with_stack(
+        &mut self,
+        thread: ThreadHandle,
+        timestamp: Timestamp,
+        stack: Option<StackHandle>,
+        cpu_delta: CpuDelta,
+        weight: i32,
+    ) {
+        let stack_index = stack.map(|StackHandle(stack_thread, stack_index)| {
+            assert_eq!(
+                stack_thread, thread,
+                "StackHandle from a different thread passed to Profile::add_sample_with_stack"
+            );
+            stack_index
+        });
+        self.threads[thread.0].add_sample(timestamp, stack_index, cpu_delta, weight);
+    }
+
     /// Add a sample with a CPU delta of zero. Internally, multiple consecutive
     /// samples with a delta of zero will be combined into one sample with an accumulated
     /// weight.
@@ -451,7 +706,48 @@ impl Profile {
         self.marker_schemas
             .entry(T::MARKER_TYPE_NAME)
             .or_insert_with(T::schema);
-        self.threads[thread.0].add_marker(category, name, marker, timing, None);
+        self.threads[thread.0].add_marker(
+            &mut self.string_table,
+            category,
+            name,
+            marker,
+            timing,
+            None,
+        );
+    }
+
+    /// Add a marker whose type is only known at runtime, for example because its
+    /// fields are supplied by the user. `data` needs to have a `"type"` property
+    /// which matches `schema.type_name`.
+    ///
+    /// The schema is registered when the first marker with this type name is
+    /// added. Fields which are only in the schemas passed with later markers of
+    /// the same type are added to it, so markers from different sources can
+    /// share a type even if they don't have the same fields.
+    pub fn add_marker_with_schema(
+        &mut self,
+        thread: ThreadHandle,
+        category: CategoryHandle,
+        name: &str,
+        schema: &RuntimeMarkerSchema,
+        data: serde_json::Value,
+        timing: MarkerTiming,
+    ) {
+        match self.runtime_marker_schemas.get_mut(&schema.type_name) {
+            Some(existing_schema) => existing_schema.merge(schema),
+            None => {
+                self.runtime_marker_schemas
+                    .insert(schema.type_name.clone(), schema.clone());
+            }
+        }
+        self.threads[thread.0].add_marker_data(
+            &mut self.string_table,
+            category,
+            name,
+            data,
+            timing,
+            None,
+        );
     }
 
     /// Add a marker to the given thread, with a stack.
@@ -468,7 +764,14 @@ impl Profile {
             .entry(T::MARKER_TYPE_NAME)
             .or_insert_with(T::schema);
         let stack_index = self.stack_index_for_frames(thread, stack_frames);
-        self.threads[thread.0].add_marker(category, name, marker, timing, stack_index);
+        self.threads[thread.0].add_marker(
+            &mut self.string_table,
+            category,
+            name,
+            marker,
+            timing,
+            stack_index,
+        );
     }
 
     /// Add a data point to a counter. For a memory counter, `value_delta` is the number
@@ -521,8 +824,7 @@ impl Profile {
                     InternalFrameLocation::AddressInLib(nudged_relative_address, global_lib_index)
                 }
                 Frame::Label(string_index) => {
-                    let thread_string_index =
-                        thread.convert_string_index(&self.string_table, string_index.0);
+                    let thread_string_index = thread.convert_string_index(string_index.0);
                     InternalFrameLocation::Label(thread_string_index)
                 }
             };
@@ -531,7 +833,11 @@ impl Profile {
                 flags: frame_info.flags,
                 category_pair: frame_info.category_pair,
             };
-            let frame_index = thread.frame_index_for_frame(internal_frame, &self.global_libs);
+            let frame_index = thread.frame_index_for_frame(
+                internal_frame,
+                &self.global_libs,
+                &mut self.string_table,
+            );
             prefix =
                 Some(thread.stack_index_for_stack(prefix, frame_index, frame_info.category_pair));
         }
@@ -543,11 +849,17 @@ impl Profile {
     // The processed profile format has all threads from all processes in a flattened threads list.
     // Each thread duplicates some information about its process, which allows the Firefox Profiler
     // UI to group threads from the same process.
-    fn sorted_threads(&self) -> (Vec<ThreadHandle>, Vec<usize>) {
+    fn sorted_threads(
+        &self,
+        processes: Option<&[ProcessHandle]>,
+    ) -> (Vec<ThreadHandle>, Vec<usize>) {
         let mut sorted_threads = Vec::with_capacity(self.threads.len());
         let mut first_thread_index_per_process = vec![0; self.processes.len()];
 
-        let mut sorted_processes: Vec<_> = (0..self.processes.len()).map(ProcessHandle).collect();
+        let mut sorted_processes: Vec<_> = match processes {
+            Some(processes) => processes.to_vec(),
+            None => (0..self.processes.len()).map(ProcessHandle).collect(),
+        };
         sorted_processes.sort_by(|a_handle, b_handle| {
             let a = &self.processes[a_handle.0];
             let b = &self.processes[b_handle.0];
@@ -578,43 +890,68 @@ impl Profile {
             threads: &self.threads,
             processes: &self.processes,
             categories: &self.categories,
+            string_table: &self.string_table,
             sorted_threads,
+            spill_file: self.spill_file.as_ref(),
         }
     }
 
     fn serializable_counters<'a>(
         &'a self,
         first_thread_index_per_process: &'a [usize],
+        processes: Option<&'a [ProcessHandle]>,
     ) -> SerializableProfileCountersProperty<'a> {
         SerializableProfileCountersProperty {
             counters: &self.counters,
             first_thread_index_per_process,
+            processes,
         }
     }
 
-    fn contains_js_function(&self) -> bool {
-        self.threads.iter().any(|t| t.contains_js_function())
-    }
-}
-
-impl Serialize for Profile {
-    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let (sorted_threads, first_thread_index_per_process) = self.sorted_threads();
+    fn serialize_processes<S: Serializer>(
+        &self,
+        serializer: S,
+        processes: Option<&[ProcessHandle]>,
+    ) -> Result<S::Ok, S::Error> {
+        let (sorted_threads, first_thread_index_per_process) = self.sorted_threads(processes);
         let mut map = serializer.serialize_map(None)?;
-        map.serialize_entry("meta", &SerializableProfileMeta(self))?;
+        map.serialize_entry("meta", &SerializableProfileMeta(self, &sorted_threads))?;
         map.serialize_entry("libs", &self.global_libs)?;
         map.serialize_entry("threads", &self.serializable_threads(&sorted_threads))?;
         map.serialize_entry("pages", &[] as &[()])?;
         map.serialize_entry("profilerOverhead", &[] as &[()])?;
         map.serialize_entry(
             "counters",
-            &self.serializable_counters(&first_thread_index_per_process),
+            &self.serializable_counters(&first_thread_index_per_process, processes),
         )?;
         map.end()
     }
+
+    fn contains_js_function(&self) -> bool {
+        self.threads.iter().any(|t| t.contains_js_function())
+    }
 }
 
-struct SerializableProfileMeta<'a>(&'a Profile);
+impl Serialize for Profile {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.serialize_processes(serializer, None)
+    }
+}
+
+/// Returned by [`Profile::process_subset`].
+pub struct ProfileProcessSubset<'a> {
+    profile: &'a Profile,
+    processes: &'a [ProcessHandle],
+}
+
+impl<'a> Serialize for ProfileProcessSubset<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.profile
+            .serialize_processes(serializer, Some(self.processes))
+    }
+}
+
+struct SerializableProfileMeta<'a>(&'a Profile, &'a [ThreadHandle]);
 
 impl<'a> Serialize for SerializableProfileMeta<'a> {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
@@ -649,32 +986,89 @@ impl<'a> Serialize for SerializableProfileMeta<'a> {
         map.serialize_entry("usesOnlyOneStackType", &(!self.0.contains_js_function()))?;
         map.serialize_entry("doesNotUseFrameImplementation", &true)?;
         map.serialize_entry("sourceCodeIsNotOnSearchfox", &true)?;
+        self.0.meta_info.serialize_entries::<S>(&mut map)?;
+        if self
+            .1
+            .iter()
+            .any(|thread| self.0.threads[thread.0].is_hidden_by_default())
+        {
+            // The indexes refer to the serialized (sorted) threads list.
+            let visible_threads: Vec<usize> = self
+                .1
+                .iter()
+                .enumerate()
+                .filter(|(_, thread)| !self.0.threads[thread.0].is_hidden_by_default())
+                .map(|(index, _)| index)
+                .collect();
+            map.serialize_entry("initialVisibleThreads", &visible_threads)?;
+        }
 
-        let mut marker_schemas: Vec<MarkerSchema> =
-            self.0.marker_schemas.values().cloned().collect();
-        marker_schemas.sort_by_key(|schema| schema.type_name);
+        let mut marker_schemas: Vec<SerializableMarkerSchema> = self
+            .0
+            .marker_schemas
+            .values()
+            .map(SerializableMarkerSchema::Static)
+            .chain(
+                self.0
+                    .runtime_marker_schemas
+                    .values()
+                    .filter(|schema| {
+                        !self
+                            .0
+                            .marker_schemas
+                            .contains_key(schema.type_name.as_str())
+                    })
+                    .map(SerializableMarkerSchema::Runtime),
+            )
+            .collect();
+        marker_schemas.sort_by(|a, b| a.type_name().cmp(b.type_name()));
         map.serialize_entry("markerSchema", &marker_schemas)?;
 
         map.end()
     }
 }
 
+/// The schemas of the marker types which are known at compile time, and of
+/// those which are only known at runtime, serialized into the same list.
+#[derive(serde_derive::Serialize)]
+#[serde(untagged)]
+enum SerializableMarkerSchema<'a> {
+    Static(&'a MarkerSchema),
+    Runtime(&'a RuntimeMarkerSchema),
+}
+
+impl SerializableMarkerSchema<'_> {
+    fn type_name(&self) -> &str {
+        match self {
+            SerializableMarkerSchema::Static(schema) => schema.type_name,
+            SerializableMarkerSchema::Runtime(schema) => &schema.type_name,
+        }
+    }
+}
+
 struct SerializableProfileThreadsProperty<'a> {
     threads: &'a [Thread],
     processes: &'a [Process],
     categories: &'a [Category],
+    string_table: &'a GlobalStringTable,
     sorted_threads: &'a [ThreadHandle],
+    spill_file: Option<&'a File>,
 }
 
 impl<'a> Serialize for SerializableProfileThreadsProperty<'a> {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut seq = serializer.serialize_seq(Some(self.threads.len()))?;
+        let mut seq = serializer.serialize_seq(Some(self.sorted_threads.len()))?;
 
         for thread in self.sorted_threads {
             let categories = &self.categories;
             let thread = &self.threads[thread.0];
-            let process = &self.processes[thread.process().0];
-            seq.serialize_element(&SerializableProfileThread(process, thread, categories))?;
+            seq.serialize_element(&SerializableProfileThread(
+                self.processes,
+                thread,
+                categories,
+                self.string_table,
+                self.spill_file,
+            ))?;
         }
 
         seq.end()
@@ -684,13 +1078,19 @@ impl<'a> Serialize for SerializableProfileThreadsProperty<'a> {
 struct SerializableProfileCountersProperty<'a> {
     counters: &'a [Counter],
     first_thread_index_per_process: &'a [usize],
+    processes: Option<&'a [ProcessHandle]>,
 }
 
 impl<'a> Serialize for SerializableProfileCountersProperty<'a> {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut seq = serializer.serialize_seq(Some(self.counters.len()))?;
+        let mut seq = serializer.serialize_seq(None)?;
 
         for counter in self.counters {
+            if let Some(processes) = self.processes {
+                if !processes.contains(&counter.process()) {
+                    continue;
+                }
+            }
             let main_thread_index = self.first_thread_index_per_process[counter.process().0];
             seq.serialize_element(&counter.as_serializable(main_thread_index))?;
         }
@@ -699,22 +1099,68 @@ impl<'a> Serialize for SerializableProfileCountersProperty<'a> {
     }
 }
 
-struct SerializableProfileThread<'a>(&'a Process, &'a Thread, &'a [Category]);
+struct SerializableProfileThread<'a>(
+    &'a [Process],
+    &'a Thread,
+    &'a [Category],
+    &'a GlobalStringTable,
+    Option<&'a File>,
+);
 
 impl<'a> Serialize for SerializableProfileThread<'a> {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let SerializableProfileThread(process, thread, categories) = self;
+        let SerializableProfileThread(processes, thread, categories, string_table, spill_file) =
+            self;
+        if let (Some(spilled), Some(file)) = (thread.spilled(), spill_file) {
+            let json = read_spilled_thread(file, spilled).map_err(S::Error::custom)?;
+            let raw = RawValue::from_string(json).map_err(S::Error::custom)?;
+            return raw.serialize(serializer);
+        }
+        let process = &processes[thread.process().0];
         let process_start_time = process.start_time();
         let process_end_time = process.end_time();
-        let process_name = process.name();
+        let process_name = process_name_with_ancestors(processes, process);
         let pid = process.pid();
+        let parent_pid = process.parent().map(|parent| processes[parent.0].pid());
         thread.serialize_with(
             serializer,
             categories,
+            string_table,
             process_start_time,
             process_end_time,
-            process_name,
+            &process_name,
             pid,
+            parent_pid,
+            *spill_file,
         )
     }
 }
+
+/// Returns the name of the process, prefixed with the names of its ancestors,
+/// for example "bash>make>cc1".
+fn process_name_with_ancestors<'a>(processes: &'a [Process], process: &'a Process) -> Cow<'a, str> {
+    // Limit the depth, so that a parent cycle can't make this loop forever.
+    const MAX_ANCESTORS: usize = 8;
+
+    let mut names = vec![process.name()];
+    let mut current = process;
+    while let Some(parent) = current.parent() {
+        if names.len() > MAX_ANCESTORS {
+            break;
+        }
+        current = &processes[parent.0];
+        names.push(current.name());
+    }
+    if names.len() == 1 {
+        return Cow::Borrowed(process.name());
+    }
+    names.reverse();
+    Cow::Owned(names.join(">"))
+}
+
+fn read_spilled_thread(mut file: &File, spilled: SpilledThread) -> std::io::Result<String> {
+    file.seek(SeekFrom::Start(spilled.offset))?;
+    let mut json = String::with_capacity(spilled.len as usize);
+    file.take(spilled.len).read_to_string(&mut json)?;
+    Ok(json)
+}
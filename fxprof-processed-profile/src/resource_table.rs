@@ -3,6 +3,7 @@ use serde::ser::{Serialize, SerializeMap, Serializer};
 use crate::fast_hash_map::FastHashMap;
 use crate::global_lib_table::{GlobalLibIndex, GlobalLibTable};
 use crate::serialization_helpers::SerializableSingleValueColumn;
+use crate::string_table::GlobalStringTable;
 use crate::thread_string_table::ThreadInternalStringIndex;
 use crate::thread_string_table::ThreadStringTable;
 
@@ -23,6 +24,7 @@ impl ResourceTable {
         lib_index: GlobalLibIndex,
         global_libs: &GlobalLibTable,
         thread_string_table: &mut ThreadStringTable,
+        global_string_table: &mut GlobalStringTable,
     ) -> ResourceIndex {
         let resource_libs = &mut self.resource_libs;
         let resource_names = &mut self.resource_names;
@@ -30,7 +32,8 @@ impl ResourceTable {
             let resource = ResourceIndex(resource_libs.len() as u32);
             let lib_name = &global_libs.get_lib(lib_index).unwrap().name;
             resource_libs.push(lib_index);
-            resource_names.push(thread_string_table.index_for_string(lib_name));
+            resource_names
+                .push(thread_string_table.index_for_string(lib_name, global_string_table));
             resource
         })
     }
@@ -1,14 +1,52 @@
-use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::io::{Read, Write};
+
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
 
 use crate::cpu_delta::CpuDelta;
+use crate::serialization_helpers::SerializablePermutedColumn;
+use crate::stack_table::{decode_index, encode_index};
 use crate::Timestamp;
 
+/// What the sample weights of a thread mean. See [`Profile::set_weight_type`](crate::Profile::set_weight_type).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeightType {
+    /// Each sample counts as many samples as its weight. This is the default.
+    Samples,
+    /// Each sample's weight is a duration in microseconds. The call tree shows
+    /// the summed up durations in milliseconds instead of sample counts.
+    TracingMicroseconds,
+    /// Each sample's weight is a number of bytes, e.g. the size of an allocation.
+    /// The call tree shows the summed up sizes.
+    Bytes,
+}
+
+impl Default for WeightType {
+    fn default() -> Self {
+        WeightType::Samples
+    }
+}
+
+impl WeightType {
+    fn json_name(self) -> &'static str {
+        match self {
+            WeightType::Samples => "samples",
+            WeightType::TracingMicroseconds => "tracing-ms",
+            WeightType::Bytes => "bytes",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct SampleTable {
+    weight_type: WeightType,
     sample_weights: Vec<i32>,
     sample_timestamps: Vec<Timestamp>,
     sample_stack_indexes: Vec<Option<usize>>,
     sample_cpu_deltas: Vec<CpuDelta>,
+    /// Set if a sample was added with an earlier timestamp than the sample
+    /// before it. This happens when the samples of several processes are
+    /// combined into one thread.
+    is_unsorted: bool,
 }
 
 impl SampleTable {
@@ -23,12 +61,85 @@ impl SampleTable {
         cpu_delta: CpuDelta,
         weight: i32,
     ) {
+        if self
+            .sample_timestamps
+            .last()
+            .map_or(false, |last| *last > timestamp)
+        {
+            self.is_unsorted = true;
+        }
         self.sample_weights.push(weight);
         self.sample_timestamps.push(timestamp);
         self.sample_stack_indexes.push(stack_index);
         self.sample_cpu_deltas.push(cpu_delta);
     }
 
+    pub fn set_weight_type(&mut self, weight_type: WeightType) {
+        self.weight_type = weight_type;
+    }
+
+    /// The number of samples in this table.
+    pub fn len(&self) -> usize {
+        self.sample_timestamps.len()
+    }
+
+    /// Writes the samples and removes them from this table. The samples are
+    /// read back with [`SampleTable::read_spilled`].
+    pub fn spill(&mut self, writer: &mut impl Write) -> std::io::Result<()> {
+        for i in 0..self.sample_timestamps.len() {
+            let timestamp = self.sample_timestamps[i].nanos_since_reference();
+            writer.write_all(&timestamp.to_le_bytes())?;
+            writer.write_all(&encode_index(self.sample_stack_indexes[i]).to_le_bytes())?;
+            writer.write_all(&self.sample_cpu_deltas[i].as_micros().to_le_bytes())?;
+            writer.write_all(&self.sample_weights[i].to_le_bytes())?;
+        }
+        self.sample_weights = Vec::new();
+        self.sample_timestamps = Vec::new();
+        self.sample_stack_indexes = Vec::new();
+        self.sample_cpu_deltas = Vec::new();
+        Ok(())
+    }
+
+    /// Reads `count` samples which were written by [`SampleTable::spill`] and
+    /// appends them.
+    pub fn read_spilled(&mut self, reader: &mut impl Read, count: usize) -> std::io::Result<()> {
+        for _ in 0..count {
+            let mut buf = [0; 28];
+            reader.read_exact(&mut buf)?;
+            let timestamp = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+            let stack_index = decode_index(u64::from_le_bytes(buf[8..16].try_into().unwrap()));
+            let cpu_delta = u64::from_le_bytes(buf[16..24].try_into().unwrap());
+            let weight = i32::from_le_bytes(buf[24..28].try_into().unwrap());
+            self.add_sample(
+                Timestamp::from_nanos_since_reference(timestamp),
+                stack_index,
+                CpuDelta::from_micros(cpu_delta),
+                weight,
+            );
+        }
+        Ok(())
+    }
+
+    /// Appends the samples of `other`.
+    pub fn extend_from(&mut self, other: &SampleTable) {
+        for i in 0..other.sample_timestamps.len() {
+            self.add_sample(
+                other.sample_timestamps[i],
+                other.sample_stack_indexes[i],
+                other.sample_cpu_deltas[i],
+                other.sample_weights[i],
+            );
+        }
+    }
+
+    /// An empty table with the same weight type.
+    pub fn new_like(&self) -> Self {
+        Self {
+            weight_type: self.weight_type,
+            ..Default::default()
+        }
+    }
+
     pub fn modify_last_sample(&mut self, timestamp: Timestamp, weight: i32) {
         *self.sample_weights.last_mut().unwrap() += weight;
         *self.sample_timestamps.last_mut().unwrap() = timestamp;
@@ -40,11 +151,65 @@ impl Serialize for SampleTable {
         let len = self.sample_timestamps.len();
         let mut map = serializer.serialize_map(None)?;
         map.serialize_entry("length", &len)?;
-        map.serialize_entry("stack", &self.sample_stack_indexes)?;
-        map.serialize_entry("time", &self.sample_timestamps)?;
-        map.serialize_entry("weight", &self.sample_weights)?;
-        map.serialize_entry("weightType", &"samples")?;
-        map.serialize_entry("threadCPUDelta", &self.sample_cpu_deltas)?;
+        if self.is_unsorted {
+            // The front-end expects the samples to be ordered by time.
+            let mut order: Vec<usize> = (0..len).collect();
+            order.sort_by_key(|index| self.sample_timestamps[*index]);
+            map.serialize_entry(
+                "stack",
+                &SerializablePermutedColumn(&self.sample_stack_indexes, &order),
+            )?;
+            map.serialize_entry(
+                "time",
+                &SerializablePermutedColumn(&self.sample_timestamps, &order),
+            )?;
+            map.serialize_entry(
+                "weight",
+                &SerializableWeightColumn(&self.sample_weights, Some(&order), self.weight_type),
+            )?;
+            map.serialize_entry("weightType", self.weight_type.json_name())?;
+            map.serialize_entry(
+                "threadCPUDelta",
+                &SerializablePermutedColumn(&self.sample_cpu_deltas, &order),
+            )?;
+        } else {
+            map.serialize_entry("stack", &self.sample_stack_indexes)?;
+            map.serialize_entry("time", &self.sample_timestamps)?;
+            map.serialize_entry(
+                "weight",
+                &SerializableWeightColumn(&self.sample_weights, None, self.weight_type),
+            )?;
+            map.serialize_entry("weightType", self.weight_type.json_name())?;
+            map.serialize_entry("threadCPUDelta", &self.sample_cpu_deltas)?;
+        }
         map.end()
     }
 }
+
+/// Serializes the weights in the order given by the indexes, if any. Durations
+/// in microseconds are converted to the milliseconds which the front-end expects.
+struct SerializableWeightColumn<'a>(&'a [i32], Option<&'a [usize]>, WeightType);
+
+impl<'a> Serialize for SerializableWeightColumn<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let SerializableWeightColumn(weights, order, weight_type) = *self;
+        if weight_type != WeightType::TracingMicroseconds {
+            return match order {
+                Some(order) => SerializablePermutedColumn(weights, order).serialize(serializer),
+                None => weights.serialize(serializer),
+            };
+        }
+        let mut seq = serializer.serialize_seq(Some(weights.len()))?;
+        let mut serialize_weight =
+            |weight: i32| seq.serialize_element(&(f64::from(weight) / 1000.0));
+        match order {
+            Some(order) => order
+                .iter()
+                .try_for_each(|index| serialize_weight(weights[*index]))?,
+            None => weights
+                .iter()
+                .try_for_each(|weight| serialize_weight(*weight))?,
+        }
+        seq.end()
+    }
+}
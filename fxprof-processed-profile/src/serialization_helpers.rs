@@ -14,6 +14,19 @@ impl<T: Serialize> Serialize for SerializableSingleValueColumn<T> {
     }
 }
 
+/// Serializes the elements of a column in the order given by `indexes`.
+pub struct SerializablePermutedColumn<'a, T: Serialize>(pub &'a [T], pub &'a [usize]);
+
+impl<'a, T: Serialize> Serialize for SerializablePermutedColumn<'a, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.1.len()))?;
+        for index in self.1 {
+            seq.serialize_element(&self.0[*index])?;
+        }
+        seq.end()
+    }
+}
+
 pub struct SerializableOptionalTimestampColumn<'a>(pub &'a [Option<Timestamp>]);
 
 impl<'a> Serialize for SerializableOptionalTimestampColumn<'a> {
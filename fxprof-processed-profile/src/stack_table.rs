@@ -1,7 +1,10 @@
+use std::io::{Read, Write};
+
 use serde::ser::{Serialize, SerializeMap, Serializer};
 
 use crate::category::{
     Category, CategoryHandle, CategoryPairHandle, SerializableSubcategoryColumn, Subcategory,
+    SubcategoryIndex,
 };
 use crate::fast_hash_map::FastHashMap;
 
@@ -12,6 +15,10 @@ pub struct StackTable {
     stack_categories: Vec<CategoryHandle>,
     stack_subcategories: Vec<Subcategory>,
 
+    /// The number of stacks which were written to the spill file. They come
+    /// before the stacks in this table.
+    spilled_len: usize,
+
     // (parent stack, frame_index) -> stack index
     index: FastHashMap<(Option<usize>, usize), usize>,
 }
@@ -36,7 +43,7 @@ impl StackTable {
                     None => Subcategory::Other(category),
                 };
 
-                let stack = self.stack_prefixes.len();
+                let stack = self.spilled_len + self.stack_prefixes.len();
                 self.stack_prefixes.push(prefix);
                 self.stack_frames.push(frame);
                 self.stack_categories.push(category);
@@ -47,6 +54,67 @@ impl StackTable {
         }
     }
 
+    /// The number of stacks in this table which haven't been spilled.
+    pub fn len(&self) -> usize {
+        self.stack_prefixes.len()
+    }
+
+    /// Writes the stacks which haven't been spilled yet and removes them from
+    /// this table. New stacks continue the numbering after the spilled ones.
+    /// Stacks which are seen again afterwards get a new index, because the
+    /// lookup table is cleared as well; the front-end handles the duplicates.
+    pub fn spill(&mut self, writer: &mut impl Write) -> std::io::Result<()> {
+        for i in 0..self.stack_prefixes.len() {
+            writer.write_all(&encode_index(self.stack_prefixes[i]).to_le_bytes())?;
+            writer.write_all(&(self.stack_frames[i] as u64).to_le_bytes())?;
+            writer.write_all(&self.stack_categories[i].0.to_le_bytes())?;
+            let subcategory = match self.stack_subcategories[i] {
+                Subcategory::Normal(index) => index.0 as i32,
+                Subcategory::Other(category) => -1 - category.0 as i32,
+            };
+            writer.write_all(&subcategory.to_le_bytes())?;
+        }
+        self.spilled_len += self.stack_prefixes.len();
+        self.stack_prefixes = Vec::new();
+        self.stack_frames = Vec::new();
+        self.stack_categories = Vec::new();
+        self.stack_subcategories = Vec::new();
+        self.index = FastHashMap::default();
+        Ok(())
+    }
+
+    /// Reads `count` stacks which were written by [`StackTable::spill`] and
+    /// appends them, keeping their indexes.
+    pub fn read_spilled(&mut self, reader: &mut impl Read, count: usize) -> std::io::Result<()> {
+        for _ in 0..count {
+            let mut buf = [0; 22];
+            reader.read_exact(&mut buf)?;
+            let prefix = decode_index(u64::from_le_bytes(buf[0..8].try_into().unwrap()));
+            let frame = u64::from_le_bytes(buf[8..16].try_into().unwrap()) as usize;
+            let category = CategoryHandle(u16::from_le_bytes(buf[16..18].try_into().unwrap()));
+            let subcategory = match i32::from_le_bytes(buf[18..22].try_into().unwrap()) {
+                index if index >= 0 => Subcategory::Normal(SubcategoryIndex(index as u8)),
+                other => Subcategory::Other(CategoryHandle((-1 - other) as u16)),
+            };
+            self.stack_prefixes.push(prefix);
+            self.stack_frames.push(frame);
+            self.stack_categories.push(category);
+            self.stack_subcategories.push(subcategory);
+        }
+        Ok(())
+    }
+
+    /// Appends the stacks of `other` without deduplicating them, so that
+    /// they keep their indexes.
+    pub fn extend_from(&mut self, other: &StackTable) {
+        self.stack_prefixes.extend_from_slice(&other.stack_prefixes);
+        self.stack_frames.extend_from_slice(&other.stack_frames);
+        self.stack_categories
+            .extend_from_slice(&other.stack_categories);
+        self.stack_subcategories
+            .extend_from_slice(&other.stack_subcategories);
+    }
+
     pub fn serialize_with_categories<'a>(
         &'a self,
         categories: &'a [Category],
@@ -78,3 +146,16 @@ impl<'a> Serialize for SerializableStackTable<'a> {
         map.end()
     }
 }
+
+/// Encodes an optional index for the spill file, with `u64::MAX` for `None`.
+pub(crate) fn encode_index(index: Option<usize>) -> u64 {
+    index.map_or(u64::MAX, |index| index as u64)
+}
+
+pub(crate) fn decode_index(value: u64) -> Option<usize> {
+    if value == u64::MAX {
+        None
+    } else {
+        Some(value as usize)
+    }
+}
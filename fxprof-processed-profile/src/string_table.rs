@@ -5,7 +5,7 @@ use serde::{Serialize, Serializer};
 use crate::fast_hash_map::FastHashMap;
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
-pub struct StringIndex(u32);
+pub struct StringIndex(pub(crate) u32);
 
 #[derive(Debug, Clone, Default)]
 pub struct StringTable {
@@ -1,8 +1,10 @@
 use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
 
-use serde::ser::{SerializeMap, Serializer};
-use serde_json::json;
+use serde::ser::{Error, SerializeMap, Serializer};
+use serde_json::{json, Value};
 
 use crate::category::{Category, CategoryPairHandle};
 use crate::cpu_delta::CpuDelta;
@@ -12,7 +14,7 @@ use crate::global_lib_table::GlobalLibTable;
 use crate::marker_table::MarkerTable;
 use crate::native_symbols::NativeSymbols;
 use crate::resource_table::ResourceTable;
-use crate::sample_table::SampleTable;
+use crate::sample_table::{SampleTable, WeightType};
 use crate::stack_table::StackTable;
 use crate::string_table::{GlobalStringIndex, GlobalStringTable};
 use crate::thread_string_table::{ThreadInternalStringIndex, ThreadStringTable};
@@ -25,6 +27,22 @@ pub struct ProcessHandle(pub(crate) usize);
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub struct CounterHandle(pub(crate) usize);
 
+/// The location of a thread's JSON in the profile's spill file.
+#[derive(Debug, Clone, Copy)]
+pub struct SpilledThread {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// The location of a chunk of a thread's sample and stack tables in the
+/// profile's spill file.
+#[derive(Debug, Clone, Copy)]
+struct SpilledTables {
+    offset: u64,
+    sample_count: usize,
+    stack_count: usize,
+}
+
 #[derive(Debug)]
 pub struct Thread {
     process: ProcessHandle,
@@ -33,6 +51,7 @@ pub struct Thread {
     start_time: Timestamp,
     end_time: Option<Timestamp>,
     is_main: bool,
+    hidden_by_default: bool,
     stack_table: StackTable,
     frame_table: FrameTable,
     func_table: FuncTable,
@@ -43,6 +62,13 @@ pub struct Thread {
     string_table: ThreadStringTable,
     last_sample_stack: Option<usize>,
     last_sample_was_zero_cpu: bool,
+    /// The latest sample timestamp. Remembered across spilling.
+    latest_sample_time: Option<Timestamp>,
+    spilled: Option<SpilledThread>,
+    /// The chunks of the sample and stack tables which were spilled, in order.
+    spilled_tables: Vec<SpilledTables>,
+    /// Remembered across spilling, because the func table is freed.
+    spilled_contains_js_function: bool,
 }
 
 impl Thread {
@@ -54,6 +80,7 @@ impl Thread {
             start_time,
             end_time: None,
             is_main,
+            hidden_by_default: false,
             stack_table: StackTable::new(),
             frame_table: FrameTable::new(),
             func_table: FuncTable::new(),
@@ -64,6 +91,10 @@ impl Thread {
             string_table: ThreadStringTable::new(),
             last_sample_stack: None,
             last_sample_was_zero_cpu: false,
+            latest_sample_time: None,
+            spilled: None,
+            spilled_tables: Vec::new(),
+            spilled_contains_js_function: false,
         }
     }
 
@@ -71,6 +102,18 @@ impl Thread {
         self.name = Some(name.to_string());
     }
 
+    pub fn set_hidden_by_default(&mut self, hidden: bool) {
+        self.hidden_by_default = hidden;
+    }
+
+    pub fn is_hidden_by_default(&self) -> bool {
+        self.hidden_by_default
+    }
+
+    pub fn set_weight_type(&mut self, weight_type: WeightType) {
+        self.samples.set_weight_type(weight_type);
+    }
+
     pub fn set_start_time(&mut self, start_time: Timestamp) {
         self.start_time = start_time;
     }
@@ -83,22 +126,19 @@ impl Thread {
         self.process
     }
 
-    pub fn convert_string_index(
-        &mut self,
-        global_table: &GlobalStringTable,
-        index: GlobalStringIndex,
-    ) -> ThreadInternalStringIndex {
-        self.string_table
-            .index_for_global_string(index, global_table)
+    pub fn convert_string_index(&mut self, index: GlobalStringIndex) -> ThreadInternalStringIndex {
+        self.string_table.index_for_global_string(index)
     }
 
     pub fn frame_index_for_frame(
         &mut self,
         frame: InternalFrame,
         global_libs: &GlobalLibTable,
+        global_string_table: &mut GlobalStringTable,
     ) -> usize {
         self.frame_table.index_for_frame(
             &mut self.string_table,
+            global_string_table,
             &mut self.resources,
             &mut self.func_table,
             &mut self.native_symbols,
@@ -128,9 +168,11 @@ impl Thread {
             .add_sample(timestamp, stack_index, cpu_delta, weight);
         self.last_sample_stack = stack_index;
         self.last_sample_was_zero_cpu = cpu_delta == CpuDelta::ZERO;
+        self.note_sample_time(timestamp);
     }
 
     pub fn add_sample_same_stack_zero_cpu(&mut self, timestamp: Timestamp, weight: i32) {
+        self.note_sample_time(timestamp);
         if self.last_sample_was_zero_cpu {
             self.samples.modify_last_sample(timestamp, weight);
         } else {
@@ -143,14 +185,35 @@ impl Thread {
 
     pub fn add_marker<T: ProfilerMarker>(
         &mut self,
+        global_string_table: &mut GlobalStringTable,
         category: CategoryHandle,
         name: &str,
         marker: T,
         timing: MarkerTiming,
         stack_index: Option<usize>,
     ) {
-        let name_string_index = self.string_table.index_for_string(name);
-        let mut data = marker.json_marker_data();
+        self.add_marker_data(
+            global_string_table,
+            category,
+            name,
+            marker.json_marker_data(),
+            timing,
+            stack_index,
+        );
+    }
+
+    pub fn add_marker_data(
+        &mut self,
+        global_string_table: &mut GlobalStringTable,
+        category: CategoryHandle,
+        name: &str,
+        mut data: Value,
+        timing: MarkerTiming,
+        stack_index: Option<usize>,
+    ) {
+        let name_string_index = self
+            .string_table
+            .index_for_string(name, global_string_table);
         if let Some(stack_index) = stack_index {
             if let Some(obj) = data.as_object_mut() {
                 obj.insert("cause".to_string(), json!({ "stack": stack_index }));
@@ -160,8 +223,99 @@ impl Thread {
             .add_marker(category, name_string_index, timing, data);
     }
 
+    fn note_sample_time(&mut self, timestamp: Timestamp) {
+        if self
+            .latest_sample_time
+            .map_or(true, |latest| latest < timestamp)
+        {
+            self.latest_sample_time = Some(timestamp);
+        }
+    }
+
+    pub fn latest_sample_time(&self) -> Option<Timestamp> {
+        self.latest_sample_time
+    }
+
     pub fn contains_js_function(&self) -> bool {
-        self.func_table.contains_js_function()
+        self.spilled_contains_js_function || self.func_table.contains_js_function()
+    }
+
+    pub fn spilled(&self) -> Option<SpilledThread> {
+        self.spilled
+    }
+
+    /// Frees the tables once the thread's JSON has been written to the spill file.
+    pub fn set_spilled(&mut self, spilled: SpilledThread) {
+        self.spilled_contains_js_function = self.contains_js_function();
+        self.spilled = Some(spilled);
+        self.stack_table = StackTable::new();
+        self.frame_table = FrameTable::new();
+        self.func_table = FuncTable::new();
+        self.samples = SampleTable::new();
+        self.markers = MarkerTable::new();
+        self.resources = ResourceTable::new();
+        self.native_symbols = NativeSymbols::new();
+        self.string_table = ThreadStringTable::new();
+        self.last_sample_stack = None;
+        self.spilled_tables = Vec::new();
+    }
+
+    /// Appends the sample and stack tables to the spill file and frees them.
+    /// The thread can keep receiving samples; stack indexes stay valid.
+    pub fn spill_tables(&mut self, mut file: &File) -> std::io::Result<()> {
+        let sample_count = self.samples.len();
+        let stack_count = self.stack_table.len();
+        if sample_count == 0 && stack_count == 0 {
+            return Ok(());
+        }
+        let offset = file.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriter::new(file);
+        self.samples.spill(&mut writer)?;
+        self.stack_table.spill(&mut writer)?;
+        writer.flush()?;
+        self.spilled_tables.push(SpilledTables {
+            offset,
+            sample_count,
+            stack_count,
+        });
+        // The last sample is in the file now, so it can't be modified.
+        self.last_sample_was_zero_cpu = false;
+        Ok(())
+    }
+
+    /// Returns the complete sample and stack tables, including the spilled
+    /// chunks, which are read back from the spill file.
+    fn full_tables(
+        &self,
+        spill_file: Option<&File>,
+    ) -> std::io::Result<(Cow<'_, SampleTable>, Cow<'_, StackTable>)> {
+        let file = match (self.spilled_tables.is_empty(), spill_file) {
+            (true, _) => {
+                return Ok((
+                    Cow::Borrowed(&self.samples),
+                    Cow::Borrowed(&self.stack_table),
+                ))
+            }
+            (false, Some(file)) => file,
+            (false, None) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "the thread's tables were spilled, but there is no spill file",
+                ))
+            }
+        };
+        let mut samples = self.samples.new_like();
+        let mut stack_table = StackTable::new();
+        for chunk in &self.spilled_tables {
+            let mut file_ref = file;
+            file_ref.seek(SeekFrom::Start(chunk.offset))?;
+            let mut reader = BufReader::new(file_ref);
+            samples.read_spilled(&mut reader, chunk.sample_count)?;
+            stack_table.read_spilled(&mut reader, chunk.stack_count)?;
+        }
+        samples.extend_from(&self.samples);
+        stack_table.extend_from(&self.stack_table);
+        Ok((Cow::Owned(samples), Cow::Owned(stack_table)))
     }
 
     pub fn cmp_for_json_order(&self, other: &Thread) -> Ordering {
@@ -181,15 +335,20 @@ impl Thread {
         self.tid.cmp(&other.tid)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn serialize_with<S: Serializer>(
         &self,
         serializer: S,
         categories: &[Category],
+        global_string_table: &GlobalStringTable,
         process_start_time: Timestamp,
         process_end_time: Option<Timestamp>,
         process_name: &str,
         pid: &str,
+        parent_pid: Option<&str>,
+        spill_file: Option<&File>,
     ) -> Result<S::Ok, S::Error> {
+        let (samples, stack_table) = self.full_tables(spill_file).map_err(S::Error::custom)?;
         let thread_name: Cow<str> = match (self.is_main, &self.name) {
             (true, _) => process_name.into(),
             (false, Some(name)) => name.into(),
@@ -207,6 +366,9 @@ impl Thread {
         map.serialize_entry("isMainThread", &self.is_main)?;
         map.serialize_entry("nativeSymbols", &self.native_symbols)?;
         map.serialize_entry("pausedRanges", &[] as &[()])?;
+        if let Some(parent_pid) = parent_pid {
+            map.serialize_entry("parentPid", parent_pid)?;
+        }
         map.serialize_entry("pid", &pid)?;
         map.serialize_entry("processName", process_name)?;
         map.serialize_entry("processShutdownTime", &process_end_time)?;
@@ -214,12 +376,15 @@ impl Thread {
         map.serialize_entry("processType", &"default")?;
         map.serialize_entry("registerTime", &thread_register_time)?;
         map.serialize_entry("resourceTable", &self.resources)?;
-        map.serialize_entry("samples", &self.samples)?;
+        map.serialize_entry("samples", &*samples)?;
         map.serialize_entry(
             "stackTable",
-            &self.stack_table.serialize_with_categories(categories),
+            &stack_table.serialize_with_categories(categories),
+        )?;
+        map.serialize_entry(
+            "stringArray",
+            &self.string_table.as_serializable(global_string_table),
         )?;
-        map.serialize_entry("stringArray", &self.string_table)?;
         map.serialize_entry("tid", &self.tid)?;
         map.serialize_entry("unregisterTime", &thread_unregister_time)?;
         map.end()
@@ -1,7 +1,7 @@
-use serde::ser::{Serialize, Serializer};
+use serde::ser::{Serialize, SerializeSeq, Serializer};
 
+use crate::fast_hash_map::FastHashMap;
 use crate::string_table::{GlobalStringIndex, GlobalStringTable, StringIndex};
-use crate::{fast_hash_map::FastHashMap, string_table::StringTable};
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub struct ThreadInternalStringIndex(pub StringIndex);
@@ -12,9 +12,15 @@ impl Serialize for ThreadInternalStringIndex {
     }
 }
 
+/// The string array of a thread.
+///
+/// The strings themselves live in the profile's [`GlobalStringTable`], so that
+/// strings which are used by many threads, such as library names and function
+/// names, are only kept in memory once. The thread only stores which global
+/// strings it uses, in the order of its own string indexes.
 #[derive(Debug, Clone, Default)]
 pub struct ThreadStringTable {
-    table: StringTable,
+    local_to_global_string: Vec<GlobalStringIndex>,
     global_to_local_string: FastHashMap<GlobalStringIndex, ThreadInternalStringIndex>,
 }
 
@@ -23,28 +29,52 @@ impl ThreadStringTable {
         Default::default()
     }
 
-    pub fn index_for_string(&mut self, s: &str) -> ThreadInternalStringIndex {
-        ThreadInternalStringIndex(self.table.index_for_string(s))
+    pub fn index_for_string(
+        &mut self,
+        s: &str,
+        global_table: &mut GlobalStringTable,
+    ) -> ThreadInternalStringIndex {
+        self.index_for_global_string(global_table.index_for_string(s))
     }
 
     pub fn index_for_global_string(
         &mut self,
         global_index: GlobalStringIndex,
-        global_table: &GlobalStringTable,
     ) -> ThreadInternalStringIndex {
-        let table = &mut self.table;
+        let local_to_global_string = &mut self.local_to_global_string;
         *self
             .global_to_local_string
             .entry(global_index)
             .or_insert_with(|| {
-                let s = global_table.get_string(global_index).unwrap();
-                ThreadInternalStringIndex(table.index_for_string(s))
+                let local_index = local_to_global_string.len() as u32;
+                local_to_global_string.push(global_index);
+                ThreadInternalStringIndex(StringIndex(local_index))
             })
     }
+
+    pub fn as_serializable<'a>(
+        &'a self,
+        global_table: &'a GlobalStringTable,
+    ) -> impl Serialize + 'a {
+        SerializableThreadStringTable {
+            table: self,
+            global_table,
+        }
+    }
+}
+
+struct SerializableThreadStringTable<'a> {
+    table: &'a ThreadStringTable,
+    global_table: &'a GlobalStringTable,
 }
 
-impl Serialize for ThreadStringTable {
+impl<'a> Serialize for SerializableThreadStringTable<'a> {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        self.table.serialize(serializer)
+        let strings = &self.table.local_to_global_string;
+        let mut seq = serializer.serialize_seq(Some(strings.len()))?;
+        for global_index in strings {
+            seq.serialize_element(self.global_table.get_string(*global_index).unwrap())?;
+        }
+        seq.end()
     }
 }
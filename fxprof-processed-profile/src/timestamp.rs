@@ -18,6 +18,11 @@ impl Timestamp {
             nanos: (millis * 1_000_000.0) as u64,
         }
     }
+
+    /// The number of nanoseconds since the profile's reference timestamp.
+    pub fn nanos_since_reference(&self) -> u64 {
+        self.nanos
+    }
 }
 
 impl Serialize for Timestamp {
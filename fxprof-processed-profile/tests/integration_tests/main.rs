@@ -5,8 +5,9 @@ use serde_json::json;
 use fxprof_processed_profile::{
     CategoryColor, CategoryHandle, CpuDelta, Frame, FrameFlags, FrameInfo, LibraryInfo,
     MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
-    MarkerStaticField, MarkerTiming, Profile, ProfilerMarker, ReferenceTimestamp, SamplingInterval,
-    Symbol, SymbolTable, Timestamp,
+    MarkerStaticField, MarkerTiming, Profile, ProfilerMarker, ReferenceTimestamp,
+    RuntimeMarkerField, RuntimeMarkerSchema, SamplingInterval, Symbol, SymbolTable, Timestamp,
+    WeightType,
 };
 
 use std::sync::Arc;
@@ -1594,3 +1595,502 @@ fn profile_counters_with_sorted_processes() {
         )
     )
 }
+
+#[test]
+fn profile_with_spilled_threads() {
+    let mut profile = Profile::new(
+        "test with spilled threads",
+        ReferenceTimestamp::from_millis_since_unix_epoch(1636162232627.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+    let main_thread = profile.add_thread(
+        process,
+        123,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    let other_thread = profile.add_thread(
+        process,
+        124,
+        Timestamp::from_millis_since_reference(1.0),
+        false,
+    );
+    profile.set_thread_name(other_thread, "Worker");
+    let label = profile.intern_string("Some label string");
+    let category = profile.add_category("Regular", CategoryColor::Green);
+    for (thread, time) in [(main_thread, 1.0), (other_thread, 2.0), (main_thread, 3.0)] {
+        profile.add_sample(
+            thread,
+            Timestamp::from_millis_since_reference(time),
+            vec![FrameInfo {
+                frame: Frame::Label(label),
+                category_pair: category.into(),
+                flags: FrameFlags::IS_JS,
+            }]
+            .into_iter(),
+            CpuDelta::ZERO,
+            1,
+        );
+    }
+    profile.add_marker(
+        other_thread,
+        CategoryHandle::OTHER,
+        "Marker",
+        TextMarker("Some text".to_string()),
+        MarkerTiming::Instant(Timestamp::from_millis_since_reference(2.0)),
+    );
+    let expected = serde_json::to_value(&profile).unwrap();
+
+    profile.set_spill_file(tempfile::tempfile().unwrap());
+    profile.spill_process_threads(process).unwrap();
+    assert_json_eq!(profile, expected);
+}
+
+#[test]
+fn profile_with_spilled_thread_tables() {
+    fn build_profile(spill: bool) -> Profile {
+        let mut profile = Profile::new(
+            "test with spilled thread tables",
+            ReferenceTimestamp::from_millis_since_unix_epoch(1636162232627.0),
+            SamplingInterval::from_millis(1),
+        );
+        if spill {
+            profile.set_spill_file(tempfile::tempfile().unwrap());
+        }
+        let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            123,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let outer = profile.intern_string("outer");
+        let inner = profile.intern_string("inner");
+        let category = profile.add_category("Regular", CategoryColor::Green);
+        let frame = |label| FrameInfo {
+            frame: Frame::Label(label),
+            category_pair: category.into(),
+            flags: FrameFlags::empty(),
+        };
+        // The last sample is earlier than the ones before, so the samples
+        // from the spill file and from memory have to be sorted together.
+        let samples = [
+            (1.0, vec![frame(outer)]),
+            (2.0, vec![frame(outer), frame(inner)]),
+            (4.0, vec![frame(outer), frame(inner)]),
+            (3.0, vec![frame(inner)]),
+        ];
+        for (time, stack) in samples {
+            profile.add_sample(
+                thread,
+                Timestamp::from_millis_since_reference(time),
+                stack.into_iter(),
+                CpuDelta::from_micros(10),
+                1,
+            );
+            profile.add_sample_same_stack_zero_cpu(
+                thread,
+                Timestamp::from_millis_since_reference(time + 0.5),
+                1,
+            );
+            if spill {
+                profile.spill_process_thread_tables(process).unwrap();
+            }
+        }
+        profile
+    }
+
+    /// The samples of the only thread, with their stacks as lists of frame indexes.
+    fn samples_with_stacks(profile: &Profile) -> Vec<(f64, Vec<u64>, u64)> {
+        let json = serde_json::to_value(profile).unwrap();
+        let thread = &json["threads"][0];
+        let stack_table = &thread["stackTable"];
+        let samples = &thread["samples"];
+        (0..samples["length"].as_u64().unwrap() as usize)
+            .map(|i| {
+                let mut frames = Vec::new();
+                let mut stack = samples["stack"][i].as_u64();
+                while let Some(index) = stack {
+                    frames.push(stack_table["frame"][index as usize].as_u64().unwrap());
+                    stack = stack_table["prefix"][index as usize].as_u64();
+                }
+                (
+                    samples["time"][i].as_f64().unwrap(),
+                    frames,
+                    samples["threadCPUDelta"][i].as_u64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    let expected = samples_with_stacks(&build_profile(false));
+    assert_eq!(expected.len(), 8);
+    assert_eq!(samples_with_stacks(&build_profile(true)), expected);
+}
+
+#[test]
+fn profile_with_process_parents() {
+    let mut profile = Profile::new(
+        "test with process parents",
+        ReferenceTimestamp::from_millis_since_unix_epoch(1636162232627.0),
+        SamplingInterval::from_millis(1),
+    );
+    let make = profile.add_process("make", 100, Timestamp::from_millis_since_reference(0.0));
+    let cc1 = profile.add_process("cc1", 101, Timestamp::from_millis_since_reference(1.0));
+    let as_process = profile.add_process("as", 102, Timestamp::from_millis_since_reference(2.0));
+    profile.set_process_parent(cc1, make);
+    profile.set_process_parent(as_process, cc1);
+    for (process, pid) in [(make, 100), (cc1, 101), (as_process, 102)] {
+        profile.add_thread(
+            process,
+            pid,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+    }
+    assert_eq!(profile.process_parent(cc1), Some(make));
+    assert_eq!(profile.process_parent(make), None);
+
+    let threads = serde_json::to_value(&profile).unwrap()["threads"].clone();
+    let summary: Vec<_> = threads
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|thread| {
+            (
+                thread["processName"].as_str().unwrap().to_string(),
+                thread["parentPid"].as_str().map(str::to_string),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("make".to_string(), None),
+            ("make>cc1".to_string(), Some("100".to_string())),
+            ("make>cc1>as".to_string(), Some("101".to_string())),
+        ]
+    );
+}
+
+#[test]
+fn profile_with_hidden_threads() {
+    let mut profile = Profile::new(
+        "test with hidden threads",
+        ReferenceTimestamp::from_millis_since_unix_epoch(1636162232627.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("java", 100, Timestamp::from_millis_since_reference(0.0));
+    let gc_thread = profile.add_thread(
+        process,
+        101,
+        Timestamp::from_millis_since_reference(0.0),
+        false,
+    );
+    let main_thread = profile.add_thread(
+        process,
+        100,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    profile.set_thread_name(gc_thread, "GC Thread#0");
+    profile.set_thread_name(main_thread, "java");
+
+    let meta = serde_json::to_value(&profile).unwrap()["meta"].clone();
+    assert_eq!(meta.get("initialVisibleThreads"), None);
+
+    profile.set_thread_hidden_by_default(gc_thread, true);
+    let profile_json = serde_json::to_value(&profile).unwrap();
+    // The main thread is sorted first.
+    assert_eq!(profile_json["threads"][1]["name"], "GC Thread#0");
+    assert_eq!(profile_json["meta"]["initialVisibleThreads"], json!([0]));
+}
+
+#[test]
+fn profile_with_duration_weights() {
+    let mut profile = Profile::new(
+        "test with duration weights",
+        ReferenceTimestamp::from_millis_since_unix_epoch(1636162232627.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+    let thread = profile.add_thread(
+        process,
+        123,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    profile.set_weight_type(WeightType::TracingMicroseconds);
+    let category = profile.add_category("Regular", CategoryColor::Green);
+    let label = profile.intern_string("blocked");
+    profile.add_sample(
+        thread,
+        Timestamp::from_millis_since_reference(1.0),
+        vec![FrameInfo {
+            frame: Frame::Label(label),
+            category_pair: category.into(),
+            flags: FrameFlags::empty(),
+        }]
+        .into_iter(),
+        CpuDelta::ZERO,
+        2500,
+    );
+
+    let samples = serde_json::to_value(&profile).unwrap()["threads"][0]["samples"].clone();
+    assert_eq!(samples["weightType"], "tracing-ms");
+    assert_eq!(samples["weight"], json!([2.5]));
+}
+
+#[test]
+fn profile_with_lib_file_size() {
+    let mut profile = Profile::new(
+        "test with lib file size",
+        ReferenceTimestamp::from_millis_since_unix_epoch(1636162232627.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+    let thread = profile.add_thread(
+        process,
+        123,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    let lib = profile.add_lib(LibraryInfo {
+        name: "libc.so.6".to_string(),
+        debug_name: "libc.so.6".to_string(),
+        path: "/usr/lib/libc.so.6".to_string(),
+        debug_path: "/usr/lib/libc.so.6".to_string(),
+        debug_id: DebugId::nil(),
+        code_id: None,
+        arch: None,
+        symbol_table: None,
+    });
+    profile.set_lib_file_size(lib, 2105184);
+    let category = profile.add_category("Regular", CategoryColor::Green);
+    profile.add_sample(
+        thread,
+        Timestamp::from_millis_since_reference(1.0),
+        vec![FrameInfo {
+            frame: Frame::RelativeAddressFromInstructionPointer(lib, 0x1234),
+            category_pair: category.into(),
+            flags: FrameFlags::empty(),
+        }]
+        .into_iter(),
+        CpuDelta::ZERO,
+        1,
+    );
+
+    let libs = serde_json::to_value(&profile).unwrap()["libs"].clone();
+    assert_eq!(libs[0]["path"], "/usr/lib/libc.so.6");
+    assert_eq!(libs[0]["fileSize"], 2105184);
+}
+
+#[test]
+fn profile_with_unsorted_samples() {
+    let mut profile = Profile::new(
+        "test with unsorted samples",
+        ReferenceTimestamp::from_millis_since_unix_epoch(1636162232627.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+    let thread = profile.add_thread(
+        process,
+        123,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    let category = profile.add_category("Regular", CategoryColor::Green);
+    // The samples of two overlapping processes which share one thread.
+    for (time, label) in [(1.0, "a"), (3.0, "a"), (2.0, "b"), (4.0, "b")] {
+        let label = profile.intern_string(label);
+        profile.add_sample(
+            thread,
+            Timestamp::from_millis_since_reference(time),
+            vec![FrameInfo {
+                frame: Frame::Label(label),
+                category_pair: category.into(),
+                flags: FrameFlags::empty(),
+            }]
+            .into_iter(),
+            CpuDelta::from_millis(time),
+            1,
+        );
+    }
+
+    let samples = serde_json::to_value(&profile).unwrap()["threads"][0]["samples"].clone();
+    assert_eq!(samples["time"], json!([1.0, 2.0, 3.0, 4.0]));
+    assert_eq!(samples["stack"], json!([0, 1, 0, 1]));
+    assert_eq!(samples["threadCPUDelta"], json!([1000, 2000, 3000, 4000]));
+}
+
+#[test]
+fn profile_with_meta_info() {
+    let mut profile = Profile::new(
+        "test with meta info",
+        ReferenceTimestamp::from_millis_since_unix_epoch(1636162232627.0),
+        SamplingInterval::from_millis(1),
+    );
+    profile.set_os_name("Ubuntu 22.04.4 LTS (Linux 6.5.0-35-generic)");
+    profile.set_cpu_name("AMD Ryzen 9 7950X 16-Core Processor");
+    profile.set_cpu_counts(Some(16), Some(32));
+    profile.set_main_memory(64 * 1024 * 1024 * 1024);
+    profile.set_arguments("samply record ./app");
+    profile.add_extra_info("Recording", "samply version", "0.11.0");
+    profile.add_extra_info("Environment", "LANG", "C.UTF-8");
+    profile.add_extra_info("Recording", "Sampling rate", "1000 Hz");
+
+    let meta = serde_json::to_value(&profile).unwrap()["meta"].clone();
+    assert_eq!(meta["oscpu"], "Ubuntu 22.04.4 LTS (Linux 6.5.0-35-generic)");
+    assert_eq!(meta["CPUName"], "AMD Ryzen 9 7950X 16-Core Processor");
+    assert_eq!(meta["physicalCPUs"], 16);
+    assert_eq!(meta["logicalCPUs"], 32);
+    assert_eq!(meta["mainMemory"], 68719476736u64);
+    assert_eq!(meta["arguments"], "samply record ./app");
+    assert_json_eq!(
+        meta["extra"],
+        json!([
+            {
+                "label": "Recording",
+                "entries": [
+                    { "label": "samply version", "format": "string", "value": "0.11.0" },
+                    { "label": "Sampling rate", "format": "string", "value": "1000 Hz" }
+                ]
+            },
+            {
+                "label": "Environment",
+                "entries": [
+                    { "label": "LANG", "format": "string", "value": "C.UTF-8" }
+                ]
+            }
+        ])
+    );
+}
+
+#[test]
+fn profile_with_interned_stacks() {
+    fn build_profile(use_stack_handles: bool) -> Profile {
+        let mut profile = Profile::new(
+            "test with interned stacks",
+            ReferenceTimestamp::from_millis_since_unix_epoch(1636162232627.0),
+            SamplingInterval::from_millis(1),
+        );
+        let category = profile.add_category("Regular", CategoryColor::Green);
+        let outer = profile.intern_string("outer");
+        let inner = profile.intern_string("inner");
+        for pid in [123, 124] {
+            let process =
+                profile.add_process("compiler", pid, Timestamp::from_millis_since_reference(0.0));
+            let thread = profile.add_thread(
+                process,
+                pid,
+                Timestamp::from_millis_since_reference(0.0),
+                true,
+            );
+            let frames = || {
+                [outer, inner].into_iter().map(|label| FrameInfo {
+                    frame: Frame::Label(label),
+                    category_pair: category.into(),
+                    flags: FrameFlags::empty(),
+                })
+            };
+            let stack = profile.intern_stack_frames(thread, frames());
+            for time in [1.0, 2.0] {
+                let timestamp = Timestamp::from_millis_since_reference(time);
+                if use_stack_handles {
+                    profile.add_sample_with_stack(thread, timestamp, stack, CpuDelta::ZERO, 1);
+                } else {
+                    profile.add_sample(thread, timestamp, frames(), CpuDelta::ZERO, 1);
+                }
+            }
+        }
+        profile
+    }
+
+    let profile = build_profile(true);
+    let json = serde_json::to_value(&profile).unwrap();
+    for thread in json["threads"].as_array().unwrap() {
+        // Each thread only has the strings it uses, even though the strings are
+        // stored in the profile's string table.
+        assert_eq!(thread["stringArray"], json!(["outer", "inner"]));
+        assert_eq!(thread["stackTable"]["prefix"], json!([null, 0]));
+        assert_eq!(thread["samples"]["stack"], json!([1, 1]));
+    }
+    assert_json_eq!(profile, serde_json::to_value(build_profile(false)).unwrap());
+}
+
+#[test]
+fn profile_with_runtime_marker_schemas() {
+    let mut profile = Profile::new(
+        "test with runtime marker schemas",
+        ReferenceTimestamp::from_millis_since_unix_epoch(1636162232627.0),
+        SamplingInterval::from_millis(1),
+    );
+    let schema = |fields: &[(&str, MarkerFieldFormat)]| RuntimeMarkerSchema {
+        type_name: "Request".to_owned(),
+        locations: vec![MarkerLocation::MarkerTable],
+        chart_label: None,
+        tooltip_label: None,
+        table_label: Some("{marker.name}".to_owned()),
+        fields: fields
+            .iter()
+            .map(|(key, format)| RuntimeMarkerField {
+                key: key.to_string(),
+                label: key.to_string(),
+                format: format.clone(),
+                searchable: false,
+            })
+            .collect(),
+    };
+    // Two processes report markers of the same type, with different fields.
+    for (pid, fields) in [
+        (
+            123,
+            schema(&[
+                ("url", MarkerFieldFormat::Url),
+                ("status", MarkerFieldFormat::Integer),
+            ]),
+        ),
+        (
+            124,
+            schema(&[
+                ("status", MarkerFieldFormat::Decimal),
+                ("size", MarkerFieldFormat::Bytes),
+            ]),
+        ),
+    ] {
+        let process =
+            profile.add_process("server", pid, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            pid,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        profile.add_marker_with_schema(
+            thread,
+            CategoryHandle::OTHER,
+            "GET",
+            &fields,
+            json!({ "type": "Request" }),
+            MarkerTiming::Instant(Timestamp::from_millis_since_reference(1.0)),
+        );
+    }
+
+    let json = serde_json::to_value(&profile).unwrap();
+    assert_eq!(
+        json["meta"]["markerSchema"],
+        json!([
+            {
+                "name": "Request",
+                "display": ["marker-table"],
+                "tableLabel": "{marker.name}",
+                "data": [
+                    { "key": "url", "label": "url", "format": "url", "searchable": false },
+                    { "key": "status", "label": "status", "format": "decimal", "searchable": false },
+                    { "key": "size", "label": "size", "format": "bytes", "searchable": false }
+                ]
+            }
+        ])
+    );
+}
@@ -138,6 +138,10 @@
 //!     fn location_for_breakpad_symindex(&self) -> Option<Self> {
 //!         Some(Self(self.0.with_extension("symindex")))
 //!     }
+//!
+//!     fn location_for_def_file(&self) -> Option<Self> {
+//!         Some(Self(self.0.with_extension("def")))
+//!     }
 //! }
 //! ```
 
@@ -185,6 +189,8 @@ impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> Api<'a, 'h, H> {
     /// The following "URLs" are supported:
     ///  - `/symbolicate/v5`: This API is documented at <https://tecken.readthedocs.io/en/latest/symbolication.html>.
     ///    The returned data has two extra fields: inlines (per address) and module_errors (per job).
+    ///    The request can have two extra fields: `maxInlineDepth`, which limits the number of
+    ///    inline frames per address, and `skipInlineResolution`, which skips the debug info lookup.
     ///  - `/source/v1`: Experimental API. Symbolicates an address and lets you read one of the files in the
     ///    symbol information for that address.
     ///  - `/asm/v1`: Experimental API. Symbolicates an address and lets you read one of the files in the
@@ -1,6 +1,8 @@
 use crate::to_debug_id;
 use crate::{api_file_path::to_api_file_path, error::Error};
-use samply_symbols::{FileAndPathHelper, FramesLookupResult, LibraryInfo, SymbolManager};
+use samply_symbols::{
+    FileAndPathHelper, FramesLookupResult, InlineFrameLimit, LibraryInfo, SymbolManager,
+};
 use std::collections::HashMap;
 use std::num::NonZeroU32;
 
@@ -41,7 +43,7 @@ impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> SymbolicateApi<'a, 'h, H> {
     ) -> Result<response_json::Response, Error> {
         let requested_addresses = gather_requested_addresses(request)?;
         let symbolicated_addresses = self
-            .symbolicate_requested_addresses(requested_addresses)
+            .symbolicate_requested_addresses(requested_addresses, request.inline_frame_limit())
             .await;
         Ok(create_response(request, symbolicated_addresses))
     }
@@ -49,11 +51,12 @@ impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> SymbolicateApi<'a, 'h, H> {
     async fn symbolicate_requested_addresses(
         &self,
         requested_addresses: HashMap<Lib, Vec<u32>>,
+        inline_frame_limit: Option<InlineFrameLimit>,
     ) -> HashMap<Lib, Result<LookedUpAddresses, samply_symbols::Error>> {
         let mut symbolicated_addresses = HashMap::new();
         for (lib, addresses) in requested_addresses.into_iter() {
             let address_results = self
-                .symbolicate_requested_addresses_for_lib(&lib, addresses)
+                .symbolicate_requested_addresses_for_lib(&lib, addresses, inline_frame_limit)
                 .await;
             symbolicated_addresses.insert(lib, address_results);
         }
@@ -64,6 +67,7 @@ impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> SymbolicateApi<'a, 'h, H> {
         &self,
         lib: &Lib,
         mut addresses: Vec<u32>,
+        inline_frame_limit: Option<InlineFrameLimit>,
     ) -> Result<LookedUpAddresses, samply_symbols::Error> {
         // Sort the addresses before the lookup, to have a higher chance of hitting
         // the same external file for subsequent addresses.
@@ -75,6 +79,7 @@ impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> SymbolicateApi<'a, 'h, H> {
         let mut symbolication_result = LookedUpAddresses::for_addresses(&addresses);
         let mut external_addresses = Vec::new();
         let debug_file_location;
+        let effective_inline_frame_limit;
 
         // Do the synchronous work first, and keep the symbol_map in a scope without
         // any other await calls so that the Rust compiler can see that the symbol
@@ -87,7 +92,11 @@ impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> SymbolicateApi<'a, 'h, H> {
                 debug_id: Some(debug_id),
                 ..Default::default()
             };
-            let symbol_map = self.symbol_manager.load_symbol_map(&info).await?;
+            let mut symbol_map = self.symbol_manager.load_symbol_map(&info).await?;
+            if let Some(inline_frame_limit) = inline_frame_limit {
+                symbol_map.set_inline_frame_limit(inline_frame_limit);
+            }
+            effective_inline_frame_limit = symbol_map.inline_frame_limit();
             debug_file_location = symbol_map.debug_file_location().clone();
 
             symbolication_result.set_total_symbol_count(symbol_map.symbol_count() as u32);
@@ -120,11 +129,12 @@ impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> SymbolicateApi<'a, 'h, H> {
         external_addresses.sort_unstable_by(|(_, a), (_, b)| a.cmp(b));
 
         for (address, ext_address) in external_addresses {
-            if let Some(frames) = self
+            if let Some(mut frames) = self
                 .symbol_manager
                 .lookup_external(&debug_file_location, &ext_address)
                 .await
             {
+                effective_inline_frame_limit.truncate_frames(&mut frames);
                 symbolication_result.add_address_debug_info(address, frames);
             }
         }
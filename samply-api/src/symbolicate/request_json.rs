@@ -1,17 +1,43 @@
+use samply_symbols::InlineFrameLimit;
 use serde_derive::Deserialize;
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Request {
+    #[serde(flatten)]
+    pub jobs: Jobs,
+    /// The maximum number of inline frames per address. This is not part of the
+    /// Tecken API.
+    #[serde(default)]
+    pub max_inline_depth: Option<u32>,
+    /// Only return the symbols, without inline frames or file / line information.
+    /// This is much faster for big requests. This is not part of the Tecken API.
+    #[serde(default)]
+    pub skip_inline_resolution: bool,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
-pub enum Request {
+pub enum Jobs {
     WithJobsList { jobs: Vec<Job> },
     JustOneJob(Job),
 }
 
 impl Request {
     pub fn jobs(&self) -> JobIterator {
-        match self {
-            Request::WithJobsList { jobs } => JobIterator::WithJobsList(jobs.iter()),
-            Request::JustOneJob(job) => JobIterator::JustOneJob(std::iter::once(job)),
+        match &self.jobs {
+            Jobs::WithJobsList { jobs } => JobIterator::WithJobsList(jobs.iter()),
+            Jobs::JustOneJob(job) => JobIterator::JustOneJob(std::iter::once(job)),
+        }
+    }
+
+    /// The inline frame limit requested by this request, if any. If the request
+    /// doesn't specify one, the limit of the `SymbolManager` is used.
+    pub fn inline_frame_limit(&self) -> Option<InlineFrameLimit> {
+        if self.skip_inline_resolution {
+            Some(InlineFrameLimit::SkipInlineResolution)
+        } else {
+            self.max_inline_depth.map(InlineFrameLimit::MaxDepth)
         }
     }
 }
@@ -120,4 +146,26 @@ mod test {
         assert_eq!(r.jobs().count(), 1);
         Ok(())
     }
+
+    #[test]
+    fn parse_inline_frame_limit() -> Result<()> {
+        use samply_symbols::InlineFrameLimit;
+
+        let data = r#"{ "memoryMap": [], "stacks": [], "maxInlineDepth": 2 }"#;
+        let r: Request = serde_json::from_str(data)?;
+        assert_eq!(r.jobs().count(), 1);
+        assert_eq!(r.inline_frame_limit(), Some(InlineFrameLimit::MaxDepth(2)));
+
+        let data = r#"{ "jobs": [], "skipInlineResolution": true }"#;
+        let r: Request = serde_json::from_str(data)?;
+        assert_eq!(
+            r.inline_frame_limit(),
+            Some(InlineFrameLimit::SkipInlineResolution)
+        );
+
+        let data = r#"{ "jobs": [] }"#;
+        let r: Request = serde_json::from_str(data)?;
+        assert_eq!(r.inline_frame_limit(), None);
+        Ok(())
+    }
 }
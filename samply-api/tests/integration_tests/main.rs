@@ -205,6 +205,10 @@ impl FileLocation for FileLocationType {
     fn location_for_breakpad_symindex(&self) -> Option<Self> {
         Some(Self(self.0.with_extension("symindex")))
     }
+
+    fn location_for_def_file(&self) -> Option<Self> {
+        Some(Self(self.0.with_extension("def")))
+    }
 }
 
 fn fixtures_dir() -> PathBuf {
@@ -330,6 +334,43 @@ fn android32_v5_local() {
     );
 }
 
+#[test]
+fn android32_v5_inline_frame_limit() {
+    let query = |extra_fields: &str| {
+        let request_json = format!(
+            r#"{{
+                "memoryMap": [["libmozglue.so", "0CE47B7C29F27CED55C41233B93EBA450"]],
+                "stacks": [[[0, 685896]]]{extra_fields}
+            }}"#
+        );
+        let output = futures::executor::block_on(crate::query_api(
+            "/symbolicate/v5",
+            &request_json,
+            fixtures_dir().join("android32-local"),
+        ));
+        let output: serde_json::Value = serde_json::from_str(&output).unwrap();
+        output["results"][0]["stacks"][0][0].clone()
+    };
+
+    let frame = query("");
+    assert_eq!(frame["inlines"].as_array().unwrap().len(), 2);
+
+    // Only the inline frame closest to the outer function is kept.
+    let frame = query(r#", "maxInlineDepth": 1"#);
+    let inlines = frame["inlines"].as_array().unwrap();
+    assert_eq!(inlines.len(), 1);
+    assert_eq!(inlines[0]["line"], 992);
+    assert_eq!(frame["line"], 747);
+
+    let frame = query(r#", "skipInlineResolution": true"#);
+    assert!(frame["function"]
+        .as_str()
+        .unwrap()
+        .starts_with("mozilla::baseprofiler::ProfileBuffer::StreamSamplesToJSON"));
+    assert!(frame.get("inlines").is_none());
+    assert!(frame.get("file").is_none());
+}
+
 #[test]
 fn stripped_macos() {
     // The address 232505 (0x38c39) is inside the __stub_helper section.
@@ -7,7 +7,7 @@ use object::{
 
 use crate::{
     debug_id_and_code_id_for_jitdump, debug_id_for_object,
-    debugid_util::code_id_for_object,
+    debugid_util::{code_id_for_object, text_hash_code_id_for_elf_object},
     jitdump::JitDumpIndex,
     macho::{DyldCacheFileData, MachOData, MachOFatArchiveMemberData, ObjectAndMachOData},
     relative_address_base,
@@ -135,6 +135,14 @@ impl<F: FileContents> BinaryImageInner<F> {
                         let (debug_path, debug_name) = (path.clone(), name.clone());
                         (debug_id, code_id, debug_path, debug_name, arch)
                     }
+                    FileKind::Elf32 | FileKind::Elf64 => {
+                        let code_id = code_id_for_object(&object)
+                            .or_else(|| text_hash_code_id_for_elf_object(&object));
+                        let (debug_path, debug_name) = (path.clone(), name.clone());
+                        let arch =
+                            object_arch_to_string(object.architecture()).map(ToOwned::to_owned);
+                        (debug_id, code_id, debug_path, debug_name, arch)
+                    }
                     _ => {
                         let code_id = code_id_for_object(&object);
                         let (debug_path, debug_name) = (path.clone(), name.clone());
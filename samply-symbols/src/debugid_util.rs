@@ -51,15 +51,8 @@ impl DebugIdExt for DebugId {
         DebugId::from_uuid(uuid)
     }
 
-    // This algorithm XORs 16-byte chunks directly into a 16-byte buffer.
     fn from_text_first_page(text_first_page: &[u8], little_endian: bool) -> Self {
-        const UUID_SIZE: usize = 16;
-        const PAGE_SIZE: usize = 4096;
-        let mut hash = [0; UUID_SIZE];
-        for (i, byte) in text_first_page.iter().cloned().take(PAGE_SIZE).enumerate() {
-            hash[i % UUID_SIZE] ^= byte;
-        }
-        DebugId::from_identifier(&hash, little_endian)
+        DebugId::from_identifier(&text_first_page_hash(text_first_page), little_endian)
     }
 }
 
@@ -86,22 +79,17 @@ pub fn debug_id_for_object<'data: 'file, 'file>(
 
     // We were not able to locate a build ID, so fall back to creating a synthetic
     // identifier from a hash of the first page of the ".text" (program code) section.
-    if let Some(section) = obj.section_by_name(".text") {
-        let data_len = section.size().min(4096);
-        if let Ok(Some(first_page_data)) = section.data_range(section.address(), data_len) {
-            return Some(DebugId::from_text_first_page(
-                first_page_data,
-                obj.is_little_endian(),
-            ));
-        }
-    }
-
-    None
+    let text_first_page = text_section_first_page(obj)?;
+    Some(DebugId::from_text_first_page(
+        text_first_page,
+        obj.is_little_endian(),
+    ))
 }
 
 /// Tries to obtain a CodeId for an object.
 ///
-/// This currently only handles mach-O and ELF.
+/// This currently only handles mach-O and ELF. ELF files without a build ID
+/// are handled by [`text_hash_code_id_for_elf_object`].
 pub fn code_id_for_object<'data: 'file, 'file>(
     obj: &'file impl Object<'data, 'file>,
 ) -> Option<CodeId> {
@@ -117,3 +105,74 @@ pub fn code_id_for_object<'data: 'file, 'file>(
 
     None
 }
+
+/// The code ID for an ELF file without a build ID. Like in Breakpad's `dump_syms`,
+/// this is the hash of the first page of the text section, which is also the basis
+/// of the debug ID in that case.
+pub fn text_hash_code_id_for_elf_object<'data: 'file, 'file>(
+    obj: &'file impl Object<'data, 'file>,
+) -> Option<CodeId> {
+    let hash = text_first_page_hash(text_section_first_page(obj)?);
+    Some(CodeId::ElfBuildId(ElfBuildId::from_bytes(&hash)))
+}
+
+/// The first 4096 bytes of the .text section, or all of it if it's smaller.
+fn text_section_first_page<'data: 'file, 'file>(
+    obj: &'file impl Object<'data, 'file>,
+) -> Option<&'data [u8]> {
+    let section = obj.section_by_name(".text")?;
+    let data_len = section.size().min(4096);
+    section
+        .data_range(section.address(), data_len)
+        .ok()
+        .flatten()
+}
+
+/// XORs the first 4096 bytes of `text_first_page` into a 16-byte buffer, in
+/// 16-byte chunks. This is the basis of both the debug ID and the code ID of
+/// ELF files without a build ID.
+fn text_first_page_hash(text_first_page: &[u8]) -> [u8; 16] {
+    let mut hash = [0; 16];
+    for (i, byte) in text_first_page.iter().take(4096).enumerate() {
+        hash[i % 16] ^= byte;
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn text_first_page_hash_is_shared() {
+        let text: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let hash = text_first_page_hash(&text);
+        // Only the first page counts.
+        assert_eq!(hash, text_first_page_hash(&text[..4096]));
+        assert_ne!(hash, text_first_page_hash(&text[..4095]));
+
+        // The code ID has the hash bytes as they are, and the debug ID is
+        // derived from the same bytes.
+        let code_id = CodeId::ElfBuildId(ElfBuildId::from_bytes(&hash));
+        let debug_id = DebugId::from_text_first_page(&text, true);
+        assert_eq!(debug_id, DebugId::from_identifier(&hash, true));
+        assert_eq!(
+            debug_id,
+            DebugId::from_identifier(&hex_bytes(&code_id.to_string()), true)
+        );
+    }
+
+    #[test]
+    fn short_text_section() {
+        let hash = text_first_page_hash(&[1, 2, 3]);
+        assert_eq!(hash[..4], [1, 2, 3, 0]);
+        assert_eq!(text_first_page_hash(&[]), [0; 16]);
+    }
+
+    fn hex_bytes(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
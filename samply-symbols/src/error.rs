@@ -59,14 +59,16 @@ pub enum Error {
     FileLocationRefusedSourceFileLocation,
 
     #[error(
-        "No disambiguator supplied for universal binary, available images: {}", format_multiarch_members(.0)
+        "The universal binary contains more than one architecture and none was chosen, available architectures: {}", format_multiarch_members(.0)
     )]
     NoDisambiguatorForFatArchive(Vec<FatArchiveMember>),
 
     #[error("The universal binary (fat archive) was empty")]
     EmptyFatArchive,
 
-    #[error("No match in multi-arch binary, available UUIDs: {}", format_multiarch_members(.0))]
+    #[error(
+        "No architecture in the universal binary matches, available architectures: {}", format_multiarch_members(.0)
+    )]
     NoMatchMultiArch(Vec<FatArchiveMember>),
 
     #[error("Couldn't get symbols from system library, errors: {}", format_errors(.0))]
@@ -188,9 +190,9 @@ fn format_multiarch_members(members: &[FatArchiveMember]) -> String {
                 .uuid
                 .map(|uuid| DebugId::from_uuid(uuid).breakpad().to_string());
             format!(
-                "{} ({} {:08x}/{:08x})",
-                uuid_string.as_deref().unwrap_or("<no debug ID>"),
+                "{} ({}, CPU type {:08x}/{:08x})",
                 member.arch.as_deref().unwrap_or("<unrecognized arch>"),
+                uuid_string.as_deref().unwrap_or("<no debug ID>"),
                 member.cputype,
                 member.cpusubtype
             )
@@ -217,6 +217,10 @@
 //!     fn location_for_breakpad_symindex(&self) -> Option<Self> {
 //!         Some(Self(self.0.with_extension("symindex")))
 //!     }
+//!
+//!     fn location_for_def_file(&self) -> Option<Self> {
+//!         Some(Self(self.0.with_extension("def")))
+//!     }
 //! }
 //! ```
 
@@ -224,6 +228,7 @@ use std::sync::Mutex;
 
 use binary_image::BinaryImageInner;
 pub use debugid;
+use debugid::DebugId;
 use jitdump::JitDumpIndex;
 use linux_perf_data::jitdump::JitDumpReader;
 pub use object;
@@ -259,7 +264,9 @@ pub use crate::breakpad::{
 };
 pub use crate::cache::{FileByteSource, FileContentsWithChunkedCaching};
 pub use crate::compact_symbol_table::CompactSymbolTable;
-pub use crate::debugid_util::{debug_id_for_object, DebugIdExt};
+pub use crate::debugid_util::{
+    code_id_for_object, debug_id_for_object, text_hash_code_id_for_elf_object, DebugIdExt,
+};
 pub use crate::error::Error;
 pub use crate::external_file::{load_external_file, ExternalFileSymbolMap};
 pub use crate::jitdump::debug_id_and_code_id_for_jitdump;
@@ -269,14 +276,17 @@ pub use crate::shared::{
     relative_address_base, AddressInfo, CandidatePathInfo, CodeId, ElfBuildId,
     ExternalFileAddressInFileRef, ExternalFileAddressRef, ExternalFileRef, FileAndPathHelper,
     FileAndPathHelperError, FileAndPathHelperResult, FileContents, FileContentsWrapper,
-    FileLocation, FrameDebugInfo, FramesLookupResult, LibraryInfo, MultiArchDisambiguator,
-    OptionallySendFuture, PeCodeId, SourceFilePath, SymbolInfo,
+    FileLocation, FrameDebugInfo, FramesLookupResult, FunctionInfo, InlineFrameLimit, LibraryInfo,
+    LineRange, MultiArchDisambiguator, OptionallySendFuture, PeCodeId, SourceFilePath, SymbolInfo,
 };
 pub use crate::symbol_map::SymbolMap;
 
 pub struct SymbolManager<'h, H: FileAndPathHelper<'h>> {
     helper: &'h H,
     cached_external_file: Mutex<Option<ExternalFileSymbolMap>>,
+    ignore_debug_id_mismatch: bool,
+    ignore_pdb_age: bool,
+    inline_frame_limit: InlineFrameLimit,
 }
 
 impl<'h, H, F, FL> SymbolManager<'h, H>
@@ -290,9 +300,50 @@ where
         Self {
             helper,
             cached_external_file: Mutex::new(None),
+            ignore_debug_id_mismatch: false,
+            ignore_pdb_age: false,
+            inline_frame_limit: InlineFrameLimit::default(),
         }
     }
 
+    /// Accept symbol files and binaries whose debug ID doesn't match the requested
+    /// debug ID, if no candidate with a matching debug ID is found.
+    ///
+    /// This is useful for binaries which were rebuilt locally after profiling: the
+    /// rebuilt binary usually has the same code, but a different build ID. The
+    /// symbols may of course be wrong if the code has changed. Callers can detect
+    /// this case by comparing the debug ID of the returned object.
+    pub fn set_ignore_debug_id_mismatch(&mut self, ignore: bool) {
+        self.ignore_debug_id_mismatch = ignore;
+    }
+
+    /// Only compare the signature (GUID) part of debug IDs, and ignore the age.
+    ///
+    /// The age of a PDB is incremented whenever the linker updates the PDB, so a
+    /// rebuilt but otherwise identical PDB can have a different age than the one
+    /// the binary refers to. The age is also reported inconsistently: it's stored
+    /// both in the PDB info stream and in the DBI stream, and the two can differ
+    /// by one. Unlike [`set_ignore_debug_id_mismatch`](Self::set_ignore_debug_id_mismatch),
+    /// this still requires the GUID to match, so it can't pick up an unrelated file.
+    pub fn set_ignore_pdb_age(&mut self, ignore: bool) {
+        self.ignore_pdb_age = ignore;
+    }
+
+    /// Limits the number of inline frames which are returned for each address,
+    /// in the symbol maps returned by this `SymbolManager` and by
+    /// [`lookup_external`](Self::lookup_external).
+    ///
+    /// [`InlineFrameLimit::SkipInlineResolution`] makes lookups much faster when
+    /// only the function names are needed, e.g. for the first display of a
+    /// huge profile.
+    pub fn set_inline_frame_limit(&mut self, inline_frame_limit: InlineFrameLimit) {
+        self.inline_frame_limit = inline_frame_limit;
+    }
+
+    fn debug_ids_match(&self, found: DebugId, expected: DebugId) -> bool {
+        debug_ids_match(found, expected, self.ignore_pdb_age)
+    }
+
     /// Exposes the helper.
     pub fn helper(&self) -> &'h H {
         self.helper
@@ -341,14 +392,30 @@ where
             })?;
 
         let mut all_errors = Vec::new();
+        let mut mismatched_symbol_map = None;
         for candidate_info in candidate_paths {
             let symbol_map = match candidate_info {
                 CandidatePathInfo::SingleFile(file_location) => {
-                    self.load_symbol_map_from_location(
-                        file_location,
-                        Some(MultiArchDisambiguator::DebugId(debug_id)),
-                    )
-                    .await
+                    let result = self
+                        .load_symbol_map_from_location(
+                            file_location.clone(),
+                            Some(MultiArchDisambiguator::DebugId(debug_id)),
+                        )
+                        .await;
+                    match (result, &library_info.arch) {
+                        // No member of a universal binary has the debug ID. If mismatches
+                        // are accepted, fall back to the member with the right architecture.
+                        (Err(Error::NoMatchMultiArch(_)), Some(arch))
+                            if self.ignore_debug_id_mismatch =>
+                        {
+                            self.load_symbol_map_from_location(
+                                file_location,
+                                Some(MultiArchDisambiguator::Arch(arch.clone())),
+                            )
+                            .await
+                        }
+                        (result, _) => result,
+                    }
                 }
                 CandidatePathInfo::InDyldCache {
                     dyld_cache_path,
@@ -356,19 +423,28 @@ where
                 } => {
                     macho::load_symbol_map_for_dyld_cache(dyld_cache_path, dylib_path, self.helper)
                         .await
+                        .map(|symbol_map| self.with_inline_frame_limit(symbol_map))
                 }
             };
 
             match symbol_map {
-                Ok(symbol_map) if symbol_map.debug_id() == debug_id => return Ok(symbol_map),
+                Ok(symbol_map) if self.debug_ids_match(symbol_map.debug_id(), debug_id) => {
+                    return Ok(symbol_map)
+                }
                 Ok(symbol_map) => {
                     all_errors.push(Error::UnmatchedDebugId(symbol_map.debug_id(), debug_id));
+                    if self.ignore_debug_id_mismatch && mismatched_symbol_map.is_none() {
+                        mismatched_symbol_map = Some(symbol_map);
+                    }
                 }
                 Err(e) => {
                     all_errors.push(e);
                 }
             }
         }
+        if let Some(symbol_map) = mismatched_symbol_map {
+            return Ok(symbol_map);
+        }
         let err = match all_errors.len() {
             0 => Error::NoCandidatePathForDebugFile(Box::new(library_info.clone())),
             1 => all_errors.pop().unwrap(),
@@ -407,6 +483,21 @@ where
         &self,
         debug_file_location: &H::FL,
         address: &ExternalFileAddressRef,
+    ) -> Option<Vec<FrameDebugInfo>> {
+        if self.inline_frame_limit == InlineFrameLimit::SkipInlineResolution {
+            return None;
+        }
+        let mut frames = self
+            .lookup_external_impl(debug_file_location, address)
+            .await?;
+        self.inline_frame_limit.truncate_frames(&mut frames);
+        Some(frames)
+    }
+
+    async fn lookup_external_impl(
+        &self,
+        debug_file_location: &H::FL,
+        address: &ExternalFileAddressRef,
     ) -> Option<Vec<FrameDebugInfo>> {
         {
             let cached_external_file = self.cached_external_file.lock().ok()?;
@@ -459,6 +550,7 @@ where
         };
 
         let mut last_err = None;
+        let mut mismatched_image = None;
         for candidate_info in candidate_paths_for_binary {
             let image = match candidate_info {
                 CandidatePathInfo::SingleFile(file_location) => {
@@ -482,10 +574,18 @@ where
             match image {
                 Ok(image) => {
                     let e = if let Some(expected_debug_id) = info.debug_id {
-                        if image.debug_id() == Some(expected_debug_id) {
+                        if image
+                            .debug_id()
+                            .map_or(false, |id| self.debug_ids_match(id, expected_debug_id))
+                        {
                             return Ok(image);
                         }
-                        Error::UnmatchedDebugIdOptional(expected_debug_id, image.debug_id())
+                        let e =
+                            Error::UnmatchedDebugIdOptional(expected_debug_id, image.debug_id());
+                        if self.ignore_debug_id_mismatch && mismatched_image.is_none() {
+                            mismatched_image = Some(image);
+                        }
+                        e
                     } else if let Some(expected_code_id) = info.code_id.as_ref() {
                         if image.code_id().as_ref() == Some(expected_code_id) {
                             return Ok(image);
@@ -503,6 +603,9 @@ where
                 }
             }
         }
+        if let Some(image) = mismatched_image {
+            return Ok(image);
+        }
         Err(last_err.unwrap_or_else(|| {
             Error::NoCandidatePathForBinary(info.debug_name.clone(), info.debug_id)
         }))
@@ -566,6 +669,8 @@ where
                 self.helper,
             )
             .await;
+            let symbol_map_res =
+                symbol_map_res.map(|symbol_map| self.with_inline_frame_limit(symbol_map));
             match (&multi_arch_disambiguator, symbol_map_res) {
                 (Some(MultiArchDisambiguator::DebugId(expected_debug_id)), Ok(symbol_map)) => {
                     if &symbol_map.debug_id() == expected_debug_id {
@@ -587,6 +692,22 @@ where
         &self,
         file_location: FL,
         multi_arch_disambiguator: Option<MultiArchDisambiguator>,
+    ) -> Result<SymbolMap<FL>, Error> {
+        let symbol_map = self
+            .load_symbol_map_from_location_impl(file_location, multi_arch_disambiguator)
+            .await?;
+        Ok(self.with_inline_frame_limit(symbol_map))
+    }
+
+    fn with_inline_frame_limit(&self, mut symbol_map: SymbolMap<FL>) -> SymbolMap<FL> {
+        symbol_map.set_inline_frame_limit(self.inline_frame_limit);
+        symbol_map
+    }
+
+    async fn load_symbol_map_from_location_impl(
+        &self,
+        file_location: FL,
+        multi_arch_disambiguator: Option<MultiArchDisambiguator>,
     ) -> Result<SymbolMap<FL>, Error> {
         let file_contents = self
             .helper
@@ -623,19 +744,38 @@ where
                     macho::get_symbol_map_for_macho(file_location, file_contents)
                 }
                 FileKind::Pe32 | FileKind::Pe64 => {
-                    match windows::load_symbol_map_for_pdb_corresponding_to_binary(
-                        file_kind,
-                        &file_contents,
-                        file_location.clone(),
-                        self.helper,
-                    )
-                    .await
-                    {
-                        Ok(symbol_map) => Ok(symbol_map),
-                        Err(_) => {
-                            windows::get_symbol_map_for_pe(file_contents, file_kind, file_location)
+                    // Binaries built by MinGW or by Clang with -gdwarf have their debug
+                    // info embedded as DWARF, and any PDB path in them is not useful.
+                    if !windows::has_embedded_dwarf(&file_contents) {
+                        if let Ok(symbol_map) =
+                            windows::load_symbol_map_for_pdb_corresponding_to_binary(
+                                file_kind,
+                                &file_contents,
+                                file_location.clone(),
+                                self.helper,
+                                self.ignore_pdb_age,
+                            )
+                            .await
+                        {
+                            return Ok(symbol_map);
                         }
                     }
+                    let def_file_contents =
+                        if let Some(def_file_location) = file_location.location_for_def_file() {
+                            self.helper
+                                .load_file(def_file_location)
+                                .await
+                                .ok()
+                                .map(FileContentsWrapper::new)
+                        } else {
+                            None
+                        };
+                    windows::get_symbol_map_for_pe(
+                        file_contents,
+                        file_kind,
+                        file_location,
+                        def_file_contents,
+                    )
                 }
                 _ => Err(Error::InvalidInputError(
                     "Input was Archive, Coff or Wasm format, which are unsupported for now",
@@ -723,3 +863,10 @@ where
         BinaryImage::new(inner, name, path)
     }
 }
+
+/// Whether a file with the debug ID `found` can be used where `expected` was
+/// asked for. With `ignore_pdb_age`, only the GUIDs have to match, see
+/// [`SymbolManager::set_ignore_pdb_age`].
+pub(crate) fn debug_ids_match(found: DebugId, expected: DebugId, ignore_pdb_age: bool) -> bool {
+    found == expected || (ignore_pdb_age && found.uuid() == expected.uuid())
+}
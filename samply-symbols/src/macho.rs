@@ -10,7 +10,7 @@ use debugid::DebugId;
 use macho_unwind_info::UnwindInfo;
 use object::macho::{self, LinkeditDataCommand, MachHeader32, MachHeader64};
 use object::read::macho::{
-    FatArch, LoadCommandIterator, MachHeader, MachOFatFile32, MachOFatFile64,
+    DyldSubCacheSlice, FatArch, LoadCommandIterator, MachHeader, MachOFatFile32, MachOFatFile64,
 };
 use object::read::{File, Object, ObjectSection};
 use object::{Endianness, FileKind, ReadRef};
@@ -243,22 +243,10 @@ where
     let root_contents = FileContentsWrapper::new(root_contents);
 
     let mut subcache_contents = Vec::new();
-    for subcache_index in 1.. {
-        // Find the subcache at dyld_shared_cache_arm64e.1 or dyld_shared_cache_arm64e.01
-        let suffix = format!(".{subcache_index}");
-        let suffix2 = format!(".{subcache_index:02}");
-        let subcache = match dcl.load_subcache(&suffix).await {
-            Ok(subcache) => subcache,
-            Err(_) => match dcl.load_subcache(&suffix2).await {
-                Ok(subcache) => subcache,
-                Err(_) => break,
-            },
-        };
+    for suffix in get_dyld_subcache_suffixes(&root_contents)? {
+        let subcache = dcl.load_subcache(&suffix).await?;
         subcache_contents.push(FileContentsWrapper::new(subcache));
     }
-    if let Ok(subcache) = dcl.load_subcache(".symbols").await {
-        subcache_contents.push(FileContentsWrapper::new(subcache));
-    };
 
     Ok(DyldCacheFileData::new(
         root_contents,
@@ -281,6 +269,47 @@ where
     Ok(SymbolMap::new(dyld_cache_path, Box::new(symbol_map)))
 }
 
+/// Returns the file name suffixes of the subcaches of a split dyld shared cache,
+/// in the order in which they need to be passed to `DyldCache::parse`, with the
+/// `.symbols` subcache last. Returns an empty list for caches from before
+/// macOS 12, which consist of a single file.
+///
+/// Since macOS 13, the suffixes are stored in the header, and they can be more
+/// than a number, e.g. ".25.data" or ".03.development". On macOS 12, the
+/// subcaches are numbered ".1", ".2" etc.
+fn get_dyld_subcache_suffixes(
+    root_contents: &FileContentsWrapper<impl FileContents>,
+) -> Result<Vec<String>, Error> {
+    let data = root_contents.full_range();
+    let header =
+        macho::DyldCacheHeader::<Endianness>::parse(data).map_err(Error::DyldCacheParseError)?;
+    let (_arch, endian) = header.parse_magic().map_err(Error::DyldCacheParseError)?;
+    let mut suffixes: Vec<String> = match header
+        .subcaches(endian, data)
+        .map_err(Error::DyldCacheParseError)?
+    {
+        Some(DyldSubCacheSlice::V2(entries)) => entries
+            .iter()
+            .map(|entry| {
+                let len = entry
+                    .file_suffix
+                    .iter()
+                    .position(|&b| b == 0)
+                    .unwrap_or(entry.file_suffix.len());
+                String::from_utf8_lossy(&entry.file_suffix[..len]).into_owned()
+            })
+            .collect(),
+        Some(DyldSubCacheSlice::V1(entries)) => (1..=entries.len())
+            .map(|index| format!(".{index}"))
+            .collect(),
+        _ => Vec::new(),
+    };
+    if header.symbols_subcache_uuid(endian).is_some() {
+        suffixes.push(".symbols".to_string());
+    }
+    Ok(suffixes)
+}
+
 pub struct DyldCacheFileData<T>
 where
     T: FileContents + 'static,
@@ -644,3 +673,75 @@ fn read_uleb128(mut bytes: &[u8]) -> Option<(u64, &[u8])> {
     }
     None
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use object::{LittleEndian as LE, U32, U64};
+
+    /// A dyld cache header whose subcache entries are right after it, as in
+    /// real caches, followed by `entries`.
+    fn dyld_cache(header_size: u32, subcache_count: u32, symbols: bool, entries: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; std::mem::size_of::<macho::DyldCacheHeader<LE>>()];
+        let (header, _) =
+            object::pod::from_bytes_mut::<macho::DyldCacheHeader<LE>>(&mut data).unwrap();
+        header.magic = *b"dyld_v1  arm64e\0";
+        header.mapping_offset = U32::new(LE, header_size);
+        header.subcaches_offset = U32::new(LE, header_size);
+        header.subcaches_count = U32::new(LE, subcache_count);
+        if symbols {
+            header.symbols_subcache_uuid = [1; 16];
+        }
+        data.resize(data.len().max(header_size as usize), 0);
+        data.extend_from_slice(entries);
+        data
+    }
+
+    fn suffixes(data: Vec<u8>) -> Vec<String> {
+        get_dyld_subcache_suffixes(&FileContentsWrapper::new(data)).unwrap()
+    }
+
+    #[test]
+    fn subcache_suffixes_from_macos_13() {
+        let mut entries = Vec::new();
+        for suffix in [&b".01"[..], b".25.data"] {
+            let mut file_suffix = [0; 32];
+            file_suffix[..suffix.len()].copy_from_slice(suffix);
+            let entry = macho::DyldSubCacheEntryV2::<LE> {
+                uuid: [2; 16],
+                cache_vm_offset: U64::new(LE, 0),
+                file_suffix,
+            };
+            entries.extend_from_slice(object::pod::bytes_of(&entry));
+        }
+        assert_eq!(
+            suffixes(dyld_cache(0x1d0, 2, true, &entries)),
+            vec![".01", ".25.data", ".symbols"]
+        );
+    }
+
+    #[test]
+    fn subcache_suffixes_from_macos_12() {
+        let mut entries = Vec::new();
+        for _ in 0..3 {
+            let entry = macho::DyldSubCacheEntryV1::<LE> {
+                uuid: [2; 16],
+                cache_vm_offset: U64::new(LE, 0),
+            };
+            entries.extend_from_slice(object::pod::bytes_of(&entry));
+        }
+        assert_eq!(
+            suffixes(dyld_cache(0x1c8, 3, false, &entries)),
+            vec![".1", ".2", ".3"]
+        );
+    }
+
+    #[test]
+    fn no_subcache_suffixes_before_macos_12() {
+        // The header ends before the subcache fields.
+        assert_eq!(
+            suffixes(dyld_cache(0x140, 0, true, &[])),
+            Vec::<String>::new()
+        );
+    }
+}
@@ -431,6 +431,16 @@ pub trait FileLocation: Clone + Display {
     /// Called on the location of a Breakpad sym file, to get a location for its
     /// corresponding symindex file.
     fn location_for_breakpad_symindex(&self) -> Option<Self>;
+
+    /// Called on the location of a PE binary, to get a location for a module-definition
+    /// (.def) file, which is used to name the functions that are only exported by
+    /// ordinal. This is usually the binary's path with a .def extension.
+    ///
+    /// The default implementation returns `None`, so ordinal-only exports get
+    /// placeholder names.
+    fn location_for_def_file(&self) -> Option<Self> {
+        None
+    }
 }
 
 /// The path of a source file, as found in the debug info.
@@ -615,6 +625,33 @@ pub struct AddressInfo {
     pub frames: FramesLookupResult,
 }
 
+/// A function in a symbol map, as returned by
+/// [`SymbolMap::iter_functions`](crate::SymbolMap::iter_functions).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionInfo {
+    /// The function's symbol, with the function's address and size.
+    pub symbol: SymbolInfo,
+    /// The line ranges of the function, sorted by address. This is `None` if
+    /// lines weren't requested or if the symbol map doesn't have line information.
+    pub lines: Option<Vec<LineRange>>,
+}
+
+/// A range of instructions which belong to the same source line.
+///
+/// For code which was inlined into the function, the file and line refer to the
+/// inlined code, not to the call site.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineRange {
+    /// The start address of the range. This is a relative address.
+    pub address: u32,
+    /// The size of the range, in bytes.
+    pub size: u32,
+    /// The source file, if known.
+    pub file_path: Option<SourceFilePath>,
+    /// The line number, if known.
+    pub line_number: Option<u32>,
+}
+
 /// Contains address debug info (inlined functions, file names, line numbers) if
 /// available.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -645,6 +682,49 @@ pub enum FramesLookupResult {
     Unavailable,
 }
 
+/// Limits how many inline frames are returned for each looked-up address.
+///
+/// Set with [`SymbolManager::set_inline_frame_limit`](crate::SymbolManager::set_inline_frame_limit)
+/// or [`SymbolMap::set_inline_frame_limit`](crate::SymbolMap::set_inline_frame_limit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InlineFrameLimit {
+    /// Return all inline frames. This is the default.
+    Unlimited,
+
+    /// Return at most this many inline frames per address, in addition to the
+    /// frame for the outer function. The innermost inline frames are dropped.
+    MaxDepth(u32),
+
+    /// Don't resolve inline frames at all, and don't look up any debug info:
+    /// lookups only return the symbol. This is much faster for huge profiles,
+    /// because the debug info doesn't need to be parsed and external object
+    /// files aren't loaded.
+    SkipInlineResolution,
+}
+
+impl Default for InlineFrameLimit {
+    fn default() -> Self {
+        Self::Unlimited
+    }
+}
+
+impl InlineFrameLimit {
+    /// Removes the frames which exceed the limit. `frames` is ordered like in
+    /// [`FramesLookupResult::Available`], with the outer function last.
+    pub fn truncate_frames(&self, frames: &mut Vec<FrameDebugInfo>) {
+        match *self {
+            InlineFrameLimit::Unlimited => {}
+            InlineFrameLimit::MaxDepth(max_depth) => {
+                let max_len = max_depth as usize + 1;
+                if frames.len() > max_len {
+                    frames.drain(..frames.len() - max_len);
+                }
+            }
+            InlineFrameLimit::SkipInlineResolution => frames.clear(),
+        }
+    }
+}
+
 /// Information to find an external file and an address within that file, to be
 /// passed to `SymbolManager::lookup_external`.
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
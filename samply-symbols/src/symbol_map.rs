@@ -4,11 +4,15 @@ use debugid::DebugId;
 use yoke::Yoke;
 use yoke_derive::Yokeable;
 
-use crate::{shared::AddressInfo, Error, FileLocation};
+use crate::{
+    shared::{AddressInfo, FramesLookupResult, FunctionInfo, InlineFrameLimit},
+    Error, FileLocation,
+};
 
 pub struct SymbolMap<FL: FileLocation> {
     debug_file_location: FL,
     pub(crate) inner: Box<dyn SymbolMapTrait + Send>,
+    inline_frame_limit: InlineFrameLimit,
 }
 
 impl<FL: FileLocation> SymbolMap<FL> {
@@ -16,9 +20,31 @@ impl<FL: FileLocation> SymbolMap<FL> {
         Self {
             debug_file_location,
             inner,
+            inline_frame_limit: InlineFrameLimit::default(),
         }
     }
 
+    /// Limits the number of inline frames returned by the lookup methods. Symbol
+    /// maps returned by the `SymbolManager` use the limit of the `SymbolManager`.
+    pub fn set_inline_frame_limit(&mut self, inline_frame_limit: InlineFrameLimit) {
+        self.inline_frame_limit = inline_frame_limit;
+    }
+
+    pub fn inline_frame_limit(&self) -> InlineFrameLimit {
+        self.inline_frame_limit
+    }
+
+    fn apply_inline_frame_limit(&self, mut address_info: AddressInfo) -> AddressInfo {
+        match (&mut address_info.frames, self.inline_frame_limit) {
+            (_, InlineFrameLimit::SkipInlineResolution) => {
+                address_info.frames = FramesLookupResult::Unavailable;
+            }
+            (FramesLookupResult::Available(frames), limit) => limit.truncate_frames(frames),
+            _ => {}
+        }
+        address_info
+    }
+
     pub fn debug_file_location(&self) -> &FL {
         &self.debug_file_location
     }
@@ -35,16 +61,30 @@ impl<FL: FileLocation> SymbolMap<FL> {
         self.inner.iter_symbols()
     }
 
+    /// Iterates over the functions in this symbol map, sorted by address. Unlike
+    /// [`iter_symbols`](Self::iter_symbols), this returns the function sizes and
+    /// the demangled names, and, if `with_lines` is true, the line ranges of each
+    /// function. Line ranges are currently only available for DWARF debug info.
+    pub fn iter_functions(&self, with_lines: bool) -> Box<dyn Iterator<Item = FunctionInfo> + '_> {
+        self.inner.iter_functions(with_lines)
+    }
+
     pub fn lookup_relative_address(&self, address: u32) -> Option<AddressInfo> {
-        self.inner.lookup_relative_address(address)
+        if self.inline_frame_limit == InlineFrameLimit::SkipInlineResolution {
+            return self.inner.lookup_relative_address_without_frames(address);
+        }
+        let address_info = self.inner.lookup_relative_address(address)?;
+        Some(self.apply_inline_frame_limit(address_info))
     }
 
     pub fn lookup_svma(&self, svma: u64) -> Option<AddressInfo> {
-        self.inner.lookup_svma(svma)
+        let address_info = self.inner.lookup_svma(svma)?;
+        Some(self.apply_inline_frame_limit(address_info))
     }
 
     pub fn lookup_offset(&self, offset: u64) -> Option<AddressInfo> {
-        self.inner.lookup_offset(offset)
+        let address_info = self.inner.lookup_offset(offset)?;
+        Some(self.apply_inline_frame_limit(address_info))
     }
 }
 
@@ -55,7 +95,31 @@ pub trait SymbolMapTrait {
 
     fn iter_symbols(&self) -> Box<dyn Iterator<Item = (u32, Cow<'_, str>)> + '_>;
 
+    /// Iterates over the functions, see `SymbolMap::iter_functions`. The default
+    /// implementation looks up each symbol and doesn't return line ranges.
+    fn iter_functions(&self, _with_lines: bool) -> Box<dyn Iterator<Item = FunctionInfo> + '_> {
+        Box::new(self.iter_symbols().filter_map(|(address, _name)| {
+            let address_info = self.lookup_relative_address_without_frames(address)?;
+            Some(FunctionInfo {
+                symbol: address_info.symbol,
+                lines: None,
+            })
+        }))
+    }
+
     fn lookup_relative_address(&self, address: u32) -> Option<AddressInfo>;
+
+    /// Like `lookup_relative_address`, but only looks up the symbol and returns
+    /// `FramesLookupResult::Unavailable`. Symbol maps for which the debug info
+    /// lookup is expensive override this to skip it.
+    fn lookup_relative_address_without_frames(&self, address: u32) -> Option<AddressInfo> {
+        let address_info = self.lookup_relative_address(address)?;
+        Some(AddressInfo {
+            symbol: address_info.symbol,
+            frames: FramesLookupResult::Unavailable,
+        })
+    }
+
     fn lookup_svma(&self, svma: u64) -> Option<AddressInfo>;
     fn lookup_offset(&self, offset: u64) -> Option<AddressInfo>;
 }
@@ -123,6 +187,17 @@ impl<SMDO: SymbolMapDataOuterTrait> SymbolMapTrait for GenericSymbolMap<SMDO> {
         self.0.get().0.lookup_relative_address(address)
     }
 
+    fn lookup_relative_address_without_frames(&self, address: u32) -> Option<AddressInfo> {
+        self.0
+            .get()
+            .0
+            .lookup_relative_address_without_frames(address)
+    }
+
+    fn iter_functions(&self, with_lines: bool) -> Box<dyn Iterator<Item = FunctionInfo> + '_> {
+        self.0.get().0.iter_functions(with_lines)
+    }
+
     fn lookup_svma(&self, svma: u64) -> Option<AddressInfo> {
         self.0.get().0.lookup_svma(svma)
     }
@@ -13,10 +13,10 @@ use crate::{
     path_mapper::PathMapper,
     shared::{
         relative_address_base, AddressInfo, ExternalFileAddressInFileRef, ExternalFileRef,
-        SymbolInfo,
+        FunctionInfo, LineRange, SymbolInfo,
     },
     symbol_map::{SymbolMapDataMidTrait, SymbolMapInnerWrapper, SymbolMapTrait},
-    Error, FramesLookupResult,
+    Error, FramesLookupResult, SourceFilePath,
 };
 
 pub trait FunctionAddressesComputer<'data> {
@@ -38,6 +38,7 @@ pub struct ObjectSymbolMapDataMid<'data, R: ReadRef<'data>, FAC: FunctionAddress
     addr2line_context_data: Addr2lineContextData,
     arch: Option<&'static str>,
     debug_id: DebugId,
+    unnamed_exports: Vec<(u32, String)>,
 }
 
 impl<'data, R: ReadRef<'data>, FAC: FunctionAddressesComputer<'data>>
@@ -61,8 +62,17 @@ impl<'data, R: ReadRef<'data>, FAC: FunctionAddressesComputer<'data>>
             addr2line_context_data: Addr2lineContextData::new(),
             arch,
             debug_id,
+            unnamed_exports: Vec::new(),
         }
     }
+
+    /// Adds symbols for exports which `object` doesn't list because they don't
+    /// have a name, i.e. PE exports which are only exported by ordinal. The
+    /// addresses are relative addresses.
+    pub fn with_unnamed_exports(mut self, unnamed_exports: Vec<(u32, String)>) -> Self {
+        self.unnamed_exports = unnamed_exports;
+        self
+    }
 }
 
 impl<'data, R: ReadRef<'data> + Send + Sync, FAC: FunctionAddressesComputer<'data>>
@@ -81,6 +91,7 @@ impl<'data, R: ReadRef<'data> + Send + Sync, FAC: FunctionAddressesComputer<'dat
             self.debug_id,
             function_starts.as_deref(),
             function_ends.as_deref(),
+            &self.unnamed_exports,
             self.arch,
             &self.addr2line_context_data,
         );
@@ -97,6 +108,9 @@ enum FullSymbolListEntry<'a, Symbol: object::ObjectSymbol<'a>> {
     SynthesizedEntryPoint,
     Symbol(Symbol),
     Export(object::Export<'a>),
+    /// An export which only has an ordinal, with a name from a .def file or
+    /// a placeholder name.
+    UnnamedExport(String),
     EndAddress,
 }
 
@@ -113,6 +127,7 @@ impl<'a, Symbol: object::ObjectSymbol<'a>> std::fmt::Debug for FullSymbolListEnt
                 .debug_tuple("Export")
                 .field(&std::str::from_utf8(arg0.name()).unwrap())
                 .finish(),
+            Self::UnnamedExport(name) => f.debug_tuple("UnnamedExport").field(name).finish(),
             Self::EndAddress => write!(f, "EndAddress"),
         }
     }
@@ -128,6 +143,7 @@ impl<'a, Symbol: object::ObjectSymbol<'a>> FullSymbolListEntry<'a, Symbol> {
                 Err(_) => Err(()),
             },
             FullSymbolListEntry::Export(export) => Ok(String::from_utf8_lossy(export.name())),
+            FullSymbolListEntry::UnnamedExport(name) => Ok(name.clone().into()),
             FullSymbolListEntry::EndAddress => Err(()),
         }
     }
@@ -211,6 +227,7 @@ where
         debug_id: DebugId,
         function_start_addresses: Option<&[u32]>,
         function_end_addresses: Option<&[u32]>,
+        unnamed_exports: &[(u32, String)],
         arch: Option<&'static str>,
         addr2line_context_data: &'file Addr2lineContextData,
     ) -> Self
@@ -295,6 +312,12 @@ where
                 ));
             }
         }
+        // Exports which are only known by their ordinal, named from a .def file.
+        entries.extend(
+            unnamed_exports.iter().map(|(address, name)| {
+                (*address, FullSymbolListEntry::UnnamedExport(name.clone()))
+            }),
+        );
 
         // 4. Placeholder symbols based on function start addresses
         if let Some(function_start_addresses) = function_start_addresses {
@@ -421,36 +444,44 @@ where
         }
         None
     }
-}
 
-impl<'data, 'file, Symbol: object::ObjectSymbol<'data>> SymbolMapTrait
-    for ObjectSymbolMapInner<'data, 'file, Symbol>
-where
-    'data: 'file,
-{
-    fn debug_id(&self) -> DebugId {
-        self.debug_id
-    }
-
-    fn symbol_count(&self) -> usize {
-        self.entries
-            .iter()
-            .filter(|&(_, entry)| {
-                matches!(
-                    entry,
-                    FullSymbolListEntry::Symbol(_) | FullSymbolListEntry::Export(_)
-                )
+    /// Returns the line ranges from the DWARF line tables for the given address
+    /// range, clamped to that range.
+    fn line_ranges(&self, address: u32, size: u32) -> Option<Vec<LineRange>> {
+        let context = self.context.as_ref()?;
+        let start_svma = self.image_base_address + u64::from(address);
+        let end_svma = start_svma + u64::from(size);
+        let mut path_mapper = self.path_mapper.lock().unwrap();
+        let mut line_ranges: Vec<LineRange> = context
+            .find_location_range(start_svma, end_svma)
+            .ok()?
+            .filter_map(|(range_svma, range_size, location)| {
+                let range_start = range_svma.max(start_svma);
+                let range_end = range_svma.checked_add(range_size)?.min(end_svma);
+                if range_start >= range_end {
+                    return None;
+                }
+                Some(LineRange {
+                    address: u32::try_from(range_start - self.image_base_address).ok()?,
+                    size: (range_end - range_start) as u32,
+                    file_path: location.file.map(|file| {
+                        let mapped_path = path_mapper.map_path(file);
+                        SourceFilePath::new(file.into(), mapped_path)
+                    }),
+                    line_number: location.line,
+                })
             })
-            .count()
-    }
-
-    fn iter_symbols(&self) -> Box<dyn Iterator<Item = (u32, Cow<'_, str>)> + '_> {
-        Box::new(SymbolMapIter {
-            inner: self.entries.iter(),
-        })
+            .collect();
+        if line_ranges.is_empty() {
+            return None;
+        }
+        line_ranges.sort_by_key(|line_range| line_range.address);
+        Some(line_ranges)
     }
 
-    fn lookup_relative_address(&self, address: u32) -> Option<AddressInfo> {
+    /// Looks up the symbol for the address, and, if `with_frames` is true, the
+    /// debug info.
+    fn lookup_relative_address_impl(&self, address: u32, with_frames: bool) -> Option<AddressInfo> {
         let index = match self
             .entries
             .binary_search_by_key(&address, |&(addr, _)| addr)
@@ -467,6 +498,17 @@ where
         if let (Ok(name), Some((end_addr, _))) = (entry.name(*start_addr), next_entry) {
             let function_size = end_addr - *start_addr;
 
+            if !with_frames {
+                return Some(AddressInfo {
+                    symbol: SymbolInfo {
+                        address: *start_addr,
+                        size: Some(function_size),
+                        name: demangle::demangle_any(&name),
+                    },
+                    frames: FramesLookupResult::Unavailable,
+                });
+            }
+
             let mut path_mapper = self.path_mapper.lock().unwrap();
 
             let svma = self.image_base_address + u64::from(address);
@@ -509,7 +551,18 @@ where
                 }
             };
 
-            let name = demangle::demangle_any(&name);
+            let name = match (entry, &frames) {
+                // We only know the function start address from .pdata or .eh_frame, but
+                // the debug info knows the function name. This happens for functions
+                // without a symbol in binaries with embedded DWARF, e.g. MinGW binaries.
+                (FullSymbolListEntry::Synthesized, FramesLookupResult::Available(frames)) => {
+                    match frames.last().and_then(|frame| frame.function.clone()) {
+                        Some(function_name) => function_name,
+                        None => demangle::demangle_any(&name),
+                    }
+                }
+                _ => demangle::demangle_any(&name),
+            };
             Some(AddressInfo {
                 symbol: SymbolInfo {
                     address: *start_addr,
@@ -522,6 +575,60 @@ where
             None
         }
     }
+}
+
+impl<'data, 'file, Symbol: object::ObjectSymbol<'data>> SymbolMapTrait
+    for ObjectSymbolMapInner<'data, 'file, Symbol>
+where
+    'data: 'file,
+{
+    fn debug_id(&self) -> DebugId {
+        self.debug_id
+    }
+
+    fn symbol_count(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|&(_, entry)| {
+                matches!(
+                    entry,
+                    FullSymbolListEntry::Symbol(_)
+                        | FullSymbolListEntry::Export(_)
+                        | FullSymbolListEntry::UnnamedExport(_)
+                )
+            })
+            .count()
+    }
+
+    fn iter_symbols(&self) -> Box<dyn Iterator<Item = (u32, Cow<'_, str>)> + '_> {
+        Box::new(SymbolMapIter {
+            inner: self.entries.iter(),
+        })
+    }
+
+    fn lookup_relative_address(&self, address: u32) -> Option<AddressInfo> {
+        self.lookup_relative_address_impl(address, true)
+    }
+
+    fn lookup_relative_address_without_frames(&self, address: u32) -> Option<AddressInfo> {
+        self.lookup_relative_address_impl(address, false)
+    }
+
+    fn iter_functions(&self, with_lines: bool) -> Box<dyn Iterator<Item = FunctionInfo> + '_> {
+        Box::new(
+            self.entries
+                .iter()
+                .filter(|(_, entry)| !matches!(entry, FullSymbolListEntry::EndAddress))
+                .filter_map(move |&(address, _)| {
+                    let symbol = self.lookup_relative_address_impl(address, false)?.symbol;
+                    let lines = match (with_lines, symbol.size) {
+                        (true, Some(size)) => self.line_ranges(symbol.address, size),
+                        _ => None,
+                    };
+                    Some(FunctionInfo { symbol, lines })
+                }),
+        )
+    }
 
     fn lookup_svma(&self, svma: u64) -> Option<AddressInfo> {
         let relative_address = svma.checked_sub(self.image_base_address)?.try_into().ok()?;
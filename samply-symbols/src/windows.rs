@@ -15,7 +15,8 @@ use debugid::DebugId;
 use nom::bytes::complete::{tag, take_until1};
 use nom::combinator::eof;
 use nom::sequence::terminated;
-use object::{File, FileKind};
+use object::read::pe::ExportTarget;
+use object::{File, FileKind, ReadRef};
 use pdb::PDB;
 use pdb_addr2line::pdb;
 use std::borrow::Cow;
@@ -32,6 +33,7 @@ pub async fn load_symbol_map_for_pdb_corresponding_to_binary<
     file_contents: &FileContentsWrapper<impl FileContents + 'static>,
     file_location: FL,
     helper: &'h H,
+    ignore_pdb_age: bool,
 ) -> Result<SymbolMap<FL>, Error> {
     use object::Object;
     let pe =
@@ -53,7 +55,7 @@ pub async fn load_symbol_map_for_pdb_corresponding_to_binary<
         .await
         .map_err(|e| Error::HelperErrorDuringOpenFile(pdb_path_str.to_string(), e))?;
     let symbol_map = get_symbol_map_for_pdb(FileContentsWrapper::new(pdb_file), file_location)?;
-    if symbol_map.debug_id() != binary_debug_id {
+    if !crate::debug_ids_match(symbol_map.debug_id(), binary_debug_id, ignore_pdb_age) {
         return Err(Error::UnmatchedDebugId(
             binary_debug_id,
             symbol_map.debug_id(),
@@ -62,16 +64,30 @@ pub async fn load_symbol_map_for_pdb_corresponding_to_binary<
     Ok(symbol_map)
 }
 
+/// Creates a symbol map from the PE binary itself, for binaries without a PDB.
+/// This uses the COFF symbol table, embedded DWARF debug info (MinGW / Clang
+/// binaries), the exports, and the function list in .pdata.
+///
+/// Exports which only have an ordinal are named with the help of the
+/// module-definition (.def) file, if one is supplied.
 pub fn get_symbol_map_for_pe<F, FL>(
     file_contents: FileContentsWrapper<F>,
     file_kind: FileKind,
     file_location: FL,
+    def_file_contents: Option<FileContentsWrapper<F>>,
 ) -> Result<SymbolMap<FL>, Error>
 where
     F: FileContents + 'static,
     FL: FileLocation,
 {
-    let owner = PeSymbolMapData::new(file_contents, file_kind);
+    let ordinal_names = def_file_contents
+        .and_then(|def_file| {
+            let len = def_file.len();
+            let bytes = def_file.read_bytes_at(0, len).ok()?;
+            Some(parse_def_file_ordinals(&String::from_utf8_lossy(bytes)))
+        })
+        .unwrap_or_default();
+    let owner = PeSymbolMapData::new(file_contents, file_kind, ordinal_names);
     let symbol_map = GenericSymbolMap::new(owner)?;
     Ok(SymbolMap::new(file_location, Box::new(symbol_map)))
 }
@@ -82,13 +98,19 @@ where
 {
     file_data: FileContentsWrapper<T>,
     file_kind: FileKind,
+    ordinal_names: HashMap<u32, String>,
 }
 
 impl<T: FileContents> PeSymbolMapData<T> {
-    pub fn new(file_data: FileContentsWrapper<T>, file_kind: FileKind) -> Self {
+    pub fn new(
+        file_data: FileContentsWrapper<T>,
+        file_kind: FileKind,
+        ordinal_names: HashMap<u32, String>,
+    ) -> Self {
         Self {
             file_data,
             file_kind,
+            ordinal_names,
         }
     }
 }
@@ -101,6 +123,7 @@ impl<T: FileContents + 'static> SymbolMapDataOuterTrait for PeSymbolMapData<T> {
             File::parse(&self.file_data).map_err(|e| Error::ObjectParseError(self.file_kind, e))?;
         let debug_id = debug_id_for_object(&object)
             .ok_or(Error::InvalidInputError("debug ID cannot be read"))?;
+        let unnamed_exports = unnamed_exports(&object, &self.ordinal_names);
         let object = ObjectSymbolMapDataMid::new(
             object,
             None,
@@ -109,7 +132,8 @@ impl<T: FileContents + 'static> SymbolMapDataOuterTrait for PeSymbolMapData<T> {
             None,
             None,
             debug_id,
-        );
+        )
+        .with_unnamed_exports(unnamed_exports);
 
         Ok(Box::new(object))
     }
@@ -140,6 +164,105 @@ impl<'data> FunctionAddressesComputer<'data> for PeFunctionAddressesComputer {
     }
 }
 
+/// Returns the exports which are only exported by ordinal, as pairs of
+/// (relative address, name). `object` only lists the exports with names.
+///
+/// The names come from `ordinal_names` if possible; other exports are called
+/// "Ordinal123", which is also how debuggers on Windows refer to them.
+fn unnamed_exports<'data, R: ReadRef<'data>>(
+    object: &File<'data, R>,
+    ordinal_names: &HashMap<u32, String>,
+) -> Vec<(u32, String)> {
+    let export_table = match object {
+        File::Pe32(pe) => pe.export_table(),
+        File::Pe64(pe) => pe.export_table(),
+        _ => return Vec::new(),
+    };
+    let Some(exports) = export_table
+        .ok()
+        .flatten()
+        .and_then(|export_table| export_table.exports().ok())
+    else {
+        return Vec::new();
+    };
+    exports
+        .into_iter()
+        .filter(|export| export.name.is_none())
+        .filter_map(|export| match export.target {
+            // Gaps in the ordinal range show up as exports with a zero address.
+            ExportTarget::Address(address) if address != 0 => {
+                let name = match ordinal_names.get(&export.ordinal) {
+                    Some(name) => name.clone(),
+                    None => format!("Ordinal{}", export.ordinal),
+                };
+                Some((address, name))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parses the EXPORTS section of a module-definition (.def) file, and returns
+/// a map from ordinal to export name. Entries without an ordinal are skipped.
+///
+/// The entries look like `name[=internal_name] [@ordinal [NONAME]] [DATA] [PRIVATE]`.
+fn parse_def_file_ordinals(def_file: &str) -> HashMap<u32, String> {
+    let mut ordinal_names = HashMap::new();
+    let mut in_exports = false;
+    for line in def_file.lines() {
+        let line = match line.split_once(';') {
+            Some((before_comment, _)) => before_comment,
+            None => line,
+        };
+        let mut words = line.split_whitespace().peekable();
+        let Some(&first_word) = words.peek() else {
+            continue;
+        };
+        match first_word.to_ascii_uppercase().as_str() {
+            "EXPORTS" => {
+                in_exports = true;
+                words.next();
+                if words.peek().is_none() {
+                    continue;
+                }
+            }
+            "LIBRARY" | "NAME" | "HEAPSIZE" | "STACKSIZE" | "SECTIONS" | "STUB" | "VERSION" => {
+                in_exports = false;
+                continue;
+            }
+            _ => {}
+        }
+        if !in_exports {
+            continue;
+        }
+        let Some(name) = words.next() else {
+            continue;
+        };
+        let name = name.split('=').next().unwrap_or(name).trim_matches('"');
+        let ordinal = match words.next() {
+            Some("@") => words.next(),
+            Some(word) => word.strip_prefix('@'),
+            None => None,
+        };
+        if let Some(ordinal) = ordinal.and_then(|ordinal| ordinal.parse::<u32>().ok()) {
+            ordinal_names.insert(ordinal, name.to_string());
+        }
+    }
+    ordinal_names
+}
+
+/// Returns whether the PE binary has DWARF debug info in its sections, which is
+/// the case for binaries built by MinGW or by Clang with -gdwarf.
+pub fn has_embedded_dwarf<F: FileContents>(file: &FileContentsWrapper<F>) -> bool {
+    use object::{Object, ObjectSection};
+    match File::parse(file) {
+        Ok(object) => object
+            .section_by_name(".debug_info")
+            .map_or(false, |section| section.size() != 0),
+        Err(_) => false,
+    }
+}
+
 pub fn is_pdb_file<F: FileContents>(file: &FileContentsWrapper<F>) -> bool {
     PDB::open(file).is_ok()
 }
@@ -299,6 +422,9 @@ impl<T: FileContents + 'static> SymbolMapDataOuterTrait for PdbSymbolData<T> {
         let mut pdb = PDB::open(&self.0)?;
         let info = pdb.pdb_information().context("pdb_information")?;
         let dbi = pdb.debug_information()?;
+        // The DBI stream's age is the one that the binary refers to. It can be one
+        // higher than the age in the PDB info stream, so there's some ambiguity; see
+        // `SymbolManager::set_ignore_pdb_age` for tolerating mismatched ages.
         let age = dbi.age().unwrap_or(info.age);
         let debug_id = DebugId::from_parts(info.guid, age);
 
@@ -521,6 +647,26 @@ fn parse_gitiles_url(input: &str) -> Result<MappedPath, nom::Err<nom::error::Err
 mod test {
     use super::*;
 
+    #[test]
+    fn test_parse_def_file_ordinals() {
+        let def_file = r#"
+LIBRARY mylib.dll
+EXPORTS
+    ; Exported by name and ordinal
+    CreateThing @1
+    DestroyThing=destroy_thing_impl @2 NONAME
+    "QuotedName" @ 3 PRIVATE
+    g_data DATA
+EXPORTS UnnamedHelper @17 NONAME
+"#;
+        let ordinal_names = parse_def_file_ordinals(def_file);
+        assert_eq!(ordinal_names.len(), 4);
+        assert_eq!(ordinal_names[&1], "CreateThing");
+        assert_eq!(ordinal_names[&2], "DestroyThing");
+        assert_eq!(ordinal_names[&3], "QuotedName");
+        assert_eq!(ordinal_names[&17], "UnnamedHelper");
+    }
+
     #[test]
     fn test_parse_gitiles_url() {
         assert_eq!(
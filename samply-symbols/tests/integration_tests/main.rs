@@ -119,6 +119,10 @@ impl FileLocation for FileLocationType {
     fn location_for_breakpad_symindex(&self) -> Option<Self> {
         Some(Self(self.0.with_extension("symindex")))
     }
+
+    fn location_for_def_file(&self) -> Option<Self> {
+        Some(Self(self.0.with_extension("def")))
+    }
 }
 
 fn mmap_to_file_contents(m: memmap2::Mmap) -> FileContentsType {
@@ -349,6 +353,78 @@ fn unsuccessful_pdb_wrong_id() {
     }
 }
 
+/// Writes a copy of win64-ci/mozglue.dll whose CodeView record is changed by
+/// `patch`, and which refers to the unchanged mozglue.pdb by a relative path.
+fn write_patched_mozglue_dll(dir: &Path, patch: impl FnOnce(&mut [u8; 16], &mut u32)) -> PathBuf {
+    let fixtures_dir = fixtures_dir().join("win64-ci");
+    let mut dll = std::fs::read(fixtures_dir.join("mozglue.dll")).unwrap();
+    let pdb_path_in_binary = b"/builds/worker/workspace/obj-build/mozglue/build/mozglue.pdb";
+    let pdb_path_offset = dll
+        .windows(pdb_path_in_binary.len())
+        .position(|window| window == pdb_path_in_binary)
+        .unwrap();
+    // The CodeView record is "RSDS", the GUID, the age, and the PDB path.
+    let record_offset = pdb_path_offset - 24;
+    assert_eq!(&dll[record_offset..record_offset + 4], b"RSDS");
+    let mut guid: [u8; 16] = dll[record_offset + 4..record_offset + 20]
+        .try_into()
+        .unwrap();
+    let mut age = u32::from_le_bytes(dll[record_offset + 20..pdb_path_offset].try_into().unwrap());
+    patch(&mut guid, &mut age);
+    dll[record_offset + 4..record_offset + 20].copy_from_slice(&guid);
+    dll[record_offset + 20..pdb_path_offset].copy_from_slice(&age.to_le_bytes());
+    // The tests run in the crate directory.
+    let pdb_path = b"../fixtures/win64-ci/mozglue.pdb\0";
+    assert!(Path::new(std::str::from_utf8(&pdb_path[..pdb_path.len() - 1]).unwrap()).exists());
+    dll[pdb_path_offset..pdb_path_offset + pdb_path.len()].copy_from_slice(pdb_path);
+    let dll_path = dir.join("mozglue.dll");
+    std::fs::write(&dll_path, dll).unwrap();
+    dll_path
+}
+
+/// Loads the symbol map for the DLL, and returns the debug ID of the file it
+/// came from: the PDB's if the PDB was accepted, otherwise the DLL's own.
+fn debug_id_of_dll_symbol_map(dll_path: &Path, ignore_pdb_age: bool) -> DebugId {
+    let helper = Helper {
+        symbol_directory: dll_path.parent().unwrap().to_path_buf(),
+    };
+    let mut symbol_manager = SymbolManager::with_helper(&helper);
+    symbol_manager.set_ignore_pdb_age(ignore_pdb_age);
+    let symbol_map = futures::executor::block_on(
+        symbol_manager.load_symbol_map_from_location(FileLocationType::new(dll_path), None),
+    )
+    .unwrap();
+    symbol_map.debug_id()
+}
+
+#[test]
+fn pdb_with_different_age() {
+    let pdb_debug_id = DebugId::from_breakpad("63C609072D3499F64C4C44205044422E1").unwrap();
+    let dir = tempfile::tempdir().unwrap();
+
+    let unchanged_dll = write_patched_mozglue_dll(dir.path(), |_, _| {});
+    assert_eq!(
+        debug_id_of_dll_symbol_map(&unchanged_dll, false),
+        pdb_debug_id
+    );
+
+    // Only the age differs: the PDB is used if ages are ignored.
+    let dll = write_patched_mozglue_dll(dir.path(), |_, age| *age += 1);
+    let dll_debug_id = DebugId::from_breakpad("63C609072D3499F64C4C44205044422E2").unwrap();
+    assert_eq!(debug_id_of_dll_symbol_map(&dll, true), pdb_debug_id);
+    assert_eq!(debug_id_of_dll_symbol_map(&dll, false), dll_debug_id);
+}
+
+#[test]
+fn pdb_with_different_guid() {
+    let dir = tempfile::tempdir().unwrap();
+    let dll = write_patched_mozglue_dll(dir.path(), |guid, _| guid[0] ^= 0xff);
+    // The PDB is never used, so the symbols come from the DLL itself.
+    let dll_debug_id = DebugId::from_breakpad("63C609F82D3499F64C4C44205044422E1").unwrap();
+    assert_eq!(debug_id_of_dll_symbol_map(&dll, true), dll_debug_id);
+    assert_eq!(debug_id_of_dll_symbol_map(&dll, false), dll_debug_id);
+}
+
 #[test]
 fn unspecified_id_fat_arch() {
     let result = futures::executor::block_on(crate::get_table(
@@ -413,6 +489,52 @@ fn fat_arch_2() {
     );
 }
 
+#[test]
+fn iter_functions_with_lines() {
+    let helper = Helper {
+        symbol_directory: fixtures_dir().join("android32-local"),
+    };
+    let symbol_manager = SymbolManager::with_helper(&helper);
+    let symbol_map = futures::executor::block_on(symbol_manager.load_symbol_map_from_location(
+        FileLocationType(fixtures_dir().join("android32-local").join("libmozglue.so")),
+        None,
+    ))
+    .unwrap();
+
+    let functions: Vec<_> = symbol_map.iter_functions(false).collect();
+    assert!(functions.iter().all(|function| function.lines.is_none()));
+    assert!(functions
+        .windows(2)
+        .all(|pair| pair[0].symbol.address < pair[1].symbol.address));
+
+    let function = symbol_map
+        .iter_functions(true)
+        .find(|function| function.symbol.address == 0xa7199)
+        .unwrap();
+    assert_eq!(function.symbol.size, Some(0x9a0));
+    assert!(function
+        .symbol
+        .name
+        .starts_with("mozilla::baseprofiler::ProfileBuffer::StreamSamplesToJSON"));
+    let lines = function.lines.unwrap();
+    assert!(lines.iter().all(|line_range| line_range.address >= 0xa7199
+        && line_range.address + line_range.size <= 0xa7199 + 0x9a0));
+    // 0xa7748 is in std::string::append, which was inlined into this function.
+    let line_range = lines
+        .iter()
+        .find(|line_range| {
+            line_range.address <= 0xa7748 && 0xa7748 < line_range.address + line_range.size
+        })
+        .unwrap();
+    assert_eq!(line_range.line_number, Some(2582));
+    assert!(line_range
+        .file_path
+        .as_ref()
+        .unwrap()
+        .raw_path()
+        .ends_with("include/string"));
+}
+
 #[test]
 fn linux_nonzero_base_address() {
     let helper = Helper {
@@ -0,0 +1,48 @@
+#![no_main]
+
+use fxprof_processed_profile::{
+    LibraryInfo, Profile, ReferenceTimestamp, SamplingInterval, Timestamp,
+};
+use libfuzzer_sys::fuzz_target;
+use linux_perf_data::jitdump::JitDumpReader;
+use samply::shared::jit_category_manager::JitCategoryManager;
+use samply::shared::jitdump_manager::SingleJitDumpProcessor;
+use samply::shared::timestamp_converter::TimestampConverter;
+
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(reader) = JitDumpReader::new(Cursor::new(data)) else {
+        return;
+    };
+    let mut profile = Profile::new(
+        "fuzz",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("fuzz", 1, Timestamp::from_millis_since_reference(0.0));
+    let thread = profile.add_thread(process, 1, Timestamp::from_millis_since_reference(0.0), true);
+    let lib = profile.add_lib(LibraryInfo {
+        name: "jit-1.dump".to_string(),
+        debug_name: "jit-1.dump".to_string(),
+        path: "/tmp/jit-1.dump".to_string(),
+        debug_path: "/tmp/jit-1.dump".to_string(),
+        debug_id: Default::default(),
+        code_id: None,
+        arch: None,
+        symbol_table: None,
+    });
+    let timestamp_converter = TimestampConverter {
+        reference_raw: 0,
+        raw_to_ns_factor: 1,
+    };
+    let mut jit_category_manager = JitCategoryManager::new();
+    let mut processor = SingleJitDumpProcessor::new(reader, lib, thread);
+    processor.process_pending_records(
+        &mut jit_category_manager,
+        &mut profile,
+        None,
+        &timestamp_converter,
+    );
+    processor.finish(&mut profile);
+});
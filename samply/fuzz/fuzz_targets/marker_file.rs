@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use samply::shared::marker_file::process_marker_file_line;
+use samply::shared::timestamp_converter::TimestampConverter;
+
+fuzz_target!(|data: &[u8]| {
+    let timestamp_converter = TimestampConverter {
+        reference_raw: 0,
+        raw_to_ns_factor: 1,
+    };
+    for line in String::from_utf8_lossy(data).lines() {
+        let _ = process_marker_file_line(line, &timestamp_converter);
+    }
+});
@@ -0,0 +1,26 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use samply::import::perf::convert;
+use samply::shared::recording_props::ConversionProps;
+
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    let conversion_props = ConversionProps {
+        profile_name: "fuzz".to_string(),
+        reuse_threads: false,
+        fold_recursive_prefix: false,
+        frame_marker: None,
+        latency_markers: None,
+        stack_stitching_rules: Vec::new(),
+        per_cpu_threads: false,
+        aggregate_by_name: false,
+        wall_clock: false,
+        blocked_time_weights: false,
+        code_address_bits: Vec::new(),
+        unknown_events_file: None,
+    };
+    // Errors are fine, panics are not.
+    let _ = convert(Cursor::new(data), None, conversion_props);
+});
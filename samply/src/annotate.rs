@@ -0,0 +1,590 @@
+use serde_json::Value;
+use wholesym::{MappedPath, SymbolManager};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+
+use crate::profile_symbolication::{
+    count_leaf_samples, json_array, lookup_symbols, parse_hex_u32, profile_lib_ids, read_profile,
+    AddressSymbol,
+};
+use crate::server::{symbol_manager_for_parsed_profile, SymbolDownloads, SymbolIdMatching};
+
+/// How many lines around each sampled line are printed in the text output.
+const CONTEXT_LINES: u64 = 2;
+
+pub struct AnnotateProps {
+    pub source_dirs: Vec<PathBuf>,
+    pub file_count: usize,
+    /// Disassemble the functions with the most samples, instead of showing
+    /// source lines.
+    pub asm: bool,
+    pub function_count: usize,
+    pub html_output: Option<PathBuf>,
+    pub verbose: bool,
+    pub symbol_dirs: Vec<PathBuf>,
+    pub symbol_downloads: SymbolDownloads,
+}
+
+/// A source file with the number of samples per line, and its contents if the
+/// file was found on this machine.
+struct AnnotatedFile {
+    /// The path from the debug info.
+    path: String,
+    local_path: Option<PathBuf>,
+    source_lines: Option<Vec<String>>,
+    sample_count: u64,
+    line_samples: BTreeMap<u64, u64>,
+}
+
+/// A function with the number of samples per instruction address.
+struct AnnotatedFunction {
+    name: String,
+    lib: usize,
+    address: u32,
+    size: Option<u32>,
+    sample_count: u64,
+    address_samples: BTreeMap<u32, u64>,
+    /// The address and the text of each instruction, or the reason why the
+    /// function couldn't be disassembled.
+    instructions: Result<Vec<(u32, String)>, String>,
+}
+
+/// Prints the source files which have the most samples in the profile, with the
+/// number of samples for each line. Only the leaf frame of each sample counts,
+/// and for inlined code the sample is attributed to the innermost inlined
+/// function's line. In asm mode, the functions with the most samples are
+/// disassembled from the local binaries instead, with the samples per instruction.
+#[tokio::main]
+pub async fn annotate_profile(profile_path: &Path, props: &AnnotateProps) -> Result<(), String> {
+    let profile = read_profile(profile_path)
+        .map_err(|err| format!("Could not read {profile_path:?}: {err}"))?;
+    if profile
+        .pointer("/meta/preprocessedProfileVersion")
+        .is_none()
+    {
+        return Err(format!(
+            "{profile_path:?} is not a processed profile. Only profiles in the Firefox Profiler's processed format can be annotated."
+        ));
+    }
+
+    let self_samples = count_self_samples(&profile);
+    if self_samples.is_empty() {
+        return Err("The profile has no samples in native code.".into());
+    }
+    let mut addresses: Vec<(usize, u32)> = self_samples.keys().copied().collect();
+    addresses.sort_unstable();
+
+    let symbol_manager = symbol_manager_for_parsed_profile(
+        &profile,
+        props.verbose,
+        &props.symbol_dirs,
+        SymbolIdMatching::default(),
+        &props.symbol_downloads,
+    );
+    let symbols = lookup_symbols(&symbol_manager, &profile_lib_ids(&profile), &addresses).await?;
+
+    let total_samples: u64 = self_samples.values().sum();
+    if props.asm {
+        let mut functions = hottest_functions(&self_samples, &symbols, props.function_count);
+        if functions.is_empty() {
+            return Err(
+                "None of the sampled addresses have a symbol. Make sure that the profiled binaries are available, or pass --symbol-dir."
+                    .into(),
+            );
+        }
+        let libs = json_array(&profile, "libs");
+        for function in &mut functions {
+            function.instructions = match libs.get(function.lib) {
+                Some(lib) => disassemble_function(&symbol_manager, lib, function).await,
+                None => Err("The function's library is not in the profile.".into()),
+            };
+        }
+        return write_report(
+            props.html_output.as_deref(),
+            || asm_html(&functions, total_samples),
+            || asm_text(&functions, total_samples),
+        );
+    }
+
+    let mut files: HashMap<&str, AnnotatedFile> = HashMap::new();
+    for (key, count) in &self_samples {
+        let Some((path, line)) = symbols
+            .get(key)
+            .and_then(|symbol| symbol.source_location.as_ref())
+        else {
+            continue;
+        };
+        let file = files.entry(path).or_insert_with(|| AnnotatedFile {
+            path: path.clone(),
+            local_path: None,
+            source_lines: None,
+            sample_count: 0,
+            line_samples: BTreeMap::new(),
+        });
+        file.sample_count += count;
+        *file.line_samples.entry(*line).or_default() += count;
+    }
+    if files.is_empty() {
+        return Err(
+            "None of the sampled addresses have line information. Make sure that the profiled binaries have debug info, or pass --symbol-dir."
+                .into(),
+        );
+    }
+
+    let mut files: Vec<AnnotatedFile> = files.into_values().collect();
+    files.sort_by(|a, b| {
+        b.sample_count
+            .cmp(&a.sample_count)
+            .then_with(|| a.path.cmp(&b.path))
+    });
+    files.truncate(props.file_count);
+    for file in &mut files {
+        file.local_path = find_source_file(&file.path, &props.source_dirs);
+        file.source_lines = file
+            .local_path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|source| source.lines().map(String::from).collect());
+    }
+
+    write_report(
+        props.html_output.as_deref(),
+        || annotation_html(&files, total_samples),
+        || annotation_text(&files, total_samples),
+    )
+}
+
+/// Writes the HTML report to `html_output` if it's set, and prints the text
+/// report otherwise.
+fn write_report(
+    html_output: Option<&Path>,
+    html: impl FnOnce() -> String,
+    text: impl FnOnce() -> String,
+) -> Result<(), String> {
+    match html_output {
+        Some(html_output) => {
+            std::fs::write(html_output, html())
+                .map_err(|err| format!("Could not write {html_output:?}: {err}"))?;
+            eprintln!("Saved the annotation to {html_output:?}.");
+        }
+        None => print!("{}", text()),
+    }
+    Ok(())
+}
+
+/// Counts how many samples (or how much sample weight) each (lib index, address)
+/// pair has as the leaf frame, across all threads.
+fn count_self_samples(profile: &Value) -> HashMap<(usize, u32), u64> {
+    let mut counts = HashMap::new();
+    for thread in json_array(profile, "threads") {
+        count_leaf_samples(thread, &mut counts);
+    }
+    counts
+}
+
+/// Groups the sampled addresses by function, and returns the `count` functions
+/// with the most samples.
+fn hottest_functions(
+    self_samples: &HashMap<(usize, u32), u64>,
+    symbols: &HashMap<(usize, u32), AddressSymbol>,
+    count: usize,
+) -> Vec<AnnotatedFunction> {
+    let mut functions: HashMap<(usize, u32), AnnotatedFunction> = HashMap::new();
+    for (&(lib, address), sample_count) in self_samples {
+        let Some(symbol) = symbols.get(&(lib, address)) else {
+            continue;
+        };
+        let Some(function_address) = symbol.function_address else {
+            continue;
+        };
+        let function =
+            functions
+                .entry((lib, function_address))
+                .or_insert_with(|| AnnotatedFunction {
+                    name: symbol.function.clone(),
+                    lib,
+                    address: function_address,
+                    size: symbol.function_size,
+                    sample_count: 0,
+                    address_samples: BTreeMap::new(),
+                    instructions: Ok(Vec::new()),
+                });
+        function.sample_count += sample_count;
+        *function.address_samples.entry(address).or_default() += sample_count;
+    }
+    let mut functions: Vec<AnnotatedFunction> = functions.into_values().collect();
+    functions.sort_by(|a, b| {
+        b.sample_count
+            .cmp(&a.sample_count)
+            .then_with(|| (a.lib, a.address).cmp(&(b.lib, b.address)))
+    });
+    functions.truncate(count);
+    functions
+}
+
+/// Disassembles the function with the server's asm API, from the binary for
+/// `lib` on this machine.
+async fn disassemble_function(
+    symbol_manager: &SymbolManager,
+    lib: &Value,
+    function: &AnnotatedFunction,
+) -> Result<Vec<(u32, String)>, String> {
+    // Without a known function size, disassemble at least up to the last sampled
+    // instruction and let the API find the function end.
+    let size = match function.size {
+        Some(size) => size,
+        None => function
+            .address_samples
+            .keys()
+            .next_back()
+            .map_or(1, |last| last - function.address + 1),
+    };
+    let request = serde_json::json!({
+        "name": lib.get("name"),
+        "codeId": lib.get("codeId"),
+        "debugName": lib.get("debugName"),
+        "debugId": lib.get("breakpadId"),
+        "startAddress": format!("{:#x}", function.address),
+        "size": format!("{size:#x}"),
+        "continueUntilFunctionEnd": function.size.is_none(),
+    });
+    let response = symbol_manager
+        .query_json_api("/asm/v1", &request.to_string())
+        .await;
+    let response: Value =
+        serde_json::from_str(&response).map_err(|err| format!("Invalid asm response: {err}"))?;
+    if let Some(error) = response.get("error") {
+        return Err(error.as_str().unwrap_or("Unknown error").to_string());
+    }
+    let start_address = response
+        .get("startAddress")
+        .and_then(Value::as_str)
+        .and_then(parse_hex_u32)
+        .unwrap_or(function.address);
+    let instructions = json_array(&response, "instructions")
+        .iter()
+        .filter_map(|instruction| {
+            let offset = instruction.get(0)?.as_u64()? as u32;
+            let text = instruction.get(1)?.as_str()?;
+            Some((start_address + offset, text.to_string()))
+        })
+        .collect();
+    Ok(instructions)
+}
+
+/// Sums up the samples for each instruction. Sampled addresses which aren't at
+/// the start of an instruction count towards the instruction they're in.
+fn instruction_samples(
+    instructions: &[(u32, String)],
+    address_samples: &BTreeMap<u32, u64>,
+) -> Vec<u64> {
+    instructions
+        .iter()
+        .enumerate()
+        .map(|(i, (address, _))| {
+            let end = instructions.get(i + 1).map_or(u32::MAX, |(next, _)| *next);
+            address_samples
+                .range(*address..end)
+                .map(|(_, count)| count)
+                .sum()
+        })
+        .collect()
+}
+
+/// Finds the source file for a path from the debug info. Relative paths, and
+/// paths from a different machine, are looked up in the source directories by
+/// trying successively shorter suffixes of the path.
+fn find_source_file(path: &str, source_dirs: &[PathBuf]) -> Option<PathBuf> {
+    let path = match MappedPath::from_special_path_str(path) {
+        Some(mapped_path) => PathBuf::from(mapped_path.display_path()),
+        None => PathBuf::from(path),
+    };
+    if path.is_absolute() && path.is_file() {
+        return Some(path);
+    }
+    let components: Vec<Component> = path
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    (0..components.len()).find_map(|start| {
+        let suffix: PathBuf = components[start..].iter().collect();
+        source_dirs
+            .iter()
+            .map(|dir| dir.join(&suffix))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+/// Merges the lines around each sampled line into ranges, clamped to the file.
+fn context_ranges(
+    sampled_lines: impl Iterator<Item = u64>,
+    context: u64,
+    line_count: u64,
+) -> Vec<RangeInclusive<u64>> {
+    let mut ranges: Vec<RangeInclusive<u64>> = Vec::new();
+    for line in sampled_lines {
+        let start = line.saturating_sub(context).max(1);
+        let end = (line + context).min(line_count.max(line));
+        match ranges.last_mut() {
+            Some(last) if start <= last.end() + 1 => *last = *last.start()..=end.max(*last.end()),
+            _ => ranges.push(start..=end),
+        }
+    }
+    ranges
+}
+
+fn percentage(count: u64, total: u64) -> f64 {
+    count as f64 * 100.0 / total as f64
+}
+
+fn annotation_text(files: &[AnnotatedFile], total_samples: u64) -> String {
+    let mut out = String::new();
+    for file in files {
+        let _ = writeln!(
+            out,
+            "{} ({} samples, {:.1}%)",
+            file.path,
+            file.sample_count,
+            percentage(file.sample_count, total_samples)
+        );
+        let Some(source_lines) = &file.source_lines else {
+            let _ = writeln!(
+                out,
+                "  (source file not found, use --source-dir to locate it)"
+            );
+            for (line, count) in &file.line_samples {
+                let _ = writeln!(
+                    out,
+                    "{count:>8} {:>5.1}% {line:>6}",
+                    percentage(*count, total_samples)
+                );
+            }
+            out.push('\n');
+            continue;
+        };
+        let ranges = context_ranges(
+            file.line_samples.keys().copied(),
+            CONTEXT_LINES,
+            source_lines.len() as u64,
+        );
+        for (i, range) in ranges.into_iter().enumerate() {
+            if i != 0 {
+                let _ = writeln!(out, "{:>22}", "...");
+            }
+            for line in range {
+                let source = source_lines
+                    .get(line as usize - 1)
+                    .map_or("", String::as_str);
+                match file.line_samples.get(&line) {
+                    Some(count) => {
+                        let _ = writeln!(
+                            out,
+                            "{count:>8} {:>5.1}% {line:>6} | {source}",
+                            percentage(*count, total_samples)
+                        );
+                    }
+                    None => {
+                        let _ = writeln!(out, "{:>15} {line:>6} | {source}", "");
+                    }
+                }
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn annotation_html(files: &[AnnotatedFile], total_samples: u64) -> String {
+    let mut out = String::from(HTML_HEADER);
+    for file in files {
+        let max_line_samples = file.line_samples.values().copied().max().unwrap_or(1);
+        let _ = writeln!(
+            out,
+            "<h2>{}</h2>\n<p>{} samples, {:.1}%{}</p>\n<table>",
+            escape_html(&file.path),
+            file.sample_count,
+            percentage(file.sample_count, total_samples),
+            match &file.local_path {
+                Some(path) if file.source_lines.is_some() =>
+                    format!(" &mdash; {}", escape_html(&path.to_string_lossy())),
+                _ => " &mdash; source file not found".to_string(),
+            }
+        );
+        let line_count = match &file.source_lines {
+            Some(source_lines) => source_lines.len() as u64,
+            None => file.line_samples.keys().copied().max().unwrap_or(0),
+        };
+        for line in 1..=line_count {
+            if file.source_lines.is_none() && !file.line_samples.contains_key(&line) {
+                continue;
+            }
+            let source = file
+                .source_lines
+                .as_ref()
+                .and_then(|source_lines| source_lines.get(line as usize - 1))
+                .map_or("", String::as_str);
+            write_html_row(
+                &mut out,
+                file.line_samples.get(&line).copied().unwrap_or(0),
+                total_samples,
+                max_line_samples,
+                &line.to_string(),
+                source,
+            );
+        }
+        out.push_str("</table>\n");
+    }
+    out.push_str(HTML_FOOTER);
+    out
+}
+
+fn asm_text(functions: &[AnnotatedFunction], total_samples: u64) -> String {
+    let mut out = String::new();
+    for function in functions {
+        let _ = writeln!(
+            out,
+            "{} ({} samples, {:.1}%)",
+            function.name,
+            function.sample_count,
+            percentage(function.sample_count, total_samples)
+        );
+        let instructions = match &function.instructions {
+            Ok(instructions) => instructions,
+            Err(err) => {
+                let _ = writeln!(out, "  (could not disassemble the function: {err})\n");
+                continue;
+            }
+        };
+        let samples = instruction_samples(instructions, &function.address_samples);
+        for ((address, text), count) in instructions.iter().zip(samples) {
+            if count == 0 {
+                let _ = writeln!(out, "{:>15} {address:>8x}  {text}", "");
+            } else {
+                let _ = writeln!(
+                    out,
+                    "{count:>8} {:>5.1}% {address:>8x}  {text}",
+                    percentage(count, total_samples)
+                );
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn asm_html(functions: &[AnnotatedFunction], total_samples: u64) -> String {
+    let mut out = String::from(HTML_HEADER);
+    for function in functions {
+        let _ = writeln!(
+            out,
+            "<h2>{}</h2>\n<p>{} samples, {:.1}%</p>",
+            escape_html(&function.name),
+            function.sample_count,
+            percentage(function.sample_count, total_samples),
+        );
+        let instructions = match &function.instructions {
+            Ok(instructions) => instructions,
+            Err(err) => {
+                let _ = writeln!(
+                    out,
+                    "<p>Could not disassemble the function: {}</p>",
+                    escape_html(err)
+                );
+                continue;
+            }
+        };
+        let samples = instruction_samples(instructions, &function.address_samples);
+        let max_samples = samples.iter().copied().max().unwrap_or(1);
+        out.push_str("<table>\n");
+        for ((address, text), count) in instructions.iter().zip(samples) {
+            write_html_row(
+                &mut out,
+                count,
+                total_samples,
+                max_samples,
+                &format!("{address:x}"),
+                text,
+            );
+        }
+        out.push_str("</table>\n");
+    }
+    out.push_str(HTML_FOOTER);
+    out
+}
+
+const HTML_HEADER: &str = "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>samply annotate</title>\n<style>\nbody { font-family: sans-serif; }\ntable { border-collapse: collapse; font-family: monospace; white-space: pre; }\ntd { padding: 0 0.5em; }\ntd.count, td.line { text-align: right; color: #666; }\n</style></head><body>\n";
+const HTML_FOOTER: &str = "</body></html>\n";
+
+/// Writes a table row for a source line or an instruction. Rows with samples
+/// are shaded according to how hot they are compared to the hottest row.
+fn write_html_row(
+    out: &mut String,
+    count: u64,
+    total_samples: u64,
+    max_count: u64,
+    label: &str,
+    text: &str,
+) {
+    if count == 0 {
+        let _ = writeln!(
+            out,
+            "<tr><td></td><td></td><td class=\"line\">{label}</td><td>{}</td></tr>",
+            escape_html(text)
+        );
+        return;
+    }
+    let heat = count as f64 / max_count as f64 * 0.6;
+    let _ = writeln!(
+        out,
+        "<tr style=\"background: rgba(255, 0, 0, {heat:.2})\"><td class=\"count\">{count}</td><td class=\"count\">{:.1}%</td><td class=\"line\">{label}</td><td>{}</td></tr>",
+        percentage(count, total_samples),
+        escape_html(text)
+    );
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{context_ranges, instruction_samples};
+
+    #[test]
+    fn merged_context_ranges() {
+        assert_eq!(
+            context_ranges([1, 4, 10, 30].into_iter(), 2, 31),
+            vec![1..=6, 8..=12, 28..=31]
+        );
+        // Lines past the end of the file (e.g. a stale source file) are kept.
+        assert_eq!(context_ranges([20].into_iter(), 2, 10), vec![18..=20]);
+    }
+
+    #[test]
+    fn samples_per_instruction() {
+        let instructions: Vec<(u32, String)> = [0x10, 0x14, 0x17, 0x1c]
+            .into_iter()
+            .map(|address| (address, String::new()))
+            .collect();
+        let address_samples = BTreeMap::from([(0x10, 3), (0x15, 1), (0x17, 2), (0x20, 5)]);
+        assert_eq!(
+            instruction_samples(&instructions, &address_samples),
+            vec![3, 1, 2, 5]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use linux_perf_data::PerfFileReader;
+use serde_json::{json, Value};
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::import;
+use crate::shared::recording_props::ConversionProps;
+
+pub struct BenchImportProps {
+    pub iterations: u32,
+    pub save_baseline: Option<PathBuf>,
+    pub baseline: Option<PathBuf>,
+    /// By how many percent the throughput may drop, or the peak memory usage
+    /// may grow, compared to the baseline before it counts as a regression.
+    pub max_regression_percent: f64,
+}
+
+/// The measurements for one input file.
+struct ImportTiming {
+    name: String,
+    event_count: u64,
+    median_duration: Duration,
+    peak_rss_bytes: Option<u64>,
+}
+
+impl ImportTiming {
+    fn events_per_second(&self) -> f64 {
+        self.event_count as f64 / self.median_duration.as_secs_f64()
+    }
+}
+
+/// Imports each of the perf.data files in `paths` a few times, and prints how
+/// many events per second were converted and how much memory the conversion
+/// needed. Directories are searched for files ending in `.data`.
+///
+/// If a baseline file is given, the results are compared to it, and an error is
+/// returned if any of the files got slower or needed more memory than allowed.
+pub fn bench_import(paths: &[PathBuf], props: &BenchImportProps) -> Result<(), String> {
+    let files = collect_input_files(paths)?;
+    if files.is_empty() {
+        return Err("No perf.data files found.".into());
+    }
+
+    let mut timings = Vec::new();
+    for file in &files {
+        let timing = bench_import_file(file, props.iterations)?;
+        println!(
+            "{:<40} {:>9} events {:>10.1} ms {:>12.0} events/s {:>10}",
+            timing.name,
+            timing.event_count,
+            timing.median_duration.as_secs_f64() * 1000.0,
+            timing.events_per_second(),
+            timing
+                .peak_rss_bytes
+                .map_or("-".to_string(), |bytes| format!("{} MB", bytes / 1_000_000)),
+        );
+        timings.push(timing);
+    }
+
+    if let Some(path) = &props.save_baseline {
+        let results: Vec<Value> = timings
+            .iter()
+            .map(|timing| {
+                json!({
+                    "file": timing.name,
+                    "eventCount": timing.event_count,
+                    "eventsPerSecond": timing.events_per_second(),
+                    "peakRssBytes": timing.peak_rss_bytes,
+                })
+            })
+            .collect();
+        let contents = json!({
+            "samplyVersion": env!("CARGO_PKG_VERSION"),
+            "results": results,
+        });
+        std::fs::write(path, contents.to_string())
+            .map_err(|err| format!("Could not write {path:?}: {err}"))?;
+    }
+
+    if let Some(path) = &props.baseline {
+        let regressions = compare_to_baseline(&timings, path, props.max_regression_percent)?;
+        if !regressions.is_empty() {
+            for regression in &regressions {
+                eprintln!("Regression: {regression}");
+            }
+            return Err(format!(
+                "{} regressions compared to {path:?}.",
+                regressions.len()
+            ));
+        }
+        eprintln!("No regressions compared to {path:?}.");
+    }
+    Ok(())
+}
+
+fn collect_input_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut dir_files: Vec<PathBuf> = std::fs::read_dir(path)
+            .map_err(|err| format!("Could not read directory {path:?}: {err}"))?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().map_or(false, |ext| ext == "data"))
+            .collect();
+        dir_files.sort();
+        files.extend(dir_files);
+    }
+    Ok(files)
+}
+
+fn bench_import_file(path: &Path, iterations: u32) -> Result<ImportTiming, String> {
+    // Read the file into memory, so that the disk isn't part of the measurement.
+    let data = std::fs::read(path).map_err(|err| format!("Could not read {path:?}: {err}"))?;
+    let event_count = count_events(&data)
+        .map_err(|err| format!("Could not parse {path:?} as a perf.data file: {err}"))?;
+    let extra_dir = path.parent();
+
+    reset_peak_rss();
+    let mut durations = Vec::new();
+    for _ in 0..iterations.max(1) {
+        let start = Instant::now();
+        let conversion_props = ConversionProps {
+            profile_name: "Benchmark".to_string(),
+            reuse_threads: false,
+            fold_recursive_prefix: false,
+            frame_marker: None,
+            latency_markers: None,
+            stack_stitching_rules: Vec::new(),
+            per_cpu_threads: false,
+            aggregate_by_name: false,
+            wall_clock: false,
+            blocked_time_weights: false,
+            code_address_bits: Vec::new(),
+            unknown_events_file: None,
+        };
+        let profile = import::perf::convert(Cursor::new(&data[..]), extra_dir, conversion_props)
+            .map_err(|err| format!("Could not import {path:?}: {err}"))?;
+        serde_json::to_writer(std::io::sink(), &profile)
+            .map_err(|err| format!("Could not serialize the profile for {path:?}: {err}"))?;
+        durations.push(start.elapsed());
+    }
+    durations.sort();
+
+    Ok(ImportTiming {
+        name: path
+            .file_name()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy()
+            .into_owned(),
+        event_count,
+        median_duration: durations[durations.len() / 2],
+        peak_rss_bytes: peak_rss_bytes(),
+    })
+}
+
+fn count_events(data: &[u8]) -> Result<u64, linux_perf_data::Error> {
+    let PerfFileReader {
+        mut perf_file,
+        mut record_iter,
+    } = PerfFileReader::parse_file(Cursor::new(data))?;
+    let mut count = 0;
+    while record_iter.next_record(&mut perf_file)?.is_some() {
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Compares the results to a file written with `--save-baseline`, and returns
+/// a description of each regression.
+fn compare_to_baseline(
+    timings: &[ImportTiming],
+    baseline_path: &Path,
+    max_regression_percent: f64,
+) -> Result<Vec<String>, String> {
+    let contents = std::fs::read(baseline_path)
+        .map_err(|err| format!("Could not read {baseline_path:?}: {err}"))?;
+    let baseline: Value = serde_json::from_slice(&contents)
+        .map_err(|err| format!("Could not parse {baseline_path:?}: {err}"))?;
+    let results = baseline
+        .get("results")
+        .and_then(Value::as_array)
+        .ok_or_else(|| format!("{baseline_path:?} has no results"))?;
+
+    let allowed_factor = max_regression_percent / 100.0;
+    let mut regressions = Vec::new();
+    for timing in timings {
+        let Some(result) = results
+            .iter()
+            .find(|result| result.get("file").and_then(Value::as_str) == Some(&timing.name))
+        else {
+            eprintln!("{} is not in the baseline.", timing.name);
+            continue;
+        };
+        if let Some(baseline_events_per_second) =
+            result.get("eventsPerSecond").and_then(Value::as_f64)
+        {
+            let events_per_second = timing.events_per_second();
+            if events_per_second < baseline_events_per_second * (1.0 - allowed_factor) {
+                regressions.push(format!(
+                    "{} converted {events_per_second:.0} events/s, down from {baseline_events_per_second:.0}",
+                    timing.name
+                ));
+            }
+        }
+        if let (Some(baseline_rss), Some(rss)) = (
+            result.get("peakRssBytes").and_then(Value::as_u64),
+            timing.peak_rss_bytes,
+        ) {
+            if rss as f64 > baseline_rss as f64 * (1.0 + allowed_factor) {
+                regressions.push(format!(
+                    "{} needed {} MB at peak, up from {} MB",
+                    timing.name,
+                    rss / 1_000_000,
+                    baseline_rss / 1_000_000
+                ));
+            }
+        }
+    }
+    Ok(regressions)
+}
+
+/// Resets the peak resident set size which is reported by [`peak_rss_bytes`],
+/// so that each file is measured on its own.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn reset_peak_rss() {
+    // Writing 5 to clear_refs resets VmHWM, the peak RSS.
+    let _ = std::fs::write("/proc/self/clear_refs", "5");
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn reset_peak_rss() {}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn peak_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?;
+    let kilobytes: u64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// The peak resident set size of the whole process. This can't be reset, so
+/// only the first file is measured accurately.
+#[cfg(target_os = "macos")]
+fn peak_rss_bytes() -> Option<u64> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    // ru_maxrss is in bytes on macOS.
+    Some(usage.ru_maxrss as u64)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux", target_os = "macos")))]
+fn peak_rss_bytes() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::bench_import_file;
+
+    use std::path::Path;
+
+    #[test]
+    fn import_synthetic_fixture() {
+        let path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../fixtures/perf/synthetic-fp.perf.data");
+        let timing = bench_import_file(&path, 1).unwrap();
+        // 2500 samples, plus the comm, mmap, fork and exit records.
+        assert_eq!(timing.event_count, 2513);
+    }
+}
@@ -0,0 +1,96 @@
+//! `cargo samply`: builds the current crate with debug info and frame pointers,
+//! and records the binary with `samply record`.
+
+use clap::{Args, Parser};
+use samply::cargo_samply::{build_for_profiling, samply_executable, BuildOptions};
+
+use std::ffi::OsString;
+use std::process::Command;
+
+/// cargo runs subcommands as `cargo-samply samply <args>`.
+#[derive(Debug, Parser)]
+#[command(name = "cargo", bin_name = "cargo")]
+enum Cargo {
+    Samply(CargoSamplyArgs),
+}
+
+/// Build the current crate for profiling and record it with samply.
+///
+/// The binary is built with the release profile, with debug info and with
+/// frame pointers, and then run under `samply record`.
+#[derive(Debug, Args)]
+#[command(version, about)]
+struct CargoSamplyArgs {
+    /// Package to build.
+    #[arg(short, long, value_name = "SPEC")]
+    package: Option<String>,
+
+    /// Binary target to build and record.
+    #[arg(long, value_name = "NAME", conflicts_with = "example")]
+    bin: Option<String>,
+
+    /// Example target to build and record.
+    #[arg(long, value_name = "NAME")]
+    example: Option<String>,
+
+    /// Cargo profile to build with. Debug info is turned on for it.
+    #[arg(long, value_name = "PROFILE-NAME", default_value = "release")]
+    profile: String,
+
+    /// Space or comma separated list of features to activate.
+    #[arg(short = 'F', long)]
+    features: Vec<String>,
+
+    /// Activate all available features.
+    #[arg(long)]
+    all_features: bool,
+
+    /// Do not activate the `default` feature.
+    #[arg(long)]
+    no_default_features: bool,
+
+    /// Extra argument for `samply record`, e.g. `--record-arg=--rate=4000`.
+    /// Can be given multiple times.
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    record_arg: Vec<OsString>,
+
+    /// Arguments for the recorded binary.
+    #[arg(last = true)]
+    args: Vec<OsString>,
+}
+
+fn main() {
+    let Cargo::Samply(args) = Cargo::parse();
+    let build_options = BuildOptions {
+        package: args.package,
+        bin: args.bin,
+        example: args.example,
+        profile: Some(args.profile),
+        features: args.features,
+        all_features: args.all_features,
+        no_default_features: args.no_default_features,
+    };
+    let executable = match build_for_profiling(&build_options) {
+        Ok(executable) => executable,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1)
+        }
+    };
+
+    let samply = samply_executable();
+    let status = Command::new(&samply)
+        .arg("record")
+        .args(&args.record_arg)
+        .arg("--")
+        .arg(&executable)
+        .args(&args.args)
+        .status();
+    match status {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(err) => {
+            eprintln!("Could not run {samply:?}: {err}");
+            std::process::exit(1)
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::saved_profiles::format_utc_timestamp;
+
+/// Set in the environment of the samply process which records one capture, to
+/// the path of the profile.
+const CAPTURE_OUTPUT_ENV_VAR: &str = "SAMPLY_SCHEDULED_CAPTURE_OUTPUT";
+
+/// The environment variable which --export-token is read from.
+pub const EXPORT_TOKEN_ENV_VAR: &str = "SAMPLY_EXPORT_TOKEN";
+
+/// The length of the timestamp in the capture file names, e.g. "20261016T130203Z".
+const TIMESTAMP_LEN: usize = 16;
+
+/// Repeated fixed-length recordings for `samply record --every`, as a simple
+/// form of continuous profiling on a single machine.
+///
+/// The captures are saved in `output_dir` as `<name>-<UTC timestamp>.json`,
+/// and only the `keep` most recent ones are kept.
+pub struct CaptureSchedule {
+    /// The time from the start of one capture to the start of the next one.
+    pub every: Duration,
+    pub output_dir: PathBuf,
+    pub name: String,
+    pub keep: usize,
+}
+
+impl CaptureSchedule {
+    /// Calls `capture` with the path of the profile to record, once per
+    /// interval, until `stop` is set or until `capture` returns false. Old
+    /// captures are deleted after each new one.
+    pub fn run(&self, stop: &AtomicBool, mut capture: impl FnMut(&Path) -> bool) {
+        if let Err(err) = std::fs::create_dir_all(&self.output_dir) {
+            eprintln!(
+                "Could not create the directory {:?}: {err}",
+                self.output_dir
+            );
+            return;
+        }
+        loop {
+            let start = Instant::now();
+            let seconds_since_epoch = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            let path = self.output_dir.join(format!(
+                "{}-{}.json",
+                self.name,
+                format_utc_timestamp(seconds_since_epoch)
+            ));
+            let succeeded = capture(&path);
+            self.delete_old_captures();
+            if !succeeded || stop.load(Ordering::SeqCst) {
+                return;
+            }
+            // If a capture took longer than the interval, start the next one
+            // right away.
+            let next_start = start + self.every;
+            while Instant::now() < next_start {
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    /// Deletes all but the `keep` most recent captures, with their metadata
+    /// files.
+    fn delete_old_captures(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.output_dir) else {
+            return;
+        };
+        let mut file_names: Vec<String> = entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|file_name| is_capture_file_name(file_name, &self.name))
+            .collect();
+        // The timestamps in the names sort by time.
+        file_names.sort_unstable();
+        let delete_count = file_names.len().saturating_sub(self.keep);
+        for file_name in &file_names[..delete_count] {
+            let path = self.output_dir.join(file_name);
+            if let Err(err) = std::fs::remove_file(&path) {
+                eprintln!("Could not delete the old capture {path:?}: {err}");
+            }
+            let _ = std::fs::remove_file(self.output_dir.join(format!("{file_name}.meta.json")));
+        }
+    }
+}
+
+/// If this process records a single capture of a schedule, returns the path of
+/// its profile.
+pub fn scheduled_capture_output() -> Option<PathBuf> {
+    let path = std::env::var_os(CAPTURE_OUTPUT_ENV_VAR)?;
+    std::env::remove_var(CAPTURE_OUTPUT_ENV_VAR);
+    Some(PathBuf::from(path))
+}
+
+/// Records one capture to `path` by running this samply command again, so that
+/// each capture starts out with a fresh process, just like a single recording.
+/// Returns whether the capture succeeded.
+pub fn record_capture(path: &Path) -> bool {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => {
+            eprintln!("Could not find the samply executable: {err}");
+            return false;
+        }
+    };
+    // The capture doesn't need the export token, the schedule exports it.
+    let status = Command::new(exe)
+        .args(args_without_export_token(std::env::args_os().skip(1)))
+        .env(CAPTURE_OUTPUT_ENV_VAR, path)
+        .status();
+    match status {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            eprintln!("Stopping, because the recording for {path:?} failed: {status}");
+            false
+        }
+        Err(err) => {
+            eprintln!("Could not start the recording for {path:?}: {err}");
+            false
+        }
+    }
+}
+
+/// Returns the arguments of a samply command without --export-token, for
+/// running samply again. Command lines can be read by other users of the
+/// machine, so the token is passed in the environment where it's needed.
+/// Arguments after "--" belong to the recorded command and are kept.
+pub fn args_without_export_token(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut result = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            result.push(arg);
+            result.extend(args);
+            break;
+        }
+        if arg == "--export-token" {
+            // Skip the value, too.
+            args.next();
+            continue;
+        }
+        if arg.to_string_lossy().starts_with("--export-token=") {
+            continue;
+        }
+        result.push(arg);
+    }
+    result
+}
+
+/// Whether `file_name` is a profile which was saved by the schedule called
+/// `name`. Profiles of schedules whose name starts with `name` don't match.
+fn is_capture_file_name(file_name: &str, name: &str) -> bool {
+    let Some(timestamp) = file_name
+        .strip_prefix(name)
+        .and_then(|rest| rest.strip_prefix('-'))
+        .and_then(|rest| rest.strip_suffix(".json"))
+    else {
+        return false;
+    };
+    timestamp.len() == TIMESTAMP_LEN
+        && timestamp
+            .bytes()
+            .all(|b| b.is_ascii_digit() || b == b'T' || b == b'Z')
+}
+
+/// Parses an interval like "90s", "15m", "1h" or "1d". A number without a
+/// unit is in seconds.
+pub fn parse_interval(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, unit_seconds) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("{s:?} is not an interval like 90s, 15m, 1h or 1d"))?;
+    if number == 0 {
+        return Err("The interval must not be zero".to_string());
+    }
+    let seconds = number
+        .checked_mul(unit_seconds)
+        .ok_or_else(|| format!("The interval {s:?} is too long"))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+    use std::time::Duration;
+
+    use super::{args_without_export_token, is_capture_file_name, parse_interval};
+
+    #[test]
+    fn intervals() {
+        assert_eq!(parse_interval("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_interval("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_interval("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_interval("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_interval("2d"), Ok(Duration::from_secs(172800)));
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("1.5h").is_err());
+        assert!(parse_interval("h").is_err());
+        assert!(parse_interval("999999999999999d").is_err());
+        assert_eq!(
+            parse_interval("18446744073709551615"),
+            Ok(Duration::from_secs(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn capture_file_names() {
+        assert!(is_capture_file_name("app-20261016T130203Z.json", "app"));
+        assert!(!is_capture_file_name(
+            "app-server-20261016T130203Z.json",
+            "app"
+        ));
+        assert!(!is_capture_file_name(
+            "app-20261016T130203Z.json.meta.json",
+            "app"
+        ));
+        assert!(!is_capture_file_name("app-notes.json", "app"));
+    }
+
+    #[test]
+    fn export_token_is_not_passed_on() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            args_without_export_token(args(&[
+                "record",
+                "--export-token",
+                "secret",
+                "--export-token=secret",
+                "--every",
+                "1h",
+                "--",
+                "cmd",
+                "--export-token",
+                "arg",
+            ])),
+            args(&[
+                "record",
+                "--every",
+                "1h",
+                "--",
+                "cmd",
+                "--export-token",
+                "arg"
+            ])
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! The build step of `cargo samply`, which builds the current crate for
+//! profiling before the `cargo-samply` binary records it with `samply record`.
+//!
+//! The build uses the release profile by default, with debug info turned on
+//! for it, and with frame pointers, so that the recorded stacks are complete
+//! and the source view works without any changes to Cargo.toml.
+
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// The cargo profile which is used if none is given.
+pub const DEFAULT_PROFILE: &str = "release";
+
+/// Which target of which package to build, and how.
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    pub package: Option<String>,
+    pub bin: Option<String>,
+    pub example: Option<String>,
+    /// The cargo profile, [`DEFAULT_PROFILE`] if `None`.
+    pub profile: Option<String>,
+    pub features: Vec<String>,
+    pub all_features: bool,
+    pub no_default_features: bool,
+}
+
+/// Builds the binary described by `options` and returns the path of the
+/// executable. cargo's diagnostics go to stderr as usual.
+pub fn build_for_profiling(options: &BuildOptions) -> Result<PathBuf, String> {
+    let profile = options.profile.as_deref().unwrap_or(DEFAULT_PROFILE);
+    // cargo sets CARGO for the subcommands it runs.
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo"));
+    let mut command = Command::new(cargo);
+    command.args([
+        "build",
+        "--message-format=json-render-diagnostics",
+        "--profile",
+        profile,
+    ]);
+    if let Some(package) = &options.package {
+        command.args(["--package", package]);
+    }
+    if let Some(bin) = &options.bin {
+        command.args(["--bin", bin]);
+    }
+    if let Some(example) = &options.example {
+        command.args(["--example", example]);
+    }
+    if !options.features.is_empty() {
+        command.args(["--features", &options.features.join(",")]);
+    }
+    if options.all_features {
+        command.arg("--all-features");
+    }
+    if options.no_default_features {
+        command.arg("--no-default-features");
+    }
+    command.env(profile_debug_env_var(profile), "true");
+    command.env("RUSTFLAGS", rustflags_with_frame_pointers());
+    command.stdout(Stdio::piped());
+
+    let mut child = command
+        .spawn()
+        .map_err(|err| format!("Could not run cargo: {err}"))?;
+    let stdout = child.stdout.take().expect("stdout was piped");
+    let executables = executables_from_messages(BufReader::new(stdout));
+    let status = child
+        .wait()
+        .map_err(|err| format!("Could not wait for cargo: {err}"))?;
+    if !status.success() {
+        return Err("The build failed.".to_string());
+    }
+
+    match executables.as_slice() {
+        [executable] => Ok(executable.clone()),
+        [] => Err(
+            "The build didn't produce an executable. Use --bin or --example to pick one."
+                .to_string(),
+        ),
+        _ => Err(format!(
+            "The build produced {} executables, use --bin or --example to pick one: {}",
+            executables.len(),
+            executables
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// The environment variable which overrides the `debug` setting of a cargo
+/// profile, e.g. `CARGO_PROFILE_RELEASE_DEBUG`.
+fn profile_debug_env_var(profile: &str) -> String {
+    format!(
+        "CARGO_PROFILE_{}_DEBUG",
+        profile.to_uppercase().replace('-', "_")
+    )
+}
+
+/// The user's RUSTFLAGS, if any, plus the flag for frame pointers. This
+/// replaces any `build.rustflags` from cargo's config files, like setting
+/// RUSTFLAGS by hand would.
+fn rustflags_with_frame_pointers() -> OsString {
+    let mut rustflags = std::env::var_os("RUSTFLAGS").unwrap_or_default();
+    if !rustflags.is_empty() {
+        rustflags.push(" ");
+    }
+    rustflags.push("-C force-frame-pointers=yes");
+    rustflags
+}
+
+/// Reads cargo's JSON messages and returns the executables of the binary and
+/// example targets which were built.
+fn executables_from_messages(reader: impl BufRead) -> Vec<PathBuf> {
+    let mut executables = Vec::new();
+    for line in reader.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let Ok(message) = serde_json::from_str::<serde_json::Value>(&line) else {
+            continue;
+        };
+        if message["reason"] != "compiler-artifact" {
+            continue;
+        }
+        let is_bin_or_example = message["target"]["kind"].as_array().map_or(false, |kinds| {
+            kinds.iter().any(|kind| kind == "bin" || kind == "example")
+        });
+        if let (true, Some(executable)) = (is_bin_or_example, message["executable"].as_str()) {
+            executables.push(PathBuf::from(executable));
+        }
+    }
+    executables
+}
+
+/// The samply executable which is installed next to `cargo-samply`, or the
+/// one in PATH if there is none.
+pub fn samply_executable() -> PathBuf {
+    let samply_name = format!("samply{}", std::env::consts::EXE_SUFFIX);
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join(&samply_name)))
+        .filter(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from(samply_name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{executables_from_messages, profile_debug_env_var};
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn build_messages() {
+        let messages = r#"{"reason":"compiler-artifact","target":{"kind":["lib"],"name":"dep"},"executable":null}
+{"reason":"compiler-message","message":{"rendered":"warning: unused variable"}}
+{"reason":"compiler-artifact","target":{"kind":["bin"],"name":"app"},"executable":"/work/target/release/app"}
+{"reason":"compiler-artifact","target":{"kind":["custom-build"],"name":"build-script-build"},"executable":null}
+{"reason":"build-finished","success":true}
+"#;
+        assert_eq!(
+            executables_from_messages(messages.as_bytes()),
+            vec![PathBuf::from("/work/target/release/app")]
+        );
+
+        assert_eq!(
+            profile_debug_env_var("release"),
+            "CARGO_PROFILE_RELEASE_DEBUG"
+        );
+        assert_eq!(
+            profile_debug_env_var("release-lto"),
+            "CARGO_PROFILE_RELEASE_LTO_DEBUG"
+        );
+    }
+}
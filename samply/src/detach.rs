@@ -0,0 +1,441 @@
+//! Recording in the background, for `samply record --detach` and `samply stop`.
+//!
+//! The detached recording is the same samply command, started again in a new
+//! session without a terminal. Its pid is written to a pidfile, which is how
+//! `samply stop` finds it. Stopping sends SIGINT to the whole session, just
+//! like pressing Ctrl+C in the terminal of a regular recording.
+//!
+//! The default pidfiles live in a directory which only the current user can
+//! access, and the files are opened without following symlinks, so that other
+//! users can't redirect them or make samply signal their processes. This
+//! matters most under sudo, where the runtime directory usually isn't set.
+
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::capture_schedule::{args_without_export_token, EXPORT_TOKEN_ENV_VAR};
+
+/// Set in the environment of the detached process, to the path of its pidfile.
+const DETACHED_PIDFILE_ENV_VAR: &str = "SAMPLY_DETACHED_PIDFILE";
+
+/// If this process is a detached recording, returns its pidfile. The variable
+/// is removed from the environment, so that the recorded command doesn't
+/// inherit it.
+pub fn detached_pidfile() -> Option<PathBuf> {
+    let pidfile = std::env::var_os(DETACHED_PIDFILE_ENV_VAR)?;
+    std::env::remove_var(DETACHED_PIDFILE_ENV_VAR);
+    Some(PathBuf::from(pidfile))
+}
+
+/// The pidfile of the detached recording called `name`, if no other pidfile
+/// was specified. Its directory is created if needed.
+pub fn default_pidfile_path(name: &str) -> Result<PathBuf, String> {
+    Ok(private_dir()?.join(format!("{name}.pid")))
+}
+
+/// The directory of the default pidfiles. The runtime directory is private to
+/// the user, but without one, e.g. under sudo, the directory is in the shared
+/// temporary directory. Either way, it has to belong to the current user and be
+/// closed to everyone else, so that no one else can place files in it.
+fn private_dir() -> Result<PathBuf, String> {
+    let uid = unsafe { libc::geteuid() };
+    let dir = match dirs::runtime_dir() {
+        Some(runtime_dir) => runtime_dir.join("samply"),
+        None => std::env::temp_dir().join(format!("samply-{uid}")),
+    };
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(format!("Could not create the directory {dir:?}: {err}")),
+    }
+    let metadata = std::fs::symlink_metadata(&dir)
+        .map_err(|err| format!("Could not access the directory {dir:?}: {err}"))?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(format!(
+            "{dir:?} is not a directory which only the current user can access, so it can't be used for the pidfile."
+        ));
+    }
+    Ok(dir)
+}
+
+/// The file which gets the output of the detached recording, next to its
+/// pidfile.
+pub fn log_path(pidfile: &Path) -> PathBuf {
+    pidfile.with_extension("log")
+}
+
+/// Runs this samply command again as a detached recording, and writes its pid
+/// to `pidfile`. Returns the pid. The `export_token` is passed to it in the
+/// environment rather than on its command line.
+pub fn spawn_detached(pidfile: &Path, export_token: Option<&str>) -> Result<u32, String> {
+    if let Some(dir) = pidfile.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|err| format!("Could not create the directory {dir:?}: {err}"))?;
+    }
+    // Hold the lock until the pidfile has been written, so that two recordings
+    // which are started at the same time don't both use this pidfile.
+    let _lock = PidfileLock::acquire(pidfile)?;
+    check_pidfile_unused(pidfile)?;
+    let log_path = log_path(pidfile);
+    let log = create_new_file(&log_path)
+        .map_err(|err| format!("Could not create the log file {log_path:?}: {err}"))?;
+    let log_for_stderr = log
+        .try_clone()
+        .map_err(|err| format!("Could not open the log file {log_path:?}: {err}"))?;
+
+    let exe = std::env::current_exe()
+        .map_err(|err| format!("Could not find the samply executable: {err}"))?;
+    let mut command = Command::new(exe);
+    command
+        .args(args_without_export_token(std::env::args_os().skip(1)))
+        .env(DETACHED_PIDFILE_ENV_VAR, pidfile)
+        .stdin(Stdio::null())
+        .stdout(log)
+        .stderr(log_for_stderr);
+    if let Some(export_token) = export_token {
+        command.env(EXPORT_TOKEN_ENV_VAR, export_token);
+    }
+    // Start a new session, so that the recording doesn't get the signals of the
+    // terminal, and so that `samply stop` can signal the recorded command too.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command
+        .spawn()
+        .map_err(|err| format!("Could not start the detached recording: {err}"))?;
+    let pid = child.id();
+    create_new_file(pidfile)
+        .and_then(|mut file| writeln!(file, "{pid}"))
+        .map_err(|err| format!("Could not write the pidfile {pidfile:?}: {err}"))?;
+    Ok(pid)
+}
+
+/// Stops the detached recording with this pidfile, or with this name if there
+/// is no such file, and waits until the profile has been saved, for at most
+/// `timeout`.
+pub fn stop_detached_recording(pidfile_or_name: &str, timeout: Duration) -> Result<(), String> {
+    let pidfile = if Path::new(pidfile_or_name).is_file() {
+        PathBuf::from(pidfile_or_name)
+    } else {
+        default_pidfile_path(pidfile_or_name)?
+    };
+    let pid = read_pidfile(&pidfile).ok_or_else(|| {
+        format!("Could not find a detached recording called {pidfile_or_name:?}, there is no pidfile at {pidfile:?}.")
+    })?;
+    if !is_running(pid) {
+        let _ = std::fs::remove_file(&pidfile);
+        return Err(format!(
+            "The recording with pid {pid} has already finished."
+        ));
+    }
+    if !is_detached_recording(pid) {
+        return Err(format!(
+            "The process with pid {pid} from {pidfile:?} is not a detached samply recording, so it's not stopped."
+        ));
+    }
+
+    eprintln!("Stopping the recording with pid {pid}...");
+    // The detached recording is the leader of its process group.
+    if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGINT) } == -1 {
+        return Err(format!(
+            "Could not stop the recording with pid {pid}: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    if !wait_until_finished(&pidfile, pid, timeout) {
+        return Err(format!(
+            "The recording with pid {pid} is still running after {} seconds. Its output is in {:?}. Run samply stop again to keep waiting.",
+            timeout.as_secs(),
+            log_path(&pidfile)
+        ));
+    }
+    let _ = std::fs::remove_file(&pidfile);
+    eprintln!(
+        "The recording has finished. Its output is in {:?}.",
+        log_path(&pidfile)
+    );
+    Ok(())
+}
+
+/// Returns whether the recording with this pidfile and pid has finished before
+/// `timeout` elapsed.
+fn wait_until_finished(pidfile: &Path, pid: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    // The recording removes its pidfile once it's done. Its process might not be
+    // reaped right away, so that alone doesn't tell whether it's finished.
+    while pidfile.exists() && is_running(pid) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+
+/// Returns an error if the pidfile belongs to a recording which is still
+/// running. Pidfiles of finished recordings can be reused.
+fn check_pidfile_unused(pidfile: &Path) -> Result<(), String> {
+    match read_pidfile(pidfile) {
+        Some(pid) if is_running(pid) => Err(format!(
+            "The recording in {pidfile:?} is still running, with pid {pid}."
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// An exclusive lock on the file next to a pidfile, for as long as this value
+/// lives. The lock file is left behind, so that everyone locks the same file.
+struct PidfileLock {
+    _file: File,
+}
+
+impl PidfileLock {
+    /// Blocks until the lock has been acquired.
+    fn acquire(pidfile: &Path) -> Result<Self, String> {
+        let lock_path = pidfile.with_extension("lock");
+        let file = open_own_file(&lock_path, OpenOptions::new().write(true).create(true))
+            .map_err(|err| format!("Could not create the lock file {lock_path:?}: {err}"))?;
+        // The lock is released when the file is closed.
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                return Ok(Self { _file: file });
+            }
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(format!("Could not lock {lock_path:?}: {err}"));
+            }
+        }
+    }
+}
+
+/// Opens a file without following a symlink, and checks that it belongs to the
+/// current user. New files are only accessible to the current user.
+fn open_own_file(path: &Path, options: &mut OpenOptions) -> std::io::Result<File> {
+    let file = options
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o600)
+        .open(path)?;
+    if file.metadata()?.uid() != unsafe { libc::geteuid() } {
+        return Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            "the file belongs to another user",
+        ));
+    }
+    Ok(file)
+}
+
+/// Replaces the file at `path` with a new, empty file which only the current
+/// user can access. A symlink at `path` is removed rather than followed.
+fn create_new_file(path: &Path) -> std::io::Result<File> {
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    open_own_file(path, OpenOptions::new().write(true).create_new(true))
+}
+
+fn read_pidfile(pidfile: &Path) -> Option<u32> {
+    let mut contents = String::new();
+    open_own_file(pidfile, OpenOptions::new().read(true))
+        .ok()?
+        .read_to_string(&mut contents)
+        .ok()?;
+    contents.trim().parse().ok()
+}
+
+fn is_running(pid: u32) -> bool {
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Whether `pid` is a detached recording, i.e. a samply process which leads its
+/// own session. The executable comes from the kernel, like the pid of a marker
+/// socket connection, so a pidfile with the pid of some other process doesn't
+/// get that process's session signalled.
+fn is_detached_recording(pid: u32) -> bool {
+    let pid = pid as libc::pid_t;
+    if unsafe { libc::getsid(pid) } != pid {
+        return false;
+    }
+    let (Some(exe), Ok(current_exe)) = (process_executable(pid), std::env::current_exe()) else {
+        return false;
+    };
+    let canonical = |path: PathBuf| std::fs::canonicalize(&path).unwrap_or(path);
+    canonical(exe) == canonical(current_exe)
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn process_executable(pid: libc::pid_t) -> Option<PathBuf> {
+    let exe = std::fs::read_link(format!("/proc/{pid}/exe")).ok()?;
+    // The executable may have been replaced since the process started, e.g. by
+    // an update of samply.
+    match exe.to_str().and_then(|exe| exe.strip_suffix(" (deleted)")) {
+        Some(exe) => Some(PathBuf::from(exe)),
+        None => Some(exe),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn process_executable(pid: libc::pid_t) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut buf = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    let len = unsafe { libc::proc_pidpath(pid, buf.as_mut_ptr().cast(), buf.len() as u32) };
+    if len <= 0 {
+        return None;
+    }
+    buf.truncate(len as usize);
+    Some(PathBuf::from(std::ffi::OsString::from_vec(buf)))
+}
+
+#[cfg(target_os = "freebsd")]
+fn process_executable(pid: libc::pid_t) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let mib = [
+        libc::CTL_KERN,
+        libc::KERN_PROC,
+        libc::KERN_PROC_PATHNAME,
+        pid,
+    ];
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let mut len = buf.len();
+    let result = unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            buf.as_mut_ptr().cast(),
+            &mut len,
+            std::ptr::null(),
+            0,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    // The length includes the terminating NUL.
+    buf.truncate(len.saturating_sub(1));
+    Some(PathBuf::from(std::ffi::OsString::from_vec(buf)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn pidfile_of_finished_recording_is_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let pidfile = dir.path().join("rec.pid");
+        assert!(check_pidfile_unused(&pidfile).is_ok());
+
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        std::fs::write(&pidfile, format!("{pid}\n")).unwrap();
+        assert!(check_pidfile_unused(&pidfile).is_ok());
+
+        std::fs::write(&pidfile, format!("{}\n", std::process::id())).unwrap();
+        assert!(check_pidfile_unused(&pidfile).is_err());
+    }
+
+    #[test]
+    fn pidfile_lock_is_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let pidfile = dir.path().join("rec.pid");
+        let lock = PidfileLock::acquire(&pidfile).unwrap();
+
+        let acquired = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let pidfile = pidfile.clone();
+            let acquired = acquired.clone();
+            std::thread::spawn(move || {
+                let _lock = PidfileLock::acquire(&pidfile).unwrap();
+                acquired.store(true, Ordering::SeqCst);
+            })
+        };
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!acquired.load(Ordering::SeqCst));
+        drop(lock);
+        thread.join().unwrap();
+        assert!(acquired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn files_are_not_followed_through_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        std::fs::write(&target, "keep").unwrap();
+        let link = dir.path().join("rec.log");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        assert!(open_own_file(&link, OpenOptions::new().write(true).create(true)).is_err());
+
+        let mut file = create_new_file(&link).unwrap();
+        writeln!(file, "42").unwrap();
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "keep");
+        assert!(!std::fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(read_pidfile(&link), Some(42));
+        let mode = std::fs::metadata(&link).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn only_samply_session_leaders_are_recordings() {
+        let exe = process_executable(std::process::id() as libc::pid_t).unwrap();
+        assert_eq!(
+            std::fs::canonicalize(exe).unwrap(),
+            std::fs::canonicalize(std::env::current_exe().unwrap()).unwrap()
+        );
+
+        // A session leader which isn't samply.
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        unsafe {
+            command.pre_exec(|| {
+                libc::setsid();
+                Ok(())
+            });
+        }
+        let mut child = command.spawn().unwrap();
+        assert!(!is_detached_recording(child.id()));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn wait_for_recording_with_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let pidfile = dir.path().join("rec.pid");
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        std::fs::write(&pidfile, format!("{}\n", child.id())).unwrap();
+        assert!(!wait_until_finished(
+            &pidfile,
+            child.id(),
+            Duration::from_millis(300)
+        ));
+
+        // The recording removes its pidfile when it's done.
+        std::fs::remove_file(&pidfile).unwrap();
+        assert!(wait_until_finished(
+            &pidfile,
+            child.id(),
+            Duration::from_millis(300)
+        ));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,3 @@
+pub mod profiler;
+
+mod procstat;
@@ -0,0 +1,29 @@
+use std::process::Command;
+
+use crate::import::pmclog::ProcessMapping;
+
+/// Returns the file mappings of the running process `pid`, so that samples
+/// in the libraries which it loaded before the recording started can be
+/// attributed. hwpmc only logs the mappings which are created while it's
+/// attached.
+pub fn mappings_for_pid(pid: u32) -> Vec<ProcessMapping> {
+    let output = match Command::new("procstat")
+        .arg("-v")
+        .arg(pid.to_string())
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            eprintln!(
+                "procstat -v {pid} failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return Vec::new();
+        }
+        Err(err) => {
+            eprintln!("Could not run procstat: {err}");
+            return Vec::new();
+        }
+    };
+    ProcessMapping::from_procstat_vm_output(&String::from_utf8_lossy(&output.stdout))
+}
@@ -0,0 +1,204 @@
+use fxprof_processed_profile::{Profile, SamplingInterval};
+use serde_json::to_writer;
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::procstat::mappings_for_pid;
+use crate::import::pmclog::{self, ProcessMapping};
+use crate::server::{start_server_main, ServerProps};
+use crate::shared::recording_props::{ConversionProps, RecordingProps};
+use crate::shared::utils::WarmupIterations;
+
+/// The hwpmc event which is sampled. It's the alias for the unhalted core
+/// cycles counter of the CPU, so it only counts while a thread is running.
+const SAMPLED_EVENT: &str = "unhalted-cycles";
+
+pub fn start_recording(
+    command_name: OsString,
+    command_args: &[OsString],
+    iteration_count: u32,
+    recording_props: RecordingProps,
+    conversion_props: ConversionProps,
+    server_props: Option<ServerProps>,
+) -> Result<ExitStatus, std::io::Error> {
+    if iteration_count > 1 {
+        eprintln!(
+            "Warning: --iteration-count is not supported on FreeBSD, the command is only run once."
+        );
+    }
+    WarmupIterations::new(
+        &command_name,
+        command_args,
+        recording_props.warmup_iterations,
+    )
+    .run();
+
+    let log_file = tempfile::NamedTempFile::new()?;
+    let mut pmcstat = pmcstat_command(&recording_props, log_file.path());
+    // Follow the processes which the command launches.
+    pmcstat.arg("-d");
+    pmcstat.arg("--").arg(command_name).args(command_args);
+
+    // Ignore SIGINT while the command is running. The signal still reaches the
+    // command and pmcstat, which stops recording once the command exits.
+    let should_terminate_on_ctrl_c = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register_conditional_default(
+        signal_hook::consts::SIGINT,
+        should_terminate_on_ctrl_c.clone(),
+    )
+    .expect("cannot register signal handler");
+
+    let exit_status = run_pmcstat(pmcstat, recording_props.time_limit)?;
+
+    // The command is done. From now on, we want to terminate if the user presses Ctrl+C.
+    should_terminate_on_ctrl_c.store(true, std::sync::atomic::Ordering::SeqCst);
+
+    let profile = convert_log(log_file.path(), &recording_props, &conversion_props, &[]);
+    save_profile_and_serve(profile, &recording_props, server_props);
+    Ok(exit_status)
+}
+
+pub fn start_profiling_pid(
+    pid: u32,
+    recording_props: RecordingProps,
+    conversion_props: ConversionProps,
+    server_props: Option<ServerProps>,
+) {
+    // hwpmc only logs the mappings which are created after it attached.
+    let initial_mappings = mappings_for_pid(pid);
+
+    let log_file = match tempfile::NamedTempFile::new() {
+        Ok(log_file) => log_file,
+        Err(err) => {
+            eprintln!("Could not create the log file: {err}");
+            std::process::exit(1)
+        }
+    };
+    let mut pmcstat = pmcstat_command(&recording_props, log_file.path());
+    pmcstat.arg("-t").arg(pid.to_string());
+
+    // pmcstat stops recording on Ctrl+C and writes out the log, so we wait
+    // for it instead of terminating.
+    let should_terminate_on_ctrl_c = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register_conditional_default(
+        signal_hook::consts::SIGINT,
+        should_terminate_on_ctrl_c.clone(),
+    )
+    .expect("cannot register signal handler");
+
+    eprintln!("Recording process {pid}. Press Ctrl+C to stop.");
+    if let Err(err) = run_pmcstat(pmcstat, recording_props.time_limit) {
+        eprintln!("Could not run pmcstat: {err}");
+        std::process::exit(1)
+    }
+
+    should_terminate_on_ctrl_c.store(true, std::sync::atomic::Ordering::SeqCst);
+
+    let profile = convert_log(
+        log_file.path(),
+        &recording_props,
+        &conversion_props,
+        &initial_mappings,
+    );
+    save_profile_and_serve(profile, &recording_props, server_props);
+}
+
+/// Builds the pmcstat invocation which samples the cycles of the target
+/// process, with call chains, into the log at `log_path`.
+fn pmcstat_command(recording_props: &RecordingProps, log_path: &Path) -> Command {
+    let cycles_per_sample =
+        (cpu_frequency() as f64 * recording_props.interval.as_secs_f64()).max(1.0) as u64;
+    let mut command = Command::new("pmcstat");
+    // -n only applies to the events which are specified after it.
+    command
+        .arg("-n")
+        .arg(cycles_per_sample.to_string())
+        .arg("-P")
+        .arg(SAMPLED_EVENT)
+        .arg("-O")
+        .arg(log_path);
+    command
+}
+
+/// The frequency of the TSC, which is close enough to the core clock to
+/// turn the sampling interval into a cycle count.
+fn cpu_frequency() -> u64 {
+    Command::new("sysctl")
+        .args(["-n", "machdep.tsc_freq"])
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8_lossy(&output.stdout).trim().parse().ok())
+        // e.g. on arm64, which has no TSC.
+        .unwrap_or(2_000_000_000)
+}
+
+/// Runs pmcstat until the target process exits, or until the time limit
+/// has elapsed.
+fn run_pmcstat(
+    mut pmcstat: Command,
+    time_limit: Option<Duration>,
+) -> Result<ExitStatus, std::io::Error> {
+    let mut child = pmcstat.spawn().map_err(|err| {
+        eprintln!("Could not launch pmcstat. Is the hwpmc kernel module loaded (kldload hwpmc)?");
+        err
+    })?;
+    if let Some(time_limit) = time_limit {
+        let pmcstat_pid = child.id() as libc::pid_t;
+        thread::spawn(move || {
+            thread::sleep(time_limit);
+            // pmcstat stops recording and flushes the log when it's interrupted.
+            unsafe { libc::kill(pmcstat_pid, libc::SIGINT) };
+        });
+    }
+    child.wait()
+}
+
+fn convert_log(
+    log_path: &Path,
+    recording_props: &RecordingProps,
+    conversion_props: &ConversionProps,
+    initial_mappings: &[ProcessMapping],
+) -> Profile {
+    let data = match std::fs::read(log_path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Could not read the pmcstat log: {err}");
+            std::process::exit(1)
+        }
+    };
+    match pmclog::convert(&data, &conversion_props.profile_name, initial_mappings) {
+        Ok(mut profile) => {
+            profile.set_interval(SamplingInterval::from_nanos(
+                recording_props.interval.as_nanos() as u64,
+            ));
+            profile
+        }
+        Err(err) => {
+            eprintln!("Could not convert the pmcstat log: {err}");
+            std::process::exit(1)
+        }
+    }
+}
+
+fn save_profile_and_serve(
+    profile: Profile,
+    recording_props: &RecordingProps,
+    server_props: Option<ServerProps>,
+) {
+    let output_file = &recording_props.output_file;
+    let file = File::create(output_file).unwrap();
+    let writer = BufWriter::new(file);
+    to_writer(writer, &profile).expect("Couldn't write JSON");
+
+    if let Some(server_props) = server_props {
+        start_server_main(output_file, server_props);
+    }
+}
@@ -0,0 +1,113 @@
+//! Import of collapsed stacks, the input format of flamegraph.pl and inferno,
+//! e.g. from
+//!
+//! ```text
+//! perf script | stackcollapse-perf.pl > out.folded
+//! ```
+//!
+//! Each line has a stack with the frames from the root to the leaf, separated
+//! by semicolons, and the number of samples after the last space:
+//!
+//! ```text
+//! app;main;parse;read 12
+//! app;main;render 30
+//! ```
+//!
+//! The samples have no timestamps, so each stack becomes a single sample
+//! whose weight is its count.
+
+use fxprof_processed_profile::{
+    CategoryHandle, CpuDelta, Frame, FrameFlags, FrameInfo, Profile, ReferenceTimestamp,
+    SamplingInterval, Timestamp,
+};
+
+use std::io::BufRead;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O Error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("The file doesn't contain any collapsed stacks")]
+    NoStacks,
+}
+
+/// Whether the first line of `data` is a collapsed stack.
+pub fn is_collapsed(data: &[u8]) -> bool {
+    let first_line = data.split(|b| *b == b'\n').next().unwrap_or_default();
+    std::str::from_utf8(first_line).map_or(false, |line| parse_line(line).is_some())
+}
+
+/// Parses a line into its frames, from the root to the leaf, and its count.
+fn parse_line(line: &str) -> Option<(Vec<&str>, u64)> {
+    let (stack, count) = line.trim_end().rsplit_once(' ')?;
+    let count = count.parse().ok()?;
+    let stack = stack.trim();
+    if stack.is_empty() {
+        return None;
+    }
+    Some((stack.split(';').collect(), count))
+}
+
+pub fn convert<R: BufRead>(reader: R, profile_name: &str) -> Result<Profile, Error> {
+    let mut profile = Profile::new(
+        profile_name,
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let start_time = Timestamp::from_millis_since_reference(0.0);
+    let process = profile.add_process(profile_name, 0, start_time);
+    let thread = profile.add_thread(process, 0, start_time, true);
+    profile.set_thread_name(thread, profile_name);
+
+    let mut sample_count = 0;
+    for line in reader.lines() {
+        let line = line?;
+        // Skip lines which aren't stacks, like flamegraph.pl does.
+        let Some((stack, count)) = parse_line(&line) else {
+            continue;
+        };
+        let frames: Vec<FrameInfo> = stack
+            .iter()
+            .map(|frame| FrameInfo {
+                frame: Frame::Label(profile.intern_string(frame)),
+                category_pair: CategoryHandle::OTHER.into(),
+                flags: FrameFlags::empty(),
+            })
+            .collect();
+        // The stacks are laid out one after the other, so that the timeline
+        // doesn't put them all at the same time.
+        let timestamp = Timestamp::from_millis_since_reference(sample_count as f64);
+        let weight = i32::try_from(count).unwrap_or(i32::MAX);
+        profile.add_sample(
+            thread,
+            timestamp,
+            frames.into_iter(),
+            CpuDelta::ZERO,
+            weight,
+        );
+        sample_count += 1;
+    }
+    if sample_count == 0 {
+        return Err(Error::NoStacks);
+    }
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_collapsed, parse_line};
+
+    #[test]
+    fn lines() {
+        assert_eq!(
+            parse_line("app;main;read file 12"),
+            Some((vec!["app", "main", "read file"], 12))
+        );
+        assert_eq!(parse_line(" 12"), None);
+        assert_eq!(parse_line("main"), None);
+        assert!(is_collapsed(b"main;work 3\nmain 1\n"));
+        assert!(!is_collapsed(b"{\"meta\": {}}"));
+        assert!(!is_collapsed(b"\n              libc.so.1`read+0x15\n"));
+    }
+}
@@ -0,0 +1,568 @@
+//! Import of the crash state from an ELF core dump, for
+//! `samply load --with-core`. This puts the moment of a crash into the profile
+//! which was recorded up to it, so that both can be looked at together.
+//!
+//! The core dump's notes contain the registers of each thread (`NT_PRSTATUS`,
+//! the crashing thread comes first), the process name (`NT_PRPSINFO`), the
+//! fault address (`NT_SIGINFO`) and the mapped files (`NT_FILE`). The stacks
+//! are walked with the frame pointers, by reading the stack memory from the
+//! `PT_LOAD` segments. Only 64-bit x86 and ARM core dumps have stacks; for
+//! other architectures, only the signal and the modules are imported.
+
+use fxprof_processed_profile::{
+    CategoryColor, CategoryPairHandle, CpuDelta, Frame, FrameFlags, FrameInfo, LibraryHandle,
+    LibraryInfo, MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema,
+    MarkerSchemaField, MarkerTiming, ProcessHandle, Profile, ProfilerMarker, Timestamp,
+};
+use memmap2::Mmap;
+use object::elf::FileHeader64;
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::{Endianness, Object};
+use samply_symbols::debug_id_for_object;
+use serde_json::json;
+use wholesym::samply_symbols;
+use wholesym::{CodeId, ElfBuildId};
+
+use std::collections::HashMap;
+use std::fs::File;
+
+/// The offset of `pr_reg` in `struct elf_prstatus` on 64-bit Linux.
+const PRSTATUS_REGS_OFFSET: usize = 112;
+/// The offset of `pr_fname` in `struct elf_prpsinfo` on 64-bit Linux.
+const PRPSINFO_FNAME_OFFSET: usize = 40;
+const PRPSINFO_FNAME_LEN: usize = 16;
+/// Stop walking a stack after this many frames, in case the frame pointers
+/// form a cycle which goes unnoticed.
+const MAX_STACK_DEPTH: usize = 512;
+
+pub struct CoreDump {
+    pub pid: u32,
+    pub process_name: String,
+    pub signal: u32,
+    /// The address which caused the crash, for signals like SIGSEGV.
+    pub fault_address: Option<u64>,
+    /// The threads of the process, with the crashing thread first.
+    pub threads: Vec<CoreThread>,
+    pub modules: Vec<CoreModule>,
+}
+
+pub struct CoreThread {
+    pub tid: u32,
+    /// The instruction pointer followed by the return addresses, from the
+    /// innermost frame outwards. Empty if the architecture isn't supported.
+    pub stack: Vec<u64>,
+}
+
+/// A file mapping of the process, from the `NT_FILE` note.
+pub struct CoreModule {
+    pub path: String,
+    pub start: u64,
+    pub end: u64,
+    pub file_offset: u64,
+}
+
+impl CoreDump {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let header = FileHeader64::<Endianness>::parse(data)
+            .map_err(|_| "Not a 64-bit ELF file".to_string())?;
+        let endian = header
+            .endian()
+            .map_err(|err| format!("Invalid ELF header: {err}"))?;
+        if header.e_type(endian) != object::elf::ET_CORE {
+            return Err("Not a core dump".to_string());
+        }
+        // The indexes of the instruction pointer and the frame pointer in pr_reg.
+        let register_indexes = match header.e_machine(endian) {
+            object::elf::EM_X86_64 => Some((16, 4)),
+            object::elf::EM_AARCH64 => Some((32, 29)),
+            _ => None,
+        };
+        let program_headers = header
+            .program_headers(endian, data)
+            .map_err(|err| format!("Invalid program headers: {err}"))?;
+        let memory = Memory {
+            endian,
+            segments: program_headers
+                .iter()
+                .filter(|segment| segment.p_type(endian) == object::elf::PT_LOAD)
+                .filter_map(|segment| {
+                    Some((segment.p_vaddr(endian), segment.data(endian, data).ok()?))
+                })
+                .collect(),
+        };
+
+        let mut core = CoreDump {
+            pid: 0,
+            process_name: String::new(),
+            signal: 0,
+            fault_address: None,
+            threads: Vec::new(),
+            modules: Vec::new(),
+        };
+        for segment in program_headers {
+            let Ok(Some(mut notes)) = segment.notes(endian, data) else {
+                continue;
+            };
+            while let Ok(Some(note)) = notes.next() {
+                if note.name() != b"CORE" {
+                    continue;
+                }
+                let desc = note.desc();
+                match note.n_type(endian) {
+                    object::elf::NT_PRSTATUS => {
+                        let Some(tid) = read_u32(desc, 32, endian) else {
+                            continue;
+                        };
+                        if core.threads.is_empty() {
+                            core.signal = read_u16(desc, 12, endian).unwrap_or(0).into();
+                        }
+                        let register =
+                            |index: usize| read_u64(desc, PRSTATUS_REGS_OFFSET + index * 8, endian);
+                        let stack = register_indexes
+                            .and_then(|(pc, fp)| {
+                                Some(memory.walk_stack(register(pc)?, register(fp)?))
+                            })
+                            .unwrap_or_default();
+                        core.threads.push(CoreThread { tid, stack });
+                    }
+                    object::elf::NT_PRPSINFO => {
+                        core.pid = read_u32(desc, 24, endian).unwrap_or(0);
+                        let fname = desc
+                            .get(PRPSINFO_FNAME_OFFSET..PRPSINFO_FNAME_OFFSET + PRPSINFO_FNAME_LEN)
+                            .unwrap_or_default();
+                        let len = fname.iter().position(|b| *b == 0).unwrap_or(fname.len());
+                        core.process_name = String::from_utf8_lossy(&fname[..len]).into_owned();
+                    }
+                    object::elf::NT_SIGINFO => {
+                        core.fault_address = read_u64(desc, 16, endian);
+                    }
+                    object::elf::NT_FILE => {
+                        core.modules = parse_file_note(desc, endian).unwrap_or_default();
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if core.threads.is_empty() {
+            return Err("The core dump has no threads".to_string());
+        }
+        if core.pid == 0 {
+            core.pid = core.threads[0].tid;
+        }
+        if !matches!(core.signal, 4 | 5 | 7 | 8 | 11) {
+            // The fault address is only meaningful for SIGILL, SIGTRAP, SIGBUS,
+            // SIGFPE and SIGSEGV.
+            core.fault_address = None;
+        }
+        Ok(core)
+    }
+
+    /// Adds the crash to the process of the core dump in the profile, or to a
+    /// new process if the profile doesn't have it. A "Core dump" thread gets a
+    /// sample with the stack of each thread at the time of the crash, and a
+    /// "Crash" marker with the stack of the crashing thread. The modules are
+    /// listed in the profile's metadata.
+    pub fn add_to_profile(&self, profile: &mut Profile, core_file_name: &str) {
+        let pid = self.pid.to_string();
+        // Recycled pids get a suffix, e.g. "1234.1". The last process with the
+        // pid is the one which crashed.
+        let existing_process = profile
+            .process_handles()
+            .filter(|process| profile.process_pid(*process).split('.').next() == Some(&pid))
+            .last();
+        // The crash happened when the process ended, or, if the recording
+        // didn't see it end, after the process's last sample.
+        let (process, timestamp) = match existing_process {
+            Some(process) => (
+                process,
+                profile
+                    .process_end_time(process)
+                    .or_else(|| profile.process_latest_sample_time(process))
+                    .unwrap_or_else(|| Timestamp::from_millis_since_reference(0.0)),
+            ),
+            None => {
+                // Put the crash at the end of the profile.
+                let timestamp = profile
+                    .process_handles()
+                    .filter_map(|process| {
+                        profile
+                            .process_end_time(process)
+                            .max(profile.process_latest_sample_time(process))
+                    })
+                    .max()
+                    .unwrap_or_else(|| Timestamp::from_millis_since_reference(0.0));
+                let process = profile.add_process(&self.process_name, self.pid, timestamp);
+                self.add_lib_mappings(profile, process);
+                (process, timestamp)
+            }
+        };
+
+        let category = profile.add_category("Crash", CategoryColor::Red);
+        // This thread only holds the crash, so it gets tid 0 like other
+        // synthetic threads, instead of the tid of one of the real threads.
+        let thread = profile.add_thread(process, 0, timestamp, false);
+        profile.set_thread_name(thread, "Core dump");
+        for (i, core_thread) in self.threads.iter().enumerate() {
+            let label = if i == 0 {
+                format!(
+                    "Thread {} (crashed with {})",
+                    core_thread.tid,
+                    signal_name(self.signal)
+                )
+            } else {
+                format!("Thread {}", core_thread.tid)
+            };
+            let label = FrameInfo {
+                frame: Frame::Label(profile.intern_string(&label)),
+                category_pair: category.into(),
+                flags: FrameFlags::empty(),
+            };
+            let frames =
+                std::iter::once(label).chain(stack_frames(category.into(), &core_thread.stack));
+            profile.add_sample(thread, timestamp, frames, CpuDelta::ZERO, 1);
+        }
+
+        let crashing_thread = &self.threads[0];
+        let marker = CrashMarker {
+            signal: signal_name(self.signal),
+            tid: crashing_thread.tid,
+            fault_address: self.fault_address,
+            core_file: core_file_name.to_string(),
+        };
+        profile.add_marker_with_stack(
+            thread,
+            category,
+            "Crash",
+            marker,
+            MarkerTiming::Instant(timestamp),
+            stack_frames(category.into(), &crashing_thread.stack),
+        );
+
+        let mut module_paths: Vec<&str> = self
+            .modules
+            .iter()
+            .map(|module| module.path.as_str())
+            .collect();
+        module_paths.sort_unstable();
+        module_paths.dedup();
+        profile.add_extra_info("Core dump", "File", core_file_name);
+        profile.add_extra_info("Core dump", "Signal", &signal_name(self.signal));
+        profile.add_extra_info("Core dump", "Modules", &module_paths.join(", "));
+    }
+
+    /// Maps the modules which exist on this machine into `process`, so that the
+    /// stacks can be symbolicated.
+    fn add_lib_mappings(&self, profile: &mut Profile, process: ProcessHandle) {
+        let mut libs: HashMap<&str, Option<LibraryHandle>> = HashMap::new();
+        for module in &self.modules {
+            let lib = *libs
+                .entry(&module.path)
+                .or_insert_with(|| lib_for_path(profile, &module.path));
+            // Addresses are relative to the start of the file's first mapping.
+            let base = self
+                .modules
+                .iter()
+                .find(|m| m.path == module.path && m.file_offset == 0);
+            let (Some(lib), Some(base)) = (lib, base) else {
+                continue;
+            };
+            let relative_address_at_start = (module.start - base.start) as u32;
+            profile.add_lib_mapping(
+                process,
+                lib,
+                module.start,
+                module.end,
+                relative_address_at_start,
+            );
+        }
+    }
+}
+
+/// The stack memory of the crashed process.
+struct Memory<'data> {
+    endian: Endianness,
+    /// The start address and the contents of each `PT_LOAD` segment.
+    segments: Vec<(u64, &'data [u8])>,
+}
+
+impl<'data> Memory<'data> {
+    fn read_u64(&self, address: u64) -> Option<u64> {
+        self.segments.iter().find_map(|(start, data)| {
+            let offset = usize::try_from(address.checked_sub(*start)?).ok()?;
+            read_u64(data, offset, self.endian)
+        })
+    }
+
+    /// Follows the chain of frame pointers. Each frame record consists of the
+    /// caller's frame pointer, followed by the return address.
+    fn walk_stack(&self, pc: u64, mut fp: u64) -> Vec<u64> {
+        let mut stack = vec![pc];
+        while stack.len() < MAX_STACK_DEPTH && fp != 0 && fp % 8 == 0 {
+            let (Some(caller_fp), Some(return_address)) =
+                (self.read_u64(fp), self.read_u64(fp + 8))
+            else {
+                break;
+            };
+            if return_address == 0 {
+                break;
+            }
+            stack.push(return_address);
+            // The stack grows downwards, so the caller's frame is at a higher address.
+            if caller_fp <= fp {
+                break;
+            }
+            fp = caller_fp;
+        }
+        stack
+    }
+}
+
+/// Parses the `NT_FILE` note: a count and a page size, then the start, end and
+/// file offset (in pages) of each mapping, then the paths.
+fn parse_file_note(desc: &[u8], endian: Endianness) -> Option<Vec<CoreModule>> {
+    let count = usize::try_from(read_u64(desc, 0, endian)?).ok()?;
+    let page_size = read_u64(desc, 8, endian)?;
+    let paths_offset = 16usize.checked_add(count.checked_mul(24)?)?;
+    let mut paths = desc.get(paths_offset..)?.split(|b| *b == 0);
+    (0..count)
+        .map(|i| {
+            let entry = 16 + i * 24;
+            Some(CoreModule {
+                start: read_u64(desc, entry, endian)?,
+                end: read_u64(desc, entry + 8, endian)?,
+                file_offset: read_u64(desc, entry + 16, endian)? * page_size,
+                path: String::from_utf8_lossy(paths.next()?).into_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Returns the frames of a stack from [`CoreThread::stack`], from the root to
+/// the leaf.
+fn stack_frames(
+    category_pair: CategoryPairHandle,
+    stack: &[u64],
+) -> impl Iterator<Item = FrameInfo> + '_ {
+    stack
+        .iter()
+        .enumerate()
+        .rev()
+        .map(move |(i, address)| FrameInfo {
+            frame: match i {
+                0 => Frame::InstructionPointer(*address),
+                _ => Frame::ReturnAddress(*address),
+            },
+            category_pair,
+            flags: FrameFlags::empty(),
+        })
+}
+
+/// Adds the library for the ELF file at `path`, if it exists on this machine.
+fn lib_for_path(profile: &mut Profile, path: &str) -> Option<LibraryHandle> {
+    let file = File::open(path).ok()?;
+    let mmap = unsafe { Mmap::map(&file) }.ok()?;
+    let object = object::File::parse(&mmap[..]).ok()?;
+    let debug_id = debug_id_for_object(&object)?;
+    let code_id = object
+        .build_id()
+        .ok()
+        .flatten()
+        .map(|build_id| CodeId::ElfBuildId(ElfBuildId::from_bytes(build_id)).to_string());
+    let name = path.rsplit('/').next().unwrap_or(path).to_string();
+    let lib = profile.add_lib(LibraryInfo {
+        name: name.clone(),
+        debug_name: name,
+        path: path.to_string(),
+        debug_path: path.to_string(),
+        debug_id,
+        code_id,
+        arch: None,
+        symbol_table: None,
+    });
+    profile.set_lib_file_size(lib, mmap.len() as u64);
+    Some(lib)
+}
+
+fn signal_name(signal: u32) -> String {
+    let name = match signal {
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        5 => "SIGTRAP",
+        6 => "SIGABRT",
+        7 => "SIGBUS",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        11 => "SIGSEGV",
+        31 => "SIGSYS",
+        _ => return format!("signal {signal}"),
+    };
+    name.to_string()
+}
+
+fn read_u16(data: &[u8], offset: usize, endian: Endianness) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?.try_into().ok()?;
+    Some(match endian {
+        Endianness::Little => u16::from_le_bytes(bytes),
+        Endianness::Big => u16::from_be_bytes(bytes),
+    })
+}
+
+fn read_u32(data: &[u8], offset: usize, endian: Endianness) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?.try_into().ok()?;
+    Some(match endian {
+        Endianness::Little => u32::from_le_bytes(bytes),
+        Endianness::Big => u32::from_be_bytes(bytes),
+    })
+}
+
+fn read_u64(data: &[u8], offset: usize, endian: Endianness) -> Option<u64> {
+    let bytes = data.get(offset..offset.checked_add(8)?)?.try_into().ok()?;
+    Some(match endian {
+        Endianness::Little => u64::from_le_bytes(bytes),
+        Endianness::Big => u64::from_be_bytes(bytes),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct CrashMarker {
+    pub signal: String,
+    pub tid: u32,
+    pub fault_address: Option<u64>,
+    pub core_file: String,
+}
+
+impl ProfilerMarker for CrashMarker {
+    const MARKER_TYPE_NAME: &'static str = "Crash";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "signal": self.signal,
+            "tid": self.tid.to_string(),
+            "faultAddress": self.fault_address.map(|address| format!("{address:#x}")),
+            "coreFile": self.core_file,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.signal}"),
+            tooltip_label: Some("Crash: {marker.data.signal} in thread {marker.data.tid}"),
+            table_label: Some("{marker.data.signal} in thread {marker.data.tid}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "signal",
+                    label: "Signal",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "tid",
+                    label: "Thread",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "faultAddress",
+                    label: "Fault address",
+                    format: MarkerFieldFormat::String,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "coreFile",
+                    label: "Core dump",
+                    format: MarkerFieldFormat::FilePath,
+                    searchable: false,
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{
+        CpuDelta, Profile, ReferenceTimestamp, SamplingInterval, Timestamp,
+    };
+    use object::Endianness;
+
+    use super::{parse_file_note, CoreDump, CoreThread, Memory};
+
+    #[test]
+    fn file_note() {
+        let mut desc = Vec::new();
+        for value in [
+            2u64, 0x1000, 0x40_0000, 0x40_1000, 0, 0x40_1000, 0x40_3000, 1,
+        ] {
+            desc.extend_from_slice(&value.to_le_bytes());
+        }
+        desc.extend_from_slice(b"/bin/app\0/bin/app\0");
+        let modules = parse_file_note(&desc, Endianness::Little).unwrap();
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[1].path, "/bin/app");
+        assert_eq!(modules[1].start, 0x40_1000);
+        assert_eq!(modules[1].end, 0x40_3000);
+        assert_eq!(modules[1].file_offset, 0x1000);
+    }
+
+    #[test]
+    fn frame_pointer_walk() {
+        // Two frame records at 0x7000 and 0x7010, the outer one ends the chain.
+        let mut stack = Vec::new();
+        for value in [0x7010u64, 0x1234, 0, 0x5678] {
+            stack.extend_from_slice(&value.to_le_bytes());
+        }
+        let memory = Memory {
+            endian: Endianness::Little,
+            segments: vec![(0x7000, &stack[..])],
+        };
+        assert_eq!(
+            memory.walk_stack(0x1000, 0x7000),
+            vec![0x1000, 0x1234, 0x5678]
+        );
+        assert_eq!(memory.walk_stack(0x1000, 0), vec![0x1000]);
+    }
+
+    #[test]
+    fn crash_after_last_sample() {
+        let mut profile = Profile::new(
+            "app",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let ms = Timestamp::from_millis_since_reference;
+        let process = profile.add_process("app", 1234, ms(0.0));
+        let main_thread = profile.add_thread(process, 1234, ms(0.0), true);
+        for time in [5.0, 20.0, 10.0] {
+            profile.add_sample(main_thread, ms(time), std::iter::empty(), CpuDelta::ZERO, 1);
+        }
+        let core = CoreDump {
+            pid: 1234,
+            process_name: "app".to_string(),
+            signal: 11,
+            fault_address: Some(0),
+            threads: vec![CoreThread {
+                tid: 1234,
+                stack: vec![0x1000],
+            }],
+            modules: Vec::new(),
+        };
+        core.add_to_profile(&mut profile, "core.1234");
+
+        let profile = serde_json::to_value(&profile).unwrap();
+        let threads = profile["threads"].as_array().unwrap();
+        let core_thread = threads
+            .iter()
+            .find(|thread| thread["name"] == "Core dump")
+            .unwrap();
+        assert_eq!(core_thread["tid"], "0");
+        assert_eq!(core_thread["markers"]["startTime"][0], 20.0);
+        assert_eq!(core_thread["samples"]["time"][0], 20.0);
+    }
+}
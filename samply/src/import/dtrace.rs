@@ -0,0 +1,361 @@
+//! Import of aggregated stacks from DTrace, e.g. from
+//!
+//! ```text
+//! dtrace -n 'profile-997 /pid == $target/ { @[ustack()] = count(); }' -c ./app -o out.stacks
+//! ```
+//!
+//! The output has one block per distinct stack, with the innermost frame
+//! first and the count on the last line:
+//!
+//! ```text
+//!               libc.so.1`__nanosleep+0x15
+//!               libc.so.1`nanosleep+0x1d
+//!               app`main+0x2a
+//!               app`_start+0x83
+//!                 3
+//! ```
+//!
+//! If the aggregation has a key in front of the stack, e.g.
+//! `@[execname, ustack()]`, the key is on the first line of the block, and
+//! the stacks are grouped into one process per key.
+//!
+//! OpenBSD's btrace(8) prints its maps with the stack between brackets and
+//! the count after them, which is accepted too:
+//!
+//! ```text
+//! @[
+//! sched_idle+0x26b
+//! proc_trampoline+0x1c
+//! ]: 1234
+//! ```
+//!
+//! The samples have no timestamps, so each stack becomes a single sample
+//! whose weight is its count.
+
+use fxprof_processed_profile::{
+    CategoryHandle, CpuDelta, Frame, FrameFlags, FrameInfo, Profile, ReferenceTimestamp,
+    SamplingInterval, ThreadHandle, Timestamp,
+};
+
+use std::collections::HashMap;
+use std::io::BufRead;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O Error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("The file doesn't contain any aggregated DTrace stacks")]
+    NoStacks,
+}
+
+/// One entry of the aggregation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AggregatedStack {
+    key: Option<String>,
+    /// Innermost frame first, as printed by DTrace.
+    frames: Vec<String>,
+    count: u64,
+}
+
+pub fn convert<R: BufRead>(reader: R, profile_name: &str) -> Result<Profile, Error> {
+    let stacks = parse_aggregated_stacks(reader)?;
+    if stacks.is_empty() {
+        return Err(Error::NoStacks);
+    }
+
+    let interval = SamplingInterval::from_millis(1);
+    let mut profile = Profile::new(
+        profile_name,
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        interval,
+    );
+    let start_time = Timestamp::from_millis_since_reference(0.0);
+    let mut threads: HashMap<Option<String>, ThreadHandle> = HashMap::new();
+    for (index, stack) in stacks.into_iter().enumerate() {
+        let thread = *threads.entry(stack.key.clone()).or_insert_with(|| {
+            let name = stack.key.as_deref().unwrap_or("dtrace");
+            let process = profile.add_process(name, 0, start_time);
+            let thread = profile.add_thread(process, 0, start_time, true);
+            profile.set_thread_name(thread, name);
+            thread
+        });
+        let frames: Vec<FrameInfo> = stack
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| FrameInfo {
+                frame: Frame::Label(profile.intern_string(frame)),
+                category_pair: CategoryHandle::OTHER.into(),
+                flags: FrameFlags::empty(),
+            })
+            .collect();
+        // The stacks are laid out one after the other, so that the timeline
+        // doesn't put them all at the same time.
+        let timestamp = Timestamp::from_millis_since_reference(index as f64);
+        let weight = i32::try_from(stack.count).unwrap_or(i32::MAX);
+        profile.add_sample(
+            thread,
+            timestamp,
+            frames.into_iter(),
+            CpuDelta::ZERO,
+            weight,
+        );
+    }
+    Ok(profile)
+}
+
+/// Returns an empty list if the input doesn't have the shape of DTrace or
+/// btrace output, so that other formats aren't mistaken for it.
+fn parse_aggregated_stacks<R: BufRead>(mut reader: R) -> Result<Vec<AggregatedStack>, Error> {
+    // Bail out early on JSON profiles, which are often a single huge line.
+    let first_byte = reader
+        .fill_buf()?
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .copied();
+    if matches!(first_byte, Some(b'{' | b'[') | None) {
+        return Ok(Vec::new());
+    }
+
+    let mut stacks = Vec::new();
+    // The lines of the current DTrace block, up to the next empty line.
+    let mut block = Vec::new();
+    // The key and frames of the current btrace entry, until its "]: count" line.
+    let mut btrace_entry: Option<(Option<String>, Vec<String>)> = None;
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            // Not a text file.
+            Err(err) if err.kind() == std::io::ErrorKind::InvalidData => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let line = line.trim();
+        if let Some((key, frames)) = &mut btrace_entry {
+            if let Some(count) = line.strip_prefix("]:") {
+                let Ok(count) = count.trim().parse::<u64>() else {
+                    return Ok(Vec::new());
+                };
+                if !frames.is_empty() {
+                    stacks.push(AggregatedStack {
+                        key: key.take(),
+                        frames: std::mem::take(frames),
+                        count,
+                    });
+                }
+                btrace_entry = None;
+            } else if is_btrace_frame(line) {
+                frames.push(frame_without_offset(line).to_string());
+            } else if !line.is_empty() {
+                return Ok(Vec::new());
+            }
+        } else if let Some(entry) = line.strip_prefix('@') {
+            // A btrace map entry, `@name[key, stack]: count`, where the stack
+            // spans multiple lines. It can follow a header like
+            // "Attaching 1 probe...", without an empty line in between.
+            let Some((_map_name, entry)) = entry.split_once('[') else {
+                return Ok(Vec::new());
+            };
+            if !take_dtrace_block(&mut block, &mut stacks) {
+                return Ok(Vec::new());
+            }
+            match entry.rsplit_once("]:") {
+                Some((entry_key, count)) => {
+                    // A map without a stack in its key, e.g. `@[comm] = count()`.
+                    let Ok(count) = count.trim().parse::<u64>() else {
+                        return Ok(Vec::new());
+                    };
+                    stacks.push(AggregatedStack {
+                        key: None,
+                        frames: vec![entry_key.trim().to_string()],
+                        count,
+                    });
+                }
+                None => {
+                    let entry_key = entry.trim().trim_end_matches(',').trim();
+                    let key = (!entry_key.is_empty()).then(|| entry_key.to_string());
+                    btrace_entry = Some((key, Vec::new()));
+                }
+            }
+        } else if line.is_empty() {
+            if !take_dtrace_block(&mut block, &mut stacks) {
+                return Ok(Vec::new());
+            }
+        } else {
+            block.push(line.to_string());
+        }
+    }
+    if btrace_entry.is_some() || !take_dtrace_block(&mut block, &mut stacks) {
+        return Ok(Vec::new());
+    }
+    Ok(stacks)
+}
+
+/// Adds the stack from a block of DTrace output, which is separated from the
+/// next one by an empty line. Blocks which aren't stacks are only accepted
+/// before the first stack, because DTrace prints a header, e.g. the
+/// "CPU ID FUNCTION:NAME" line of a tick probe. Returns false for a block
+/// which doesn't fit, which means that this isn't DTrace output.
+fn take_dtrace_block(block: &mut Vec<String>, stacks: &mut Vec<AggregatedStack>) -> bool {
+    if block.is_empty() {
+        return true;
+    }
+    match parse_dtrace_block(std::mem::take(block)) {
+        Some(stack) => {
+            stacks.push(stack);
+            true
+        }
+        None => stacks.is_empty(),
+    }
+}
+
+/// A block is an optional key line, at least one frame, and the count.
+fn parse_dtrace_block(mut lines: Vec<String>) -> Option<AggregatedStack> {
+    let count = lines.pop()?.parse::<u64>().ok()?;
+    let key = match lines.first() {
+        Some(first) if !is_dtrace_frame(first) => Some(lines.remove(0)),
+        _ => None,
+    };
+    if lines.is_empty() || !lines.iter().all(|line| is_dtrace_frame(line)) {
+        return None;
+    }
+    let frames = lines
+        .iter()
+        .map(|line| frame_without_offset(line).to_string())
+        .collect();
+    Some(AggregatedStack { key, frames, count })
+}
+
+/// DTrace frames are `module`function+0x1a`, or just an address if there was
+/// no symbol for it.
+fn is_dtrace_frame(line: &str) -> bool {
+    if let Some(address) = line.strip_prefix("0x") {
+        return is_hex(address);
+    }
+    match line.split_once('`') {
+        Some((module, function)) => {
+            !module.is_empty() && !module.contains(char::is_whitespace) && !function.is_empty()
+        }
+        None => false,
+    }
+}
+
+/// btrace frames are `function+0x1a`, or just an address.
+fn is_btrace_frame(line: &str) -> bool {
+    if let Some(address) = line.strip_prefix("0x") {
+        return is_hex(address);
+    }
+    !line.is_empty() && !line.contains(char::is_whitespace)
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Strips the offset from `module`function+0x1a`, or from `function+0x1a`
+/// in btrace stacks, so that the samples in the same function are merged.
+fn frame_without_offset(frame: &str) -> &str {
+    match frame.rsplit_once("+0x") {
+        Some((function, offset))
+            if !function.is_empty() && offset.bytes().all(|b| b.is_ascii_hexdigit()) =>
+        {
+            function
+        }
+        _ => frame,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_aggregated_stacks, AggregatedStack};
+
+    #[test]
+    fn parse_dtrace_output() {
+        let output = "dtrace: description 'profile-997 ' matched 1 probe
+CPU     ID                    FUNCTION:NAME
+  0  75411                        :tick-60s
+
+  firefox
+              libc.so.1`__nanosleep+0x15
+              libc.so.1`nanosleep+0x1d
+              firefox`main+0x2a
+                3
+
+  firefox
+              0x7fffbf201ab0
+              firefox`main+0x40
+               12
+";
+        let stacks = parse_aggregated_stacks(output.as_bytes()).unwrap();
+        assert_eq!(
+            stacks,
+            vec![
+                AggregatedStack {
+                    key: Some("firefox".to_string()),
+                    frames: vec![
+                        "libc.so.1`__nanosleep".to_string(),
+                        "libc.so.1`nanosleep".to_string(),
+                        "firefox`main".to_string(),
+                    ],
+                    count: 3,
+                },
+                AggregatedStack {
+                    key: Some("firefox".to_string()),
+                    frames: vec!["0x7fffbf201ab0".to_string(), "firefox`main".to_string()],
+                    count: 12,
+                },
+            ]
+        );
+
+        let output = "Attaching 1 probe...
+@[firefox,
+sched_idle+0x26b
+proc_trampoline+0x1c
+]: 1234
+@syscalls[read]: 56
+";
+        let stacks = parse_aggregated_stacks(output.as_bytes()).unwrap();
+        assert_eq!(
+            stacks,
+            vec![
+                AggregatedStack {
+                    key: Some("firefox".to_string()),
+                    frames: vec!["sched_idle".to_string(), "proc_trampoline".to_string()],
+                    count: 1234,
+                },
+                AggregatedStack {
+                    key: None,
+                    frames: vec!["read".to_string()],
+                    count: 56,
+                },
+            ]
+        );
+
+        assert!(parse_aggregated_stacks(&b"{\"meta\": {}}"[..])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn reject_other_text() {
+        let not_stacks = [
+            // Plain text which ends in a number.
+            "Some notes\nabout the run\n3\n",
+            // A stack block followed by a block which isn't a stack.
+            "  app\n    app`main+0x2a\n    3\n\n  total samples: 3\n",
+            // A frame which isn't `module`function or an address.
+            "  app\n    app`main+0x2a\n    main.c:12\n    3\n",
+            // An unterminated btrace entry, and one with a bad count.
+            "@[\nsched_idle+0x26b\n",
+            "@[\nsched_idle+0x26b\n]: many\n",
+            // A btrace frame with spaces.
+            "@[\nsched idle\n]: 1\n",
+        ];
+        for text in not_stacks {
+            assert!(
+                parse_aggregated_stacks(text.as_bytes()).unwrap().is_empty(),
+                "{text:?}"
+            );
+        }
+    }
+}
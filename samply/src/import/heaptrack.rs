@@ -0,0 +1,440 @@
+//! Import of heap profiles from heaptrack, e.g. from
+//!
+//! ```text
+//! heaptrack ./app
+//! samply load heaptrack.app.12345.zst
+//! ```
+//!
+//! The file is zstd or gzip compressed, and is a list of lines which start
+//! with a mode character, followed by hexadecimal numbers:
+//!
+//! ```text
+//! v 10500 3                 heaptrack version, file format version
+//! X ./app --arg             command line
+//! I 1000 3e8a5              page size, physical pages
+//! s 4 main                  string, with its length since format version 3
+//! i 401234 1 2 3 1a         instruction pointer: address, module string,
+//!                           then function, file and line of the innermost
+//!                           frame, followed by those of the functions it was
+//!                           inlined into, from the innermost one out
+//! t 1 0                     trace: instruction pointer, parent trace
+//! a 40 1                    allocation info: size, trace
+//! + 0                       allocation with this allocation info
+//! - 0                       deallocation with this allocation info
+//! c 64                      elapsed time in milliseconds
+//! R 2a4                     resident set size in pages
+//! ```
+//!
+//! Strings, instruction pointers and traces are numbered from 1, so that 0 can
+//! mean "none", and allocation infos are numbered from 0.
+//!
+//! The allocations between two timestamps become one sample per allocation
+//! info, whose weight is the number of allocated bytes. The heap usage and the
+//! resident set size become memory counters.
+
+use flate2::read::GzDecoder;
+use fxprof_processed_profile::{
+    CategoryHandle, CounterHandle, CpuDelta, Frame, FrameFlags, FrameInfo, Profile,
+    ReferenceTimestamp, SamplingInterval, StringHandle, ThreadHandle, Timestamp, WeightType,
+};
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O Error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not decompress the file: {0}")]
+    Zstd(String),
+
+    #[error("The file isn't a heaptrack file")]
+    NotHeaptrack,
+
+    #[error("Heaptrack files of format version {0} are not supported, only version 1 and newer")]
+    UnsupportedVersion(u64),
+
+    #[error("Invalid line {0:?}")]
+    InvalidLine(String),
+}
+
+/// Whether `data` is a heaptrack file, compressed or not.
+pub fn is_heaptrack(data: &[u8]) -> bool {
+    let mut start = [0; 2];
+    match decompressed_reader(data) {
+        Ok(mut reader) => reader.read_exact(&mut start).is_ok() && &start == b"v ",
+        Err(_) => false,
+    }
+}
+
+fn decompressed_reader<'a>(data: &'a [u8]) -> Result<Box<dyn Read + 'a>, Error> {
+    if data.starts_with(&ZSTD_MAGIC) {
+        let decoder =
+            ruzstd::StreamingDecoder::new(data).map_err(|err| Error::Zstd(err.to_string()))?;
+        Ok(Box::new(decoder))
+    } else if data.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(GzDecoder::new(data)))
+    } else {
+        Ok(Box::new(data))
+    }
+}
+
+pub fn convert(data: &[u8], profile_name: &str) -> Result<Profile, Error> {
+    let reader = BufReader::new(decompressed_reader(data)?);
+    let mut lines = reader.lines();
+    let file_version = match lines.next().transpose()? {
+        Some(line) if line.starts_with("v ") => {
+            let mut fields = line[2..].split_whitespace();
+            let _heaptrack_version = fields.next();
+            fields
+                .next()
+                .and_then(|version| u64::from_str_radix(version, 16).ok())
+                .unwrap_or(0)
+        }
+        _ => return Err(Error::NotHeaptrack),
+    };
+    if file_version < 1 {
+        return Err(Error::UnsupportedVersion(file_version));
+    }
+
+    let mut converter = Converter::new(profile_name);
+    while let Some(line) = lines.next() {
+        let line = line?;
+        let mut chars = line.chars();
+        let Some(mode) = chars.next() else {
+            continue;
+        };
+        let rest = chars.as_str().trim_start();
+        match mode {
+            'X' => converter.command = Some(rest.to_string()),
+            '#' => {}
+            's' => {
+                let mut string = rest.to_string();
+                if file_version >= 3 {
+                    // The string is prefixed by its length, and can contain
+                    // line breaks.
+                    if let Some((len, s)) = rest.split_once(' ') {
+                        if let Ok(len) = usize::from_str_radix(len, 16) {
+                            string = s.to_string();
+                            while string.len() < len {
+                                let Some(next_line) = lines.next().transpose()? else {
+                                    break;
+                                };
+                                string.push('\n');
+                                string.push_str(&next_line);
+                            }
+                        }
+                    }
+                }
+                converter.strings.push(string);
+            }
+            _ => {
+                let numbers = rest
+                    .split_whitespace()
+                    .map(|number| u64::from_str_radix(number, 16))
+                    .collect::<Result<Vec<u64>, _>>();
+                match numbers {
+                    Ok(numbers) => converter.handle_line(mode, &numbers),
+                    Err(_) => return Err(Error::InvalidLine(line)),
+                }
+            }
+        }
+    }
+    Ok(converter.finish())
+}
+
+struct Converter {
+    profile: Profile,
+    command: Option<String>,
+    page_size: u64,
+    strings: Vec<String>,
+    /// The frames of each instruction pointer, innermost first.
+    ip_frames: Vec<Vec<StringHandle>>,
+    /// The instruction pointer and the parent trace of each trace.
+    traces: Vec<(usize, usize)>,
+    /// The size and the trace of each allocation info.
+    allocation_infos: Vec<(u64, usize)>,
+    tracks: Option<Tracks>,
+    time: Timestamp,
+    /// The allocated bytes per allocation info since the last timestamp.
+    pending_allocations: BTreeMap<usize, u64>,
+    pending_heap_delta: f64,
+    pending_operation_count: u32,
+    rss_pages: u64,
+}
+
+/// The thread and the counters, which are created once the command line is
+/// known.
+struct Tracks {
+    thread: ThreadHandle,
+    heap_counter: CounterHandle,
+    rss_counter: CounterHandle,
+}
+
+impl Converter {
+    fn new(profile_name: &str) -> Self {
+        let mut profile = Profile::new(
+            profile_name,
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        profile.set_weight_type(WeightType::Bytes);
+        Self {
+            profile,
+            command: None,
+            page_size: 4096,
+            strings: Vec::new(),
+            ip_frames: Vec::new(),
+            traces: Vec::new(),
+            allocation_infos: Vec::new(),
+            tracks: None,
+            time: Timestamp::from_millis_since_reference(0.0),
+            pending_allocations: BTreeMap::new(),
+            pending_heap_delta: 0.0,
+            pending_operation_count: 0,
+            rss_pages: 0,
+        }
+    }
+
+    fn handle_line(&mut self, mode: char, numbers: &[u64]) {
+        match (mode, numbers) {
+            ('I', [page_size, ..]) => self.page_size = *page_size,
+            ('i', [address, module, frames @ ..]) => {
+                let mut ip_frames: Vec<StringHandle> = frames
+                    .chunks_exact(3)
+                    .map(|frame| {
+                        let function = self.string(frame[0]).to_string();
+                        self.profile.intern_string(&function)
+                    })
+                    .collect();
+                if ip_frames.is_empty() {
+                    let module = self.string(*module);
+                    let module = module.rsplit('/').next().unwrap_or(module);
+                    let label = format!("0x{address:x} in {module}");
+                    ip_frames.push(self.profile.intern_string(&label));
+                }
+                self.ip_frames.push(ip_frames);
+            }
+            ('t', [ip, parent, ..]) => self.traces.push((*ip as usize, *parent as usize)),
+            ('a', [size, trace, ..]) => self.allocation_infos.push((*size, *trace as usize)),
+            ('+', [index, ..]) => {
+                let index = *index as usize;
+                if let Some((size, _trace)) = self.allocation_infos.get(index) {
+                    *self.pending_allocations.entry(index).or_default() += size;
+                    self.pending_heap_delta += *size as f64;
+                    self.pending_operation_count += 1;
+                }
+            }
+            ('-', [index, ..]) => {
+                if let Some((size, _trace)) = self.allocation_infos.get(*index as usize) {
+                    self.pending_heap_delta -= *size as f64;
+                    self.pending_operation_count += 1;
+                }
+            }
+            ('c', [ms, ..]) => {
+                self.flush();
+                self.time = Timestamp::from_millis_since_reference(*ms as f64);
+            }
+            ('R', [pages, ..]) => {
+                let delta = (*pages as f64 - self.rss_pages as f64) * self.page_size as f64;
+                self.rss_pages = *pages;
+                let rss_counter = self.tracks().rss_counter;
+                self.profile
+                    .add_counter_sample(rss_counter, self.time, delta, 0);
+            }
+            // Other modes, like "A" for an attached process or "S" for
+            // suppressions, don't affect the profile.
+            _ => {}
+        }
+    }
+
+    fn string(&self, index: u64) -> &str {
+        match (index as usize).checked_sub(1) {
+            Some(index) => self.strings.get(index).map_or("<unknown>", String::as_str),
+            None => "<unknown>",
+        }
+    }
+
+    fn tracks(&mut self) -> &Tracks {
+        if self.tracks.is_none() {
+            let command = self.command.as_deref().unwrap_or("");
+            let name = command
+                .split_whitespace()
+                .next()
+                .map(|exe| exe.rsplit('/').next().unwrap_or(exe))
+                .unwrap_or("heaptrack");
+            let start_time = Timestamp::from_millis_since_reference(0.0);
+            let process = self.profile.add_process(name, 0, start_time);
+            let thread = self.profile.add_thread(process, 0, start_time, true);
+            self.profile.set_thread_name(thread, name);
+            let heap_counter =
+                self.profile
+                    .add_counter(process, "malloc", "Memory", "Amount of allocated memory");
+            let rss_counter =
+                self.profile
+                    .add_counter(process, "RSS", "Memory", "Resident set size");
+            self.tracks = Some(Tracks {
+                thread,
+                heap_counter,
+                rss_counter,
+            });
+        }
+        self.tracks.as_ref().unwrap()
+    }
+
+    /// Adds the allocations since the last timestamp to the profile.
+    fn flush(&mut self) {
+        if self.pending_operation_count == 0 {
+            return;
+        }
+        let Tracks {
+            thread,
+            heap_counter,
+            ..
+        } = *self.tracks();
+        self.profile.add_counter_sample(
+            heap_counter,
+            self.time,
+            self.pending_heap_delta,
+            self.pending_operation_count,
+        );
+        for (index, bytes) in std::mem::take(&mut self.pending_allocations) {
+            let (_size, trace) = self.allocation_infos[index];
+            let frames = self.stack(trace);
+            let weight = i32::try_from(bytes).unwrap_or(i32::MAX);
+            self.profile.add_sample(
+                thread,
+                self.time,
+                frames.into_iter(),
+                CpuDelta::ZERO,
+                weight,
+            );
+        }
+        self.pending_heap_delta = 0.0;
+        self.pending_operation_count = 0;
+    }
+
+    /// Returns the frames of the trace, from the root to the leaf.
+    fn stack(&self, mut trace: usize) -> Vec<FrameInfo> {
+        let mut frames = Vec::new();
+        // Bound the walk in case the parents form a cycle.
+        for _ in 0..self.traces.len() {
+            let Some((ip, parent)) = trace.checked_sub(1).and_then(|i| self.traces.get(i)) else {
+                break;
+            };
+            if let Some(ip_frames) = ip.checked_sub(1).and_then(|i| self.ip_frames.get(i)) {
+                frames.extend(ip_frames.iter().map(|name| FrameInfo {
+                    frame: Frame::Label(*name),
+                    category_pair: CategoryHandle::OTHER.into(),
+                    flags: FrameFlags::empty(),
+                }));
+            }
+            trace = *parent;
+        }
+        frames.reverse();
+        frames
+    }
+
+    fn finish(mut self) -> Profile {
+        self.flush();
+        self.profile
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{convert, is_heaptrack};
+
+    /// The function names of a sample's stack, from the leaf to the root.
+    fn sample_stack(thread: &serde_json::Value, sample: usize) -> Vec<&str> {
+        let strings = thread["stringArray"].as_array().unwrap();
+        let mut names = Vec::new();
+        let mut stack = thread["samples"]["stack"][sample].as_u64();
+        while let Some(index) = stack {
+            let frame = thread["stackTable"]["frame"][index as usize]
+                .as_u64()
+                .unwrap();
+            let func = thread["frameTable"]["func"][frame as usize]
+                .as_u64()
+                .unwrap();
+            let name = thread["funcTable"]["name"][func as usize].as_u64().unwrap();
+            names.push(strings[name as usize].as_str().unwrap());
+            stack = thread["stackTable"]["prefix"][index as usize].as_u64();
+        }
+        names
+    }
+
+    #[test]
+    fn allocations_become_samples() {
+        let data = "v 10500 3\n\
+                    X /usr/bin/app --arg\n\
+                    s 4 main\n\
+                    s 5 alloc\n\
+                    s 5 outer\n\
+                    s 8 /bin/app\n\
+                    i 1000 4 1 0 0\n\
+                    i 1010 4 2 0 0 3 0 0\n\
+                    t 1 0\n\
+                    t 2 1\n\
+                    a 40 2\n\
+                    a 10 1\n\
+                    + 0\n\
+                    + 0\n\
+                    + 1\n\
+                    c 5\n\
+                    - 0\n\
+                    c a\n";
+        let profile = convert(data.as_bytes(), "test").unwrap();
+        let profile = serde_json::to_value(&profile).unwrap();
+        let thread = &profile["threads"][0];
+        assert_eq!(thread["processName"], "app");
+        assert_eq!(thread["samples"]["weightType"], "bytes");
+        assert_eq!(thread["samples"]["weight"], serde_json::json!([128, 16]));
+
+        // alloc was inlined into outer.
+        let names = sample_stack(thread, 0);
+        assert_eq!(names, vec!["alloc", "outer", "main"]);
+    }
+
+    #[test]
+    fn convert_fixture() {
+        // heaptrack ./inline_test 3, for a program whose allocate() is
+        // inlined into compute(), which is inlined into main().
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../fixtures/heaptrack/heaptrack.inline_test.4242.zst");
+        let data = std::fs::read(path).unwrap();
+        assert!(is_heaptrack(&data));
+        let profile = convert(&data, "inline_test").unwrap();
+        let profile = serde_json::to_value(&profile).unwrap();
+        let thread = &profile["threads"][0];
+        assert_eq!(thread["processName"], "inline_test");
+        assert_eq!(
+            thread["samples"]["time"],
+            serde_json::json!([0.0, 2.0, 4.0])
+        );
+        assert_eq!(
+            thread["samples"]["weight"],
+            serde_json::json!([128, 1024, 64])
+        );
+        assert_eq!(
+            sample_stack(thread, 0),
+            vec![
+                "allocate",
+                "compute",
+                "main",
+                "__libc_start_call_main",
+                "0x401065 in inline_test"
+            ]
+        );
+        assert_eq!(
+            sample_stack(thread, 1),
+            vec!["main", "__libc_start_call_main", "0x401065 in inline_test"]
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Import of heap profiles from Valgrind's massif tool, e.g. from
+//!
+//! ```text
+//! valgrind --tool=massif --massif-out-file=massif.out ./app
+//! ```
+//!
+//! The output is a header followed by a list of snapshots of the heap usage.
+//! Some snapshots are "detailed" and have a tree of the allocation sites, which
+//! is inverted: the root has all heap bytes, and the children of a node are the
+//! callers of the node's function.
+//!
+//! ```text
+//! desc: (none)
+//! cmd: ./app
+//! time_unit: i
+//! #-----------
+//! snapshot=0
+//! #-----------
+//! time=0
+//! mem_heap_B=0
+//! mem_heap_extra_B=0
+//! mem_stacks_B=0
+//! heap_tree=empty
+//! #-----------
+//! snapshot=1
+//! #-----------
+//! time=183908
+//! mem_heap_B=4000
+//! mem_heap_extra_B=16
+//! mem_stacks_B=0
+//! heap_tree=peak
+//! n2: 4000 (heap allocation functions) malloc/new/new[], --alloc-fns, etc.
+//!  n1: 3000 0x1091A5: g (app.c:10)
+//!   n0: 3000 0x1091D3: main (app.c:20)
+//!  n0: 1000 0x1091E2: main (app.c:21)
+//! ```
+//!
+//! The heap usage of all snapshots becomes a memory counter. The tree of the
+//! peak snapshot, or of the last detailed snapshot if there's no peak, becomes
+//! one sample per allocation site, whose weight is the number of bytes which
+//! were allocated there.
+//!
+//! Snapshot times are in milliseconds with `--time-unit=ms`. With the other
+//! time units, instructions or bytes, the snapshots are laid out one
+//! millisecond apart.
+
+use fxprof_processed_profile::{
+    CategoryHandle, CpuDelta, Frame, FrameFlags, FrameInfo, Profile, ReferenceTimestamp,
+    SamplingInterval, Timestamp, WeightType,
+};
+
+use std::io::BufRead;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O Error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("The file doesn't contain any massif snapshots")]
+    NoSnapshots,
+
+    #[error("Invalid heap tree line {0:?}")]
+    InvalidTreeLine(String),
+}
+
+/// Whether `data` looks like the output of massif, which starts with the
+/// `desc:` line.
+pub fn is_massif(data: &[u8]) -> bool {
+    data.starts_with(b"desc:")
+}
+
+#[derive(Debug, Default)]
+struct Snapshot {
+    time: f64,
+    heap_bytes: u64,
+    heap_extra_bytes: u64,
+    is_peak: bool,
+    tree: Vec<TreeNode>,
+}
+
+/// A node of a snapshot's heap tree, in the order of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TreeNode {
+    depth: usize,
+    bytes: u64,
+    function: String,
+}
+
+pub fn convert<R: BufRead>(reader: R, profile_name: &str) -> Result<Profile, Error> {
+    let mut command = String::new();
+    let mut time_is_in_ms = false;
+    let mut snapshots: Vec<Snapshot> = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if let Some(cmd) = line.strip_prefix("cmd: ") {
+            command = cmd.to_string();
+        } else if let Some(time_unit) = line.strip_prefix("time_unit: ") {
+            time_is_in_ms = time_unit.trim() == "ms";
+        } else if line.starts_with("snapshot=") {
+            snapshots.push(Snapshot::default());
+        } else if let Some(snapshot) = snapshots.last_mut() {
+            if let Some(time) = line.strip_prefix("time=") {
+                snapshot.time = time.trim().parse().unwrap_or(0.0);
+            } else if let Some(bytes) = line.strip_prefix("mem_heap_B=") {
+                snapshot.heap_bytes = bytes.trim().parse().unwrap_or(0);
+            } else if let Some(bytes) = line.strip_prefix("mem_heap_extra_B=") {
+                snapshot.heap_extra_bytes = bytes.trim().parse().unwrap_or(0);
+            } else if let Some(kind) = line.strip_prefix("heap_tree=") {
+                snapshot.is_peak = kind.trim() == "peak";
+            } else if line.trim_start().starts_with('n') {
+                snapshot.tree.push(parse_tree_line(&line)?);
+            }
+        }
+    }
+    if snapshots.is_empty() {
+        return Err(Error::NoSnapshots);
+    }
+
+    let timestamp_of = |index: usize, snapshot: &Snapshot| {
+        let ms = if time_is_in_ms {
+            snapshot.time
+        } else {
+            index as f64
+        };
+        Timestamp::from_millis_since_reference(ms)
+    };
+
+    let mut profile = Profile::new(
+        profile_name,
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    profile.set_weight_type(WeightType::Bytes);
+    let process_name = command
+        .split_whitespace()
+        .next()
+        .map(|exe| exe.rsplit('/').next().unwrap_or(exe))
+        .unwrap_or("massif");
+    let start_time = timestamp_of(0, &snapshots[0]);
+    let process = profile.add_process(process_name, 0, start_time);
+    let thread = profile.add_thread(process, 0, start_time, true);
+    profile.set_thread_name(thread, process_name);
+
+    let counter = profile.add_counter(process, "malloc", "Memory", "Amount of allocated memory");
+    let mut previous_bytes = 0;
+    for (index, snapshot) in snapshots.iter().enumerate() {
+        let bytes = snapshot.heap_bytes + snapshot.heap_extra_bytes;
+        profile.add_counter_sample(
+            counter,
+            timestamp_of(index, snapshot),
+            bytes as f64 - previous_bytes as f64,
+            0,
+        );
+        previous_bytes = bytes;
+    }
+
+    let detailed_snapshot = snapshots
+        .iter()
+        .enumerate()
+        .filter(|(_, snapshot)| !snapshot.tree.is_empty())
+        .max_by_key(|(_, snapshot)| snapshot.is_peak);
+    if let Some((index, snapshot)) = detailed_snapshot {
+        let timestamp = timestamp_of(index, snapshot);
+        for (stack, bytes) in allocation_sites(&snapshot.tree) {
+            let frames: Vec<FrameInfo> = stack
+                .iter()
+                .map(|function| FrameInfo {
+                    frame: Frame::Label(profile.intern_string(function)),
+                    category_pair: CategoryHandle::OTHER.into(),
+                    flags: FrameFlags::empty(),
+                })
+                .collect();
+            let weight = i32::try_from(bytes).unwrap_or(i32::MAX);
+            profile.add_sample(
+                thread,
+                timestamp,
+                frames.into_iter(),
+                CpuDelta::ZERO,
+                weight,
+            );
+        }
+    }
+    Ok(profile)
+}
+
+/// Parses a line like ` n1: 3000 0x1091A5: g (app.c:10)`, where the number of
+/// leading spaces is the depth in the tree.
+fn parse_tree_line(line: &str) -> Result<TreeNode, Error> {
+    let invalid = || Error::InvalidTreeLine(line.to_string());
+    let trimmed = line.trim_start_matches(' ');
+    let depth = line.len() - trimmed.len();
+    let (_child_count, rest) = trimmed.split_once(": ").ok_or_else(invalid)?;
+    let (bytes, label) = rest.split_once(' ').unwrap_or((rest, ""));
+    let bytes = bytes.parse().map_err(|_| invalid())?;
+    // Strip the address from "0x1091A5: g (app.c:10)".
+    let function = match label.split_once(": ") {
+        Some((address, function)) if address.starts_with("0x") => function,
+        _ => label,
+    };
+    Ok(TreeNode {
+        depth,
+        bytes,
+        function: function.to_string(),
+    })
+}
+
+/// Turns the inverted heap tree into one stack per node, from the node's
+/// function to the function which called the allocation function. Each stack
+/// has the bytes of its node which aren't attributed to any of the node's
+/// callers. The root node, which stands for the allocation functions, is left
+/// out.
+fn allocation_sites(tree: &[TreeNode]) -> Vec<(Vec<String>, u64)> {
+    let mut sites = Vec::new();
+    for (i, node) in tree.iter().enumerate() {
+        if node.depth == 0 {
+            continue;
+        }
+        // The callers of a node are the nodes below it with a larger depth.
+        let callers_bytes: u64 = tree[i + 1..]
+            .iter()
+            .take_while(|caller| caller.depth > node.depth)
+            .filter(|caller| caller.depth == node.depth + 1)
+            .map(|caller| caller.bytes)
+            .sum();
+        let self_bytes = node.bytes.saturating_sub(callers_bytes);
+        if self_bytes == 0 {
+            continue;
+        }
+        // The path from the node up to the root, i.e. from the outermost
+        // caller to the allocating function.
+        let mut stack = vec![node.function.clone()];
+        let mut depth = node.depth;
+        for ancestor in tree[..i].iter().rev() {
+            if ancestor.depth == 0 {
+                break;
+            }
+            if ancestor.depth < depth {
+                stack.push(ancestor.function.clone());
+                depth = ancestor.depth;
+            }
+        }
+        sites.push((stack, self_bytes));
+    }
+    sites
+}
+
+#[cfg(test)]
+mod test {
+    use super::{allocation_sites, parse_tree_line};
+
+    #[test]
+    fn heap_tree() {
+        let tree: Vec<_> = [
+            "n2: 4000 (heap allocation functions) malloc/new/new[], --alloc-fns, etc.",
+            " n2: 3000 0x1091A5: g (app.c:10)",
+            "  n0: 2000 0x1091D3: main (app.c:20)",
+            "  n0: 500 in 1 place, below massif's threshold (1.00%)",
+            " n0: 1000 0x1091E2: main (app.c:21)",
+        ]
+        .iter()
+        .map(|line| parse_tree_line(line).unwrap())
+        .collect();
+        assert_eq!(tree[1].depth, 1);
+        assert_eq!(tree[1].function, "g (app.c:10)");
+
+        let sites = allocation_sites(&tree);
+        let sites: Vec<(Vec<&str>, u64)> = sites
+            .iter()
+            .map(|(stack, bytes)| (stack.iter().map(String::as_str).collect(), *bytes))
+            .collect();
+        assert_eq!(
+            sites,
+            vec![
+                (vec!["g (app.c:10)"], 500),
+                (vec!["main (app.c:20)", "g (app.c:10)"], 2000),
+                (
+                    vec![
+                        "in 1 place, below massif's threshold (1.00%)",
+                        "g (app.c:10)"
+                    ],
+                    500
+                ),
+                (vec!["main (app.c:21)"], 1000),
+            ]
+        );
+    }
+}
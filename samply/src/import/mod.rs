@@ -1 +1,10 @@
+pub mod collapsed;
+pub mod core_dump;
+pub mod dtrace;
+pub mod heaptrack;
+pub mod massif;
 pub mod perf;
+pub mod pmclog;
+pub mod pprof;
+pub mod speedscope;
+pub mod unknown_events;
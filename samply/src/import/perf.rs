@@ -1,16 +1,20 @@
 use framehop::{Module, Unwinder};
 use fxprof_processed_profile::Profile;
 use linux_perf_data::linux_perf_event_reader;
-use linux_perf_data::{DsoInfo, DsoKey, PerfFileReader, PerfFileRecord};
-use linux_perf_event_reader::EventRecord;
+use linux_perf_data::{DsoInfo, DsoKey, Feature, PerfFileReader, PerfFileRecord};
+use linux_perf_event_reader::{EventRecord, RecordType};
 
 use std::collections::HashMap;
-use std::io::{Read, Seek};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom};
 use std::path::Path;
 
+use super::unknown_events::UnknownEventsSummary;
+
 use crate::linux_shared::{
-    ConvertRegs, ConvertRegsAarch64, ConvertRegsX86_64, Converter, EventInterpretation, KnownEvent,
-    MmapRangeOrVec,
+    parse_tracing_data, ConvertRegs, ConvertRegsAarch64, ConvertRegsPpc64, ConvertRegsRiscv64,
+    ConvertRegsX86_64, Converter, CpuTopology, EventInterpretation, KnownEvent, MmapRangeOrVec,
+    SystemInfo,
 };
 use crate::shared::recording_props::ConversionProps;
 
@@ -21,18 +25,31 @@ pub enum Error {
 
     #[error("Linux Perf error: {0}")]
     LinuxPerf(#[from] linux_perf_data::Error),
+
+    #[error("The perf.data file has no sampled events")]
+    NoSampledEvents,
 }
 
 pub fn convert<C: Read + Seek>(
-    cursor: C,
+    mut cursor: C,
     extra_dir: Option<&Path>,
     conversion_props: ConversionProps,
 ) -> Result<Profile, Error> {
-    let perf_file = PerfFileReader::parse_file(cursor)?;
+    let patched_header = patched_header_for_truncated_file(&mut cursor)?;
+    let is_truncated = patched_header.is_some();
+    let mut patches = attr_flag_patches_for_big_endian_file(&mut cursor, patched_header)?;
+    if let Some(patched_header) = patched_header {
+        patches.push((0, patched_header.to_vec()));
+    }
+    let perf_file = PerfFileReader::parse_file(PatchingReader {
+        inner: cursor,
+        patches,
+        pos: 0,
+    })?;
 
     let arch = perf_file.perf_file.arch().ok().flatten();
 
-    let profile = match arch {
+    match arch {
         Some("aarch64") => {
             let cache = framehop::aarch64::CacheAarch64::new();
             convert_impl::<framehop::aarch64::UnwinderAarch64<MmapRangeOrVec>, ConvertRegsAarch64, _>(
@@ -40,6 +57,29 @@ pub fn convert<C: Read + Seek>(
                 extra_dir,
                 cache,
                 conversion_props,
+                is_truncated,
+            )
+        }
+        // framehop can't unwind these, so the x86_64 unwinder only fills in the
+        // type parameter, and user stacks are unwound along the frame chain.
+        Some("riscv64") => {
+            let cache = framehop::x86_64::CacheX86_64::new();
+            convert_impl::<framehop::x86_64::UnwinderX86_64<MmapRangeOrVec>, ConvertRegsRiscv64, _>(
+                perf_file,
+                extra_dir,
+                cache,
+                conversion_props,
+                is_truncated,
+            )
+        }
+        Some("ppc64le" | "ppc64") => {
+            let cache = framehop::x86_64::CacheX86_64::new();
+            convert_impl::<framehop::x86_64::UnwinderX86_64<MmapRangeOrVec>, ConvertRegsPpc64, _>(
+                perf_file,
+                extra_dir,
+                cache,
+                conversion_props,
+                is_truncated,
             )
         }
         _ => {
@@ -55,10 +95,10 @@ pub fn convert<C: Read + Seek>(
                 extra_dir,
                 cache,
                 conversion_props,
+                is_truncated,
             )
         }
-    };
-    Ok(profile)
+    }
 }
 
 fn convert_impl<U, C, R>(
@@ -66,7 +106,8 @@ fn convert_impl<U, C, R>(
     extra_dir: Option<&Path>,
     cache: U::Cache,
     conversion_props: ConversionProps,
-) -> Profile
+    is_truncated: bool,
+) -> Result<Profile, Error>
 where
     U: Unwinder<Module = Module<MmapRangeOrVec>> + Default,
     C: ConvertRegs<UnwindRegs = U::UnwindRegs>,
@@ -78,22 +119,25 @@ where
     } = file;
     let mut build_ids = perf_file.build_ids().ok().unwrap_or_default();
     fixup_perf_jit_build_ids(&mut build_ids);
-    let first_sample_time = perf_file
-        .sample_time_range()
-        .unwrap()
+    // The header sections are optional, and may be malformed in a damaged file.
+    let sample_time_range = perf_file.sample_time_range().ok().flatten();
+    let first_sample_time = sample_time_range
+        .as_ref()
         .map_or(0, |r| r.first_sample_time);
     let endian = perf_file.endian();
     let host = perf_file
         .hostname()
-        .unwrap()
+        .ok()
+        .flatten()
         .unwrap_or("<unknown host>")
         .to_owned();
     let perf_version = perf_file
         .perf_version()
-        .unwrap()
+        .ok()
+        .flatten()
         .unwrap_or("<unknown version>")
         .to_owned();
-    let linux_version = perf_file.os_release().unwrap();
+    let linux_version = perf_file.os_release().ok().flatten();
     let attributes = perf_file.event_attributes();
     if let Ok(Some(cmd_line)) = perf_file.cmdline() {
         eprintln!("cmd line: {}", cmd_line.join(" "));
@@ -101,10 +145,27 @@ where
     for event_name in attributes.iter().filter_map(|attr| attr.name()) {
         eprintln!("event {event_name}");
     }
-    let interpretation = EventInterpretation::divine_from_attrs(attributes);
+    let tracepoint_formats = perf_file
+        .feature_section_data(Feature::TRACING_DATA)
+        .and_then(parse_tracing_data)
+        .unwrap_or_default();
+    let interpretation = EventInterpretation::divine_from_attrs(attributes, &tracepoint_formats)
+        .ok_or(Error::NoSampledEvents)?;
+    let mut unknown_events = conversion_props
+        .unknown_events_file
+        .as_ref()
+        .map(|_| UnknownEventsSummary::default());
+    let event_names: Vec<String> = attributes
+        .iter()
+        .enumerate()
+        .map(|(index, attr)| match attr.name() {
+            Some(name) => name.to_string(),
+            None => format!("event #{index}"),
+        })
+        .collect();
 
     let mut converter = Converter::<U>::new(
-        &conversion_props.profile_name,
+        &conversion_props,
         Some(Box::new(move |name| {
             format!("{name} on {host} (perf version {perf_version})")
         })),
@@ -115,17 +176,38 @@ where
         cache,
         extra_dir,
         interpretation.clone(),
-        conversion_props.reuse_threads,
-        conversion_props.fold_recursive_prefix,
     );
+    converter.set_system_info(&SystemInfo::from_perf_file(&perf_file));
+    if conversion_props.per_cpu_threads {
+        converter.set_cpu_topology(CpuTopology::from_perf_file_sections(
+            perf_file.feature_section_data(Feature::CPU_TOPOLOGY),
+            perf_file.feature_section_data(Feature::NUMA_TOPOLOGY),
+            endian,
+        ));
+    }
 
     let mut last_timestamp = 0;
+    let mut unparsable_record_count = 0;
 
-    while let Ok(Some(record)) = record_iter.next_record(&mut perf_file) {
+    // Files from crashed machines often end in the middle of a record. In that
+    // case, keep everything up to that point instead of failing the import.
+    let read_error = loop {
+        let record = match record_iter.next_record(&mut perf_file) {
+            Ok(Some(record)) => record,
+            Ok(None) => break None,
+            Err(err) => break Some(err),
+        };
         let (record, parsed_record, attr_index) = match record {
             PerfFileRecord::EventRecord { attr_index, record } => match record.parse() {
                 Ok(r) => (record, r, attr_index),
-                Err(_) => continue,
+                Err(_) => {
+                    unparsable_record_count += 1;
+                    if let Some(unknown_events) = &mut unknown_events {
+                        let kind = format!("unparsable {:?} record", record.record_type);
+                        unknown_events.add(&kind, &record.data.as_slice());
+                    }
+                    continue;
+                }
             },
             PerfFileRecord::UserRecord(_) => continue,
         };
@@ -148,12 +230,33 @@ where
 
                 match interpretation.known_event_indices.get(&attr_index) {
                     Some(KnownEvent::RssStat) => converter.handle_rss_stat_sample::<C>(&e),
-                    _ => {
+                    Some(KnownEvent::DrmVblankEvent) => {
+                        converter.handle_drm_vblank_event_sample(&e)
+                    }
+                    Some(KnownEvent::SndPcmXrun) => converter.handle_snd_pcm_xrun_sample(&e),
+                    Some(KnownEvent::GpuJob(format)) => {
+                        converter.handle_gpu_job_sample(&e, *format)
+                    }
+                    Some(KnownEvent::FutexEnter) => converter.handle_futex_enter_sample::<C>(&e),
+                    Some(KnownEvent::FutexExit) => converter.handle_futex_exit_sample(&e),
+                    Some(KnownEvent::SyscallEnter(reason)) => {
+                        converter.handle_syscall_enter_sample(&e, *reason)
+                    }
+                    Some(KnownEvent::SyscallExit) => converter.handle_syscall_exit_sample(&e),
+                    known_event => {
                         // the main event and sched_switch are already covered by regular samples so don't add other event markers
                         if !(attr_index == interpretation.main_event_attr_index
                             || Some(attr_index) == interpretation.sched_switch_attr_index)
                         {
-                            converter.handle_other_event_sample::<C>(&e, attr_index)
+                            converter.handle_other_event_sample::<C>(&e, attr_index);
+                            // Only tracepoint samples carry a payload which we don't
+                            // decode; other events are fully covered by their markers.
+                            if let (Some(unknown_events), None, Some(raw)) =
+                                (&mut unknown_events, known_event, &e.raw)
+                            {
+                                let kind = format!("sample of {}", event_names[attr_index]);
+                                unknown_events.add(&kind, &raw.as_slice());
+                            }
                         }
                     }
                 }
@@ -180,13 +283,51 @@ where
                 };
                 converter.handle_context_switch(e, common);
             }
+            _ if is_routine_record_type(record.record_type) => {}
             _ => {
-                // println!("{:?}", record.record_type);
+                if let Some(unknown_events) = &mut unknown_events {
+                    let kind = format!("{:?} record", record.record_type);
+                    unknown_events.add(&kind, &record.data.as_slice());
+                }
+            }
+        }
+    };
+
+    if unparsable_record_count != 0 {
+        eprintln!("Skipped {unparsable_record_count} records which could not be parsed.");
+    }
+    let read_error = match read_error {
+        Some(err) => Some(err.to_string()),
+        None if is_truncated => Some("The file ends before the end of its data section".into()),
+        None => None,
+    };
+    if let Some(err) = read_error {
+        eprintln!(
+            "Warning: The perf.data file could not be read past timestamp {last_timestamp}: {err}"
+        );
+        eprintln!("The profile only contains the data before this point.");
+        let last_sample_time = sample_time_range.map(|r| r.last_sample_time);
+        converter.add_truncated_data_marker(last_timestamp, last_sample_time, err);
+    }
+
+    if let (Some(unknown_events), Some(path)) =
+        (unknown_events, &conversion_props.unknown_events_file)
+    {
+        let result =
+            File::create(path).and_then(|file| unknown_events.write_to(BufWriter::new(file)));
+        match result {
+            Ok(()) if unknown_events.is_empty() => {
+                eprintln!("All events were recognized, wrote an empty summary to {path:?}.")
             }
+            Ok(()) => eprintln!(
+                "Wrote a summary of {} kinds of unknown events to {path:?}.",
+                unknown_events.len()
+            ),
+            Err(err) => eprintln!("Could not write the unknown events summary to {path:?}: {err}"),
         }
     }
 
-    converter.finish()
+    Ok(converter.finish())
 }
 
 /// This is a terrible hack to work around ambiguous build IDs in old versions
@@ -208,6 +349,24 @@ where
 /// TODO: We should not do this adjustment if the length of 16 was written down in
 /// the perf.data file. However, at the moment linux-perf-data doesn't tell us
 /// whether the build ID length is "real" or guessed.
+/// Whether records of this type are part of every normal `perf record`
+/// session. We don't need anything from them, so they aren't reported as
+/// unknown events.
+fn is_routine_record_type(record_type: RecordType) -> bool {
+    matches!(
+        record_type,
+        RecordType::LOST
+            | RecordType::THROTTLE
+            | RecordType::UNTHROTTLE
+            | RecordType::LOST_SAMPLES
+            | RecordType::NAMESPACES
+            | RecordType::KSYMBOL
+            | RecordType::BPF_EVENT
+            | RecordType::CGROUP
+            | RecordType::TEXT_POKE
+    )
+}
+
 fn fixup_perf_jit_build_ids(build_ids: &mut HashMap<DsoKey, DsoInfo>) {
     for (key, info) in build_ids {
         let name = key.name();
@@ -217,3 +376,314 @@ fn fixup_perf_jit_build_ids(build_ids: &mut HashMap<DsoKey, DsoInfo>) {
         }
     }
 }
+
+/// The size of the part of the perf.data header that we may need to patch: the
+/// magic, header size, attr size, the attr / data / event types sections and the
+/// feature bitmap.
+const PATCHED_HEADER_LEN: usize = 104;
+const ATTR_SIZE_POS: usize = 16;
+const ATTR_SECTION_OFFSET_POS: usize = 24;
+const ATTR_SECTION_SIZE_POS: usize = 32;
+const DATA_SECTION_OFFSET_POS: usize = 40;
+const DATA_SECTION_SIZE_POS: usize = 48;
+const FEATURES_POS: usize = 72;
+
+/// The offset of the flags bitfield in struct perf_event_attr.
+const ATTR_FLAGS_POS: u64 = 40;
+/// The bit of the EVENT_DESC feature in the feature bitmap.
+const EVENT_DESC_FEATURE_BIT: u32 = 12;
+
+/// perf writes the feature sections after the data section, once recording has
+/// finished. If the file ends before the end of the data section, for example
+/// because the machine crashed while the file was written, the feature sections
+/// are missing and linux-perf-data refuses to open the file.
+///
+/// For such files, this returns a modified header which shrinks the data section
+/// to the end of the last complete record and which has no feature sections, so
+/// that the records which were written completely can still be read. Returns
+/// `None` for intact files. Leaves the cursor at the start of the file.
+fn patched_header_for_truncated_file<C: Read + Seek>(
+    cursor: &mut C,
+) -> Result<Option<[u8; PATCHED_HEADER_LEN]>, std::io::Error> {
+    let file_len = cursor.seek(SeekFrom::End(0))?;
+    cursor.seek(SeekFrom::Start(0))?;
+    if file_len < PATCHED_HEADER_LEN as u64 {
+        return Ok(None);
+    }
+    let mut header = [0; PATCHED_HEADER_LEN];
+    cursor.read_exact(&mut header)?;
+
+    let is_little_endian = header[0] == b'P';
+    let read_u64 = |pos: usize| {
+        let bytes = header[pos..pos + 8].try_into().unwrap();
+        if is_little_endian {
+            u64::from_le_bytes(bytes)
+        } else {
+            u64::from_be_bytes(bytes)
+        }
+    };
+    let data_offset = read_u64(DATA_SECTION_OFFSET_POS);
+    let data_size = read_u64(DATA_SECTION_SIZE_POS);
+    if data_offset > file_len || data_offset.saturating_add(data_size) <= file_len {
+        cursor.seek(SeekFrom::Start(0))?;
+        return Ok(None);
+    }
+
+    // Find the end of the last complete record. A partial record at the end
+    // would make linux-perf-data drop all the records of the current round.
+    let mut records_end = data_offset;
+    let mut record_header = [0; 8];
+    while records_end + 8 <= file_len {
+        cursor.seek(SeekFrom::Start(records_end))?;
+        cursor.read_exact(&mut record_header)?;
+        let size_bytes = [record_header[6], record_header[7]];
+        let record_size = u64::from(if is_little_endian {
+            u16::from_le_bytes(size_bytes)
+        } else {
+            u16::from_be_bytes(size_bytes)
+        });
+        if record_size < 8 || records_end + record_size > file_len {
+            break;
+        }
+        records_end += record_size;
+    }
+    cursor.seek(SeekFrom::Start(0))?;
+
+    let new_data_size = records_end - data_offset;
+    let new_data_size = if is_little_endian {
+        new_data_size.to_le_bytes()
+    } else {
+        new_data_size.to_be_bytes()
+    };
+    header[DATA_SECTION_SIZE_POS..DATA_SECTION_SIZE_POS + 8].copy_from_slice(&new_data_size);
+    header[FEATURES_POS..].fill(0);
+    Ok(Some(header))
+}
+
+/// The flags of perf_event_attr are a C bitfield. On big-endian machines, the
+/// compiler allocates bitfields starting at the most significant bit, so the
+/// first flag, `disabled`, is bit 63 rather than bit 0 of the u64. perf swaps
+/// the bit order when it reads a file from a machine with the other byte order,
+/// but linux-perf-data doesn't, so e.g. `sample_id_all` would be read from the
+/// wrong bit.
+///
+/// For big-endian files, this returns patches which reverse the bits of the
+/// flags of each attribute, both in the attr section and in the EVENT_DESC
+/// feature section, which is where linux-perf-data takes the attributes from if
+/// it's present. `patched_header` is the header which the file will be read
+/// with, if it's different from the one in the file. Leaves the cursor at the
+/// start of the file.
+fn attr_flag_patches_for_big_endian_file<C: Read + Seek>(
+    cursor: &mut C,
+    patched_header: Option<[u8; PATCHED_HEADER_LEN]>,
+) -> Result<Vec<(u64, Vec<u8>)>, std::io::Error> {
+    let header = match patched_header {
+        Some(header) => header,
+        None => {
+            let mut header = [0; PATCHED_HEADER_LEN];
+            if cursor.read_exact(&mut header).is_err() {
+                cursor.seek(SeekFrom::Start(0))?;
+                return Ok(Vec::new());
+            }
+            header
+        }
+    };
+    // Big-endian files start with "PERFILE2" written as a big-endian u64.
+    if &header[..8] != b"2ELIFREP" {
+        cursor.seek(SeekFrom::Start(0))?;
+        return Ok(Vec::new());
+    }
+    let read_u64 = |pos: usize| u64::from_be_bytes(header[pos..pos + 8].try_into().unwrap());
+
+    let mut flag_positions = Vec::new();
+    let attr_size = read_u64(ATTR_SIZE_POS);
+    let attr_section_offset = read_u64(ATTR_SECTION_OFFSET_POS);
+    let attr_section_size = read_u64(ATTR_SECTION_SIZE_POS);
+    let attr_count = attr_section_size.checked_div(attr_size).unwrap_or(0);
+    for index in 0..attr_count {
+        flag_positions.push(attr_section_offset + index * attr_size + ATTR_FLAGS_POS);
+    }
+
+    // The feature sections are described by a table after the data section,
+    // with one entry per feature bit which is set.
+    let features = read_u64(FEATURES_POS);
+    if features & (1 << EVENT_DESC_FEATURE_BIT) != 0 {
+        let preceding_features = (features & ((1 << EVENT_DESC_FEATURE_BIT) - 1)).count_ones();
+        let feature_table_offset =
+            read_u64(DATA_SECTION_OFFSET_POS).saturating_add(read_u64(DATA_SECTION_SIZE_POS));
+        let event_desc_offset = cursor
+            .seek(SeekFrom::Start(
+                feature_table_offset + u64::from(preceding_features) * 16,
+            ))
+            .and_then(|_| read_be_u64(cursor));
+        if let Ok(event_desc_offset) = event_desc_offset {
+            // A damaged EVENT_DESC section just means that fewer flags get fixed.
+            let _ = event_desc_flag_positions(cursor, event_desc_offset, &mut flag_positions);
+        }
+    }
+
+    let mut patches = Vec::new();
+    for pos in flag_positions {
+        let mut flags = [0; 8];
+        cursor.seek(SeekFrom::Start(pos))?;
+        if cursor.read_exact(&mut flags).is_err() {
+            break;
+        }
+        let flags = u64::from_be_bytes(flags).reverse_bits();
+        patches.push((pos, flags.to_be_bytes().to_vec()));
+    }
+    cursor.seek(SeekFrom::Start(0))?;
+    Ok(patches)
+}
+
+/// Adds the positions of the attribute flags in the EVENT_DESC section at
+/// `offset` to `flag_positions`.
+fn event_desc_flag_positions<C: Read + Seek>(
+    cursor: &mut C,
+    offset: u64,
+    flag_positions: &mut Vec<u64>,
+) -> Result<(), std::io::Error> {
+    cursor.seek(SeekFrom::Start(offset))?;
+    let nr = read_be_u32(cursor)?;
+    let attr_size = read_be_u32(cursor)?;
+    let mut pos = offset + 8;
+    for _ in 0..nr {
+        flag_positions.push(pos + ATTR_FLAGS_POS);
+        pos += u64::from(attr_size);
+        cursor.seek(SeekFrom::Start(pos))?;
+        let nr_ids = read_be_u32(cursor)?;
+        let event_string_len = read_be_u32(cursor)?;
+        pos += 8 + u64::from(event_string_len) + u64::from(nr_ids) * 8;
+    }
+    Ok(())
+}
+
+fn read_be_u32<C: Read>(cursor: &mut C) -> Result<u32, std::io::Error> {
+    let mut bytes = [0; 4];
+    cursor.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_be_u64<C: Read>(cursor: &mut C) -> Result<u64, std::io::Error> {
+    let mut bytes = [0; 8];
+    cursor.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Returns the bytes of the patches instead of the bytes of `inner` at the
+/// patched positions.
+struct PatchingReader<C> {
+    inner: C,
+    patches: Vec<(u64, Vec<u8>)>,
+    pos: u64,
+}
+
+impl<C: Read> Read for PatchingReader<C> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        let read_end = self.pos + len as u64;
+        for (patch_pos, patch) in &self.patches {
+            let start = self.pos.max(*patch_pos);
+            let end = read_end.min(patch_pos + patch.len() as u64);
+            if start < end {
+                buf[(start - self.pos) as usize..(end - self.pos) as usize].copy_from_slice(
+                    &patch[(start - patch_pos) as usize..(end - patch_pos) as usize],
+                );
+            }
+        }
+        self.pos = read_end;
+        Ok(len)
+    }
+}
+
+impl<C: Seek> Seek for PatchingReader<C> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{convert, is_routine_record_type, RecordType};
+    use crate::shared::recording_props::ConversionProps;
+
+    use std::io::Cursor;
+    use std::path::Path;
+
+    #[test]
+    fn convert_truncated_file() {
+        let path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../fixtures/perf/synthetic-fp.perf.data");
+        let data = std::fs::read(path).unwrap();
+        let truncated = &data[..data.len() * 6 / 10];
+        let conversion_props = ConversionProps {
+            profile_name: "Truncated".to_string(),
+            reuse_threads: false,
+            fold_recursive_prefix: false,
+            frame_marker: None,
+            latency_markers: None,
+            stack_stitching_rules: Vec::new(),
+            per_cpu_threads: false,
+            aggregate_by_name: false,
+            wall_clock: false,
+            blocked_time_weights: false,
+            code_address_bits: Vec::new(),
+            unknown_events_file: None,
+        };
+        let profile = convert(Cursor::new(truncated), None, conversion_props).unwrap();
+        let profile = serde_json::to_value(&profile).unwrap();
+        let threads = profile["threads"].as_array().unwrap();
+        let sample_count: u64 = threads
+            .iter()
+            .map(|thread| thread["samples"]["length"].as_u64().unwrap())
+            .sum();
+        assert!(sample_count > 1000 && sample_count < 2500);
+        let main_thread_markers = &threads[0]["markers"]["data"];
+        assert!(main_thread_markers.to_string().contains("TruncatedData"));
+    }
+
+    #[test]
+    fn convert_big_endian_file() {
+        // The two files have the same records, in different byte orders.
+        let convert_fixture = |name: &str| {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../fixtures/perf")
+                .join(name);
+            let data = std::fs::read(path).unwrap();
+            let conversion_props = ConversionProps {
+                profile_name: "Synthetic".to_string(),
+                reuse_threads: false,
+                fold_recursive_prefix: false,
+                frame_marker: None,
+                latency_markers: None,
+                stack_stitching_rules: Vec::new(),
+                per_cpu_threads: false,
+                aggregate_by_name: false,
+                wall_clock: false,
+                blocked_time_weights: false,
+                code_address_bits: Vec::new(),
+                unknown_events_file: None,
+            };
+            let profile = convert(Cursor::new(data), None, conversion_props).unwrap();
+            serde_json::to_value(&profile).unwrap()
+        };
+        let little_endian = convert_fixture("synthetic-fp.perf.data");
+        let big_endian = convert_fixture("synthetic-fp-be.perf.data");
+        let threads = big_endian["threads"].as_array().unwrap();
+        assert_eq!(threads.len(), 4);
+        assert_eq!(threads[1]["name"], "worker 1");
+        assert_eq!(big_endian["threads"], little_endian["threads"]);
+        assert_eq!(big_endian["libs"], little_endian["libs"]);
+    }
+
+    #[test]
+    fn routine_records_are_not_unknown_events() {
+        for record_type in [RecordType::LOST, RecordType::THROTTLE, RecordType::KSYMBOL] {
+            assert!(is_routine_record_type(record_type));
+        }
+        for record_type in [RecordType::AUX, RecordType::ITRACE_START, RecordType(1000)] {
+            assert!(!is_routine_record_type(record_type));
+        }
+    }
+}
@@ -0,0 +1,556 @@
+//! Import of hwpmc logs, as written by `pmcstat -P <event> -O <file>` on
+//! FreeBSD. This is what the FreeBSD recorder produces, and such logs can
+//! also be loaded with `samply load` on any platform; the libraries are only
+//! resolved if the binaries exist at the same paths.
+//!
+//! The log is a sequence of records which each start with this header, see
+//! `sys/pmclog.h`:
+//!
+//! ```text
+//! struct pmclog_header {
+//!     uint32_t pl_header;  /* magic 0xee << 24 | type << 16 | length */
+//!     uint32_t pl_spare;
+//!     uint64_t pl_tsc;
+//! };
+//! ```
+//!
+//! Samples are `CALLCHAIN` records, and the libraries of a process are
+//! announced with `MAP_IN` records. Processes which were already running when
+//! the recording started don't have `MAP_IN` records for their existing
+//! mappings, so those can be supplied from `procstat -v`, see
+//! [`ProcessMapping::from_procstat_vm_output`].
+
+use fxprof_processed_profile::{
+    CategoryColor, CategoryPairHandle, CpuDelta, Frame, FrameFlags, FrameInfo, LibraryHandle,
+    LibraryInfo, ProcessHandle, Profile, ReferenceTimestamp, SamplingInterval, ThreadHandle,
+    Timestamp,
+};
+use memmap2::Mmap;
+use object::{Object, ObjectSegment};
+use samply_symbols::{code_id_for_object, debug_id_for_object, text_hash_code_id_for_elf_object};
+use wholesym::samply_symbols;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+const PMCLOG_HEADER_MAGIC: u32 = 0xee;
+const HEADER_SIZE: usize = 16;
+
+const PMCLOG_TYPE_INITIALIZE: u32 = 3;
+const PMCLOG_TYPE_PROCEXEC: u32 = 10;
+const PMCLOG_TYPE_PROCEXIT: u32 = 11;
+const PMCLOG_TYPE_PROCFORK: u32 = 12;
+const PMCLOG_TYPE_MAP_IN: u32 = 15;
+const PMCLOG_TYPE_CALLCHAIN: u32 = 17;
+const PMCLOG_TYPE_THR_CREATE: u32 = 19;
+const PMCLOG_TYPE_PROC_CREATE: u32 = 21;
+
+/// The kernel is mapped in the upper half of the address space on amd64
+/// and arm64.
+const KERNEL_ADDRESS_START: u64 = 0xffff_0000_0000_0000;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("The file is not an hwpmc log")]
+    NotAPmcLog,
+
+    #[error("The hwpmc log has a malformed record at offset {0}")]
+    MalformedRecord(usize),
+}
+
+/// A file mapping of a process which existed before the recording started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessMapping {
+    pub pid: u32,
+    pub start: u64,
+    pub end: u64,
+    pub path: String,
+}
+
+impl ProcessMapping {
+    /// Parses the output of `procstat -v <pid>`, and returns the mappings
+    /// of files, one per file with the range of all its segments:
+    ///
+    /// ```text
+    ///   PID              START                END PRT  RES PRES REF SHD FLAG  TP PATH
+    ///  1234           0x200000           0x201000 r--    1    3   3   1 CN--- vn /usr/bin/app
+    /// ```
+    pub fn from_procstat_vm_output(output: &str) -> Vec<Self> {
+        let mut mappings: Vec<Self> = Vec::new();
+        for line in output.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 11 || fields[9] != "vn" {
+                continue;
+            }
+            let (Ok(pid), Some(start), Some(end)) = (
+                fields[0].parse::<u32>(),
+                parse_hex(fields[1]),
+                parse_hex(fields[2]),
+            ) else {
+                continue;
+            };
+            let path = fields[10..].join(" ");
+            match mappings
+                .iter_mut()
+                .find(|mapping| mapping.pid == pid && mapping.path == path)
+            {
+                Some(mapping) => {
+                    mapping.start = mapping.start.min(start);
+                    mapping.end = mapping.end.max(end);
+                }
+                None => mappings.push(ProcessMapping {
+                    pid,
+                    start,
+                    end,
+                    path,
+                }),
+            }
+        }
+        mappings
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+/// Whether `data` starts with the `INITIALIZE` record of an hwpmc log.
+pub fn is_pmclog(data: &[u8]) -> bool {
+    matches!(record_header(data, 0), Some((PMCLOG_TYPE_INITIALIZE, _)))
+}
+
+/// Returns the type and length of the record at `offset`.
+fn record_header(data: &[u8], offset: usize) -> Option<(u32, usize)> {
+    let header = read_u32(data, offset)?;
+    if header >> 24 != PMCLOG_HEADER_MAGIC {
+        return None;
+    }
+    let length = (header & 0xffff) as usize;
+    if length < HEADER_SIZE || offset + length > data.len() {
+        return None;
+    }
+    Some(((header >> 16) & 0xff, length))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// A nul-terminated string in a fixed-size or record-terminated field.
+fn read_str(data: &[u8]) -> String {
+    let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).into_owned()
+}
+
+pub fn convert(
+    data: &[u8],
+    profile_name: &str,
+    initial_mappings: &[ProcessMapping],
+) -> Result<Profile, Error> {
+    if !is_pmclog(data) {
+        return Err(Error::NotAPmcLog);
+    }
+    let mut converter = PmcLogConverter::new(profile_name, initial_mappings);
+    let mut offset = 0;
+    while offset < data.len() {
+        let (record_type, length) =
+            record_header(data, offset).ok_or(Error::MalformedRecord(offset))?;
+        let record = &data[offset..offset + length];
+        converter
+            .handle_record(record_type, record)
+            .ok_or(Error::MalformedRecord(offset))?;
+        offset += length;
+    }
+    Ok(converter.finish())
+}
+
+struct PmcLogProcess {
+    handle: ProcessHandle,
+    threads: HashMap<u32, ThreadHandle>,
+}
+
+/// The libraries which were found for the mapped files, by path. `None` if
+/// the file couldn't be read.
+type LibCache = HashMap<String, Option<(LibraryHandle, u64)>>;
+
+struct PmcLogConverter<'a> {
+    profile: Profile,
+    profile_name: String,
+    initial_mappings: &'a [ProcessMapping],
+    user_category: CategoryPairHandle,
+    kernel_category: CategoryPairHandle,
+    /// The TSC value and the wall-clock time from the INITIALIZE record.
+    tsc_reference: Option<(u64, u64)>,
+    tsc_freq: u64,
+    processes: HashMap<u32, PmcLogProcess>,
+    process_names: HashMap<u32, String>,
+    thread_names: HashMap<u32, String>,
+    libs: LibCache,
+    last_time: Timestamp,
+}
+
+impl<'a> PmcLogConverter<'a> {
+    fn new(profile_name: &str, initial_mappings: &'a [ProcessMapping]) -> Self {
+        let mut profile = Profile::new(
+            profile_name,
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        Self {
+            profile,
+            profile_name: profile_name.to_string(),
+            initial_mappings,
+            user_category,
+            kernel_category,
+            tsc_reference: None,
+            tsc_freq: 0,
+            processes: HashMap::new(),
+            process_names: HashMap::new(),
+            thread_names: HashMap::new(),
+            libs: HashMap::new(),
+            last_time: Timestamp::from_nanos_since_reference(0),
+        }
+    }
+
+    /// Returns `None` if the record is too short for its type.
+    fn handle_record(&mut self, record_type: u32, record: &[u8]) -> Option<()> {
+        let tsc = read_u64(record, 8)?;
+        match record_type {
+            PMCLOG_TYPE_INITIALIZE => {
+                self.tsc_freq = read_u64(record, 24)?;
+                let seconds = read_u64(record, 32)?;
+                let nanos = read_u64(record, 40)?;
+                let start_time_ns = seconds * 1_000_000_000 + nanos;
+                self.tsc_reference = Some((tsc, start_time_ns));
+                let start_time = SystemTime::UNIX_EPOCH + Duration::from_nanos(start_time_ns);
+                self.profile = Profile::new(
+                    &self.profile_name,
+                    ReferenceTimestamp::from_system_time(start_time),
+                    SamplingInterval::from_millis(1),
+                );
+                self.user_category = self
+                    .profile
+                    .add_category("User", CategoryColor::Yellow)
+                    .into();
+                self.kernel_category = self
+                    .profile
+                    .add_category("Kernel", CategoryColor::Orange)
+                    .into();
+                self.add_kernel_lib();
+            }
+            PMCLOG_TYPE_PROC_CREATE => {
+                let pid = read_u32(record, 16)?;
+                let name = read_str(record.get(24..)?);
+                self.process_names.insert(pid, name);
+            }
+            PMCLOG_TYPE_THR_CREATE => {
+                let tid = read_u32(record, 16)?;
+                let name = read_str(record.get(32..)?);
+                self.thread_names.insert(tid, name);
+            }
+            PMCLOG_TYPE_PROCEXEC => {
+                let pid = read_u32(record, 16)?;
+                // FreeBSD 13.1 added the base and dynamic linker addresses
+                // in front of the path, which replaced a single entry address.
+                let path_offset = if record.get(32) == Some(&b'/') {
+                    32
+                } else {
+                    40
+                };
+                let path = read_str(record.get(path_offset..)?);
+                let name = file_name(&path);
+                self.process_names.insert(pid, name);
+                // The process gets a new address space, so it gets a new track.
+                let time = self.time(tsc);
+                self.end_process(pid, time);
+            }
+            PMCLOG_TYPE_PROCFORK => {
+                let new_pid = read_u32(record, 20)?;
+                let parent_pid = read_u32(record, 16)?;
+                if let Some(name) = self.process_names.get(&parent_pid).cloned() {
+                    self.process_names.insert(new_pid, name);
+                }
+            }
+            PMCLOG_TYPE_PROCEXIT => {
+                let pid = read_u32(record, 20)?;
+                let time = self.time(tsc);
+                self.end_process(pid, time);
+            }
+            PMCLOG_TYPE_MAP_IN => {
+                let pid = read_u32(record, 16)?;
+                let start = read_u64(record, 24)?;
+                let path = read_str(record.get(32..)?);
+                let time = self.time(tsc);
+                self.add_mapping(pid, start, None, &path, time);
+            }
+            PMCLOG_TYPE_CALLCHAIN => {
+                let pid = read_u32(record, 16)?;
+                let tid = read_u32(record, 20)?;
+                let pcs: Vec<u64> = record
+                    .get(32..)?
+                    .chunks_exact(8)
+                    .map(|pc| u64::from_le_bytes(pc.try_into().unwrap()))
+                    .collect();
+                let time = self.time(tsc);
+                self.add_sample(pid, tid, &pcs, time);
+            }
+            _ => {}
+        }
+        Some(())
+    }
+
+    fn time(&mut self, tsc: u64) -> Timestamp {
+        let time = match self.tsc_reference {
+            Some((reference_tsc, _)) if self.tsc_freq != 0 => {
+                let ticks = tsc.saturating_sub(reference_tsc) as u128;
+                let nanos = ticks * 1_000_000_000 / self.tsc_freq as u128;
+                Timestamp::from_nanos_since_reference(nanos as u64)
+            }
+            _ => self.last_time,
+        };
+        self.last_time = time;
+        time
+    }
+
+    fn process(&mut self, pid: u32, time: Timestamp) -> &mut PmcLogProcess {
+        if !self.processes.contains_key(&pid) {
+            let name = self
+                .process_names
+                .get(&pid)
+                .cloned()
+                .unwrap_or_else(|| format!("pid {pid}"));
+            let handle = self.profile.add_process(&name, pid, time);
+            self.processes.insert(
+                pid,
+                PmcLogProcess {
+                    handle,
+                    threads: HashMap::new(),
+                },
+            );
+            let initial_mappings = self.initial_mappings;
+            for mapping in initial_mappings.iter().filter(|mapping| mapping.pid == pid) {
+                self.add_mapping(pid, mapping.start, Some(mapping.end), &mapping.path, time);
+            }
+        }
+        self.processes.get_mut(&pid).unwrap()
+    }
+
+    fn end_process(&mut self, pid: u32, time: Timestamp) {
+        if let Some(process) = self.processes.remove(&pid) {
+            self.profile.set_process_end_time(process.handle, time);
+            for thread in process.threads.values() {
+                self.profile.set_thread_end_time(*thread, time);
+            }
+        }
+    }
+
+    /// `end` is `None` for MAP_IN records, which only have the start address,
+    /// so the end is computed from the size of the file's segments.
+    fn add_mapping(&mut self, pid: u32, start: u64, end: Option<u64>, path: &str, time: Timestamp) {
+        let Some((lib, image_size)) = lib_for_path(&mut self.libs, &mut self.profile, path) else {
+            return;
+        };
+        let end = end.unwrap_or(start + image_size);
+        let process = self.process(pid, time).handle;
+        self.profile.add_lib_mapping(process, lib, start, end, 0);
+    }
+
+    /// The kernel isn't relocated, so its addresses are the ones in the file.
+    fn add_kernel_lib(&mut self) {
+        let path = "/boot/kernel/kernel";
+        let Some(file) = File::open(path).ok() else {
+            return;
+        };
+        let Ok(mmap) = (unsafe { Mmap::map(&file) }) else {
+            return;
+        };
+        let Ok(object) = object::File::parse(&mmap[..]) else {
+            return;
+        };
+        let base_svma = samply_symbols::relative_address_base(&object);
+        if let Some((lib, image_size)) = lib_for_path(&mut self.libs, &mut self.profile, path) {
+            self.profile
+                .add_kernel_lib_mapping(lib, base_svma, base_svma + image_size, 0);
+        }
+    }
+
+    fn add_sample(&mut self, pid: u32, tid: u32, pcs: &[u64], time: Timestamp) {
+        let thread_name = self.thread_names.get(&tid).cloned();
+        let process = self.process(pid, time);
+        let process_handle = process.handle;
+        let is_main_thread = process.threads.is_empty();
+        let thread = match process.threads.get(&tid) {
+            Some(thread) => *thread,
+            None => {
+                let thread = self
+                    .profile
+                    .add_thread(process_handle, tid, time, is_main_thread);
+                if let Some(name) = thread_name {
+                    self.profile.set_thread_name(thread, &name);
+                }
+                self.processes
+                    .get_mut(&pid)
+                    .unwrap()
+                    .threads
+                    .insert(tid, thread);
+                thread
+            }
+        };
+        let (user_category, kernel_category) = (self.user_category, self.kernel_category);
+        let frames = pcs.iter().enumerate().rev().map(|(index, &pc)| FrameInfo {
+            frame: if index == 0 {
+                Frame::InstructionPointer(pc)
+            } else {
+                Frame::ReturnAddress(pc)
+            },
+            category_pair: if pc >= KERNEL_ADDRESS_START {
+                kernel_category
+            } else {
+                user_category
+            },
+            flags: FrameFlags::empty(),
+        });
+        self.profile
+            .add_sample(thread, time, frames, CpuDelta::ZERO, 1);
+    }
+
+    fn finish(self) -> Profile {
+        self.profile
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map_or_else(
+        || path.to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+/// Adds the library for the ELF file at `path`, with its code ID, and
+/// returns it with the size of its image, from the start of its first
+/// segment to the end of its last one.
+fn lib_for_path(
+    libs: &mut LibCache,
+    profile: &mut Profile,
+    path: &str,
+) -> Option<(LibraryHandle, u64)> {
+    if let Some(lib) = libs.get(path) {
+        return *lib;
+    }
+    let lib = (|| {
+        let file = File::open(path).ok()?;
+        let mmap = unsafe { Mmap::map(&file) }.ok()?;
+        let object = object::File::parse(&mmap[..]).ok()?;
+        let debug_id = debug_id_for_object(&object)?;
+        let base_svma = samply_symbols::relative_address_base(&object);
+        let image_end = object
+            .segments()
+            .map(|segment| segment.address() + segment.size())
+            .max()?;
+        // Files without a GNU build ID, like most OpenBSD and NetBSD
+        // binaries, get the text hash code ID that the symbolicator computes
+        // for them too.
+        let code_id = code_id_for_object(&object)
+            .or_else(|| text_hash_code_id_for_elf_object(&object))
+            .map(|code_id| code_id.to_string());
+        let name = file_name(path);
+        let handle = profile.add_lib(LibraryInfo {
+            name: name.clone(),
+            debug_name: name,
+            path: path.to_string(),
+            debug_path: path.to_string(),
+            debug_id,
+            code_id,
+            arch: None,
+            symbol_table: None,
+        });
+        Some((handle, image_end.saturating_sub(base_svma)))
+    })();
+    libs.insert(path.to_string(), lib);
+    lib
+}
+
+#[cfg(test)]
+mod test {
+    use super::{convert, is_pmclog, ProcessMapping};
+
+    fn record(record_type: u32, tsc: u64, body: &[u8]) -> Vec<u8> {
+        let length = (16 + body.len() + 7) & !7;
+        let mut record = Vec::with_capacity(length);
+        record
+            .extend_from_slice(&((0xee << 24) | (record_type << 16) | length as u32).to_le_bytes());
+        record.extend_from_slice(&0u32.to_le_bytes());
+        record.extend_from_slice(&tsc.to_le_bytes());
+        record.extend_from_slice(body);
+        record.resize(length, 0);
+        record
+    }
+
+    #[test]
+    fn convert_pmclog() {
+        let mut initialize = Vec::new();
+        initialize.extend_from_slice(&0u32.to_le_bytes()); // version
+        initialize.extend_from_slice(&0u32.to_le_bytes()); // cpu
+        initialize.extend_from_slice(&1_000_000_000u64.to_le_bytes()); // tsc_freq
+        initialize.extend_from_slice(&1_700_000_000u64.to_le_bytes()); // tv_sec
+        initialize.extend_from_slice(&0u64.to_le_bytes()); // tv_nsec
+
+        let mut proc_create = Vec::new();
+        proc_create.extend_from_slice(&42u32.to_le_bytes());
+        proc_create.extend_from_slice(&0u32.to_le_bytes());
+        proc_create.extend_from_slice(b"app\0");
+
+        let mut callchain = Vec::new();
+        callchain.extend_from_slice(&42u32.to_le_bytes()); // pid
+        callchain.extend_from_slice(&100_042u32.to_le_bytes()); // tid
+        callchain.extend_from_slice(&0u32.to_le_bytes()); // pmcid
+        callchain.extend_from_slice(&1u32.to_le_bytes()); // cpuflags
+        callchain.extend_from_slice(&0x20_1234u64.to_le_bytes());
+        callchain.extend_from_slice(&0x20_0100u64.to_le_bytes());
+
+        let mut data = record(3, 1000, &initialize);
+        data.extend(record(21, 1500, &proc_create));
+        data.extend(record(17, 2000, &callchain));
+        data.extend(record(17, 3000, &callchain));
+        assert!(is_pmclog(&data));
+
+        let profile = convert(&data, "test", &[]).unwrap();
+        let json = serde_json::to_value(&profile).unwrap();
+        let thread = &json["threads"][0];
+        assert_eq!(thread["processName"], "app");
+        assert_eq!(thread["samples"]["length"], 2);
+
+        assert!(!is_pmclog(b"PERFILE2"));
+    }
+
+    #[test]
+    fn parse_procstat_output() {
+        let output =
+            "  PID              START                END PRT  RES PRES REF SHD FLAG  TP PATH
+ 1234           0x200000           0x201000 r--    1    3   3   1 CN--- vn /usr/bin/my app
+ 1234           0x201000           0x203000 r-x    2    3   3   1 CN--- vn /usr/bin/my app
+ 1234       0x8002a0000        0x8002b0000 rw-    0    0   1   0 ----- sw
+";
+        assert_eq!(
+            ProcessMapping::from_procstat_vm_output(output),
+            vec![ProcessMapping {
+                pid: 1234,
+                start: 0x200000,
+                end: 0x203000,
+                path: "/usr/bin/my app".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,377 @@
+//! Import of pprof profiles, as written by Go's runtime/pprof, by
+//! `perf_to_profile`, or by async-profiler with `-o pprof`. The files are
+//! gzip-compressed protocol buffers, with the messages from
+//! <https://github.com/google/pprof/blob/main/proto/profile.proto>.
+//!
+//! Each sample has a list of location IDs, from the leaf to the root, and one
+//! value per sample type, e.g. a count and a CPU time. The sample type which
+//! the profile names as its default, or the last one, becomes the sample
+//! weight. A location with inlined functions has one line per function, from
+//! the innermost one outwards.
+//!
+//! The samples have no timestamps, so they're spread evenly over the
+//! duration of the profile. A "thread" label on the samples puts them into
+//! one thread per label value.
+
+use flate2::read::GzDecoder;
+use fxprof_processed_profile::{
+    CategoryHandle, CpuDelta, Frame, FrameFlags, FrameInfo, Profile, ReferenceTimestamp,
+    SamplingInterval, StringHandle, ThreadHandle, Timestamp, WeightType,
+};
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use crate::shared::protobuf::{DecodeError, FieldValue, Fields};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// How much of the decompressed message [`is_pprof`] looks at.
+const SNIFF_LEN: u64 = 4096;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O Error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid protocol buffer: {0}")]
+    InvalidProtobuf(#[from] DecodeError),
+
+    #[error("The file isn't a pprof profile")]
+    NotPprof,
+}
+
+#[derive(Debug, Default)]
+struct PprofProfile {
+    /// The type and the unit of each sample value, as string indexes.
+    sample_types: Vec<(i64, i64)>,
+    samples: Vec<Sample>,
+    /// The file name of each mapping, by mapping ID.
+    mappings: HashMap<u64, i64>,
+    locations: HashMap<u64, Location>,
+    /// The name of each function, by function ID.
+    functions: HashMap<u64, i64>,
+    strings: Vec<String>,
+    time_nanos: i64,
+    duration_nanos: i64,
+    default_sample_type: i64,
+}
+
+#[derive(Debug, Default)]
+struct Sample {
+    /// From the leaf to the root.
+    location_ids: Vec<u64>,
+    values: Vec<u64>,
+    /// The key and the string value of each label, as string indexes.
+    labels: Vec<(i64, i64)>,
+}
+
+#[derive(Debug, Default)]
+struct Location {
+    mapping_id: u64,
+    address: u64,
+    /// The function of each line, from the innermost inlined one outwards.
+    function_ids: Vec<u64>,
+}
+
+impl PprofProfile {
+    fn parse(data: &[u8]) -> Result<Self, Error> {
+        let mut profile = PprofProfile::default();
+        let mut fields = Fields::new(data);
+        while let Some((number, value)) = fields.next_field()? {
+            match number {
+                1 => profile
+                    .sample_types
+                    .push(parse_value_type(value.as_bytes())?),
+                2 => profile.samples.push(parse_sample(value.as_bytes())?),
+                3 => {
+                    let (mut id, mut filename) = (0, 0);
+                    let mut fields = Fields::new(value.as_bytes());
+                    while let Some((number, value)) = fields.next_field()? {
+                        match number {
+                            1 => id = value.as_u64(),
+                            5 => filename = value.as_i64(),
+                            _ => {}
+                        }
+                    }
+                    profile.mappings.insert(id, filename);
+                }
+                4 => {
+                    let (id, location) = parse_location(value.as_bytes())?;
+                    profile.locations.insert(id, location);
+                }
+                5 => {
+                    let (mut id, mut name) = (0, 0);
+                    let mut fields = Fields::new(value.as_bytes());
+                    while let Some((number, value)) = fields.next_field()? {
+                        match number {
+                            1 => id = value.as_u64(),
+                            2 => name = value.as_i64(),
+                            _ => {}
+                        }
+                    }
+                    profile.functions.insert(id, name);
+                }
+                6 => {
+                    let string = std::str::from_utf8(value.as_bytes())
+                        .map_err(|_| Error::InvalidProtobuf(DecodeError("string is not UTF-8")))?;
+                    profile.strings.push(string.to_string());
+                }
+                9 => profile.time_nanos = value.as_i64(),
+                10 => profile.duration_nanos = value.as_i64(),
+                14 => profile.default_sample_type = value.as_i64(),
+                _ => {}
+            }
+        }
+        // The string table always starts with the empty string.
+        if profile.sample_types.is_empty()
+            || profile.strings.first().map(String::as_str) != Some("")
+        {
+            return Err(Error::NotPprof);
+        }
+        Ok(profile)
+    }
+
+    fn string(&self, index: i64) -> &str {
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| self.strings.get(index))
+            .map_or("", String::as_str)
+    }
+}
+
+fn parse_value_type(data: &[u8]) -> Result<(i64, i64), Error> {
+    let (mut kind, mut unit) = (0, 0);
+    let mut fields = Fields::new(data);
+    while let Some((number, value)) = fields.next_field()? {
+        match number {
+            1 => kind = value.as_i64(),
+            2 => unit = value.as_i64(),
+            _ => {}
+        }
+    }
+    Ok((kind, unit))
+}
+
+fn parse_sample(data: &[u8]) -> Result<Sample, Error> {
+    let mut sample = Sample::default();
+    let mut fields = Fields::new(data);
+    while let Some((number, value)) = fields.next_field()? {
+        match number {
+            1 => value.push_varints(&mut sample.location_ids)?,
+            2 => value.push_varints(&mut sample.values)?,
+            3 => {
+                let (mut key, mut string) = (0, 0);
+                let mut fields = Fields::new(value.as_bytes());
+                while let Some((number, value)) = fields.next_field()? {
+                    match number {
+                        1 => key = value.as_i64(),
+                        2 => string = value.as_i64(),
+                        _ => {}
+                    }
+                }
+                sample.labels.push((key, string));
+            }
+            _ => {}
+        }
+    }
+    Ok(sample)
+}
+
+fn parse_location(data: &[u8]) -> Result<(u64, Location), Error> {
+    let mut id = 0;
+    let mut location = Location::default();
+    let mut fields = Fields::new(data);
+    while let Some((number, value)) = fields.next_field()? {
+        match number {
+            1 => id = value.as_u64(),
+            2 => location.mapping_id = value.as_u64(),
+            3 => location.address = value.as_u64(),
+            4 => {
+                let mut fields = Fields::new(value.as_bytes());
+                while let Some((number, value)) = fields.next_field()? {
+                    if number == 1 {
+                        location.function_ids.push(value.as_u64());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((id, location))
+}
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+/// Whether `header`, the start of a file, is the start of a pprof profile,
+/// compressed or not. Only the fields in the first [`SNIFF_LEN`] bytes of the
+/// message are checked, so that the whole profile isn't decompressed just to
+/// recognize it.
+pub fn is_pprof(header: &[u8]) -> bool {
+    if header.starts_with(&GZIP_MAGIC) {
+        let mut start = Vec::new();
+        // The header usually ends in the middle of the compressed stream, so
+        // reading fails at its end, after the bytes before it were read.
+        let _ = GzDecoder::new(header)
+            .take(SNIFF_LEN)
+            .read_to_end(&mut start);
+        starts_like_profile(&start)
+    } else {
+        starts_like_profile(&header[..header.len().min(SNIFF_LEN as usize)])
+    }
+}
+
+/// Whether `data` starts with a `sample_type` field, and all the complete
+/// fields in it are fields of a `Profile` message with the right wire type.
+/// The last field is usually cut off.
+fn starts_like_profile(data: &[u8]) -> bool {
+    let mut fields = Fields::new(data);
+    let Ok(Some((1, FieldValue::Bytes(value_type)))) = fields.next_field() else {
+        return false;
+    };
+    let mut value_type_fields = Fields::new(value_type);
+    while let Some(field) = value_type_fields.next_field().transpose() {
+        if !matches!(field, Ok((1 | 2, FieldValue::Varint(_)))) {
+            return false;
+        }
+    }
+    while let Ok(Some((number, value))) = fields.next_field() {
+        let is_valid = match (number, value) {
+            (1..=6 | 11, FieldValue::Bytes(_)) => true,
+            (7..=10 | 12 | 14, FieldValue::Varint(_)) => true,
+            // The comments are a repeated int64, which can be packed.
+            (13, FieldValue::Varint(_) | FieldValue::Bytes(_)) => true,
+            _ => false,
+        };
+        if !is_valid {
+            return false;
+        }
+    }
+    true
+}
+
+pub fn convert(data: &[u8], profile_name: &str) -> Result<Profile, Error> {
+    let pprof = if data.starts_with(&GZIP_MAGIC) {
+        PprofProfile::parse(&decompress(data)?)?
+    } else {
+        PprofProfile::parse(data)?
+    };
+
+    // By convention, the last sample type is the default one.
+    let value_index = pprof
+        .sample_types
+        .iter()
+        .position(|(kind, _unit)| *kind == pprof.default_sample_type && *kind != 0)
+        .unwrap_or(pprof.sample_types.len() - 1);
+    let (_kind, unit) = pprof.sample_types[value_index];
+    let (weight_type, weight_factor) = match pprof.string(unit) {
+        "bytes" => (WeightType::Bytes, 1.0),
+        "nanoseconds" => (WeightType::TracingMicroseconds, 0.001),
+        "microseconds" => (WeightType::TracingMicroseconds, 1.0),
+        "milliseconds" => (WeightType::TracingMicroseconds, 1000.0),
+        _ => (WeightType::Samples, 1.0),
+    };
+
+    let mut profile = Profile::new(
+        profile_name,
+        ReferenceTimestamp::from_millis_since_unix_epoch(pprof.time_nanos as f64 / 1_000_000.0),
+        SamplingInterval::from_millis(1),
+    );
+    profile.set_weight_type(weight_type);
+
+    // The first mapping is the main binary.
+    let process_name = pprof
+        .mappings
+        .get(&1)
+        .map(|filename| pprof.string(*filename))
+        .and_then(|path| path.rsplit('/').next())
+        .filter(|name| !name.is_empty())
+        .unwrap_or(profile_name);
+    let start_time = Timestamp::from_millis_since_reference(0.0);
+    let process = profile.add_process(process_name, 0, start_time);
+    let thread_label = pprof.strings.iter().position(|s| s == "thread");
+    let mut threads: HashMap<Option<i64>, ThreadHandle> = HashMap::new();
+
+    // The frames of each location, from the innermost one outwards.
+    let mut location_frames: HashMap<u64, Vec<StringHandle>> = HashMap::new();
+    for (id, location) in &pprof.locations {
+        let mut names: Vec<String> = location
+            .function_ids
+            .iter()
+            .filter_map(|function_id| pprof.functions.get(function_id))
+            .map(|name| pprof.string(*name).to_string())
+            .collect();
+        if names.is_empty() {
+            let mapping = pprof
+                .mappings
+                .get(&location.mapping_id)
+                .map(|filename| pprof.string(*filename))
+                .and_then(|path| path.rsplit('/').next())
+                .filter(|name| !name.is_empty());
+            names.push(match mapping {
+                Some(mapping) => format!("0x{:x} in {mapping}", location.address),
+                None => format!("0x{:x}", location.address),
+            });
+        }
+        let frames = names
+            .iter()
+            .map(|name| profile.intern_string(name))
+            .collect();
+        location_frames.insert(*id, frames);
+    }
+
+    let sample_count = pprof.samples.len().max(1) as f64;
+    let duration_ms = if pprof.duration_nanos > 0 {
+        pprof.duration_nanos as f64 / 1_000_000.0
+    } else {
+        sample_count
+    };
+    for (index, sample) in pprof.samples.iter().enumerate() {
+        let thread_name = thread_label.and_then(|key| {
+            sample
+                .labels
+                .iter()
+                .find(|(label_key, _)| *label_key == key as i64)
+                .map(|(_, value)| *value)
+        });
+        let tid = threads.len() as u32;
+        let thread = *threads.entry(thread_name).or_insert_with(|| {
+            // Main threads are named after their process, so threads with a
+            // label aren't main threads.
+            let is_main = thread_name.is_none();
+            let thread = profile.add_thread(process, tid, start_time, is_main);
+            let name = thread_name.map_or(process_name, |name| pprof.string(name));
+            profile.set_thread_name(thread, name);
+            thread
+        });
+        let frames: Vec<FrameInfo> = sample
+            .location_ids
+            .iter()
+            .rev()
+            .filter_map(|id| location_frames.get(id))
+            .flat_map(|frames| frames.iter().rev())
+            .map(|name| FrameInfo {
+                frame: Frame::Label(*name),
+                category_pair: CategoryHandle::OTHER.into(),
+                flags: FrameFlags::empty(),
+            })
+            .collect();
+        let value = sample.values.get(value_index).copied().unwrap_or(0) as i64;
+        let weight = (value as f64 * weight_factor)
+            .round()
+            .clamp(0.0, i32::MAX as f64) as i32;
+        let timestamp =
+            Timestamp::from_millis_since_reference(index as f64 * duration_ms / sample_count);
+        profile.add_sample(
+            thread,
+            timestamp,
+            frames.into_iter(),
+            CpuDelta::ZERO,
+            weight,
+        );
+    }
+    Ok(profile)
+}
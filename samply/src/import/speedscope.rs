@@ -0,0 +1,229 @@
+//! Import of profiles in the speedscope file format, which many profilers
+//! can write, e.g. py-spy with `--format speedscope`.
+//!
+//! A speedscope file has a list of shared frames and a list of profiles:
+//!
+//! ```text
+//! {
+//!   "$schema": "https://www.speedscope.app/file-format-schema.json",
+//!   "shared": { "frames": [{ "name": "main" }, { "name": "work" }] },
+//!   "profiles": [
+//!     { "type": "sampled", "name": "Thread 1", "unit": "milliseconds",
+//!       "startValue": 0, "endValue": 3,
+//!       "samples": [[0, 1], [0]], "weights": [2, 1] },
+//!     { "type": "evented", "name": "Thread 2", "unit": "milliseconds",
+//!       "startValue": 0, "endValue": 3,
+//!       "events": [{ "type": "O", "at": 0, "frame": 0 },
+//!                  { "type": "C", "at": 3, "frame": 0 }] }
+//!   ]
+//! }
+//! ```
+//!
+//! Each profile becomes a thread. Sampled profiles have no timestamps, so the
+//! samples are laid out one after the other, each as long as its weight if the
+//! weights are times. Evented profiles get a sample for each span between two
+//! events, whose weight is the length of the span.
+
+use fxprof_processed_profile::{
+    CategoryHandle, CpuDelta, Frame, FrameFlags, FrameInfo, Profile, ReferenceTimestamp,
+    SamplingInterval, StringHandle, Timestamp, WeightType,
+};
+use serde_derive::Deserialize;
+
+use std::io::Read;
+
+/// Part of the URL in the `$schema` field of speedscope files.
+const SCHEMA_URL: &[u8] = b"speedscope.app/file-format-schema.json";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid speedscope file: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Frame {0} doesn't exist")]
+    InvalidFrame(usize),
+}
+
+#[derive(Deserialize)]
+struct File {
+    name: Option<String>,
+    shared: Shared,
+    profiles: Vec<SpeedscopeProfile>,
+}
+
+#[derive(Deserialize)]
+struct Shared {
+    frames: Vec<SpeedscopeFrame>,
+}
+
+#[derive(Deserialize)]
+struct SpeedscopeFrame {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum SpeedscopeProfile {
+    Sampled {
+        name: String,
+        unit: String,
+        /// The frames of each sample, from the root to the leaf.
+        samples: Vec<Vec<usize>>,
+        weights: Vec<f64>,
+    },
+    Evented {
+        name: String,
+        unit: String,
+        #[serde(rename = "startValue")]
+        start_value: f64,
+        events: Vec<Event>,
+    },
+}
+
+#[derive(Deserialize)]
+struct Event {
+    /// "O" when the frame is entered, and "C" when it's left.
+    #[serde(rename = "type")]
+    kind: String,
+    at: f64,
+    frame: usize,
+}
+
+/// Whether `data` is a speedscope file, based on the schema URL at its start.
+pub fn is_speedscope(data: &[u8]) -> bool {
+    let start = &data[..data.len().min(1024)];
+    start.starts_with(b"{") && start.windows(SCHEMA_URL.len()).any(|w| w == SCHEMA_URL)
+}
+
+/// How many milliseconds one unit of the profile's values is, or `None` if
+/// the values aren't times.
+fn unit_in_ms(unit: &str) -> Option<f64> {
+    match unit {
+        "nanoseconds" => Some(0.000_001),
+        "microseconds" => Some(0.001),
+        "milliseconds" => Some(1.0),
+        "seconds" => Some(1000.0),
+        _ => None,
+    }
+}
+
+pub fn convert<R: Read>(reader: R, profile_name: &str) -> Result<Profile, Error> {
+    let file: File = serde_json::from_reader(reader)?;
+    let profile_name = file.name.as_deref().unwrap_or(profile_name);
+
+    // The weight type is the same for all threads, so it's chosen by the unit
+    // of the first profile.
+    let first_unit = match file.profiles.first() {
+        Some(SpeedscopeProfile::Sampled { unit, .. } | SpeedscopeProfile::Evented { unit, .. }) => {
+            unit.as_str()
+        }
+        None => "none",
+    };
+    let weight_type = match first_unit {
+        "bytes" => WeightType::Bytes,
+        unit if unit_in_ms(unit).is_some() => WeightType::TracingMicroseconds,
+        _ => WeightType::Samples,
+    };
+
+    let mut profile = Profile::new(
+        profile_name,
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    profile.set_weight_type(weight_type);
+    let frame_names: Vec<StringHandle> = file
+        .shared
+        .frames
+        .iter()
+        .map(|frame| profile.intern_string(&frame.name))
+        .collect();
+    let frame_info = |frame: usize| -> Result<FrameInfo, Error> {
+        Ok(FrameInfo {
+            frame: Frame::Label(*frame_names.get(frame).ok_or(Error::InvalidFrame(frame))?),
+            category_pair: CategoryHandle::OTHER.into(),
+            flags: FrameFlags::empty(),
+        })
+    };
+    // Converts a value of the profile to a sample weight.
+    let weight = |value: f64, unit: &str| -> i32 {
+        let value = match (weight_type, unit_in_ms(unit)) {
+            (WeightType::TracingMicroseconds, Some(ms)) => value * ms * 1000.0,
+            _ => value,
+        };
+        value.round().clamp(0.0, i32::MAX as f64) as i32
+    };
+
+    let start_time = Timestamp::from_millis_since_reference(0.0);
+    let process = profile.add_process(profile_name, 0, start_time);
+    for (index, speedscope_profile) in file.profiles.iter().enumerate() {
+        let tid = index as u32;
+        let thread = profile.add_thread(process, tid, start_time, index == 0);
+        match speedscope_profile {
+            SpeedscopeProfile::Sampled {
+                name,
+                unit,
+                samples,
+                weights,
+            } => {
+                profile.set_thread_name(thread, name);
+                let mut time = 0.0;
+                for (i, stack) in samples.iter().enumerate() {
+                    let value = weights.get(i).copied().unwrap_or(1.0);
+                    let frames = stack
+                        .iter()
+                        .map(|frame| frame_info(*frame))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let timestamp = Timestamp::from_millis_since_reference(time);
+                    profile.add_sample(
+                        thread,
+                        timestamp,
+                        frames.into_iter(),
+                        CpuDelta::ZERO,
+                        weight(value, unit),
+                    );
+                    time += unit_in_ms(unit).map_or(1.0, |ms| value * ms);
+                }
+            }
+            SpeedscopeProfile::Evented {
+                name,
+                unit,
+                start_value,
+                events,
+            } => {
+                profile.set_thread_name(thread, name);
+                let ms = unit_in_ms(unit).unwrap_or(1.0);
+                let mut stack: Vec<usize> = Vec::new();
+                let mut last_at = *start_value;
+                for event in events {
+                    if event.at > last_at && !stack.is_empty() {
+                        let frames = stack
+                            .iter()
+                            .map(|frame| frame_info(*frame))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        let timestamp =
+                            Timestamp::from_millis_since_reference((last_at - start_value) * ms);
+                        profile.add_sample(
+                            thread,
+                            timestamp,
+                            frames.into_iter(),
+                            CpuDelta::ZERO,
+                            weight(event.at - last_at, unit),
+                        );
+                    }
+                    last_at = last_at.max(event.at);
+                    match event.kind.as_str() {
+                        "O" => stack.push(event.frame),
+                        // Frames are closed in the reverse order of opening,
+                        // but be lenient with files which don't do that.
+                        _ => {
+                            if let Some(pos) = stack.iter().rposition(|f| *f == event.frame) {
+                                stack.truncate(pos);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(profile)
+}
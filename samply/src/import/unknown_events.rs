@@ -0,0 +1,117 @@
+//! A summary of the events in an imported file which samply doesn't interpret,
+//! as written by `samply load --unknown-events dump`. It lists how often each
+//! kind of event occurred, with a hexdump of the first few payloads, so that
+//! a feature request for the missing support has something to go on.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// The number of example payloads which are kept for each kind of event.
+const MAX_EXAMPLES: usize = 3;
+/// Longer payloads are cut off in the examples.
+const MAX_EXAMPLE_LEN: usize = 256;
+
+#[derive(Debug, Default)]
+pub struct UnknownEventsSummary {
+    events: BTreeMap<String, UnknownEvent>,
+}
+
+#[derive(Debug, Default)]
+struct UnknownEvent {
+    count: u64,
+    examples: Vec<Vec<u8>>,
+}
+
+impl UnknownEventsSummary {
+    pub fn add(&mut self, kind: &str, payload: &[u8]) {
+        let event = match self.events.get_mut(kind) {
+            Some(event) => event,
+            None => self.events.entry(kind.to_string()).or_default(),
+        };
+        event.count += 1;
+        if event.examples.len() < MAX_EXAMPLES {
+            let len = payload.len().min(MAX_EXAMPLE_LEN);
+            event.examples.push(payload[..len].to_vec());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// The number of distinct kinds of events.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Writes the summary, with the most frequent events first.
+    pub fn write_to<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        let mut events: Vec<(&String, &UnknownEvent)> = self.events.iter().collect();
+        events.sort_by_key(|(_, event)| std::cmp::Reverse(event.count));
+        for (kind, event) in events {
+            writeln!(w, "{kind}: {} occurrences", event.count)?;
+            for (index, example) in event.examples.iter().enumerate() {
+                writeln!(w, "  example {} ({} bytes):", index + 1, example.len())?;
+                write_hexdump(&mut w, example)?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `bytes` in the layout of `hexdump -C`, indented by four spaces.
+fn write_hexdump<W: Write>(w: &mut W, bytes: &[u8]) -> std::io::Result<()> {
+    for (line_index, line) in bytes.chunks(16).enumerate() {
+        write!(w, "    {:08x} ", line_index * 16)?;
+        for i in 0..16 {
+            if i == 8 {
+                write!(w, " ")?;
+            }
+            match line.get(i) {
+                Some(byte) => write!(w, " {byte:02x}")?,
+                None => write!(w, "   ")?,
+            }
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(w, "  |{ascii}|")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::UnknownEventsSummary;
+
+    #[test]
+    fn write_summary() {
+        let mut summary = UnknownEventsSummary::default();
+        summary.add("LOST record", &[1, 0, 0, 0]);
+        for _ in 0..5 {
+            summary.add("sample of irq:irq_handler_entry", b"\x2a\x00\x00\x00eth0");
+        }
+        assert_eq!(summary.len(), 2);
+
+        let mut output = Vec::new();
+        summary.write_to(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output.lines().take(3).collect::<Vec<_>>(),
+            vec![
+                "sample of irq:irq_handler_entry: 5 occurrences",
+                "  example 1 (8 bytes):",
+                "    00000000  2a 00 00 00 65 74 68 30                           |*...eth0|",
+            ]
+        );
+        assert_eq!(output.matches("example").count(), 4);
+    }
+}
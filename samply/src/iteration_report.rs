@@ -0,0 +1,346 @@
+use serde_json::{json, Value};
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use crate::profile_symbolication::{
+    count_leaf_samples, json_array, lookup_symbols, profile_lib_ids, read_profile,
+};
+use crate::server::{symbol_manager_for_parsed_profile, SymbolDownloads, SymbolIdMatching};
+
+/// How many functions, by mean self time, are in the report.
+const FUNCTION_COUNT: usize = 30;
+
+/// Functions whose self time has a standard deviation of more than this
+/// fraction of their mean self time are flagged as noisy.
+const HIGH_VARIANCE_THRESHOLD: f64 = 0.25;
+
+pub struct IterationReportProps {
+    /// Print the text report to stdout.
+    pub print_text: bool,
+    pub json_output: Option<PathBuf>,
+    pub verbose: bool,
+    pub symbol_dirs: Vec<PathBuf>,
+    pub symbol_downloads: SymbolDownloads,
+}
+
+/// The number of samples per (lib index, address) of the leaf frame.
+type LeafSamples = HashMap<(usize, u32), u64>;
+
+/// One run of the launched command, with all the processes it started.
+struct Iteration {
+    /// The pid of the launched process.
+    pid: String,
+    /// The self time of each function in this iteration, in milliseconds.
+    function_self_time: HashMap<String, f64>,
+    total_time: f64,
+}
+
+struct FunctionStats {
+    name: String,
+    self_times: Vec<f64>,
+    mean: f64,
+    stddev: f64,
+}
+
+impl FunctionStats {
+    /// The standard deviation relative to the mean.
+    fn coefficient_of_variation(&self) -> f64 {
+        if self.mean > 0.0 {
+            self.stddev / self.mean
+        } else {
+            0.0
+        }
+    }
+
+    fn has_high_variance(&self) -> bool {
+        self.coefficient_of_variation() > HIGH_VARIANCE_THRESHOLD
+    }
+}
+
+/// Compares the iterations of a profile recorded with `--iteration-count`: for
+/// the functions with the most self time, prints or writes the mean and the
+/// standard deviation of their self time across iterations, and flags the ones
+/// whose self time varies a lot. Consistent hotspots are worth optimizing,
+/// whereas noisy functions need more iterations before they can be judged.
+///
+/// The self time is estimated from the number of samples in the function. Each
+/// launched process in `launched_pids` and its descendants make up one
+/// iteration. The launched processes of warm-up iterations shouldn't be in
+/// `launched_pids`.
+#[tokio::main]
+pub async fn write_iteration_report(
+    profile_path: &Path,
+    props: &IterationReportProps,
+    launched_pids: &[u32],
+) -> Result<(), String> {
+    let profile = read_profile(profile_path)
+        .map_err(|err| format!("Could not read {profile_path:?}: {err}"))?;
+    let interval_ms = profile
+        .pointer("/meta/interval")
+        .and_then(Value::as_f64)
+        .unwrap_or(1.0);
+
+    // Count the leaf addresses per iteration.
+    let mut iteration_samples: Vec<(String, LeafSamples)> = Vec::new();
+    let threads = json_array(&profile, "threads");
+    for (thread, iteration_pid) in threads.iter().zip(iteration_pids(threads, launched_pids)) {
+        let Some(iteration_pid) = iteration_pid else {
+            continue;
+        };
+        let index = match iteration_samples
+            .iter()
+            .position(|(pid, _)| *pid == iteration_pid)
+        {
+            Some(index) => index,
+            None => {
+                iteration_samples.push((iteration_pid, HashMap::new()));
+                iteration_samples.len() - 1
+            }
+        };
+        count_leaf_samples(thread, &mut iteration_samples[index].1);
+    }
+    if iteration_samples.len() < 2 {
+        return Err(format!(
+            "The profile has {} iteration(s) with samples. Record at least two with --iteration-count to compare them.",
+            iteration_samples.len()
+        ));
+    }
+
+    let mut addresses: Vec<(usize, u32)> = iteration_samples
+        .iter()
+        .flat_map(|(_, samples)| samples.keys().copied())
+        .collect();
+    addresses.sort_unstable();
+    addresses.dedup();
+    let symbol_manager = symbol_manager_for_parsed_profile(
+        &profile,
+        props.verbose,
+        &props.symbol_dirs,
+        SymbolIdMatching::default(),
+        &props.symbol_downloads,
+    );
+    let libs = profile_lib_ids(&profile);
+    let symbols = lookup_symbols(&symbol_manager, &libs, &addresses).await?;
+
+    let iterations: Vec<Iteration> = iteration_samples
+        .into_iter()
+        .map(|(pid, samples)| {
+            let mut function_self_time: HashMap<String, f64> = HashMap::new();
+            for ((lib, address), count) in samples {
+                let lib_name = libs.get(lib).map_or("", |(name, _)| name.as_str());
+                let name = match symbols.get(&(lib, address)) {
+                    Some(symbol) => format!("{} ({lib_name})", symbol.function),
+                    None => format!("<unsymbolicated code in {lib_name}>"),
+                };
+                *function_self_time.entry(name).or_default() += count as f64 * interval_ms;
+            }
+            let total_time = function_self_time.values().sum();
+            Iteration {
+                pid,
+                function_self_time,
+                total_time,
+            }
+        })
+        .collect();
+
+    let mut functions = function_stats(&iterations);
+    functions.truncate(FUNCTION_COUNT);
+
+    if props.print_text {
+        print!("{}", report_text(&iterations, &functions));
+    }
+    if let Some(json_output) = &props.json_output {
+        let report = report_json(&iterations, &functions);
+        std::fs::write(json_output, report.to_string())
+            .map_err(|err| format!("Could not write {json_output:?}: {err}"))?;
+        eprintln!("Saved the iteration report to {json_output:?}.");
+    }
+    Ok(())
+}
+
+/// Returns, for each thread, the pid of the launched process in `launched_pids`
+/// which its process is or descends from, or `None` if the thread isn't part of
+/// an iteration.
+fn iteration_pids(threads: &[Value], launched_pids: &[u32]) -> Vec<Option<String>> {
+    let parent_pids: HashMap<&str, Option<&str>> = threads
+        .iter()
+        .filter_map(|thread| {
+            let pid = thread["pid"].as_str()?;
+            Some((pid, thread["parentPid"].as_str()))
+        })
+        .collect();
+    threads
+        .iter()
+        .map(|thread| {
+            let mut pid = thread["pid"].as_str()?;
+            // Bound the walk in case the parents form a cycle.
+            for _ in 0..=parent_pids.len() {
+                // The launched process has a suffix after it execs, e.g. "1234.1".
+                let base_pid = pid.split('.').next().unwrap_or(pid);
+                if launched_pids
+                    .iter()
+                    .any(|launched_pid| base_pid.parse() == Ok(*launched_pid))
+                {
+                    return Some(base_pid.to_string());
+                }
+                pid = parent_pids.get(pid).copied().flatten()?;
+            }
+            None
+        })
+        .collect()
+}
+
+/// Returns the statistics of each function's self time across the iterations,
+/// sorted by mean self time. Iterations in which a function has no samples count
+/// with a self time of zero.
+fn function_stats(iterations: &[Iteration]) -> Vec<FunctionStats> {
+    let mut names: Vec<&String> = iterations
+        .iter()
+        .flat_map(|iteration| iteration.function_self_time.keys())
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    let mut functions: Vec<FunctionStats> = names
+        .into_iter()
+        .map(|name| {
+            let self_times: Vec<f64> = iterations
+                .iter()
+                .map(|iteration| {
+                    iteration
+                        .function_self_time
+                        .get(name)
+                        .copied()
+                        .unwrap_or(0.0)
+                })
+                .collect();
+            let (mean, stddev) = mean_and_stddev(&self_times);
+            FunctionStats {
+                name: name.clone(),
+                self_times,
+                mean,
+                stddev,
+            }
+        })
+        .collect();
+    functions.sort_by(|a, b| {
+        b.mean
+            .partial_cmp(&a.mean)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    functions
+}
+
+/// Returns the mean and the sample standard deviation.
+fn mean_and_stddev(values: &[f64]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    if values.len() < 2 {
+        return (mean, 0.0);
+    }
+    let variance =
+        values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64;
+    (mean, variance.sqrt())
+}
+
+fn report_text(iterations: &[Iteration], functions: &[FunctionStats]) -> String {
+    let totals: Vec<f64> = iterations.iter().map(|i| i.total_time).collect();
+    let (total_mean, total_stddev) = mean_and_stddev(&totals);
+    let mut s = String::new();
+    let _ = writeln!(
+        s,
+        "Self time across {} iterations, total {total_mean:.1} ms ± {total_stddev:.1} ms per iteration:",
+        iterations.len()
+    );
+    let _ = writeln!(s);
+    let _ = writeln!(
+        s,
+        "{:>10} {:>10} {:>6}  function",
+        "mean ms", "stddev ms", "cv"
+    );
+    for function in functions {
+        let cv = function.coefficient_of_variation();
+        let flag = if function.has_high_variance() {
+            "  [high variance]"
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            s,
+            "{:>10.1} {:>10.1} {:>5.0}%  {}{flag}",
+            function.mean,
+            function.stddev,
+            cv * 100.0,
+            function.name
+        );
+    }
+    s
+}
+
+fn report_json(iterations: &[Iteration], functions: &[FunctionStats]) -> Value {
+    json!({
+        "iterations": iterations.iter().map(|iteration| json!({
+            "pid": iteration.pid,
+            "totalMs": iteration.total_time,
+        })).collect::<Vec<_>>(),
+        "highVarianceThreshold": HIGH_VARIANCE_THRESHOLD,
+        "functions": functions.iter().map(|function| json!({
+            "name": function.name,
+            "selfTimeMs": function.self_times,
+            "meanMs": function.mean,
+            "stddevMs": function.stddev,
+            "coefficientOfVariation": function.coefficient_of_variation(),
+            "highVariance": function.has_high_variance(),
+        })).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{iteration_pids, mean_and_stddev};
+
+    #[test]
+    fn threads_are_grouped_by_launched_process() {
+        let threads = [
+            json!({ "pid": "100" }),
+            json!({ "pid": "100.1" }),
+            json!({ "pid": "101", "parentPid": "100.1" }),
+            json!({ "pid": "102", "parentPid": "101" }),
+            json!({ "pid": "200.1" }),
+            // A recorded warm-up iteration, under the "warmup" group process.
+            json!({ "pid": "300", "parentPid": "0" }),
+            // A process whose parent isn't in the profile.
+            json!({ "pid": "400", "parentPid": "1" }),
+            json!({ "pid": "500" }),
+        ];
+        assert_eq!(
+            iteration_pids(&threads, &[100, 200]),
+            vec![
+                Some("100".to_string()),
+                Some("100".to_string()),
+                Some("100".to_string()),
+                Some("100".to_string()),
+                Some("200".to_string()),
+                None,
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn sample_standard_deviation() {
+        assert_eq!(mean_and_stddev(&[]), (0.0, 0.0));
+        assert_eq!(mean_and_stddev(&[3.0]), (3.0, 0.0));
+        let (mean, stddev) = mean_and_stddev(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+        assert_eq!(mean, 5.0);
+        assert!((stddev - 2.138).abs() < 0.001);
+    }
+}
@@ -0,0 +1,43 @@
+//! The profiler, importer and server code behind the `samply` command line
+//! tool, whose argument parsing lives in main.rs.
+//!
+//! This library is not a stable API. It exists so that the fuzz targets in
+//! `samply/fuzz` can call the importers and file parsers directly.
+
+#[cfg(target_os = "freebsd")]
+pub mod freebsd;
+
+#[cfg(target_os = "macos")]
+pub mod mac;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub mod linux;
+
+pub mod annotate;
+pub mod bench;
+pub mod capture_schedule;
+pub mod cargo_samply;
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos",
+    target_os = "linux"
+))]
+pub mod detach;
+pub mod import;
+pub mod iteration_report;
+pub mod linux_shared;
+pub mod modules;
+pub mod profile_convert;
+pub mod profile_export;
+pub mod profile_json_preparse;
+pub mod profile_symbolication;
+pub mod rustc_wrapper;
+pub mod saved_profiles;
+pub mod server;
+pub mod server_metrics;
+pub mod shared;
+pub mod split_profiles;
+pub mod symbol_upload;
+pub mod symbolication_cache;
+pub mod view_hints;
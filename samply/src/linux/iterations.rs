@@ -0,0 +1,270 @@
+use fxprof_processed_profile::{ProcessHandle, Profile};
+
+use std::process::ExitStatus;
+
+use crate::linux_shared::LaunchedProcessGroups;
+
+/// Keeps track of the runs of the launched command with --iteration-count,
+/// --skip-failed-iterations and --record-warmup, so that the profile can be
+/// adjusted once recording has finished.
+///
+/// Runs are numbered from 1. The first `recorded_warmups` runs are warm-up
+/// iterations, the remaining ones are the measured iterations.
+pub struct Iterations {
+    recorded_warmups: u32,
+    iteration_count: u32,
+    skip_failed: bool,
+    /// The pids of the launched processes of the measured iterations.
+    measured_pids: Vec<u32>,
+    /// The runs which exited with a non-zero status, with --skip-failed-iterations.
+    failed: Vec<FailedRun>,
+    /// Puts the launched processes of the warm-up runs into the "warmup" group.
+    process_groups: LaunchedProcessGroups,
+}
+
+struct FailedRun {
+    label: String,
+    /// The pid of the launched process. Its descendants belong to the run, too.
+    pid: u32,
+    exit_status: ExitStatus,
+}
+
+impl Iterations {
+    pub fn new(recorded_warmups: u32, iteration_count: u32, skip_failed: bool) -> Self {
+        Self {
+            recorded_warmups,
+            iteration_count,
+            skip_failed,
+            measured_pids: Vec::new(),
+            failed: Vec::new(),
+            process_groups: Default::default(),
+        }
+    }
+
+    /// The total number of times the command is launched while recording.
+    pub fn run_count(&self) -> u32 {
+        self.recorded_warmups + self.iteration_count
+    }
+
+    fn is_warmup(&self, run: u32) -> bool {
+        run <= self.recorded_warmups
+    }
+
+    fn label(&self, run: u32) -> String {
+        if self.is_warmup(run) {
+            format!("warm-up iteration {run}")
+        } else {
+            format!("iteration {}", run - self.recorded_warmups)
+        }
+    }
+
+    /// Prints which iteration is about to run.
+    pub fn announce(&self, run: u32) {
+        if self.is_warmup(run) {
+            eprintln!(
+                "Running warm-up iteration {run} of {}...",
+                self.recorded_warmups
+            );
+        } else if run > 1 {
+            let iteration = run - self.recorded_warmups;
+            eprintln!(
+                "Running iteration {iteration} of {}...",
+                self.iteration_count
+            );
+        }
+    }
+
+    /// The groups which the converter should show the launched processes in.
+    pub fn process_groups(&self) -> LaunchedProcessGroups {
+        self.process_groups.clone()
+    }
+
+    /// Must be called before the launched process `pid` starts running.
+    pub fn run_started(&mut self, run: u32, pid: u32) {
+        if self.is_warmup(run) {
+            self.process_groups
+                .lock()
+                .unwrap()
+                .insert(pid as i32, "warmup".to_string());
+        } else {
+            self.measured_pids.push(pid);
+        }
+    }
+
+    /// The pids of the launched processes of the iterations which aren't
+    /// warm-ups, in the order in which they ran.
+    pub fn measured_pids(&self) -> &[u32] {
+        &self.measured_pids
+    }
+
+    /// Returns whether the remaining runs should be launched.
+    pub fn run_finished(&mut self, run: u32, pid: u32, exit_status: ExitStatus) -> bool {
+        if exit_status.success() {
+            return true;
+        }
+        if self.skip_failed {
+            eprintln!(
+                "Leaving out {} due to non-success exit status: \"{exit_status}\"",
+                self.label(run)
+            );
+            self.failed.push(FailedRun {
+                label: self.label(run),
+                pid,
+                exit_status,
+            });
+            return true;
+        }
+        if self.is_warmup(run) {
+            // Warm-up iterations only prepare the measured ones.
+            eprintln!(
+                "Warm-up iteration {run} exited with non-success exit status: \"{exit_status}\""
+            );
+            return true;
+        }
+        if run < self.run_count() {
+            eprintln!(
+                "Skipping remaining iterations due to non-success exit status: \"{exit_status}\""
+            );
+        }
+        false
+    }
+
+    /// Lists the failed runs in the profile. Returns the processes which should
+    /// be written, or `None` if all processes should be written.
+    pub fn apply_to_profile(&self, profile: &mut Profile) -> Option<Vec<ProcessHandle>> {
+        let has_groups = !self.process_groups.lock().unwrap().is_empty();
+        if self.failed.is_empty() && !has_groups {
+            return None;
+        }
+        if !self.failed.is_empty() {
+            let skipped: Vec<String> = self
+                .failed
+                .iter()
+                .map(|failed| format!("{} ({})", failed.label, failed.exit_status))
+                .collect();
+            profile.add_extra_info("Profiler", "Skipped iterations", &skipped.join(", "));
+        }
+        let kept_processes = profile
+            .process_handles()
+            .filter(|&process| match launched_pid(profile, process) {
+                // The group processes have pid 0 and no threads.
+                Some(0) | None => false,
+                Some(pid) => !self.failed.iter().any(|failed| failed.pid == pid),
+            })
+            .collect();
+        Some(kept_processes)
+    }
+}
+
+/// Returns the pid of the launched process which `process` descends from. The
+/// launched processes don't have a parent in the profile, other than the
+/// process of their group.
+fn launched_pid(profile: &Profile, process: ProcessHandle) -> Option<u32> {
+    // Bound the walk in case recycled processes form a cycle.
+    let process_count = profile.process_handles().count();
+    let mut root = process;
+    for _ in 0..process_count {
+        match profile.process_parent(root) {
+            // Stop below the group process, which has pid 0.
+            Some(parent) if profile.process_pid(parent) != "0" => root = parent,
+            _ => break,
+        }
+    }
+    // Recycled pids get a suffix, e.g. "1234.1".
+    let pid = profile.process_pid(root);
+    pid.split('.').next().unwrap_or(pid).parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::process::ExitStatusExt;
+
+    use fxprof_processed_profile::{ReferenceTimestamp, SamplingInterval, Timestamp};
+
+    use super::*;
+
+    fn exit_status(code: i32) -> ExitStatus {
+        ExitStatus::from_raw(code << 8)
+    }
+
+    fn profile_with_processes(pids: &[(u32, Option<u32>)]) -> (Profile, Vec<ProcessHandle>) {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let mut handles: Vec<ProcessHandle> = Vec::new();
+        for &(pid, parent_pid) in pids {
+            let handle =
+                profile.add_process("cmd", pid, Timestamp::from_millis_since_reference(0.0));
+            if let Some(parent_pid) = parent_pid {
+                let parent = handles[pids.iter().position(|p| p.0 == parent_pid).unwrap()];
+                profile.set_process_parent(handle, parent);
+            }
+            handles.push(handle);
+        }
+        (profile, handles)
+    }
+
+    #[test]
+    fn failed_iteration_stops_without_skip() {
+        let mut iterations = Iterations::new(0, 3, false);
+        assert!(iterations.run_finished(1, 100, exit_status(0)));
+        assert!(!iterations.run_finished(2, 200, exit_status(1)));
+    }
+
+    #[test]
+    fn failed_warmup_continues() {
+        let mut iterations = Iterations::new(1, 2, false);
+        assert!(iterations.run_finished(1, 100, exit_status(1)));
+    }
+
+    #[test]
+    fn skip_failed_iterations() {
+        let mut iterations = Iterations::new(0, 3, true);
+        assert!(iterations.run_finished(1, 100, exit_status(0)));
+        assert!(iterations.run_finished(2, 200, exit_status(1)));
+        assert!(iterations.run_finished(3, 300, exit_status(0)));
+
+        // 201 is a child of the failed run, 301 a child of a successful one.
+        let (mut profile, handles) = profile_with_processes(&[
+            (100, None),
+            (200, None),
+            (201, Some(200)),
+            (300, None),
+            (301, Some(300)),
+        ]);
+        let kept = iterations.apply_to_profile(&mut profile).unwrap();
+        assert_eq!(kept, vec![handles[0], handles[3], handles[4]]);
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let extra = json["meta"]["extra"].to_string();
+        assert!(extra.contains("Skipped iterations"));
+        assert!(extra.contains("iteration 2 (exit status: 1)"));
+    }
+
+    #[test]
+    fn nothing_to_filter() {
+        let mut iterations = Iterations::new(0, 2, true);
+        assert!(iterations.run_finished(1, 100, exit_status(0)));
+        assert!(iterations.run_finished(2, 200, exit_status(0)));
+        let (mut profile, _) = profile_with_processes(&[(100, None), (200, None)]);
+        assert_eq!(iterations.apply_to_profile(&mut profile), None);
+    }
+
+    #[test]
+    fn warmup_group_process_is_left_out() {
+        let mut iterations = Iterations::new(1, 1, false);
+        iterations.run_started(1, 100);
+        assert!(iterations.run_finished(1, 100, exit_status(0)));
+        iterations.run_started(2, 200);
+        assert!(iterations.run_finished(2, 200, exit_status(0)));
+
+        // The converter puts the warm-up process under a group process with pid 0.
+        let (mut profile, handles) =
+            profile_with_processes(&[(0, None), (100, Some(0)), (101, Some(100)), (200, None)]);
+        let kept = iterations.apply_to_profile(&mut profile).unwrap();
+        assert_eq!(kept, vec![handles[1], handles[2], handles[3]]);
+        assert_eq!(iterations.measured_pids(), &[200]);
+    }
+}
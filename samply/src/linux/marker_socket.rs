@@ -0,0 +1,189 @@
+use crossbeam_channel::{Receiver, Sender};
+
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use tempfile::TempDir;
+
+/// The environment variable which tells launched processes where to connect
+/// to in order to send markers.
+pub const MARKER_SOCKET_ENV_VAR: &str = "SAMPLY_MARKER_SOCKET";
+
+/// A line which was received on a connection to the marker socket.
+#[derive(Debug, Clone)]
+pub struct MarkerSocketMessage {
+    pub pid: i32,
+    pub tid: i32,
+    pub line: String,
+}
+
+/// A UNIX socket which launched processes can connect to in order to send
+/// markers while they're running, as an alternative to writing a marker file.
+///
+/// The first line on each connection has to be `<pid> <tid>`, which identifies
+/// the thread that subsequent markers are attributed to. All following lines
+/// use the marker file format, see [`crate::shared::marker_file::MarkerFileEntry`].
+/// The markers always go to the process which connected, as reported by the
+/// kernel; the tid is only used if the pid in the header matches it.
+///
+/// The socket is only accessible to the current user. It lives in a private
+/// directory, which is removed when this object is dropped.
+pub struct MarkerSocket {
+    path: PathBuf,
+    _dir: TempDir,
+    receiver: Receiver<MarkerSocketMessage>,
+}
+
+impl MarkerSocket {
+    pub fn bind() -> io::Result<Self> {
+        // Restrict the directory to the current user before creating the socket,
+        // so that no other user can connect before the socket's own permissions
+        // are restricted.
+        let dir = tempfile::Builder::new()
+            .prefix("samply-markers-")
+            .tempdir()?;
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700))?;
+        let path = dir.path().join("markers.sock");
+        let listener = UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let sender = sender.clone();
+                thread::spawn(move || read_connection(stream, sender));
+            }
+        });
+        Ok(Self {
+            path,
+            _dir: dir,
+            receiver,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns all messages which have been received so far, without blocking.
+    pub fn try_iter(&self) -> impl Iterator<Item = MarkerSocketMessage> + '_ {
+        self.receiver.try_iter()
+    }
+}
+
+fn read_connection(stream: UnixStream, sender: Sender<MarkerSocketMessage>) {
+    let pid = match peer_pid(&stream) {
+        Ok(pid) => pid,
+        Err(err) => {
+            eprintln!("Ignoring marker socket connection from an unknown process: {err}");
+            return;
+        }
+    };
+    let mut lines = BufReader::new(stream).lines();
+    let Some(Ok(header)) = lines.next() else {
+        return;
+    };
+    let Some((header_pid, header_tid)) = header.split_once(' ') else {
+        eprintln!("Ignoring marker socket connection with invalid header {header:?}");
+        return;
+    };
+    let (Ok(header_pid), Ok(header_tid)) = (header_pid.parse::<i32>(), header_tid.trim().parse())
+    else {
+        eprintln!("Ignoring marker socket connection with invalid header {header:?}");
+        return;
+    };
+    // A process can't send markers on behalf of other processes. If the header
+    // names a different pid, e.g. because the sender is in another pid
+    // namespace, its tid can't be trusted either, so use the main thread.
+    let tid = if header_pid == pid { header_tid } else { pid };
+    for line in lines {
+        let Ok(line) = line else {
+            break;
+        };
+        if sender.send(MarkerSocketMessage { pid, tid, line }).is_err() {
+            // The recording has finished.
+            break;
+        }
+    }
+}
+
+/// The pid of the process on the other end of `stream`, from `SO_PEERCRED`.
+/// This is the process which connected, and it can't be spoofed.
+fn peer_pid(stream: &UnixStream) -> io::Result<i32> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.pid)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn receive_one(socket: &MarkerSocket) -> MarkerSocketMessage {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(message) = socket.try_iter().next() {
+                return message;
+            }
+            assert!(Instant::now() < deadline, "no message received");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn socket_is_private() {
+        let socket = MarkerSocket::bind().unwrap();
+        let mode = std::fs::metadata(socket.path())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let dir_mode = std::fs::metadata(socket.path().parent().unwrap())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(dir_mode & 0o777, 0o700);
+        let dir = socket.path().parent().unwrap().to_owned();
+        drop(socket);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn pid_comes_from_the_peer() {
+        let socket = MarkerSocket::bind().unwrap();
+        let own_pid = std::process::id() as i32;
+
+        let mut stream = UnixStream::connect(socket.path()).unwrap();
+        writeln!(stream, "{own_pid} 12345\n1 2 Own").unwrap();
+        let message = receive_one(&socket);
+        assert_eq!((message.pid, message.tid), (own_pid, 12345));
+        assert_eq!(message.line, "1 2 Own");
+
+        let mut stream = UnixStream::connect(socket.path()).unwrap();
+        writeln!(stream, "1 1\n1 2 Spoofed").unwrap();
+        let message = receive_one(&socket);
+        assert_eq!((message.pid, message.tid), (own_pid, own_pid));
+        assert_eq!(message.line, "1 2 Spoofed");
+    }
+}
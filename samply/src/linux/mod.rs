@@ -1,3 +1,7 @@
+mod iterations;
+pub(crate) mod marker_socket;
+mod otlp_receiver;
+mod output_capture;
 mod perf_event;
 mod perf_group;
 mod proc_maps;
@@ -5,3 +9,4 @@ mod process;
 pub mod profiler;
 mod sorter;
 mod sys;
+mod trigger;
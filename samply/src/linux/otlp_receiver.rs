@@ -0,0 +1,501 @@
+use crossbeam_channel::{Receiver, Sender};
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::Value;
+use tokio::net::TcpListener;
+
+use std::convert::Infallible;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::MetadataExt;
+use std::sync::Arc;
+use std::thread;
+
+/// Requests with a larger body are rejected. Exporters send spans in batches
+/// of a few hundred, which is far less than this.
+const MAX_REQUEST_SIZE: usize = 16 * 1024 * 1024;
+
+/// A span which was received from the profiled process, with its timestamps
+/// converted to `CLOCK_MONOTONIC` nanoseconds.
+#[derive(Debug, Clone)]
+pub struct OtlpSpan {
+    /// The process which sent the span. The `process.pid` resource attribute
+    /// is only used if it names that process.
+    pub pid: Option<i32>,
+    /// From the `thread.id` span attribute, if the `process.pid` attribute
+    /// names the process which sent the span.
+    pub tid: Option<i32>,
+    pub name: String,
+    pub start_time_ns: u64,
+    pub end_time_ns: u64,
+}
+
+/// An OTLP/HTTP trace receiver, which accepts spans in the JSON encoding on
+/// `/v1/traces`. The protobuf encoding and OTLP/gRPC are not supported.
+///
+/// The receiver listens on the loopback interface, and only accepts
+/// connections from processes of the current user (or of any user if samply
+/// runs as root). The spans are always attributed to the process which
+/// connected, which is found through `/proc`.
+pub struct OtlpReceiver {
+    addr: SocketAddr,
+    receiver: Receiver<OtlpSpan>,
+}
+
+impl OtlpReceiver {
+    pub fn start(port: u16) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()?;
+        let listener =
+            runtime.block_on(TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], port))))?;
+        let addr = listener.local_addr()?;
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        thread::spawn(move || runtime.block_on(run_receiver(listener, sender)));
+        Ok(Self { addr, receiver })
+    }
+
+    /// The value for the `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` environment variable.
+    pub fn traces_endpoint(&self) -> String {
+        format!("http://{}/v1/traces", self.addr)
+    }
+
+    /// Returns all spans which have been received so far, without blocking.
+    pub fn try_iter(&self) -> impl Iterator<Item = OtlpSpan> + '_ {
+        self.receiver.try_iter()
+    }
+}
+
+async fn run_receiver(listener: TcpListener, sender: Sender<OtlpSpan>) {
+    loop {
+        let Ok((stream, peer_addr)) = listener.accept().await else {
+            continue;
+        };
+        let Ok(local_addr) = stream.local_addr() else {
+            continue;
+        };
+        let peer_pids: Arc<[i32]> = match peer_processes(local_addr, peer_addr) {
+            Ok(pids) => pids.into(),
+            Err(err) => {
+                eprintln!("Ignoring OTLP connection from {peer_addr}: {err}");
+                continue;
+            }
+        };
+        let io = TokioIo::new(stream);
+        let sender = sender.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(
+                    io,
+                    service_fn(move |req| otlp_service(req, sender.clone(), peer_pids.clone())),
+                )
+                .await
+            {
+                eprintln!("Error serving OTLP connection: {:?}", err);
+            }
+        });
+    }
+}
+
+/// Handles one request. `peer_pids` are the processes which have the other
+/// end of the connection open, usually just one.
+async fn otlp_service(
+    req: Request<hyper::body::Incoming>,
+    sender: Sender<OtlpSpan>,
+    peer_pids: Arc<[i32]>,
+) -> Result<Response<String>, Infallible> {
+    let mut response = Response::new(String::new());
+    if (req.method(), req.uri().path()) != (&Method::POST, "/v1/traces") {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    let is_json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("application/json"));
+    if !is_json {
+        *response.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        return Ok(response);
+    }
+
+    let body = match Limited::new(req.into_body(), MAX_REQUEST_SIZE)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(err) => {
+            *response.status_mut() = if err.is::<LengthLimitError>() {
+                StatusCode::PAYLOAD_TOO_LARGE
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            return Ok(response);
+        }
+    };
+    let Ok(request) = serde_json::from_slice::<Value>(&body) else {
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        return Ok(response);
+    };
+    let clock_offset_ns = realtime_to_monotonic_offset_ns();
+    for span in parse_export_trace_service_request(&request) {
+        // A process can't send spans on behalf of other processes. If the
+        // span names a different pid, e.g. because the sender is in another
+        // pid namespace, its tid can't be trusted either.
+        let (pid, tid) = match span.pid {
+            Some(pid) if peer_pids.contains(&pid) => (pid, span.tid),
+            _ => (peer_pids[0], None),
+        };
+        let span = OtlpSpan {
+            pid: Some(pid),
+            tid,
+            start_time_ns: span.start_time_ns.saturating_sub(clock_offset_ns),
+            end_time_ns: span.end_time_ns.saturating_sub(clock_offset_ns),
+            ..span
+        };
+        if sender.send(span).is_err() {
+            break;
+        }
+    }
+
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    *response.body_mut() = "{}".to_string();
+    Ok(response)
+}
+
+/// Parses the JSON encoding of an `ExportTraceServiceRequest`. The timestamps
+/// of the returned spans are still in Unix time.
+fn parse_export_trace_service_request(request: &Value) -> Vec<OtlpSpan> {
+    let mut spans = Vec::new();
+    for resource_spans in json_array(request, "resourceSpans") {
+        let resource_attributes = resource_spans
+            .get("resource")
+            .map_or(&[][..], |resource| json_array(resource, "attributes"));
+        let pid = int_attribute(resource_attributes, "process.pid");
+        for scope_spans in json_array(resource_spans, "scopeSpans") {
+            for span in json_array(scope_spans, "spans") {
+                let (Some(start_time_ns), Some(end_time_ns)) = (
+                    json_u64(span.get("startTimeUnixNano")),
+                    json_u64(span.get("endTimeUnixNano")),
+                ) else {
+                    continue;
+                };
+                let name = span.get("name").and_then(Value::as_str).unwrap_or_default();
+                let tid = int_attribute(json_array(span, "attributes"), "thread.id");
+                spans.push(OtlpSpan {
+                    pid,
+                    tid,
+                    name: name.to_string(),
+                    start_time_ns,
+                    end_time_ns,
+                });
+            }
+        }
+    }
+    spans
+}
+
+fn json_array<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value
+        .get(key)
+        .and_then(Value::as_array)
+        .map_or(&[], Vec::as_slice)
+}
+
+/// The JSON encoding of OTLP uses strings for 64-bit integers, but some
+/// exporters send numbers.
+fn json_u64(value: Option<&Value>) -> Option<u64> {
+    match value? {
+        Value::String(s) => s.parse().ok(),
+        value => value.as_u64(),
+    }
+}
+
+fn int_attribute(attributes: &[Value], key: &str) -> Option<i32> {
+    let attribute = attributes
+        .iter()
+        .find(|attribute| attribute.get("key").and_then(Value::as_str) == Some(key))?;
+    let value = json_u64(attribute.get("value")?.get("intValue"))?;
+    i32::try_from(value).ok()
+}
+
+/// The processes which have the other end of a loopback TCP connection open,
+/// found through the socket's inode in `/proc/net/tcp`. Fails if the socket
+/// belongs to another user, unless we're root.
+fn peer_processes(local_addr: SocketAddr, peer_addr: SocketAddr) -> io::Result<Vec<i32>> {
+    let (uid, inode) = std::fs::read_to_string("/proc/net/tcp")?
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10
+                || Some(fields[1]) != proc_net_tcp_address(peer_addr).as_deref()
+                || Some(fields[2]) != proc_net_tcp_address(local_addr).as_deref()
+            {
+                return None;
+            }
+            Some((fields[7].parse::<u32>().ok()?, fields[9].to_owned()))
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the socket was not found"))?;
+    let euid = unsafe { libc::geteuid() };
+    if uid != euid && euid != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("the connection is from user {uid}"),
+        ));
+    }
+
+    let socket_link = format!("socket:[{inode}]");
+    let mut pids = Vec::new();
+    for entry in std::fs::read_dir("/proc")?.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        // Only look at processes which the socket's user owns.
+        if entry
+            .metadata()
+            .map_or(true, |metadata| metadata.uid() != uid)
+        {
+            continue;
+        }
+        let Ok(fds) = std::fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        let owns_socket = fds.flatten().any(|fd| {
+            std::fs::read_link(fd.path())
+                .map_or(false, |target| target.as_os_str() == &*socket_link)
+        });
+        if owns_socket {
+            pids.push(pid);
+        }
+    }
+    if pids.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the process which connected was not found",
+        ));
+    }
+    pids.sort_unstable();
+    Ok(pids)
+}
+
+/// Formats an IPv4 address like the kernel does in `/proc/net/tcp`.
+fn proc_net_tcp_address(addr: SocketAddr) -> Option<String> {
+    let IpAddr::V4(ip) = addr.ip() else {
+        return None;
+    };
+    Some(format!(
+        "{:08X}:{:04X}",
+        u32::from_ne_bytes(ip.octets()),
+        addr.port()
+    ))
+}
+
+/// OTLP timestamps are in Unix time, and the samples are in `CLOCK_MONOTONIC`.
+fn realtime_to_monotonic_offset_ns() -> u64 {
+    fn now_ns(clock_id: libc::clockid_t) -> u64 {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(clock_id, &mut ts) };
+        ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+    }
+    now_ns(libc::CLOCK_REALTIME).saturating_sub(now_ns(libc::CLOCK_MONOTONIC))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::time::{Duration, Instant};
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn request_with_pid(pid: serde_json::Value) -> Value {
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{ "key": "process.pid", "value": { "intValue": pid } }]
+                },
+                "scopeSpans": [{
+                    "spans": [
+                        {
+                            "name": "string timestamps",
+                            "startTimeUnixNano": "1700000000000000000",
+                            "endTimeUnixNano": "1700000000000001000",
+                            "attributes": [{ "key": "thread.id", "value": { "intValue": "12" } }]
+                        },
+                        {
+                            "name": "numeric timestamps",
+                            "startTimeUnixNano": 1700000000000000000u64,
+                            "endTimeUnixNano": 1700000000000002000u64
+                        },
+                        {
+                            "name": "no end time",
+                            "startTimeUnixNano": "1700000000000000000"
+                        }
+                    ]
+                }]
+            }]
+        })
+    }
+
+    #[test]
+    fn parse_spans() {
+        let spans = parse_export_trace_service_request(&request_with_pid(json!("1234")));
+        let spans: Vec<_> = spans
+            .iter()
+            .map(|span| {
+                (
+                    span.pid,
+                    span.tid,
+                    span.name.as_str(),
+                    span.start_time_ns,
+                    span.end_time_ns,
+                )
+            })
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (
+                    Some(1234),
+                    Some(12),
+                    "string timestamps",
+                    1700000000000000000,
+                    1700000000000001000
+                ),
+                (
+                    Some(1234),
+                    None,
+                    "numeric timestamps",
+                    1700000000000000000,
+                    1700000000000002000
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_spans_without_pid() {
+        let mut request = request_with_pid(json!(1));
+        request["resourceSpans"][0]["resource"]["attributes"] = json!([]);
+        let spans = parse_export_trace_service_request(&request);
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().all(|span| span.pid.is_none()));
+
+        request["resourceSpans"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("resource");
+        assert_eq!(parse_export_trace_service_request(&request).len(), 2);
+    }
+
+    #[test]
+    fn json_integers() {
+        assert_eq!(
+            json_u64(Some(&json!("18446744073709551615"))),
+            Some(u64::MAX)
+        );
+        assert_eq!(json_u64(Some(&json!(42))), Some(42));
+        assert_eq!(json_u64(Some(&json!("18446744073709551616"))), None);
+        assert_eq!(json_u64(Some(&json!(-1))), None);
+        assert_eq!(json_u64(Some(&json!("-1"))), None);
+        assert_eq!(json_u64(Some(&json!(1.5))), None);
+        assert_eq!(json_u64(Some(&json!(null))), None);
+        assert_eq!(json_u64(None), None);
+    }
+
+    #[test]
+    fn int_attributes() {
+        let attributes = |value: serde_json::Value| {
+            vec![
+                json!({ "key": "other", "value": { "intValue": 1 } }),
+                json!({ "key": "process.pid", "value": { "intValue": value } }),
+            ]
+        };
+        assert_eq!(int_attribute(&attributes(json!(5)), "process.pid"), Some(5));
+        assert_eq!(
+            int_attribute(&attributes(json!("5")), "process.pid"),
+            Some(5)
+        );
+        assert_eq!(int_attribute(&attributes(json!(5)), "other"), Some(1));
+        assert_eq!(int_attribute(&attributes(json!(5)), "missing"), None);
+        // Out of range for a pid.
+        assert_eq!(
+            int_attribute(&attributes(json!(2147483648u64)), "process.pid"),
+            None
+        );
+        assert_eq!(
+            int_attribute(&attributes(json!("4294967295")), "process.pid"),
+            None
+        );
+        assert_eq!(int_attribute(&attributes(json!(-1)), "process.pid"), None);
+        // A string attribute instead of an int attribute.
+        let string_attribute = [json!({ "key": "process.pid", "value": { "stringValue": "5" } })];
+        assert_eq!(int_attribute(&string_attribute, "process.pid"), None);
+    }
+
+    fn post(receiver: &OtlpReceiver, body: &[u8]) -> String {
+        let mut stream = std::net::TcpStream::connect(receiver.addr).unwrap();
+        write!(
+            stream,
+            "POST /v1/traces HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        // The receiver may reply and close before all of a large body is sent.
+        let _ = stream.write_all(body);
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    }
+
+    #[test]
+    fn spans_are_attributed_to_the_sender() {
+        let receiver = OtlpReceiver::start(0).unwrap();
+        let own_pid = std::process::id() as i32;
+        for pid in [json!(own_pid), json!(1)] {
+            let body = request_with_pid(pid).to_string();
+            let response = post(&receiver, body.as_bytes());
+            assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut spans = Vec::new();
+        while spans.len() < 4 {
+            spans.extend(receiver.try_iter());
+            assert!(Instant::now() < deadline, "not all spans were received");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let pids_and_tids: Vec<_> = spans.iter().map(|span| (span.pid, span.tid)).collect();
+        assert_eq!(
+            pids_and_tids,
+            vec![
+                (Some(own_pid), Some(12)),
+                (Some(own_pid), None),
+                // The span claims to be from pid 1.
+                (Some(own_pid), None),
+                (Some(own_pid), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn large_requests_are_rejected() {
+        let receiver = OtlpReceiver::start(0).unwrap();
+        let response = post(&receiver, &vec![b' '; MAX_REQUEST_SIZE + 1]);
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+    }
+}
@@ -0,0 +1,305 @@
+use crossbeam_channel::{Receiver, Sender};
+use regex::Regex;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::process::{monotonic_now_ns, OutputPipes};
+use crate::linux_shared::OutputStream;
+
+/// A line which a launched process printed to stdout or stderr.
+#[derive(Debug, Clone)]
+pub struct CapturedLine {
+    pub pid: i32,
+    /// In `CLOCK_MONOTONIC` nanoseconds, taken when we read the line.
+    pub time_ns: u64,
+    pub stream: OutputStream,
+    pub text: String,
+    /// The 1-based line number in the tee file of this stream, if the output
+    /// is written to files.
+    pub tee_line: Option<u64>,
+}
+
+/// Which output lines are collected for markers.
+#[derive(Debug, Clone)]
+pub enum MarkerLines {
+    None,
+    All,
+    Matching(Regex),
+}
+
+impl MarkerLines {
+    fn collects(&self, text: &str) -> bool {
+        match self {
+            MarkerLines::None => false,
+            MarkerLines::All => !text.is_empty(),
+            MarkerLines::Matching(regex) => !text.is_empty() && regex.is_match(text),
+        }
+    }
+}
+
+/// Reads the output of launched processes, passes it through to our own
+/// stdout / stderr, optionally writes it to files, and collects the lines so
+/// that they can be turned into markers.
+pub struct OutputCapture {
+    marker_lines: MarkerLines,
+    tee_files: Option<TeeFiles>,
+    sender: Sender<CapturedLine>,
+    receiver: Receiver<CapturedLine>,
+}
+
+impl OutputCapture {
+    /// Returns `None` if there's nothing to do with the output, so that the
+    /// launched processes can keep writing to our stdout / stderr directly.
+    /// Otherwise, all lines are passed through, and are written to `tee_files`
+    /// if given.
+    pub fn new_if_needed(marker_lines: MarkerLines, tee_files: Option<TeeFiles>) -> Option<Self> {
+        if matches!(marker_lines, MarkerLines::None) && tee_files.is_none() {
+            return None;
+        }
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        Some(Self {
+            marker_lines,
+            tee_files,
+            sender,
+            receiver,
+        })
+    }
+
+    /// Start reading the output of the process `pid` on background threads.
+    pub fn capture(&self, pid: u32, pipes: OutputPipes) {
+        self.spawn_reader(pid, pipes.stdout, OutputStream::Stdout);
+        self.spawn_reader(pid, pipes.stderr, OutputStream::Stderr);
+    }
+
+    /// Returns all lines which have been collected so far, without blocking.
+    pub fn try_iter(&self) -> impl Iterator<Item = CapturedLine> + '_ {
+        self.receiver.try_iter()
+    }
+
+    pub fn tee_files(&self) -> Option<&TeeFiles> {
+        self.tee_files.as_ref()
+    }
+
+    fn spawn_reader(&self, pid: u32, fd: OwnedFd, stream: OutputStream) {
+        let marker_lines = self.marker_lines.clone();
+        let tee_file = self.tee_files.as_ref().map(|tee| tee.file(stream).clone());
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(File::from(fd));
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                let time_ns = monotonic_now_ns();
+                let _ = match stream {
+                    OutputStream::Stdout => std::io::stdout().write_all(&line),
+                    OutputStream::Stderr => std::io::stderr().write_all(&line),
+                };
+                let tee_line = match &tee_file {
+                    Some(tee_file) => match tee_file.write_line(&line) {
+                        Ok(tee_line) => tee_line,
+                        Err(err) => {
+                            eprintln!(
+                                "Could not write to {}: {err}. Not writing any more output to it.",
+                                tee_file.path.display()
+                            );
+                            None
+                        }
+                    },
+                    None => None,
+                };
+                let text = String::from_utf8_lossy(&line);
+                let text = text.trim_end_matches(['\n', '\r']);
+                if !marker_lines.collects(text) {
+                    continue;
+                }
+                let captured_line = CapturedLine {
+                    pid: pid as i32,
+                    time_ns,
+                    stream,
+                    text: text.to_string(),
+                    tee_line,
+                };
+                if sender.send(captured_line).is_err() {
+                    // The OutputCapture has been dropped, so nobody collects the
+                    // lines anymore. Keep passing the output through.
+                    continue;
+                }
+            }
+        });
+    }
+}
+
+/// The files which `--tee-output` writes the output of the launched processes
+/// to, one per stream. The output of all launched processes, including all
+/// iterations, goes into the same files.
+pub struct TeeFiles {
+    stdout: Arc<TeeFile>,
+    stderr: Arc<TeeFile>,
+}
+
+impl TeeFiles {
+    /// Creates `<path>.stdout` and `<path>.stderr`, truncating existing files.
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            stdout: Arc::new(TeeFile::create(path_with_suffix(path, ".stdout"))?),
+            stderr: Arc::new(TeeFile::create(path_with_suffix(path, ".stderr"))?),
+        })
+    }
+
+    pub fn path(&self, stream: OutputStream) -> &Path {
+        &self.file(stream).path
+    }
+
+    fn file(&self, stream: OutputStream) -> &Arc<TeeFile> {
+        match stream {
+            OutputStream::Stdout => &self.stdout,
+            OutputStream::Stderr => &self.stderr,
+        }
+    }
+}
+
+struct TeeFile {
+    path: PathBuf,
+    state: Mutex<TeeFileState>,
+}
+
+struct TeeFileState {
+    file: File,
+    /// The number of lines which have been written to the file.
+    line_count: u64,
+    /// Set after a failed write. We stop writing then, so that the line numbers
+    /// of later lines don't refer to the wrong lines.
+    failed: bool,
+}
+
+impl TeeFile {
+    fn create(path: PathBuf) -> std::io::Result<Self> {
+        let file = File::create(&path)?;
+        // Store the absolute path, so that the profile says where to find the
+        // file no matter where it's opened from.
+        let path = path.canonicalize().unwrap_or(path);
+        Ok(Self {
+            path,
+            state: Mutex::new(TeeFileState {
+                file,
+                line_count: 0,
+                failed: false,
+            }),
+        })
+    }
+
+    /// Appends `line` and returns its 1-based line number. Returns `Ok(None)`
+    /// without writing anything if an earlier write has failed.
+    fn write_line(&self, line: &[u8]) -> std::io::Result<Option<u64>> {
+        let mut state = self.state.lock().unwrap();
+        if state.failed {
+            return Ok(None);
+        }
+        if let Err(err) = state.file.write_all(line) {
+            state.failed = true;
+            return Err(err);
+        }
+        state.line_count += 1;
+        Ok(Some(state.line_count))
+    }
+}
+
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn marker_lines() {
+        assert!(!MarkerLines::None.collects("hello"));
+        assert!(MarkerLines::All.collects("hello"));
+        assert!(!MarkerLines::All.collects(""));
+        let matching = MarkerLines::Matching(Regex::new("^error").unwrap());
+        assert!(matching.collects("error: oops"));
+        assert!(!matching.collects("warning: hmm"));
+    }
+
+    #[test]
+    fn capture_is_only_needed_for_markers_or_tee_files() {
+        assert!(OutputCapture::new_if_needed(MarkerLines::None, None).is_none());
+        assert!(OutputCapture::new_if_needed(MarkerLines::All, None).is_some());
+    }
+
+    #[test]
+    fn tee_file_line_numbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let tee_files = TeeFiles::create(&dir.path().join("out")).unwrap();
+        let stdout = tee_files.file(OutputStream::Stdout);
+        assert_eq!(stdout.write_line(b"one\n").unwrap(), Some(1));
+        assert_eq!(stdout.write_line(b"two\n").unwrap(), Some(2));
+        let stderr = tee_files.file(OutputStream::Stderr);
+        assert_eq!(stderr.write_line(b"three\n").unwrap(), Some(1));
+        assert!(tee_files
+            .path(OutputStream::Stdout)
+            .to_string_lossy()
+            .ends_with("out.stdout"));
+        assert_eq!(
+            std::fs::read_to_string(tee_files.path(OutputStream::Stdout)).unwrap(),
+            "one\ntwo\n"
+        );
+    }
+
+    #[test]
+    fn tee_file_write_error() {
+        // Writes to /dev/full fail with ENOSPC.
+        let tee_file = TeeFile::create(PathBuf::from("/dev/full")).unwrap();
+        assert!(tee_file.write_line(b"one\n").is_err());
+        assert_eq!(tee_file.write_line(b"two\n").unwrap(), None);
+    }
+
+    #[test]
+    fn capture_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let tee_files = TeeFiles::create(&dir.path().join("out")).unwrap();
+        let capture = OutputCapture::new_if_needed(
+            MarkerLines::Matching(Regex::new("marker").unwrap()),
+            Some(tee_files),
+        )
+        .unwrap();
+        let (stdout_rp, stdout_sp) = nix::unistd::pipe().unwrap();
+        let (stderr_rp, stderr_sp) = nix::unistd::pipe().unwrap();
+        capture.capture(
+            1234,
+            OutputPipes {
+                stdout: stdout_rp,
+                stderr: stderr_rp,
+            },
+        );
+        let mut stdout = File::from(stdout_sp);
+        stdout
+            .write_all(b"samply test: not collected\nsamply test: marker\n")
+            .unwrap();
+        drop(stdout);
+        drop(stderr_sp);
+
+        let line = capture
+            .receiver
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(line.pid, 1234);
+        assert_eq!(line.stream, OutputStream::Stdout);
+        assert_eq!(line.text, "samply test: marker");
+        assert_eq!(line.tee_line, Some(2));
+    }
+}
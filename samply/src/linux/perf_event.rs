@@ -1,18 +1,13 @@
-use std::cell::RefCell;
 use std::cmp::max;
-use std::collections::BinaryHeap;
 use std::io;
 use std::mem;
-use std::ops::Range;
 use std::os::unix::io::RawFd;
 use std::ptr;
-use std::rc::Rc;
 use std::slice;
 use std::sync::atomic::fence;
 use std::sync::atomic::Ordering;
-use std::{cmp, fmt};
 
-use libc::{self, c_void, pid_t};
+use libc::{self, c_int, c_void, pid_t};
 use linux_perf_data::linux_perf_event_reader;
 use linux_perf_event_reader::{Endianness, RawData, RawEventRecord, RecordParseInfo, RecordType};
 
@@ -26,43 +21,6 @@ struct PerfEventHeader {
     size: u16,
 }
 
-#[derive(Clone, Debug)]
-enum SliceLocation {
-    Single(Range<usize>),
-    Split(Range<usize>, Range<usize>),
-}
-
-impl SliceLocation {
-    #[inline]
-    fn get<'a>(&self, buffer: &'a [u8]) -> RawData<'a> {
-        match *self {
-            SliceLocation::Single(ref range) => RawData::Single(&buffer[range.clone()]),
-            SliceLocation::Split(ref left, ref right) => {
-                RawData::Split(&buffer[left.clone()], &buffer[right.clone()])
-            }
-        }
-    }
-}
-
-#[derive(Clone, Debug)]
-struct RawRecordLocation {
-    kind: u32,
-    misc: u16,
-    data_location: SliceLocation,
-}
-
-impl RawRecordLocation {
-    #[inline]
-    fn get<'a>(&self, buffer: &'a [u8], parse_info: RecordParseInfo) -> RawEventRecord<'a> {
-        RawEventRecord {
-            record_type: RecordType(self.kind),
-            misc: self.misc,
-            data: self.data_location.get(buffer),
-            parse_info,
-        }
-    }
-}
-
 unsafe fn read_head(pointer: *const u8) -> u64 {
     let page = &*(pointer as *const PerfEventMmapPage);
     let head = ptr::read_volatile(&page.data_head);
@@ -83,81 +41,169 @@ unsafe fn write_tail(pointer: *mut u8, value: u64) {
     ptr::write_volatile(&mut page.data_tail, value);
 }
 
+/// The mmapped ring buffer of an event. Other events on the same CPU can write
+/// into it too, see [`Perf::set_output`].
 #[derive(Debug)]
-pub struct Perf {
-    event_ref_state: Rc<RefCell<EventRefState>>,
-    buffer: *mut u8,
+struct RingBuffer {
+    /// Points to the metadata page, which is followed by `size` bytes of data.
+    pointer: *mut u8,
     size: u64,
+    /// In overwrite mode, the kernel writes backward and replaces the oldest
+    /// records once the buffer is full, instead of dropping new ones.
+    overwrite: bool,
+    /// For overwrite buffers, the head at the time of the previous read, so
+    /// that the same records aren't read twice.
+    last_read_head: Option<u64>,
+}
+
+impl RingBuffer {
+    fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.pointer.offset(4096), self.size as usize) }
+    }
+
+    fn copy_range(&self, position: u64, len: u64, out: &mut Vec<u8>) {
+        let data = self.data();
+        let start = (position % self.size) as usize;
+        let len = len as usize;
+        let first_len = len.min(data.len() - start);
+        out.extend_from_slice(&data[start..start + first_len]);
+        out.extend_from_slice(&data[..len - first_len]);
+    }
+
+    fn header_at(&self, position: u64) -> PerfEventHeader {
+        // Records are 8-byte aligned and the size is a power of two, so the
+        // header never wraps around the end of the buffer.
+        let offset = (position % self.size) as usize;
+        let header = &self.data()[offset..offset + mem::size_of::<PerfEventHeader>()];
+        unsafe { ptr::read_unaligned(header.as_ptr() as *const PerfEventHeader) }
+    }
+
+    /// Copies the records between the tail and the head, and advances the tail
+    /// so that the kernel can reuse the space.
+    fn read_pending(&mut self, out: &mut Vec<u8>) {
+        let head = unsafe { read_head(self.pointer) };
+        let tail = unsafe { read_tail(self.pointer) };
+        self.copy_range(tail, head.wrapping_sub(tail), out);
+        unsafe {
+            write_tail(self.pointer, head);
+        }
+    }
+
+    /// Copies the records which the kernel has written since the last read.
+    /// The output of the event needs to be paused while this runs.
+    fn read_overwritten(&mut self, out: &mut Vec<u8>) {
+        // The kernel writes backward, so the newest record starts at the head
+        // and older records follow it. The oldest record may have been partly
+        // overwritten by the newest one, so we stop before it.
+        let head = unsafe { read_head(self.pointer) };
+        let limit = match self.last_read_head {
+            Some(last_read_head) => last_read_head.wrapping_sub(head).min(self.size),
+            None => self.size,
+        };
+        let header_size = mem::size_of::<PerfEventHeader>() as u64;
+        let mut len = 0;
+        while len + header_size <= limit {
+            let record_size = self.header_at(head.wrapping_add(len)).size as u64;
+            if record_size == 0 || len + record_size > limit {
+                break;
+            }
+            len += record_size;
+        }
+        self.copy_range(head, len, out);
+        self.last_read_head = Some(head);
+    }
+}
+
+impl Drop for RingBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.pointer as *mut c_void, (self.size + 4096) as _);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Perf {
     fd: RawFd,
-    position: u64,
+    ring_buffer: Option<RingBuffer>,
+    ring_buffer_page_count: u32,
+    overwrite: bool,
+    sample_type: u64,
     parse_info: RecordParseInfo,
 }
 
+// The ring buffer mapping is only accessed through `&mut self`, so a `Perf` can
+// be moved to the thread which drains the ring buffers.
+unsafe impl Send for Perf {}
+
 impl Drop for Perf {
     fn drop(&mut self) {
+        self.ring_buffer = None;
         unsafe {
             libc::close(self.fd);
         }
     }
 }
 
-#[inline]
-unsafe fn get_buffer<'a>(buffer: *const u8, size: u64) -> &'a [u8] {
-    slice::from_raw_parts(buffer.offset(4096), size as usize)
+/// Records which were copied out of a ring buffer. Copying them frees up the
+/// space in the ring buffer right away, so the kernel doesn't have to drop new
+/// records while the copied ones are being processed.
+pub struct RecordBatch {
+    data: Vec<u8>,
+    parse_info: RecordParseInfo,
 }
 
-fn next_raw_event(
-    buffer: *const u8,
-    size: u64,
-    position_cell: &mut u64,
-) -> Option<RawRecordLocation> {
-    let head = unsafe { read_head(buffer) };
-    if head == *position_cell {
-        return None;
-    }
-
-    let buffer = unsafe { get_buffer(buffer, size) };
-    let position = *position_cell;
-    let relative_position = position % size;
-    let event_position = relative_position as usize;
-    let event_data_position =
-        (relative_position + mem::size_of::<PerfEventHeader>() as u64) as usize;
-    let event_header = unsafe {
-        &*(&buffer[event_position..event_data_position] as *const _ as *const PerfEventHeader)
-    };
-    let next_event_position = event_position + event_header.size as usize;
-
-    let data_location = if next_event_position > size as usize {
-        let first = event_data_position..buffer.len();
-        let second = 0..next_event_position % size as usize;
-        SliceLocation::Split(first, second)
-    } else {
-        SliceLocation::Single(event_data_position..next_event_position)
-    };
-
-    let raw_event_location = RawRecordLocation {
-        kind: event_header.kind,
-        misc: event_header.misc,
-        data_location,
-    };
-
-    // trace!("Parsed raw event: {:?}", raw_event_location);
-
-    let next_position = position + event_header.size as u64;
-    *position_cell = next_position;
-
-    Some(raw_event_location)
+impl RecordBatch {
+    fn header_at(&self, offset: usize) -> Option<PerfEventHeader> {
+        let header_size = mem::size_of::<PerfEventHeader>();
+        let bytes = self.data.get(offset..offset + header_size)?;
+        let header = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const PerfEventHeader) };
+        if (header.size as usize) < header_size || offset + header.size as usize > self.data.len() {
+            return None;
+        }
+        Some(header)
+    }
+
+    /// The offsets of the records in this batch, for use with [`RecordBatch::record_at`].
+    pub fn record_offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            let header = self.header_at(offset)?;
+            let record_offset = offset;
+            offset += header.size as usize;
+            Some(record_offset)
+        })
+    }
+
+    pub fn record_at(&self, offset: usize) -> RawEventRecord<'_> {
+        let header = self
+            .header_at(offset)
+            .expect("should be the offset of a record in this batch");
+        let data_offset = offset + mem::size_of::<PerfEventHeader>();
+        RawEventRecord {
+            record_type: RecordType(header.kind),
+            misc: header.misc,
+            data: RawData::Single(&self.data[data_offset..offset + header.size as usize]),
+            parse_info: self.parse_info,
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum EventSource {
     HwCpuCycles,
     SwCpuClock,
+    /// A tracepoint, identified by the id in /sys/kernel/tracing/events/<category>/<name>/id.
+    /// Every hit is sampled, and the sample contains the raw tracepoint data.
+    Tracepoint(u64),
+    /// An event which never produces samples. It's used to get the records
+    /// about processes, threads and mappings into a separate ring buffer.
+    Dummy,
 }
 
 #[derive(Clone, Debug)]
 pub struct PerfBuilder {
-    pid: u32,
+    pid: Option<u32>,
     cpu: Option<u32>,
     frequency: u64,
     stack_size: u32,
@@ -168,11 +214,20 @@ pub struct PerfBuilder {
     enable_on_exec: bool,
     exclude_kernel: bool,
     gather_context_switches: bool,
+    process_tracking: bool,
+    overwrite: bool,
+    ring_buffer_size: Option<u32>,
 }
 
 impl PerfBuilder {
     pub fn pid(mut self, pid: u32) -> Self {
-        self.pid = pid;
+        self.pid = Some(pid);
+        self
+    }
+
+    /// Observe all processes. This needs to be combined with `only_cpu`.
+    pub fn any_pid(mut self) -> Self {
+        self.pid = None;
         self
     }
 
@@ -232,8 +287,29 @@ impl PerfBuilder {
         self
     }
 
+    /// Don't emit records about processes, threads, mappings and context switches.
+    pub fn without_process_tracking(mut self) -> Self {
+        self.process_tracking = false;
+        self.gather_context_switches = false;
+        self
+    }
+
+    /// Let the kernel overwrite the oldest records once the ring buffer is full.
+    /// The ring buffer is then read with [`Perf::read_overwritten`].
+    pub fn overwrite(mut self) -> Self {
+        self.overwrite = true;
+        self
+    }
+
+    /// The minimum size of the ring buffer in bytes. By default, it's big enough
+    /// for 32 stack samples.
+    pub fn ring_buffer_size(mut self, size: u32) -> Self {
+        self.ring_buffer_size = Some(size);
+        self
+    }
+
     pub fn open(self) -> io::Result<Perf> {
-        let pid = self.pid;
+        let pid = self.pid.map_or(-1, |pid| pid as pid_t);
         let cpu = self.cpu.map(|cpu| cpu as i32).unwrap_or(-1);
         let frequency = self.frequency;
         let stack_size = self.stack_size;
@@ -300,7 +376,17 @@ impl PerfBuilder {
                 attr.kind = PERF_TYPE_SOFTWARE;
                 attr.config = PERF_COUNT_SW_CPU_CLOCK;
             }
+            EventSource::Tracepoint(id) => {
+                attr.kind = PERF_TYPE_TRACEPOINT;
+                attr.config = id;
+            }
+            EventSource::Dummy => {
+                attr.kind = PERF_TYPE_SOFTWARE;
+                attr.config = PERF_COUNT_SW_DUMMY;
+            }
         }
+        let is_tracepoint = matches!(event_source, EventSource::Tracepoint(_));
+        let is_dummy = event_source == EventSource::Dummy;
 
         attr.sample_type = PERF_SAMPLE_IP
             | PERF_SAMPLE_TID
@@ -316,20 +402,36 @@ impl PerfBuilder {
             attr.sample_type |= PERF_SAMPLE_STACK_USER;
         }
 
+        if is_tracepoint {
+            attr.sample_type |= PERF_SAMPLE_RAW;
+        }
+
         attr.sample_regs_user = reg_mask;
         attr.sample_stack_user = stack_size;
-        attr.sample_period_or_freq = frequency;
+        attr.sample_period_or_freq = if is_tracepoint || is_dummy {
+            1
+        } else {
+            frequency
+        };
         attr.clock_id = libc::CLOCK_MONOTONIC;
 
-        attr.flags = PERF_ATTR_FLAG_DISABLED
-            | PERF_ATTR_FLAG_MMAP
-            | PERF_ATTR_FLAG_MMAP2
-            | PERF_ATTR_FLAG_MMAP_DATA
-            | PERF_ATTR_FLAG_COMM
-            | PERF_ATTR_FLAG_FREQ
-            | PERF_ATTR_FLAG_TASK
-            | PERF_ATTR_FLAG_SAMPLE_ID_ALL
-            | PERF_ATTR_FLAG_USE_CLOCKID;
+        attr.flags =
+            PERF_ATTR_FLAG_DISABLED | PERF_ATTR_FLAG_SAMPLE_ID_ALL | PERF_ATTR_FLAG_USE_CLOCKID;
+
+        if !is_tracepoint && !is_dummy {
+            attr.flags |= PERF_ATTR_FLAG_FREQ;
+        }
+
+        if !is_tracepoint && self.process_tracking {
+            // Tracepoints are only used for auxiliary events. The process-related
+            // records come from the main sampling events, or from dummy events
+            // if the sampling events don't track processes.
+            attr.flags |= PERF_ATTR_FLAG_MMAP
+                | PERF_ATTR_FLAG_MMAP2
+                | PERF_ATTR_FLAG_MMAP_DATA
+                | PERF_ATTR_FLAG_COMM
+                | PERF_ATTR_FLAG_TASK;
+        }
 
         if self.enable_on_exec {
             attr.flags |= PERF_ATTR_FLAG_ENABLE_ON_EXEC;
@@ -347,7 +449,25 @@ impl PerfBuilder {
             attr.flags |= PERF_ATTR_FLAG_CONTEX_SWITCH;
         }
 
-        let fd = sys_perf_event_open(&attr, pid as pid_t, cpu as _, -1, PERF_FLAG_FD_CLOEXEC);
+        const STACK_COUNT_PER_BUFFER: u32 = 32;
+        let required_space = self
+            .ring_buffer_size
+            .unwrap_or(max(stack_size, 4096) * STACK_COUNT_PER_BUFFER);
+        let n = (1..26)
+            .find(|n| (1_u32 << n) * 4096_u32 >= required_space)
+            .expect("cannot find appropriate page count for given stack size");
+        let page_count: u32 = max(1 << n, 16);
+
+        if self.overwrite {
+            attr.flags |= PERF_ATTR_FLAG_WRITE_BACKWARD;
+        } else {
+            // Wake up the reader once a quarter of the buffer is filled, rather
+            // than at the default of half, to leave more room for bursts.
+            attr.flags |= PERF_ATTR_FLAG_WATERMARK;
+            attr.wakeup_events_or_watermark = page_count * 4096 / 4;
+        }
+
+        let fd = sys_perf_event_open(&attr, pid, cpu as _, -1, PERF_FLAG_FD_CLOEXEC);
         if fd < 0 {
             let err = io::Error::from_raw_os_error(-fd);
             // eprintln!(
@@ -363,39 +483,6 @@ impl PerfBuilder {
             return Err(err);
         }
 
-        const STACK_COUNT_PER_BUFFER: u32 = 32;
-        let required_space = max(stack_size, 4096) * STACK_COUNT_PER_BUFFER;
-        let page_size = 4096;
-        let n = (1..26)
-            .find(|n| (1_u32 << n) * 4096_u32 >= required_space)
-            .expect("cannot find appropriate page count for given stack size");
-        let page_count: u32 = max(1 << n, 16);
-        // debug!(
-        //     "Allocating {} + 1 pages for the ring buffer for PID {} on CPU {}",
-        //     page_count, pid, cpu
-        // );
-
-        let full_size = (page_size * (page_count + 1)) as usize;
-
-        let buffer;
-        unsafe {
-            buffer = libc::mmap(
-                ptr::null_mut(),
-                full_size,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_SHARED,
-                fd,
-                0,
-            );
-            if buffer == libc::MAP_FAILED {
-                libc::close(fd);
-                return Err(io::Error::new(io::ErrorKind::Other, "mmap failed"));
-            }
-        }
-
-        let buffer = buffer as *mut u8;
-        let size = (page_size * page_count) as u64;
-
         let attr_bytes_ptr = &attr as *const PerfEventAttr as *const u8;
         let attr_bytes_len = mem::size_of::<PerfEventAttr>();
         let attr_bytes = unsafe { slice::from_raw_parts(attr_bytes_ptr, attr_bytes_len) };
@@ -406,11 +493,11 @@ impl PerfBuilder {
 
         // debug!("Perf events open with fd={}", fd);
         let mut perf = Perf {
-            event_ref_state: Rc::new(RefCell::new(EventRefState::new(buffer, size))),
-            buffer,
-            size,
             fd,
-            position: 0,
+            ring_buffer: None,
+            ring_buffer_page_count: page_count,
+            overwrite: self.overwrite,
+            sample_type: attr.sample_type,
             parse_info,
         };
 
@@ -430,7 +517,7 @@ impl Perf {
 
     pub fn build() -> PerfBuilder {
         PerfBuilder {
-            pid: 0,
+            pid: Some(0),
             cpu: None,
             frequency: 0,
             stack_size: 0,
@@ -441,6 +528,9 @@ impl Perf {
             enable_on_exec: false,
             exclude_kernel: true,
             gather_context_switches: false,
+            process_tracking: true,
+            overwrite: false,
+            ring_buffer_size: None,
         }
     }
 
@@ -450,141 +540,253 @@ impl Perf {
         assert!(result != -1);
     }
 
-    #[inline]
-    pub fn are_events_pending(&self) -> bool {
-        let head = unsafe { read_head(self.buffer) };
-        head != self.position
+    /// Maps the ring buffer of this event.
+    pub fn map_ring_buffer(&mut self) -> io::Result<()> {
+        let page_size = 4096;
+        let full_size = (page_size * (self.ring_buffer_page_count + 1)) as usize;
+        // Overwrite buffers are mapped read-only, which tells the kernel that
+        // we won't update the tail.
+        let protection = if self.overwrite {
+            libc::PROT_READ
+        } else {
+            libc::PROT_READ | libc::PROT_WRITE
+        };
+
+        let buffer = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                full_size,
+                protection,
+                libc::MAP_SHARED,
+                self.fd,
+                0,
+            )
+        };
+        if buffer == libc::MAP_FAILED {
+            return Err(io::Error::new(io::ErrorKind::Other, "mmap failed"));
+        }
+
+        self.ring_buffer = Some(RingBuffer {
+            pointer: buffer as *mut u8,
+            size: (page_size * self.ring_buffer_page_count) as u64,
+            overwrite: self.overwrite,
+            last_read_head: None,
+        });
+        Ok(())
     }
 
-    #[inline]
-    pub fn fd(&self) -> RawFd {
-        self.fd
+    /// Makes this event write its records into the ring buffer of `target`,
+    /// instead of into its own. Both events need to be on the same CPU.
+    pub fn set_output(&mut self, target: &Perf) -> io::Result<()> {
+        let result =
+            unsafe { libc::ioctl(self.fd, PERF_EVENT_IOC_SET_OUTPUT as _, target.fd as c_int) };
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
     }
 
     #[inline]
-    pub fn iter(&mut self) -> EventIter {
-        EventIter::new(self)
+    pub fn is_overwrite(&self) -> bool {
+        self.overwrite
     }
-}
 
-#[derive(Debug)]
-struct EventRefState {
-    buffer: *mut u8,
-    size: u64,
-    pending_commits: BinaryHeap<cmp::Reverse<(u64, u64)>>,
-}
+    /// The `PERF_SAMPLE_*` flags of this event. Records in a ring buffer are
+    /// parsed with the sample type of the buffer's owner, so only events with
+    /// the same sample type can share a ring buffer.
+    #[inline]
+    pub fn sample_type(&self) -> u64 {
+        self.sample_type
+    }
 
-impl EventRefState {
-    fn new(buffer: *mut u8, size: u64) -> Self {
-        EventRefState {
-            buffer,
-            size,
-            pending_commits: BinaryHeap::new(),
+    /// Whether this event has its own regular ring buffer with unread records.
+    #[inline]
+    pub fn are_events_pending(&self) -> bool {
+        match &self.ring_buffer {
+            Some(ring_buffer) if !ring_buffer.overwrite => {
+                let head = unsafe { read_head(ring_buffer.pointer) };
+                let tail = unsafe { read_tail(ring_buffer.pointer) };
+                head != tail
+            }
+            _ => false,
         }
     }
 
-    /// Mark the read of [from, to) as complete.
-    /// If reads are completed in-order, then this will advance the tail pointer to `to` immediately.
-    /// Otherwise, it will remain in the "pending commit" queue, and committed once all previous
-    /// reads are also committed.
-    fn try_commit(&mut self, from: u64, to: u64) {
-        self.pending_commits.push(cmp::Reverse((from, to)));
+    #[inline]
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
 
-        let mut position = unsafe { read_tail(self.buffer) };
-        while let Some(&cmp::Reverse((from, to))) = self.pending_commits.peek() {
-            if from == position {
-                unsafe {
-                    write_tail(self.buffer, to);
-                }
-                position = to;
-                self.pending_commits.pop();
-            } else {
-                break;
-            }
+    /// Copies the unread records out of this event's regular ring buffer.
+    pub fn read_pending(&mut self) -> Option<RecordBatch> {
+        if !self.are_events_pending() {
+            return None;
         }
+        let mut data = Vec::new();
+        self.ring_buffer.as_mut()?.read_pending(&mut data);
+        Some(RecordBatch {
+            data,
+            parse_info: self.parse_info,
+        })
     }
-}
 
-impl Drop for EventRefState {
-    fn drop(&mut self) {
+    /// Copies the records which are still in this event's overwrite ring buffer
+    /// and which haven't been read before.
+    pub fn read_overwritten(&mut self) -> Option<RecordBatch> {
+        let ring_buffer = self.ring_buffer.as_mut().filter(|rb| rb.overwrite)?;
+        let mut data = Vec::new();
         unsafe {
-            libc::munmap(self.buffer as *mut c_void, (self.size + 4096) as _);
+            libc::ioctl(self.fd, PERF_EVENT_IOC_PAUSE_OUTPUT as _, 1 as c_int);
         }
+        ring_buffer.read_overwritten(&mut data);
+        unsafe {
+            libc::ioctl(self.fd, PERF_EVENT_IOC_PAUSE_OUTPUT as _, 0 as c_int);
+        }
+        if data.is_empty() {
+            return None;
+        }
+        Some(RecordBatch {
+            data,
+            parse_info: self.parse_info,
+        })
     }
 }
 
-/// Handle to a single event in the perf ring buffer.
-///
-/// On Drop, the event will be "consumed" and the read pointer will be advanced.
-///
-/// If events are dropped out of order, then it will be added to a list of pending commits and
-/// committed when all prior events are also dropped. For this reason, events should be dropped
-/// in-order to achieve the lowest overhead.
-#[derive(Clone)]
-pub struct EventRef {
-    buffer: *mut u8,
-    buffer_size: usize,
-    state: Rc<RefCell<EventRefState>>,
-    event_location: RawRecordLocation,
-    prev_position: u64,
-    position: u64,
-    parse_info: RecordParseInfo,
-}
+#[cfg(test)]
+pub(super) mod test {
+    use super::*;
 
-impl fmt::Debug for EventRef {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        fmt.debug_map()
-            .entry(&"location", &self.event_location)
-            .entry(&"prev_position", &self.prev_position)
-            .entry(&"position", &self.position)
-            .finish()
+    /// The parse info for sample records which only contain the time.
+    fn time_only_parse_info() -> RecordParseInfo {
+        let mut attr: PerfEventAttr = unsafe { mem::zeroed() };
+        attr.size = mem::size_of::<PerfEventAttr>() as u32;
+        attr.sample_type = PERF_SAMPLE_TIME;
+        let attr_bytes = unsafe {
+            slice::from_raw_parts(
+                &attr as *const PerfEventAttr as *const u8,
+                mem::size_of::<PerfEventAttr>(),
+            )
+        };
+        let (attr, _size) =
+            linux_perf_event_reader::PerfEventAttr::parse::<_, byteorder::NativeEndian>(attr_bytes)
+                .unwrap();
+        RecordParseInfo::new(&attr, Endianness::NATIVE)
     }
-}
 
-impl Drop for EventRef {
-    #[inline]
-    fn drop(&mut self) {
-        self.state
-            .borrow_mut()
-            .try_commit(self.prev_position, self.position);
+    /// A sample record with the given time, 16 bytes long.
+    fn sample_record(timestamp: u64) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&PERF_RECORD_SAMPLE.to_ne_bytes());
+        record.extend_from_slice(&0u16.to_ne_bytes());
+        record.extend_from_slice(&16u16.to_ne_bytes());
+        record.extend_from_slice(&timestamp.to_ne_bytes());
+        record
     }
-}
 
-impl EventRef {
-    pub fn get(&self) -> RawEventRecord<'_> {
-        let buffer = unsafe { slice::from_raw_parts(self.buffer.offset(4096), self.buffer_size) };
+    /// A batch of sample records with the given times.
+    pub fn sample_batch(timestamps: &[u64]) -> RecordBatch {
+        RecordBatch {
+            data: timestamps.iter().flat_map(|t| sample_record(*t)).collect(),
+            parse_info: time_only_parse_info(),
+        }
+    }
 
-        self.event_location.get(buffer, self.parse_info)
+    fn batch_timestamps(batch: &RecordBatch) -> Vec<u64> {
+        batch
+            .record_offsets()
+            .map(|offset| match batch.record_at(offset).data {
+                RawData::Single(data) => u64::from_ne_bytes(data.try_into().unwrap()),
+                RawData::Split(..) => panic!("batches are contiguous"),
+            })
+            .collect()
     }
-}
 
-pub struct EventIter<'a> {
-    perf: &'a mut Perf,
-}
+    #[test]
+    fn record_offsets() {
+        let mut batch = sample_batch(&[10, 20, 30]);
+        assert_eq!(batch.record_offsets().collect::<Vec<_>>(), vec![0, 16, 32]);
+        assert_eq!(batch_timestamps(&batch), vec![10, 20, 30]);
+
+        // A truncated record at the end, and a header with an invalid size,
+        // end the iteration.
+        batch.data.truncate(40);
+        assert_eq!(batch.record_offsets().collect::<Vec<_>>(), vec![0, 16]);
+        batch.data[16 + 6] = 4;
+        assert_eq!(batch.record_offsets().collect::<Vec<_>>(), vec![0]);
+    }
 
-impl<'a> EventIter<'a> {
-    #[inline]
-    fn new(perf: &'a mut Perf) -> Self {
-        EventIter { perf }
+    /// An anonymous mapping which looks like an overwrite ring buffer with
+    /// `size` bytes of data.
+    fn overwrite_ring_buffer(size: u64) -> RingBuffer {
+        let pointer = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                (size + 4096) as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(pointer, libc::MAP_FAILED);
+        RingBuffer {
+            pointer: pointer as *mut u8,
+            size,
+            overwrite: true,
+            last_read_head: None,
+        }
     }
-}
 
-impl<'a> Iterator for EventIter<'a> {
-    type Item = EventRef;
+    /// Writes a record backward, like the kernel does in overwrite mode.
+    fn write_backward(ring_buffer: &mut RingBuffer, record: &[u8]) {
+        let page = unsafe { &mut *(ring_buffer.pointer as *mut PerfEventMmapPage) };
+        let head = page.data_head.wrapping_sub(record.len() as u64);
+        let data = unsafe {
+            slice::from_raw_parts_mut(ring_buffer.pointer.add(4096), ring_buffer.size as usize)
+        };
+        for (i, byte) in record.iter().enumerate() {
+            data[(head.wrapping_add(i as u64) % ring_buffer.size) as usize] = *byte;
+        }
+        page.data_head = head;
+    }
 
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
-        let perf = &mut self.perf;
-        let prev_position = perf.position;
-        let event_location = next_raw_event(perf.buffer, perf.size, &mut perf.position)?;
-        Some(EventRef {
-            buffer: perf.buffer,
-            buffer_size: perf.size as usize,
-            state: perf.event_ref_state.clone(),
-            event_location,
-            prev_position,
-            position: perf.position,
-            parse_info: self.perf.parse_info,
+    fn read_overwritten_timestamps(ring_buffer: &mut RingBuffer) -> Vec<u64> {
+        let mut data = Vec::new();
+        ring_buffer.read_overwritten(&mut data);
+        batch_timestamps(&RecordBatch {
+            data,
+            parse_info: time_only_parse_info(),
         })
     }
+
+    #[test]
+    fn read_overwritten() {
+        let mut ring_buffer = overwrite_ring_buffer(64);
+        write_backward(&mut ring_buffer, &sample_record(1));
+        write_backward(&mut ring_buffer, &sample_record(2));
+        // Newest first, and the empty rest of the buffer is not a record.
+        assert_eq!(read_overwritten_timestamps(&mut ring_buffer), vec![2, 1]);
+        assert!(read_overwritten_timestamps(&mut ring_buffer).is_empty());
+
+        // Only the records since the last read are returned.
+        write_backward(&mut ring_buffer, &sample_record(3));
+        assert_eq!(read_overwritten_timestamps(&mut ring_buffer), vec![3]);
+
+        // This record wraps around and overwrites the second half of the
+        // first record.
+        let mut long_record = sample_record(4);
+        long_record[6] = 24;
+        long_record.extend_from_slice(&[0; 8]);
+        write_backward(&mut ring_buffer, &long_record);
+        let mut data = Vec::new();
+        ring_buffer.read_overwritten(&mut data);
+        assert_eq!(data, long_record);
+
+        // When reading everything, the partly overwritten record is left out.
+        ring_buffer.last_read_head = None;
+        let mut data = Vec::new();
+        ring_buffer.read_overwritten(&mut data);
+        assert_eq!(data.len(), 24 + 16 + 16);
+        assert_eq!(data[..24], long_record[..]);
+    }
 }
@@ -1,16 +1,16 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
-use std::ops::{Deref, DerefMut};
 use std::os::unix::io::RawFd;
+use std::rc::Rc;
 use std::time::Duration;
 use std::{fs, io};
 
 use byteorder::LittleEndian;
-use linux_perf_data::linux_perf_event_reader::get_record_timestamp;
+use linux_perf_data::linux_perf_event_reader::{get_record_timestamp, RawEventRecord};
 use mio::unix::SourceFd;
 use mio::{Events, Interest, Poll, Token};
 
-use super::perf_event::{EventRef, EventSource, Perf};
+use super::perf_event::{EventSource, Perf, RecordBatch};
 use super::sorter::EventSorter;
 
 struct StoppedProcess(u32);
@@ -38,41 +38,46 @@ impl Drop for StoppedProcess {
 
 struct Member {
     perf: Perf,
+    /// The fd of the member whose ring buffer this member writes into. This is
+    /// the member's own fd if it has its own ring buffer.
+    ring_fd: RawFd,
     is_closed: bool,
+    /// Auxiliary members are tracepoints. They don't keep the group alive:
+    /// the system-wide ones never close, and the ones for the profiled
+    /// processes close together with the main events.
+    is_auxiliary: bool,
 }
 
-impl Member {
-    fn new(perf: Perf) -> Self {
-        Member {
-            perf,
-            is_closed: false,
-        }
-    }
-}
-
-impl Deref for Member {
-    type Target = Perf;
-    fn deref(&self) -> &Self::Target {
-        &self.perf
-    }
-}
-
-impl DerefMut for Member {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.perf
-    }
+/// How the ring buffers of a [`PerfGroup`] are set up.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RingBufferConfig {
+    /// The minimum size of each ring buffer in bytes, if it should differ
+    /// from the default.
+    pub size: Option<u32>,
+    /// Put the samples into ring buffers which the kernel overwrites when
+    /// they're full, and only read them once profiling stops. This keeps the
+    /// most recent samples, like a flight recorder.
+    pub overwrite: bool,
 }
 
 pub struct PerfGroup {
-    event_sorter: EventSorter<RawFd, u64, EventRef>,
     members: BTreeMap<RawFd, Member>,
+    /// The ring buffers which all events on a CPU share, keyed by the CPU, by
+    /// whether it's an overwrite buffer and by the sample type of the events.
+    /// The values are the fds of the members which own the ring buffers.
+    cpu_rings: HashMap<(u32, bool, u64), RawFd>,
     poll: Poll,
     poll_events: Events,
     frequency: u32,
     stack_size: u32,
     regs_mask: u64,
     event_source: EventSource,
+    ring_buffer: RingBufferConfig,
     stopped_processes: Vec<StoppedProcess>,
+    /// The tracepoints which are sampled for every profiled process, as the
+    /// tracepoint id and whether the samples have user stacks. See
+    /// [`PerfGroup::open_process_tracepoint`].
+    process_tracepoints: Vec<(u64, bool)>,
 }
 
 fn get_threads(pid: u32) -> Result<Vec<u32>, io::Error> {
@@ -91,6 +96,24 @@ fn get_threads(pid: u32) -> Result<Vec<u32>, io::Error> {
     Ok(tids)
 }
 
+/// The (tid, cpu) pairs which events need to be opened for to observe the
+/// process `pid`. The events for the process's main thread are inherited by
+/// the threads and processes which it creates later. Existing threads need
+/// their own events.
+fn process_targets(pid: u32) -> Result<Vec<(u32, Option<u32>)>, io::Error> {
+    let threads = get_threads(pid)?;
+    let cpu_count = num_cpus::get() as u32;
+    let mut targets: Vec<(u32, Option<u32>)> = (0..cpu_count).map(|cpu| (pid, Some(cpu))).collect();
+    if cpu_count as usize * (threads.len() + 1) >= 1000 {
+        targets.extend(threads.iter().map(|&tid| (tid, None)));
+    } else {
+        for cpu in 0..cpu_count {
+            targets.extend(threads.iter().map(|&tid| (tid, Some(cpu))));
+        }
+    }
+    Ok(targets)
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AttachMode {
     AttachWithEnableOnExec,
@@ -98,17 +121,25 @@ pub enum AttachMode {
 }
 
 impl PerfGroup {
-    pub fn new(frequency: u32, stack_size: u32, regs_mask: u64, event_source: EventSource) -> Self {
+    pub fn new(
+        frequency: u32,
+        stack_size: u32,
+        regs_mask: u64,
+        event_source: EventSource,
+        ring_buffer: RingBufferConfig,
+    ) -> Self {
         PerfGroup {
-            event_sorter: EventSorter::new(),
             members: Default::default(),
+            cpu_rings: Default::default(),
             poll: Poll::new().unwrap(),
             poll_events: Events::with_capacity(16),
             frequency,
             stack_size,
             event_source,
             regs_mask,
+            ring_buffer,
             stopped_processes: Vec::new(),
+            process_tracepoints: Vec::new(),
         }
     }
 
@@ -119,104 +150,239 @@ impl PerfGroup {
         event_source: EventSource,
         regs_mask: u64,
         attach_mode: AttachMode,
+        ring_buffer: RingBufferConfig,
     ) -> Result<Self, io::Error> {
-        let mut group = PerfGroup::new(frequency, stack_size, regs_mask, event_source);
+        let mut group = PerfGroup::new(frequency, stack_size, regs_mask, event_source, ring_buffer);
         group.open_process(pid, attach_mode)?;
         Ok(group)
     }
 
+    /// Whether the samples are only read once profiling stops, see
+    /// [`RingBufferConfig::overwrite`].
+    pub fn overwrites_samples(&self) -> bool {
+        self.ring_buffer.overwrite
+    }
+
     pub fn open_process(&mut self, pid: u32, attach_mode: AttachMode) -> Result<(), io::Error> {
         if attach_mode == AttachMode::StopAttachEnableResume {
             self.stopped_processes.push(StoppedProcess::new(pid)?);
         }
-        let mut perf_events = Vec::new();
-        let threads = get_threads(pid)?;
+        let targets = process_targets(pid)?;
+        let mut added_fds = Vec::new();
+        let result = targets.iter().try_for_each(|&(tid, cpu)| {
+            self.open_thread_events(tid, cpu, attach_mode, &mut added_fds)
+        });
+        if result.is_err() {
+            for fd in added_fds {
+                self.remove_member(fd);
+            }
+            return result;
+        }
 
-        let cpu_count = num_cpus::get();
-        for cpu in 0..cpu_count as u32 {
+        // The process is profiled even if its tracepoints can't be opened.
+        for (tracepoint_id, with_user_stacks) in self.process_tracepoints.clone() {
+            let _ = self.open_tracepoint_for_targets(
+                &targets,
+                tracepoint_id,
+                with_user_stacks,
+                attach_mode,
+            );
+        }
+        Ok(())
+    }
+
+    /// Samples every hit of the given tracepoint in the process `pid`, and in
+    /// every process which is profiled later. Like the main event, the
+    /// tracepoint is inherited by the threads and processes which the process
+    /// creates. Its samples go into a ring buffer per CPU, which is shared with
+    /// the other tracepoints that have the same sample type.
+    ///
+    /// If `with_user_stacks` is true, the samples contain the user stack and
+    /// registers, like the samples of the main event, so that they can be
+    /// unwound.
+    pub fn open_process_tracepoint(
+        &mut self,
+        pid: u32,
+        tracepoint_id: u64,
+        with_user_stacks: bool,
+        attach_mode: AttachMode,
+    ) -> Result<(), io::Error> {
+        let targets = process_targets(pid)?;
+        self.open_tracepoint_for_targets(&targets, tracepoint_id, with_user_stacks, attach_mode)?;
+        self.process_tracepoints
+            .push((tracepoint_id, with_user_stacks));
+        Ok(())
+    }
+
+    fn open_tracepoint_for_targets(
+        &mut self,
+        targets: &[(u32, Option<u32>)],
+        tracepoint_id: u64,
+        with_user_stacks: bool,
+        attach_mode: AttachMode,
+    ) -> Result<(), io::Error> {
+        let mut added_fds = Vec::new();
+        let result = targets.iter().try_for_each(|&(tid, cpu)| {
             let mut builder = Perf::build()
-                .pid(pid)
-                .only_cpu(cpu as _)
-                .frequency(self.frequency as u64)
-                .sample_user_stack(self.stack_size)
-                .sample_user_regs(self.regs_mask)
+                .pid(tid)
                 .sample_kernel()
-                .gather_context_switches()
-                .event_source(self.event_source)
-                .inherit_to_children()
+                .event_source(EventSource::Tracepoint(tracepoint_id))
                 .start_disabled();
-
+            if with_user_stacks {
+                builder = builder
+                    .sample_user_stack(self.stack_size)
+                    .sample_user_regs(self.regs_mask);
+            }
+            builder = match cpu {
+                Some(cpu) => builder.only_cpu(cpu).inherit_to_children(),
+                None => builder.any_cpu(),
+            };
             if attach_mode == AttachMode::AttachWithEnableOnExec {
                 builder = builder.enable_on_exec();
             }
+            added_fds.push(self.add_member(builder.open()?, cpu, true)?);
+            Ok(())
+        });
+        if result.is_err() {
+            for fd in added_fds {
+                self.remove_member(fd);
+            }
+        }
+        result
+    }
 
-            let perf = builder.open()?;
+    fn open_thread_events(
+        &mut self,
+        tid: u32,
+        cpu: Option<u32>,
+        attach_mode: AttachMode,
+        added_fds: &mut Vec<RawFd>,
+    ) -> Result<(), io::Error> {
+        let mut builder = Perf::build()
+            .pid(tid)
+            .frequency(self.frequency as u64)
+            .sample_user_stack(self.stack_size)
+            .sample_user_regs(self.regs_mask)
+            .sample_kernel()
+            .event_source(self.event_source)
+            .start_disabled();
+        builder = match cpu {
+            Some(cpu) => builder
+                .only_cpu(cpu)
+                .gather_context_switches()
+                .inherit_to_children(),
+            None => builder.any_cpu(),
+        };
+        if attach_mode == AttachMode::AttachWithEnableOnExec {
+            builder = builder.enable_on_exec();
+        }
 
-            perf_events.push((Some(cpu), perf));
+        if self.ring_buffer.overwrite {
+            // The records about processes, threads and mappings must not be
+            // overwritten, because the samples can't be interpreted without
+            // them. They come from a dummy event with a regular ring buffer.
+            let tracking = builder.clone().event_source(EventSource::Dummy).open()?;
+            added_fds.push(self.add_member(tracking, cpu, false)?);
+            builder = builder.without_process_tracking().overwrite();
+        }
+        if let Some(size) = self.ring_buffer.size {
+            builder = builder.ring_buffer_size(size);
         }
+        added_fds.push(self.add_member(builder.open()?, cpu, false)?);
+        Ok(())
+    }
 
-        if cpu_count * (threads.len() + 1) >= 1000 {
-            for &tid in &threads {
-                let mut builder = Perf::build()
-                    .pid(tid)
-                    .any_cpu()
-                    .frequency(self.frequency as u64)
+    /// Sample every hit of the given tracepoint, on all CPUs and for all processes.
+    /// Like the tracepoints of the profiled processes, the samples go into a
+    /// ring buffer per CPU and sample type.
+    /// If `with_user_stacks` is true, the samples contain the user stack and
+    /// registers, like the samples of the main event, so that they can be
+    /// unwound. This usually requires root privileges.
+    pub fn open_system_wide_tracepoint(
+        &mut self,
+        tracepoint_id: u64,
+        with_user_stacks: bool,
+    ) -> Result<(), io::Error> {
+        let mut perf_events = Vec::new();
+        for cpu in 0..num_cpus::get() as u32 {
+            let mut builder = Perf::build()
+                .any_pid()
+                .only_cpu(cpu)
+                .sample_kernel()
+                .event_source(EventSource::Tracepoint(tracepoint_id));
+            if with_user_stacks {
+                builder = builder
                     .sample_user_stack(self.stack_size)
-                    .sample_user_regs(self.regs_mask)
-                    .sample_kernel()
-                    .event_source(self.event_source)
-                    .start_disabled();
-                if attach_mode == AttachMode::AttachWithEnableOnExec {
-                    builder = builder.enable_on_exec();
-                }
-                let perf = builder.open()?;
-
-                perf_events.push((None, perf));
-            }
-        } else {
-            for cpu in 0..cpu_count as u32 {
-                for &tid in &threads {
-                    let mut builder = Perf::build()
-                        .pid(tid)
-                        .only_cpu(cpu as _)
-                        .frequency(self.frequency as u64)
-                        .sample_user_stack(self.stack_size)
-                        .sample_user_regs(self.regs_mask)
-                        .sample_kernel()
-                        .gather_context_switches()
-                        .event_source(self.event_source)
-                        .inherit_to_children()
-                        .start_disabled();
-                    if attach_mode == AttachMode::AttachWithEnableOnExec {
-                        builder = builder.enable_on_exec();
-                    }
-                    let perf = builder.open()?;
-
-                    perf_events.push((Some(cpu), perf));
-                }
+                    .sample_user_regs(self.regs_mask);
             }
+            perf_events.push((cpu, builder.open()?));
         }
+        for (cpu, perf) in perf_events {
+            self.add_member(perf, Some(cpu), true)?;
+        }
+        Ok(())
+    }
 
-        for (_cpu, perf) in perf_events {
-            let fd = perf.fd();
-            self.members.insert(fd, Member::new(perf));
-            self.poll.registry().register(
-                &mut SourceFd(&fd),
-                Token(fd as usize),
-                Interest::READABLE,
-            )?;
+    /// Adds an event to the group. If `shared_cpu` is given, the event writes
+    /// into the ring buffer which all events on this CPU share, so that there's
+    /// one ring buffer per CPU rather than one per thread and CPU. This means
+    /// fewer buffers to read and fewer wakeups. The event gets its own ring
+    /// buffer if it can't share one.
+    fn add_member(
+        &mut self,
+        mut perf: Perf,
+        shared_cpu: Option<u32>,
+        is_auxiliary: bool,
+    ) -> Result<RawFd, io::Error> {
+        let fd = perf.fd();
+        let ring_key = shared_cpu.map(|cpu| (cpu, perf.is_overwrite(), perf.sample_type()));
+        let shared_ring_fd = ring_key
+            .and_then(|key| self.cpu_rings.get(&key).copied())
+            .filter(|ring_fd| perf.set_output(&self.members[ring_fd].perf).is_ok());
+        let ring_fd = match shared_ring_fd {
+            Some(ring_fd) => ring_fd,
+            None => {
+                perf.map_ring_buffer()?;
+                fd
+            }
+        };
+
+        self.poll.registry().register(
+            &mut SourceFd(&fd),
+            Token(fd as usize),
+            Interest::READABLE,
+        )?;
+        if let (Some(key), true) = (ring_key, ring_fd == fd) {
+            self.cpu_rings.insert(key, fd);
         }
+        self.members.insert(
+            fd,
+            Member {
+                perf,
+                ring_fd,
+                is_closed: false,
+                is_auxiliary,
+            },
+        );
+        Ok(fd)
+    }
 
-        Ok(())
+    fn remove_member(&mut self, fd: RawFd) {
+        let result = self.poll.registry().deregister(&mut SourceFd(&fd));
+        if let Err(err) = result {
+            eprintln!("deregister failed: {}", err);
+        }
+        self.members.remove(&fd);
+        self.cpu_rings.retain(|_, ring_fd| *ring_fd != fd);
     }
 
     pub fn is_empty(&self) -> bool {
-        self.members.is_empty()
+        self.members.values().all(|member| member.is_auxiliary)
     }
 
     pub fn enable(&mut self) {
-        for perf in self.members.values_mut() {
-            perf.enable();
+        for member in self.members.values_mut() {
+            member.perf.enable();
         }
 
         self.stopped_processes.clear();
@@ -224,7 +390,7 @@ impl PerfGroup {
 
     pub fn wait(&mut self) {
         for member in self.members.values() {
-            if member.are_events_pending() {
+            if member.perf.are_events_pending() {
                 return;
             }
         }
@@ -240,58 +406,182 @@ impl PerfGroup {
         for ev in self.poll_events.iter() {
             if ev.is_read_closed() {
                 let fd = ev.token().0 as RawFd;
-                self.members.get_mut(&fd).unwrap().is_closed = true;
+                if let Some(member) = self.members.get_mut(&fd) {
+                    member.is_closed = true;
+                }
             }
         }
     }
 
-    pub fn consume_events(&mut self, cb: &mut impl FnMut(EventRef)) {
-        let mut fds_to_remove = Vec::new();
-        loop {
-            for (&fd, member) in &mut self.members {
-                self.event_sorter.begin_group(fd);
-                while let Some(ev) = self.event_sorter.pop() {
-                    cb(ev);
-                }
+    /// Copies the unread records out of the regular ring buffers. The batches
+    /// are ordered by the fd of the ring buffer's owner, as [`RecordMerger`]
+    /// expects.
+    ///
+    /// Ring buffers whose events have all been closed are read one last time,
+    /// including overwrite buffers, and are then removed.
+    pub fn drain(&mut self) -> Vec<(RawFd, RecordBatch)> {
+        let mut closed_rings = BTreeMap::new();
+        for member in self.members.values() {
+            *closed_rings.entry(member.ring_fd).or_insert(true) &= member.is_closed;
+        }
+        closed_rings.retain(|_, is_closed| *is_closed);
 
-                let perf = &mut member.perf;
-                if !perf.are_events_pending() {
-                    if member.is_closed {
-                        fds_to_remove.push(perf.fd());
-                        continue;
-                    }
-                    continue;
-                }
+        let mut batches = Vec::new();
+        for (&fd, member) in &mut self.members {
+            let batch = if member.perf.is_overwrite() && closed_rings.contains_key(&fd) {
+                member.perf.read_overwritten()
+            } else {
+                member.perf.read_pending()
+            };
+            if let Some(batch) = batch {
+                batches.push((fd, batch));
+            }
+        }
+
+        let closed_fds: Vec<RawFd> = self
+            .members
+            .iter()
+            .filter(|(_, member)| closed_rings.contains_key(&member.ring_fd))
+            .map(|(&fd, _)| fd)
+            .collect();
+        for fd in closed_fds {
+            self.remove_member(fd);
+        }
+        batches
+    }
+
+    /// Copies the records out of all overwrite ring buffers.
+    pub fn drain_overwritten(&mut self) -> Vec<(RawFd, RecordBatch)> {
+        self.members
+            .iter_mut()
+            .filter_map(|(&fd, member)| Some((fd, member.perf.read_overwritten()?)))
+            .collect()
+    }
+}
+
+/// Merges the batches from all ring buffers into a single stream of records,
+/// ordered by timestamp.
+pub struct RecordMerger {
+    event_sorter: EventSorter<RawFd, u64, (Rc<RecordBatch>, usize)>,
+    /// Hold back all records until [`RecordMerger::finish`]. This is needed in
+    /// overwrite mode, where the samples are only read at the end.
+    hold_until_finish: bool,
+}
+
+impl RecordMerger {
+    pub fn new(hold_until_finish: bool) -> Self {
+        RecordMerger {
+            event_sorter: EventSorter::new(),
+            hold_until_finish,
+        }
+    }
 
-                self.event_sorter.extend(perf.iter().map(|event| {
-                    let rec = event.get();
+    /// Adds the batches from one pass over the ring buffers, see
+    /// [`PerfGroup::drain`]. Records which can no longer be preceded by
+    /// records from later passes are passed to the callback.
+    pub fn add_round(
+        &mut self,
+        batches: Vec<(RawFd, RecordBatch)>,
+        cb: &mut impl FnMut(RawEventRecord),
+    ) {
+        for (fd, batch) in batches {
+            let group = if self.hold_until_finish { 0 } else { fd };
+            self.event_sorter.begin_group(group);
+            self.pop_into(cb);
+
+            let batch = Rc::new(batch);
+            let timestamps: Vec<(u64, usize)> = batch
+                .record_offsets()
+                .map(|offset| {
+                    let rec = batch.record_at(offset);
                     let timestamp = get_record_timestamp::<LittleEndian>(
                         rec.record_type,
                         rec.data,
                         &rec.parse_info,
                     )
                     .expect("All events should have a record identifier");
-                    (timestamp, event)
-                }));
-            }
+                    (timestamp, offset)
+                })
+                .collect();
+            self.event_sorter.extend(
+                timestamps
+                    .into_iter()
+                    .map(|(timestamp, offset)| (timestamp, (batch.clone(), offset))),
+            );
+        }
 
+        if !self.hold_until_finish {
             self.event_sorter.advance_round();
-            while let Some(ev) = self.event_sorter.pop() {
-                cb(ev);
-            }
+            self.pop_into(cb);
+        }
+    }
 
-            for fd in fds_to_remove.drain(..) {
-                let result = self.poll.registry().deregister(&mut SourceFd(&fd));
-                if let Err(err) = result {
-                    eprintln!("deregister failed: {}", err);
-                    continue;
-                }
-                self.members.remove(&fd);
-            }
+    /// Passes all remaining records to the callback.
+    pub fn finish(mut self, cb: &mut impl FnMut(RawEventRecord)) {
+        // Two more rounds release everything, even records from the current round.
+        self.event_sorter.advance_round();
+        self.event_sorter.advance_round();
+        self.pop_into(cb);
+    }
 
-            if !self.event_sorter.has_more() {
-                break;
-            }
+    fn pop_into(&mut self, cb: &mut impl FnMut(RawEventRecord)) {
+        while let Some((batch, offset)) = self.event_sorter.pop() {
+            cb(batch.record_at(offset));
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::linux::perf_event::test::sample_batch;
+
+    fn timestamp(record: RawEventRecord) -> u64 {
+        get_record_timestamp::<LittleEndian>(record.record_type, record.data, &record.parse_info)
+            .unwrap()
+    }
+
+    #[test]
+    fn records_are_merged_by_timestamp() {
+        let mut merger = RecordMerger::new(false);
+        let mut merged = Vec::new();
+        // The buffer of fd 3 is read before the one of fd 4 in each round, so
+        // the second round can have records from fd 3 which are older than
+        // the ones from fd 4 in the first round.
+        merger.add_round(
+            vec![(3, sample_batch(&[1, 3])), (4, sample_batch(&[2, 9]))],
+            &mut |record| merged.push(timestamp(record)),
+        );
+        merger.add_round(
+            vec![(3, sample_batch(&[5, 12])), (4, sample_batch(&[13]))],
+            &mut |record| merged.push(timestamp(record)),
+        );
+        merger.add_round(
+            vec![(3, sample_batch(&[14])), (4, sample_batch(&[15]))],
+            &mut |record| merged.push(timestamp(record)),
+        );
+        // Records are passed on before the end, in order.
+        assert!(!merged.is_empty());
+        assert!(merged.windows(2).all(|w| w[0] <= w[1]));
+        merger.finish(&mut |record| merged.push(timestamp(record)));
+        assert_eq!(merged, vec![1, 2, 3, 5, 9, 12, 13, 14, 15]);
+    }
+
+    #[test]
+    fn records_are_held_until_finish() {
+        let mut merger = RecordMerger::new(true);
+        let mut merged = Vec::new();
+        merger.add_round(
+            vec![(3, sample_batch(&[5, 6])), (4, sample_batch(&[7]))],
+            &mut |record| merged.push(timestamp(record)),
+        );
+        // The samples of overwrite ring buffers are only read at the end, and
+        // they can be older than everything else.
+        merger.add_round(vec![(5, sample_batch(&[1, 8]))], &mut |record| {
+            merged.push(timestamp(record))
+        });
+        assert!(merged.is_empty());
+        merger.finish(&mut |record| merged.push(timestamp(record)));
+        assert_eq!(merged, vec![1, 5, 6, 7, 8]);
+    }
+}
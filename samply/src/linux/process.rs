@@ -10,16 +10,31 @@ use std::process::ExitStatus;
 
 /// Allows launching a command in a suspended state, so that we can know its
 /// pid and initialize profiling before proceeding to execute the command.
+///
+/// The child waits for the go-ahead before it calls execve, and the perf events
+/// are opened with enable-on-exec, so sampling starts at the first instruction
+/// of the new program.
 pub struct SuspendedLaunchedProcess {
     pid: Pid,
+    /// When the child was forked, in `CLOCK_MONOTONIC` nanoseconds.
+    launch_time_ns: u64,
     send_end_of_resume_pipe: OwnedFd,
     recv_end_of_execerr_pipe: OwnedFd,
+    output_pipes: Option<OutputPipes>,
+}
+
+/// The read ends of the pipes which the launched process's stdout and stderr
+/// have been redirected to.
+pub struct OutputPipes {
+    pub stdout: OwnedFd,
+    pub stderr: OwnedFd,
 }
 
 impl SuspendedLaunchedProcess {
     pub fn launch_in_suspended_state(
         command_name: &OsStr,
         command_args: &[OsString],
+        capture_output: bool,
     ) -> std::io::Result<Self> {
         let argv: Vec<CString> = std::iter::once(command_name)
             .chain(command_args.iter().map(|s| s.as_os_str()))
@@ -33,21 +48,45 @@ impl SuspendedLaunchedProcess {
 
         let (resume_rp, resume_sp) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC).unwrap();
         let (execerr_rp, execerr_sp) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC).unwrap();
+        let output_pipes = if capture_output {
+            let stdout = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+            let stderr = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+            Some((stdout, stderr))
+        } else {
+            None
+        };
 
+        let launch_time_ns = monotonic_now_ns();
         match unsafe { nix::unistd::fork() }.expect("Fork failed") {
             nix::unistd::ForkResult::Child => {
                 // std::panic::always_abort();
                 nix::unistd::close(resume_sp.into_raw_fd()).unwrap();
                 nix::unistd::close(execerr_rp.into_raw_fd()).unwrap();
+                if let Some(((stdout_rp, stdout_sp), (stderr_rp, stderr_sp))) = output_pipes {
+                    // The duplicated fds don't have O_CLOEXEC, so they survive the exec.
+                    nix::unistd::dup2(stdout_sp.as_raw_fd(), libc::STDOUT_FILENO).unwrap();
+                    nix::unistd::dup2(stderr_sp.as_raw_fd(), libc::STDERR_FILENO).unwrap();
+                    drop((stdout_rp, stdout_sp, stderr_rp, stderr_sp));
+                }
                 Self::run_child(resume_rp, execerr_sp, &argv)
             }
             nix::unistd::ForkResult::Parent { child } => {
                 nix::unistd::close(resume_rp.into_raw_fd())?;
                 nix::unistd::close(execerr_sp.into_raw_fd())?;
+                let output_pipes = output_pipes.map(|((stdout_rp, _), (stderr_rp, _))| {
+                    // The write ends are dropped here, so that we see EOF once the
+                    // child and its descendants have exited.
+                    OutputPipes {
+                        stdout: stdout_rp,
+                        stderr: stderr_rp,
+                    }
+                });
                 Ok(Self {
                     pid: child,
+                    launch_time_ns,
                     send_end_of_resume_pipe: resume_sp,
                     recv_end_of_execerr_pipe: execerr_rp,
+                    output_pipes,
                 })
             }
         }
@@ -57,6 +96,17 @@ impl SuspendedLaunchedProcess {
         self.pid.as_raw() as u32
     }
 
+    /// When the process was launched, in `CLOCK_MONOTONIC` nanoseconds.
+    pub fn launch_time_ns(&self) -> u64 {
+        self.launch_time_ns
+    }
+
+    /// Returns the pipes for the launched process's output, if it was launched
+    /// with `capture_output`.
+    pub fn take_output_pipes(&mut self) -> Option<OutputPipes> {
+        self.output_pipes.take()
+    }
+
     const EXECERR_MSG_FOOTER: [u8; 4] = *b"NOEX";
 
     pub fn unsuspend_and_run(self) -> std::io::Result<RunningProcess> {
@@ -176,7 +226,12 @@ impl RunningProcess {
     pub fn wait(self) -> Result<std::process::ExitStatus, nix::errno::Errno> {
         let wait_status = nix::sys::wait::waitpid(self.pid, None)?;
         let exit_status = match wait_status {
-            WaitStatus::Exited(_pid, exit_code) => ExitStatus::from_raw(exit_code),
+            // `from_raw` takes the raw wait status, which has the exit code in
+            // the second byte and the signal number in the low bits.
+            WaitStatus::Exited(_pid, exit_code) => ExitStatus::from_raw(exit_code << 8),
+            WaitStatus::Signaled(_pid, signal, core_dumped) => {
+                ExitStatus::from_raw(signal as i32 | if core_dumped { 0x80 } else { 0 })
+            }
             wait_status => {
                 panic!("Unexpected waitpid result: {wait_status:?}");
             }
@@ -184,3 +239,14 @@ impl RunningProcess {
         Ok(exit_status)
     }
 }
+
+/// The current `CLOCK_MONOTONIC` time in nanoseconds, which is the clock of
+/// the perf events.
+pub fn monotonic_now_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
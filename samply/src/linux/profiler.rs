@@ -1,29 +1,43 @@
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use linux_perf_data::linux_perf_event_reader::EventRecord;
 use linux_perf_data::linux_perf_event_reader::{
-    CpuMode, Endianness, Mmap2FileId, Mmap2InodeAndVersion, Mmap2Record, RawData,
+    CpuMode, Endianness, Mmap2FileId, Mmap2InodeAndVersion, Mmap2Record, RawData, RawEventRecord,
 };
 
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::BufWriter;
+use std::os::unix::io::RawFd;
 use std::path::Path;
 use std::process::ExitStatus;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
-
-use super::perf_event::EventSource;
-use super::perf_group::{AttachMode, PerfGroup};
+use std::time::{Duration, Instant};
+
+use super::iterations::Iterations;
+use super::marker_socket::{MarkerSocket, MARKER_SOCKET_ENV_VAR};
+use super::otlp_receiver::OtlpReceiver;
+use super::output_capture::{MarkerLines, OutputCapture, TeeFiles};
+use super::perf_event::{EventSource, RecordBatch};
+use super::perf_group::{AttachMode, PerfGroup, RecordMerger, RingBufferConfig};
 use super::proc_maps;
-use super::process::SuspendedLaunchedProcess;
+use super::process::{monotonic_now_ns, SuspendedLaunchedProcess};
+use super::trigger::{self, LatencyTrigger};
+use crate::iteration_report::write_iteration_report;
 use crate::linux_shared::{
-    ConvertRegs, Converter, EventInterpretation, MmapRangeOrVec, OffCpuIndicator,
+    ConvertRegs, Converter, CpuTopology, EventInterpretation, GpuJobEventFormat, KnownEvent,
+    MmapRangeOrVec, OffCpuIndicator, OutputStream, SystemInfo, TracepointFormat, BLOCKING_SYSCALLS,
+    GPU_JOB_TRACEPOINTS,
 };
+use crate::profile_symbolication::symbolicate_saved_profile;
+use crate::rustc_wrapper::set_rustc_wrapper_env_vars;
 use crate::server::{start_server_main, ServerProps};
-use crate::shared::recording_props::{ConversionProps, RecordingProps};
+use crate::shared::off_cpu_reason::OffCpuReason;
+use crate::shared::recording_props::{ConversionProps, OutputMarkerProps, RecordingProps, Trigger};
+use crate::shared::utils::WarmupIterations;
+use crate::split_profiles::{merge_split_profiles, write_process_profiles, write_split_profiles};
 
 #[cfg(target_arch = "x86_64")]
 pub type ConvertRegsNative = crate::linux_shared::ConvertRegsX86_64;
@@ -31,7 +45,7 @@ pub type ConvertRegsNative = crate::linux_shared::ConvertRegsX86_64;
 #[cfg(target_arch = "aarch64")]
 pub type ConvertRegsNative = crate::linux_shared::ConvertRegsAarch64;
 
-#[allow(clippy::too_many_arguments)]
+#[allow(clippy::too_many_arguments, clippy::result_unit_err)]
 pub fn start_recording(
     command_name: OsString,
     command_args: &[OsString],
@@ -53,11 +67,110 @@ pub fn start_recording(
     )
     .expect("cannot register signal handler");
 
+    // Capture the environment for the unrecorded warm-up runs before we add our
+    // own variables to it.
+    let warmups = if recording_props.record_warmup {
+        None
+    } else {
+        Some(WarmupIterations::new(
+            &command_name,
+            command_args,
+            recording_props.warmup_iterations,
+        ))
+    };
+
+    // Create the marker socket before launching the command, so that the command
+    // inherits the environment variable with the socket path.
+    // The rustc wrapper reports each compilation over the marker socket.
+    let marker_socket = if recording_props.marker_socket || recording_props.rustc_wrapper {
+        match MarkerSocket::bind() {
+            Ok(marker_socket) => {
+                std::env::set_var(MARKER_SOCKET_ENV_VAR, marker_socket.path());
+                Some(marker_socket)
+            }
+            Err(err) => {
+                eprintln!("Could not create the marker socket: {err}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if recording_props.rustc_wrapper {
+        if let Err(err) = set_rustc_wrapper_env_vars() {
+            eprintln!("Could not set up the rustc wrapper: {err}");
+        }
+    }
+    let otlp_receiver = match recording_props.otlp_port {
+        Some(port) => match OtlpReceiver::start(port) {
+            Ok(otlp_receiver) => {
+                std::env::set_var(
+                    "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+                    otlp_receiver.traces_endpoint(),
+                );
+                std::env::set_var("OTEL_EXPORTER_OTLP_TRACES_PROTOCOL", "http/json");
+                Some(otlp_receiver)
+            }
+            Err(err) => {
+                eprintln!("Could not start the OTLP receiver on port {port}: {err}");
+                None
+            }
+        },
+        None => None,
+    };
+
+    let tee_files = recording_props.tee_output.as_deref().map(|path| {
+        TeeFiles::create(path).unwrap_or_else(|err| {
+            eprintln!(
+                "Could not create the output files for {}: {err}",
+                path.display()
+            );
+            std::process::exit(1)
+        })
+    });
+    let marker_lines = match &recording_props.output_markers {
+        None => MarkerLines::None,
+        Some(OutputMarkerProps { filter: None }) => MarkerLines::All,
+        Some(OutputMarkerProps {
+            filter: Some(filter),
+        }) => MarkerLines::Matching(filter.clone()),
+    };
+    // Only pipe the launched command's output through us if we need to look at it.
+    let output_capture = OutputCapture::new_if_needed(marker_lines, tee_files);
+
+    let recorded_warmups = if recording_props.record_warmup {
+        recording_props.warmup_iterations
+    } else {
+        0
+    };
+    let iterations = Arc::new(Mutex::new(Iterations::new(
+        recorded_warmups,
+        iteration_count,
+        recording_props.skip_failed_iterations,
+    )));
+
     // Start a new process for the launched command and get its pid.
     // The command will not start running until we tell it to.
-    let process =
-        SuspendedLaunchedProcess::launch_in_suspended_state(&command_name, command_args).unwrap();
+    let mut process = SuspendedLaunchedProcess::launch_in_suspended_state(
+        &command_name,
+        command_args,
+        output_capture.is_some(),
+    )
+    .unwrap();
     let pid = process.pid();
+    let launch_time_ns = process.launch_time_ns();
+    iterations.lock().unwrap().announce(1);
+    iterations.lock().unwrap().run_started(1, pid);
+    if let (Some(output_capture), Some(pipes)) = (&output_capture, process.take_output_pipes()) {
+        output_capture.capture(pid, pipes);
+    }
+    let live_markers = Arc::new(LiveMarkerSources {
+        marker_socket,
+        otlp_receiver,
+        output_capture,
+        root_pid: pid as i32,
+        latency_trigger: None,
+    });
 
     // Create a channel for the observer thread to notify the main thread once
     // profiling has been initialized and the launched process can start.
@@ -68,10 +181,28 @@ pub fn start_recording(
 
     // Launch the observer thread. This thread will manage the perf events.
     let output_file_copy = recording_props.output_file.clone();
+    let split_processes = recording_props.split_processes;
     let interval = recording_props.interval;
     let time_limit = recording_props.time_limit;
+    let vsync = recording_props.vsync;
+    let gpu = recording_props.gpu;
+    let audio_xruns = recording_props.audio_xruns;
+    let process_environment = recording_props.process_environment;
+    let env_deny_list = recording_props.env_deny_list.clone();
+    let lock_contention = recording_props.lock_contention;
+    let off_cpu_reasons = recording_props.off_cpu_reasons;
+    let ring_buffer = ring_buffer_config(&recording_props);
+    let live_markers_copy = live_markers.clone();
+    let iterations_copy = iterations.clone();
+    let process_groups = iterations.lock().unwrap().process_groups();
     let observer_thread = thread::spawn(move || {
-        let mut converter = make_converter(interval, conversion_props);
+        let mut converter = make_converter(
+            interval,
+            conversion_props,
+            process_environment,
+            env_deny_list,
+        );
+        converter.set_launched_process_groups(process_groups);
 
         // Wait for the initial pid to profile.
         let SamplerRequest::StartProfilingAnotherProcess(pid, attach_mode) =
@@ -81,7 +212,28 @@ pub fn start_recording(
         };
 
         // Create the perf events, setting ENABLE_ON_EXEC.
-        let perf_group = init_profiler(interval, pid, attach_mode, &mut converter);
+        let perf_group = init_profiler(
+            interval,
+            pid,
+            attach_mode,
+            vsync,
+            gpu,
+            audio_xruns,
+            lock_contention,
+            off_cpu_reasons,
+            ring_buffer,
+            &mut converter,
+        );
+        converter.add_attach_latency(pid as i32, launch_time_ns, monotonic_now_ns());
+        if let Some(tee_files) = live_markers_copy
+            .output_capture
+            .as_ref()
+            .and_then(OutputCapture::tee_files)
+        {
+            for stream in [OutputStream::Stdout, OutputStream::Stderr] {
+                converter.add_tee_output_path(stream, tee_files.path(stream));
+            }
+        }
 
         // Tell the main thread to tell the child process to begin executing.
         profile_another_pid_reply_sender.send(true).unwrap();
@@ -98,10 +250,14 @@ pub fn start_recording(
             perf_group,
             converter,
             &output_file_copy,
+            split_processes,
             time_limit,
             profile_another_pid_request_receiver,
             profile_another_pid_reply_sender,
             stop_flag,
+            Some(live_markers_copy),
+            Some(iterations_copy),
+            None,
         );
     });
 
@@ -116,6 +272,13 @@ pub fn start_recording(
         .unwrap();
     let _ = profile_another_pid_reply_receiver.recv().unwrap();
 
+    // Run the unrecorded warm-ups now that we know that we can profile. The
+    // perf events are only enabled once the launched command execs, so the
+    // warm-ups aren't recorded.
+    if let Some(warmups) = &warmups {
+        warmups.run();
+    }
+
     // Now tell the child process to start executing.
     let process = match process.unsuspend_and_run() {
         Ok(process) => process,
@@ -130,30 +293,36 @@ pub fn start_recording(
     // Wait for the child process to quit.
     // This is where the main thread spends all its time during profiling.
     let mut exit_status = process.wait().unwrap();
+    let mut keep_running = iterations.lock().unwrap().run_finished(1, pid, exit_status);
 
-    for i in 2..=iteration_count {
-        if !exit_status.success() {
-            eprintln!(
-                "Skipping remaining iterations due to non-success exit status: \"{}\"",
-                exit_status
-            );
+    let run_count = iterations.lock().unwrap().run_count();
+    for run in 2..=run_count {
+        if !keep_running {
             break;
         }
-        eprintln!("Running iteration {i} of {iteration_count}...");
-        let process =
-            SuspendedLaunchedProcess::launch_in_suspended_state(&command_name, command_args)
-                .unwrap();
+        iterations.lock().unwrap().announce(run);
+        let mut process = SuspendedLaunchedProcess::launch_in_suspended_state(
+            &command_name,
+            command_args,
+            live_markers.output_capture.is_some(),
+        )
+        .unwrap();
         let pid = process.pid();
+        iterations.lock().unwrap().run_started(run, pid);
+        if let (Some(output_capture), Some(pipes)) =
+            (&live_markers.output_capture, process.take_output_pipes())
+        {
+            output_capture.capture(pid, pipes);
+        }
 
         // Tell the sampler to start profiling another pid, and wait for it to signal us to go ahead.
-        profile_another_pid_request_sender
-            .send(SamplerRequest::StartProfilingAnotherProcess(
-                pid,
-                AttachMode::AttachWithEnableOnExec,
-            ))
-            .unwrap();
-        let succeeded = profile_another_pid_reply_receiver.recv().unwrap();
-        if !succeeded {
+        if !request_profiling_of_launched_process(
+            &profile_another_pid_request_sender,
+            &profile_another_pid_reply_receiver,
+            pid,
+        ) {
+            // Dropping the suspended process makes it exit without running the command.
+            eprintln!("The recording has stopped, not running the remaining iterations.");
             break;
         }
 
@@ -167,11 +336,15 @@ pub fn start_recording(
         };
 
         exit_status = process.wait().expect("couldn't wait for child");
+        keep_running = iterations
+            .lock()
+            .unwrap()
+            .run_finished(run, pid, exit_status);
     }
 
-    profile_another_pid_request_sender
-        .send(SamplerRequest::StopProfilingOncePerfEventsExhausted)
-        .unwrap();
+    // The profiler might have stopped already, once the --duration elapsed.
+    let _ = profile_another_pid_request_sender
+        .send(SamplerRequest::StopProfilingOncePerfEventsExhausted);
 
     // The child has quit.
     // From now on, we want to terminate if the user presses Ctrl+C.
@@ -184,9 +357,8 @@ pub fn start_recording(
         .join()
         .expect("couldn't join observer thread");
 
-    if let Some(server_props) = server_props {
-        start_server_main(&recording_props.output_file, server_props);
-    }
+    let launched_pids = iterations.lock().unwrap().measured_pids().to_vec();
+    finish_recording(&recording_props, server_props, &launched_pids);
 
     Ok(exit_status)
 }
@@ -215,31 +387,106 @@ pub fn start_profiling_pid(
         crossbeam_channel::bounded(2);
 
     let output_file = recording_props.output_file.clone();
+    let split_processes = recording_props.split_processes;
+    let interval = recording_props.interval;
+    let time_limit = recording_props.time_limit;
+    let vsync = recording_props.vsync;
+    let gpu = recording_props.gpu;
+    let audio_xruns = recording_props.audio_xruns;
+    let process_environment = recording_props.process_environment;
+    let env_deny_list = recording_props.env_deny_list.clone();
+    let lock_contention = recording_props.lock_contention;
+    let off_cpu_reasons = recording_props.off_cpu_reasons;
+    let ring_buffer = ring_buffer_config(&recording_props);
+    let trigger_fired = recording_props
+        .trigger
+        .as_ref()
+        .map(|_| Arc::new(AtomicBool::new(false)));
+    // The process was started without us, so it needs to be configured to
+    // export its spans to this receiver.
+    let otlp_receiver =
+        recording_props
+            .otlp_port
+            .and_then(|port| match OtlpReceiver::start(port) {
+                Ok(otlp_receiver) => {
+                    eprintln!(
+                        "Accepting OpenTelemetry spans at {}",
+                        otlp_receiver.traces_endpoint()
+                    );
+                    Some(otlp_receiver)
+                }
+                Err(err) => {
+                    eprintln!("Could not start the OTLP receiver on port {port}: {err}");
+                    std::process::exit(1)
+                }
+            });
+    let latency_trigger = match (&recording_props.trigger, &trigger_fired) {
+        (
+            Some(Trigger::Latency {
+                threshold,
+                span_name,
+            }),
+            Some(trigger_fired),
+        ) => Some(LatencyTrigger::new(
+            *threshold,
+            span_name.clone(),
+            stop.clone(),
+            trigger_fired.clone(),
+        )),
+        _ => None,
+    };
+    let live_markers = otlp_receiver.map(|otlp_receiver| {
+        Arc::new(LiveMarkerSources {
+            marker_socket: None,
+            otlp_receiver: Some(otlp_receiver),
+            output_capture: None,
+            root_pid: pid as i32,
+            latency_trigger,
+        })
+    });
     let observer_thread = thread::spawn({
         let stop = stop.clone();
+        let trigger_fired = trigger_fired.clone();
         move || {
-            let interval = recording_props.interval;
-            let time_limit = recording_props.time_limit;
-            let mut converter = make_converter(interval, conversion_props);
+            let mut converter = make_converter(
+                interval,
+                conversion_props,
+                process_environment,
+                env_deny_list,
+            );
             let SamplerRequest::StartProfilingAnotherProcess(pid, attach_mode) =
                 profile_another_pid_request_receiver.recv().unwrap()
             else {
                 panic!("The first message should be a StartProfilingAnotherProcess")
             };
-            let perf_group = init_profiler(interval, pid, attach_mode, &mut converter);
+            let perf_group = init_profiler(
+                interval,
+                pid,
+                attach_mode,
+                vsync,
+                gpu,
+                audio_xruns,
+                lock_contention,
+                off_cpu_reasons,
+                ring_buffer,
+                &mut converter,
+            );
 
             // Tell the main thread that we are now executing.
             profile_another_pid_reply_sender.send(true).unwrap();
 
-            let output_file = recording_props.output_file;
             run_profiler(
                 perf_group,
                 converter,
                 &output_file,
+                split_processes,
                 time_limit,
                 profile_another_pid_request_receiver,
                 profile_another_pid_reply_sender,
                 stop,
+                live_markers,
+                None,
+                trigger_fired,
             )
         }
     });
@@ -258,9 +505,37 @@ pub fn start_profiling_pid(
     // Now that we know that profiler initialization has succeeded, tell the user about it.
     eprintln!("Recording process with PID {pid} until Ctrl+C...");
 
-    profile_another_pid_request_sender
-        .send(SamplerRequest::StopProfilingOncePerfEventsExhausted)
-        .unwrap();
+    match (&recording_props.trigger, &trigger_fired) {
+        (
+            Some(Trigger::Cpu {
+                cpu_percent,
+                sustained_for,
+            }),
+            Some(trigger_fired),
+        ) => {
+            eprintln!(
+                "The recording is only saved once the CPU usage is above {cpu_percent}% for {} seconds.",
+                sustained_for.as_secs()
+            );
+            let (cpu_percent, sustained_for) = (*cpu_percent, *sustained_for);
+            let stop = stop.clone();
+            let trigger_fired = trigger_fired.clone();
+            thread::spawn(move || {
+                trigger::watch_cpu_usage(pid, cpu_percent, sustained_for, &stop, &trigger_fired)
+            });
+        }
+        (Some(Trigger::Latency { threshold, .. }), Some(_)) => {
+            eprintln!(
+                "The recording is only saved once a span takes longer than {:.1} ms.",
+                threshold.as_secs_f64() * 1000.0
+            );
+        }
+        _ => {}
+    }
+
+    // The profiler might have stopped already, once the --duration elapsed.
+    let _ = profile_another_pid_request_sender
+        .send(SamplerRequest::StopProfilingOncePerfEventsExhausted);
 
     // Now wait for the observer thread to quit. It will keep running until the
     // stop flag has been set to true by Ctrl+C, or until all perf events are closed,
@@ -273,9 +548,112 @@ pub fn start_profiling_pid(
     // false if the observer thread finished because the observed processes terminated.
     stop.store(true, Ordering::SeqCst);
 
-    if let Some(server_props) = server_props {
-        start_server_main(&output_file, server_props);
+    if trigger_fired.map_or(false, |fired| !fired.load(Ordering::SeqCst)) {
+        return;
     }
+    finish_recording(&recording_props, server_props, &[pid]);
+}
+
+/// Asks the observer thread to profile the launched process `pid` once it
+/// execs, and waits until it's ready. Returns false if the process couldn't be
+/// attached to, or if the profiler has already stopped, for example because
+/// the --duration elapsed.
+fn request_profiling_of_launched_process(
+    request_sender: &Sender<SamplerRequest>,
+    reply_receiver: &Receiver<bool>,
+    pid: u32,
+) -> bool {
+    let request =
+        SamplerRequest::StartProfilingAnotherProcess(pid, AttachMode::AttachWithEnableOnExec);
+    request_sender.send(request).is_ok() && reply_receiver.recv() == Ok(true)
+}
+
+/// Symbolicates the saved profile if requested, and then serves it if there are
+/// server props. Split per-process profiles are combined into a single profile
+/// for serving. `launched_pids` are the root processes of the iterations which
+/// the iteration report compares.
+fn finish_recording(
+    recording_props: &RecordingProps,
+    server_props: Option<ServerProps>,
+    launched_pids: &[u32],
+) {
+    let output = &recording_props.output_file;
+    if recording_props.symbolicate_on_save {
+        let verbose = server_props.as_ref().map_or(false, |props| props.verbose);
+        if let Err(err) = symbolicate_saved_profile(
+            output,
+            verbose,
+            &recording_props.symbol_dirs,
+            &recording_props.symbol_downloads,
+        ) {
+            eprintln!("Could not symbolicate the profile: {err}");
+        }
+    }
+    if let Some(report_props) = &recording_props.iteration_report {
+        let result = if recording_props.split_processes {
+            merge_split_profiles(output)
+                .map_err(|err| format!("Could not combine the per-process profiles: {err}"))
+                .and_then(|merged_file| {
+                    write_iteration_report(merged_file.path(), report_props, launched_pids)
+                })
+        } else {
+            write_iteration_report(output, report_props, launched_pids)
+        };
+        if let Err(err) = result {
+            eprintln!("Could not create the iteration report: {err}");
+        }
+    }
+    let Some(server_props) = server_props else {
+        return;
+    };
+    if !recording_props.split_processes {
+        start_server_main(output, server_props);
+        return;
+    }
+    match merge_split_profiles(output) {
+        Ok(merged_file) => start_server_main(merged_file.path(), server_props),
+        Err(err) => eprintln!("Could not combine the per-process profiles: {err}"),
+    }
+}
+
+/// The default size of the per-CPU ring buffers in overwrite mode, which
+/// determines how much history is kept.
+const DEFAULT_OVERWRITE_RING_BUFFER_SIZE: u32 = 16 * 1024 * 1024;
+
+fn ring_buffer_config(recording_props: &RecordingProps) -> RingBufferConfig {
+    let size = recording_props
+        .ring_buffer_size
+        .or(if recording_props.overwrite {
+            Some(DEFAULT_OVERWRITE_RING_BUFFER_SIZE)
+        } else {
+            None
+        });
+    RingBufferConfig {
+        size,
+        overwrite: recording_props.overwrite,
+    }
+}
+
+/// Look up the id of a tracepoint in tracefs, for use as the perf event config.
+fn tracepoint_id(category: &str, name: &str) -> Option<u64> {
+    ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"]
+        .iter()
+        .find_map(|tracefs| {
+            let id = read_string_lossy(format!("{tracefs}/events/{category}/{name}/id")).ok()?;
+            id.trim().parse().ok()
+        })
+}
+
+/// Read the format of a tracepoint from tracefs, which describes the layout of
+/// its raw data.
+fn tracepoint_format(category: &str, name: &str) -> Option<TracepointFormat> {
+    ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"]
+        .iter()
+        .find_map(|tracefs| {
+            let format =
+                read_string_lossy(format!("{tracefs}/events/{category}/{name}/format")).ok()?;
+            Some(TracepointFormat::parse(&format))
+        })
 }
 
 fn paranoia_level() -> Option<u32> {
@@ -287,6 +665,8 @@ fn paranoia_level() -> Option<u32> {
 fn make_converter(
     interval: Duration,
     conversion_props: ConversionProps,
+    process_environment: bool,
+    env_deny_list: Vec<String>,
 ) -> Converter<framehop::UnwinderNative<MmapRangeOrVec, framehop::MayAllocateDuringUnwind>> {
     let interval_nanos = if interval.as_nanos() > 0 {
         interval.as_nanos() as u64
@@ -312,8 +692,10 @@ fn make_converter(
         event_names: vec!["cycles".to_string()],
     };
 
-    Converter::<framehop::UnwinderNative<MmapRangeOrVec, framehop::MayAllocateDuringUnwind>>::new(
-        &conversion_props.profile_name,
+    let mut converter = Converter::<
+        framehop::UnwinderNative<MmapRangeOrVec, framehop::MayAllocateDuringUnwind>,
+    >::new(
+        &conversion_props,
         None,
         HashMap::new(),
         machine_info.as_ref().map(|info| info.release.as_str()),
@@ -322,15 +704,28 @@ fn make_converter(
         framehop::CacheNative::new(),
         None,
         interpretation,
-        conversion_props.reuse_threads,
-        conversion_props.fold_recursive_prefix,
-    )
+    );
+    converter.set_system_info(&SystemInfo::from_running_system(&env_deny_list));
+    if process_environment {
+        converter.capture_process_environments(env_deny_list);
+    }
+    if conversion_props.per_cpu_threads {
+        converter.set_cpu_topology(CpuTopology::from_sysfs());
+    }
+    converter
 }
 
+#[allow(clippy::too_many_arguments)]
 fn init_profiler(
     interval: Duration,
     pid: u32,
     attach_mode: AttachMode,
+    vsync: bool,
+    gpu: bool,
+    audio_xruns: bool,
+    lock_contention: bool,
+    off_cpu_reasons: bool,
+    ring_buffer: RingBufferConfig,
     converter: &mut Converter<
         framehop::UnwinderNative<MmapRangeOrVec, framehop::MayAllocateDuringUnwind>,
     >,
@@ -352,6 +747,7 @@ fn init_profiler(
         EventSource::HwCpuCycles,
         regs_mask,
         attach_mode,
+        ring_buffer,
     );
 
     if let Err(error) = &perf {
@@ -389,6 +785,7 @@ fn init_profiler(
                 EventSource::SwCpuClock,
                 regs_mask,
                 attach_mode,
+                ring_buffer,
             );
             match perf {
                 Ok(perf) => perf, // Success!
@@ -400,6 +797,118 @@ fn init_profiler(
         }
     };
 
+    // Each entry is a description, whether the tracepoints are only sampled in
+    // the profiled processes rather than system-wide, and the tracepoints.
+    let mut tracepoints = Vec::new();
+    if vsync {
+        tracepoints.push((
+            "vsync events",
+            false,
+            vec![(
+                "drm",
+                "drm_vblank_event".to_string(),
+                KnownEvent::DrmVblankEvent,
+            )],
+        ));
+    }
+    if gpu {
+        // Use the tracepoints of each driver interface whose tracepoints all exist.
+        let events: Vec<_> = ["gpu_scheduler", "i915"]
+            .iter()
+            .filter_map(|driver_system| {
+                GPU_JOB_TRACEPOINTS
+                    .iter()
+                    .filter(|(system, _, _)| system == driver_system)
+                    .map(|(system, name, phase)| {
+                        let format =
+                            GpuJobEventFormat::new(*phase, &tracepoint_format(system, name)?)?;
+                        Some((*system, name.to_string(), KnownEvent::GpuJob(format)))
+                    })
+                    .collect::<Option<Vec<_>>>()
+            })
+            .flatten()
+            .collect();
+        if events.is_empty() {
+            eprintln!("Could not record GPU jobs: Neither the gpu_scheduler tracepoints nor the i915 low-level tracepoints were found.");
+        } else {
+            tracepoints.push(("GPU jobs", false, events));
+        }
+    }
+    if audio_xruns {
+        tracepoints.push((
+            "audio xruns",
+            false,
+            vec![("snd_pcm", "xrun".to_string(), KnownEvent::SndPcmXrun)],
+        ));
+    }
+    if lock_contention {
+        tracepoints.push((
+            "lock contention",
+            true,
+            vec![
+                (
+                    "syscalls",
+                    "sys_enter_futex".to_string(),
+                    KnownEvent::FutexEnter,
+                ),
+                (
+                    "syscalls",
+                    "sys_exit_futex".to_string(),
+                    KnownEvent::FutexExit,
+                ),
+            ],
+        ));
+    }
+    if off_cpu_reasons {
+        // Not every architecture has all of these syscalls, so the missing ones
+        // are skipped rather than treated as an error. Without
+        // --lock-contention, futex waits only count as off-CPU time waiting for
+        // a lock, and don't get lock wait markers.
+        let futex = (!lock_contention).then_some(("futex", OffCpuReason::Lock));
+        let events = BLOCKING_SYSCALLS
+            .iter()
+            .copied()
+            .chain(futex)
+            .flat_map(|(syscall, reason)| {
+                [
+                    (
+                        format!("sys_enter_{syscall}"),
+                        KnownEvent::SyscallEnter(reason),
+                    ),
+                    (format!("sys_exit_{syscall}"), KnownEvent::SyscallExit),
+                ]
+            })
+            .filter(|(name, _)| tracepoint_id("syscalls", name).is_some())
+            .map(|(name, event)| ("syscalls", name, event))
+            .collect();
+        tracepoints.push(("off-CPU reasons", true, events));
+    }
+    for (description, for_profiled_processes, events) in tracepoints {
+        let result = events.into_iter().try_for_each(|(category, name, event)| {
+            let id = tracepoint_id(category, &name).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("the {category}:{name} tracepoint was not found"),
+                )
+            })?;
+            // Futex waits are only useful with the stack of the waiting thread.
+            let with_user_stacks = matches!(event, KnownEvent::FutexEnter);
+            if for_profiled_processes {
+                perf.open_process_tracepoint(pid, id, with_user_stacks, attach_mode)?;
+            } else {
+                perf.open_system_wide_tracepoint(id, with_user_stacks)?;
+            }
+            converter.register_tracepoint(id, event);
+            Ok::<(), std::io::Error>(())
+        });
+        if let Err(error) = result {
+            eprintln!("Could not record {description}: {error}");
+            if !for_profiled_processes {
+                eprintln!("System-wide tracepoints usually require running samply as root, or setting /proc/sys/kernel/perf_event_paranoid to -1.");
+            }
+        }
+    }
+
     // TODO: Gather threads / processes recursively, here and in PerfGroup setup.
     for entry in std::fs::read_dir(format!("/proc/{pid}/task"))
         .unwrap()
@@ -414,6 +923,12 @@ fn init_profiler(
         }
     }
 
+    // A launched process gets its environment when it execs. A process which
+    // we attach to has been running for a while, so get it now.
+    if attach_mode == AttachMode::StopAttachEnableResume {
+        converter.add_process_environment(pid as i32);
+    }
+
     let maps = read_string_lossy(format!("/proc/{pid}/maps")).expect("couldn't read proc maps");
     let maps = proc_maps::parse(&maps);
 
@@ -474,27 +989,176 @@ enum SamplerRequest {
     StopProfilingOncePerfEventsExhausted,
 }
 
+/// Sources of markers which the launched process sends while it's running.
+struct LiveMarkerSources {
+    marker_socket: Option<MarkerSocket>,
+    otlp_receiver: Option<OtlpReceiver>,
+    output_capture: Option<OutputCapture>,
+    /// The pid of the launched process. Spans which don't say which process
+    /// they're from are put on this process's main thread.
+    root_pid: i32,
+    /// Stops the recording once a slow span arrives, for `--trigger latency>...`.
+    latency_trigger: Option<LatencyTrigger>,
+}
+
+impl LiveMarkerSources {
+    fn drain_into(
+        &self,
+        converter: &mut Converter<
+            framehop::UnwinderNative<MmapRangeOrVec, framehop::MayAllocateDuringUnwind>,
+        >,
+    ) {
+        if let Some(marker_socket) = &self.marker_socket {
+            for message in marker_socket.try_iter() {
+                converter.handle_marker_socket_line(message.pid, message.tid, &message.line);
+            }
+        }
+        if let Some(otlp_receiver) = &self.otlp_receiver {
+            for span in otlp_receiver.try_iter() {
+                if let Some(latency_trigger) = &self.latency_trigger {
+                    latency_trigger.check_span(&span);
+                }
+                let pid = span.pid.unwrap_or(self.root_pid);
+                let tid = span.tid.unwrap_or(pid);
+                converter.add_live_marker_span(
+                    pid,
+                    tid,
+                    span.start_time_ns,
+                    span.end_time_ns,
+                    span.name,
+                );
+            }
+        }
+        if let Some(output_capture) = &self.output_capture {
+            for line in output_capture.try_iter() {
+                converter.add_output_line_marker(
+                    line.pid,
+                    line.time_ns,
+                    line.stream,
+                    line.text,
+                    line.tee_line,
+                );
+            }
+        }
+    }
+}
+
+/// The number of passes over the ring buffers whose records can be waiting for
+/// the converting thread. Each pass copies at most the size of all ring buffers.
+const MAX_PENDING_DRAIN_ROUNDS: usize = 16;
+
+#[allow(clippy::too_many_arguments)]
 fn run_profiler(
-    mut perf: PerfGroup,
+    perf: PerfGroup,
     mut converter: Converter<
         framehop::UnwinderNative<MmapRangeOrVec, framehop::MayAllocateDuringUnwind>,
     >,
     output_filename: &Path,
-    _time_limit: Option<Duration>,
+    split_processes: bool,
+    time_limit: Option<Duration>,
     more_processes_request_receiver: Receiver<SamplerRequest>,
     more_processes_reply_sender: Sender<bool>,
     stop: Arc<AtomicBool>,
+    live_markers: Option<Arc<LiveMarkerSources>>,
+    iterations: Option<Arc<Mutex<Iterations>>>,
+    trigger_fired: Option<Arc<AtomicBool>>,
 ) {
     // eprintln!("Running...");
 
+    // The ring buffers are drained on their own thread, so that they don't fill
+    // up while we're busy converting samples. If the conversion falls behind
+    // for too long, the drain thread waits instead of buffering an unbounded
+    // amount of records, and the kernel drops new records once the ring
+    // buffers are full.
+    let mut merger = RecordMerger::new(perf.overwrites_samples());
+    let (batch_sender, batch_receiver) = crossbeam_channel::bounded(MAX_PENDING_DRAIN_ROUNDS);
+    let deadline = time_limit.map(|time_limit| Instant::now() + time_limit);
+    let drain_thread = thread::spawn(move || {
+        drain_perf_events(
+            perf,
+            more_processes_request_receiver,
+            more_processes_reply_sender,
+            stop,
+            deadline,
+            batch_sender,
+        )
+    });
+
+    let mut stats = RecordStats::default();
+    loop {
+        match batch_receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(batches) => merger.add_round(batches, &mut |record| {
+                handle_record(&mut converter, &mut stats, record)
+            }),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if let Some(live_markers) = &live_markers {
+            live_markers.drain_into(&mut converter);
+        }
+    }
+    drain_thread.join().expect("couldn't join drain thread");
+    if trigger_fired.map_or(false, |fired| !fired.load(Ordering::SeqCst)) {
+        eprintln!("The trigger condition wasn't met, so the recording was discarded.");
+        return;
+    }
+    merger.finish(&mut |record| handle_record(&mut converter, &mut stats, record));
+
+    // Pick up any markers which arrived after the loop above last drained them.
+    if let Some(live_markers) = &live_markers {
+        live_markers.drain_into(&mut converter);
+    }
+
+    if stats.lost_events > 0 {
+        eprintln!("Lost {} events.", stats.lost_events);
+    }
+
+    let mut profile = converter.finish();
+    let kept_processes =
+        iterations.and_then(|iterations| iterations.lock().unwrap().apply_to_profile(&mut profile));
+
+    if split_processes {
+        match &kept_processes {
+            Some(kept_processes) => {
+                write_process_profiles(&profile, output_filename, kept_processes)
+            }
+            None => write_split_profiles(&profile, output_filename, &[]),
+        }
+        .expect("Couldn't write the per-process profiles");
+        return;
+    }
+
+    let output_file = File::create(output_filename).unwrap();
+    let writer = BufWriter::new(output_file);
+    match &kept_processes {
+        Some(kept_processes) => {
+            serde_json::to_writer(writer, &profile.process_subset(kept_processes))
+        }
+        None => serde_json::to_writer(writer, &profile),
+    }
+    .expect("Couldn't write JSON");
+}
+
+/// Reads the ring buffers until profiling stops, or until the deadline of
+/// --duration, and sends the copied records to the converting thread, one
+/// batch per ring buffer and pass.
+fn drain_perf_events(
+    mut perf: PerfGroup,
+    more_processes_request_receiver: Receiver<SamplerRequest>,
+    more_processes_reply_sender: Sender<bool>,
+    stop: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+    batch_sender: Sender<Vec<(RawFd, RecordBatch)>>,
+) {
     let mut should_stop_profiling_once_perf_events_exhausted = false;
-    let mut pending_lost_events = 0;
-    let mut total_lost_events = 0;
-    let mut last_timestamp = 0;
     loop {
         if stop.load(Ordering::SeqCst) {
             break;
         }
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            break;
+        }
 
         match more_processes_request_receiver.try_recv() {
             Ok(SamplerRequest::StartProfilingAnotherProcess(another_pid, attach_mode)) => {
@@ -542,79 +1206,122 @@ fn run_profiler(
             break;
         }
 
-        perf.consume_events(&mut |event_ref| {
-            let record = event_ref.get();
-            let parsed_record = record.parse().unwrap();
-            // debug!("Recording parsed_record: {:#?}", parsed_record);
+        if batch_sender.send(perf.drain()).is_err() {
+            return;
+        }
 
-            if let Some(timestamp) = record.timestamp() {
-                if timestamp < last_timestamp {
-                    // eprintln!(
-                    //     "bad timestamp ordering; {timestamp} is earlier but arrived after {last_timestamp}"
-                    // );
-                }
-                last_timestamp = timestamp;
-            }
+        perf.wait();
+    }
 
-            match parsed_record {
-                EventRecord::Sample(e) => {
-                    converter.handle_main_event_sample::<ConvertRegsNative>(&e);
-                    /*
-                    } else if interpretation.sched_switch_attr_index == Some(attr_index) {
-                        converter.handle_sched_switch_sample::<C>(e);
-                    }*/
-                }
-                EventRecord::Fork(e) => {
-                    converter.handle_fork(e);
-                }
-                EventRecord::Comm(e) => {
-                    converter.handle_comm(e, record.timestamp());
-                }
-                EventRecord::Exit(e) => {
-                    converter.handle_exit(e);
-                }
-                EventRecord::Mmap(e) => {
-                    converter.handle_mmap(e, last_timestamp);
-                }
-                EventRecord::Mmap2(e) => {
-                    converter.handle_mmap2(e, last_timestamp);
-                }
-                EventRecord::ContextSwitch(e) => {
-                    let common = match record.common_data() {
-                        Ok(common) => common,
-                        Err(_) => return,
-                    };
-                    converter.handle_context_switch(e, common);
-                }
-                EventRecord::Lost(event) => {
-                    pending_lost_events += event.count;
-                    total_lost_events += event.count;
-                    return;
-                }
-                _ => {}
-            }
+    let _ = batch_sender.send(perf.drain());
+    let _ = batch_sender.send(perf.drain_overwritten());
+}
 
-            if pending_lost_events > 0 {
-                // eprintln!("Pending lost events: {pending_lost_events}");
-                pending_lost_events = 0;
-            }
-        });
+#[derive(Debug, Default)]
+struct RecordStats {
+    last_timestamp: u64,
+    lost_events: u64,
+}
 
-        perf.wait();
+fn handle_record(
+    converter: &mut Converter<
+        framehop::UnwinderNative<MmapRangeOrVec, framehop::MayAllocateDuringUnwind>,
+    >,
+    stats: &mut RecordStats,
+    record: RawEventRecord,
+) {
+    let parsed_record = record.parse().unwrap();
+    // debug!("Recording parsed_record: {:#?}", parsed_record);
+
+    if let Some(timestamp) = record.timestamp() {
+        if timestamp < stats.last_timestamp {
+            // eprintln!(
+            //     "bad timestamp ordering; {timestamp} is earlier but arrived after {}",
+            //     stats.last_timestamp
+            // );
+        }
+        stats.last_timestamp = timestamp;
     }
 
-    if total_lost_events > 0 {
-        eprintln!("Lost {total_lost_events} events.");
+    match parsed_record {
+        EventRecord::Sample(e) if e.raw.is_some() => {
+            // Only the tracepoint events (see init_profiler) have raw data.
+            converter.handle_tracepoint_sample::<ConvertRegsNative>(&e);
+        }
+        EventRecord::Sample(e) => {
+            converter.handle_main_event_sample::<ConvertRegsNative>(&e);
+            /*
+            } else if interpretation.sched_switch_attr_index == Some(attr_index) {
+                converter.handle_sched_switch_sample::<C>(e);
+            }*/
+        }
+        EventRecord::Fork(e) => {
+            converter.handle_fork(e);
+        }
+        EventRecord::Comm(e) => {
+            converter.handle_comm(e, record.timestamp());
+        }
+        EventRecord::Exit(e) => {
+            converter.handle_exit(e);
+        }
+        EventRecord::Mmap(e) => {
+            converter.handle_mmap(e, stats.last_timestamp);
+        }
+        EventRecord::Mmap2(e) => {
+            converter.handle_mmap2(e, stats.last_timestamp);
+        }
+        EventRecord::ContextSwitch(e) => {
+            if let Ok(common) = record.common_data() {
+                converter.handle_context_switch(e, common);
+            }
+        }
+        EventRecord::Lost(event) => {
+            stats.lost_events += event.count;
+        }
+        _ => {}
     }
-
-    let profile = converter.finish();
-
-    let output_file = File::create(output_filename).unwrap();
-    let writer = BufWriter::new(output_file);
-    serde_json::to_writer(writer, &profile).expect("Couldn't write JSON");
 }
 
 pub fn read_string_lossy<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
     let data = std::fs::read(path)?;
     Ok(String::from_utf8_lossy(&data).into_owned())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn launched_process_after_profiler_stopped() {
+        // The drain thread has exited, for example because --duration elapsed.
+        let (request_sender, request_receiver) = crossbeam_channel::bounded(2);
+        let (reply_sender, reply_receiver) = crossbeam_channel::bounded::<bool>(2);
+        drop(request_receiver);
+        drop(reply_sender);
+        assert!(!request_profiling_of_launched_process(
+            &request_sender,
+            &reply_receiver,
+            1234
+        ));
+    }
+
+    #[test]
+    fn launched_process_which_could_not_be_attached_to() {
+        let (request_sender, request_receiver) = crossbeam_channel::bounded(2);
+        let (reply_sender, reply_receiver) = crossbeam_channel::bounded(2);
+        let sampler = thread::spawn(move || {
+            let request = request_receiver.recv().unwrap();
+            assert!(matches!(
+                request,
+                SamplerRequest::StartProfilingAnotherProcess(1234, _)
+            ));
+            reply_sender.send(false).unwrap();
+        });
+        assert!(!request_profiling_of_launched_process(
+            &request_sender,
+            &reply_receiver,
+            1234
+        ));
+        sampler.join().unwrap();
+    }
+}
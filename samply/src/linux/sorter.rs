@@ -60,17 +60,6 @@ impl<G: Clone + Ord, K: Ord, V> EventSorter<G, K, V> {
         }
     }
 
-    /// Check if there are buffered events.
-    ///
-    /// Even when [`EventSorter::pop`] returns `None`, there may still be events buffered that will
-    /// be released when additional rounds are read.
-    ///
-    /// This helper allows to check if additional rounds should be read before going into a wait
-    /// state.
-    pub fn has_more(&self) -> bool {
-        !self.heap.is_empty()
-    }
-
     /// Begin a new round.
     ///
     /// This should be called when the group with the largest identifier has been read and returning
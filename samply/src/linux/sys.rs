@@ -48,6 +48,7 @@ pub const PERF_ATTR_FLAG_MMAP2: u64 = flag!(23);
 pub const PERF_ATTR_FLAG_COMM_EXEC: u64 = flag!(24);
 pub const PERF_ATTR_FLAG_USE_CLOCKID: u64 = flag!(25);
 pub const PERF_ATTR_FLAG_CONTEX_SWITCH: u64 = flag!(26);
+pub const PERF_ATTR_FLAG_WRITE_BACKWARD: u64 = flag!(27);
 
 pub const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
 pub const PERF_COUNT_HW_REF_CPU_CYCLES: u64 = 9;
@@ -221,6 +222,7 @@ mod ioctl {
         pub const IOC_SIZEBITS: c_ulong = 14;
         pub const IOC_DIRBITS: c_ulong = 2;
         pub const IOC_NONE: c_ulong = 0;
+        pub const IOC_WRITE: c_ulong = 1;
     }
 
     #[cfg(any(
@@ -235,6 +237,7 @@ mod ioctl {
         pub const IOC_SIZEBITS: c_ulong = 13;
         pub const IOC_DIRBITS: c_ulong = 3;
         pub const IOC_NONE: c_ulong = 1;
+        pub const IOC_WRITE: c_ulong = 4;
     }
 
     pub use self::arch::*;
@@ -262,8 +265,16 @@ macro_rules! io {
     };
 }
 
+macro_rules! iow {
+    ($kind:expr, $nr:expr, $size:expr) => {
+        ioc!(ioctl::IOC_WRITE, $kind, $nr, $size)
+    };
+}
+
 pub const PERF_EVENT_IOC_ENABLE: c_ulong = io!(b'$', 0);
 pub const PERF_EVENT_IOC_DISABLE: c_ulong = io!(b'$', 1);
+pub const PERF_EVENT_IOC_SET_OUTPUT: c_ulong = io!(b'$', 5);
+pub const PERF_EVENT_IOC_PAUSE_OUTPUT: c_ulong = iow!(b'$', 9, 4);
 
 #[repr(C)]
 pub struct PerfEventAttr {
@@ -0,0 +1,156 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::otlp_receiver::OtlpSpan;
+
+/// How often the CPU usage is measured.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Measures the CPU usage of the process `pid` once per second, until it has
+/// been above `cpu_percent` for `sustained_for` or `stop` is set. When the
+/// trigger fires, `fired` and `stop` are set, so that recording stops and the
+/// profile is saved.
+pub fn watch_cpu_usage(
+    pid: u32,
+    cpu_percent_threshold: f64,
+    sustained_for: Duration,
+    stop: &AtomicBool,
+    fired: &AtomicBool,
+) {
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+    let Some(mut cpu_ticks) = process_cpu_ticks(pid) else {
+        return;
+    };
+    let mut last_check = Instant::now();
+    let mut above_threshold_since = None;
+    while !stop.load(Ordering::SeqCst) {
+        thread::sleep(CHECK_INTERVAL);
+        // The process has exited if its stat file is gone.
+        let Some(new_cpu_ticks) = process_cpu_ticks(pid) else {
+            return;
+        };
+        let now = Instant::now();
+        let interval_start = last_check;
+        let cpu_seconds = new_cpu_ticks.saturating_sub(cpu_ticks) as f64 / ticks_per_second;
+        let cpu_percent = cpu_seconds / (now - interval_start).as_secs_f64() * 100.0;
+        cpu_ticks = new_cpu_ticks;
+        last_check = now;
+
+        if cpu_percent <= cpu_percent_threshold {
+            above_threshold_since = None;
+            continue;
+        }
+        // The usage over the first interval counts, too.
+        let since = *above_threshold_since.get_or_insert(interval_start);
+        if now - since >= sustained_for {
+            eprintln!(
+                "The CPU usage of process {pid} was above {}% for {:.0} seconds, saving the recording.",
+                cpu_percent_threshold,
+                (now - since).as_secs_f64()
+            );
+            fired.store(true, Ordering::SeqCst);
+            stop.store(true, Ordering::SeqCst);
+            return;
+        }
+    }
+}
+
+/// Returns the user and system CPU time of the process, with all its threads,
+/// in clock ticks.
+fn process_cpu_ticks(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name can contain spaces and parentheses, so skip past the
+    // last ')'. The fields after it start with field 3, "state".
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// Fires once the recorded process reports an OpenTelemetry span which took
+/// longer than the threshold. The spans are checked as they arrive from the
+/// OTLP receiver, while recording.
+pub struct LatencyTrigger {
+    threshold: Duration,
+    span_name: Option<String>,
+    stop: Arc<AtomicBool>,
+    fired: Arc<AtomicBool>,
+}
+
+impl LatencyTrigger {
+    pub fn new(
+        threshold: Duration,
+        span_name: Option<String>,
+        stop: Arc<AtomicBool>,
+        fired: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            threshold,
+            span_name,
+            stop,
+            fired,
+        }
+    }
+
+    /// Sets `fired` and `stop` if `span` is slow enough, so that recording
+    /// stops and the profile is saved. Only the first slow span is reported.
+    pub fn check_span(&self, span: &OtlpSpan) {
+        if self.fired.load(Ordering::SeqCst) || !self.matches(span) {
+            return;
+        }
+        let duration = Duration::from_nanos(span.end_time_ns.saturating_sub(span.start_time_ns));
+        eprintln!(
+            "The span \"{}\" took {:.1} ms, which is longer than {:.1} ms, saving the recording.",
+            span.name,
+            duration.as_secs_f64() * 1000.0,
+            self.threshold.as_secs_f64() * 1000.0
+        );
+        self.fired.store(true, Ordering::SeqCst);
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    fn matches(&self, span: &OtlpSpan) -> bool {
+        if let Some(span_name) = &self.span_name {
+            if &span.name != span_name {
+                return false;
+            }
+        }
+        span.end_time_ns.saturating_sub(span.start_time_ns) > self.threshold.as_nanos() as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn span(name: &str, duration: Duration) -> OtlpSpan {
+        OtlpSpan {
+            pid: None,
+            tid: None,
+            name: name.to_string(),
+            start_time_ns: 1_000_000_000,
+            end_time_ns: 1_000_000_000 + duration.as_nanos() as u64,
+        }
+    }
+
+    #[test]
+    fn latency_trigger() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let fired = Arc::new(AtomicBool::new(false));
+        let trigger = LatencyTrigger::new(
+            Duration::from_millis(100),
+            Some("request".to_string()),
+            stop.clone(),
+            fired.clone(),
+        );
+        trigger.check_span(&span("request", Duration::from_millis(100)));
+        trigger.check_span(&span("startup", Duration::from_secs(2)));
+        assert!(!fired.load(Ordering::SeqCst));
+        assert!(!stop.load(Ordering::SeqCst));
+        trigger.check_span(&span("request", Duration::from_millis(101)));
+        assert!(fired.load(Ordering::SeqCst));
+        assert!(stop.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,180 @@
+use byteorder::ByteOrder;
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use linux_perf_data::linux_perf_event_reader;
+use linux_perf_data::Endianness;
+use serde_json::json;
+
+use linux_perf_event_reader::RawData;
+
+use std::fmt::Debug;
+
+/// ```text
+/// # cat /sys/kernel/tracing/events/snd_pcm/xrun/format
+/// name: xrun
+/// ID: 1349
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:snd_pcm_uframes_t period_size;    offset:8;       size:8; signed:0;
+///         field:snd_pcm_uframes_t buffer_size;    offset:16;      size:8; signed:0;
+///         field:snd_pcm_uframes_t old_hw_ptr;     offset:24;      size:8; signed:0;
+///         field:snd_pcm_uframes_t hw_ptr_base;    offset:32;      size:8; signed:0;
+///         field:unsigned int card;        offset:40;      size:4; signed:0;
+///         field:unsigned int device;      offset:44;      size:4; signed:0;
+///         field:unsigned int number;      offset:48;      size:4; signed:0;
+///         field:unsigned int stream;      offset:52;      size:4; signed:0;
+///
+/// print fmt: "pcmC%dD%d%s/sub%d: XRUN: old=%lu, base=%lu, period=%lu, buf=%lu", ...
+/// ```
+///
+/// Sound servers like PipeWire and PulseAudio drive the ALSA devices, so their
+/// device-level xruns show up here too.
+#[derive(Debug)]
+pub struct SndPcmXrunEvent {
+    pub period_size: u64,
+    pub buffer_size: u64,
+    pub card: u32,
+    pub device: u32,
+    pub subdevice: u32,
+    pub is_capture: bool,
+}
+
+impl SndPcmXrunEvent {
+    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
+        data.skip(8)?;
+        let period_size = data.read_u64::<O>()?;
+        let buffer_size = data.read_u64::<O>()?;
+        data.skip(16)?;
+        let card = data.read_u32::<O>()?;
+        let device = data.read_u32::<O>()?;
+        let subdevice = data.read_u32::<O>()?;
+        let stream = data.read_u32::<O>()?;
+        Ok(SndPcmXrunEvent {
+            period_size,
+            buffer_size,
+            card,
+            device,
+            subdevice,
+            is_capture: stream == 1,
+        })
+    }
+
+    /// The name of the PCM device, like its node in /dev/snd, e.g. "pcmC0D0p".
+    pub fn pcm_name(&self) -> String {
+        let direction = if self.is_capture { 'c' } else { 'p' };
+        format!("pcmC{}D{}{direction}", self.card, self.device)
+    }
+}
+
+/// An underrun of a playback device or an overrun of a capture device, i.e.
+/// an audible glitch.
+#[derive(Debug, Clone)]
+pub struct AudioXrunMarker {
+    pub pcm: String,
+    pub subdevice: u32,
+    pub is_capture: bool,
+    pub period_size: u64,
+    pub buffer_size: u64,
+}
+
+impl ProfilerMarker for AudioXrunMarker {
+    const MARKER_TYPE_NAME: &'static str = "AudioXrun";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "pcm": self.pcm,
+            "subdevice": self.subdevice,
+            "kind": if self.is_capture { "overrun" } else { "underrun" },
+            "periodSize": self.period_size,
+            "bufferSize": self.buffer_size,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.kind}"),
+            tooltip_label: Some("Audio {marker.data.kind} on {marker.data.pcm}"),
+            table_label: Some(
+                "{marker.data.kind} on {marker.data.pcm}, sub {marker.data.subdevice}",
+            ),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "pcm",
+                    label: "PCM device",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "subdevice",
+                    label: "Subdevice",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "kind",
+                    label: "Kind",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "periodSize",
+                    label: "Period size (frames)",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "bufferSize",
+                    label: "Buffer size (frames)",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted when the snd_pcm:xrun tracepoint is hit, i.e. when an ALSA playback buffer ran empty or a capture buffer overflowed, which causes an audible glitch.",
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SndPcmXrunEvent;
+
+    use linux_perf_data::linux_perf_event_reader::RawData;
+    use linux_perf_data::Endianness;
+
+    #[test]
+    fn parse_xrun_event() {
+        let mut xrun = vec![0u8; 56];
+        xrun[8..16].copy_from_slice(&256u64.to_le_bytes());
+        xrun[16..24].copy_from_slice(&1024u64.to_le_bytes());
+        xrun[40..44].copy_from_slice(&1u32.to_le_bytes());
+        xrun[44..48].copy_from_slice(&3u32.to_le_bytes());
+        let xrun =
+            SndPcmXrunEvent::parse(RawData::Single(&xrun), Endianness::LittleEndian).unwrap();
+        assert_eq!(xrun.period_size, 256);
+        assert_eq!(xrun.buffer_size, 1024);
+        assert_eq!(xrun.pcm_name(), "pcmC1D3p");
+    }
+}
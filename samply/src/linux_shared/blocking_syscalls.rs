@@ -0,0 +1,82 @@
+use crate::shared::off_cpu_reason::OffCpuReason;
+
+/// The syscalls which are traced to find out why threads are off-CPU, and the
+/// reason they stand for. Each one has a `syscalls:sys_enter_<name>` and a
+/// `syscalls:sys_exit_<name>` tracepoint. Not every architecture has all of
+/// them, e.g. arm64 only has epoll_pwait.
+///
+/// futex isn't in this list. Its waits are classified as [`OffCpuReason::Lock`],
+/// either by the lock contention tracing or as an extra syscall when recording.
+pub const BLOCKING_SYSCALLS: &[(&str, OffCpuReason)] = &[
+    ("read", OffCpuReason::Io),
+    ("write", OffCpuReason::Io),
+    ("readv", OffCpuReason::Io),
+    ("writev", OffCpuReason::Io),
+    ("pread64", OffCpuReason::Io),
+    ("pwrite64", OffCpuReason::Io),
+    ("recvfrom", OffCpuReason::Io),
+    ("recvmsg", OffCpuReason::Io),
+    ("sendto", OffCpuReason::Io),
+    ("sendmsg", OffCpuReason::Io),
+    ("accept4", OffCpuReason::Io),
+    ("connect", OffCpuReason::Io),
+    ("fsync", OffCpuReason::Io),
+    ("fdatasync", OffCpuReason::Io),
+    ("io_uring_enter", OffCpuReason::Io),
+    ("io_getevents", OffCpuReason::Io),
+    ("epoll_wait", OffCpuReason::Polling),
+    ("epoll_pwait", OffCpuReason::Polling),
+    ("poll", OffCpuReason::Polling),
+    ("ppoll", OffCpuReason::Polling),
+    ("select", OffCpuReason::Polling),
+    ("pselect6", OffCpuReason::Polling),
+    ("nanosleep", OffCpuReason::Sleep),
+    ("clock_nanosleep", OffCpuReason::Sleep),
+];
+
+/// The reason for the syscall of a `syscalls:sys_enter_*` tracepoint name, or
+/// `None` if it's not a traced syscall.
+pub fn reason_for_sys_enter_event(event_name: &str) -> Option<OffCpuReason> {
+    let syscall = event_name.strip_prefix("syscalls:sys_enter_")?;
+    reason_for_syscall(syscall)
+}
+
+/// Whether this is the `syscalls:sys_exit_*` tracepoint of a traced syscall.
+pub fn is_sys_exit_event(event_name: &str) -> bool {
+    event_name
+        .strip_prefix("syscalls:sys_exit_")
+        .map_or(false, |syscall| reason_for_syscall(syscall).is_some())
+}
+
+fn reason_for_syscall(syscall: &str) -> Option<OffCpuReason> {
+    BLOCKING_SYSCALLS
+        .iter()
+        .find(|(name, _)| *name == syscall)
+        .map(|(_, reason)| *reason)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_sys_exit_event, reason_for_sys_enter_event};
+    use crate::shared::off_cpu_reason::OffCpuReason;
+
+    #[test]
+    fn syscall_event_names() {
+        assert_eq!(
+            reason_for_sys_enter_event("syscalls:sys_enter_epoll_wait"),
+            Some(OffCpuReason::Polling)
+        );
+        assert_eq!(
+            reason_for_sys_enter_event("syscalls:sys_enter_read"),
+            Some(OffCpuReason::Io)
+        );
+        assert_eq!(
+            reason_for_sys_enter_event("syscalls:sys_enter_clock_nanosleep"),
+            Some(OffCpuReason::Sleep)
+        );
+        assert_eq!(reason_for_sys_enter_event("syscalls:sys_enter_futex"), None);
+        assert_eq!(reason_for_sys_enter_event("syscalls:sys_exit_read"), None);
+        assert!(is_sys_exit_event("syscalls:sys_exit_read"));
+        assert!(!is_sys_exit_event("syscalls:sys_exit_mmap"));
+    }
+}
@@ -33,14 +33,20 @@
 /// Does the accumulated time cross an "off-cpu sampling" threshold?
 /// If yes, turn it into an off-cpu sampling group and consume a multiple of the interval.
 /// If no, don't emit any samples. The next sample's cpu delta will just be smaller.
+///
+/// With `consume_whole_duration`, a sampling group consumes all of the accumulated
+/// off-cpu time rather than a multiple of the interval, so that its duration is the
+/// actual time the thread was blocked since the previous group.
 pub struct ContextSwitchHandler {
     off_cpu_sampling_interval_ns: u64,
+    consume_whole_duration: bool,
 }
 
 impl ContextSwitchHandler {
-    pub fn new(off_cpu_sampling_interval_ns: u64) -> Self {
+    pub fn new(off_cpu_sampling_interval_ns: u64, consume_whole_duration: bool) -> Self {
         Self {
             off_cpu_sampling_interval_ns,
+            consume_whole_duration,
         }
     }
 
@@ -179,6 +185,27 @@ impl ContextSwitchHandler {
         off_cpu_sample
     }
 
+    /// Called at the end of the profile for threads which may still be off-cpu,
+    /// so that their off-cpu time up to `timestamp` isn't lost.
+    pub fn handle_end(
+        &self,
+        timestamp: u64,
+        thread: &mut ThreadContextSwitchData,
+    ) -> Option<OffCpuSampleGroup> {
+        let ThreadState::Off {
+            off_switch_timestamp,
+        } = thread.state
+        else {
+            return None;
+        };
+        let off_duration = timestamp.saturating_sub(off_switch_timestamp);
+        thread.off_cpu_duration_since_last_off_cpu_sample += off_duration;
+        thread.state = ThreadState::Off {
+            off_switch_timestamp: off_switch_timestamp.max(timestamp),
+        };
+        self.maybe_consume_off_cpu(timestamp, thread)
+    }
+
     fn maybe_consume_off_cpu(
         &self,
         timestamp: u64,
@@ -195,9 +222,9 @@ impl ContextSwitchHandler {
         let sample_count = thread.off_cpu_duration_since_last_off_cpu_sample / interval;
         debug_assert!(sample_count >= 1);
 
+        let accumulated_duration = thread.off_cpu_duration_since_last_off_cpu_sample;
         let consumed_duration = sample_count * interval;
-        let remaining_duration =
-            thread.off_cpu_duration_since_last_off_cpu_sample - consumed_duration;
+        let remaining_duration = accumulated_duration - consumed_duration;
 
         let begin_timestamp =
             timestamp - (thread.off_cpu_duration_since_last_off_cpu_sample - interval);
@@ -208,12 +235,18 @@ impl ContextSwitchHandler {
         );
 
         // Consume the consumed duration and save the leftover duration.
+        let (duration, remaining_duration) = if self.consume_whole_duration {
+            (accumulated_duration, 0)
+        } else {
+            (consumed_duration, remaining_duration)
+        };
         thread.off_cpu_duration_since_last_off_cpu_sample = remaining_duration;
 
         Some(OffCpuSampleGroup {
             begin_timestamp,
             end_timestamp,
             sample_count,
+            duration,
         })
     }
 
@@ -227,6 +260,8 @@ pub struct OffCpuSampleGroup {
     pub begin_timestamp: u64,
     pub end_timestamp: u64,
     pub sample_count: u64,
+    /// The off-cpu time which this group stands for.
+    pub duration: u64,
 }
 
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
@@ -269,7 +304,7 @@ mod test {
         //  v Off-cpu sample
 
         let mut thread = ThreadContextSwitchData::default();
-        let handler = ContextSwitchHandler::new(10);
+        let handler = ContextSwitchHandler::new(10, false);
         let s = handler.handle_switch_in(0, &mut thread);
         assert_eq!(s, None);
         handler.handle_switch_out(3, &mut thread);
@@ -292,7 +327,8 @@ mod test {
             Some(OffCpuSampleGroup {
                 begin_timestamp: 24,
                 end_timestamp: 24,
-                sample_count: 1
+                sample_count: 1,
+                duration: 10,
             })
         );
         let delta = handler.consume_cpu_delta(&mut thread);
@@ -304,7 +340,8 @@ mod test {
             Some(OffCpuSampleGroup {
                 begin_timestamp: 37,
                 end_timestamp: 47,
-                sample_count: 2
+                sample_count: 2,
+                duration: 20,
             })
         );
         let delta = handler.consume_cpu_delta(&mut thread);
@@ -318,4 +355,51 @@ mod test {
         assert_eq!(s, None);
         assert_eq!(delta, 10);
     }
+
+    #[test]
+    fn end_while_off_cpu() {
+        let mut thread = ThreadContextSwitchData::default();
+        let handler = ContextSwitchHandler::new(10, false);
+        handler.handle_switch_in(0, &mut thread);
+        handler.handle_switch_out(5, &mut thread);
+        let s = handler.handle_end(40, &mut thread);
+        assert_eq!(
+            s,
+            Some(OffCpuSampleGroup {
+                begin_timestamp: 15,
+                end_timestamp: 35,
+                sample_count: 3,
+                duration: 30,
+            })
+        );
+        // The remaining 5 units are kept, and aren't counted twice.
+        assert_eq!(handler.handle_end(40, &mut thread), None);
+        assert_eq!(
+            handler.handle_end(40, &mut ThreadContextSwitchData::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn consume_whole_duration() {
+        let mut thread = ThreadContextSwitchData::default();
+        let handler = ContextSwitchHandler::new(10, true);
+        handler.handle_switch_in(0, &mut thread);
+        handler.handle_switch_out(5, &mut thread);
+        // Too short for a sample, the 7 units are accumulated.
+        assert_eq!(handler.handle_switch_in(12, &mut thread), None);
+        handler.handle_switch_out(13, &mut thread);
+        assert_eq!(
+            handler.handle_switch_in(29, &mut thread),
+            Some(OffCpuSampleGroup {
+                begin_timestamp: 16,
+                end_timestamp: 26,
+                sample_count: 2,
+                duration: 23,
+            })
+        );
+        // Nothing is left over for the next group.
+        handler.handle_switch_out(30, &mut thread);
+        assert_eq!(handler.handle_switch_in(39, &mut thread), None);
+    }
 }
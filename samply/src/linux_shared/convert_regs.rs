@@ -1,5 +1,6 @@
-use framehop::aarch64::UnwindRegsAarch64;
+use framehop::aarch64::{PtrAuthMask, UnwindRegsAarch64};
 use framehop::x86_64::UnwindRegsX86_64;
+use framehop::FrameAddress;
 
 use linux_perf_data::linux_perf_event_reader;
 
@@ -9,21 +10,63 @@ use linux_perf_event_reader::constants::{
 };
 use linux_perf_event_reader::Regs;
 
+// linux-perf-event-reader doesn't have constants for these architectures.
+// From arch/riscv/include/uapi/asm/perf_regs.h:
+const PERF_REG_RISCV_PC: u64 = 0;
+const PERF_REG_RISCV_RA: u64 = 1;
+const PERF_REG_RISCV_SP: u64 = 2;
+const PERF_REG_RISCV_S0: u64 = 8;
+// From arch/powerpc/include/uapi/asm/perf_regs.h:
+const PERF_REG_POWERPC_R1: u64 = 1;
+const PERF_REG_POWERPC_NIP: u64 = 32;
+const PERF_REG_POWERPC_LINK: u64 = 36;
+
 pub trait ConvertRegs {
     type UnwindRegs;
-    fn convert_regs(regs: &Regs) -> (u64, u64, Self::UnwindRegs);
+
+    /// Returns the instruction pointer, the stack pointer and the registers
+    /// for framehop, or `None` if the sample is missing one of the registers.
+    /// `code_address_mask` comes from [`ConvertRegs::code_address_mask`].
+    fn convert_regs(regs: &Regs, code_address_mask: u64) -> Option<(u64, u64, Self::UnwindRegs)>;
+
     fn regs_mask() -> u64;
+
+    /// The mask which strips the high bits that aren't part of the address
+    /// from a user return address, for a process whose highest code mapping
+    /// ends at `highest_code_address`. Only arm64 uses these bits, for pointer
+    /// authentication codes and memory tags.
+    fn code_address_mask(_highest_code_address: u64) -> u64 {
+        u64::MAX
+    }
+
+    /// Whether user stacks are unwound with framehop. Architectures which
+    /// framehop doesn't support use [`ConvertRegs::walk_frame_chain`] instead.
+    fn has_framehop_unwinder() -> bool {
+        true
+    }
+
+    /// Unwinds the user stack by following the architecture's frame chain,
+    /// starting with the instruction pointer. `read_stack` reads from the
+    /// sampled stack bytes. Returns `Err` if the chain leaves the sampled bytes.
+    #[allow(clippy::result_unit_err)]
+    fn walk_frame_chain(
+        _regs: &Regs,
+        _read_stack: &mut impl FnMut(u64) -> Result<u64, ()>,
+        _push_frame: &mut impl FnMut(FrameAddress),
+    ) -> Result<(), ()> {
+        Ok(())
+    }
 }
 
 pub struct ConvertRegsX86_64;
 impl ConvertRegs for ConvertRegsX86_64 {
     type UnwindRegs = UnwindRegsX86_64;
-    fn convert_regs(regs: &Regs) -> (u64, u64, UnwindRegsX86_64) {
-        let ip = regs.get(PERF_REG_X86_IP).unwrap();
-        let sp = regs.get(PERF_REG_X86_SP).unwrap();
-        let bp = regs.get(PERF_REG_X86_BP).unwrap();
+    fn convert_regs(regs: &Regs, _code_address_mask: u64) -> Option<(u64, u64, UnwindRegsX86_64)> {
+        let ip = regs.get(PERF_REG_X86_IP)?;
+        let sp = regs.get(PERF_REG_X86_SP)?;
+        let bp = regs.get(PERF_REG_X86_BP)?;
         let regs = UnwindRegsX86_64::new(ip, sp, bp);
-        (ip, sp, regs)
+        Some((ip, sp, regs))
     }
 
     fn regs_mask() -> u64 {
@@ -34,13 +77,16 @@ impl ConvertRegs for ConvertRegsX86_64 {
 pub struct ConvertRegsAarch64;
 impl ConvertRegs for ConvertRegsAarch64 {
     type UnwindRegs = UnwindRegsAarch64;
-    fn convert_regs(regs: &Regs) -> (u64, u64, UnwindRegsAarch64) {
-        let ip = regs.get(PERF_REG_ARM64_PC).unwrap();
-        let lr = regs.get(PERF_REG_ARM64_LR).unwrap();
-        let sp = regs.get(PERF_REG_ARM64_SP).unwrap();
-        let fp = regs.get(PERF_REG_ARM64_X29).unwrap();
-        let regs = UnwindRegsAarch64::new(lr, sp, fp);
-        (ip, sp, regs)
+    fn convert_regs(regs: &Regs, code_address_mask: u64) -> Option<(u64, u64, UnwindRegsAarch64)> {
+        let ip = regs.get(PERF_REG_ARM64_PC)?;
+        let lr = regs.get(PERF_REG_ARM64_LR)?;
+        let sp = regs.get(PERF_REG_ARM64_SP)?;
+        let fp = regs.get(PERF_REG_ARM64_X29)?;
+        // framehop strips the mask from lr and from every return address it
+        // finds on the stack. The pc, sp and the frame pointers aren't signed.
+        let mask = PtrAuthMask(code_address_mask);
+        let regs = UnwindRegsAarch64::new_with_ptr_auth_mask(mask, lr, sp, fp);
+        Some((ip, sp, regs))
     }
 
     fn regs_mask() -> u64 {
@@ -49,4 +95,254 @@ impl ConvertRegs for ConvertRegsAarch64 {
             | 1 << PERF_REG_ARM64_SP
             | 1 << PERF_REG_ARM64_X29
     }
+
+    fn code_address_mask(highest_code_address: u64) -> u64 {
+        // User addresses have zeros above the process's virtual address size,
+        // e.g. above bit 47 with 48-bit addresses. Binaries built with
+        // -mbranch-protection=pac-ret store signed return addresses, with the
+        // pointer authentication code in those bits, and with top byte ignore,
+        // the top byte can also hold a memory tag.
+        if highest_code_address == 0 {
+            // No mappings yet, so the address size isn't known.
+            return u64::MAX;
+        }
+        PtrAuthMask::from_max_known_address(highest_code_address).0
+    }
+}
+
+/// riscv64 has no framehop unwinder, so its stacks are unwound by walking the
+/// frame pointer chain, like the kernel does for its callchains. The x86_64
+/// unwinder types are only used to fill in the converter's type parameters.
+///
+/// With frame pointers, s0 points to the caller's stack pointer, and the
+/// caller's s0 and the return address are stored just below it.
+pub struct ConvertRegsRiscv64;
+impl ConvertRegs for ConvertRegsRiscv64 {
+    type UnwindRegs = UnwindRegsX86_64;
+    fn convert_regs(regs: &Regs, _code_address_mask: u64) -> Option<(u64, u64, UnwindRegsX86_64)> {
+        let pc = regs.get(PERF_REG_RISCV_PC)?;
+        let sp = regs.get(PERF_REG_RISCV_SP)?;
+        let fp = regs.get(PERF_REG_RISCV_S0)?;
+        Some((pc, sp, UnwindRegsX86_64::new(pc, sp, fp)))
+    }
+
+    fn regs_mask() -> u64 {
+        1 << PERF_REG_RISCV_PC
+            | 1 << PERF_REG_RISCV_RA
+            | 1 << PERF_REG_RISCV_SP
+            | 1 << PERF_REG_RISCV_S0
+    }
+
+    fn has_framehop_unwinder() -> bool {
+        false
+    }
+
+    fn walk_frame_chain(
+        regs: &Regs,
+        read_stack: &mut impl FnMut(u64) -> Result<u64, ()>,
+        push_frame: &mut impl FnMut(FrameAddress),
+    ) -> Result<(), ()> {
+        let (Some(pc), Some(ra), Some(mut fp)) = (
+            regs.get(PERF_REG_RISCV_PC),
+            regs.get(PERF_REG_RISCV_RA),
+            regs.get(PERF_REG_RISCV_S0),
+        ) else {
+            return Ok(());
+        };
+        push_frame(FrameAddress::from_instruction_pointer(pc));
+        if !push_return_address(ra, push_frame) {
+            return Ok(());
+        }
+        let mut is_first_frame = true;
+        while fp != 0 && fp % 8 == 0 {
+            let saved_fp = read_stack(fp - 16)?;
+            let saved_ra = read_stack(fp - 8)?;
+            // If the sampled function has a frame, it saved ra there, so
+            // this is the return address which was already pushed.
+            let is_copy_of_ra = is_first_frame && saved_ra == ra;
+            if !is_copy_of_ra && !push_return_address(saved_ra, push_frame) {
+                return Ok(());
+            }
+            is_first_frame = false;
+            if saved_fp <= fp {
+                // The stack grows downwards, so the chain has to go up.
+                break;
+            }
+            fp = saved_fp;
+        }
+        Ok(())
+    }
+}
+
+/// ppc64 has no framehop unwinder either. Its ABI keeps a back chain: the word
+/// at r1 (the stack pointer) is the caller's r1, and each frame's link register
+/// save slot is 16 bytes above its back chain word. This works without frame
+/// pointers, so the kernel uses it for ppc64 callchains too.
+pub struct ConvertRegsPpc64;
+impl ConvertRegs for ConvertRegsPpc64 {
+    type UnwindRegs = UnwindRegsX86_64;
+    fn convert_regs(regs: &Regs, _code_address_mask: u64) -> Option<(u64, u64, UnwindRegsX86_64)> {
+        let nip = regs.get(PERF_REG_POWERPC_NIP)?;
+        let sp = regs.get(PERF_REG_POWERPC_R1)?;
+        Some((nip, sp, UnwindRegsX86_64::new(nip, sp, 0)))
+    }
+
+    fn regs_mask() -> u64 {
+        1 << PERF_REG_POWERPC_R1 | 1 << PERF_REG_POWERPC_NIP | 1 << PERF_REG_POWERPC_LINK
+    }
+
+    fn has_framehop_unwinder() -> bool {
+        false
+    }
+
+    fn walk_frame_chain(
+        regs: &Regs,
+        read_stack: &mut impl FnMut(u64) -> Result<u64, ()>,
+        push_frame: &mut impl FnMut(FrameAddress),
+    ) -> Result<(), ()> {
+        let (Some(nip), Some(lr), Some(mut sp)) = (
+            regs.get(PERF_REG_POWERPC_NIP),
+            regs.get(PERF_REG_POWERPC_LINK),
+            regs.get(PERF_REG_POWERPC_R1),
+        ) else {
+            return Ok(());
+        };
+        push_frame(FrameAddress::from_instruction_pointer(nip));
+        if !push_return_address(lr, push_frame) {
+            return Ok(());
+        }
+        // The first back chain word is the caller's r1. The return addresses
+        // are read from the save slots of the frames after that.
+        let mut level = 0;
+        while sp != 0 && sp % 8 == 0 {
+            let next_sp = read_stack(sp)?;
+            if level > 0 {
+                let saved_lr = read_stack(sp + 16)?;
+                // The sampled function saves the link register into its
+                // caller's frame, so this can be the one which was pushed.
+                let is_copy_of_lr = level == 1 && saved_lr == lr;
+                if !is_copy_of_lr && !push_return_address(saved_lr, push_frame) {
+                    return Ok(());
+                }
+            }
+            level += 1;
+            if next_sp <= sp {
+                break;
+            }
+            sp = next_sp;
+        }
+        Ok(())
+    }
+}
+
+/// Pushes a return address which was read from a register or from the stack.
+/// Returns false if it's zero, which marks the outermost frame.
+fn push_return_address(address: u64, push_frame: &mut impl FnMut(FrameAddress)) -> bool {
+    match FrameAddress::from_return_address(address) {
+        Some(frame) => {
+            push_frame(frame);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use byteorder::NativeEndian;
+    use linux_perf_data::linux_perf_event_reader::{RawData, RawDataU64, Regs};
+
+    fn regs_data(values: &[(u64, u64)]) -> (u64, Vec<u8>) {
+        let mut values = values.to_vec();
+        values.sort();
+        let mask = values.iter().fold(0, |mask, (reg, _)| mask | 1 << reg);
+        let data = values.iter().flat_map(|(_, v)| v.to_ne_bytes()).collect();
+        (mask, data)
+    }
+
+    fn walk<C: ConvertRegs>(regs: &Regs, stack: &[(u64, u64)]) -> (Vec<u64>, Result<(), ()>) {
+        let mut read_stack = |addr| {
+            stack
+                .iter()
+                .find(|(a, _)| *a == addr)
+                .map(|(_, v)| *v)
+                .ok_or(())
+        };
+        let mut frames = Vec::new();
+        let result = C::walk_frame_chain(regs, &mut read_stack, &mut |frame| {
+            frames.push(frame.address())
+        });
+        (frames, result)
+    }
+
+    #[test]
+    fn arm64_code_address_mask() {
+        let mask = ConvertRegsAarch64::code_address_mask(0xffff_8a3c_5000);
+        assert_eq!(mask, 0xffff_ffff_ffff);
+        // A pac-ret signed return address.
+        assert_eq!(0x002a_ffff_8a3c_1234 & mask, 0xffff_8a3c_1234);
+        // A return address with a memory tag in the top byte.
+        assert_eq!(0x0b00_aaaa_c001_0234 & mask, 0xaaaa_c001_0234);
+
+        assert_eq!(ConvertRegsAarch64::code_address_mask(0), u64::MAX);
+        assert_eq!(
+            ConvertRegsX86_64::code_address_mask(0x7fff_1234_0000),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn riscv64_frame_chain() {
+        let (mask, data) = regs_data(&[
+            (PERF_REG_RISCV_PC, 0x1_0100),
+            (PERF_REG_RISCV_RA, 0x1_0200),
+            (PERF_REG_RISCV_SP, 0x7ff0),
+            (PERF_REG_RISCV_S0, 0x8000),
+        ]);
+        let regs = Regs::new(
+            mask,
+            RawDataU64::from_raw_data::<NativeEndian>(RawData::Single(&data)),
+        );
+        let stack = [
+            // frame of the sampled function
+            (0x7ff0, 0x8040),
+            (0x7ff8, 0x1_0200),
+            // frame of its caller
+            (0x8030, 0),
+            (0x8038, 0x1_0300),
+        ];
+        let (frames, result) = walk::<ConvertRegsRiscv64>(&regs, &stack);
+        assert_eq!(result, Ok(()));
+        assert_eq!(frames, vec![0x1_0100, 0x1_0200, 0x1_0300]);
+    }
+
+    #[test]
+    fn ppc64_back_chain() {
+        let (mask, data) = regs_data(&[
+            (PERF_REG_POWERPC_R1, 0x7000),
+            (PERF_REG_POWERPC_NIP, 0x1_0100),
+            (PERF_REG_POWERPC_LINK, 0x1_0200),
+        ]);
+        let regs = Regs::new(
+            mask,
+            RawDataU64::from_raw_data::<NativeEndian>(RawData::Single(&data)),
+        );
+        let stack = [
+            (0x7000, 0x7100),
+            (0x7100, 0x7200),
+            (0x7110, 0x1_0300),
+            (0x7200, 0),
+            (0x7210, 0x1_0400),
+        ];
+        let (frames, result) = walk::<ConvertRegsPpc64>(&regs, &stack);
+        assert_eq!(result, Ok(()));
+        assert_eq!(frames, vec![0x1_0100, 0x1_0200, 0x1_0300, 0x1_0400]);
+
+        // A chain which leaves the sampled stack is truncated.
+        let (frames, result) = walk::<ConvertRegsPpc64>(&regs, &stack[..2]);
+        assert_eq!(result, Err(()));
+        assert_eq!(frames, vec![0x1_0100, 0x1_0200]);
+    }
 }
@@ -3,14 +3,15 @@ use debugid::{CodeId, DebugId};
 
 use framehop::{ExplicitModuleSectionInfo, FrameAddress, Module, Unwinder};
 use fxprof_processed_profile::{
-    CpuDelta, LibraryInfo, Profile, ReferenceTimestamp, SamplingInterval, ThreadHandle,
+    CategoryHandle, CpuDelta, LibraryHandle, LibraryInfo, MarkerTiming, ProcessHandle, Profile,
+    ReferenceTimestamp, SamplingInterval, ThreadHandle, Timestamp, WeightType,
 };
 use linux_perf_data::linux_perf_event_reader;
 use linux_perf_data::{DsoInfo, DsoKey, Endianness};
 use linux_perf_event_reader::constants::PERF_CONTEXT_MAX;
 use linux_perf_event_reader::{
     CommOrExecRecord, CommonData, ContextSwitchRecord, ForkOrExitRecord, Mmap2FileId, Mmap2Record,
-    MmapRecord, RawDataU64, SampleRecord,
+    MmapRecord, RawDataU64, SampleRecord, TaskWasPreempted,
 };
 use memmap2::Mmap;
 use object::pe::{ImageNtHeaders32, ImageNtHeaders64};
@@ -24,28 +25,48 @@ use wholesym::samply_symbols;
 use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use std::{ops::Range, path::Path};
 
+use super::audio_xrun::{AudioXrunMarker, SndPcmXrunEvent};
 use super::context_switch::{ContextSwitchHandler, OffCpuSampleGroup};
 use super::convert_regs::ConvertRegs;
-use super::event_interpretation::{EventInterpretation, OffCpuIndicator};
+use super::cpu_topology::CpuTopology;
+use super::cpus::Cpus;
+use super::event_interpretation::{EventInterpretation, KnownEvent, OffCpuIndicator};
+use super::futex::{PendingFutexWait, SysEnterFutex, SysExitFutex};
+use super::gpu_jobs::{GpuJobEventFormat, GpuJobMarker, GpuJobPhase, PendingGpuJob};
 use super::injected_jit_object::{correct_bad_perf_jit_so_file, jit_function_name};
 use super::kernel_symbols::{kernel_module_build_id, KernelSymbols};
+use super::log_marker::{LogMarker, OutputStream};
 use super::mmap_range_or_vec::MmapRangeOrVec;
+use super::process::Process;
 use super::processes::Processes;
 use super::rss_stat::{RssStat, MM_ANONPAGES, MM_FILEPAGES, MM_SHMEMPAGES, MM_SWAPENTS};
+use super::sched_switch::{CpuRunningMarker, SchedSwitch};
 use super::svma_file_range::compute_vma_bias;
+use super::system_info::SystemInfo;
+use super::truncated_data_marker::TruncatedDataMarker;
+use super::vblank_event::{DrmVblankEvent, VblankMarker};
+use super::wine::{
+    has_pe_extension, is_wine_loader_name, pdb_debug_name_and_path, pe_code_id,
+    pe_file_and_product_version, program_name_for_exe, PeModuleSectionInfo,
+};
 
 use crate::shared::jit_category_manager::JitCategoryManager;
+use crate::shared::library_load_marker::{LibraryEvent, LibraryLoadMarker};
+use crate::shared::marker_file::{process_marker_file_line, MarkerFileEntry, MarkerSpan};
+use crate::shared::off_cpu_reason::OffCpuReason;
 use crate::shared::process_sample_data::RssStatMember;
+use crate::shared::recording_props::{CodeAddressBits, ConversionProps, LatencyMarkerNames};
+use crate::shared::stack_stitching::StackStitchingRule;
 use crate::shared::timestamp_converter::TimestampConverter;
-use crate::shared::types::{StackFrame, StackMode};
+use crate::shared::types::{FastHashSet, StackFrame, StackMode};
 use crate::shared::unresolved_samples::{
     UnresolvedSamples, UnresolvedStackHandle, UnresolvedStacks,
 };
-use crate::shared::utils::open_file_with_fallback;
+use crate::shared::utils::{open_file_from_perf_build_id_cache, open_file_with_fallback};
 
 pub type BoxedProductNameGenerator = Box<dyn FnOnce(&str) -> String>;
 
@@ -73,7 +94,10 @@ where
     extra_binary_artifact_dir: Option<PathBuf>,
     context_switch_handler: ContextSwitchHandler,
     unresolved_stacks: UnresolvedStacks,
-    off_cpu_weight_per_sample: i32,
+    off_cpu_sample_options: OffCpuSampleOptions,
+    /// The weight of an on-CPU sample: 1, or the sampling interval in
+    /// microseconds with [`ConversionProps::blocked_time_weights`].
+    on_cpu_weight_per_sample: i32,
     off_cpu_indicator: Option<OffCpuIndicator>,
     event_names: Vec<String>,
     kernel_symbols: Option<KernelSymbols>,
@@ -84,11 +108,71 @@ where
 
     jit_category_manager: JitCategoryManager,
 
+    /// The pseudo-process which holds the display refresh tracks, and one thread
+    /// per CRTC. Created when the first vblank event is seen.
+    display_process: Option<ProcessHandle>,
+    vblank_threads: HashMap<i32, ThreadHandle>,
+
+    /// The pseudo-process which holds one thread per audio device which had
+    /// an xrun. Created when the first xrun is seen.
+    audio_process: Option<ProcessHandle>,
+    xrun_threads: HashMap<String, ThreadHandle>,
+
+    /// The pseudo-process which holds one thread per GPU ring. Created when
+    /// the first GPU job finishes.
+    gpu_process: Option<ProcessHandle>,
+    gpu_ring_threads: HashMap<String, ThreadHandle>,
+    /// The GPU jobs which haven't finished yet, by fence address.
+    pending_gpu_jobs: HashMap<[u64; 3], PendingGpuJob>,
+
     /// Whether repeated frames at the base of the stack should be folded
     /// into one frame.
     fold_recursive_prefix: bool,
+
+    /// See [`ConversionProps::frame_marker`].
+    frame_marker: Option<String>,
+    /// See [`ConversionProps::latency_markers`].
+    latency_markers: Option<LatencyMarkerNames>,
+    /// See [`ConversionProps::stack_stitching_rules`].
+    stack_stitching_rules: Vec<StackStitchingRule>,
+    /// See [`ConversionProps::code_address_bits`].
+    code_address_bits: Vec<CodeAddressBits>,
+    /// The libraries whose files have no debug info, for the warnings at the
+    /// end of the conversion.
+    libs_without_debug_info: FastHashSet<LibraryHandle>,
+
+    /// The per-CPU tracks, if `--per-cpu-threads` was specified.
+    cpus: Option<Cpus>,
+
+    /// See [`ConversionProps::wall_clock`].
+    wall_clock: bool,
+
+    /// The tracepoints which are sampled while recording, by tracepoint id.
+    /// See [`Self::handle_tracepoint_sample`].
+    tracepoint_events: HashMap<u64, KnownEvent>,
+
+    /// Whether blocking syscalls are traced, so that threads which go off-CPU
+    /// get an [`OffCpuReason`].
+    classify_off_cpu_time: bool,
+
+    /// If the environment of each process is recorded while recording, the
+    /// words which keep variables out of it in addition to the built-in ones.
+    /// See [`Self::capture_process_environments`].
+    process_environment_deny_list: Option<Vec<String>>,
+    /// The pid, launch time and attach time of the launched process, until the
+    /// process execs and gets a "Waiting for the profiler" marker.
+    pending_attach_span: Option<(i32, u64, u64)>,
+    /// See [`Self::set_launched_process_groups`].
+    launched_process_groups: Option<LaunchedProcessGroups>,
+    /// The processes which stand for the groups, by group name.
+    group_processes: HashMap<String, ProcessHandle>,
 }
 
+/// The group which each launched process is shown in, by pid, e.g. "warmup".
+/// While recording, the launching thread adds each launched process before it
+/// starts running.
+pub type LaunchedProcessGroups = Arc<Mutex<HashMap<i32, String>>>;
+
 const DEFAULT_OFF_CPU_SAMPLING_INTERVAL_NS: u64 = 1_000_000; // 1ms
 
 impl<U> Converter<U>
@@ -97,7 +181,7 @@ where
 {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
-        product: &str,
+        conversion_props: &ConversionProps,
         delayed_product_name_generator: Option<BoxedProductNameGenerator>,
         build_ids: HashMap<DsoKey, DsoInfo>,
         linux_version: Option<&str>,
@@ -106,15 +190,13 @@ where
         cache: U::Cache,
         extra_binary_artifact_dir: Option<&Path>,
         interpretation: EventInterpretation,
-        reuse_threads: bool,
-        fold_recursive_prefix: bool,
     ) -> Self {
         let interval = match interpretation.sampling_is_time_based {
             Some(nanos) => SamplingInterval::from_nanos(nanos),
             None => SamplingInterval::from_millis(1),
         };
-        let profile = Profile::new(
-            product,
+        let mut profile = Profile::new(
+            &conversion_props.profile_name,
             ReferenceTimestamp::from_system_time(SystemTime::now()),
             interval,
         );
@@ -123,6 +205,27 @@ where
                 Some(interval_ns) => (*interval_ns, 1),
                 None => (DEFAULT_OFF_CPU_SAMPLING_INTERVAL_NS, 0),
             };
+        let blocked_time_weights = match (
+            conversion_props.blocked_time_weights,
+            interpretation.sampling_is_time_based,
+        ) {
+            (true, Some(_)) => true,
+            (true, None) => {
+                eprintln!("Ignoring --blocked-time-weights, because the samples aren't taken at a fixed time interval.");
+                false
+            }
+            (false, _) => false,
+        };
+        let classify_off_cpu_time = interpretation
+            .known_event_indices
+            .values()
+            .any(|event| matches!(event, KnownEvent::SyscallEnter(_)));
+        let on_cpu_weight_per_sample = if blocked_time_weights {
+            profile.set_weight_type(WeightType::TracingMicroseconds);
+            duration_weight(off_cpu_sampling_interval_ns)
+        } else {
+            1
+        };
         let kernel_symbols = match KernelSymbols::new_for_running_kernel() {
             Ok(kernel_symbols) => Some(kernel_symbols),
             Err(err) => {
@@ -130,15 +233,32 @@ where
                 None
             }
         };
+        let spill_removed_processes = match tempfile::tempfile() {
+            Ok(file) => {
+                profile.set_spill_file(file);
+                true
+            }
+            Err(err) => {
+                eprintln!("Could not create a spill file for thread data: {err}");
+                false
+            }
+        };
         let timestamp_converter = TimestampConverter {
             reference_raw: first_sample_time,
             raw_to_ns_factor: 1,
         };
+        let cpus = conversion_props
+            .per_cpu_threads
+            .then(|| Cpus::new(timestamp_converter.convert_time(first_sample_time)));
 
         Self {
             profile,
             cache,
-            processes: Processes::new(reuse_threads),
+            processes: Processes::new(
+                conversion_props.reuse_threads,
+                spill_removed_processes,
+                conversion_props.aggregate_by_name,
+            ),
             timestamp_converter,
             current_sample_time: first_sample_time,
             build_ids,
@@ -146,39 +266,168 @@ where
             delayed_product_name_generator,
             linux_version: linux_version.map(ToOwned::to_owned),
             extra_binary_artifact_dir: extra_binary_artifact_dir.map(ToOwned::to_owned),
-            off_cpu_weight_per_sample,
-            context_switch_handler: ContextSwitchHandler::new(off_cpu_sampling_interval_ns),
+            off_cpu_sample_options: OffCpuSampleOptions {
+                weight_per_sample: off_cpu_weight_per_sample,
+                sample_per_interval: conversion_props.wall_clock,
+                blocked_time_weights,
+            },
+            on_cpu_weight_per_sample,
+            context_switch_handler: ContextSwitchHandler::new(
+                off_cpu_sampling_interval_ns,
+                blocked_time_weights,
+            ),
             unresolved_stacks: UnresolvedStacks::default(),
             off_cpu_indicator: interpretation.off_cpu_indicator,
             event_names: interpretation.event_names,
             kernel_symbols,
             suspected_pe_mappings: BTreeMap::new(),
             jit_category_manager: JitCategoryManager::new(),
-            fold_recursive_prefix,
+            display_process: None,
+            vblank_threads: HashMap::new(),
+            audio_process: None,
+            xrun_threads: HashMap::new(),
+            gpu_process: None,
+            gpu_ring_threads: HashMap::new(),
+            pending_gpu_jobs: HashMap::new(),
+            fold_recursive_prefix: conversion_props.fold_recursive_prefix,
+            frame_marker: conversion_props.frame_marker.clone(),
+            latency_markers: conversion_props.latency_markers.clone(),
+            stack_stitching_rules: conversion_props.stack_stitching_rules.clone(),
+            code_address_bits: conversion_props.code_address_bits.clone(),
+            libs_without_debug_info: FastHashSet::default(),
+            cpus,
+            wall_clock: conversion_props.wall_clock,
+            tracepoint_events: HashMap::new(),
+            classify_off_cpu_time,
+            process_environment_deny_list: None,
+            pending_attach_span: None,
+            launched_process_groups: None,
+            group_processes: HashMap::new(),
+        }
+    }
+
+    /// Used to group the per-CPU tracks for `--per-cpu-threads`.
+    pub fn set_cpu_topology(&mut self, topology: CpuTopology) {
+        if let Some(cpus) = &mut self.cpus {
+            cpus.set_topology(topology);
+        }
+    }
+
+    pub fn set_system_info(&mut self, system_info: &SystemInfo) {
+        system_info.apply_to_profile(&mut self.profile);
+    }
+
+    /// Record the environment of each process when it execs, or when we attach
+    /// to it, from /proc. Only used when recording on this machine, while the
+    /// processes are running.
+    pub fn capture_process_environments(&mut self, extra_deny_list: Vec<String>) {
+        self.process_environment_deny_list = Some(extra_deny_list);
+    }
+
+    /// Adds the environment of the running process `pid` to the profile's
+    /// meta info, in a section named after the process, if process
+    /// environments are captured. This is called while attaching to `pid`.
+    pub fn add_process_environment(&mut self, pid: i32) {
+        let Some(deny_list) = &self.process_environment_deny_list else {
+            return;
+        };
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(environment) = super::system_info::process_environment(pid, deny_list) {
+            self.add_process_environment_info(pid, environment);
+        }
+        #[cfg(not(any(target_os = "android", target_os = "linux")))]
+        let _ = (pid, deny_list);
+    }
+
+    /// Adds the environment of the process `pid`, which has exec'd `comm`, if
+    /// process environments are captured. The recorder sees the exec shortly
+    /// after it happened, so the process is usually still running; if it has
+    /// exec'd again or exited in the meantime, nothing is added.
+    fn add_exec_environment(&mut self, pid: i32, comm: &str) {
+        let Some(deny_list) = &self.process_environment_deny_list else {
+            return;
+        };
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(environment) = super::system_info::exec_environment(pid, comm, deny_list) {
+            self.add_process_environment_info(pid, environment);
+        }
+        #[cfg(not(any(target_os = "android", target_os = "linux")))]
+        let _ = (pid, comm, deny_list);
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn add_process_environment_info(&mut self, pid: i32, environment: Vec<(String, String)>) {
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let name = process.name.as_deref().unwrap_or("<unknown>");
+        let section = format!("Environment of {name} (pid {pid})");
+        for (name, value) in environment {
+            self.profile.add_extra_info(&section, &name, &value);
         }
     }
 
     pub fn finish(mut self) -> Profile {
+        if self.wall_clock {
+            self.flush_off_cpu_samples();
+        }
         let mut profile = self.profile;
-        self.processes.finish(
+        let stack_quality = self.processes.finish(
             &mut profile,
             &self.unresolved_stacks,
             &self.event_names,
             &mut self.jit_category_manager,
             &self.timestamp_converter,
+            self.frame_marker.as_deref(),
+            self.latency_markers.as_ref(),
+            &self.stack_stitching_rules,
         );
+        for warning in stack_quality.add_warnings(&mut profile, &self.libs_without_debug_info) {
+            eprintln!("Warning: {warning}");
+        }
+        if let Some(cpus) = self.cpus {
+            cpus.finish(&mut profile);
+        }
         profile
     }
 
+    /// Emit the off-CPU samples for threads which were still blocked when the
+    /// recording ended. Otherwise, a thread which blocks until the end would
+    /// have no samples for its last wait.
+    fn flush_off_cpu_samples(&mut self) {
+        let end_timestamp = self.current_sample_time;
+        for process in self.processes.iter_mut() {
+            for thread in process.threads.iter_mut() {
+                let off_cpu_sample = self
+                    .context_switch_handler
+                    .handle_end(end_timestamp, &mut thread.context_switch_data);
+                if let (Some(off_cpu_sample), Some(off_cpu_stack)) =
+                    (off_cpu_sample, thread.off_cpu_stack.take())
+                {
+                    let cpu_delta_ns = self
+                        .context_switch_handler
+                        .consume_cpu_delta(&mut thread.context_switch_data);
+                    process_off_cpu_sample_group(
+                        off_cpu_sample,
+                        thread.profile_thread,
+                        cpu_delta_ns,
+                        &self.timestamp_converter,
+                        self.off_cpu_sample_options,
+                        off_cpu_stack,
+                        thread.off_cpu_reason.take(),
+                        &mut process.unresolved_samples,
+                    );
+                }
+            }
+        }
+    }
+
     pub fn handle_main_event_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
         &mut self,
         e: &SampleRecord,
     ) {
-        let pid = e.pid.expect("Can't handle samples without pids");
-        let tid = e.tid.expect("Can't handle samples without tids");
-        let timestamp = e
-            .timestamp
-            .expect("Can't handle samples without timestamps");
+        let (Some(pid), Some(tid), Some(timestamp)) = (e.pid, e.tid, e.timestamp) else {
+            // We can't handle samples without pids, tids or timestamps.
+            return;
+        };
         self.current_sample_time = timestamp;
 
         let profile_timestamp = self.timestamp_converter.convert_time(timestamp);
@@ -194,9 +443,11 @@ where
         Self::get_sample_stack::<C>(
             e,
             &process.unwinder,
+            Self::code_address_mask::<C>(&self.code_address_bits, process),
             &mut self.cache,
             &mut stack,
             self.fold_recursive_prefix,
+            self.endian,
         );
 
         let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
@@ -224,8 +475,9 @@ where
                 thread_handle,
                 cpu_delta_ns,
                 &self.timestamp_converter,
-                self.off_cpu_weight_per_sample,
+                self.off_cpu_sample_options,
                 off_cpu_stack,
+                thread.off_cpu_reason.take(),
                 &mut process.unresolved_samples,
             );
         }
@@ -244,23 +496,41 @@ where
         };
 
         let stack_index = self.unresolved_stacks.convert(stack.iter().rev().cloned());
+        thread.last_on_cpu_stack = Some(stack_index);
         process.unresolved_samples.add_sample(
             thread_handle,
             profile_timestamp,
             timestamp,
             stack_index,
             cpu_delta,
-            1,
+            self.on_cpu_weight_per_sample,
             None,
         );
+
+        if let (Some(cpus), Some(cpu)) = (&mut self.cpus, e.cpu) {
+            // The sample is resolved with the lib mappings of its process, so it
+            // needs to go into this process's sample list even though the thread
+            // belongs to the CPUs process.
+            let cpu_thread_handle = cpus.thread_handle_for_cpu(cpu, &mut self.profile);
+            process.unresolved_samples.add_sample(
+                cpu_thread_handle,
+                profile_timestamp,
+                timestamp,
+                stack_index,
+                cpu_delta,
+                self.on_cpu_weight_per_sample,
+                None,
+            );
+        }
     }
 
     pub fn handle_sched_switch_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
         &mut self,
         e: &SampleRecord,
     ) {
-        let pid = e.pid.expect("Can't handle samples without pids");
-        let tid = e.tid.expect("Can't handle samples without tids");
+        let (Some(pid), Some(tid)) = (e.pid, e.tid) else {
+            return;
+        };
         let process = self.processes.get_by_pid(pid, &mut self.profile);
         process.check_jitdump(
             &mut self.jit_category_manager,
@@ -272,9 +542,11 @@ where
         Self::get_sample_stack::<C>(
             e,
             &process.unwinder,
+            Self::code_address_mask::<C>(&self.code_address_bits, process),
             &mut self.cache,
             &mut stack,
             self.fold_recursive_prefix,
+            self.endian,
         );
 
         let stack_index = self
@@ -283,15 +555,43 @@ where
         let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
         thread.off_cpu_stack = Some(stack_index);
 
+        let switch = e
+            .raw
+            .and_then(|raw| SchedSwitch::parse(raw, self.endian).ok());
+        let prev_state = switch.as_ref().map(|switch| switch.prev_state);
+        if let (Some(cpus), Some(cpu), Some(switch), Some(timestamp)) =
+            (&mut self.cpus, e.cpu, switch, e.timestamp)
+        {
+            let timestamp = self.timestamp_converter.convert_time(timestamp);
+            cpus.handle_switch(
+                cpu,
+                timestamp,
+                CpuRunningMarker {
+                    comm: switch.prev_comm,
+                    tid: switch.prev_tid,
+                },
+                CpuRunningMarker {
+                    comm: switch.next_comm,
+                    tid: switch.next_tid,
+                },
+                &mut self.profile,
+            );
+        }
+
         if self.off_cpu_indicator == Some(OffCpuIndicator::SchedSwitchAndSamples) {
             // Treat this sched_switch sample as a switch-out.
             // Sometimes we have sched_switch samples but no context switch records; for
             // example when using `simpleperf record --trace-offcpu`.
-            let timestamp = e
-                .timestamp
-                .expect("Can't handle context switch without time");
-            self.context_switch_handler
-                .handle_switch_out(timestamp, &mut thread.context_switch_data);
+            if let Some(timestamp) = e.timestamp {
+                self.context_switch_handler
+                    .handle_switch_out(timestamp, &mut thread.context_switch_data);
+            }
+            if let (true, Some(prev_state)) = (self.classify_off_cpu_time, prev_state) {
+                thread.off_cpu_reason = Some(match prev_state {
+                    0 => OffCpuReason::Preempted,
+                    _ => thread.blocking_syscall.unwrap_or(OffCpuReason::Unknown),
+                });
+            }
         }
     }
 
@@ -299,8 +599,7 @@ where
         &mut self,
         e: &SampleRecord,
     ) {
-        let pid = e.pid.expect("Can't handle samples without pids");
-        // let tid = e.tid.expect("Can't handle samples without tids");
+        let Some(pid) = e.pid else { return };
         let process = self.processes.get_by_pid(pid, &mut self.profile);
 
         let Some(raw) = e.raw else { return };
@@ -353,9 +652,11 @@ where
         Self::get_sample_stack::<C>(
             e,
             &process.unwinder,
+            Self::code_address_mask::<C>(&self.code_address_bits, process),
             &mut self.cache,
             &mut stack,
             self.fold_recursive_prefix,
+            self.endian,
         );
         let unresolved_stack = self.unresolved_stacks.convert(stack.into_iter().rev());
         let thread_handle = process.threads.main_thread.profile_thread;
@@ -370,15 +671,351 @@ where
         );
     }
 
+    /// Vblank events aren't associated with the process that happened to be
+    /// interrupted, so they go on a separate "Display" track, one thread per CRTC.
+    pub fn handle_drm_vblank_event_sample(&mut self, e: &SampleRecord) {
+        let Some(raw) = e.raw else { return };
+        let Ok(vblank) = DrmVblankEvent::parse(raw, self.endian) else {
+            return;
+        };
+        let Some(timestamp_mono) = e.timestamp else {
+            eprintln!("drm_vblank_event record doesn't have a timestamp");
+            return;
+        };
+        let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
+
+        let profile = &mut self.profile;
+        let display_process = *self
+            .display_process
+            .get_or_insert_with(|| profile.add_process("Display", 0, timestamp));
+        let thread_handle = *self.vblank_threads.entry(vblank.crtc).or_insert_with(|| {
+            let thread = profile.add_thread(display_process, 0, timestamp, false);
+            profile.set_thread_name(thread, &format!("Vblank (CRTC {})", vblank.crtc));
+            thread
+        });
+        profile.add_marker(
+            thread_handle,
+            CategoryHandle::OTHER,
+            "Vblank",
+            VblankMarker {
+                crtc: vblank.crtc,
+                seq: vblank.seq,
+            },
+            MarkerTiming::Instant(timestamp),
+        );
+    }
+
+    /// Audio xruns happen in interrupt context, so like vblank events they go
+    /// on a separate "Audio" track, one thread per PCM device.
+    pub fn handle_snd_pcm_xrun_sample(&mut self, e: &SampleRecord) {
+        let Some(raw) = e.raw else { return };
+        let Ok(xrun) = SndPcmXrunEvent::parse(raw, self.endian) else {
+            return;
+        };
+        let Some(timestamp_mono) = e.timestamp else {
+            eprintln!("snd_pcm:xrun record doesn't have a timestamp");
+            return;
+        };
+        let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
+
+        let pcm = xrun.pcm_name();
+        let profile = &mut self.profile;
+        let audio_process = *self
+            .audio_process
+            .get_or_insert_with(|| profile.add_process("Audio", 0, timestamp));
+        let thread_handle = match self.xrun_threads.get(&pcm) {
+            Some(thread_handle) => *thread_handle,
+            None => {
+                let thread = profile.add_thread(audio_process, 0, timestamp, false);
+                profile.set_thread_name(thread, &format!("Audio xruns ({pcm})"));
+                self.xrun_threads.insert(pcm.clone(), thread);
+                thread
+            }
+        };
+        profile.add_marker(
+            thread_handle,
+            CategoryHandle::OTHER,
+            "Audio xrun",
+            AudioXrunMarker {
+                pcm,
+                subdevice: xrun.subdevice,
+                is_capture: xrun.is_capture,
+                period_size: xrun.period_size,
+                buffer_size: xrun.buffer_size,
+            },
+            MarkerTiming::Instant(timestamp),
+        );
+    }
+
+    /// Called for a sample of one of the
+    /// [`GPU_JOB_TRACEPOINTS`](super::gpu_jobs::GPU_JOB_TRACEPOINTS). The
+    /// tracepoint for queueing a job is emitted in the context of the thread
+    /// which submits it. Jobs which were queued before the recording started
+    /// don't have a submitter. When a job is done, it gets a marker on the
+    /// track of its GPU ring, and one on the thread which submitted it, if
+    /// that thread's process is being profiled.
+    pub fn handle_gpu_job_sample(&mut self, e: &SampleRecord, format: GpuJobEventFormat) {
+        let (Some(raw), Some(timestamp_mono)) = (e.raw, e.timestamp) else {
+            return;
+        };
+        let Some(event) = format.parse(&raw.as_slice(), self.endian) else {
+            return;
+        };
+        let job = match format.phase {
+            GpuJobPhase::Queued => {
+                self.pending_gpu_jobs.insert(
+                    event.key,
+                    PendingGpuJob {
+                        ring: event.ring.unwrap_or_default(),
+                        job_id: event.job_id.unwrap_or_default(),
+                        submitter: e.pid.zip(e.tid),
+                        queue_time_mono: Some(timestamp_mono),
+                        start_time_mono: None,
+                    },
+                );
+                return;
+            }
+            GpuJobPhase::Started => {
+                let pending_job =
+                    self.pending_gpu_jobs
+                        .entry(event.key)
+                        .or_insert_with(|| PendingGpuJob {
+                            ring: String::new(),
+                            job_id: 0,
+                            submitter: None,
+                            queue_time_mono: None,
+                            start_time_mono: None,
+                        });
+                if let Some(ring) = event.ring {
+                    pending_job.ring = ring;
+                }
+                if let Some(job_id) = event.job_id {
+                    pending_job.job_id = job_id;
+                }
+                pending_job.start_time_mono = Some(timestamp_mono);
+                return;
+            }
+            GpuJobPhase::Finished => match self.pending_gpu_jobs.remove(&event.key) {
+                Some(job) => job,
+                None => return,
+            },
+        };
+        let end_time_mono = timestamp_mono;
+        let Some(start_time_mono) = job.start_time_mono.or(job.queue_time_mono) else {
+            return;
+        };
+        let start_time = self.timestamp_converter.convert_time(start_time_mono);
+        let end_time = self.timestamp_converter.convert_time(end_time_mono);
+        let queue_delay_ms = job
+            .queue_time_mono
+            .zip(job.start_time_mono)
+            .map(|(queued, started)| started.saturating_sub(queued) as f64 / 1_000_000.0);
+        let marker = GpuJobMarker {
+            ring: job.ring,
+            job_id: job.job_id,
+            submitter_pid: job.submitter.map(|(pid, _)| pid),
+            queue_delay_ms,
+        };
+
+        if let Some((pid, tid)) = job.submitter {
+            if self.processes.contains(pid) {
+                let process = self.processes.get_by_pid(pid, &mut self.profile);
+                let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
+                self.profile.add_marker(
+                    thread.profile_thread,
+                    CategoryHandle::OTHER,
+                    "GPU job",
+                    marker.clone(),
+                    MarkerTiming::Interval(start_time, end_time),
+                );
+            }
+        }
+
+        let profile = &mut self.profile;
+        let gpu_process = *self
+            .gpu_process
+            .get_or_insert_with(|| profile.add_process("GPU", 0, start_time));
+        let thread_handle = match self.gpu_ring_threads.get(&marker.ring) {
+            Some(thread_handle) => *thread_handle,
+            None => {
+                let thread = profile.add_thread(gpu_process, 0, start_time, false);
+                profile.set_thread_name(thread, &format!("GPU ring {}", marker.ring));
+                self.gpu_ring_threads.insert(marker.ring.clone(), thread);
+                thread
+            }
+        };
+        profile.add_marker(
+            thread_handle,
+            CategoryHandle::OTHER,
+            "GPU job",
+            marker,
+            MarkerTiming::Interval(start_time, end_time),
+        );
+    }
+
+    /// Called for a sys_enter_futex sample. The start of a futex wait is stored
+    /// on the thread, and the marker is added once the wait ends.
+    pub fn handle_futex_enter_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: &SampleRecord,
+    ) {
+        let (Some(pid), Some(tid), Some(raw), Some(timestamp_mono)) =
+            (e.pid, e.tid, e.raw, e.timestamp)
+        else {
+            return;
+        };
+        let Ok(futex) = SysEnterFutex::parse(raw, self.endian) else {
+            return;
+        };
+        if !futex.is_wait() {
+            return;
+        }
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        process.check_jitdump(
+            &mut self.jit_category_manager,
+            &mut self.profile,
+            &self.timestamp_converter,
+        );
+
+        let mut stack = Vec::new();
+        Self::get_sample_stack::<C>(
+            e,
+            &process.unwinder,
+            Self::code_address_mask::<C>(&self.code_address_bits, process),
+            &mut self.cache,
+            &mut stack,
+            self.fold_recursive_prefix,
+            self.endian,
+        );
+        let stack = self
+            .unresolved_stacks
+            .convert_no_kernel(stack.into_iter().rev());
+        let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
+        thread.blocking_syscall = Some(OffCpuReason::Lock);
+        thread.pending_futex_wait = Some(PendingFutexWait {
+            address: futex.uaddr,
+            start_time_mono: timestamp_mono,
+            stack,
+        });
+    }
+
+    /// Called for a sys_exit_futex sample. If the thread waited until the lock
+    /// was released, this adds a "Lock wait" marker and counts the wait towards
+    /// the futex's contention.
+    pub fn handle_futex_exit_sample(&mut self, e: &SampleRecord) {
+        let (Some(pid), Some(tid), Some(raw), Some(end_time_mono)) =
+            (e.pid, e.tid, e.raw, e.timestamp)
+        else {
+            return;
+        };
+        let Ok(futex) = SysExitFutex::parse(raw, self.endian) else {
+            return;
+        };
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
+        thread.blocking_syscall = None;
+        let Some(wait) = thread.pending_futex_wait.take() else {
+            return;
+        };
+        if !futex.was_woken() {
+            return;
+        }
+        let thread_handle = thread.profile_thread;
+        let start_time = self.timestamp_converter.convert_time(wait.start_time_mono);
+        let end_time = self.timestamp_converter.convert_time(end_time_mono);
+        process.unresolved_samples.add_lock_wait_marker(
+            thread_handle,
+            start_time,
+            wait.start_time_mono,
+            wait.stack,
+            wait.address,
+            end_time,
+        );
+        process.lock_contention.add_wait(
+            wait.address,
+            start_time,
+            end_time,
+            end_time_mono.saturating_sub(wait.start_time_mono),
+        );
+    }
+
+    /// Called for the sys_enter event of one of the traced blocking syscalls.
+    /// If the thread goes off-CPU before the syscall returns, it's counted as
+    /// waiting for `reason`.
+    pub fn handle_syscall_enter_sample(&mut self, e: &SampleRecord, reason: OffCpuReason) {
+        let (Some(pid), Some(tid)) = (e.pid, e.tid) else {
+            return;
+        };
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
+        thread.blocking_syscall = Some(reason);
+    }
+
+    /// Called for the sys_exit event of one of the traced blocking syscalls.
+    pub fn handle_syscall_exit_sample(&mut self, e: &SampleRecord) {
+        let (Some(pid), Some(tid)) = (e.pid, e.tid) else {
+            return;
+        };
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
+        thread.blocking_syscall = None;
+    }
+
+    /// Makes [`Self::handle_tracepoint_sample`] treat samples of the tracepoint
+    /// with this id as `event`.
+    pub fn register_tracepoint(&mut self, tracepoint_id: u64, event: KnownEvent) {
+        if matches!(event, KnownEvent::SyscallEnter(_)) {
+            self.classify_off_cpu_time = true;
+        }
+        self.tracepoint_events.insert(tracepoint_id, event);
+    }
+
+    /// Called for samples from the tracepoints which are opened while recording.
+    /// There's no attribute index to tell them apart, but the raw data starts
+    /// with the tracepoint id. Syscall events from processes which aren't being
+    /// profiled are ignored.
+    pub fn handle_tracepoint_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: &SampleRecord,
+    ) {
+        let Some(mut raw) = e.raw else { return };
+        let common_type = match self.endian {
+            Endianness::LittleEndian => raw.read_u16::<LittleEndian>(),
+            Endianness::BigEndian => raw.read_u16::<byteorder::BigEndian>(),
+        };
+        let Ok(common_type) = common_type else {
+            return;
+        };
+        let is_profiled_process = e.pid.map_or(false, |pid| self.processes.contains(pid));
+        match self.tracepoint_events.get(&u64::from(common_type)) {
+            Some(KnownEvent::DrmVblankEvent) => self.handle_drm_vblank_event_sample(e),
+            Some(KnownEvent::SndPcmXrun) => self.handle_snd_pcm_xrun_sample(e),
+            Some(KnownEvent::GpuJob(format)) => {
+                let format = *format;
+                self.handle_gpu_job_sample(e, format)
+            }
+            Some(KnownEvent::FutexEnter) if is_profiled_process => {
+                self.handle_futex_enter_sample::<C>(e)
+            }
+            Some(KnownEvent::FutexExit) if is_profiled_process => self.handle_futex_exit_sample(e),
+            Some(KnownEvent::SyscallEnter(reason)) if is_profiled_process => {
+                self.handle_syscall_enter_sample(e, *reason)
+            }
+            Some(KnownEvent::SyscallExit) if is_profiled_process => {
+                self.handle_syscall_exit_sample(e)
+            }
+            _ => {}
+        }
+    }
+
     pub fn handle_other_event_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
         &mut self,
         e: &SampleRecord,
         attr_index: usize,
     ) {
-        let pid = e.pid.expect("Can't handle samples without pids");
-        let timestamp_mono = e
-            .timestamp
-            .expect("Can't handle samples without timestamps");
+        let (Some(pid), Some(timestamp_mono)) = (e.pid, e.timestamp) else {
+            return;
+        };
         let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
         // let tid = e.tid.expect("Can't handle samples without tids");
         let process = self.processes.get_by_pid(pid, &mut self.profile);
@@ -392,9 +1029,11 @@ where
         Self::get_sample_stack::<C>(
             e,
             &process.unwinder,
+            Self::code_address_mask::<C>(&self.code_address_bits, process),
             &mut self.cache,
             &mut stack,
             self.fold_recursive_prefix,
+            self.endian,
         );
 
         let thread_handle = match e.tid {
@@ -439,9 +1078,11 @@ where
     fn get_sample_stack<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
         e: &SampleRecord,
         unwinder: &U,
+        code_address_mask: u64,
         cache: &mut U::Cache,
         stack: &mut Vec<StackFrame>,
         fold_recursive_prefix: bool,
+        endian: Endianness,
     ) {
         stack.truncate(0);
 
@@ -460,9 +1101,14 @@ where
                     continue;
                 }
 
-                let stack_frame = match is_first_frame {
-                    true => StackFrame::InstructionPointer(address, mode),
-                    false => StackFrame::ReturnAddress(address, mode),
+                let stack_frame = match (is_first_frame, mode) {
+                    (true, _) => StackFrame::InstructionPointer(address, mode),
+                    // Recent kernels strip pointer authentication codes from user
+                    // callchains, but older ones don't.
+                    (false, StackMode::User) => {
+                        StackFrame::ReturnAddress(address & code_address_mask, mode)
+                    }
+                    (false, StackMode::Kernel) => StackFrame::ReturnAddress(address, mode),
                 };
                 stack.push(stack_frame);
 
@@ -472,41 +1118,50 @@ where
 
         // Append the user stack with the help of DWARF unwinding.
         if let (Some(regs), Some((user_stack, _))) = (&e.user_regs, e.user_stack) {
-            let ustack_bytes = RawDataU64::from_raw_data::<LittleEndian>(user_stack);
-            let (pc, sp, regs) = C::convert_regs(regs);
-            let mut read_stack = |addr: u64| {
-                // ustack_bytes has the stack bytes starting from the current stack pointer.
-                let offset = addr.checked_sub(sp).ok_or(())?;
-                let index = usize::try_from(offset / 8).map_err(|_| ())?;
-                ustack_bytes.get(index).ok_or(())
+            // The stack bytes are in the byte order of the recording machine.
+            let ustack_bytes = match endian {
+                Endianness::LittleEndian => RawDataU64::from_raw_data::<LittleEndian>(user_stack),
+                Endianness::BigEndian => {
+                    RawDataU64::from_raw_data::<byteorder::BigEndian>(user_stack)
+                }
             };
-
-            // Unwind.
-            let mut frames = unwinder.iter_frames(pc, regs, cache, &mut read_stack);
-            loop {
-                let frame = match frames.next() {
-                    Ok(Some(frame)) => frame,
-                    Ok(None) => break,
-                    Err(_) => {
-                        stack.push(StackFrame::TruncatedStackMarker);
-                        break;
-                    }
+            if let Some((pc, sp, unwind_regs)) = C::convert_regs(regs, code_address_mask) {
+                let mut read_stack = |addr: u64| {
+                    // ustack_bytes has the stack bytes starting from the current stack pointer.
+                    let offset = addr.checked_sub(sp).ok_or(())?;
+                    let index = usize::try_from(offset / 8).map_err(|_| ())?;
+                    ustack_bytes.get(index).ok_or(())
                 };
-                let stack_frame = match frame {
-                    FrameAddress::InstructionPointer(addr) => {
-                        StackFrame::InstructionPointer(addr, StackMode::User)
+
+                if C::has_framehop_unwinder() {
+                    // Unwind.
+                    let mut frames = unwinder.iter_frames(pc, unwind_regs, cache, &mut read_stack);
+                    loop {
+                        let frame = match frames.next() {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(_) => {
+                                stack.push(StackFrame::TruncatedStackMarker);
+                                break;
+                            }
+                        };
+                        stack.push(Self::user_stack_frame(frame));
                     }
-                    FrameAddress::ReturnAddress(addr) => {
-                        StackFrame::ReturnAddress(addr.into(), StackMode::User)
+                } else {
+                    let walk_result = C::walk_frame_chain(regs, &mut read_stack, &mut |frame| {
+                        stack.push(Self::user_stack_frame(frame))
+                    });
+                    if walk_result.is_err() {
+                        stack.push(StackFrame::TruncatedStackMarker);
                     }
-                };
-                stack.push(stack_frame);
+                }
             }
         }
 
         if stack.is_empty() {
             if let Some(ip) = e.ip {
-                stack.push(StackFrame::InstructionPointer(ip, e.cpu_mode.into()));
+                let mode = StackMode::from(e.cpu_mode);
+                stack.push(StackFrame::InstructionPointer(ip, mode));
             }
         } else if fold_recursive_prefix {
             let last_frame = *stack.last().unwrap();
@@ -516,6 +1171,35 @@ where
         }
     }
 
+    /// The mask for the return addresses of `process`. It comes from
+    /// `code_address_bits` if the process's name is listed there, and from the
+    /// process's mappings otherwise.
+    fn code_address_mask<C: ConvertRegs>(
+        code_address_bits: &[CodeAddressBits],
+        process: &Process<U>,
+    ) -> u64 {
+        let configured = process.name.as_deref().and_then(|name| {
+            code_address_bits
+                .iter()
+                .find(|bits| bits.process_name == name)
+        });
+        match configured {
+            Some(bits) => bits.mask(),
+            None => C::code_address_mask(process.highest_code_address),
+        }
+    }
+
+    fn user_stack_frame(frame: FrameAddress) -> StackFrame {
+        match frame {
+            FrameAddress::InstructionPointer(addr) => {
+                StackFrame::InstructionPointer(addr, StackMode::User)
+            }
+            FrameAddress::ReturnAddress(addr) => {
+                StackFrame::ReturnAddress(addr.into(), StackMode::User)
+            }
+        }
+    }
+
     /// This is a terrible hack to get binary correlation working with apps on Wine.
     ///
     /// Unlike ELF, PE has the notion of "file alignment" that is different from page alignment.
@@ -539,13 +1223,18 @@ where
     /// offset of 0, we'll add it to the list of "suspected PE images". When we see a later mapping
     /// that belongs to one of the suspected PE ranges, we'll match the mapping with the file,
     /// which allows binary correlation and unwinding to work.
-    fn check_for_pe_mapping(&mut self, path_slice: &[u8], mapping_start_avma: u64) {
+    ///
+    /// This is also where Wine processes are recognized: once a process which
+    /// is still named after Wine's loader maps an .exe, it's named after the .exe.
+    fn check_for_pe_mapping(
+        &mut self,
+        pid: i32,
+        path_slice: &[u8],
+        mapping_start_avma: u64,
+        timestamp: u64,
+    ) {
         // Do a quick extension check first, to avoid end up trying to parse every mmapped file.
-        let filename_is_pe = path_slice.ends_with(b".exe")
-            || path_slice.ends_with(b".dll")
-            || path_slice.ends_with(b".EXE")
-            || path_slice.ends_with(b".DLL");
-        if !filename_is_pe {
+        if !has_pe_extension(path_slice) {
             return;
         }
 
@@ -564,6 +1253,15 @@ where
                 size,
             };
             self.suspected_pe_mappings.insert(mapping.start, mapping);
+
+            if let Some(program_name) = program_name_for_exe(path_slice) {
+                let process = self.processes.get_by_pid(pid, &mut self.profile);
+                if process.name.as_deref().map_or(false, is_wine_loader_name) {
+                    let timestamp = self.timestamp_converter.convert_time(timestamp);
+                    self.processes
+                        .rename_process(pid, timestamp, program_name, &mut self.profile);
+                }
+            }
         }
     }
 
@@ -575,7 +1273,7 @@ where
         }
 
         if e.page_offset == 0 {
-            self.check_for_pe_mapping(&e.path.as_slice(), e.address);
+            self.check_for_pe_mapping(e.pid, &e.path.as_slice(), e.address, timestamp);
         }
 
         if !e.is_executable {
@@ -621,7 +1319,7 @@ where
         }
 
         if e.page_offset == 0 {
-            self.check_for_pe_mapping(&path, e.address);
+            self.check_for_pe_mapping(e.pid, &path, e.address, timestamp);
         }
 
         const PROT_EXEC: u32 = 0b100;
@@ -654,6 +1352,155 @@ where
         );
     }
 
+    /// Handle a line which a profiled process sent over the marker socket. The
+    /// line uses the marker file format. Lines from processes which aren't
+    /// being profiled, or which have already exited, are ignored.
+    pub fn handle_marker_socket_line(&mut self, pid: i32, tid: i32, line: &str) {
+        let Some(entry) = process_marker_file_line(line, &self.timestamp_converter) else {
+            return;
+        };
+        let Some(process) = self.processes.get_existing_by_pid(pid) else {
+            return;
+        };
+        let thread = process.threads.get_existing_thread_or_main_thread(tid);
+        let profile_thread = thread.profile_thread;
+        process.add_marker_file_entry(profile_thread, entry);
+    }
+
+    /// Add an interval marker which was received while recording, e.g. an
+    /// OpenTelemetry span. The times are in `CLOCK_MONOTONIC` nanoseconds.
+    /// Spans for processes which aren't being profiled are ignored.
+    pub fn add_live_marker_span(
+        &mut self,
+        pid: i32,
+        tid: i32,
+        start_time_ns: u64,
+        end_time_ns: u64,
+        name: String,
+    ) {
+        let span = MarkerSpan {
+            start_time: self.timestamp_converter.convert_time(start_time_ns),
+            end_time: self.timestamp_converter.convert_time(end_time_ns),
+            name,
+        };
+        let Some(process) = self.processes.get_existing_by_pid(pid) else {
+            return;
+        };
+        let thread = process.threads.get_existing_thread_or_main_thread(tid);
+        let profile_thread = thread.profile_thread;
+        process.add_marker_file_entry(profile_thread, MarkerFileEntry::Span(span));
+    }
+
+    /// Records how long the launched process `pid` was kept suspended while
+    /// the perf events were set up, from `launch_time_ns` until
+    /// `attach_time_ns`, in `CLOCK_MONOTONIC` nanoseconds. The process doesn't
+    /// run during this time, so no samples are missing, but it starts later.
+    pub fn add_attach_latency(&mut self, pid: i32, launch_time_ns: u64, attach_time_ns: u64) {
+        let latency_ms = attach_time_ns.saturating_sub(launch_time_ns) as f64 / 1_000_000.0;
+        self.profile.add_extra_info(
+            "Profiler",
+            "Attach latency",
+            &format!("{latency_ms:.1} ms, the process was suspended until sampling was set up"),
+        );
+        // The marker goes on the process after the exec, which is the process
+        // that the user is interested in.
+        self.pending_attach_span = Some((pid, launch_time_ns, attach_time_ns));
+    }
+
+    /// Lists the file which the output stream `stream` of the launched command
+    /// was written to in the profile's information panel.
+    pub fn add_tee_output_path(&mut self, stream: OutputStream, path: &Path) {
+        self.profile
+            .add_extra_info("Output", stream.name(), &path.to_string_lossy());
+    }
+
+    /// Show the launched processes in `groups` as children of a process which is
+    /// named after their group. The parent is set when the launched process
+    /// execs, so that the process and its descendants are named "group>...".
+    pub fn set_launched_process_groups(&mut self, groups: LaunchedProcessGroups) {
+        self.launched_process_groups = Some(groups);
+    }
+
+    fn group_process_for_launched_process(&mut self, pid: i32) -> Option<ProcessHandle> {
+        let group = self
+            .launched_process_groups
+            .as_ref()?
+            .lock()
+            .unwrap()
+            .get(&pid)?
+            .clone();
+        let profile = &mut self.profile;
+        let group_process = *self
+            .group_processes
+            .entry(group)
+            .or_insert_with_key(|group| {
+                profile.add_process(group, 0, Timestamp::from_millis_since_reference(0.0))
+            });
+        Some(group_process)
+    }
+
+    /// Add an instant marker for a line of output of the process `pid`. The
+    /// marker is put on the process's main thread, because we don't know which
+    /// thread printed the line. The time is in `CLOCK_MONOTONIC` nanoseconds.
+    /// Lines which arrive after the process has exited are ignored.
+    pub fn add_output_line_marker(
+        &mut self,
+        pid: i32,
+        time_ns: u64,
+        stream: OutputStream,
+        text: String,
+        tee_line: Option<u64>,
+    ) {
+        let timestamp = self.timestamp_converter.convert_time(time_ns);
+        let Some(process) = self.processes.get_existing_by_pid(pid) else {
+            return;
+        };
+        let profile_thread = process.threads.main_thread.profile_thread;
+        self.profile.add_marker(
+            profile_thread,
+            CategoryHandle::OTHER,
+            stream.name(),
+            LogMarker {
+                stream,
+                text,
+                tee_line,
+            },
+            MarkerTiming::Instant(timestamp),
+        );
+    }
+
+    /// Adds a marker to the main thread of each live process, which covers the
+    /// time from `start_time_raw` until `end_time_raw`, or until the end of the
+    /// profile if the end isn't known. This is used when the rest of the input
+    /// couldn't be read.
+    pub fn add_truncated_data_marker(
+        &mut self,
+        start_time_raw: u64,
+        end_time_raw: Option<u64>,
+        error: String,
+    ) {
+        let start_time = self.timestamp_converter.convert_time(start_time_raw);
+        let timing = match end_time_raw {
+            Some(end_time_raw) if end_time_raw > start_time_raw => MarkerTiming::Interval(
+                start_time,
+                self.timestamp_converter.convert_time(end_time_raw),
+            ),
+            _ => MarkerTiming::IntervalStart(start_time),
+        };
+        let main_threads: Vec<ThreadHandle> = self.processes.main_threads().collect();
+        for thread in main_threads {
+            self.profile.add_marker(
+                thread,
+                CategoryHandle::OTHER,
+                "Truncated data",
+                TruncatedDataMarker {
+                    error: error.clone(),
+                },
+                timing.clone(),
+            );
+        }
+    }
+
     fn check_jitdump_or_marker_file(&mut self, path: &[u8], pid: i32, tid: i32) -> bool {
         let Ok(path) = std::str::from_utf8(path) else {
             return false;
@@ -677,7 +1524,9 @@ where
             return true;
         }
 
-        if filename.starts_with("marker-") && filename.ends_with(".txt") {
+        if filename.starts_with("marker-")
+            && (filename.ends_with(".txt") || filename.ends_with(".jsonl"))
+        {
             let marker_file_path = Path::new(path);
             let process = self.processes.get_by_pid(pid, &mut self.profile);
             let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
@@ -694,11 +1543,10 @@ where
     }
 
     pub fn handle_context_switch(&mut self, e: ContextSwitchRecord, common: CommonData) {
-        let pid = common.pid.expect("Can't handle samples without pids");
-        let tid = common.tid.expect("Can't handle samples without tids");
-        let timestamp = common
-            .timestamp
-            .expect("Can't handle context switch without time");
+        let (Some(pid), Some(tid), Some(timestamp)) = (common.pid, common.tid, common.timestamp)
+        else {
+            return;
+        };
         let process = self.processes.get_by_pid(pid, &mut self.profile);
         let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
 
@@ -719,15 +1567,35 @@ where
                         thread.profile_thread,
                         cpu_delta_ns,
                         &self.timestamp_converter,
-                        self.off_cpu_weight_per_sample,
+                        self.off_cpu_sample_options,
                         off_cpu_stack,
+                        thread.off_cpu_reason.take(),
                         &mut process.unresolved_samples,
                     );
                 }
             }
-            ContextSwitchRecord::Out { .. } => {
+            ContextSwitchRecord::Out { preempted, .. } => {
                 self.context_switch_handler
                     .handle_switch_out(timestamp, &mut thread.context_switch_data);
+                if self.classify_off_cpu_time {
+                    thread.off_cpu_reason = Some(match preempted {
+                        TaskWasPreempted::Yes => OffCpuReason::Preempted,
+                        TaskWasPreempted::No => {
+                            thread.blocking_syscall.unwrap_or(OffCpuReason::Unknown)
+                        }
+                    });
+                }
+                if self.wall_clock && thread.off_cpu_stack.is_none() {
+                    // Without a sched_switch sample, we don't know where the thread
+                    // blocked. Its most recent on-CPU stack is the best guess, and
+                    // threads which haven't been sampled yet get an empty stack so
+                    // that their blocked time is still counted.
+                    thread.off_cpu_stack = Some(
+                        thread
+                            .last_on_cpu_stack
+                            .unwrap_or(UnresolvedStackHandle::EMPTY),
+                    );
+                }
             }
         }
     }
@@ -747,12 +1615,15 @@ where
                 eprintln!("Unexpected data in FORK record: If we fork into a different process, the forked child thread should be the main thread of the new process");
             }
             let parent_process_name = parent_process.name.clone();
-            self.processes.recycle_or_get_new(
+            let parent_profile_process = parent_process.profile_process;
+            let process = self.processes.recycle_or_get_new(
                 e.pid,
                 parent_process_name,
                 start_time,
                 &mut self.profile,
             );
+            self.profile
+                .set_process_parent(process.profile_process, parent_profile_process);
         } else {
             let parent_thread = parent_process
                 .threads
@@ -775,10 +1646,19 @@ where
             self.processes.remove(
                 e.pid,
                 end_time,
+                false,
                 &mut self.profile,
                 &mut self.jit_category_manager,
                 &self.timestamp_converter,
             );
+            self.processes.flush_removed_processes(
+                &mut self.profile,
+                &self.unresolved_stacks,
+                &self.event_names,
+                self.frame_marker.as_deref(),
+                self.latency_markers.as_ref(),
+                &self.stack_stitching_rules,
+            );
         } else {
             let process = self.processes.get_by_pid(e.pid, &mut self.profile);
             process
@@ -809,19 +1689,57 @@ where
         if e.is_execve {
             // Mark the old thread / process as ended.
             if is_main {
+                // The new image has the same parent process as the old one.
+                let old_process = self
+                    .processes
+                    .get_by_pid(e.pid, &mut self.profile)
+                    .profile_process;
+                let mut parent = self.profile.process_parent(old_process);
+                if parent.is_none() {
+                    parent = self.group_process_for_launched_process(e.pid);
+                    if let Some(group_process) = parent {
+                        self.profile.set_process_parent(old_process, group_process);
+                    }
+                }
                 self.processes.remove(
                     e.pid,
                     timestamp,
+                    true,
                     &mut self.profile,
                     &mut self.jit_category_manager,
                     &self.timestamp_converter,
                 );
-                self.processes.recycle_or_get_new(
+                self.processes.flush_removed_processes(
+                    &mut self.profile,
+                    &self.unresolved_stacks,
+                    &self.event_names,
+                    self.frame_marker.as_deref(),
+                    self.latency_markers.as_ref(),
+                    &self.stack_stitching_rules,
+                );
+                let process = self.processes.recycle_or_get_new(
                     e.pid,
                     Some(name.to_string()),
                     timestamp,
                     &mut self.profile,
                 );
+                if let Some(parent) = parent {
+                    self.profile
+                        .set_process_parent(process.profile_process, parent);
+                }
+                self.add_exec_environment(e.pid, &name);
+                if let Some((pid, launch_time_ns, attach_time_ns)) = self.pending_attach_span {
+                    if pid == e.pid {
+                        self.pending_attach_span = None;
+                        self.add_live_marker_span(
+                            pid,
+                            pid,
+                            launch_time_ns,
+                            attach_time_ns,
+                            "Waiting for the profiler".to_string(),
+                        );
+                    }
+                }
             } else {
                 eprintln!(
                     "Unexpected is_execve on non-main thread! pid: {}, tid: {}",
@@ -893,7 +1811,7 @@ where
         build_id: Option<&[u8]>,
         path: &[u8],
     ) {
-        let path = std::str::from_utf8(path).unwrap().to_string();
+        let path = String::from_utf8_lossy(path).into_owned();
         let build_id: Option<Vec<u8>> = match (build_id, self.kernel_symbols.as_ref()) {
             (None, Some(kernel_symbols)) if kernel_symbols.base_avma == base_address => {
                 Some(kernel_symbols.build_id.clone())
@@ -959,14 +1877,29 @@ where
     ) {
         let process = self.processes.get_by_pid(process_pid, &mut self.profile);
 
-        let path = std::str::from_utf8(path_slice).unwrap();
-        let (mut file, mut path): (Option<_>, String) = match open_file_with_fallback(
-            Path::new(path),
-            self.extra_binary_artifact_dir.as_deref(),
-        ) {
-            Ok((file, path)) => (Some(file), path.to_string_lossy().to_string()),
-            _ => (None, path.to_owned()),
-        };
+        let path = String::from_utf8_lossy(path_slice);
+        let path = &*path;
+
+        // Prefer the copy in perf's build-id cache, because the file at the original
+        // path may have been upgraded since the profile was recorded. The cached file
+        // is called "elf", so keep using the original file name as the library name.
+        let mut name_override = None;
+        let (mut file, mut path): (Option<_>, String) =
+            match build_id.and_then(open_file_from_perf_build_id_cache) {
+                Some((file, cache_path)) => {
+                    name_override = Path::new(path)
+                        .file_name()
+                        .map(|f| f.to_string_lossy().to_string());
+                    (Some(file), cache_path.to_string_lossy().to_string())
+                }
+                None => match open_file_with_fallback(
+                    Path::new(path),
+                    self.extra_binary_artifact_dir.as_deref(),
+                ) {
+                    Ok((file, path)) => (Some(file), path.to_string_lossy().to_string()),
+                    _ => (None, path.to_owned()),
+                },
+            };
 
         let mut suspected_pe_mapping = None;
         if file.is_none() {
@@ -977,11 +1910,12 @@ where
                 .map(|(_, m)| m)
                 .filter(|m| {
                     mapping_start_avma >= m.start
-                        && mapping_start_avma + mapping_size <= m.start + m.size
+                        && mapping_start_avma.saturating_add(mapping_size)
+                            <= m.start.saturating_add(m.size)
                 });
             if let Some(mapping) = suspected_pe_mapping {
                 if let Ok((pe_file, pe_path)) = open_file_with_fallback(
-                    Path::new(std::str::from_utf8(&mapping.path).unwrap()),
+                    Path::new(&*String::from_utf8_lossy(&mapping.path)),
                     self.extra_binary_artifact_dir.as_deref(),
                 ) {
                     file = Some(pe_file);
@@ -1006,9 +1940,11 @@ where
         let mapping_end_avma = mapping_start_avma + mapping_size;
         let avma_range = mapping_start_avma..mapping_end_avma;
 
-        let name = Path::new(&path)
-            .file_name()
-            .map_or("<unknown>".into(), |f| f.to_string_lossy().to_string());
+        let name = name_override.unwrap_or_else(|| {
+            Path::new(&path)
+                .file_name()
+                .map_or("<unknown>".into(), |f| f.to_string_lossy().to_string())
+        });
 
         if let Some(file) = file {
             let mmap = match unsafe { memmap2::MmapOptions::new().map(&file) } {
@@ -1142,38 +2078,83 @@ where
                 text_segment,
             };
 
-            let module = Module::new(
-                path.to_string(),
-                avma_range.clone(),
-                base_avma,
-                module_section_info,
-            );
+            let module = match &file {
+                object::File::Pe32(_) | object::File::Pe64(_) => Module::new(
+                    path.to_string(),
+                    avma_range.clone(),
+                    base_avma,
+                    PeModuleSectionInfo::new(&file, mmap.clone(), base_svma),
+                ),
+                _ => Module::new(
+                    path.to_string(),
+                    avma_range.clone(),
+                    base_avma,
+                    module_section_info,
+                ),
+            };
             process.unwinder.add_module(module);
 
-            let debug_id = if let Some(debug_id) = debug_id_for_object(&file) {
-                debug_id
-            } else {
-                return;
-            };
-            let code_id = file
+            // Keep the library even if it has no debug ID, so that it's still
+            // listed with its path, code ID and size.
+            let debug_id = debug_id_for_object(&file).unwrap_or_default();
+            let elf_build_id = file
                 .build_id()
                 .ok()
                 .flatten()
                 .map(|build_id| CodeId::from_binary(build_id).to_string());
+            let code_id = match &elf_build_id {
+                Some(build_id) => Some(build_id.clone()),
+                None => pe_code_id(&file).map(|code_id| code_id.to_string()),
+            };
+            let (file_version, product_version) = match pe_file_and_product_version(&file) {
+                Some((file_version, product_version)) => {
+                    (Some(file_version), Some(product_version))
+                }
+                None => (None, None),
+            };
+            let is_injected_jit_lib = name.starts_with("jitted-") && name.ends_with(".so");
+            // Each JIT function gets its own injected library, which would drown
+            // out the real library loads. Mappings which existed before
+            // recording started have no timestamp and weren't loaded now.
+            if !is_injected_jit_lib && timestamp != 0 {
+                let marker = LibraryLoadMarker {
+                    event: LibraryEvent::Load,
+                    name: name.clone(),
+                    path: path.clone(),
+                    base_address: base_avma,
+                    build_id: elf_build_id,
+                    file_version,
+                    product_version,
+                };
+                self.profile.add_marker(
+                    process.threads.main_thread.profile_thread,
+                    CategoryHandle::OTHER,
+                    marker.marker_name(),
+                    marker,
+                    MarkerTiming::Instant(self.timestamp_converter.convert_time(timestamp)),
+                );
+            }
+
+            // PE images under Wine are symbolicated with their PDB, which
+            // has a different name.
+            let (debug_name, debug_path) = pdb_debug_name_and_path(&file, &path)
+                .unwrap_or_else(|| (name.clone(), path.clone()));
             let lib_handle = self.profile.add_lib(LibraryInfo {
                 debug_id,
                 code_id,
                 path: path.clone(),
-                debug_path: path,
-                debug_name: name.clone(),
+                debug_path,
+                debug_name,
                 name: name.clone(),
                 arch: None,
                 symbol_table: None,
             });
+            self.profile
+                .set_lib_file_size(lib_handle, mmap.len() as u64);
 
             let relative_address_at_start = (avma_range.start - base_avma) as u32;
 
-            if name.starts_with("jitted-") && name.ends_with(".so") {
+            if is_injected_jit_lib {
                 let symbol_name = jit_function_name(&file);
                 process.add_lib_mapping_for_injected_jit_lib(
                     timestamp,
@@ -1187,12 +2168,19 @@ where
                     &mut self.profile,
                 );
             } else {
+                if !has_debug_info(&file) {
+                    self.libs_without_debug_info.insert(lib_handle);
+                }
+                let category = self
+                    .jit_category_manager
+                    .classify_runtime_lib(&name, &mut self.profile);
                 process.add_regular_lib_mapping(
                     timestamp,
                     mapping_start_avma,
                     mapping_end_avma,
                     relative_address_at_start,
                     lib_handle,
+                    category,
                 );
             }
         } else {
@@ -1205,9 +2193,30 @@ where
 
             // If we have a build ID, convert it to a debug_id and a code_id.
             let debug_id = build_id
-                .map(|id| DebugId::from_identifier(id, true)) // TODO: endian
+                .map(|id| DebugId::from_identifier(id, self.endian == Endianness::LittleEndian))
                 .unwrap_or_default();
             let code_id = build_id.map(|build_id| CodeId::from_binary(build_id).to_string());
+            let category = self
+                .jit_category_manager
+                .classify_runtime_lib(&name, &mut self.profile);
+            if timestamp != 0 {
+                let marker = LibraryLoadMarker {
+                    event: LibraryEvent::Load,
+                    name: name.clone(),
+                    path: path.clone(),
+                    base_address: base_avma,
+                    build_id: code_id.clone(),
+                    file_version: None,
+                    product_version: None,
+                };
+                self.profile.add_marker(
+                    process.threads.main_thread.profile_thread,
+                    CategoryHandle::OTHER,
+                    marker.marker_name(),
+                    marker,
+                    MarkerTiming::Instant(self.timestamp_converter.convert_time(timestamp)),
+                );
+            }
 
             let lib_handle = self.profile.add_lib(LibraryInfo {
                 debug_id,
@@ -1225,11 +2234,25 @@ where
                 mapping_end_avma,
                 relative_address_at_start,
                 lib_handle,
+                category,
             );
         }
     }
 }
 
+/// Whether the file has DWARF debug info, or links to a separate debug file
+/// which can have it, like distro libraries do.
+fn has_debug_info(file: &object::File) -> bool {
+    [
+        ".debug_info",
+        ".zdebug_info",
+        ".gnu_debuglink",
+        ".gnu_debugaltlink",
+    ]
+    .iter()
+    .any(|name| file.section_by_name(name).is_some())
+}
+
 // #[test]
 // fn test_my_jit() {
 //     let data = std::fs::read("/Users/mstange/Downloads/jitted-123175-0-fixed.so").unwrap();
@@ -1237,50 +2260,120 @@ where
 //     dbg!(jit_function_name(&file));
 // }
 
+/// How an [`OffCpuSampleGroup`] is turned into samples.
+#[derive(Debug, Clone, Copy)]
+struct OffCpuSampleOptions {
+    /// The weight of a sample which stands for one off-CPU sampling interval.
+    weight_per_sample: i32,
+    /// Emit one sample per sampling interval, like a wall-clock profiler would,
+    /// rather than one sample at each end of the group.
+    sample_per_interval: bool,
+    /// Weigh the samples by the blocked duration, in microseconds, and put them
+    /// into the Off-CPU category. See [`ConversionProps::blocked_time_weights`].
+    blocked_time_weights: bool,
+}
+
+/// A duration in nanoseconds, as a sample weight in microseconds.
+fn duration_weight(duration_ns: u64) -> i32 {
+    i32::try_from(duration_ns / 1000).unwrap_or(i32::MAX)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn process_off_cpu_sample_group(
     off_cpu_sample: OffCpuSampleGroup,
     thread_handle: ThreadHandle,
     cpu_delta_ns: u64,
     timestamp_converter: &TimestampConverter,
-    off_cpu_weight_per_sample: i32,
+    options: OffCpuSampleOptions,
     off_cpu_stack: UnresolvedStackHandle,
+    reason: Option<OffCpuReason>,
     samples: &mut UnresolvedSamples,
 ) {
     let OffCpuSampleGroup {
         begin_timestamp,
         end_timestamp,
         sample_count,
+        duration,
     } = off_cpu_sample;
 
+    let emitted_sample_count = match (sample_count, options.sample_per_interval) {
+        (1, _) => 1,
+        (_, true) => sample_count,
+        (_, false) => 2,
+    };
+    // With blocked-time weights, the samples share the group's duration, and
+    // the last one gets the rounding remainder. Otherwise, each sample counts
+    // once per interval it stands for.
+    let weight_for_sample = |index: u64, interval_count: u64| {
+        if options.blocked_time_weights {
+            let total = duration_weight(duration);
+            let per_sample = total / emitted_sample_count as i32;
+            if index + 1 == emitted_sample_count {
+                total - per_sample * (emitted_sample_count as i32 - 1)
+            } else {
+                per_sample
+            }
+        } else {
+            i32::try_from(interval_count).unwrap_or(0) * options.weight_per_sample
+        }
+    };
+    // Off-CPU samples get their own category if we know why the thread was
+    // off-CPU, or if their weight is blocked time rather than a sample count.
+    let reason = match (reason, options.blocked_time_weights) {
+        (Some(reason), _) => Some(reason),
+        (None, true) => Some(OffCpuReason::Unknown),
+        (None, false) => None,
+    };
+    let mut add_sample = |timestamp: u64, cpu_delta: CpuDelta, weight: i32| {
+        let profile_timestamp = timestamp_converter.convert_time(timestamp);
+        if let Some(reason) = reason {
+            samples.add_off_cpu_sample(
+                thread_handle,
+                profile_timestamp,
+                timestamp,
+                off_cpu_stack,
+                cpu_delta,
+                weight,
+                reason,
+            );
+        } else {
+            samples.add_sample(
+                thread_handle,
+                profile_timestamp,
+                timestamp,
+                off_cpu_stack,
+                cpu_delta,
+                weight,
+                None,
+            );
+        }
+    };
+
     // Add a sample at the beginning of the paused range.
     // This "first sample" will carry any leftover accumulated running time ("cpu delta").
-    let cpu_delta = CpuDelta::from_nanos(cpu_delta_ns);
-    let weight = off_cpu_weight_per_sample;
-    let stack = off_cpu_stack;
-    let profile_timestamp = timestamp_converter.convert_time(begin_timestamp);
-    samples.add_sample(
-        thread_handle,
-        profile_timestamp,
+    add_sample(
         begin_timestamp,
-        stack,
-        cpu_delta,
-        weight,
-        None,
+        CpuDelta::from_nanos(cpu_delta_ns),
+        weight_for_sample(0, 1),
     );
 
-    if sample_count > 1 {
+    if emitted_sample_count > 2 || (emitted_sample_count == 2 && options.sample_per_interval) {
+        // Emit one sample per sampling interval, like a wall-clock profiler would,
+        // so that the blocked time is visible in the timeline.
+        let interval = (end_timestamp - begin_timestamp) / (sample_count - 1);
+        for i in 1..sample_count {
+            add_sample(
+                begin_timestamp + i * interval,
+                CpuDelta::from_nanos(0),
+                weight_for_sample(i, 1),
+            );
+        }
+    } else if emitted_sample_count == 2 {
         // Emit a "rest sample" with a CPU delta of zero covering the rest of the paused range.
-        let cpu_delta = CpuDelta::from_nanos(0);
-        let weight = i32::try_from(sample_count - 1).unwrap_or(0) * off_cpu_weight_per_sample;
-        let profile_timestamp = timestamp_converter.convert_time(end_timestamp);
-        samples.add_sample(
-            thread_handle,
-            profile_timestamp,
-            begin_timestamp,
-            stack,
-            cpu_delta,
-            weight,
-            None,
+        add_sample(
+            end_timestamp,
+            CpuDelta::from_nanos(0),
+            weight_for_sample(1, sample_count - 1),
         );
     }
 }
@@ -1302,3 +2395,37 @@ fn get_pe_mapping_size(path_slice: &[u8]) -> Option<u64> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use object::write::Object;
+    use object::{Architecture, BinaryFormat, Endianness, SectionKind};
+
+    use super::*;
+
+    fn elf_with_sections(section_names: &[&str]) -> Vec<u8> {
+        let mut obj = Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+        let text = obj.add_section(Vec::new(), b".text".to_vec(), SectionKind::Text);
+        obj.append_section_data(text, &[0xc3], 1);
+        for name in section_names {
+            let section = obj.add_section(Vec::new(), name.as_bytes().to_vec(), SectionKind::Debug);
+            obj.append_section_data(section, &[0; 4], 1);
+        }
+        obj.write().unwrap()
+    }
+
+    #[test]
+    fn detects_debug_info_and_debuglinks() {
+        for (sections, expected) in [
+            (&[][..], false),
+            (&[".debug_info"][..], true),
+            (&[".zdebug_info"][..], true),
+            (&[".gnu_debuglink"][..], true),
+            (&[".debug_line", ".debug_frame"][..], false),
+        ] {
+            let data = elf_with_sections(sections);
+            let file = object::File::parse(&data[..]).unwrap();
+            assert_eq!(has_debug_info(&file), expected, "{sections:?}");
+        }
+    }
+}
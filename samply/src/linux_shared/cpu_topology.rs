@@ -0,0 +1,313 @@
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use linux_perf_data::Endianness;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Where each CPU sits in the machine. Used to group the per-CPU tracks.
+#[derive(Debug, Clone, Default)]
+pub struct CpuTopology {
+    cpus: HashMap<u32, CpuTopologyInfo>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CpuTopologyInfo {
+    pub package_id: Option<u32>,
+    pub core_id: Option<u32>,
+    pub numa_node: Option<u32>,
+    /// The other hardware threads on the same core, not including this CPU.
+    pub smt_siblings: Vec<u32>,
+}
+
+/// The group which a CPU track is placed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CpuGroup {
+    NumaNode(u32),
+    Package(u32),
+    Unknown,
+}
+
+impl CpuTopology {
+    /// Reads the topology of the running machine from sysfs.
+    pub fn from_sysfs() -> Self {
+        let mut topology = CpuTopology::default();
+        let Ok(online) = std::fs::read_to_string("/sys/devices/system/cpu/online") else {
+            return topology;
+        };
+        for cpu in parse_cpu_list(&online) {
+            let dir = format!("/sys/devices/system/cpu/cpu{cpu}/topology");
+            let dir = Path::new(&dir);
+            let read_u32 = |name: &str| -> Option<u32> {
+                std::fs::read_to_string(dir.join(name))
+                    .ok()?
+                    .trim()
+                    .parse()
+                    .ok()
+            };
+            let smt_siblings = std::fs::read_to_string(dir.join("thread_siblings_list"))
+                .map(|list| parse_cpu_list(&list))
+                .unwrap_or_default();
+            topology.cpus.insert(
+                cpu,
+                CpuTopologyInfo {
+                    package_id: read_u32("physical_package_id"),
+                    core_id: read_u32("core_id"),
+                    numa_node: None,
+                    smt_siblings: smt_siblings.into_iter().filter(|c| *c != cpu).collect(),
+                },
+            );
+        }
+        if let Ok(nodes) = std::fs::read_to_string("/sys/devices/system/node/online") {
+            for node in parse_cpu_list(&nodes) {
+                let path = format!("/sys/devices/system/node/node{node}/cpulist");
+                let Ok(cpu_list) = std::fs::read_to_string(path) else {
+                    continue;
+                };
+                for cpu in parse_cpu_list(&cpu_list) {
+                    topology.cpus.entry(cpu).or_default().numa_node = Some(node);
+                }
+            }
+        }
+        topology
+    }
+
+    /// Reads the topology from the `HEADER_CPU_TOPOLOGY` and `HEADER_NUMA_TOPOLOGY`
+    /// feature sections of a perf.data file.
+    pub fn from_perf_file_sections(
+        cpu_topology: Option<&[u8]>,
+        numa_topology: Option<&[u8]>,
+        endian: Endianness,
+    ) -> Self {
+        match endian {
+            Endianness::LittleEndian => {
+                Self::from_perf_file_sections_impl::<LittleEndian>(cpu_topology, numa_topology)
+            }
+            Endianness::BigEndian => {
+                Self::from_perf_file_sections_impl::<BigEndian>(cpu_topology, numa_topology)
+            }
+        }
+    }
+
+    fn from_perf_file_sections_impl<O: ByteOrder>(
+        cpu_topology: Option<&[u8]>,
+        numa_topology: Option<&[u8]>,
+    ) -> Self {
+        let mut topology = CpuTopology::default();
+        if let Some(data) = cpu_topology {
+            // Sections which are cut short just leave the remaining info empty.
+            let _ = topology.parse_cpu_topology_section::<O>(data);
+        }
+        if let Some(data) = numa_topology {
+            let _ = topology.parse_numa_topology_section::<O>(data);
+        }
+        topology
+    }
+
+    /// ```text
+    /// struct {
+    ///     u32 nr_cores_sib;
+    ///     struct perf_header_string cpus[nr_cores_sib]; // CPUs per package
+    ///     u32 nr_threads_sib;
+    ///     struct perf_header_string cpus[nr_threads_sib]; // CPUs per core
+    ///     struct { u32 core_id; u32 socket_id; } cpus[nr]; // since perf 4.5
+    /// };
+    /// ```
+    fn parse_cpu_topology_section<O: ByteOrder>(&mut self, data: &[u8]) -> Option<()> {
+        let mut reader = SectionReader::<O>::new(data);
+        let package_count = reader.read_u32()?;
+        let mut package_cpu_lists = Vec::new();
+        for _ in 0..package_count {
+            package_cpu_lists.push(parse_cpu_list(reader.read_string()?));
+        }
+        let core_count = reader.read_u32()?;
+        for _ in 0..core_count {
+            let siblings = parse_cpu_list(reader.read_string()?);
+            for &cpu in &siblings {
+                self.cpus.entry(cpu).or_default().smt_siblings =
+                    siblings.iter().copied().filter(|c| *c != cpu).collect();
+            }
+        }
+        // Older perf versions don't write the per-CPU IDs, so fall back to
+        // numbering the packages in order.
+        for (package_index, cpus) in package_cpu_lists.iter().enumerate() {
+            for &cpu in cpus {
+                self.cpus.entry(cpu).or_default().package_id = Some(package_index as u32);
+            }
+        }
+        // The per-CPU IDs are indexed by CPU number, and are followed by die
+        // information in newer perf versions. CPU numbers don't need to be
+        // contiguous, e.g. if some CPUs are offline, so read entries up to the
+        // highest CPU number in the sibling lists. Offline CPUs have the ID -1.
+        let cpu_count = self.cpus.keys().max().map_or(0, |max_cpu| max_cpu + 1);
+        for cpu in 0..cpu_count {
+            let core_id = reader.read_u32()?;
+            let socket_id = reader.read_u32()?;
+            if core_id == u32::MAX {
+                continue;
+            }
+            if let Some(info) = self.cpus.get_mut(&cpu) {
+                info.core_id = Some(core_id);
+                info.package_id = Some(socket_id);
+            }
+        }
+        Some(())
+    }
+
+    /// ```text
+    /// struct {
+    ///     u32 nr;
+    ///     struct { u32 nodenr; u64 mem_total; u64 mem_free; struct perf_header_string cpus; } nodes[nr];
+    /// };
+    /// ```
+    fn parse_numa_topology_section<O: ByteOrder>(&mut self, data: &[u8]) -> Option<()> {
+        let mut reader = SectionReader::<O>::new(data);
+        let node_count = reader.read_u32()?;
+        for _ in 0..node_count {
+            let node = reader.read_u32()?;
+            let _mem_total = reader.read_u64()?;
+            let _mem_free = reader.read_u64()?;
+            for cpu in parse_cpu_list(reader.read_string()?) {
+                self.cpus.entry(cpu).or_default().numa_node = Some(node);
+            }
+        }
+        Some(())
+    }
+
+    pub fn info(&self, cpu: u32) -> Option<&CpuTopologyInfo> {
+        self.cpus.get(&cpu)
+    }
+
+    /// CPUs are grouped by NUMA node if there is more than one node, otherwise
+    /// by package.
+    pub fn group(&self, cpu: u32) -> CpuGroup {
+        let Some(info) = self.cpus.get(&cpu) else {
+            return CpuGroup::Unknown;
+        };
+        match (info.numa_node, info.package_id) {
+            (Some(node), _) if self.has_multiple_numa_nodes() => CpuGroup::NumaNode(node),
+            (_, Some(package)) => CpuGroup::Package(package),
+            _ => CpuGroup::Unknown,
+        }
+    }
+
+    /// Whether grouping the CPUs would result in more than one group.
+    pub fn has_multiple_groups(&self) -> bool {
+        let mut groups = self.cpus.keys().map(|cpu| self.group(*cpu));
+        match groups.next() {
+            Some(first) => groups.any(|group| group != first),
+            None => false,
+        }
+    }
+
+    fn has_multiple_numa_nodes(&self) -> bool {
+        let mut nodes = self.cpus.values().filter_map(|info| info.numa_node);
+        match nodes.next() {
+            Some(first) => nodes.any(|node| node != first),
+            None => false,
+        }
+    }
+}
+
+/// Parses lists like "0-3,8,10-11".
+fn parse_cpu_list(list: &str) -> Vec<u32> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|s| !s.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => {
+                if let (Ok(start), Ok(end)) = (start.parse::<u32>(), end.parse::<u32>()) {
+                    cpus.extend(start..=end);
+                }
+            }
+            None => {
+                if let Ok(cpu) = range.parse() {
+                    cpus.push(cpu);
+                }
+            }
+        }
+    }
+    cpus
+}
+
+struct SectionReader<'a, O: ByteOrder> {
+    data: &'a [u8],
+    _phantom: std::marker::PhantomData<O>,
+}
+
+impl<'a, O: ByteOrder> SectionReader<'a, O> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        let bytes = self.data.get(..4)?;
+        self.data = &self.data[4..];
+        Some(O::read_u32(bytes))
+    }
+
+    fn read_u64(&mut self) -> Option<u64> {
+        let bytes = self.data.get(..8)?;
+        self.data = &self.data[8..];
+        Some(O::read_u64(bytes))
+    }
+
+    /// A `perf_header_string`: a u32 length followed by that many bytes, which
+    /// include NUL padding.
+    fn read_string(&mut self) -> Option<&'a str> {
+        let len = self.read_u32()? as usize;
+        let bytes = self.data.get(..len)?;
+        self.data = &self.data[len..];
+        let bytes = match bytes.iter().position(|b| *b == 0) {
+            Some(nul_pos) => &bytes[..nul_pos],
+            None => bytes,
+        };
+        std::str::from_utf8(bytes).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n"), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpu_list("5"), vec![5]);
+        assert_eq!(parse_cpu_list(""), Vec::<u32>::new());
+        assert_eq!(parse_cpu_list("0,x,2-y,4"), vec![0, 4]);
+    }
+
+    fn push_string(data: &mut Vec<u8>, s: &str) {
+        let padded_len = (s.len() + 4) / 4 * 4;
+        data.extend_from_slice(&(padded_len as u32).to_le_bytes());
+        data.extend_from_slice(s.as_bytes());
+        data.resize(data.len() + padded_len - s.len(), 0);
+    }
+
+    #[test]
+    fn topology_with_offline_cpus() {
+        // CPUs 2 and 3 are offline. CPUs 0 and 4, and 1 and 5, share a core.
+        let mut data = Vec::new();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        push_string(&mut data, "0-1,4-5");
+        data.extend_from_slice(&2u32.to_le_bytes());
+        push_string(&mut data, "0,4");
+        push_string(&mut data, "1,5");
+        for (core_id, socket_id) in [(0i32, 0i32), (1, 0), (-1, -1), (-1, -1), (0, 0), (1, 0)] {
+            data.extend_from_slice(&core_id.to_le_bytes());
+            data.extend_from_slice(&socket_id.to_le_bytes());
+        }
+
+        let topology =
+            CpuTopology::from_perf_file_sections(Some(&data), None, Endianness::LittleEndian);
+        assert!(topology.info(2).is_none());
+        let cpu5 = topology.info(5).unwrap();
+        assert_eq!(cpu5.core_id, Some(1));
+        assert_eq!(cpu5.package_id, Some(0));
+        assert_eq!(cpu5.smt_siblings, vec![1]);
+        assert_eq!(topology.info(4).unwrap().core_id, Some(0));
+        assert!(!topology.has_multiple_groups());
+    }
+}
@@ -0,0 +1,140 @@
+use fxprof_processed_profile::{
+    CategoryHandle, MarkerTiming, ProcessHandle, Profile, ThreadHandle, Timestamp,
+};
+
+use std::collections::HashMap;
+
+use super::cpu_topology::{CpuGroup, CpuTopology};
+use super::sched_switch::CpuRunningMarker;
+
+/// The per-CPU tracks for `--per-cpu-threads`. They live in separate "CPUs"
+/// pseudo-processes, with one thread per CPU. If the machine has more than one
+/// NUMA node or package, there is one pseudo-process per node / package.
+pub struct Cpus {
+    start_time: Timestamp,
+    topology: CpuTopology,
+    processes: HashMap<CpuGroup, ProcessHandle>,
+    cpus: HashMap<u32, Cpu>,
+}
+
+struct Cpu {
+    thread_handle: ThreadHandle,
+    /// The thread which is currently running on this CPU, and since when. The
+    /// start time is `None` if the thread was already running when the first
+    /// sched_switch event for this CPU was seen.
+    running: Option<(Option<Timestamp>, CpuRunningMarker)>,
+}
+
+impl Cpus {
+    pub fn new(start_time: Timestamp) -> Self {
+        Self {
+            start_time,
+            topology: CpuTopology::default(),
+            processes: HashMap::new(),
+            cpus: HashMap::new(),
+        }
+    }
+
+    /// Must be called before the first CPU track is created.
+    pub fn set_topology(&mut self, topology: CpuTopology) {
+        self.topology = topology;
+    }
+
+    pub fn thread_handle_for_cpu(&mut self, cpu: u32, profile: &mut Profile) -> ThreadHandle {
+        self.get_cpu(cpu, profile).thread_handle
+    }
+
+    /// Called for a sched_switch event on `cpu`. `prev` is the thread which was
+    /// running until now.
+    pub fn handle_switch(
+        &mut self,
+        cpu: u32,
+        timestamp: Timestamp,
+        prev: CpuRunningMarker,
+        next: CpuRunningMarker,
+        profile: &mut Profile,
+    ) {
+        let cpu = self.get_cpu(cpu, profile);
+        let timing = match cpu.running.take().and_then(|(start, _)| start) {
+            Some(start) => MarkerTiming::Interval(start, timestamp),
+            None => MarkerTiming::IntervalEnd(timestamp),
+        };
+        if prev.tid != 0 {
+            // Don't emit markers for the idle task.
+            profile.add_marker(
+                cpu.thread_handle,
+                CategoryHandle::OTHER,
+                "Running",
+                prev,
+                timing,
+            );
+        }
+        cpu.running = Some((Some(timestamp), next));
+    }
+
+    /// Emit markers for the threads which are still running at the end of the profile.
+    pub fn finish(self, profile: &mut Profile) {
+        for cpu in self.cpus.into_values() {
+            if let Some((Some(start), marker)) = cpu.running {
+                if marker.tid != 0 {
+                    profile.add_marker(
+                        cpu.thread_handle,
+                        CategoryHandle::OTHER,
+                        "Running",
+                        marker,
+                        MarkerTiming::IntervalStart(start),
+                    );
+                }
+            }
+        }
+    }
+
+    fn get_cpu(&mut self, cpu: u32, profile: &mut Profile) -> &mut Cpu {
+        let start_time = self.start_time;
+        let topology = &self.topology;
+        let processes = &mut self.processes;
+        self.cpus.entry(cpu).or_insert_with(|| {
+            let group = topology.group(cpu);
+            let process = *processes.entry(group).or_insert_with(|| {
+                let name = match group {
+                    _ if !topology.has_multiple_groups() => "CPUs".to_string(),
+                    CpuGroup::NumaNode(node) => format!("CPUs (NUMA node {node})"),
+                    CpuGroup::Package(package) => format!("CPUs (package {package})"),
+                    CpuGroup::Unknown => "CPUs".to_string(),
+                };
+                profile.add_process(&name, 0, start_time)
+            });
+            let thread_handle = profile.add_thread(process, cpu, start_time, false);
+            profile.set_thread_name(thread_handle, &cpu_thread_name(cpu, topology));
+            Cpu {
+                thread_handle,
+                running: None,
+            }
+        })
+    }
+}
+
+/// For example "CPU 3 (core 1, SMT sibling of CPU 7)".
+fn cpu_thread_name(cpu: u32, topology: &CpuTopology) -> String {
+    let Some(info) = topology.info(cpu) else {
+        return format!("CPU {cpu}");
+    };
+    let mut details = Vec::new();
+    if let Some(core_id) = info.core_id {
+        details.push(format!("core {core_id}"));
+    }
+    if !info.smt_siblings.is_empty() {
+        let siblings: Vec<String> = info
+            .smt_siblings
+            .iter()
+            .map(|sibling| sibling.to_string())
+            .collect();
+        let noun = if siblings.len() == 1 { "CPU" } else { "CPUs" };
+        details.push(format!("SMT sibling of {noun} {}", siblings.join(", ")));
+    }
+    if details.is_empty() {
+        format!("CPU {cpu}")
+    } else {
+        format!("CPU {cpu} ({})", details.join(", "))
+    }
+}
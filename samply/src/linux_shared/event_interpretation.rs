@@ -6,6 +6,11 @@ use linux_perf_event_reader::{AttrFlags, PerfEventType, SamplingPolicy, Software
 use std::collections::HashMap;
 use std::fmt::Debug;
 
+use super::blocking_syscalls::{is_sys_exit_event, reason_for_sys_enter_event};
+use super::gpu_jobs::{GpuJobEventFormat, GPU_JOB_TRACEPOINTS};
+use super::tracepoint_format::TracepointFormat;
+use crate::shared::off_cpu_reason::OffCpuReason;
+
 #[derive(Debug, Clone)]
 pub enum KnownEvent {
     RssStat,
@@ -13,6 +18,16 @@ pub enum KnownEvent {
     MmapExit,
     MprotectEnter,
     PageFault,
+    DrmVblankEvent,
+    /// One of the [`GPU_JOB_TRACEPOINTS`](super::gpu_jobs::GPU_JOB_TRACEPOINTS).
+    GpuJob(GpuJobEventFormat),
+    SndPcmXrun,
+    FutexEnter,
+    FutexExit,
+    /// The start of one of the
+    /// [`BLOCKING_SYSCALLS`](super::blocking_syscalls::BLOCKING_SYSCALLS).
+    SyscallEnter(OffCpuReason),
+    SyscallExit,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -37,21 +52,26 @@ pub struct EventInterpretation {
 }
 
 impl EventInterpretation {
-    pub fn divine_from_attrs(attrs: &[AttributeDescription]) -> Self {
+    /// Returns `None` if there are no events, or if the first event, which is
+    /// treated as the main event, isn't sampled.
+    ///
+    /// `tracepoint_formats` are the formats from the file's tracing data, keyed
+    /// by "system:name". Tracepoints whose fields are found by name, like the
+    /// GPU job ones, are only known if their format is there.
+    pub fn divine_from_attrs(
+        attrs: &[AttributeDescription],
+        tracepoint_formats: &HashMap<String, TracepointFormat>,
+    ) -> Option<Self> {
         let main_event_attr_index = 0;
-        let main_event_name = attrs[0]
+        let main_event_name = attrs
+            .first()?
             .name
             .as_deref()
             .unwrap_or("<unnamed event>")
             .to_string();
         let sampling_is_time_based = match (attrs[0].attr.type_, attrs[0].attr.sampling_policy) {
-            (_, SamplingPolicy::NoSampling) => {
-                panic!("Can only convert profiles with sampled events")
-            }
-            (_, SamplingPolicy::Frequency(freq)) => {
-                let nanos = 1_000_000_000 / freq;
-                Some(nanos)
-            }
+            (_, SamplingPolicy::NoSampling) => return None,
+            (_, SamplingPolicy::Frequency(freq)) => 1_000_000_000u64.checked_div(freq),
             (
                 PerfEventType::Software(
                     SoftwareCounterType::CpuClock | SoftwareCounterType::TaskClock,
@@ -81,6 +101,10 @@ impl EventInterpretation {
             ("syscalls:sys_enter_mprotect", KnownEvent::MprotectEnter),
             ("syscalls:sys_enter_mmap", KnownEvent::MmapEnter),
             ("syscalls:sys_exit_mmap", KnownEvent::MmapExit),
+            ("drm:drm_vblank_event", KnownEvent::DrmVblankEvent),
+            ("snd_pcm:xrun", KnownEvent::SndPcmXrun),
+            ("syscalls:sys_enter_futex", KnownEvent::FutexEnter),
+            ("syscalls:sys_exit_futex", KnownEvent::FutexExit),
         ];
 
         for (event_name, event) in known_events {
@@ -92,6 +116,32 @@ impl EventInterpretation {
             }
         }
 
+        for (index, attr_desc) in attrs.iter().enumerate() {
+            let Some(name) = attr_desc.name.as_deref() else {
+                continue;
+            };
+            let gpu_job_phase = GPU_JOB_TRACEPOINTS
+                .iter()
+                .find(|(system, tracepoint, _)| {
+                    name.strip_prefix(system)
+                        .and_then(|rest| rest.strip_prefix(':'))
+                        == Some(tracepoint)
+                })
+                .map(|(_, _, phase)| *phase);
+            if let Some(phase) = gpu_job_phase {
+                let format = tracepoint_formats
+                    .get(name)
+                    .and_then(|format| GpuJobEventFormat::new(phase, format));
+                if let Some(format) = format {
+                    known_event_indices.insert(index, KnownEvent::GpuJob(format));
+                }
+            } else if let Some(reason) = reason_for_sys_enter_event(name) {
+                known_event_indices.insert(index, KnownEvent::SyscallEnter(reason));
+            } else if is_sys_exit_event(name) {
+                known_event_indices.insert(index, KnownEvent::SyscallExit);
+            }
+        }
+
         let event_names = attrs
             .iter()
             .enumerate()
@@ -103,7 +153,7 @@ impl EventInterpretation {
             })
             .collect();
 
-        Self {
+        Some(Self {
             main_event_attr_index,
             main_event_name,
             sampling_is_time_based,
@@ -111,6 +161,6 @@ impl EventInterpretation {
             sched_switch_attr_index,
             known_event_indices,
             event_names,
-        }
+        })
     }
 }
@@ -0,0 +1,281 @@
+use byteorder::ByteOrder;
+use fxprof_processed_profile::{
+    CategoryHandle, MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema,
+    MarkerSchemaField, MarkerTiming, Profile, ProfilerMarker, ThreadHandle, Timestamp,
+};
+use linux_perf_data::linux_perf_event_reader;
+use linux_perf_data::Endianness;
+use serde_json::json;
+
+use linux_perf_event_reader::RawData;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use crate::shared::unresolved_samples::UnresolvedStackHandle;
+
+const FUTEX_WAIT: i32 = 0;
+const FUTEX_LOCK_PI: i32 = 6;
+const FUTEX_WAIT_BITSET: i32 = 9;
+const FUTEX_LOCK_PI2: i32 = 13;
+const FUTEX_CMD_MASK: i32 = !(128 | 256); // ~(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME)
+
+/// ```text
+/// # cat /sys/kernel/tracing/events/syscalls/sys_enter_futex/format
+/// name: sys_enter_futex
+/// ID: 455
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:int __syscall_nr; offset:8;       size:4; signed:1;
+///         field:u32 * uaddr;      offset:16;      size:8; signed:0;
+///         field:int op;   offset:24;      size:8; signed:0;
+///         field:u32 val;  offset:32;      size:8; signed:0;
+///         field:const struct __kernel_timespec * utime;   offset:40;      size:8; signed:0;
+///         field:u32 * uaddr2;     offset:48;      size:8; signed:0;
+///         field:u32 val3; offset:56;      size:8; signed:0;
+/// ```
+#[derive(Debug)]
+pub struct SysEnterFutex {
+    pub uaddr: u64,
+    pub op: i32,
+}
+
+impl SysEnterFutex {
+    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
+        data.skip(16)?;
+        let uaddr = data.read_u64::<O>()?;
+        let op = data.read_u64::<O>()? as i32;
+        Ok(SysEnterFutex { uaddr, op })
+    }
+
+    /// Whether this futex call blocks until another thread releases the futex,
+    /// as opposed to e.g. a wake-up call. FUTEX_WAIT_REQUEUE_PI is only used for
+    /// waiting on condition variables, so it isn't counted.
+    pub fn is_wait(&self) -> bool {
+        matches!(
+            self.op & FUTEX_CMD_MASK,
+            FUTEX_WAIT | FUTEX_LOCK_PI | FUTEX_WAIT_BITSET | FUTEX_LOCK_PI2
+        )
+    }
+}
+
+/// ```text
+/// # cat /sys/kernel/tracing/events/syscalls/sys_exit_futex/format
+/// name: sys_exit_futex
+/// ID: 454
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:int __syscall_nr; offset:8;       size:4; signed:1;
+///         field:long ret; offset:16;      size:8; signed:1;
+/// ```
+#[derive(Debug)]
+pub struct SysExitFutex {
+    pub ret: i64,
+}
+
+impl SysExitFutex {
+    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
+        data.skip(16)?;
+        let ret = data.read_u64::<O>()? as i64;
+        Ok(SysExitFutex { ret })
+    }
+
+    /// Whether the thread waited until the holder of the lock woke it up. The
+    /// call returns EAGAIN if the futex value had already changed, i.e. the lock
+    /// was released before the thread went to sleep. A wait which timed out or
+    /// was interrupted by a signal didn't end because the lock was released, so
+    /// it isn't counted as contention either.
+    pub fn was_woken(&self) -> bool {
+        self.ret == 0
+    }
+}
+
+/// A futex wait which has started but not finished, stored on the waiting thread.
+#[derive(Debug, Clone)]
+pub struct PendingFutexWait {
+    pub address: u64,
+    pub start_time_mono: u64,
+    pub stack: UnresolvedStackHandle,
+}
+
+/// The waits on one futex in one process.
+#[derive(Debug, Clone)]
+struct LockContention {
+    wait_count: u64,
+    total_wait_ns: u64,
+    max_wait_ns: u64,
+    first_wait_start: Timestamp,
+    last_wait_end: Timestamp,
+}
+
+/// Sums up the futex waits of a process per futex address, so that the most
+/// contended locks can be found without going through all the wait markers.
+#[derive(Debug, Clone, Default)]
+pub struct LockContentionStats {
+    locks: HashMap<u64, LockContention>,
+}
+
+impl LockContentionStats {
+    pub fn add_wait(&mut self, address: u64, start: Timestamp, end: Timestamp, duration_ns: u64) {
+        let lock = self.locks.entry(address).or_insert(LockContention {
+            wait_count: 0,
+            total_wait_ns: 0,
+            max_wait_ns: 0,
+            first_wait_start: start,
+            last_wait_end: end,
+        });
+        lock.wait_count += 1;
+        lock.total_wait_ns += duration_ns;
+        lock.max_wait_ns = lock.max_wait_ns.max(duration_ns);
+        lock.first_wait_start = lock.first_wait_start.min(start);
+        lock.last_wait_end = lock.last_wait_end.max(end);
+    }
+
+    /// Adds a "Lock contention" marker per futex to `thread`, from the first
+    /// wait to the end of the last one, with the most contended lock first.
+    pub fn add_summary_markers(self, profile: &mut Profile, thread: ThreadHandle) {
+        let mut locks: Vec<(u64, LockContention)> = self.locks.into_iter().collect();
+        locks.sort_by(|(a_addr, a), (b_addr, b)| {
+            (b.total_wait_ns, a_addr).cmp(&(a.total_wait_ns, b_addr))
+        });
+        for (address, lock) in locks {
+            profile.add_marker(
+                thread,
+                CategoryHandle::OTHER,
+                "Lock contention",
+                LockContentionMarker {
+                    address,
+                    wait_count: lock.wait_count,
+                    total_wait_ms: lock.total_wait_ns as f64 / 1_000_000.0,
+                    max_wait_ms: lock.max_wait_ns as f64 / 1_000_000.0,
+                },
+                MarkerTiming::Interval(lock.first_wait_start, lock.last_wait_end),
+            );
+        }
+    }
+}
+
+/// The summary of all waits on one futex in a process.
+#[derive(Debug, Clone)]
+pub struct LockContentionMarker {
+    pub address: u64,
+    pub wait_count: u64,
+    pub total_wait_ms: f64,
+    pub max_wait_ms: f64,
+}
+
+impl ProfilerMarker for LockContentionMarker {
+    const MARKER_TYPE_NAME: &'static str = "LockContention";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "address": format!("{:#x}", self.address),
+            "waitCount": self.wait_count,
+            "totalWait": self.total_wait_ms,
+            "maxWait": self.max_wait_ms,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.address}: {marker.data.totalWait}"),
+            tooltip_label: Some(
+                "{marker.data.address}: {marker.data.waitCount} waits, {marker.data.totalWait} in total",
+            ),
+            table_label: Some(
+                "{marker.data.address}: {marker.data.waitCount} waits, {marker.data.totalWait} in total, at most {marker.data.maxWait}",
+            ),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "address",
+                    label: "Futex address",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "waitCount",
+                    label: "Waits",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "totalWait",
+                    label: "Total wait time",
+                    format: MarkerFieldFormat::Milliseconds,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "maxWait",
+                    label: "Longest wait",
+                    format: MarkerFieldFormat::Milliseconds,
+                    searchable: false,
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SysEnterFutex, SysExitFutex};
+
+    use linux_perf_data::linux_perf_event_reader::RawData;
+    use linux_perf_data::Endianness;
+
+    #[test]
+    fn parse_futex_events() {
+        let mut enter = vec![0u8; 64];
+        enter[16..24].copy_from_slice(&0x7f00_1234u64.to_le_bytes());
+        enter[24..32].copy_from_slice(&(128u64 | 9).to_le_bytes()); // FUTEX_WAIT_BITSET_PRIVATE
+        let enter =
+            SysEnterFutex::parse(RawData::Single(&enter), Endianness::LittleEndian).unwrap();
+        assert_eq!(enter.uaddr, 0x7f00_1234);
+        assert!(enter.is_wait());
+
+        let mut wake = vec![0u8; 64];
+        wake[24..32].copy_from_slice(&(128u64 | 1).to_le_bytes()); // FUTEX_WAKE_PRIVATE
+        let wake = SysEnterFutex::parse(RawData::Single(&wake), Endianness::LittleEndian).unwrap();
+        assert!(!wake.is_wait());
+
+        let mut requeue = vec![0u8; 64];
+        requeue[24..32].copy_from_slice(&(128u64 | 11).to_le_bytes()); // FUTEX_WAIT_REQUEUE_PI_PRIVATE
+        let requeue =
+            SysEnterFutex::parse(RawData::Single(&requeue), Endianness::LittleEndian).unwrap();
+        assert!(!requeue.is_wait());
+
+        let exit_with_ret = |ret: i64| {
+            let mut exit = vec![0u8; 24];
+            exit[16..24].copy_from_slice(&ret.to_le_bytes());
+            SysExitFutex::parse(RawData::Single(&exit), Endianness::LittleEndian).unwrap()
+        };
+        assert!(exit_with_ret(0).was_woken());
+        assert!(!exit_with_ret(-11).was_woken()); // EAGAIN
+        assert!(!exit_with_ret(-110).was_woken()); // ETIMEDOUT
+        assert!(!exit_with_ret(-4).was_woken()); // EINTR
+    }
+}
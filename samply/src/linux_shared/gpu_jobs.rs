@@ -0,0 +1,352 @@
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use linux_perf_data::Endianness;
+use serde_json::json;
+
+use super::tracepoint_format::{TracepointField, TracepointFormat};
+
+/// The point in a GPU job's life which a tracepoint marks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuJobPhase {
+    /// The job was queued, in the context of the submitting thread.
+    Queued,
+    /// The job was handed to the hardware.
+    Started,
+    /// The hardware signaled that the job is done.
+    Finished,
+}
+
+/// The tracepoints for the phases of GPU jobs, as (system, name, phase).
+///
+/// Drivers which use the DRM scheduler, e.g. amdgpu, xe, panfrost and v3d,
+/// have the gpu_scheduler tracepoints. i915 has its own, and the ones for
+/// starting and finishing a request only exist in kernels built with
+/// CONFIG_DRM_I915_LOW_LEVEL_TRACEPOINTS.
+pub const GPU_JOB_TRACEPOINTS: &[(&str, &str, GpuJobPhase)] = &[
+    ("gpu_scheduler", "drm_sched_job", GpuJobPhase::Queued),
+    ("gpu_scheduler", "drm_run_job", GpuJobPhase::Started),
+    (
+        "gpu_scheduler",
+        "drm_sched_process_job",
+        GpuJobPhase::Finished,
+    ),
+    ("i915", "i915_request_add", GpuJobPhase::Queued),
+    ("i915", "i915_request_in", GpuJobPhase::Started),
+    ("i915", "i915_request_out", GpuJobPhase::Finished),
+];
+
+/// The fields which identify a job across its tracepoints, in order of
+/// preference. Up to Linux 6.16, the gpu_scheduler tracepoints have the
+/// address of the job's "finished" fence. Later versions have the fence's
+/// context and sequence number instead. i915 requests are identified by
+/// their device, context and sequence number.
+const JOB_KEY_FIELDS: &[&[&str]] = &[
+    &["fence"],
+    &["fence_context", "fence_seqno"],
+    &["dev", "ctx", "seqno"],
+];
+
+/// The fields which hold the job number shown in the marker.
+const JOB_ID_FIELDS: &[&str] = &["id", "fence_seqno", "seqno"];
+
+/// How to get the fields of a GPU job tracepoint. The field offsets come from
+/// the tracepoint's format, because they differ between kernel versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpuJobEventFormat {
+    pub phase: GpuJobPhase,
+    key: [Option<TracepointField>; 3],
+    ring: Option<RingFormat>,
+    job_id: Option<TracepointField>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RingFormat {
+    /// The ring's name, e.g. "gfx" or "sdma0", in a `__data_loc` string.
+    Name(TracepointField),
+    /// i915's engine class and instance, which are turned into a name like "rcs0".
+    I915Engine {
+        class: TracepointField,
+        instance: TracepointField,
+    },
+}
+
+/// The fields of a GPU job tracepoint sample.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuJobEvent {
+    /// Identifies the job across its tracepoints.
+    pub key: [u64; 3],
+    pub ring: Option<String>,
+    pub job_id: Option<u64>,
+}
+
+impl GpuJobEventFormat {
+    /// Returns `None` if the format has none of the fields which identify a job.
+    pub fn new(phase: GpuJobPhase, format: &TracepointFormat) -> Option<Self> {
+        let key_fields = JOB_KEY_FIELDS.iter().find_map(|names| {
+            names
+                .iter()
+                .map(|name| format.field(name))
+                .collect::<Option<Vec<_>>>()
+        })?;
+        let mut key = [None; 3];
+        for (slot, field) in key.iter_mut().zip(key_fields) {
+            *slot = Some(field);
+        }
+        let ring = match (
+            format.field("name"),
+            format.field("class"),
+            format.field("instance"),
+        ) {
+            (Some(name), _, _) => Some(RingFormat::Name(name)),
+            (None, Some(class), Some(instance)) => Some(RingFormat::I915Engine { class, instance }),
+            _ => None,
+        };
+        let job_id = JOB_ID_FIELDS.iter().find_map(|name| format.field(name));
+        Some(Self {
+            phase,
+            key,
+            ring,
+            job_id,
+        })
+    }
+
+    pub fn parse(&self, data: &[u8], endian: Endianness) -> Option<GpuJobEvent> {
+        let mut key = [0; 3];
+        for (value, field) in key.iter_mut().zip(&self.key) {
+            if let Some(field) = field {
+                *value = field.read_u64(data, endian)?;
+            }
+        }
+        let ring = match self.ring {
+            Some(RingFormat::Name(name)) => name.read_str(data, endian),
+            Some(RingFormat::I915Engine { class, instance }) => {
+                let class = class.read_u64(data, endian)?;
+                let instance = instance.read_u64(data, endian)?;
+                Some(i915_engine_name(class, instance))
+            }
+            None => None,
+        };
+        let job_id = self.job_id.and_then(|field| field.read_u64(data, endian));
+        Some(GpuJobEvent { key, ring, job_id })
+    }
+}
+
+/// The engine names which i915 uses in its logs, e.g. "rcs0" or "vcs1".
+fn i915_engine_name(class: u64, instance: u64) -> String {
+    let class_name = match class {
+        0 => "rcs",
+        1 => "bcs",
+        2 => "vcs",
+        3 => "vecs",
+        4 => "ccs",
+        _ => return format!("class{class}:{instance}"),
+    };
+    format!("{class_name}{instance}")
+}
+
+/// A GPU job which has been queued or started, but hasn't finished yet.
+/// Keyed by [`GpuJobEvent::key`] in the converter.
+#[derive(Debug, Clone)]
+pub struct PendingGpuJob {
+    pub ring: String,
+    pub job_id: u64,
+    /// The process and thread which queued the job, if the queue event was seen.
+    pub submitter: Option<(i32, i32)>,
+    pub queue_time_mono: Option<u64>,
+    pub start_time_mono: Option<u64>,
+}
+
+/// A job's execution on a GPU ring, from the time it was handed to the
+/// hardware until the hardware signaled its completion.
+#[derive(Debug, Clone)]
+pub struct GpuJobMarker {
+    pub ring: String,
+    pub job_id: u64,
+    pub submitter_pid: Option<i32>,
+    /// The time between queueing the job and handing it to the hardware.
+    pub queue_delay_ms: Option<f64>,
+}
+
+impl ProfilerMarker for GpuJobMarker {
+    const MARKER_TYPE_NAME: &'static str = "GpuJob";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "ring": self.ring,
+            "jobId": self.job_id,
+            "pid": self.submitter_pid,
+            "queueDelay": self.queue_delay_ms,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.ring} {marker.data.jobId}"),
+            tooltip_label: Some(
+                "GPU job {marker.data.jobId} on {marker.data.ring}, submitted by pid {marker.data.pid}",
+            ),
+            table_label: Some(
+                "{marker.data.ring}: job {marker.data.jobId}, pid {marker.data.pid}",
+            ),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "ring",
+                    label: "Ring",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "jobId",
+                    label: "Job ID",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "pid",
+                    label: "Submitting process",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "queueDelay",
+                    label: "Queue delay",
+                    format: MarkerFieldFormat::Milliseconds,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "From the gpu_scheduler:drm_run_job or i915:i915_request_in tracepoint, when the job was handed to the GPU, until the gpu_scheduler:drm_sched_process_job or i915:i915_request_out tracepoint, when the GPU finished it.",
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn field_line(declaration: &str, offset: usize, size: usize) -> String {
+        format!("\tfield:{declaration};\toffset:{offset};\tsize:{size};\tsigned:0;\n")
+    }
+
+    fn format(name: &str, fields: &[(&str, usize, usize)]) -> TracepointFormat {
+        let mut text = format!("name: {name}\nID: 1\nformat:\n");
+        text += &field_line("unsigned short common_type", 0, 2);
+        for (declaration, offset, size) in fields {
+            text += &field_line(declaration, *offset, *size);
+        }
+        TracepointFormat::parse(&text)
+    }
+
+    #[test]
+    fn drm_sched_events_up_to_linux_6_16() {
+        let run_job = format(
+            "drm_run_job",
+            &[
+                ("struct drm_sched_entity * entity", 8, 8),
+                ("struct dma_fence * fence", 16, 8),
+                ("__data_loc char[] name", 24, 4),
+                ("uint64_t id", 32, 8),
+            ],
+        );
+        let run_job = GpuJobEventFormat::new(GpuJobPhase::Started, &run_job).unwrap();
+        let mut data = vec![0u8; 40];
+        data[16..24].copy_from_slice(&0xffff_8881_0000_1000u64.to_le_bytes());
+        data[24..28].copy_from_slice(&((4u32 << 16) | 40).to_le_bytes());
+        data[32..40].copy_from_slice(&1234u64.to_le_bytes());
+        data.extend_from_slice(b"gfx\0");
+        assert_eq!(
+            run_job.parse(&data, Endianness::LittleEndian),
+            Some(GpuJobEvent {
+                key: [0xffff_8881_0000_1000, 0, 0],
+                ring: Some("gfx".to_string()),
+                job_id: Some(1234),
+            })
+        );
+
+        let process_job = format(
+            "drm_sched_process_job",
+            &[("struct dma_fence * fence", 8, 8)],
+        );
+        let process_job = GpuJobEventFormat::new(GpuJobPhase::Finished, &process_job).unwrap();
+        let mut data = vec![0u8; 16];
+        data[8..16].copy_from_slice(&0xffff_8881_0000_1000u64.to_le_bytes());
+        assert_eq!(
+            process_job.parse(&data, Endianness::LittleEndian),
+            Some(GpuJobEvent {
+                key: [0xffff_8881_0000_1000, 0, 0],
+                ring: None,
+                job_id: None,
+            })
+        );
+        assert_eq!(
+            process_job.parse(&data[..12], Endianness::LittleEndian),
+            None
+        );
+    }
+
+    #[test]
+    fn drm_sched_events_with_fence_context() {
+        let process_job = format(
+            "drm_sched_process_job",
+            &[("u64 fence_context", 8, 8), ("u64 fence_seqno", 16, 8)],
+        );
+        let process_job = GpuJobEventFormat::new(GpuJobPhase::Finished, &process_job).unwrap();
+        let mut data = vec![0u8; 24];
+        data[8..16].copy_from_slice(&5u64.to_le_bytes());
+        data[16..24].copy_from_slice(&77u64.to_le_bytes());
+        assert_eq!(
+            process_job.parse(&data, Endianness::LittleEndian),
+            Some(GpuJobEvent {
+                key: [5, 77, 0],
+                ring: None,
+                job_id: Some(77),
+            })
+        );
+    }
+
+    #[test]
+    fn i915_request_events() {
+        let request_in = format(
+            "i915_request_in",
+            &[
+                ("u32 dev", 8, 4),
+                ("u64 ctx", 16, 8),
+                ("u16 class", 24, 2),
+                ("u16 instance", 26, 2),
+                ("u32 seqno", 28, 4),
+            ],
+        );
+        let request_in = GpuJobEventFormat::new(GpuJobPhase::Started, &request_in).unwrap();
+        let mut data = vec![0u8; 32];
+        data[8..12].copy_from_slice(&226u32.to_be_bytes());
+        data[16..24].copy_from_slice(&9u64.to_be_bytes());
+        data[24..26].copy_from_slice(&2u16.to_be_bytes());
+        data[26..28].copy_from_slice(&1u16.to_be_bytes());
+        data[28..32].copy_from_slice(&42u32.to_be_bytes());
+        assert_eq!(
+            request_in.parse(&data, Endianness::BigEndian),
+            Some(GpuJobEvent {
+                key: [226, 9, 42],
+                ring: Some("vcs1".to_string()),
+                job_id: Some(42),
+            })
+        );
+    }
+
+    #[test]
+    fn formats_without_a_job_key_are_rejected() {
+        let vblank = format("drm_vblank_event", &[("int crtc", 8, 4)]);
+        assert!(GpuJobEventFormat::new(GpuJobPhase::Finished, &vblank).is_none());
+    }
+}
@@ -0,0 +1,50 @@
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use serde_json::json;
+
+/// The lifetime of one process, when the processes with the same name are
+/// aggregated into a single track with `--aggregate-by-name`, or when a
+/// process reuses the track of an exited process with `--reuse-threads`.
+#[derive(Debug, Clone)]
+pub struct InvocationMarker {
+    pub pid: i32,
+}
+
+impl ProfilerMarker for InvocationMarker {
+    const MARKER_TYPE_NAME: &'static str = "Invocation";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "pid": self.pid,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.pid}"),
+            tooltip_label: Some("{marker.name} (pid {marker.data.pid})"),
+            table_label: Some("{marker.name} (pid {marker.data.pid})"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "pid",
+                    label: "Process ID",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "One run of a process which was merged with the other processes of the same name.",
+                }),
+            ],
+        }
+    }
+}
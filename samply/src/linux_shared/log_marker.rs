@@ -0,0 +1,76 @@
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use serde_json::json;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+/// A line which the profiled process printed to stdout or stderr.
+#[derive(Debug, Clone)]
+pub struct LogMarker {
+    pub stream: OutputStream,
+    pub text: String,
+    /// The line number in the file which `--tee-output` wrote this stream to.
+    pub tee_line: Option<u64>,
+}
+
+impl ProfilerMarker for LogMarker {
+    const MARKER_TYPE_NAME: &'static str = "Log";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "stream": self.stream.name(),
+            "text": self.text,
+            "teeLine": self.tee_line,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.text}"),
+            tooltip_label: Some("{marker.data.stream}: {marker.data.text}"),
+            table_label: Some("{marker.data.stream}: {marker.data.text}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "stream",
+                    label: "Stream",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "text",
+                    label: "Text",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "teeLine",
+                    label: "Line in output file",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted for each line of output of the launched command, with --capture-output markers.",
+                }),
+            ],
+        }
+    }
+}
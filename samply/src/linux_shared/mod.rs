@@ -1,20 +1,43 @@
+mod audio_xrun;
+mod blocking_syscalls;
 mod context_switch;
 mod convert_regs;
 mod converter;
+mod cpu_topology;
+mod cpus;
 mod event_interpretation;
+mod futex;
+mod gpu_jobs;
 mod injected_jit_object;
+mod invocation_marker;
 mod kernel_symbols;
+mod log_marker;
 mod mmap_range_or_vec;
 mod object_rewriter;
 mod process;
 mod process_threads;
 mod processes;
 mod rss_stat;
+mod sched_switch;
 mod svma_file_range;
+mod system_info;
 mod thread;
+mod thread_name_marker;
+mod tracepoint_format;
+mod truncated_data_marker;
+mod vblank_event;
+mod wine;
 
-pub use convert_regs::{ConvertRegs, ConvertRegsAarch64, ConvertRegsX86_64};
-pub use converter::Converter;
+pub use blocking_syscalls::BLOCKING_SYSCALLS;
+pub use convert_regs::{
+    ConvertRegs, ConvertRegsAarch64, ConvertRegsPpc64, ConvertRegsRiscv64, ConvertRegsX86_64,
+};
+pub use converter::{Converter, LaunchedProcessGroups};
+pub use cpu_topology::CpuTopology;
 #[allow(unused)]
 pub use event_interpretation::{EventInterpretation, KnownEvent, OffCpuIndicator};
+pub use gpu_jobs::{GpuJobEventFormat, GPU_JOB_TRACEPOINTS};
+pub use log_marker::OutputStream;
 pub use mmap_range_or_vec::MmapRangeOrVec;
+pub use system_info::SystemInfo;
+pub use tracepoint_format::{parse_tracing_data, TracepointFormat};
@@ -2,10 +2,11 @@ use std::path::{Path, PathBuf};
 
 use framehop::Unwinder;
 use fxprof_processed_profile::{
-    CategoryHandle, CounterHandle, LibraryHandle, MarkerTiming, ProcessHandle, Profile,
-    ThreadHandle, Timestamp,
+    CategoryHandle, CategoryPairHandle, CounterHandle, LibraryHandle, MarkerTiming, ProcessHandle,
+    Profile, ThreadHandle, Timestamp,
 };
 
+use super::futex::LockContentionStats;
 use super::process_threads::ProcessThreads;
 use super::thread::Thread;
 
@@ -13,10 +14,11 @@ use crate::shared::jit_category_manager::JitCategoryManager;
 use crate::shared::jit_function_add_marker::JitFunctionAddMarker;
 use crate::shared::jit_function_recycler::JitFunctionRecycler;
 use crate::shared::jitdump_manager::JitDumpManager;
+use crate::shared::jvm_threads::{is_jvm_process, JvmThreadKind};
 use crate::shared::lib_mappings::{LibMappingAdd, LibMappingInfo, LibMappingOp, LibMappingOpQueue};
-use crate::shared::marker_file::get_markers;
+use crate::shared::marker_file::{get_markers, MarkerFileEntry};
 use crate::shared::perf_map::try_load_perf_map;
-use crate::shared::process_sample_data::{MarkerSpanOnThread, ProcessSampleData};
+use crate::shared::process_sample_data::{ProcessMarkerData, ProcessSampleData};
 use crate::shared::recycling::{ProcessRecyclingData, ThreadRecycler};
 use crate::shared::timestamp_converter::TimestampConverter;
 
@@ -36,11 +38,18 @@ where
     pub unresolved_samples: UnresolvedSamples,
     pub jit_function_recycler: Option<JitFunctionRecycler>,
     marker_file_paths: Vec<(ThreadHandle, PathBuf, Option<PathBuf>)>,
+    /// Markers which were received while recording, e.g. via the marker socket.
+    live_markers: ProcessMarkerData,
     pub prev_mm_filepages_size: i64,
     pub prev_mm_anonpages_size: i64,
     pub prev_mm_swapents_size: i64,
     pub prev_mm_shmempages_size: i64,
     pub mem_counter: Option<CounterHandle>,
+    pub lock_contention: LockContentionStats,
+    /// The end of the highest code mapping so far. On arm64, this tells how
+    /// many address bits the process uses, and which high bits of its return
+    /// addresses are pointer authentication codes or memory tags.
+    pub highest_code_address: u64,
 }
 
 impl<U> Process<U>
@@ -66,11 +75,14 @@ where
             unresolved_samples: Default::default(),
             jit_function_recycler,
             marker_file_paths: Vec::new(),
+            live_markers: ProcessMarkerData::default(),
             prev_mm_filepages_size: 0,
             prev_mm_anonpages_size: 0,
             prev_mm_swapents_size: 0,
             prev_mm_shmempages_size: 0,
             mem_counter: None,
+            lock_contention: LockContentionStats::default(),
+            highest_code_address: 0,
         }
     }
 
@@ -141,6 +153,10 @@ where
             .push((thread, path.to_owned(), fallback_dir));
     }
 
+    pub fn add_marker_file_entry(&mut self, thread: ThreadHandle, entry: MarkerFileEntry) {
+        self.live_markers.add_entry(thread, entry);
+    }
+
     pub fn notify_dead(&mut self, end_time: Timestamp, profile: &mut Profile) {
         self.threads.notify_process_dead(end_time, profile);
         profile.set_process_end_time(self.profile_process, end_time);
@@ -154,7 +170,7 @@ where
     ) -> (ProcessSampleData, Option<(String, ProcessRecyclingData)>) {
         self.unwinder = U::default();
 
-        let perf_map_mappings = if !self.unresolved_samples.is_empty() {
+        let perf_map = if !self.unresolved_samples.is_empty() {
             try_load_perf_map(
                 self.pid as u32,
                 profile,
@@ -164,8 +180,12 @@ where
         } else {
             None
         };
+        let (perf_map_mappings, perf_map_thread_names) = match perf_map {
+            Some(perf_map) => (Some(perf_map.mappings), perf_map.thread_names),
+            None => (None, Vec::new()),
+        };
 
-        let jitdump_manager = std::mem::replace(&mut self.jitdump_manager, JitDumpManager::new());
+        let jitdump_manager = std::mem::take(&mut self.jitdump_manager);
         let jitdump_ops = jitdump_manager.finish(
             jit_category_manager,
             profile,
@@ -173,51 +193,67 @@ where
             timestamp_converter,
         );
 
-        let mut marker_spans = Vec::new();
+        let mut markers = std::mem::take(&mut self.live_markers);
         for (thread_handle, marker_file_path, fallback_dir) in self.marker_file_paths {
-            if let Ok(marker_spans_from_this_file) = get_markers(
+            if let Ok(contents) = get_markers(
                 &marker_file_path,
                 fallback_dir.as_deref(),
                 *timestamp_converter,
             ) {
-                marker_spans.extend(marker_spans_from_this_file.into_iter().map(|span| {
-                    MarkerSpanOnThread {
-                        thread_handle,
-                        start_time: span.start_time,
-                        end_time: span.end_time,
-                        name: span.name,
-                    }
-                }));
+                markers.add_marker_file_contents(thread_handle, contents);
             }
         }
 
-        let process_sample_data = ProcessSampleData::new(
+        std::mem::take(&mut self.lock_contention)
+            .add_summary_markers(profile, self.threads.main_thread.profile_thread);
+
+        let mut process_sample_data = ProcessSampleData::new(
+            self.profile_process,
             std::mem::take(&mut self.unresolved_samples),
             std::mem::take(&mut self.lib_mapping_ops),
             jitdump_ops,
             perf_map_mappings,
-            marker_spans,
+            markers,
         );
 
-        let thread_recycler = self.threads.finish();
+        let (main_thread_handle, thread_recycler, mut finished_threads) =
+            self.threads.finish(profile);
+
+        for (tid, name) in perf_map_thread_names {
+            for thread in finished_threads.iter_mut() {
+                if thread.tid as u32 == tid {
+                    profile.set_thread_name(thread.thread_handle, &name);
+                    thread.name = Some(name.clone());
+                }
+            }
+        }
 
-        let process_recycling_data = if let (
-            Some(name),
-            Some(mut jit_function_recycler),
-            (main_thread_handle, Some(thread_recycler)),
-        ) = (self.name, self.jit_function_recycler, thread_recycler)
-        {
-            jit_function_recycler.finish_round();
-            let recycling_data = ProcessRecyclingData {
-                process_handle: self.profile_process,
-                main_thread_handle,
-                thread_recycler,
-                jit_function_recycler,
+        if is_jvm_process(finished_threads.iter().filter_map(|t| t.name.as_deref())) {
+            for thread in &finished_threads {
+                let Some(kind) = thread.name.as_deref().and_then(JvmThreadKind::classify) else {
+                    continue;
+                };
+                let category = jit_category_manager.runtime_category(kind.into(), profile);
+                process_sample_data.set_thread_user_category(thread.thread_handle, category.into());
+                profile.set_thread_hidden_by_default(thread.thread_handle, true);
+            }
+        }
+
+        let process_recycling_data =
+            if let (Some(name), Some(mut jit_function_recycler), Some(thread_recycler)) =
+                (self.name, self.jit_function_recycler, thread_recycler)
+            {
+                jit_function_recycler.finish_round();
+                let recycling_data = ProcessRecyclingData {
+                    process_handle: self.profile_process,
+                    main_thread_handle,
+                    thread_recycler,
+                    jit_function_recycler,
+                };
+                Some((name, recycling_data))
+            } else {
+                None
             };
-            Some((name, recycling_data))
-        } else {
-            None
-        };
 
         (process_sample_data, process_recycling_data)
     }
@@ -230,14 +266,19 @@ where
         end_address: u64,
         relative_address_at_start: u32,
         lib_handle: LibraryHandle,
+        category: Option<CategoryPairHandle>,
     ) {
+        self.highest_code_address = self.highest_code_address.max(end_address);
         self.lib_mapping_ops.push(
             timestamp,
             LibMappingOp::Add(LibMappingAdd {
                 start_avma: start_address,
                 end_avma: end_address,
                 relative_address_at_start,
-                info: LibMappingInfo::new_lib(lib_handle),
+                info: LibMappingInfo {
+                    category,
+                    ..LibMappingInfo::new_lib(lib_handle)
+                },
             }),
         );
     }
@@ -255,6 +296,7 @@ where
         jit_category_manager: &mut JitCategoryManager,
         profile: &mut Profile,
     ) {
+        self.highest_code_address = self.highest_code_address.max(end_address);
         let main_thread = self.threads.main_thread.profile_thread;
         let timing = MarkerTiming::Instant(profile_timestamp);
         profile.add_marker(
@@ -13,6 +13,17 @@ pub struct ProcessThreads {
     pub main_thread: Thread,
     pub threads_by_tid: FastHashMap<i32, Thread>,
     pub thread_recycler: Option<ThreadRecycler>,
+    /// The non-main threads which have ended so far.
+    finished_threads: Vec<FinishedThread>,
+}
+
+/// A thread of this process which has ended, or which was still running at
+/// the end of profiling.
+#[derive(Debug, Clone)]
+pub struct FinishedThread {
+    pub tid: i32,
+    pub thread_handle: ThreadHandle,
+    pub name: Option<String>,
 }
 
 impl ProcessThreads {
@@ -28,6 +39,7 @@ impl ProcessThreads {
             main_thread: Thread::new(main_thread_handle),
             threads_by_tid: Default::default(),
             thread_recycler,
+            finished_threads: Vec::new(),
         }
     }
 
@@ -91,13 +103,15 @@ impl ProcessThreads {
                     return;
                 }
 
-                if let Some(thread_recycler) = self.thread_recycler.as_mut() {
-                    if let Some(recycled_thread_handle) = thread_recycler.recycle_by_name(&name) {
-                        let old_thread_handle =
-                            entry.get_mut().swap_thread_handle(recycled_thread_handle);
-                        if let Some(old_name) = entry.get().name.as_deref() {
-                            thread_recycler.add_to_pool(old_name, old_thread_handle);
-                        }
+                let Some(thread_recycler) = self.thread_recycler.as_mut() else {
+                    entry.get_mut().rename(name, timestamp, profile);
+                    return;
+                };
+                if let Some(recycled_thread_handle) = thread_recycler.recycle_by_name(&name) {
+                    let old_thread_handle =
+                        entry.get_mut().swap_thread_handle(recycled_thread_handle);
+                    if let Some(old_name) = entry.get().name.as_deref() {
+                        thread_recycler.add_to_pool(old_name, old_thread_handle);
                     }
                 }
 
@@ -109,22 +123,55 @@ impl ProcessThreads {
     /// Called when a process has exited, before finish(). Not called if the process
     /// is still alive at the end of the profiling run.
     pub fn notify_process_dead(&mut self, end_time: Timestamp, profile: &mut Profile) {
-        for (_tid, mut thread) in self.threads_by_tid.drain() {
+        let threads: Vec<(i32, Thread)> = self.threads_by_tid.drain().collect();
+        for (tid, mut thread) in threads {
             thread.notify_dead(end_time, profile);
-
-            let (name, thread_handle) = thread.finish();
-
-            if let (Some(name), Some(thread_recycler)) = (name, self.thread_recycler.as_mut()) {
-                thread_recycler.add_to_pool(&name, thread_handle);
-            }
+            self.finish_non_main_thread(tid, thread, profile);
         }
 
         self.main_thread.notify_dead(end_time, profile);
     }
+
     /// Called when the process has exited, or at the end of profiling. Called after notify_process_dead.
-    pub fn finish(self) -> (ThreadHandle, Option<ThreadRecycler>) {
-        let (_main_thread_name, main_thread_handle) = self.main_thread.finish();
-        (main_thread_handle, self.thread_recycler)
+    ///
+    /// Returns all threads of the process which were seen, including the main thread.
+    pub fn finish(
+        mut self,
+        profile: &mut Profile,
+    ) -> (ThreadHandle, Option<ThreadRecycler>, Vec<FinishedThread>) {
+        let threads: Vec<(i32, Thread)> = self.threads_by_tid.drain().collect();
+        for (tid, thread) in threads {
+            self.finish_non_main_thread(tid, thread, profile);
+        }
+        let (main_thread_name, main_thread_handle) = self.main_thread.finish(profile);
+        self.finished_threads.push(FinishedThread {
+            tid: self.pid,
+            thread_handle: main_thread_handle,
+            name: main_thread_name,
+        });
+        (
+            main_thread_handle,
+            self.thread_recycler,
+            self.finished_threads,
+        )
+    }
+
+    fn finish_non_main_thread(&mut self, tid: i32, thread: Thread, profile: &mut Profile) {
+        let (name, thread_handle) = thread.finish(profile);
+
+        if let (Some(name), Some(thread_recycler)) = (&name, self.thread_recycler.as_mut()) {
+            thread_recycler.add_to_pool(name, thread_handle);
+        }
+        self.finished_threads.push(FinishedThread {
+            tid,
+            thread_handle,
+            name,
+        });
+    }
+
+    /// All threads of the process, including the main thread.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Thread> {
+        std::iter::once(&mut self.main_thread).chain(self.threads_by_tid.values_mut())
     }
 
     pub fn get_thread_by_tid(&mut self, tid: i32, profile: &mut Profile) -> &mut Thread {
@@ -138,27 +185,25 @@ impl ProcessThreads {
                 Timestamp::from_millis_since_reference(0.0),
                 false,
             );
-            Thread {
-                profile_thread,
-                context_switch_data: Default::default(),
-                last_sample_timestamp: None,
-                off_cpu_stack: None,
-                name: None,
-            }
+            Thread::new(profile_thread)
         })
     }
 
+    /// Returns the thread with this tid, or the main thread if the tid isn't
+    /// a known thread of this process.
+    pub fn get_existing_thread_or_main_thread(&mut self, tid: i32) -> &mut Thread {
+        match self.threads_by_tid.get_mut(&tid) {
+            Some(thread) => thread,
+            None => &mut self.main_thread,
+        }
+    }
+
     pub fn remove_non_main_thread(&mut self, tid: i32, time: Timestamp, profile: &mut Profile) {
         let Some(mut thread) = self.threads_by_tid.remove(&tid) else {
             return;
         };
 
         thread.notify_dead(time, profile);
-
-        let (name, thread_handle) = thread.finish();
-
-        if let (Some(name), Some(thread_recycler)) = (name, self.thread_recycler.as_mut()) {
-            thread_recycler.add_to_pool(&name, thread_handle);
-        }
+        self.finish_non_main_thread(tid, thread, profile);
     }
 }
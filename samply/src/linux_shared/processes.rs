@@ -1,15 +1,23 @@
 use framehop::Unwinder;
-use fxprof_processed_profile::{CategoryColor, Profile, Timestamp};
+use fxprof_processed_profile::{
+    CategoryColor, CategoryHandle, CategoryPairHandle, MarkerTiming, ProcessHandle, Profile,
+    ThreadHandle, Timestamp,
+};
 
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 
+use super::invocation_marker::InvocationMarker;
 use super::process::Process;
 
 use crate::shared::jit_category_manager::JitCategoryManager;
 use crate::shared::jit_function_recycler::JitFunctionRecycler;
+use crate::shared::off_cpu_reason::OffCpuCategories;
 use crate::shared::process_sample_data::ProcessSampleData;
+use crate::shared::recording_props::LatencyMarkerNames;
 use crate::shared::recycling::{ProcessRecycler, ProcessRecyclingData, ThreadRecycler};
+use crate::shared::stack_quality::StackQualityStats;
+use crate::shared::stack_stitching::StackStitchingRule;
 use crate::shared::timestamp_converter::TimestampConverter;
 use crate::shared::unresolved_samples::UnresolvedStacks;
 
@@ -25,13 +33,47 @@ where
 
     /// The sample data for all removed processes.
     process_sample_datas: Vec<ProcessSampleData>,
+
+    /// Whether removed processes are flushed to the profile right away, with
+    /// their threads' data written to the profile's spill file. The threads
+    /// of processes which are flushed at the end are spilled as well.
+    spill_removed_processes: bool,
+
+    /// The user and kernel categories for stack frames, created on first flush.
+    stack_categories: Option<(CategoryPairHandle, CategoryPairHandle)>,
+
+    /// The categories for off-CPU samples, created once the first process with
+    /// off-CPU samples is flushed.
+    off_cpu_categories: Option<OffCpuCategories>,
+    /// How deep the stacks of the flushed processes were, per library.
+    stack_quality: StackQualityStats,
+
+    /// Some() if all processes with the same name should share one process
+    /// and main thread in the profile, keyed by name.
+    aggregated_processes: Option<HashMap<String, AggregatedProcess>>,
+
+    /// With `--reuse-threads`, the time at which each live process started
+    /// using its current track, by pid. Short-lived workers of forking servers
+    /// share a few tracks, so each of them gets an invocation marker.
+    reused_track_start_times: HashMap<i32, Timestamp>,
+}
+
+/// The profile process which is shared by all processes with the same name,
+/// with `--aggregate-by-name`.
+struct AggregatedProcess {
+    process_handle: ProcessHandle,
+    main_thread_handle: ThreadHandle,
+    /// The start time of each live process, by pid.
+    invocation_start_times: HashMap<i32, Timestamp>,
+    /// The latest end time of the processes which have exited.
+    end_time: Option<Timestamp>,
 }
 
 impl<U> Processes<U>
 where
     U: Unwinder + Default,
 {
-    pub fn new(allow_reuse: bool) -> Self {
+    pub fn new(allow_reuse: bool, spill_removed_processes: bool, aggregate_by_name: bool) -> Self {
         let process_recycler = if allow_reuse {
             Some(ProcessRecycler::new())
         } else {
@@ -41,6 +83,12 @@ where
             processes_by_pid: HashMap::new(),
             process_recycler,
             process_sample_datas: Vec::new(),
+            spill_removed_processes,
+            stack_categories: None,
+            off_cpu_categories: None,
+            stack_quality: StackQualityStats::default(),
+            aggregated_processes: aggregate_by_name.then(HashMap::new),
+            reused_track_start_times: HashMap::new(),
         }
     }
 
@@ -53,9 +101,40 @@ where
     ) -> &mut Process<U> {
         match self.processes_by_pid.entry(pid) {
             Entry::Vacant(entry) => {
+                if let (Some(aggregated_processes), Some(name_ref)) =
+                    (self.aggregated_processes.as_mut(), name.as_deref())
+                {
+                    let aggregated = aggregated_processes
+                        .entry(name_ref.to_owned())
+                        .or_insert_with(|| {
+                            let process_handle =
+                                profile.add_process(name_ref, pid as u32, start_time);
+                            let main_thread_handle =
+                                profile.add_thread(process_handle, pid as u32, start_time, true);
+                            profile.set_thread_name(main_thread_handle, name_ref);
+                            AggregatedProcess {
+                                process_handle,
+                                main_thread_handle,
+                                invocation_start_times: HashMap::new(),
+                                end_time: None,
+                            }
+                        });
+                    aggregated.invocation_start_times.insert(pid, start_time);
+                    let process = Process::new(
+                        pid,
+                        aggregated.process_handle,
+                        aggregated.main_thread_handle,
+                        name,
+                        None,
+                        None,
+                    );
+                    return entry.insert(process);
+                }
+
                 if let (Some(process_recycler), Some(name_ref)) =
                     (self.process_recycler.as_mut(), name.as_deref())
                 {
+                    self.reused_track_start_times.insert(pid, start_time);
                     if let Some(ProcessRecyclingData {
                         process_handle,
                         main_thread_handle,
@@ -108,6 +187,28 @@ where
         }
     }
 
+    /// The main threads of all processes which haven't been removed.
+    pub fn main_threads(&self) -> impl Iterator<Item = ThreadHandle> + '_ {
+        self.processes_by_pid
+            .values()
+            .map(|process| process.threads.main_thread.profile_thread)
+    }
+
+    pub fn contains(&self, pid: i32) -> bool {
+        self.processes_by_pid.contains_key(&pid)
+    }
+
+    /// All processes which haven't been removed.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Process<U>> {
+        self.processes_by_pid.values_mut()
+    }
+
+    /// Returns the process with this pid if it's still alive, without creating
+    /// a placeholder process for pids we haven't seen.
+    pub fn get_existing_by_pid(&mut self, pid: i32) -> Option<&mut Process<U>> {
+        self.processes_by_pid.get_mut(&pid)
+    }
+
     pub fn get_by_pid(&mut self, pid: i32, profile: &mut Profile) -> &mut Process<U> {
         self.processes_by_pid.entry(pid).or_insert_with(|| {
             let fake_start_time = Timestamp::from_millis_since_reference(0.0);
@@ -134,10 +235,13 @@ where
         })
     }
 
+    /// Called when a process exits, or when it calls execve, in which case
+    /// `is_exec` is true.
     pub fn remove(
         &mut self,
         pid: i32,
         time: Timestamp,
+        is_exec: bool,
         profile: &mut Profile,
         jit_category_manager: &mut JitCategoryManager,
         timestamp_converter: &TimestampConverter,
@@ -147,6 +251,19 @@ where
         };
 
         process.notify_dead(time, profile);
+        self.end_aggregated_invocation(&process, time, is_exec, profile);
+        if let Some(start_time) = self.reused_track_start_times.remove(&pid) {
+            if !is_exec {
+                add_invocation_marker(
+                    profile,
+                    process.profile_process,
+                    process.threads.main_thread.profile_thread,
+                    pid,
+                    start_time,
+                    time,
+                );
+            }
+        }
 
         let (process_sample_data, process_recycling_data) =
             process.finish(profile, jit_category_manager, timestamp_converter);
@@ -161,6 +278,46 @@ where
         }
     }
 
+    /// If `process` is part of an aggregated process, adds a marker for its
+    /// lifetime, and makes sure that the shared process ends after all of its
+    /// invocations. The part of a forked child before it calls execve doesn't
+    /// get a marker, because it's not a separate run of the parent program.
+    fn end_aggregated_invocation(
+        &mut self,
+        process: &Process<U>,
+        time: Timestamp,
+        is_exec: bool,
+        profile: &mut Profile,
+    ) {
+        let Some(aggregated) = self
+            .aggregated_processes
+            .iter_mut()
+            .flat_map(HashMap::values_mut)
+            .find(|aggregated| aggregated.process_handle == process.profile_process)
+        else {
+            return;
+        };
+        let Some(start_time) = aggregated.invocation_start_times.remove(&process.pid) else {
+            return;
+        };
+        let end_time = aggregated
+            .end_time
+            .map_or(time, |end_time| end_time.max(time));
+        aggregated.end_time = Some(end_time);
+        profile.set_process_end_time(aggregated.process_handle, end_time);
+        profile.set_thread_end_time(aggregated.main_thread_handle, end_time);
+        if !is_exec {
+            add_invocation_marker(
+                profile,
+                aggregated.process_handle,
+                aggregated.main_thread_handle,
+                process.pid,
+                start_time,
+                time,
+            );
+        }
+    }
+
     pub fn rename_process(
         &mut self,
         pid: i32,
@@ -177,6 +334,13 @@ where
                     return;
                 }
 
+                if self.aggregated_processes.is_some() {
+                    // Don't rename the profile process, it's shared with the
+                    // other processes of the old name.
+                    entry.get_mut().name = Some(name);
+                    return;
+                }
+
                 if let Some(process_recycler) = self.process_recycler.as_mut() {
                     if let Some(process_recycling_data) = process_recycler.recycle_by_name(&name) {
                         let old_recycling_data =
@@ -184,6 +348,25 @@ where
                         if let (Some(old_recycling_data), Some(old_name)) =
                             (old_recycling_data, entry.get().name.as_deref())
                         {
+                            // E.g. a prefork worker which renames itself after
+                            // the fork: The part before the rename stays on
+                            // the old track, which is free again from now on.
+                            let old_thread = old_recycling_data.main_thread_handle;
+                            profile
+                                .set_process_end_time(old_recycling_data.process_handle, timestamp);
+                            profile.set_thread_end_time(old_thread, timestamp);
+                            if let Some(start_time) =
+                                self.reused_track_start_times.insert(pid, timestamp)
+                            {
+                                add_invocation_marker(
+                                    profile,
+                                    old_recycling_data.process_handle,
+                                    old_thread,
+                                    pid,
+                                    start_time,
+                                    timestamp,
+                                );
+                            }
                             process_recycler.add_to_pool(old_name, old_recycling_data);
                         }
                     }
@@ -193,6 +376,50 @@ where
         }
     }
 
+    /// If spilling is enabled, flush the samples of the processes which were
+    /// removed since the last call, and write their threads to the spill file
+    /// so that their tables don't stay in memory until the end of profiling.
+    /// Threads which can still receive samples, because they're reused by
+    /// later processes or shared by all processes of the same name, only have
+    /// their sample and stack tables spilled.
+    pub fn flush_removed_processes(
+        &mut self,
+        profile: &mut Profile,
+        unresolved_stacks: &UnresolvedStacks,
+        event_names: &[String],
+        frame_marker: Option<&str>,
+        latency_markers: Option<&LatencyMarkerNames>,
+        stack_stitching_rules: &[StackStitchingRule],
+    ) {
+        if !self.spill_removed_processes {
+            return;
+        }
+        let process_sample_datas = std::mem::take(&mut self.process_sample_datas);
+        for process_sample_data in process_sample_datas {
+            let process = process_sample_data.process();
+            self.flush_process_sample_data(
+                process_sample_data,
+                profile,
+                unresolved_stacks,
+                event_names,
+                frame_marker,
+                latency_markers,
+                stack_stitching_rules,
+            );
+            let threads_are_shared =
+                self.process_recycler.is_some() || self.aggregated_processes.is_some();
+            let result = if threads_are_shared {
+                profile.spill_process_thread_tables(process)
+            } else {
+                profile.spill_process_threads(process)
+            };
+            if let Err(err) = result {
+                eprintln!("Could not write thread data to the spill file: {err}");
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn finish(
         mut self,
         profile: &mut Profile,
@@ -200,9 +427,27 @@ where
         event_names: &[String],
         jit_category_manager: &mut JitCategoryManager,
         timestamp_converter: &TimestampConverter,
-    ) {
+        frame_marker: Option<&str>,
+        latency_markers: Option<&LatencyMarkerNames>,
+        stack_stitching_rules: &[StackStitchingRule],
+    ) -> StackQualityStats {
+        // Processes which are still alive get an invocation marker which extends
+        // to the end of the profile.
+        for aggregated in self.aggregated_processes.iter().flat_map(HashMap::values) {
+            for (pid, start_time) in &aggregated.invocation_start_times {
+                let name = profile.process_name(aggregated.process_handle).to_owned();
+                profile.add_marker(
+                    aggregated.main_thread_handle,
+                    CategoryHandle::OTHER,
+                    &name,
+                    InvocationMarker { pid: *pid },
+                    MarkerTiming::IntervalStart(*start_time),
+                );
+            }
+        }
+
         // Gather the ProcessSampleData from any processes which are still alive at the end of profiling.
-        for process in self.processes_by_pid.into_values() {
+        for process in std::mem::take(&mut self.processes_by_pid).into_values() {
             let (process_sample_data, _process_recycling_data) =
                 process.finish(profile, jit_category_manager, timestamp_converter);
             if !process_sample_data.is_empty() {
@@ -210,18 +455,84 @@ where
             }
         }
 
-        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
-        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
-        let mut stack_frame_scratch_buf = Vec::new();
-        for process_sample_data in self.process_sample_datas {
-            process_sample_data.flush_samples_to_profile(
+        // Spill the tables of each process once it's flushed, so that they
+        // aren't all in memory at the same time. More markers may still be
+        // added to the threads, so they aren't spilled completely.
+        for process_sample_data in std::mem::take(&mut self.process_sample_datas) {
+            let process = process_sample_data.process();
+            self.flush_process_sample_data(
+                process_sample_data,
                 profile,
-                user_category,
-                kernel_category,
-                &mut stack_frame_scratch_buf,
                 unresolved_stacks,
                 event_names,
+                frame_marker,
+                latency_markers,
+                stack_stitching_rules,
             );
+            if self.spill_removed_processes {
+                if let Err(err) = profile.spill_process_thread_tables(process) {
+                    eprintln!("Could not write thread data to the spill file: {err}");
+                }
+            }
         }
+        self.stack_quality
     }
+
+    #[allow(clippy::too_many_arguments)]
+    fn flush_process_sample_data(
+        &mut self,
+        mut process_sample_data: ProcessSampleData,
+        profile: &mut Profile,
+        unresolved_stacks: &UnresolvedStacks,
+        event_names: &[String],
+        frame_marker: Option<&str>,
+        latency_markers: Option<&LatencyMarkerNames>,
+        stack_stitching_rules: &[StackStitchingRule],
+    ) {
+        let (user_category, kernel_category) = *self.stack_categories.get_or_insert_with(|| {
+            (
+                profile.add_category("User", CategoryColor::Yellow).into(),
+                profile.add_category("Kernel", CategoryColor::Orange).into(),
+            )
+        });
+        if process_sample_data.has_off_cpu_samples() {
+            let off_cpu_categories = *self
+                .off_cpu_categories
+                .get_or_insert_with(|| OffCpuCategories::new(profile));
+            process_sample_data.set_off_cpu_categories(off_cpu_categories);
+        }
+        let mut stack_frame_scratch_buf = Vec::new();
+        let stack_quality = process_sample_data.flush_samples_to_profile(
+            profile,
+            user_category,
+            kernel_category,
+            &mut stack_frame_scratch_buf,
+            unresolved_stacks,
+            event_names,
+            frame_marker,
+            latency_markers,
+            stack_stitching_rules,
+        );
+        self.stack_quality.merge(stack_quality);
+    }
+}
+
+/// Adds a marker for the lifetime of one process to a track which is shared
+/// with other processes, named after the process of the track.
+fn add_invocation_marker(
+    profile: &mut Profile,
+    process: ProcessHandle,
+    thread: ThreadHandle,
+    pid: i32,
+    start_time: Timestamp,
+    end_time: Timestamp,
+) {
+    let name = profile.process_name(process).to_owned();
+    profile.add_marker(
+        thread,
+        CategoryHandle::OTHER,
+        &name,
+        InvocationMarker { pid },
+        MarkerTiming::Interval(start_time, end_time),
+    );
 }
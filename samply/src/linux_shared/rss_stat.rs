@@ -22,7 +22,7 @@ pub const MM_SWAPENTS: i32 = 2;
 #[allow(unused)]
 pub const MM_SHMEMPAGES: i32 = 3;
 
-/// ```
+/// ```text
 /// # cat /sys/kernel/debug/tracing/events/kmem/rss_stat/format
 /// name: rss_stat
 /// ID: 537
@@ -0,0 +1,127 @@
+use byteorder::ByteOrder;
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use linux_perf_data::linux_perf_event_reader;
+use linux_perf_data::Endianness;
+use serde_json::json;
+
+use linux_perf_event_reader::RawData;
+
+use std::fmt::Debug;
+
+/// ```text
+/// # cat /sys/kernel/tracing/events/sched/sched_switch/format
+/// name: sched_switch
+/// ID: 316
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:char prev_comm[16];       offset:8;       size:16;        signed:0;
+///         field:pid_t prev_pid;   offset:24;      size:4; signed:1;
+///         field:int prev_prio;    offset:28;      size:4; signed:1;
+///         field:long prev_state;  offset:32;      size:8; signed:1;
+///         field:char next_comm[16];       offset:40;      size:16;        signed:0;
+///         field:pid_t next_pid;   offset:56;      size:4; signed:1;
+///         field:int next_prio;    offset:60;      size:4; signed:1;
+/// ```
+///
+/// The `pid` fields are thread IDs.
+#[derive(Debug)]
+pub struct SchedSwitch {
+    pub prev_comm: String,
+    pub prev_tid: i32,
+    /// The state of the previous thread, 0 if it's still runnable, i.e. it
+    /// was preempted.
+    pub prev_state: u64,
+    pub next_comm: String,
+    pub next_tid: i32,
+}
+
+impl SchedSwitch {
+    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
+        data.skip(8)?;
+        let prev_comm = read_comm(&mut data)?;
+        let prev_tid = data.read_i32::<O>()?;
+        let _prev_prio = data.read_i32::<O>()?;
+        let prev_state = data.read_u64::<O>()?;
+        let next_comm = read_comm(&mut data)?;
+        let next_tid = data.read_i32::<O>()?;
+        Ok(SchedSwitch {
+            prev_comm,
+            prev_tid,
+            prev_state,
+            next_comm,
+            next_tid,
+        })
+    }
+}
+
+fn read_comm(data: &mut RawData) -> Result<String, std::io::Error> {
+    let mut comm = [0; 16];
+    data.read_exact(&mut comm)?;
+    let len = comm.iter().position(|b| *b == 0).unwrap_or(comm.len());
+    Ok(String::from_utf8_lossy(&comm[..len]).into_owned())
+}
+
+/// Shows which thread occupied a CPU, on the per-CPU tracks.
+#[derive(Debug, Clone)]
+pub struct CpuRunningMarker {
+    pub comm: String,
+    pub tid: i32,
+}
+
+impl ProfilerMarker for CpuRunningMarker {
+    const MARKER_TYPE_NAME: &'static str = "CpuRunning";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "comm": self.comm,
+            "tid": self.tid,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.comm}"),
+            tooltip_label: Some("{marker.data.comm} (TID {marker.data.tid})"),
+            table_label: Some("{marker.data.comm} (TID {marker.data.tid})"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "comm",
+                    label: "Thread name",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "tid",
+                    label: "Thread ID",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted for the time during which a thread was running on this CPU, based on sched:sched_switch events.",
+                }),
+            ],
+        }
+    }
+}
@@ -1,9 +1,13 @@
-use fxprof_processed_profile::{Profile, ThreadHandle, Timestamp};
+use fxprof_processed_profile::{CategoryHandle, MarkerTiming, Profile, ThreadHandle, Timestamp};
 
+use std::collections::HashMap;
 use std::fmt::Debug;
 
 use super::context_switch::ThreadContextSwitchData;
+use super::futex::PendingFutexWait;
+use super::thread_name_marker::ThreadNameMarker;
 
+use crate::shared::off_cpu_reason::OffCpuReason;
 use crate::shared::unresolved_samples::UnresolvedStackHandle;
 
 #[derive(Debug)]
@@ -16,7 +20,23 @@ pub struct Thread {
     ///
     /// Refers to a stack in the containing Process's UnresolvedSamples stack table.
     pub off_cpu_stack: Option<UnresolvedStackHandle>,
+    /// The stack of the most recent on-CPU sample. In wall-clock mode, this is
+    /// used as the off-CPU stack if no sched_switch sample provided one.
+    pub last_on_cpu_stack: Option<UnresolvedStackHandle>,
+    /// Some() between sys_enter_futex and sys_exit_futex of a futex wait.
+    pub pending_futex_wait: Option<PendingFutexWait>,
+    /// What the traced blocking syscall which the thread is currently in
+    /// would be waiting for, between its sys_enter and sys_exit events.
+    pub blocking_syscall: Option<OffCpuReason>,
+    /// Why the thread went off-CPU, set when it's switched out if off-CPU
+    /// reasons are known.
+    pub off_cpu_reason: Option<OffCpuReason>,
     pub name: Option<String>,
+    /// The names this thread was given while it was running, with the time of
+    /// each rename. See [`Thread::rename`].
+    renames: Vec<(Timestamp, String)>,
+    /// The name the thread had before its first rename.
+    name_before_renames: Option<String>,
 }
 
 impl Thread {
@@ -26,7 +46,13 @@ impl Thread {
             context_switch_data: Default::default(),
             last_sample_timestamp: None,
             off_cpu_stack: None,
+            last_on_cpu_stack: None,
+            pending_futex_wait: None,
+            blocking_syscall: None,
+            off_cpu_reason: None,
             name: None,
+            renames: Vec::new(),
+            name_before_renames: None,
         }
     }
 
@@ -34,16 +60,156 @@ impl Thread {
         std::mem::replace(&mut self.profile_thread, thread_handle)
     }
 
+    /// Sets the name without keeping track of the old one, e.g. when the thread
+    /// is created.
     pub fn set_name(&mut self, name: String, profile: &mut Profile) {
         profile.set_thread_name(self.profile_thread, &name);
         self.name = Some(name);
     }
 
+    /// Called when a running thread changes its name, e.g. with
+    /// pthread_setname_np. Each name the thread had gets a "Thread name" marker,
+    /// and once the thread ends, the track is named after the name the thread
+    /// had most often, see [`Thread::finish_renames`].
+    pub fn rename(&mut self, name: String, timestamp: Timestamp, profile: &mut Profile) {
+        match self.renames.last() {
+            Some((rename_time, old_name)) => {
+                let timing = MarkerTiming::Interval(*rename_time, timestamp);
+                add_thread_name_marker(profile, self.profile_thread, old_name, timing);
+            }
+            None => {
+                if let Some(old_name) = &self.name {
+                    // We don't know when the thread got its first name.
+                    let timing = MarkerTiming::IntervalEnd(timestamp);
+                    add_thread_name_marker(profile, self.profile_thread, old_name, timing);
+                }
+                self.name_before_renames = self.name.clone();
+            }
+        }
+        self.renames.push((timestamp, name.clone()));
+        self.set_name(name, profile);
+    }
+
+    /// Adds the marker for the last name of a renamed thread, and picks the
+    /// track name: A thread pool worker which is renamed for each task and back
+    /// is best described by its most common name, rather than by whichever name
+    /// it had when it was created or when it ended. Ties go to the later name.
+    fn finish_renames(&mut self, end_time: Option<Timestamp>, profile: &mut Profile) {
+        let renames = std::mem::take(&mut self.renames);
+        let Some((rename_time, last_name)) = renames.last() else {
+            return;
+        };
+        let timing = match end_time {
+            Some(end_time) => MarkerTiming::Interval(*rename_time, end_time),
+            None => MarkerTiming::IntervalStart(*rename_time),
+        };
+        add_thread_name_marker(profile, self.profile_thread, last_name, timing);
+
+        let name_before_renames = self.name_before_renames.take();
+        let names = name_before_renames
+            .as_deref()
+            .into_iter()
+            .chain(renames.iter().map(|(_, name)| name.as_str()));
+        if let Some(name) = most_common_name(names) {
+            profile.set_thread_name(self.profile_thread, name);
+        }
+    }
+
     pub fn notify_dead(&mut self, end_time: Timestamp, profile: &mut Profile) {
         profile.set_thread_end_time(self.profile_thread, end_time);
+        self.finish_renames(Some(end_time), profile);
     }
 
-    pub fn finish(self) -> (Option<String>, ThreadHandle) {
+    /// Called when the thread has ended, after notify_dead, or at the end of
+    /// profiling.
+    pub fn finish(mut self, profile: &mut Profile) -> (Option<String>, ThreadHandle) {
+        self.finish_renames(None, profile);
         (self.name, self.profile_thread)
     }
 }
+
+/// The name which occurs most often in `names`, which are in the order the
+/// thread had them. Ties go to the name which the thread had last.
+fn most_common_name<'a>(names: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let mut counts: HashMap<&str, (usize, usize)> = HashMap::new();
+    for (index, name) in names.enumerate() {
+        let entry = counts.entry(name).or_default();
+        entry.0 += 1;
+        entry.1 = index;
+    }
+    let (name, _) = counts.into_iter().max_by_key(|(_, count)| *count)?;
+    Some(name)
+}
+
+fn add_thread_name_marker(
+    profile: &mut Profile,
+    thread: ThreadHandle,
+    name: &str,
+    timing: MarkerTiming,
+) {
+    profile.add_marker(
+        thread,
+        CategoryHandle::OTHER,
+        "Thread name",
+        ThreadNameMarker {
+            name: name.to_string(),
+        },
+        timing,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{ReferenceTimestamp, SamplingInterval};
+
+    use super::*;
+
+    #[test]
+    fn most_common_name_prefers_later_names() {
+        assert_eq!(most_common_name(["a", "b", "a"].into_iter()), Some("a"));
+        assert_eq!(most_common_name(["a", "b"].into_iter()), Some("b"));
+        assert_eq!(
+            most_common_name(["a", "b", "b", "a"].into_iter()),
+            Some("a")
+        );
+        assert_eq!(most_common_name([].into_iter()), None);
+    }
+
+    fn thread_name_after_renames(initial_name: &str, renames: &[&str]) -> String {
+        let mut profile = Profile::new(
+            "",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let process = profile.add_process("app", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread_handle = profile.add_thread(
+            process,
+            2,
+            Timestamp::from_millis_since_reference(0.0),
+            false,
+        );
+        let mut thread = Thread::new(thread_handle);
+        thread.set_name(initial_name.to_string(), &mut profile);
+        for (i, name) in renames.iter().enumerate() {
+            let time = Timestamp::from_millis_since_reference(i as f64 + 1.0);
+            thread.rename(name.to_string(), time, &mut profile);
+        }
+        let end_time = Timestamp::from_millis_since_reference(100.0);
+        thread.notify_dead(end_time, &mut profile);
+        let profile = serde_json::to_value(&profile).unwrap();
+        profile["threads"][0]["name"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn name_from_creation_counts_as_a_rename() {
+        // A worker which is renamed for each task and back has its pool name
+        // once from its creation, and once from the rename back.
+        assert_eq!(
+            thread_name_after_renames("worker", &["task-1", "worker", "task-2"]),
+            "worker"
+        );
+        // Without a rename back, the later name wins the tie.
+        assert_eq!(thread_name_after_renames("worker", &["task-1"]), "task-1");
+        assert_eq!(thread_name_after_renames("worker", &[]), "worker");
+    }
+}
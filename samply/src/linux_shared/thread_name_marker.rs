@@ -0,0 +1,50 @@
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use serde_json::json;
+
+/// The time during which a thread had a certain name. Only emitted for threads
+/// which were renamed while they were running, e.g. pool threads which are
+/// named after the task they're working on.
+#[derive(Debug, Clone)]
+pub struct ThreadNameMarker {
+    pub name: String,
+}
+
+impl ProfilerMarker for ThreadNameMarker {
+    const MARKER_TYPE_NAME: &'static str = "ThreadName";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "name": self.name,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.name}"),
+            tooltip_label: Some("Thread name: {marker.data.name}"),
+            table_label: Some("Thread name: {marker.data.name}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "name",
+                    label: "Name",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "The name the thread had during this time. The thread was renamed while it was running.",
+                }),
+            ],
+        }
+    }
+}
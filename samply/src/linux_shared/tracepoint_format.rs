@@ -0,0 +1,335 @@
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use linux_perf_data::Endianness;
+
+use std::collections::HashMap;
+
+/// The layout of a tracepoint's raw data, from its format file in tracefs,
+/// e.g. `/sys/kernel/tracing/events/gpu_scheduler/drm_run_job/format`:
+///
+/// ```text
+/// name: drm_run_job
+/// ID: 1612
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         ...
+///         field:struct dma_fence * fence; offset:16;      size:8; signed:0;
+///         field:__data_loc char[] name;   offset:24;      size:4; signed:1;
+///
+/// print fmt: "entity=%p, id=%llu, fence=%p, ring=%s, ...", ...
+/// ```
+///
+/// Perf copies the format files of the recorded tracepoints into the
+/// perf.data file, see [`parse_tracing_data`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TracepointFormat {
+    pub name: String,
+    fields: Vec<(String, TracepointField)>,
+}
+
+/// Where a field is in a tracepoint's raw data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TracepointField {
+    pub offset: usize,
+    pub size: usize,
+    kind: TracepointFieldKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TracepointFieldKind {
+    Plain,
+    /// A `__data_loc` field holds the offset of the dynamic data in the low
+    /// 16 bits and its length in the high 16 bits.
+    DataLoc,
+    /// A `__rel_loc` field is like a `__data_loc` field, but the offset is
+    /// relative to the end of the field.
+    RelLoc,
+}
+
+impl TracepointFormat {
+    pub fn parse(format: &str) -> Self {
+        let mut name = String::new();
+        let mut fields = Vec::new();
+        for line in format.lines() {
+            let line = line.trim();
+            if let Some(n) = line.strip_prefix("name:") {
+                name = n.trim().to_string();
+            } else if let Some(field) = parse_field_line(line) {
+                fields.push(field);
+            }
+        }
+        Self { name, fields }
+    }
+
+    pub fn field(&self, name: &str) -> Option<TracepointField> {
+        self.fields
+            .iter()
+            .find(|(field_name, _)| field_name == name)
+            .map(|(_, field)| *field)
+    }
+}
+
+/// Parses a line like
+/// `field:struct dma_fence * fence; offset:16; size:8; signed:0;`.
+fn parse_field_line(line: &str) -> Option<(String, TracepointField)> {
+    let mut declaration = None;
+    let mut offset = None;
+    let mut size = None;
+    for part in line.split(';') {
+        let Some((key, value)) = part.trim().split_once(':') else {
+            continue;
+        };
+        match key {
+            "field" => declaration = Some(value.trim()),
+            "offset" => offset = value.trim().parse().ok(),
+            "size" => size = value.trim().parse().ok(),
+            _ => {}
+        }
+    }
+    let declaration = declaration?;
+    // The name is the last word of the declaration, without an array length.
+    let name = declaration.rsplit([' ', '*']).next()?;
+    let name = name.split('[').next()?;
+    let kind = if declaration.starts_with("__data_loc ") {
+        TracepointFieldKind::DataLoc
+    } else if declaration.starts_with("__rel_loc ") {
+        TracepointFieldKind::RelLoc
+    } else {
+        TracepointFieldKind::Plain
+    };
+    let field = TracepointField {
+        offset: offset?,
+        size: size?,
+        kind,
+    };
+    Some((name.to_string(), field))
+}
+
+impl TracepointField {
+    /// Reads an integer field of up to 8 bytes.
+    pub fn read_u64(&self, data: &[u8], endian: Endianness) -> Option<u64> {
+        let bytes = data.get(self.offset..self.offset.checked_add(self.size)?)?;
+        match endian {
+            Endianness::LittleEndian => read_uint::<LittleEndian>(bytes),
+            Endianness::BigEndian => read_uint::<BigEndian>(bytes),
+        }
+    }
+
+    /// Reads a string from a `__data_loc` or `__rel_loc` field, without the
+    /// nul terminator.
+    pub fn read_str(&self, data: &[u8], endian: Endianness) -> Option<String> {
+        let loc = self.read_u64(data, endian)?;
+        let mut start = (loc & 0xffff) as usize;
+        let len = ((loc >> 16) & 0xffff) as usize;
+        match self.kind {
+            TracepointFieldKind::Plain => return None,
+            TracepointFieldKind::DataLoc => {}
+            TracepointFieldKind::RelLoc => start += self.offset + self.size,
+        }
+        let bytes = data.get(start..start + len)?;
+        let bytes = bytes.split(|b| *b == 0).next().unwrap_or_default();
+        Some(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+fn read_uint<O: ByteOrder>(bytes: &[u8]) -> Option<u64> {
+    match bytes.len() {
+        1 => Some(u64::from(bytes[0])),
+        2 => Some(u64::from(O::read_u16(bytes))),
+        4 => Some(u64::from(O::read_u32(bytes))),
+        8 => Some(O::read_u64(bytes)),
+        _ => None,
+    }
+}
+
+/// Reads the tracepoint formats from the tracing data which perf writes into
+/// the HEADER_TRACING_DATA section of perf.data files. The formats are keyed
+/// by "system:name", e.g. "gpu_scheduler:drm_run_job".
+///
+/// The tracing data consists of a header, the formats of the header page and
+/// of the ftrace events, and then the formats of the events, grouped by
+/// system. Everything after that, e.g. kallsyms, is ignored.
+pub fn parse_tracing_data(data: &[u8]) -> Option<HashMap<String, TracepointFormat>> {
+    let mut reader = TracingDataReader { data };
+    if reader.bytes(10)? != b"\x17\x08\x44tracing" {
+        return None;
+    }
+    let _version = reader.c_str()?;
+    let endian = match reader.bytes(1)?[0] {
+        0 => Endianness::LittleEndian,
+        _ => Endianness::BigEndian,
+    };
+    let _long_size = reader.bytes(1)?;
+    let _page_size = reader.u32(endian)?;
+
+    for header_name in ["header_page", "header_event"] {
+        if reader.c_str()? != header_name {
+            return None;
+        }
+        let size = reader.u64(endian)?;
+        reader.bytes(usize::try_from(size).ok()?)?;
+    }
+
+    let ftrace_format_count = reader.u32(endian)?;
+    for _ in 0..ftrace_format_count {
+        let size = reader.u64(endian)?;
+        reader.bytes(usize::try_from(size).ok()?)?;
+    }
+
+    let mut formats = HashMap::new();
+    let system_count = reader.u32(endian)?;
+    for _ in 0..system_count {
+        let system = reader.c_str()?.to_string();
+        let format_count = reader.u32(endian)?;
+        for _ in 0..format_count {
+            let size = reader.u64(endian)?;
+            let format = reader.bytes(usize::try_from(size).ok()?)?;
+            let format = TracepointFormat::parse(&String::from_utf8_lossy(format));
+            formats.insert(format!("{system}:{}", format.name), format);
+        }
+    }
+    Some(formats)
+}
+
+struct TracingDataReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> TracingDataReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn c_str(&mut self) -> Option<&'a str> {
+        let len = self.data.iter().position(|b| *b == 0)?;
+        let s = std::str::from_utf8(self.bytes(len)?).ok()?;
+        self.bytes(1)?;
+        Some(s)
+    }
+
+    fn u32(&mut self, endian: Endianness) -> Option<u32> {
+        let bytes = self.bytes(4)?;
+        Some(match endian {
+            Endianness::LittleEndian => LittleEndian::read_u32(bytes),
+            Endianness::BigEndian => BigEndian::read_u32(bytes),
+        })
+    }
+
+    fn u64(&mut self, endian: Endianness) -> Option<u64> {
+        let bytes = self.bytes(8)?;
+        Some(match endian {
+            Endianness::LittleEndian => LittleEndian::read_u64(bytes),
+            Endianness::BigEndian => BigEndian::read_u64(bytes),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DRM_RUN_JOB_FORMAT: &str = "name: drm_run_job
+ID: 1612
+format:
+\tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+\tfield:unsigned char common_flags;\toffset:2;\tsize:1;\tsigned:0;
+\tfield:unsigned char common_preempt_count;\toffset:3;\tsize:1;\tsigned:0;
+\tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;
+
+\tfield:struct drm_sched_entity * entity;\toffset:8;\tsize:8;\tsigned:0;
+\tfield:struct dma_fence * fence;\toffset:16;\tsize:8;\tsigned:0;
+\tfield:__data_loc char[] name;\toffset:24;\tsize:4;\tsigned:1;
+\tfield:uint64_t id;\toffset:32;\tsize:8;\tsigned:0;
+\tfield:u32 job_count;\toffset:40;\tsize:4;\tsigned:0;
+\tfield:int hw_job_count;\toffset:44;\tsize:4;\tsigned:1;
+
+print fmt: \"entity=%p, id=%llu, fence=%p, ring=%s, job count:%u, hw job count:%d\", REC->entity, REC->id, REC->fence, __get_str(name), REC->job_count, REC->hw_job_count
+";
+
+    #[test]
+    fn parse_format() {
+        let format = TracepointFormat::parse(DRM_RUN_JOB_FORMAT);
+        assert_eq!(format.name, "drm_run_job");
+        let fence = format.field("fence").unwrap();
+        assert_eq!((fence.offset, fence.size), (16, 8));
+        let id = format.field("id").unwrap();
+        assert_eq!((id.offset, id.size), (32, 8));
+        assert!(format.field("entity").is_some());
+        assert!(format.field("missing").is_none());
+
+        let mut data = vec![0u8; 48];
+        data[16..24].copy_from_slice(&0xffff_8881_0000_1000u64.to_le_bytes());
+        data[24..28].copy_from_slice(&((4u32 << 16) | 48).to_le_bytes());
+        data[40..44].copy_from_slice(&3u32.to_le_bytes());
+        data.extend_from_slice(b"gfx\0");
+        let le = Endianness::LittleEndian;
+        assert_eq!(fence.read_u64(&data, le), Some(0xffff_8881_0000_1000));
+        assert_eq!(
+            format.field("job_count").unwrap().read_u64(&data, le),
+            Some(3)
+        );
+        assert_eq!(
+            format.field("name").unwrap().read_str(&data, le).as_deref(),
+            Some("gfx")
+        );
+        assert_eq!(fence.read_str(&data, le), None);
+        assert_eq!(fence.read_u64(&data[..20], le), None);
+    }
+
+    #[test]
+    fn parse_rel_loc_field() {
+        let format = TracepointFormat::parse(
+            "name: test\n\tfield:__rel_loc char[] name;\toffset:8;\tsize:4;\tsigned:0;\n",
+        );
+        let name = format.field("name").unwrap();
+        let mut data = vec![0u8; 12];
+        // The string starts right after the field, at offset 12.
+        data[8..12].copy_from_slice(&(4u32 << 16).to_le_bytes());
+        data.extend_from_slice(b"rcs\0");
+        assert_eq!(
+            name.read_str(&data, Endianness::LittleEndian).as_deref(),
+            Some("rcs")
+        );
+    }
+
+    #[test]
+    fn parse_tracing_data_section() {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"\x17\x08\x44tracing0.6\0");
+        data.push(0); // little endian
+        data.push(8); // long size
+        data.extend_from_slice(&4096u32.to_le_bytes());
+        for (name, contents) in [("header_page", &b"page"[..]), ("header_event", b"event")] {
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+            data.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+            data.extend_from_slice(contents);
+        }
+        // One ftrace format.
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&4u64.to_le_bytes());
+        data.extend_from_slice(b"name");
+        // One system with one format.
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(b"gpu_scheduler\0");
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&(DRM_RUN_JOB_FORMAT.len() as u64).to_le_bytes());
+        data.extend_from_slice(DRM_RUN_JOB_FORMAT.as_bytes());
+        // kallsyms etc. follow, and are ignored.
+        data.extend_from_slice(&0u32.to_le_bytes());
+
+        let formats = parse_tracing_data(&data).unwrap();
+        assert_eq!(formats.len(), 1);
+        assert_eq!(
+            formats["gpu_scheduler:drm_run_job"],
+            TracepointFormat::parse(DRM_RUN_JOB_FORMAT)
+        );
+
+        assert!(parse_tracing_data(&data[..40]).is_none());
+        assert!(parse_tracing_data(b"not tracing data").is_none());
+    }
+}
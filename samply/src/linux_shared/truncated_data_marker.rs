@@ -0,0 +1,49 @@
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use serde_json::json;
+
+/// Covers the part of the profile after the last record which could be read
+/// from a truncated or corrupt perf.data file.
+#[derive(Debug, Clone)]
+pub struct TruncatedDataMarker {
+    pub error: String,
+}
+
+impl ProfilerMarker for TruncatedDataMarker {
+    const MARKER_TYPE_NAME: &'static str = "TruncatedData";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "error": self.error,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("Truncated data"),
+            tooltip_label: Some("Truncated data: {marker.data.error}"),
+            table_label: Some("Truncated data: {marker.data.error}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "error",
+                    label: "Error",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "The perf.data file could not be read past this point, so any samples after it are missing.",
+                }),
+            ],
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use byteorder::ByteOrder;
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use linux_perf_data::linux_perf_event_reader;
+use linux_perf_data::Endianness;
+use serde_json::json;
+
+use linux_perf_event_reader::RawData;
+
+use std::fmt::Debug;
+
+/// ```text
+/// # cat /sys/kernel/tracing/events/drm/drm_vblank_event/format
+/// name: drm_vblank_event
+/// ID: 1483
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:int crtc; offset:8;       size:4; signed:1;
+///         field:unsigned int seq; offset:12;      size:4; signed:0;
+///         field:ktime_t time;     offset:16;      size:8; signed:1;
+///         field:bool high_prec;   offset:24;      size:1; signed:0;
+///
+/// print fmt: "crtc=%d, seq=%u, time=%lld, high-prec=%s", REC->crtc, REC->seq, REC->time, REC->high_prec ? "true" : "false"
+/// ```
+///
+/// Kernels before 5.10 don't have the `time` and `high_prec` fields.
+#[derive(Debug)]
+pub struct DrmVblankEvent {
+    pub crtc: i32,
+    pub seq: u32,
+}
+
+impl DrmVblankEvent {
+    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
+        let _common_type = data.read_u16::<O>()?;
+        let _common_flags = data.read_u8()?;
+        let _common_preempt_count = data.read_u8()?;
+        let _common_pid = data.read_i32::<O>()?;
+        let crtc = data.read_i32::<O>()?;
+        let seq = data.read_u32::<O>()?;
+        Ok(DrmVblankEvent { crtc, seq })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VblankMarker {
+    pub crtc: i32,
+    pub seq: u32,
+}
+
+impl ProfilerMarker for VblankMarker {
+    const MARKER_TYPE_NAME: &'static str = "Vblank";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "crtc": self.crtc,
+            "seq": self.seq,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.seq}"),
+            tooltip_label: Some("Vblank {marker.data.seq} on CRTC {marker.data.crtc}"),
+            table_label: Some("CRTC {marker.data.crtc}, seq {marker.data.seq}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "crtc",
+                    label: "CRTC",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "seq",
+                    label: "Sequence number",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted when the drm:drm_vblank_event tracepoint is hit, i.e. when a display refresh was delivered to a DRM client.",
+                }),
+            ],
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Support for Windows programs which run under Wine or Proton.
+//!
+//! Wine loads the program's PE images into a regular Linux process, next to
+//! its own ELF libraries. The PE images need their own unwind info and their
+//! own debug names, so that stacks can be unwound through them and their
+//! symbols can be found, e.g. on a symbol server from `_NT_SYMBOL_PATH`.
+
+use framehop::ModuleSectionInfo;
+use object::{Object, ObjectSection};
+use samply_symbols::PeCodeId;
+use wholesym::samply_symbols;
+
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use memmap2::Mmap;
+
+use super::mmap_range_or_vec::MmapRangeOrVec;
+
+/// The names of Wine's loader binaries, which a Wine process has until Wine
+/// names it after the program. Linux truncates process names to 15 bytes.
+const WINE_LOADER_NAMES: &[&str] = &[
+    "wine",
+    "wine64",
+    "wine-preloader",
+    "wine64-preloade",
+    "wine64-preloader",
+];
+
+/// Whether a process with this name is a Wine process which hasn't been named
+/// after its program yet.
+pub fn is_wine_loader_name(name: &str) -> bool {
+    WINE_LOADER_NAMES.contains(&name)
+}
+
+/// Whether the file at this path is likely a PE image, based on its extension.
+pub fn has_pe_extension(path: &[u8]) -> bool {
+    let Some(extension_start) = path.iter().rposition(|&b| b == b'.') else {
+        return false;
+    };
+    let extension = &path[extension_start + 1..];
+    ["exe", "dll", "drv", "sys", "ocx", "cpl"]
+        .iter()
+        .any(|pe_extension| extension.eq_ignore_ascii_case(pe_extension.as_bytes()))
+}
+
+/// The name of the program of a Wine process, if `path` is its .exe.
+pub fn program_name_for_exe(path: &[u8]) -> Option<String> {
+    let file_name = Path::new(std::str::from_utf8(path).ok()?).file_name()?;
+    let file_name = file_name.to_str()?;
+    let (_, extension) = file_name.rsplit_once('.')?;
+    extension
+        .eq_ignore_ascii_case("exe")
+        .then(|| file_name.to_string())
+}
+
+/// The debug name and debug path of a PE image, from the PDB reference in
+/// the image. The PDB path in the image is usually a path on the build
+/// machine, so the debug path points to a PDB next to the image instead, and
+/// the debug name is what symbol servers are queried with.
+pub fn pdb_debug_name_and_path(file: &object::File, image_path: &str) -> Option<(String, String)> {
+    let pdb_info = file.pdb_info().ok()??;
+    let pdb_path = std::str::from_utf8(pdb_info.path()).ok()?;
+    let pdb_name = pdb_path.rsplit(['\\', '/']).next()?;
+    if pdb_name.is_empty() {
+        return None;
+    }
+    let debug_path = Path::new(image_path)
+        .with_file_name(pdb_name)
+        .to_string_lossy()
+        .into_owned();
+    Some((pdb_name.to_string(), debug_path))
+}
+
+/// The code ID of a PE image, which symbol servers use to find the image.
+pub fn pe_code_id(file: &object::File) -> Option<PeCodeId> {
+    use object::read::pe::ImageOptionalHeader;
+    let (timestamp, image_size) = match file {
+        object::File::Pe32(pe) => (
+            pe.nt_headers()
+                .file_header
+                .time_date_stamp
+                .get(object::LittleEndian),
+            pe.nt_headers().optional_header.size_of_image(),
+        ),
+        object::File::Pe64(pe) => (
+            pe.nt_headers()
+                .file_header
+                .time_date_stamp
+                .get(object::LittleEndian),
+            pe.nt_headers().optional_header.size_of_image(),
+        ),
+        _ => return None,
+    };
+    Some(PeCodeId {
+        timestamp,
+        image_size,
+    })
+}
+
+/// The file version and the product version of a PE image, e.g.
+/// "10.0.19041.1", from the fixed part of its version resource.
+pub fn pe_file_and_product_version(file: &object::File) -> Option<(String, String)> {
+    let version_data = match file {
+        object::File::Pe32(pe) => {
+            pe_version_resource(pe.data(), pe.data_directories(), &pe.section_table())
+        }
+        object::File::Pe64(pe) => {
+            pe_version_resource(pe.data(), pe.data_directories(), &pe.section_table())
+        }
+        _ => None,
+    }?;
+
+    // VS_VERSIONINFO starts with a header and the key "VS_VERSION_INFO", and
+    // is followed by VS_FIXEDFILEINFO, which starts with this signature.
+    const FIXED_FILE_INFO_SIGNATURE: [u8; 4] = 0xfeef04bd_u32.to_le_bytes();
+    let last_offset = version_data.len().checked_sub(24)?;
+    let fixed_info_offset = (0..=last_offset)
+        .step_by(4)
+        .find(|&offset| version_data[offset..].starts_with(&FIXED_FILE_INFO_SIGNATURE))?;
+    let fixed_info = &version_data[fixed_info_offset..];
+    let read_u32 = |offset: usize| {
+        u32::from_le_bytes([
+            fixed_info[offset],
+            fixed_info[offset + 1],
+            fixed_info[offset + 2],
+            fixed_info[offset + 3],
+        ])
+    };
+    let format_version =
+        |ms: u32, ls: u32| format!("{}.{}.{}.{}", ms >> 16, ms & 0xffff, ls >> 16, ls & 0xffff);
+    let file_version = format_version(read_u32(8), read_u32(12));
+    let product_version = format_version(read_u32(16), read_u32(20));
+    Some((file_version, product_version))
+}
+
+/// The data of the first RT_VERSION resource, usually the only one.
+fn pe_version_resource<'data>(
+    data: &'data [u8],
+    data_directories: object::read::pe::DataDirectories<'data>,
+    sections: &object::read::pe::SectionTable<'data>,
+) -> Option<&'data [u8]> {
+    use object::read::pe::ResourceNameOrId;
+
+    let resources = data_directories.resource_directory(data, sections).ok()??;
+    // The resource tree has three levels: the type, the name and the language.
+    let version_entry = resources.root().ok()?.entries.iter().find(|entry| {
+        matches!(entry.name_or_id(), ResourceNameOrId::Id(id) if id == object::pe::RT_VERSION)
+    })?;
+    let names = version_entry.data(resources).ok()?.table()?;
+    let languages = names.entries.first()?.data(resources).ok()?.table()?;
+    let data_entry = languages.entries.first()?.data(resources).ok()?.data()?;
+    let version_data =
+        sections.pe_data_at(data, data_entry.offset_to_data.get(object::LittleEndian))?;
+    version_data.get(..data_entry.size.get(object::LittleEndian) as usize)
+}
+
+/// The sections of a PE image which framehop needs for unwinding with the
+/// image's `.pdata` unwind info. `ExplicitModuleSectionInfo` only has room
+/// for the sections of ELF and mach-O images.
+pub struct PeModuleSectionInfo {
+    base_svma: u64,
+    sections: Vec<(&'static [u8], Range<u64>, Option<MmapRangeOrVec>)>,
+}
+
+impl PeModuleSectionInfo {
+    const SECTION_NAMES: [&'static [u8]; 4] = [b".text", b".pdata", b".rdata", b".xdata"];
+
+    pub fn new(file: &object::File, mmap: Arc<Mmap>, base_svma: u64) -> Self {
+        let sections = Self::SECTION_NAMES
+            .iter()
+            .filter_map(|name| {
+                let section = file.section_by_name_bytes(name)?;
+                let svma_range = section.address()..section.address() + section.size();
+                let data = section.file_range().and_then(|(start, size)| {
+                    MmapRangeOrVec::new_mmap_range(mmap.clone(), start, size)
+                });
+                Some((*name, svma_range, data))
+            })
+            .collect();
+        Self {
+            base_svma,
+            sections,
+        }
+    }
+}
+
+impl ModuleSectionInfo<MmapRangeOrVec> for PeModuleSectionInfo {
+    fn base_svma(&self) -> u64 {
+        self.base_svma
+    }
+
+    fn section_svma_range(&mut self, name: &[u8]) -> Option<Range<u64>> {
+        self.sections
+            .iter()
+            .find(|(section_name, _, _)| *section_name == name)
+            .map(|(_, svma_range, _)| svma_range.clone())
+    }
+
+    fn section_data(&mut self, name: &[u8]) -> Option<MmapRangeOrVec> {
+        self.sections
+            .iter()
+            .find(|(section_name, _, _)| *section_name == name)
+            .and_then(|(_, _, data)| data.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        has_pe_extension, is_wine_loader_name, pe_file_and_product_version, program_name_for_exe,
+    };
+
+    use std::path::Path;
+
+    #[test]
+    fn wine_names() {
+        assert!(is_wine_loader_name("wine64-preloade"));
+        assert!(!is_wine_loader_name("Game.exe"));
+        assert!(has_pe_extension(b"/games/Game/Game.EXE"));
+        assert!(has_pe_extension(
+            b"/wine/lib/wine/x86_64-windows/winex11.drv"
+        ));
+        assert!(!has_pe_extension(b"/usr/lib/libc.so.6"));
+        assert_eq!(
+            program_name_for_exe(b"/games/Game/Binaries/Win64/Game-Win64-Shipping.exe"),
+            Some("Game-Win64-Shipping.exe".to_string())
+        );
+        assert_eq!(
+            program_name_for_exe(b"/wine/x86_64-windows/ntdll.dll"),
+            None
+        );
+    }
+
+    fn versions_of_fixture(path: &str) -> Option<(String, String)> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../fixtures")
+            .join(path);
+        let data = std::fs::read(path).unwrap();
+        let file = object::File::parse(&data[..]).unwrap();
+        pe_file_and_product_version(&file)
+    }
+
+    #[test]
+    fn pe_versions() {
+        assert_eq!(
+            versions_of_fixture("win64-ci/mozglue.dll"),
+            Some(("78.0.0.7437".to_string(), "78.0.0.7437".to_string()))
+        );
+        // The product version of firefox.exe doesn't have the build number.
+        assert_eq!(
+            versions_of_fixture("win64-ci/firefox.exe"),
+            Some(("78.0.0.7437".to_string(), "78.0.0.0".to_string()))
+        );
+        // This one has no version resource.
+        assert_eq!(versions_of_fixture("win64-ci/WriteArgument.exe"), None);
+    }
+}
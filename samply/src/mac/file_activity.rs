@@ -0,0 +1,340 @@
+use fxprof_processed_profile::{
+    CategoryHandle, MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema,
+    MarkerSchemaField, MarkerStaticField, MarkerTiming, Profile, ProfilerMarker, ThreadHandle,
+};
+use serde_json::json;
+
+use std::collections::HashMap;
+use std::io;
+
+use crate::shared::timestamp_converter::TimestampConverter;
+
+use super::kdebug::{KdBuf, KdebugTracer, DBG_BSD, DBG_BSD_EXCP_SC, DBG_FSRW, DBG_FSYSTEM};
+use super::time::mach_ticks_to_nanos;
+
+/// The `VFS_LOOKUP` event code in the `DBG_FSYSTEM`/`DBG_FSRW` subclass. It is
+/// emitted during path lookups and carries the looked-up path, spread over one
+/// or more events.
+const VFS_LOOKUP_CODE: u32 = 36;
+
+/// How many events the kernel buffers between two reads.
+const KDEBUG_BUFFER_EVENT_COUNT: usize = 256 * 1024;
+
+/// Emits file I/O markers for the threads of the profiled processes, based on the
+/// BSD syscall and VFS lookup events from kdebug.
+pub struct FileActivityTracker {
+    tracer: KdebugTracer,
+    /// tid -> (pid, thread handle)
+    threads: HashMap<u32, (u32, ThreadHandle)>,
+    /// tid -> the syscall this thread is currently in
+    pending_syscalls: HashMap<u32, PendingSyscall>,
+    /// (pid, fd) -> path, so that read/write markers can show the file path
+    open_files: HashMap<(u32, u64), String>,
+}
+
+struct PendingSyscall {
+    syscall: FileSyscall,
+    start_ticks: u64,
+    fd: Option<u64>,
+    paths: Vec<String>,
+    current_path: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileSyscall {
+    name: &'static str,
+    kind: FileSyscallKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileSyscallKind {
+    /// The syscall takes a path and returns a file descriptor.
+    Open,
+    /// The syscall takes a path.
+    Path,
+    /// The syscall takes a file descriptor as its first argument.
+    Fd,
+    /// Like `Fd`, and the return value is the number of bytes transferred.
+    ReadWrite,
+    /// Like `Fd`, and the file descriptor is invalid afterwards.
+    Close,
+}
+
+impl FileSyscall {
+    /// Syscall numbers are from xnu's bsd/kern/syscalls.master.
+    fn from_syscall_number(number: u32) -> Option<Self> {
+        use FileSyscallKind::*;
+        let (name, kind) = match number {
+            3 => ("read", ReadWrite),
+            4 => ("write", ReadWrite),
+            5 => ("open", Open),
+            6 => ("close", Close),
+            10 => ("unlink", Path),
+            95 => ("fsync", Fd),
+            128 => ("rename", Path),
+            136 => ("mkdir", Path),
+            153 => ("pread", ReadWrite),
+            154 => ("pwrite", ReadWrite),
+            338 => ("stat64", Path),
+            339 => ("fstat64", Fd),
+            340 => ("lstat64", Path),
+            344 => ("getdirentries64", Fd),
+            396 => ("read", ReadWrite),
+            397 => ("write", ReadWrite),
+            398 => ("open", Open),
+            399 => ("close", Close),
+            408 => ("fsync", Fd),
+            414 => ("pread", ReadWrite),
+            415 => ("pwrite", ReadWrite),
+            463 => ("openat", Open),
+            464 => ("openat", Open),
+            _ => return None,
+        };
+        Some(FileSyscall { name, kind })
+    }
+
+    fn takes_fd(&self) -> bool {
+        matches!(
+            self.kind,
+            FileSyscallKind::Fd | FileSyscallKind::ReadWrite | FileSyscallKind::Close
+        )
+    }
+}
+
+impl FileActivityTracker {
+    pub fn start() -> io::Result<Self> {
+        let tracer = KdebugTracer::start(DBG_FSYSTEM..DBG_BSD + 1, KDEBUG_BUFFER_EVENT_COUNT)?;
+        Ok(Self {
+            tracer,
+            threads: HashMap::new(),
+            pending_syscalls: HashMap::new(),
+            open_files: HashMap::new(),
+        })
+    }
+
+    /// Make the tracker aware of the threads of a profiled process. Events from
+    /// threads which haven't been registered are discarded.
+    pub fn add_threads(&mut self, pid: u32, threads: impl Iterator<Item = (u32, ThreadHandle)>) {
+        for (tid, thread_handle) in threads {
+            self.threads.insert(tid, (pid, thread_handle));
+        }
+    }
+
+    /// Read all pending kdebug events and turn completed syscalls into markers.
+    pub fn process_events(
+        &mut self,
+        profile: &mut Profile,
+        timestamp_converter: &TimestampConverter,
+    ) {
+        let events = match self.tracer.read_events() {
+            Ok(events) => events,
+            Err(err) => {
+                eprintln!("Reading kdebug events failed: {err}");
+                return;
+            }
+        };
+        for event in events {
+            let tid = event.arg5 as u32;
+            let Some(&(pid, thread_handle)) = self.threads.get(&tid) else {
+                continue;
+            };
+            match (event.class(), event.subclass()) {
+                (DBG_BSD, DBG_BSD_EXCP_SC) => {
+                    let Some(syscall) = FileSyscall::from_syscall_number(event.code()) else {
+                        continue;
+                    };
+                    if event.is_start() {
+                        self.pending_syscalls.insert(
+                            tid,
+                            PendingSyscall {
+                                syscall,
+                                start_ticks: event.timestamp,
+                                fd: syscall.takes_fd().then_some(event.arg1),
+                                paths: Vec::new(),
+                                current_path: Vec::new(),
+                            },
+                        );
+                    } else if event.is_end() {
+                        match self.pending_syscalls.remove(&tid) {
+                            Some(pending) if pending.syscall == syscall => {
+                                let marker = Self::make_marker(
+                                    &mut self.open_files,
+                                    pid,
+                                    pending.syscall,
+                                    pending.fd,
+                                    pending.paths,
+                                    event,
+                                );
+                                let start = timestamp_converter
+                                    .convert_time(mach_ticks_to_nanos(pending.start_ticks));
+                                let end = timestamp_converter
+                                    .convert_time(mach_ticks_to_nanos(event.timestamp));
+                                profile.add_marker(
+                                    thread_handle,
+                                    CategoryHandle::OTHER,
+                                    "FileIO",
+                                    marker,
+                                    MarkerTiming::Interval(start, end),
+                                );
+                            }
+                            _ => {
+                                // We missed the start of this syscall, e.g. because it
+                                // started before tracing was enabled.
+                            }
+                        }
+                    }
+                }
+                (DBG_FSYSTEM, DBG_FSRW) if event.code() == VFS_LOOKUP_CODE => {
+                    if let Some(pending) = self.pending_syscalls.get_mut(&tid) {
+                        pending.add_lookup_event(event);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn make_marker(
+        open_files: &mut HashMap<(u32, u64), String>,
+        pid: u32,
+        syscall: FileSyscall,
+        fd: Option<u64>,
+        paths: Vec<String>,
+        end_event: &KdBuf,
+    ) -> FileIoMarker {
+        let errno = end_event.arg1;
+        let retval = end_event.arg2;
+        let path = match (fd, syscall.kind) {
+            (Some(fd), FileSyscallKind::Close) => open_files.remove(&(pid, fd)),
+            (Some(fd), _) => open_files.get(&(pid, fd)).cloned(),
+            (None, _) => None,
+        }
+        .unwrap_or_else(|| paths.join(" -> "));
+        let fd = if syscall.kind == FileSyscallKind::Open && errno == 0 {
+            open_files.insert((pid, retval), path.clone());
+            Some(retval)
+        } else {
+            fd
+        };
+        let size = if syscall.kind == FileSyscallKind::ReadWrite && errno == 0 {
+            Some(retval)
+        } else {
+            None
+        };
+        FileIoMarker {
+            operation: syscall.name,
+            path,
+            fd,
+            size,
+            errno,
+        }
+    }
+}
+
+impl PendingSyscall {
+    /// The path of a lookup is spread over a sequence of events. The first event
+    /// has DBG_FUNC_START set and carries the vnode in arg1, the last event has
+    /// DBG_FUNC_END set. All other arguments hold NUL-padded path bytes.
+    fn add_lookup_event(&mut self, event: &KdBuf) {
+        let args = if event.is_start() {
+            self.current_path.clear();
+            &[event.arg2, event.arg3, event.arg4][..]
+        } else {
+            &[event.arg1, event.arg2, event.arg3, event.arg4][..]
+        };
+        for arg in args {
+            self.current_path.extend_from_slice(&arg.to_ne_bytes());
+        }
+        if event.is_end() {
+            let len = self
+                .current_path
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(self.current_path.len());
+            let path = String::from_utf8_lossy(&self.current_path[..len]).to_string();
+            self.paths.push(path);
+            self.current_path.clear();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileIoMarker {
+    operation: &'static str,
+    path: String,
+    fd: Option<u64>,
+    size: Option<u64>,
+    errno: u64,
+}
+
+impl ProfilerMarker for FileIoMarker {
+    const MARKER_TYPE_NAME: &'static str = "FileIO";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        let mut data = json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "operation": self.operation,
+            "path": self.path,
+        });
+        if let Some(fd) = self.fd {
+            data["fd"] = json!(fd);
+        }
+        if let Some(size) = self.size {
+            data["size"] = json!(size);
+        }
+        if self.errno != 0 {
+            data["errno"] = json!(self.errno);
+        }
+        data
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineFileIO,
+            ],
+            chart_label: Some("{marker.data.operation} {marker.data.path}"),
+            tooltip_label: Some("{marker.data.operation} {marker.data.path}"),
+            table_label: Some("{marker.data.operation} {marker.data.path}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "operation",
+                    label: "Operation",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "path",
+                    label: "Path",
+                    format: MarkerFieldFormat::FilePath,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "fd",
+                    label: "File descriptor",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "size",
+                    label: "Size",
+                    format: MarkerFieldFormat::Bytes,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "errno",
+                    label: "Error",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted for file system syscalls, based on kdebug tracing.",
+                }),
+            ],
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! A minimal wrapper around the kdebug kernel trace facility, which is what
+//! fs_usage and Instruments' "File Activity" use under the hood.
+//!
+//! kdebug is controlled via sysctl and requires root privileges. There is only
+//! one kdebug trace buffer per system, so starting a trace here will take it over
+//! from any other tracing tool which is currently running.
+
+use std::{io, mem, ptr};
+
+// From <sys/sysctl.h>.
+const KERN_KDEBUG: libc::c_int = 24;
+const KERN_KDENABLE: libc::c_int = 3;
+const KERN_KDSETBUF: libc::c_int = 4;
+const KERN_KDSETUP: libc::c_int = 6;
+const KERN_KDREMOVE: libc::c_int = 7;
+const KERN_KDSETREG: libc::c_int = 8;
+const KERN_KDREADTR: libc::c_int = 10;
+
+// From <sys/kdebug.h>.
+const KDBG_CLASSTYPE: libc::c_uint = 0x10000;
+
+pub const DBG_FSYSTEM: u32 = 3;
+pub const DBG_BSD: u32 = 4;
+pub const DBG_FSRW: u32 = 1;
+pub const DBG_BSD_EXCP_SC: u32 = 0x0c;
+
+pub const DBG_FUNC_START: u32 = 1;
+pub const DBG_FUNC_END: u32 = 2;
+
+/// A single trace event, `kd_buf` in <sys/kdebug.h>. This is the layout on
+/// 64-bit kernels.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KdBuf {
+    /// In `mach_absolute_time()` units.
+    pub timestamp: u64,
+    pub arg1: u64,
+    pub arg2: u64,
+    pub arg3: u64,
+    pub arg4: u64,
+    /// The thread ID of the thread which emitted the event.
+    pub arg5: u64,
+    pub debugid: u32,
+    pub cpuid: u32,
+    pub unused: u64,
+}
+
+impl KdBuf {
+    pub fn class(&self) -> u32 {
+        self.debugid >> 24
+    }
+
+    pub fn subclass(&self) -> u32 {
+        (self.debugid >> 16) & 0xff
+    }
+
+    /// The event code, without the class, subclass and function qualifier bits.
+    pub fn code(&self) -> u32 {
+        (self.debugid & 0xffff) >> 2
+    }
+
+    pub fn is_start(&self) -> bool {
+        self.debugid & DBG_FUNC_START != 0
+    }
+
+    pub fn is_end(&self) -> bool {
+        self.debugid & DBG_FUNC_END != 0
+    }
+}
+
+/// `kd_regtype` in <sys/kdebug.h>.
+#[repr(C)]
+struct KdRegtype {
+    type_: libc::c_uint,
+    value1: libc::c_uint,
+    value2: libc::c_uint,
+    value3: libc::c_uint,
+    value4: libc::c_uint,
+}
+
+/// An active system-wide kdebug trace. Tracing is stopped and the kernel
+/// buffers are freed when this is dropped.
+pub struct KdebugTracer {
+    buffer: Vec<KdBuf>,
+}
+
+impl KdebugTracer {
+    /// Start tracing all events whose class lies in `classes` (e.g. `DBG_FSYSTEM..DBG_BSD + 1`).
+    /// `buffer_event_count` is the size of the kernel-side ring buffer, in events.
+    pub fn start(classes: std::ops::Range<u32>, buffer_event_count: usize) -> io::Result<Self> {
+        // Throw away any existing trace configuration, like fs_usage does.
+        let _ = kdebug_ctl(&[KERN_KDREMOVE]);
+
+        // From here on, dropping the tracer on error cleans up the kernel state.
+        let tracer = KdebugTracer {
+            buffer: vec![KdBuf::default(); 64 * 1024],
+        };
+        kdebug_ctl(&[KERN_KDSETBUF, buffer_event_count as libc::c_int])?;
+        kdebug_ctl(&[KERN_KDSETUP, 0])?;
+
+        let mut regtype = KdRegtype {
+            type_: KDBG_CLASSTYPE,
+            value1: classes.start,
+            value2: classes.end,
+            value3: 0,
+            value4: 0,
+        };
+        let mut size = mem::size_of::<KdRegtype>();
+        let mut mib = [libc::CTL_KERN, KERN_KDEBUG, KERN_KDSETREG];
+        sysctl(
+            &mut mib,
+            &mut regtype as *mut _ as *mut libc::c_void,
+            &mut size,
+        )?;
+
+        kdebug_ctl(&[KERN_KDENABLE, 1])?;
+        Ok(tracer)
+    }
+
+    /// Drains the events which have accumulated in the kernel buffer since the
+    /// last call. The returned events are in timestamp order.
+    pub fn read_events(&mut self) -> io::Result<&[KdBuf]> {
+        // KERN_KDREADTR takes the buffer size in bytes, and returns the number of events.
+        let mut size = self.buffer.len() * mem::size_of::<KdBuf>();
+        let mut mib = [libc::CTL_KERN, KERN_KDEBUG, KERN_KDREADTR];
+        sysctl(
+            &mut mib,
+            self.buffer.as_mut_ptr() as *mut libc::c_void,
+            &mut size,
+        )?;
+        Ok(&self.buffer[..size.min(self.buffer.len())])
+    }
+}
+
+impl Drop for KdebugTracer {
+    fn drop(&mut self) {
+        let _ = kdebug_ctl(&[KERN_KDENABLE, 0]);
+        let _ = kdebug_ctl(&[KERN_KDREMOVE]);
+    }
+}
+
+/// Issue a kdebug sysctl whose arguments are all encoded in the MIB.
+fn kdebug_ctl(op_and_args: &[libc::c_int]) -> io::Result<()> {
+    let mut mib = [libc::CTL_KERN, KERN_KDEBUG, 0, 0];
+    mib[2..][..op_and_args.len()].copy_from_slice(op_and_args);
+    let mut size = 0;
+    sysctl(
+        &mut mib[..2 + op_and_args.len()],
+        ptr::null_mut(),
+        &mut size,
+    )
+}
+
+fn sysctl(mib: &mut [libc::c_int], oldp: *mut libc::c_void, size: &mut usize) -> io::Result<()> {
+    let ret = unsafe {
+        libc::sysctl(
+            mib.as_mut_ptr(),
+            mib.len() as libc::c_uint,
+            oldp,
+            size,
+            ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -2,6 +2,8 @@
 mod dyld_bindings;
 
 mod error;
+mod file_activity;
+mod kdebug;
 pub mod kernel_error;
 mod mach_ipc;
 mod proc_maps;
@@ -209,6 +209,22 @@ fn get_all_image_info_addr(task: mach_port_t) -> kernel_error::Result<u64> {
     Ok(dyld_info.all_image_info_addr)
 }
 
+/// Keeps all threads of a task suspended until it's dropped.
+pub struct SuspendedTask(mach_port_t);
+
+impl SuspendedTask {
+    pub fn new(task: mach_port_t) -> kernel_error::Result<Self> {
+        unsafe { task_suspend(task) }.into_result()?;
+        Ok(SuspendedTask(task))
+    }
+}
+
+impl Drop for SuspendedTask {
+    fn drop(&mut self) {
+        let _ = unsafe { task_resume(self.0) };
+    }
+}
+
 fn with_suspended_task<T>(
     task: mach_port_t,
     f: impl FnOnce() -> kernel_error::Result<T>,
@@ -481,17 +497,27 @@ impl<'a> StackwalkerRef<'a> {
     }
 }
 
+/// How much of a thread's stack is mapped in one go on the first stack walk.
+const STACK_PREFETCH_SIZE: u64 = 256 * 1024;
+
 /// `frames` must be empty initially.
 ///
 /// On return, `frames` will have the stack frames from callee-most to root-most.
+///
+/// The thread is suspended during the stack walk, unless the caller has already
+/// suspended it, for example with [`SuspendedTask`]. If `prefetch_stack` is true,
+/// a large part of the stack is mapped with a single call before walking, rather
+/// than page range by page range as the unwinder reads it.
 pub fn get_backtrace(
     stackwalker: StackwalkerRef,
     memory: &mut ForeignMemory,
     thread_act: mach_port_t,
     frames: &mut Vec<FrameAddress>,
     fold_recursive_prefix: bool,
+    thread_is_suspended: bool,
+    prefetch_stack: bool,
 ) -> Result<(), SamplingError> {
-    with_suspended_thread(thread_act, || {
+    let walk = || {
         let (pc, regs) = get_unwinding_registers(thread_act).map_err(|err| match err {
             KernelError::InvalidArgument
             | KernelError::MachSendInvalidDest
@@ -500,21 +526,28 @@ pub fn get_backtrace(
             }
             err => SamplingError::Ignorable("thread_get_state in get_unwinding_registers", err),
         })?;
+        if prefetch_stack {
+            memory.prefetch(regs.sp(), STACK_PREFETCH_SIZE);
+        }
         do_stackwalk(stackwalker, pc, regs, memory, frames);
         Ok(())
-    })
-    .unwrap_or_else(|err| match err {
-        KernelError::InvalidArgument
-        | KernelError::MachSendInvalidDest
-        | KernelError::Terminated => Err(SamplingError::ThreadTerminated(
-            "thread_suspend in with_suspended_thread",
-            err,
-        )),
-        err => Err(SamplingError::Ignorable(
-            "thread_suspend in with_suspended_thread",
-            err,
-        )),
-    })?;
+    };
+    if thread_is_suspended {
+        walk()?;
+    } else {
+        with_suspended_thread(thread_act, walk).unwrap_or_else(|err| match err {
+            KernelError::InvalidArgument
+            | KernelError::MachSendInvalidDest
+            | KernelError::Terminated => Err(SamplingError::ThreadTerminated(
+                "thread_suspend in with_suspended_thread",
+                err,
+            )),
+            err => Err(SamplingError::Ignorable(
+                "thread_suspend in with_suspended_thread",
+                err,
+            )),
+        })?;
+    }
 
     if fold_recursive_prefix && !frames.is_empty() {
         let last_frame = *frames.last().unwrap();
@@ -578,6 +611,20 @@ impl ForeignMemory {
         self.data.shrink_to_fit();
     }
 
+    /// Maps up to `size` bytes starting at `address` with a single call, so that
+    /// reading them later doesn't need a call per page range. If the range
+    /// isn't fully mapped in the task, smaller ranges are tried.
+    pub fn prefetch(&mut self, address: u64, mut size: u64) {
+        let page_size = unsafe { vm_page_size } as u64;
+        let start = unsafe { mach_vm_trunc_page(address) };
+        while size >= page_size {
+            if self.get_data_for_range(start..start + size).is_ok() {
+                return;
+            }
+            size /= 2;
+        }
+    }
+
     pub fn read_u64_at_address(&mut self, address: u64) -> kernel_error::Result<u64> {
         let number: &u64 = unsafe { self.get_type_ref_at_address(address) }?;
         Ok(*number)
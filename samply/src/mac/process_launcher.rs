@@ -1,16 +1,21 @@
-use std::ffi::{OsStr, OsString};
+use std::ffi::{CString, OsStr, OsString};
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Write};
 use std::mem;
+use std::os::raw::{c_char, c_int, c_short};
 use std::os::unix::prelude::OsStrExt;
+use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
-use std::process::{Child, Command};
+use std::process::ExitStatus;
+use std::ptr;
 use std::sync::Arc;
 use std::time::Duration;
 
 pub use super::mach_ipc::{mach_port_t, MachError, OsIpcSender};
 use super::mach_ipc::{BlockingMode, OsIpcMultiShotServer, MACH_PORT_NULL};
 use flate2::write::GzDecoder;
+use mach::kern_return::KERN_SUCCESS;
+use mach::traps::{mach_task_self, task_for_pid};
 use tempfile::tempdir;
 
 pub struct TaskLauncher {
@@ -21,12 +26,9 @@ pub struct TaskLauncher {
 }
 
 impl TaskLauncher {
-    pub fn launch_child(&self) -> Child {
-        match Command::new(&self.program)
-            .args(&self.args)
-            .envs(self.child_env.clone())
-            .spawn()
-        {
+    /// Launches the child suspended. Call [`LaunchedChild::resume`] to let it run.
+    pub fn launch_child(&self) -> LaunchedChild {
+        match self.spawn_suspended() {
             Ok(child) => child,
             Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
                 eprintln!(
@@ -41,6 +43,108 @@ impl TaskLauncher {
             }
         }
     }
+
+    fn spawn_suspended(&self) -> io::Result<LaunchedChild> {
+        fn to_cstring(s: &OsStr) -> io::Result<CString> {
+            CString::new(s.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        }
+
+        let program = to_cstring(&self.program)?;
+        let mut args = vec![program.clone()];
+        for arg in &self.args {
+            args.push(to_cstring(arg)?);
+        }
+        let mut env = Vec::with_capacity(self.child_env.len());
+        for (name, val) in &self.child_env {
+            let mut name_and_val = name.clone();
+            name_and_val.push("=");
+            name_and_val.push(val);
+            env.push(to_cstring(&name_and_val)?);
+        }
+        let argv: Vec<*mut c_char> = args
+            .iter()
+            .map(|arg| arg.as_ptr() as *mut c_char)
+            .chain(std::iter::once(ptr::null_mut()))
+            .collect();
+        let envp: Vec<*mut c_char> = env
+            .iter()
+            .map(|var| var.as_ptr() as *mut c_char)
+            .chain(std::iter::once(ptr::null_mut()))
+            .collect();
+
+        let mut pid: libc::pid_t = 0;
+        let result = unsafe {
+            let mut attr: libc::posix_spawnattr_t = mem::zeroed();
+            let result = libc::posix_spawnattr_init(&mut attr);
+            if result != 0 {
+                return Err(io::Error::from_raw_os_error(result));
+            }
+            let mut result = libc::posix_spawnattr_setflags(
+                &mut attr,
+                libc::POSIX_SPAWN_START_SUSPENDED as c_short,
+            );
+            if result == 0 {
+                result = libc::posix_spawnp(
+                    &mut pid,
+                    program.as_ptr(),
+                    ptr::null(),
+                    &attr,
+                    argv.as_ptr(),
+                    envp.as_ptr(),
+                );
+            }
+            libc::posix_spawnattr_destroy(&mut attr);
+            result
+        };
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+        Ok(LaunchedChild { pid: pid as u32 })
+    }
+}
+
+/// A process which was launched with `POSIX_SPAWN_START_SUSPENDED`. It doesn't
+/// run a single instruction, not even in dyld, until it is resumed.
+pub struct LaunchedChild {
+    pid: u32,
+}
+
+impl LaunchedChild {
+    pub fn id(&self) -> u32 {
+        self.pid
+    }
+
+    /// Gets the task of the suspended process, so that we can sample it from its
+    /// first instruction. This needs root. Without it, we only get the task once
+    /// the preload library sends it to us.
+    pub fn task(&self) -> Option<mach_port_t> {
+        let mut task = MACH_PORT_NULL;
+        let kr = unsafe { task_for_pid(mach_task_self(), self.pid as c_int, &mut task) };
+        if kr == KERN_SUCCESS {
+            Some(task)
+        } else {
+            None
+        }
+    }
+
+    pub fn resume(&self) {
+        unsafe {
+            libc::kill(self.pid as libc::pid_t, libc::SIGCONT);
+        }
+    }
+
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        let mut status = 0;
+        loop {
+            if unsafe { libc::waitpid(self.pid as libc::pid_t, &mut status, 0) } != -1 {
+                return Ok(ExitStatus::from_raw(status));
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
 }
 
 pub struct TaskAccepter {
@@ -80,11 +184,14 @@ impl TaskAccepter {
         // and SAMPLY_BOOTSTRAP_SERVER_NAME.
         let mut child_env: Vec<(OsString, OsString)> = std::env::vars_os().collect();
         let mut add_env = |name: &str, val: &OsStr| {
+            // posix_spawn takes the environment as is, so replace existing values.
+            let xpc_name = format!("__XPC_{name}");
+            child_env.retain(|(existing, _)| existing != name && existing != xpc_name.as_str());
             child_env.push((name.into(), val.to_owned()));
             // Also set the same variable with an `__XPC_` prefix, so that it gets applied
             // to services launched via XPC. XPC strips the prefix when setting these environment
             // variables on the launched process.
-            child_env.push((format!("__XPC_{name}").into(), val.to_owned()));
+            child_env.push((xpc_name.into(), val.to_owned()));
         };
         add_env("DYLD_INSERT_LIBRARIES", preload_lib_path.as_os_str());
         add_env("SAMPLY_BOOTSTRAP_SERVER_NAME", OsStr::new(&server_name));
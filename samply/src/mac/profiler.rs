@@ -1,4 +1,4 @@
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{unbounded, Sender};
 use serde_json::to_writer;
 
 use std::collections::hash_map::Entry;
@@ -7,17 +7,21 @@ use std::ffi::OsString;
 use std::fs::File;
 use std::io::BufWriter;
 use std::process::ExitStatus;
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 use super::error::SamplingError;
-use super::process_launcher::{MachError, ReceivedStuff, TaskAccepter};
+use super::process_launcher::{
+    LaunchedChild, MachError, ReceivedStuff, TaskAccepter, TaskLauncher,
+};
 use super::sampler::{JitdumpOrMarkerPath, Sampler, TaskInit};
 use super::time::get_monotonic_timestamp;
+use crate::iteration_report::write_iteration_report;
 use crate::server::{start_server_main, ServerProps};
 use crate::shared::recording_props::{ConversionProps, RecordingProps};
+use crate::shared::utils::WarmupIterations;
 
 pub fn start_profiling_pid(
     _pid: u32,
@@ -30,17 +34,61 @@ pub fn start_profiling_pid(
     std::process::exit(1)
 }
 
+/// Launches a root process. It starts out suspended; if we can get its task
+/// right away, we sample it from its first instruction. Otherwise it runs until
+/// our preload library sends us its task.
+fn launch_root_child(
+    task_launcher: &TaskLauncher,
+    task_sender: &Sender<TaskInit>,
+    pre_attached_path_senders: &Mutex<HashMap<u32, Sender<JitdumpOrMarkerPath>>>,
+    last_launch_time: &AtomicU64,
+) -> LaunchedChild {
+    let launch_time_mono = get_monotonic_timestamp();
+    let root_child = task_launcher.launch_child();
+    match root_child.task() {
+        Some(task) => {
+            let pid = root_child.id();
+            let (path_sender, path_receiver) = unbounded();
+            pre_attached_path_senders
+                .lock()
+                .unwrap()
+                .insert(pid, path_sender);
+            let send_result = task_sender.send(TaskInit {
+                start_time_mono: get_monotonic_timestamp(),
+                launch_time_mono: Some(launch_time_mono),
+                task,
+                pid,
+                path_receiver,
+            });
+            if send_result.is_err() {
+                // The sampler has already shut down.
+            }
+        }
+        None => last_launch_time.store(launch_time_mono, Ordering::SeqCst),
+    }
+    root_child.resume();
+    root_child
+}
+
 pub fn start_recording(
     command_name: OsString,
     command_args: &[OsString],
     iteration_count: u32,
-    recording_props: RecordingProps,
+    mut recording_props: RecordingProps,
     conversion_props: ConversionProps,
     server_props: Option<ServerProps>,
 ) -> Result<ExitStatus, MachError> {
+    WarmupIterations::new(
+        &command_name,
+        command_args,
+        recording_props.warmup_iterations,
+    )
+    .run();
+
     let (task_sender, task_receiver) = unbounded();
     let command_name_copy = command_name.to_string_lossy().to_string();
     let output_file = recording_props.output_file.clone();
+    let iteration_report = recording_props.iteration_report.take();
     let sampler_thread = thread::spawn(move || {
         let sampler = Sampler::new(
             command_name_copy,
@@ -64,6 +112,17 @@ pub fn start_recording(
 
     let (mut task_accepter, task_launcher) = TaskAccepter::new(&command_name, command_args)?;
 
+    // The time at which we launched the most recent root process, or zero once
+    // that process has been accepted.
+    let last_launch_time = Arc::new(AtomicU64::new(0));
+    let last_launch_time_copy = last_launch_time.clone();
+
+    // The path senders of root processes whose task we got before they started
+    // running. Their preload library still checks in, but we already sample them.
+    let pre_attached_path_senders = Arc::new(Mutex::new(HashMap::new()));
+    let pre_attached_path_senders_copy = pre_attached_path_senders.clone();
+    let root_task_sender = task_sender.clone();
+
     let (accepter_sender, accepter_receiver) = unbounded();
     let accepter_thread = thread::spawn(move || {
         // Loop while accepting messages from the spawned process tree.
@@ -81,9 +140,22 @@ pub fn start_recording(
             match task_accepter.next_message(timeout) {
                 Ok(ReceivedStuff::AcceptedTask(mut accepted_task)) => {
                     let pid = accepted_task.get_id();
+                    let pre_attached_path_sender =
+                        pre_attached_path_senders_copy.lock().unwrap().remove(&pid);
+                    if let Some(path_sender) = pre_attached_path_sender {
+                        path_senders_per_pid.insert(pid, path_sender);
+                        accepted_task.start_execution();
+                        continue;
+                    }
                     let (path_sender, path_receiver) = unbounded();
+                    // The launched process checks in before any of its children.
+                    let launch_time_mono = match last_launch_time_copy.swap(0, Ordering::SeqCst) {
+                        0 => None,
+                        launch_time_mono => Some(launch_time_mono),
+                    };
                     let send_result = task_sender.send(TaskInit {
                         start_time_mono: get_monotonic_timestamp(),
+                        launch_time_mono,
                         task: accepted_task.take_task(),
                         pid,
                         path_receiver,
@@ -139,7 +211,13 @@ pub fn start_recording(
         }
     });
 
-    let mut root_child = task_launcher.launch_child();
+    let mut root_child = launch_root_child(
+        &task_launcher,
+        &root_task_sender,
+        &pre_attached_path_senders,
+        &last_launch_time,
+    );
+    let mut launched_pids = vec![root_child.id()];
     let mut exit_status = root_child.wait().expect("couldn't wait for child");
 
     for i in 2..=iteration_count {
@@ -151,13 +229,22 @@ pub fn start_recording(
             break;
         }
         eprintln!("Running iteration {i} of {iteration_count}...");
-        let mut root_child = task_launcher.launch_child();
+        let mut root_child = launch_root_child(
+            &task_launcher,
+            &root_task_sender,
+            &pre_attached_path_senders,
+            &last_launch_time,
+        );
+        launched_pids.push(root_child.id());
         exit_status = root_child.wait().expect("couldn't wait for child");
     }
 
     // The launched subprocess is done. From now on, we want to terminate if the user presses Ctrl+C.
     should_terminate_on_ctrl_c.store(true, std::sync::atomic::Ordering::SeqCst);
 
+    // The sampler stops waiting for a root task once all task senders are gone.
+    drop(root_task_sender);
+
     accepter_sender
         .send(())
         .expect("couldn't tell accepter thread to stop");
@@ -189,6 +276,12 @@ pub fn start_recording(
     let writer = BufWriter::new(file);
     to_writer(writer, &profile).expect("Couldn't write JSON");
 
+    if let Some(report_props) = &iteration_report {
+        if let Err(err) = write_iteration_report(&output_file, report_props, &launched_pids) {
+            eprintln!("Could not create the iteration report: {err}");
+        }
+    }
+
     if let Some(server_props) = server_props {
         start_server_main(&output_file, server_props);
     }
@@ -2,6 +2,7 @@ use crossbeam_channel::Receiver;
 use fxprof_processed_profile::{CategoryColor, CategoryPairHandle, Profile, ReferenceTimestamp};
 use mach::port::mach_port_t;
 
+use std::collections::HashMap;
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -11,10 +12,13 @@ use std::time::SystemTime;
 
 use crate::shared::recording_props::{ConversionProps, RecordingProps};
 use crate::shared::recycling::ProcessRecycler;
+use crate::shared::stack_quality::StackQualityStats;
 use crate::shared::timestamp_converter::TimestampConverter;
+use crate::shared::types::FastHashSet;
 use crate::shared::unresolved_samples::UnresolvedStacks;
 
 use super::error::SamplingError;
+use super::file_activity::FileActivityTracker;
 use super::task_profiler::TaskProfiler;
 use super::time::get_monotonic_timestamp;
 
@@ -26,11 +30,95 @@ pub enum JitdumpOrMarkerPath {
 #[derive(Debug, Clone)]
 pub struct TaskInit {
     pub start_time_mono: u64,
+    /// When we launched this process, if it's the one we launched. Unless we got
+    /// its task while it was still suspended, the process runs until our preload
+    /// library has sent us its task, which happens after dyld has loaded the
+    /// libraries and before their initializers run.
+    pub launch_time_mono: Option<u64>,
     pub task: mach_port_t,
     pub pid: u32,
     pub path_receiver: Receiver<JitdumpOrMarkerPath>,
 }
 
+/// Measures the cost of sampling, so that it can be reported in the profile.
+#[derive(Debug, Default)]
+struct SamplerOverhead {
+    tick_count: u64,
+    total_tick_ns: u64,
+    max_tick_ns: u64,
+    total_suspended_ns: u64,
+}
+
+impl SamplerOverhead {
+    fn add_tick(&mut self, tick_ns: u64, suspended_ns: u64) {
+        self.tick_count += 1;
+        self.total_tick_ns += tick_ns;
+        self.max_tick_ns = self.max_tick_ns.max(tick_ns);
+        self.total_suspended_ns += suspended_ns;
+    }
+
+    /// Adds the measurements to the profile's meta information, and warns if
+    /// sampling took up a large part of the sampling interval.
+    fn report(&self, profile: &mut Profile, interval: Duration) {
+        if self.tick_count == 0 {
+            return;
+        }
+        let average_tick_ns = self.total_tick_ns / self.tick_count;
+        let average_suspended_ns = self.total_suspended_ns / self.tick_count;
+        profile.add_extra_info(
+            "Profiler",
+            "Sampling time per tick",
+            &format!(
+                "{:.1} µs on average, {:.1} µs at most",
+                average_tick_ns as f64 / 1000.0,
+                self.max_tick_ns as f64 / 1000.0
+            ),
+        );
+        profile.add_extra_info(
+            "Profiler",
+            "Threads suspended per tick",
+            &format!("{:.1} µs on average", average_suspended_ns as f64 / 1000.0),
+        );
+
+        let interval_ns = interval.as_nanos() as u64;
+        if interval_ns > 0 && average_tick_ns * 4 > interval_ns {
+            eprintln!(
+                "Sampling took {:.0}% of the sampling interval on average, which slows down the profiled process noticeably. Consider a lower sampling rate.",
+                average_tick_ns as f64 * 100.0 / interval_ns as f64
+            );
+        }
+    }
+}
+
+/// The attach latencies of the processes we launched, one per iteration.
+#[derive(Debug, Default)]
+struct AttachLatencies(Vec<u64>);
+
+impl AttachLatencies {
+    fn add_task(&mut self, task: &TaskProfiler) {
+        self.0.extend(task.attach_latency_ns());
+    }
+
+    /// Adds a single "Attach latency" entry to the profile's meta information.
+    fn report(&self, profile: &mut Profile) {
+        let Some(max_ns) = self.0.iter().copied().max() else {
+            return;
+        };
+        let value = if self.0.len() == 1 {
+            format!("{:.1} ms", max_ns as f64 / 1_000_000.0)
+        } else {
+            let average_ns = self.0.iter().sum::<u64>() / self.0.len() as u64;
+            format!(
+                "{:.1} ms on average, {:.1} ms at most, over {} launches",
+                average_ns as f64 / 1_000_000.0,
+                max_ns as f64 / 1_000_000.0,
+                self.0.len()
+            )
+        };
+        profile.add_extra_info("Profiler", "Attach latency", &value);
+    }
+}
+
 pub struct Sampler {
     command_name: String,
     task_receiver: Receiver<TaskInit>,
@@ -100,11 +188,16 @@ impl Sampler {
             timestamp_converter,
             &self.command_name,
             &mut profile,
+            &mut jit_category_manager,
             process_recycler.as_mut(),
             self.recording_props.clone(),
             self.conversion_props.clone(),
         )
         .expect("couldn't create root TaskProfiler");
+        let mut attach_latencies = AttachLatencies::default();
+        attach_latencies.add_task(&root_task);
+        let mut process_handles_by_pid = HashMap::new();
+        process_handles_by_pid.insert(root_task.pid(), root_task.process_handle());
 
         let mut process_sample_datas = Vec::new();
         let mut stack_scratch_buffer = Vec::new();
@@ -112,6 +205,20 @@ impl Sampler {
         let mut unwinder_cache = Default::default();
         let mut unresolved_stacks = UnresolvedStacks::default();
         let mut last_sleep_overshoot = 0;
+        let mut overhead = SamplerOverhead::default();
+
+        let mut file_activity_tracker = if self.recording_props.file_io {
+            match FileActivityTracker::start() {
+                Ok(tracker) => Some(tracker),
+                Err(err) => {
+                    eprintln!("Could not start file I/O tracing: {err}");
+                    eprintln!("Recording file I/O requires running samply as root. Continuing without file I/O markers.");
+                    None
+                }
+            }
+        } else {
+            None
+        };
 
         loop {
             loop {
@@ -131,10 +238,21 @@ impl Sampler {
                     timestamp_converter,
                     &self.command_name,
                     &mut profile,
+                    &mut jit_category_manager,
                     process_recycler.as_mut(),
                     self.recording_props.clone(),
                     self.conversion_props.clone(),
                 ) {
+                    attach_latencies.add_task(&new_task);
+                    // Show the process under the process which launched it, so that
+                    // the descendants of each launched command can be told apart.
+                    if let Some(parent_process) = new_task
+                        .parent_pid()
+                        .and_then(|parent_pid| process_handles_by_pid.get(&parent_pid))
+                    {
+                        profile.set_process_parent(new_task.process_handle(), *parent_process);
+                    }
+                    process_handles_by_pid.insert(new_task.pid(), new_task.process_handle());
                     live_tasks.push(new_task);
                 } else {
                     // The task is probably already dead again. We get here for tasks which are
@@ -156,6 +274,7 @@ impl Sampler {
             }
 
             let sample_timestamp = timestamp_converter.convert_time(sample_mono);
+            let mut suspended_ns = 0;
 
             let mut tasks = Vec::with_capacity(live_tasks.capacity());
             mem::swap(&mut live_tasks, &mut tasks);
@@ -167,9 +286,11 @@ impl Sampler {
                     sample_mono,
                     &mut unwinder_cache,
                     &mut profile,
+                    &mut jit_category_manager,
                     &mut stack_scratch_buffer,
                     &mut unresolved_stacks,
                 )?;
+                suspended_ns += task.take_suspended_ns();
                 if still_alive {
                     live_tasks.push(task);
                 } else {
@@ -187,6 +308,15 @@ impl Sampler {
                 }
             }
 
+            if let Some(tracker) = file_activity_tracker.as_mut() {
+                for task in &live_tasks {
+                    tracker.add_threads(task.pid(), task.live_thread_handles());
+                }
+                tracker.process_events(&mut profile, &timestamp_converter);
+            }
+
+            overhead.add_tick(get_monotonic_timestamp() - sample_mono, suspended_ns);
+
             let intended_wakeup_time =
                 sample_mono + self.recording_props.interval.as_nanos() as u64;
             let before_sleep = get_monotonic_timestamp();
@@ -197,6 +327,10 @@ impl Sampler {
             last_sleep_overshoot = actual_sleep_duration.saturating_sub(sleep_time);
         }
 
+        if let Some(tracker) = file_activity_tracker.as_mut() {
+            tracker.process_events(&mut profile, &timestamp_converter);
+        }
+
         // Gather the sample data from the remaining live tasks.
         // `live_tasks` can be non-empty if we stopped profiling before all tasks ended,
         // for example because the time limit was reached,
@@ -206,16 +340,27 @@ impl Sampler {
             process_sample_datas.push(process_sample_data);
         }
 
+        overhead.report(&mut profile, self.recording_props.interval);
+        attach_latencies.report(&mut profile);
+
         let mut stack_frame_scratch_buf = Vec::new();
+        let mut stack_quality = StackQualityStats::default();
         for process_sample_data in process_sample_datas {
-            process_sample_data.flush_samples_to_profile(
+            let process_stack_quality = process_sample_data.flush_samples_to_profile(
                 &mut profile,
                 default_category,
                 default_category,
                 &mut stack_frame_scratch_buf,
                 &unresolved_stacks,
                 &[],
+                self.conversion_props.frame_marker.as_deref(),
+                self.conversion_props.latency_markers.as_ref(),
+                &self.conversion_props.stack_stitching_rules,
             );
+            stack_quality.merge(process_stack_quality);
+        }
+        for warning in stack_quality.add_warnings(&mut profile, &FastHashSet::default()) {
+            eprintln!("Warning: {warning}");
         }
 
         Ok(profile)
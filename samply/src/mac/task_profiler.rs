@@ -4,7 +4,10 @@ use framehop::{
     Unwinder, UnwinderNative,
 };
 use fxprof_processed_profile::debugid::DebugId;
-use fxprof_processed_profile::{LibraryInfo, ProcessHandle, Profile, ThreadHandle, Timestamp};
+use fxprof_processed_profile::{
+    CategoryColor, CategoryHandle, CategoryPairHandle, LibraryInfo, MarkerTiming, ProcessHandle,
+    Profile, ThreadHandle, Timestamp,
+};
 use mach::mach_types::thread_act_port_array_t;
 use mach::mach_types::thread_act_t;
 use mach::message::mach_msg_type_number_t;
@@ -30,9 +33,12 @@ use crate::shared::jitdump_manager::JitDumpManager;
 use crate::shared::lib_mappings::{
     LibMappingAdd, LibMappingInfo, LibMappingOp, LibMappingOpQueue, LibMappingRemove,
 };
+use crate::shared::library_load_marker::{LibraryEvent, LibraryLoadMarker};
 use crate::shared::marker_file::get_markers;
 use crate::shared::perf_map::try_load_perf_map;
-use crate::shared::process_sample_data::{MarkerSpanOnThread, ProcessSampleData};
+use crate::shared::process_sample_data::{
+    MarkerSpanOnThread, ProcessMarkerData, ProcessSampleData,
+};
 use crate::shared::recording_props::{ConversionProps, RecordingProps};
 use crate::shared::recycling::{ProcessRecycler, ProcessRecyclingData, ThreadRecycler};
 use crate::shared::timestamp_converter::TimestampConverter;
@@ -41,10 +47,16 @@ use crate::shared::unresolved_samples::{UnresolvedSamples, UnresolvedStacks};
 use super::error::SamplingError;
 use super::kernel_error::{IntoResult, KernelError};
 use super::proc_maps::{
-    DyldInfo, DyldInfoManager, Modification, ModuleSvmaInfo, StackwalkerRef, VmSubData,
+    DyldInfo, DyldInfoManager, Modification, ModuleSvmaInfo, StackwalkerRef, SuspendedTask,
+    VmSubData,
 };
 use super::sampler::{JitdumpOrMarkerPath, TaskInit};
-use super::thread_profiler::{get_thread_id, get_thread_name, ThreadProfiler};
+use super::thread_profiler::{get_thread_id, get_thread_name, ThreadActivity, ThreadProfiler};
+use super::time::get_monotonic_timestamp;
+
+/// If at least this many threads of a task need their stacks walked in a tick,
+/// the whole task is suspended once instead of suspending each thread.
+const SUSPEND_TASK_THREAD_COUNT: usize = 4;
 
 #[derive(Debug)]
 pub enum UnwindSectionBytes {
@@ -99,13 +111,26 @@ pub struct TaskProfiler {
     live_threads: HashMap<thread_act_t, ThreadProfiler>,
     lib_info_manager: DyldInfoManager,
     executable_name: String,
+    is_translated: bool,
+    rosetta_category: Option<CategoryPairHandle>,
     profile_process: ProcessHandle,
     main_thread_handle: ThreadHandle,
     ignored_errors: Vec<SamplingError>,
+    /// The thread list from the most recent tick. The buffer is reused.
+    thread_acts: Vec<thread_act_t>,
+    /// The threads which are sampled in the current tick. The buffer is reused.
+    sampled_threads: Vec<(thread_act_t, ThreadActivity)>,
+    /// How long the task's threads were kept suspended for stack walks, since
+    /// the last call to `take_suspended_ns`.
+    suspended_ns: u64,
     unwinder: UnwinderNative<UnwindSectionBytes, MayAllocateDuringUnwind>,
     path_receiver: Receiver<JitdumpOrMarkerPath>,
     jitdump_manager: JitDumpManager,
     marker_file_paths: Vec<(ThreadHandle, PathBuf)>,
+    /// The time between our launch of the process and the start of sampling.
+    attach_span: Option<MarkerSpanOnThread>,
+    attach_latency_ns: Option<u64>,
+    parent_pid: Option<u32>,
     unresolved_samples: UnresolvedSamples,
     lib_mapping_ops: LibMappingOpQueue,
     thread_recycler: Option<ThreadRecycler>,
@@ -116,17 +141,20 @@ pub struct TaskProfiler {
 }
 
 impl TaskProfiler {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         task_init: TaskInit,
         timestamp_converter: TimestampConverter,
         command_name: &str,
         profile: &mut Profile,
+        jit_category_manager: &mut JitCategoryManager,
         mut process_recycler: Option<&mut ProcessRecycler>,
         recording_props: Arc<RecordingProps>,
         conversion_props: Arc<ConversionProps>,
     ) -> Result<Self, SamplingError> {
         let TaskInit {
             start_time_mono,
+            launch_time_mono,
             task,
             pid,
             path_receiver,
@@ -147,7 +175,16 @@ impl TaskProfiler {
             })
             .unwrap_or_else(|| command_name.to_string());
 
-        let thread_acts = get_thread_list(task, recording_props.main_thread_only)?;
+        let is_translated = is_process_translated(pid);
+        if is_translated {
+            eprintln!(
+                "Warning: Process \"{executable_name}\" [pid: {pid}] is running under Rosetta 2. \
+                 Stacks in translated code cannot be unwound and will be truncated."
+            );
+        }
+
+        let mut thread_acts = Vec::new();
+        get_thread_list(task, recording_props.main_thread_only, &mut thread_acts)?;
         if thread_acts.is_empty() {
             return Err(SamplingError::Ignorable(
                 "No threads",
@@ -183,7 +220,12 @@ impl TaskProfiler {
                     Some(jit_function_recycler),
                 ),
                 None => {
-                    let profile_process = profile.add_process(&executable_name, pid, start_time);
+                    let process_name = if is_translated {
+                        format!("{executable_name} (Rosetta)")
+                    } else {
+                        executable_name.clone()
+                    };
+                    let profile_process = profile.add_process(&process_name, pid, start_time);
                     let main_thread_handle =
                         profile.add_thread(profile_process, main_thread_tid, start_time, true);
                     if let Some(main_thread_name) = &main_thread_name {
@@ -243,19 +285,39 @@ impl TaskProfiler {
             }
         }
 
+        // Unless we got the task while the process was still suspended, sampling
+        // only starts once the preload library has sent us the task, so the work
+        // dyld does at startup is missing from the profile.
+        let attach_latency_ns = launch_time_mono
+            .map(|launch_time_mono| start_time_mono.saturating_sub(launch_time_mono));
+        let attach_span = launch_time_mono.map(|launch_time_mono| MarkerSpanOnThread {
+            thread_handle: main_thread_handle,
+            start_time: timestamp_converter.convert_time(launch_time_mono),
+            end_time: start_time,
+            name: "Waiting for the profiler".to_string(),
+        });
+
         let mut task_profiler = TaskProfiler {
             task,
             pid,
             live_threads,
             lib_info_manager,
             executable_name,
+            is_translated,
+            rosetta_category: None,
             profile_process,
             main_thread_handle,
             ignored_errors: Vec::new(),
+            thread_acts: Vec::new(),
+            sampled_threads: Vec::new(),
+            suspended_ns: 0,
             unwinder: UnwinderNative::new(),
             path_receiver,
             jitdump_manager: JitDumpManager::new(),
             marker_file_paths: Vec::new(),
+            attach_span,
+            attach_latency_ns,
+            parent_pid: get_parent_pid(pid),
             lib_mapping_ops: Default::default(),
             unresolved_samples: Default::default(),
             thread_recycler,
@@ -265,17 +327,27 @@ impl TaskProfiler {
             conversion_props,
         };
 
-        task_profiler.process_lib_modifications(start_time_mono, initial_lib_mods, profile);
+        // The libraries which were already loaded when profiling started
+        // don't get load markers.
+        task_profiler.process_lib_modifications(
+            start_time_mono,
+            initial_lib_mods,
+            false,
+            profile,
+            jit_category_manager,
+        );
 
         Ok(task_profiler)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn sample(
         &mut self,
         now: Timestamp,
         now_mono: u64,
         unwinder_cache: &mut UnwinderCache,
         profile: &mut Profile,
+        jit_category_manager: &mut JitCategoryManager,
         stack_scratch_buffer: &mut Vec<FrameAddress>,
         unresolved_stacks: &mut UnresolvedStacks,
     ) -> Result<bool, SamplingError> {
@@ -284,6 +356,7 @@ impl TaskProfiler {
             now_mono,
             unwinder_cache,
             profile,
+            jit_category_manager,
             stack_scratch_buffer,
             unresolved_stacks,
         );
@@ -308,25 +381,35 @@ impl TaskProfiler {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn sample_impl(
         &mut self,
         now: Timestamp,
         now_mono: u64,
         unwinder_cache: &mut UnwinderCache,
         profile: &mut Profile,
+        jit_category_manager: &mut JitCategoryManager,
         stack_scratch_buffer: &mut Vec<FrameAddress>,
         unresolved_stacks: &mut UnresolvedStacks,
     ) -> Result<(), SamplingError> {
         // First, check for any newly-loaded libraries.
         if let Ok(changes) = self.lib_info_manager.check_for_changes() {
-            self.process_lib_modifications(now_mono, changes, profile);
+            self.process_lib_modifications(now_mono, changes, true, profile, jit_category_manager);
         }
 
         // Enumerate threads.
-        let thread_acts = get_thread_list(self.task, self.recording_props.main_thread_only)?;
+        get_thread_list(
+            self.task,
+            self.recording_props.main_thread_only,
+            &mut self.thread_acts,
+        )?;
         let previously_live_threads: HashSet<_> = self.live_threads.keys().cloned().collect();
         let mut now_live_threads = HashSet::new();
-        for thread_act in thread_acts {
+
+        // First, find out which threads have used CPU time since the previous
+        // tick. Only those need their stacks walked.
+        self.sampled_threads.clear();
+        for &thread_act in &self.thread_acts {
             let mut entry = self.live_threads.entry(thread_act);
             let thread = match entry {
                 Entry::Occupied(ref mut entry) => entry.get_mut(),
@@ -361,10 +444,43 @@ impl TaskProfiler {
                     }
                 }
             };
-            // Grab a sample from the thread.
-            let stackwalker = StackwalkerRef::new(&self.unwinder, unwinder_cache);
             thread.check_thread_name(profile, self.thread_recycler.as_mut());
+            match thread.begin_sample()? {
+                ThreadActivity::Terminated => {}
+                activity => self.sampled_threads.push((thread_act, activity)),
+            }
+        }
+
+        // Suspending a running thread means interrupting it and waiting for it
+        // to stop. If several threads are running, suspending the whole task
+        // once is cheaper than doing this for each of them.
+        let running_thread_count = self
+            .sampled_threads
+            .iter()
+            .filter(|(_, activity)| *activity == ThreadActivity::Running)
+            .count();
+        let walk_start = get_monotonic_timestamp();
+        let suspended_task = if running_thread_count >= SUSPEND_TASK_THREAD_COUNT {
+            Some(SuspendedTask::new(self.task).map_err(|err| match err {
+                KernelError::InvalidArgument
+                | KernelError::MachSendInvalidDest
+                | KernelError::Terminated => {
+                    SamplingError::ProcessTerminated("task_suspend in sample_impl", err)
+                }
+                err => SamplingError::Ignorable("task_suspend in sample_impl", err),
+            })?)
+        } else {
+            None
+        };
+
+        // Grab a sample from each thread.
+        for &(thread_act, activity) in &self.sampled_threads {
+            let Some(thread) = self.live_threads.get_mut(&thread_act) else {
+                continue;
+            };
+            let stackwalker = StackwalkerRef::new(&self.unwinder, unwinder_cache);
             let still_alive = thread.sample(
+                activity,
                 stackwalker,
                 now,
                 now_mono,
@@ -372,11 +488,17 @@ impl TaskProfiler {
                 unresolved_stacks,
                 &mut self.unresolved_samples,
                 self.conversion_props.fold_recursive_prefix,
+                suspended_task.is_some(),
             )?;
             if still_alive {
                 now_live_threads.insert(thread_act);
             }
         }
+        drop(suspended_task);
+        if running_thread_count > 0 {
+            self.suspended_ns += get_monotonic_timestamp() - walk_start;
+        }
+
         let dead_threads = previously_live_threads.difference(&now_live_threads);
         for thread_act in dead_threads {
             let mut thread = self.live_threads.remove(thread_act).unwrap();
@@ -395,9 +517,16 @@ impl TaskProfiler {
         &mut self,
         now_mono: u64,
         changes: Vec<Modification<DyldInfo>>,
+        add_markers: bool,
         profile: &mut Profile,
+        jit_category_manager: &mut JitCategoryManager,
     ) {
+        let now = self.timestamp_converter.convert_time(now_mono);
         for change in changes {
+            if add_markers {
+                self.add_lib_load_marker(&change, now, profile);
+            }
+
             match change {
                 Modification::Added(mut lib) => {
                     self.add_lib_to_unwinder_and_ensure_debug_id(&mut lib);
@@ -416,13 +545,23 @@ impl TaskProfiler {
                             arch: lib.arch.map(ToOwned::to_owned),
                             symbol_table: None,
                         });
+                        let category = if is_rosetta_runtime_path(&path) {
+                            Some(*self.rosetta_category.get_or_insert_with(|| {
+                                profile.add_category("Rosetta", CategoryColor::Gray).into()
+                            }))
+                        } else {
+                            jit_category_manager.classify_runtime_lib(&name, profile)
+                        };
                         self.lib_mapping_ops.push(
                             now_mono,
                             LibMappingOp::Add(LibMappingAdd {
                                 start_avma: lib.base_avma,
                                 end_avma: lib.base_avma + lib.vmsize,
                                 relative_address_at_start: 0,
-                                info: LibMappingInfo::new_lib(lib_handle),
+                                info: LibMappingInfo {
+                                    category,
+                                    ..LibMappingInfo::new_lib(lib_handle)
+                                },
                             }),
                         );
                     }
@@ -440,6 +579,37 @@ impl TaskProfiler {
         }
     }
 
+    fn add_lib_load_marker(
+        &self,
+        change: &Modification<DyldInfo>,
+        now: Timestamp,
+        profile: &mut Profile,
+    ) {
+        let (event, lib) = match change {
+            Modification::Added(lib) => (LibraryEvent::Load, lib),
+            Modification::Removed(lib) => (LibraryEvent::Unload, lib),
+        };
+        let path = Path::new(&lib.file);
+        let marker = LibraryLoadMarker {
+            event,
+            name: path
+                .file_name()
+                .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+            path: lib.file.clone(),
+            base_address: lib.base_avma,
+            build_id: lib.code_id.as_ref().map(ToString::to_string),
+            file_version: None,
+            product_version: None,
+        };
+        profile.add_marker(
+            self.main_thread_handle,
+            CategoryHandle::OTHER,
+            marker.marker_name(),
+            marker,
+            MarkerTiming::Instant(now),
+        );
+    }
+
     fn add_lib_to_unwinder_and_ensure_debug_id(&mut self, lib: &mut DyldInfo) {
         let ModuleSvmaInfo {
             base_svma,
@@ -485,6 +655,15 @@ impl TaskProfiler {
             }
         }
 
+        if self.is_translated && matches!(lib.arch, Some("x86_64" | "x86_64h")) {
+            // The thread state we get for a translated process is the state of the
+            // arm64 code that Rosetta generated, not the emulated x86_64 register
+            // state. Feeding x86_64 unwind info to the arm64 unwinder only produces
+            // garbage frames, so leave these modules out of the unwinder. They still
+            // get added to the profile so that addresses resolve to x86_64 symbols.
+            return;
+        }
+
         let unwind_info = unwind_info_data.map(UnwindSectionBytes::Remapped);
         let eh_frame = eh_frame_data.map(UnwindSectionBytes::Remapped);
         let text_segment = text_segment_data.map(UnwindSectionBytes::Remapped);
@@ -520,6 +699,39 @@ impl TaskProfiler {
         self.unwinder.add_module(module);
     }
 
+    /// Returns how long the task's threads were kept suspended for stack walks
+    /// since the previous call.
+    pub fn take_suspended_ns(&mut self) -> u64 {
+        mem::take(&mut self.suspended_ns)
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn process_handle(&self) -> ProcessHandle {
+        self.profile_process
+    }
+
+    /// The pid of the process which launched this one, if it was still running
+    /// when we started sampling this process.
+    pub fn parent_pid(&self) -> Option<u32> {
+        self.parent_pid
+    }
+
+    /// The time between our launch of the process and the start of sampling,
+    /// if this is a process we launched.
+    pub fn attach_latency_ns(&self) -> Option<u64> {
+        self.attach_latency_ns
+    }
+
+    /// The (tid, thread handle) pairs of the threads which are currently alive.
+    pub fn live_thread_handles(&self) -> impl Iterator<Item = (u32, ThreadHandle)> + '_ {
+        self.live_threads
+            .values()
+            .map(|thread| (thread.tid(), thread.profile_thread()))
+    }
+
     pub fn check_received_paths(&mut self) {
         while let Ok(jitdump_or_marker_file_path) = self.path_receiver.try_recv() {
             match jitdump_or_marker_file_path {
@@ -580,6 +792,7 @@ impl TaskProfiler {
     ) -> (ProcessSampleData, Option<(String, ProcessRecyclingData)>) {
         let perf_map_mappings = if !self.unresolved_samples.is_empty() {
             try_load_perf_map(self.pid, profile, jit_category_manager, None)
+                .map(|perf_map| perf_map.mappings)
         } else {
             None
         };
@@ -589,27 +802,20 @@ impl TaskProfiler {
             self.jit_function_recycler.as_mut(),
             &self.timestamp_converter,
         );
-        let mut marker_spans = Vec::new();
+        let mut markers = ProcessMarkerData::default();
+        markers.marker_spans.extend(self.attach_span);
         for (thread_handle, marker_file_path) in self.marker_file_paths {
-            if let Ok(marker_spans_from_this_file) =
-                get_markers(&marker_file_path, None, self.timestamp_converter)
-            {
-                marker_spans.extend(marker_spans_from_this_file.into_iter().map(|span| {
-                    MarkerSpanOnThread {
-                        thread_handle,
-                        start_time: span.start_time,
-                        end_time: span.end_time,
-                        name: span.name,
-                    }
-                }));
+            if let Ok(contents) = get_markers(&marker_file_path, None, self.timestamp_converter) {
+                markers.add_marker_file_contents(thread_handle, contents);
             }
         }
         let process_sample_data = ProcessSampleData::new(
+            self.profile_process,
             self.unresolved_samples,
             self.lib_mapping_ops,
             jitdump_lib_ops,
             perf_map_mappings,
-            marker_spans,
+            markers,
         );
 
         let recycling_data = if let (Some(mut jit_function_recycler), Some(thread_recycler)) =
@@ -686,10 +892,12 @@ fn get_debug_frame(file_path: &str) -> Option<UnwindSectionBytes> {
     }
 }
 
+/// Replaces the contents of `thread_acts` with the task's threads.
 fn get_thread_list(
     task: mach_port_t,
     main_thread_only: bool,
-) -> Result<Vec<thread_act_t>, SamplingError> {
+    thread_acts: &mut Vec<thread_act_t>,
+) -> Result<(), SamplingError> {
     let mut thread_list: thread_act_port_array_t = std::ptr::null_mut();
     let mut thread_count: mach_msg_type_number_t = Default::default();
     unsafe { task_threads(task, &mut thread_list, &mut thread_count) }
@@ -703,8 +911,10 @@ fn get_thread_list(
             err => SamplingError::Ignorable("task_threads in get_thread_list", err),
         })?;
 
-    let mut thread_acts =
-        unsafe { std::slice::from_raw_parts(thread_list, thread_count as usize) }.to_owned();
+    thread_acts.clear();
+    thread_acts.extend_from_slice(unsafe {
+        std::slice::from_raw_parts(thread_list, thread_count as usize)
+    });
 
     unsafe {
         mach_vm_deallocate(
@@ -721,7 +931,68 @@ fn get_thread_list(
         thread_acts.truncate(1);
     }
 
-    Ok(thread_acts)
+    Ok(())
+}
+
+/// The `P_TRANSLATED` flag from `<sys/proc.h>`, set on processes running under Rosetta 2.
+const P_TRANSLATED: i32 = 0x0002_0000;
+
+/// The offset of `kp_proc.p_flag` in `struct kinfo_proc`. The libc crate doesn't
+/// expose `kinfo_proc` for Apple targets, and we only need this one field.
+const KINFO_PROC_P_FLAG_OFFSET: usize = 32;
+
+/// Returns whether the process is an x86_64 process which is being translated
+/// by Rosetta 2. Always false on Intel Macs.
+/// Returns the pid of the parent process of `pid`, if it's still running.
+fn get_parent_pid(pid: u32) -> Option<u32> {
+    let mut info: libc::proc_bsdinfo = unsafe { mem::zeroed() };
+    let size = mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+    let ret = unsafe {
+        libc::proc_pidinfo(
+            pid as libc::c_int,
+            libc::PROC_PIDTBSDINFO,
+            0,
+            &mut info as *mut libc::proc_bsdinfo as *mut libc::c_void,
+            size,
+        )
+    };
+    // Orphaned processes are reparented to launchd, which has pid 1.
+    if ret != size || info.pbi_ppid <= 1 {
+        return None;
+    }
+    Some(info.pbi_ppid)
+}
+
+fn is_process_translated(pid: u32) -> bool {
+    let mut mib = [
+        libc::CTL_KERN,
+        libc::KERN_PROC,
+        libc::KERN_PROC_PID,
+        pid as libc::c_int,
+    ];
+    let mut buf = [0u8; 1024];
+    let mut size = buf.len();
+    let ret = unsafe {
+        libc::sysctl(
+            mib.as_mut_ptr(),
+            mib.len() as libc::c_uint,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            &mut size,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 || size < KINFO_PROC_P_FLAG_OFFSET + 4 {
+        return false;
+    }
+    let p_flag_bytes = buf[KINFO_PROC_P_FLAG_OFFSET..][..4].try_into().unwrap();
+    i32::from_ne_bytes(p_flag_bytes) & P_TRANSLATED != 0
+}
+
+/// Returns whether the path belongs to the Rosetta 2 runtime, which shows up as a
+/// regular library in translated processes.
+fn is_rosetta_runtime_path(path: &str) -> bool {
+    path.starts_with("/usr/libexec/rosetta/") || path.starts_with("/Library/Apple/usr/libexec/oah/")
 }
 
 fn compute_debug_id_from_text_section(
@@ -20,6 +20,22 @@ use super::thread_info::{
     THREAD_EXTENDED_INFO_COUNT, THREAD_IDENTIFIER_INFO, THREAD_IDENTIFIER_INFO_COUNT,
 };
 
+/// What [`ThreadProfiler::begin_sample`] found out about a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadActivity {
+    /// The thread has used CPU time since the previous sample, so its stack
+    /// needs to be walked.
+    Running,
+    /// The thread hasn't used any CPU time since the previous sample, so its
+    /// stack can't have changed.
+    Idle,
+    /// The CPU time couldn't be queried, because of an ignorable error. The
+    /// thread isn't sampled in this tick.
+    Unknown,
+    /// The thread has terminated.
+    Terminated,
+}
+
 pub struct ThreadProfiler {
     thread_act: thread_act_t,
     name: Option<String>,
@@ -27,7 +43,9 @@ pub struct ThreadProfiler {
     profile_thread: ThreadHandle,
     tick_count: usize,
     stack_memory: ForeignMemory,
+    has_walked_stack: bool,
     previous_sample_cpu_time_us: u64,
+    current_sample_cpu_time_us: u64,
     ignored_errors: Vec<SamplingError>,
 }
 
@@ -46,11 +64,21 @@ impl ThreadProfiler {
             profile_thread,
             tick_count: 0,
             stack_memory: ForeignMemory::new(task),
+            has_walked_stack: false,
             previous_sample_cpu_time_us: 0,
+            current_sample_cpu_time_us: 0,
             ignored_errors: Vec::new(),
         }
     }
 
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+
+    pub fn profile_thread(&self) -> ThreadHandle {
+        self.profile_thread
+    }
+
     /// Called before every call to `sample`.
     pub fn check_thread_name(
         &mut self,
@@ -71,9 +99,38 @@ impl ThreadProfiler {
         }
     }
 
+    /// The first step of taking a sample: queries the thread's CPU time, to
+    /// find out whether its stack needs to be walked. This lets the caller
+    /// suspend all running threads of a task at once, before calling `sample`
+    /// for each of them.
+    pub fn begin_sample(&mut self) -> Result<ThreadActivity, SamplingError> {
+        self.tick_count += 1;
+
+        match get_thread_cpu_time_since_thread_start(self.thread_act) {
+            Ok((user_time_us, system_time_us)) => {
+                self.current_sample_cpu_time_us = user_time_us + system_time_us;
+                if self.current_sample_cpu_time_us != self.previous_sample_cpu_time_us {
+                    Ok(ThreadActivity::Running)
+                } else {
+                    Ok(ThreadActivity::Idle)
+                }
+            }
+            Err(err) => {
+                if self.check_error(err)? {
+                    Ok(ThreadActivity::Unknown)
+                } else {
+                    Ok(ThreadActivity::Terminated)
+                }
+            }
+        }
+    }
+
+    /// Adds a sample for the activity returned by `begin_sample`. Returns false
+    /// if the thread has terminated.
     #[allow(clippy::too_many_arguments)]
     pub fn sample(
         &mut self,
+        activity: ThreadActivity,
         stackwalker: StackwalkerRef,
         now: Timestamp,
         now_mono: u64,
@@ -81,20 +138,63 @@ impl ThreadProfiler {
         unresolved_stacks: &mut UnresolvedStacks,
         unresolved_samples: &mut UnresolvedSamples,
         fold_recursive_prefix: bool,
+        thread_is_suspended: bool,
     ) -> Result<bool, SamplingError> {
-        let result = self.sample_impl(
-            stackwalker,
-            now,
-            now_mono,
-            stack_scratch_buffer,
-            unresolved_stacks,
-            unresolved_samples,
-            fold_recursive_prefix,
-        );
+        let result = match activity {
+            ThreadActivity::Running => self.sample_stack(
+                stackwalker,
+                now,
+                now_mono,
+                stack_scratch_buffer,
+                unresolved_stacks,
+                unresolved_samples,
+                fold_recursive_prefix,
+                thread_is_suspended,
+            ),
+            ThreadActivity::Idle => {
+                // No CPU time elapsed since just before the last time we grabbed a stack.
+                // Assume that the thread has done literally zero work and could not have changed
+                // its stack. This considerably reduces the overhead from sampling idle threads.
+                //
+                // More specifically, we hit this path after the following order of events
+                //  - sample n-1:
+                //     - query cpu time, call it A
+                //     - pause the thread
+                //     - walk the stack
+                //     - resume the thread
+                //  - sleep till next sample
+                //  - sample n:
+                //     - query cpu time, notice it is still the same as A
+                //     - add_sample_same_stack with stack from previous sample
+                //
+                unresolved_samples.add_sample_same_stack_zero_cpu(
+                    self.profile_thread,
+                    now,
+                    now_mono,
+                    1,
+                    None,
+                );
+                Ok(())
+            }
+            ThreadActivity::Unknown => return Ok(true),
+            ThreadActivity::Terminated => return Ok(false),
+        };
         match result {
-            Ok(()) => Ok(true),
-            Err(SamplingError::ThreadTerminated(_, _)) => Ok(false),
-            Err(err @ SamplingError::Ignorable(_, _)) => {
+            Ok(()) => {
+                self.previous_sample_cpu_time_us = self.current_sample_cpu_time_us;
+                Ok(true)
+            }
+            Err(err) => self.check_error(err),
+        }
+    }
+
+    /// Returns false if the error means that the thread has terminated, and true
+    /// if the error can be ignored for now, i.e. if the thread should be treated
+    /// as alive.
+    fn check_error(&mut self, err: SamplingError) -> Result<bool, SamplingError> {
+        match err {
+            SamplingError::ThreadTerminated(_, _) => Ok(false),
+            err @ SamplingError::Ignorable(_, _) => {
                 self.ignored_errors.push(err);
                 if self.ignored_errors.len() >= 10 {
                     eprintln!(
@@ -109,12 +209,12 @@ impl ThreadProfiler {
                     Ok(true)
                 }
             }
-            Err(err) => Err(err),
+            err => Err(err),
         }
     }
 
     #[allow(clippy::too_many_arguments)]
-    fn sample_impl(
+    fn sample_stack(
         &mut self,
         stackwalker: StackwalkerRef,
         now: Timestamp,
@@ -123,69 +223,41 @@ impl ThreadProfiler {
         unresolved_stacks: &mut UnresolvedStacks,
         unresolved_samples: &mut UnresolvedSamples,
         fold_recursive_prefix: bool,
+        thread_is_suspended: bool,
     ) -> Result<(), SamplingError> {
-        self.tick_count += 1;
-
-        let cpu_time_us = get_thread_cpu_time_since_thread_start(self.thread_act)?;
-        let cpu_time_us = cpu_time_us.0 + cpu_time_us.1;
-        let cpu_delta_us = cpu_time_us - self.previous_sample_cpu_time_us;
+        let cpu_delta_us = self.current_sample_cpu_time_us - self.previous_sample_cpu_time_us;
         let cpu_delta = CpuDelta::from_micros(cpu_delta_us);
 
-        if !cpu_delta.is_zero() || self.tick_count == 0 {
-            stack_scratch_buffer.clear();
-            get_backtrace(
-                stackwalker,
-                &mut self.stack_memory,
-                self.thread_act,
-                stack_scratch_buffer,
-                fold_recursive_prefix,
-            )?;
-
-            let frames = stack_scratch_buffer.iter().rev().map(|f| match f {
-                FrameAddress::InstructionPointer(address) => {
-                    StackFrame::InstructionPointer(*address, StackMode::User)
-                }
-                FrameAddress::ReturnAddress(address) => {
-                    StackFrame::ReturnAddress((*address).into(), StackMode::User)
-                }
-            });
-            let stack = unresolved_stacks.convert(frames);
-            unresolved_samples.add_sample(
-                self.profile_thread,
-                now,
-                now_mono,
-                stack,
-                cpu_delta,
-                1,
-                None,
-            );
-        } else {
-            // No CPU time elapsed since just before the last time we grabbed a stack.
-            // Assume that the thread has done literally zero work and could not have changed
-            // its stack. This considerably reduces the overhead from sampling idle threads.
-            //
-            // More specifically, we hit this path after the following order of events
-            //  - sample n-1:
-            //     - query cpu time, call it A
-            //     - pause the thread
-            //     - walk the stack
-            //     - resume the thread
-            //  - sleep till next sample
-            //  - sample n:
-            //     - query cpu time, notice it is still the same as A
-            //     - add_sample_same_stack with stack from previous sample
-            //
-            unresolved_samples.add_sample_same_stack_zero_cpu(
-                self.profile_thread,
-                now,
-                now_mono,
-                1,
-                None,
-            );
-        }
-
-        self.previous_sample_cpu_time_us = cpu_time_us;
+        stack_scratch_buffer.clear();
+        get_backtrace(
+            stackwalker,
+            &mut self.stack_memory,
+            self.thread_act,
+            stack_scratch_buffer,
+            fold_recursive_prefix,
+            thread_is_suspended,
+            !self.has_walked_stack,
+        )?;
+        self.has_walked_stack = true;
 
+        let frames = stack_scratch_buffer.iter().rev().map(|f| match f {
+            FrameAddress::InstructionPointer(address) => {
+                StackFrame::InstructionPointer(*address, StackMode::User)
+            }
+            FrameAddress::ReturnAddress(address) => {
+                StackFrame::ReturnAddress((*address).into(), StackMode::User)
+            }
+        });
+        let stack = unresolved_stacks.convert(frames);
+        unresolved_samples.add_sample(
+            self.profile_thread,
+            now,
+            now_mono,
+            stack,
+            cpu_delta,
+            1,
+            None,
+        );
         Ok(())
     }
 
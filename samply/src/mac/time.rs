@@ -4,6 +4,13 @@ use once_cell::sync::OnceCell;
 static NANOS_PER_TICK: OnceCell<mach_time::mach_timebase_info> = OnceCell::new();
 
 pub fn get_monotonic_timestamp() -> u64 {
+    let time = unsafe { mach_time::mach_absolute_time() };
+    mach_ticks_to_nanos(time)
+}
+
+/// Converts a `mach_absolute_time()` value, as found for example in kdebug
+/// event timestamps, into the nanosecond timebase used by `get_monotonic_timestamp()`.
+pub fn mach_ticks_to_nanos(ticks: u64) -> u64 {
     let nanos_per_tick = NANOS_PER_TICK.get_or_init(|| unsafe {
         let mut info = mach_time::mach_timebase_info::default();
         let errno = mach_time::mach_timebase_info(&mut info as *mut _);
@@ -14,7 +21,5 @@ pub fn get_monotonic_timestamp() -> u64 {
         info
     });
 
-    let time = unsafe { mach_time::mach_absolute_time() };
-
-    time * nanos_per_tick.numer as u64 / nanos_per_tick.denom as u64
+    ticks * nanos_per_tick.numer as u64 / nanos_per_tick.denom as u64
 }
@@ -1,33 +1,59 @@
-#[cfg(target_os = "macos")]
-mod mac;
-
-#[cfg(any(target_os = "android", target_os = "linux"))]
-mod linux;
-
-mod import;
-mod linux_shared;
-mod server;
-mod shared;
-
 use clap::{Args, Parser, Subcommand};
-use shared::recording_props::{ConversionProps, RecordingProps};
+use regex::Regex;
+use samply::import;
+use samply::import::core_dump::CoreDump;
+use samply::shared::recording_props::{
+    CodeAddressBits, ConversionProps, LatencyMarkerNames, OutputMarkerProps, RecordingProps,
+    Trigger,
+};
+use samply::shared::stack_stitching::StackStitchingRule;
 use tempfile::NamedTempFile;
 
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufReader, BufWriter, Read, Seek};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
-
-// To avoid warnings about unused declarations
-#[cfg(target_os = "macos")]
-pub use mac::{kernel_error, thread_act, thread_info};
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos",
+    target_os = "linux"
+))]
+use std::sync::{atomic::AtomicBool, Arc};
+use std::time::{Duration, SystemTime};
 
+#[cfg(target_os = "freebsd")]
+use samply::freebsd::profiler;
 #[cfg(any(target_os = "android", target_os = "linux"))]
-use linux::profiler;
+use samply::linux::profiler;
 #[cfg(target_os = "macos")]
-use mac::profiler;
+use samply::mac::profiler;
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos",
+    target_os = "linux"
+))]
+use samply::{capture_schedule, detach};
 
-use server::{start_server_main, PortSelection, ServerProps};
+use samply::annotate::{annotate_profile, AnnotateProps};
+use samply::bench::{bench_import, BenchImportProps};
+use samply::capture_schedule::{parse_interval, EXPORT_TOKEN_ENV_VAR};
+use samply::iteration_report::IterationReportProps;
+use samply::modules::list_profile_modules;
+use samply::profile_convert::{convert_profile, ConvertProps, ProfileFormat};
+use samply::profile_export::PyroscopeExporter;
+use samply::profile_json_preparse::{prepare_profile_for_load, validate_profile_file};
+use samply::profile_symbolication::symbolicate_profile_file;
+use samply::rustc_wrapper::{is_running_as_rustc_wrapper, run_rustc_wrapper};
+use samply::saved_profiles::{list_saved_profiles, print_saved_profiles, SavedProfile};
+use samply::server::{
+    start_api_server_main, start_server_main, PortSelection, ServerProps, SymbolDownloads,
+    SymbolIdMatching,
+};
+use samply::split_profiles::{merge_split_profiles, write_split_profiles};
+use samply::symbol_upload::{upload_symbols_for_profile, SymbolUploadProps, UploadTarget};
+use samply::view_hints::{UrlViewState, ViewHints};
 
 #[derive(Debug, Parser)]
 #[command(
@@ -36,22 +62,62 @@ use server::{start_server_main, PortSelection, ServerProps};
     about = r#"
 samply is a sampling CPU profiler.
 Run a command, record a CPU profile of its execution, and open the profiler UI.
-Recording is currently supported on Linux and macOS.
+Recording is currently supported on Linux, macOS and FreeBSD.
 On other platforms, samply can only load existing profiles.
 
 EXAMPLES:
     # Default usage:
     samply record ./yourcommand yourargs
 
-    # On Linux, you can also profile existing processes by pid:
-    samply record -p 12345 # Linux only
+    # On Linux and FreeBSD, you can also profile existing processes by pid:
+    samply record -p 12345 # Linux and FreeBSD only
 
     # Alternative usage: Save profile to file for later viewing, and then load it.
     samply record --save-only -o prof.json -- ./yourcommand yourargs
     samply load prof.json # Opens in the browser and supplies symbols
 
+    # Record in the background, and stop the recording later:
+    samply record --detach --profile-name nightly -o nightly.json -- ./server
+    samply stop nightly
+
+    # List the profiles saved in the current directory, and open one of them.
+    samply list
+    samply list --open 1
+
+    # Show the source lines with the most samples:
+    samply annotate prof.json --source-dir ~/code/yourproject
+    samply annotate prof.json --asm
+
+    # Only provide symbols, e.g. for a profile which was captured by Firefox.
+    # Enter the printed URL as the symbol server in the profiler UI.
+    samply serve --port 3000 --symbol-dir ~/code/yourproject/target
+
     # Import perf.data files from Linux perf:
     samply load perf.data
+
+    # Import aggregated stacks from DTrace, e.g. on FreeBSD or illumos:
+    dtrace -n 'profile-997 /pid == $target/ { @[ustack()] = count(); }' -c ./app -o out.stacks
+    samply load out.stacks
+
+    # Import stacks from btrace on OpenBSD:
+    btrace -e 'profile:hz:100 { @[ustack] = count(); }' > out.stacks
+    samply load out.stacks
+
+    # Import hwpmc logs from pmcstat on FreeBSD:
+    pmcstat -P unhalted-cycles -O out.pmclog ./app
+    samply load out.pmclog
+
+    # Import heap profiles from heaptrack or from Valgrind's massif tool:
+    samply load heaptrack.app.12345.zst
+    samply load massif.out.12345
+
+    # Convert a profile for other tools, e.g. perf.data to pprof or a pprof
+    # profile to speedscope:
+    samply convert perf.data -o cpu.pb.gz
+    samply convert cpu.pb.gz -o cpu.speedscope.json
+
+    # Check a profile which the profiler refuses to load:
+    samply validate profile.json
 "#
 )]
 struct Opt {
@@ -60,20 +126,123 @@ struct Opt {
 }
 
 #[derive(Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Action {
     /// Load a profile from a file and display it.
     Load(LoadArgs),
 
-    #[cfg(any(target_os = "android", target_os = "macos", target_os = "linux"))]
+    /// List the profiles which were saved with `samply record --save-only`.
+    List(ListArgs),
+
+    /// Work with the symbols for the libraries in a profile.
+    Symbols(SymbolsArgs),
+
+    /// List the libraries in a profile, with the IDs and file sizes which
+    /// identify the exact binaries and debug files.
+    Modules(ModulesArgs),
+
+    /// Show the source lines with the most samples in a profile.
+    Annotate(AnnotateArgs),
+
+    /// Convert a profile to another format, e.g. from perf.data to pprof or
+    /// from pprof to speedscope.
+    Convert(ConvertArgs),
+
+    /// Check that a profile JSON file has the structure which samply and the
+    /// profiler expect, and list the values which break it.
+    Validate(ValidateArgs),
+
+    /// Measure how fast samply is.
+    Bench(BenchArgs),
+
+    /// Run the local server without loading a profile.
+    ///
+    /// Only the symbolication, source and assembly APIs are served, and the
+    /// profiler UI isn't opened. The printed URL can be entered as the symbol server in
+    /// the profiler UI, for example for profiles which were captured with the
+    /// Firefox profiler itself. Counters for monitoring the server are
+    /// available at /metrics, in the Prometheus text format.
+    Serve(ServeArgs),
+
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "linux"
+    ))]
     /// Record a profile and display it.
     Record(RecordArgs),
+
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "linux"
+    ))]
+    /// Stop a recording which was started with `samply record --detach`, and
+    /// wait until its profile is saved.
+    Stop(StopArgs),
 }
 
 #[derive(Debug, Args)]
 struct LoadArgs {
-    /// Path to the file that should be loaded.
+    /// Path to the file that should be loaded. This can also be a directory of
+    /// per-process profiles, as written by `samply record --split-processes`.
     file: PathBuf,
 
+    /// Symbolicate the profile and write the symbolicated copy to this file,
+    /// instead of opening it in the profiler. This is useful for profiles which
+    /// were recorded on a different machine, together with --symbol-dir.
+    #[arg(long, value_name = "FILE")]
+    symbolicate_to: Option<PathBuf>,
+
+    /// Convert a perf.data file into one profile per process, and write them
+    /// to this directory together with a manifest.json which lists them,
+    /// instead of opening the profile. This makes it possible to hand out the
+    /// parts of a system-wide capture to the owners of the individual services.
+    #[arg(long, value_name = "DIR", conflicts_with = "symbolicate_to")]
+    split_by_process: Option<PathBuf>,
+
+    /// With --split-by-process, only write the processes with this pid or name.
+    /// Can be specified multiple times.
+    #[arg(
+        long = "process",
+        value_name = "PID_OR_NAME",
+        requires = "split_by_process"
+    )]
+    processes: Vec<String>,
+
+    /// What to do with the events in the file which samply doesn't interpret.
+    /// With "dump", their counts and hexdumps of a few of their payloads are
+    /// written to <FILE>.unknown-events.txt, which is useful to attach to a
+    /// feature request for the missing support.
+    #[arg(long, value_enum, value_name = "MODE", default_value = "ignore")]
+    unknown_events: UnknownEventsMode,
+
+    /// Store hints for the initial view in the profile, from a JSON file like
+    /// {"hiddenThreads": ["^kworker/"], "selectedThreads": ["^Renderer$"],
+    /// "view": "marker-chart", "markerSearch": "DOMEvent", "search": "malloc",
+    /// "invertCallStack": true, "range": {"start": 1200, "end": 1500},
+    /// "markerAnnotations": [{"marker": "DOMEvent", "chartLabel":
+    /// "{marker.data.eventType}", "searchable": ["eventType"]}]}
+    /// The thread patterns are regular expressions for thread or process names,
+    /// and the range is in milliseconds since the start of the profile. Marker
+    /// annotations change the labels and searchable fields of a marker type. The hints
+    /// are kept when the profile is written with --symbolicate-to, so that a team
+    /// can share the layout it uses for its profiles.
+    #[arg(long, value_name = "FILE")]
+    view_hints: Option<PathBuf>,
+
+    /// Add the state of a crash from this core dump of a recorded process, so
+    /// that the moments leading up to the crash and the crash itself are in one
+    /// profile. The process gets a "Core dump" thread with the stack of each of
+    /// its threads and a "Crash" marker at the time it exited, and the modules
+    /// of the core dump are listed in the profile's metadata. If the profile
+    /// doesn't have the process, it is added. This works with the files which
+    /// samply converts, such as perf.data files.
+    #[arg(long, value_name = "CORE", conflicts_with = "split_by_process")]
+    with_core: Option<PathBuf>,
+
     #[command(flatten)]
     conversion_args: ConversionArgs,
 
@@ -81,13 +250,229 @@ struct LoadArgs {
     server_args: ServerArgs,
 }
 
+#[derive(Debug, Args)]
+struct ListArgs {
+    /// The directory to look for saved profiles in.
+    #[arg(default_value = ".")]
+    dir: PathBuf,
+
+    /// Open the profile with this number from the list.
+    #[arg(long, value_name = "NUMBER")]
+    open: Option<usize>,
+
+    #[command(flatten)]
+    server_args: ServerArgs,
+}
+
+#[derive(Debug, Args)]
+struct SymbolsArgs {
+    #[command(subcommand)]
+    action: SymbolsAction,
+}
+
+#[derive(Debug, Subcommand)]
+enum SymbolsAction {
+    /// Create Breakpad symbol files for the libraries in a profile and upload
+    /// them to a symbol server, so that the profile can be symbolicated on
+    /// other machines.
+    Upload(SymbolsUploadArgs),
+}
+
+#[derive(Debug, Args)]
+struct SymbolsUploadArgs {
+    /// The profile whose libraries should be uploaded.
+    profile: PathBuf,
+
+    /// The upload URL of the symbol server, for example
+    /// https://symbols.mozilla.org/upload/.
+    #[arg(long, required_unless_present = "zip_output")]
+    url: Option<String>,
+
+    /// The authentication token for the symbol server.
+    #[arg(long, env = "SAMPLY_SYMBOLS_UPLOAD_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// The kind of symbol server.
+    #[arg(long, value_enum, default_value = "tecken")]
+    target: UploadTarget,
+
+    /// Also save the zip file with the symbol files to this path.
+    #[arg(long, value_name = "FILE")]
+    zip_output: Option<PathBuf>,
+
+    /// Look for binaries, debug files and breakpad symbol files in this
+    /// directory. Can be specified multiple times.
+    #[arg(long = "symbol-dir", value_name = "DIR")]
+    symbol_dirs: Vec<PathBuf>,
+
+    #[command(flatten)]
+    download_args: DownloadArgs,
+
+    /// Print debugging output.
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(Debug, Args)]
+struct ModulesArgs {
+    /// The profile whose libraries should be listed.
+    profile: PathBuf,
+
+    /// Print the list as JSON, with the same field names as the profile's lib list.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Args)]
+struct ValidateArgs {
+    /// The profile to check, in the processed or the Gecko format. It can be
+    /// gzipped.
+    profile: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct ServeArgs {
+    #[command(flatten)]
+    server_args: ServerArgs,
+}
+
+#[derive(Debug, Args)]
+struct BenchArgs {
+    #[command(subcommand)]
+    action: BenchAction,
+}
+
+#[derive(Debug, Subcommand)]
+enum BenchAction {
+    /// Import perf.data files several times, and print the conversion throughput
+    /// and peak memory usage for each of them.
+    Import(BenchImportArgs),
+}
+
+#[derive(Debug, Args)]
+struct BenchImportArgs {
+    /// The perf.data files to import. Directories are searched for files ending
+    /// in .data, for example fixtures/perf in the samply repository.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// How many times to import each file. The median time is reported.
+    #[arg(long, default_value = "5")]
+    iterations: u32,
+
+    /// Save the results to this file, for use with --baseline later.
+    #[arg(long, value_name = "FILE")]
+    save_baseline: Option<PathBuf>,
+
+    /// Compare the results to a file which was written with --save-baseline,
+    /// and exit with an error if any file got slower or needed more memory.
+    #[arg(long, value_name = "FILE")]
+    baseline: Option<PathBuf>,
+
+    /// How many percent slower, or bigger, a result may be than the baseline
+    /// before it counts as a regression.
+    #[arg(long, value_name = "PERCENT", default_value = "10")]
+    max_regression: f64,
+}
+
+#[derive(Debug, Args)]
+struct AnnotateArgs {
+    /// The profile to annotate. The profiled binaries, or their debug files,
+    /// need to be available on this machine.
+    profile: PathBuf,
+
+    /// Look for source files in this directory, for example the root of a
+    /// crate or of a source checkout. Can be specified multiple times.
+    #[arg(long = "source-dir", value_name = "DIR", default_value = ".")]
+    source_dirs: Vec<PathBuf>,
+
+    /// How many of the source files with the most samples to show.
+    #[arg(long, default_value = "5")]
+    files: usize,
+
+    /// Disassemble the functions with the most samples from the local binaries,
+    /// and show the number of samples for each instruction.
+    #[arg(long)]
+    asm: bool,
+
+    /// How many of the functions with the most samples to disassemble, with --asm.
+    #[arg(long, default_value = "5")]
+    functions: usize,
+
+    /// Write the annotated source files or functions to this HTML file, instead
+    /// of printing the sampled lines.
+    #[arg(long, value_name = "FILE")]
+    html: Option<PathBuf>,
+
+    /// Look for binaries, debug files and breakpad symbol files in this
+    /// directory. Can be specified multiple times.
+    #[arg(long = "symbol-dir", value_name = "DIR")]
+    symbol_dirs: Vec<PathBuf>,
+
+    #[command(flatten)]
+    download_args: DownloadArgs,
+
+    /// Print debugging output.
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(Debug, Args)]
+struct ConvertArgs {
+    /// The profile to convert. This can be a profile in the Firefox Profiler's
+    /// format, a pprof profile, a speedscope file, collapsed stacks, or any
+    /// other file which `samply load` imports, such as a perf.data file.
+    input: PathBuf,
+
+    /// The file to write.
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+
+    /// The format of the output file. By default, it's chosen by the file name:
+    /// .speedscope.json for speedscope, .json for the Firefox Profiler,
+    /// .pb.gz or .pprof for pprof, and .folded or .collapsed for collapsed
+    /// stacks.
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    to: Option<ProfileFormat>,
+
+    /// Look for binaries, debug files and breakpad symbol files in this
+    /// directory. Can be specified multiple times.
+    #[arg(long = "symbol-dir", value_name = "DIR")]
+    symbol_dirs: Vec<PathBuf>,
+
+    #[command(flatten)]
+    download_args: DownloadArgs,
+
+    /// Print debugging output.
+    #[arg(short, long)]
+    verbose: bool,
+
+    #[command(flatten)]
+    conversion_args: ConversionArgs,
+}
+
 #[allow(unused)]
 #[derive(Debug, Args)]
 struct RecordArgs {
-    /// Do not run a local server after recording.
-    #[arg(short, long)]
+    /// Do not run a local server after recording. A small metadata file is saved
+    /// next to the profile, so that it shows up in `samply list`.
+    #[arg(short, long, visible_alias = "view-later")]
     save_only: bool,
 
+    /// Record in the background, detached from the terminal, and return right
+    /// away. The recording ends when the command exits or when it's stopped with
+    /// `samply stop`, and the profile is saved as with --save-only. The output
+    /// of samply and of the command goes to a log file next to the pidfile.
+    #[arg(long)]
+    detach: bool,
+
+    /// With --detach, write the pid of the recording to this file, which can be
+    /// passed to `samply stop`. By default, the pidfile is named after
+    /// --profile-name, or after the command, and `samply stop` accepts that name.
+    /// It's then in a directory which only the current user can access.
+    #[arg(long, value_name = "FILE", requires = "detach")]
+    pidfile: Option<PathBuf>,
+
     /// Sampling rate, in Hz
     #[arg(short, long, default_value = "1000")]
     rate: f64,
@@ -100,15 +485,238 @@ struct RecordArgs {
     #[arg(short, long, default_value = "profile.json")]
     output: PathBuf,
 
+    /// Record again and again, starting a new recording at this interval, e.g.
+    /// 30m or 1h. Each recording lasts for --duration seconds and is saved in
+    /// --output-dir. This currently requires --pid, as there is no system-wide
+    /// recording. Ctrl+C ends the current recording and the schedule.
+    #[arg(
+        long,
+        value_name = "INTERVAL",
+        value_parser = parse_interval,
+        requires_all = ["pid", "duration", "output_dir"]
+    )]
+    every: Option<Duration>,
+
+    /// With --every, save the recordings in this directory, named after
+    /// --profile-name, or the pid, and the UTC time at which they started.
+    #[arg(
+        long,
+        value_name = "DIR",
+        requires = "every",
+        conflicts_with = "output"
+    )]
+    output_dir: Option<PathBuf>,
+
+    /// With --every, keep this many of the most recent recordings in
+    /// --output-dir, and delete older ones.
+    #[arg(long, value_name = "N", default_value = "24", value_parser = clap::value_parser!(u32).range(1..))]
+    keep_captures: u32,
+
+    /// With --every, also push each recording to this Grafana Pyroscope server,
+    /// e.g. http://localhost:4040, with its symbols. The recordings show up under
+    /// --profile-name, or the pid.
+    #[arg(long, value_name = "URL", requires = "every")]
+    export_url: Option<String>,
+
+    /// The authentication token for --export-url. Prefer setting it in the
+    /// SAMPLY_EXPORT_TOKEN environment variable, because other users of the
+    /// machine can see command-line arguments.
+    #[arg(long, env = "SAMPLY_EXPORT_TOKEN", hide_env_values = true)]
+    export_token: Option<String>,
+
+    /// With --export-url, only push this many of the stacks with the most
+    /// samples. The samples of the other stacks are added up in one stack.
+    #[arg(long, value_name = "N", default_value = "5000", value_parser = clap::value_parser!(u64).range(2..))]
+    export_max_stacks: u64,
+
     /// How many times to run the profiled command.
     #[arg(long, default_value = "1")]
     iteration_count: u32,
 
+    /// With --iteration-count, keep running the remaining iterations if one of
+    /// them exits with a non-zero status, and leave the failed iterations out of
+    /// the profile. The skipped iterations are listed in the profile.
+    /// This option is only respected on Linux.
+    #[arg(long)]
+    skip_failed_iterations: bool,
+
+    /// Run the command this many times without recording it before the recorded
+    /// iterations start, so that caches and JITs are warm.
+    #[arg(long, default_value = "0", value_name = "N")]
+    warmup_iterations: u32,
+
+    /// Record the warm-up iterations too, and group their processes under a
+    /// "warmup" process in the profile.
+    /// This option is only respected on Linux.
+    #[arg(long, requires = "warmup_iterations")]
+    record_warmup: bool,
+
+    /// After recording, print the mean and the standard deviation of the self
+    /// time of the hottest functions across the iterations, and flag the
+    /// functions whose self time varies a lot between iterations. Use with
+    /// --iteration-count.
+    #[arg(long)]
+    iteration_report: bool,
+
+    /// Write the iteration report as JSON to this file.
+    #[arg(long, value_name = "FILE")]
+    iteration_report_json: Option<PathBuf>,
+
     /// Reduce profiling overhead by only recording the main thread.
     /// This option is only respected on macOS.
     #[arg(long)]
     main_thread_only: bool,
 
+    /// Record file I/O syscalls as markers, with their paths and durations.
+    /// This option is only respected on macOS, and requires running samply as root.
+    #[arg(long)]
+    file_io: bool,
+
+    /// Record display refresh (vblank) events as markers, to compare frame
+    /// timing against the display's refresh signal.
+    /// This option is only respected on Linux, and usually requires root.
+    #[arg(long)]
+    vsync: bool,
+
+    /// Record the jobs which run on the GPU, as markers on one track per GPU
+    /// ring, and on the threads which submitted them. This uses the DRM
+    /// scheduler's tracepoints, so it works with drivers which use the DRM
+    /// scheduler, e.g. amdgpu, xe, panfrost and v3d. For i915, the kernel needs
+    /// to be built with CONFIG_DRM_I915_LOW_LEVEL_TRACEPOINTS.
+    /// This option is only respected on Linux, and usually requires root.
+    #[arg(long)]
+    gpu: bool,
+
+    /// Record audio underruns and overruns (xruns) as markers on an "Audio"
+    /// track, to find what the profiled program was doing when the audio
+    /// glitched. This uses ALSA's xrun tracepoint, so it also covers sound
+    /// servers like PipeWire and PulseAudio which drive ALSA devices.
+    /// This option is only respected on Linux, and usually requires root.
+    #[arg(long)]
+    audio_xruns: bool,
+
+    /// Record the environment variables of each profiled process in the profile's
+    /// meta info, as they were when the process exec'd, or when samply attached
+    /// to it with --pid. Processes which fork without exec'ing aren't listed
+    /// separately. Variables whose names look like secrets are left out, see --env-deny.
+    /// This option is only respected on Linux.
+    #[arg(long)]
+    process_env: bool,
+
+    /// Leave out environment variables whose name contains this word, ignoring
+    /// case. Variables whose names contain TOKEN, SECRET, PASSWORD, KEY, AUTH,
+    /// CREDENTIAL, COOKIE, SESSION or PRIVATE are always left out, and passwords
+    /// in URLs, e.g. in DATABASE_URL, are redacted. This applies to samply's own
+    /// environment, which is always recorded, and to the environments from
+    /// --process-env. Can be specified multiple times.
+    #[arg(long, value_name = "WORD")]
+    env_deny: Vec<String>,
+
+    /// Record the time threads spend blocked on contended locks, as "Lock wait"
+    /// markers with the stack of the waiting thread. Each process also gets a
+    /// "Lock contention" marker per lock, with the number of waits and the total
+    /// wait time. This traces the futex waits of the profiled processes, and
+    /// only counts the waits which ended because the lock was released.
+    /// This option is only respected on Linux.
+    #[arg(long)]
+    lock_contention: bool,
+
+    /// Trace the syscalls which threads block in, e.g. read, epoll_wait, futex
+    /// or nanosleep, and put off-CPU samples into an "Off-CPU" category with a
+    /// subcategory for why the thread was waiting: I/O, polling, a lock, a sleep,
+    /// or preemption. Off-CPU samples are emitted with `--mode wall` or
+    /// `--blocked-time-weights`. Lock wait markers are only added with
+    /// `--lock-contention`. The syscalls are traced in the profiled processes,
+    /// which has a cost for syscall-heavy workloads.
+    /// This option is only respected on Linux.
+    #[arg(long)]
+    off_cpu_reasons: bool,
+
+    /// Create a socket which the launched command can send markers to while it's
+    /// running, instead of writing a marker file. Its path is passed to the
+    /// command in the SAMPLY_MARKER_SOCKET environment variable.
+    /// This option is only respected on Linux.
+    #[arg(long)]
+    marker_socket: bool,
+
+    /// Make cargo run rustc through samply, so that each rustc process is shown
+    /// below a process named after the crate it compiles, with a marker for the
+    /// compilation. This sets RUSTC_WRAPPER for the launched command; an existing
+    /// RUSTC_WRAPPER, e.g. sccache, is still used.
+    /// This option is only respected on Linux.
+    #[arg(long)]
+    rustc_wrapper: bool,
+
+    /// Accept OpenTelemetry spans via OTLP/HTTP (JSON encoding) on this port,
+    /// and show them as markers. The launched command is pointed at the receiver
+    /// via the OTEL_EXPORTER_OTLP_TRACES_ENDPOINT environment variable; with
+    /// --pid, the process needs to be configured to export to it already. Use 0
+    /// to pick a free port. Only processes of the current user can send spans,
+    /// and each span is shown on the process which sent it.
+    /// This option is only respected on Linux.
+    #[arg(long, value_name = "PORT")]
+    otlp_port: Option<u16>,
+
+    /// Capture the launched command's stdout and stderr. With "markers", each line
+    /// of output is passed through and also added to the profile as a marker.
+    /// This option is only respected on Linux.
+    #[arg(long, value_enum, value_name = "MODE")]
+    capture_output: Option<CaptureOutputMode>,
+
+    /// Only add markers for output lines which match this regular expression.
+    #[arg(long, value_name = "REGEX", requires = "capture_output")]
+    capture_output_filter: Option<String>,
+
+    /// Also write the launched command's stdout and stderr to <FILE>.stdout and
+    /// <FILE>.stderr, while still passing them through. The paths are listed in
+    /// the profile, and with --capture-output markers, each marker says which
+    /// line of the file it belongs to.
+    /// This option is only respected on Linux.
+    #[arg(long, value_name = "FILE")]
+    tee_output: Option<PathBuf>,
+
+    /// Write one profile per process into the directory given with --output,
+    /// instead of a single profile file. The directory can be opened with
+    /// `samply load <dir>`, which combines the profiles again.
+    /// This option is only respected on Linux.
+    #[arg(long)]
+    split_processes: bool,
+
+    /// Symbolicate the profile before saving it, and store the function names and
+    /// line numbers in the profile file. The saved profile can then be viewed on
+    /// machines which don't have the profiled binaries. Use --symbol-dir to supply
+    /// additional symbol files. To symbolicate an imported perf.data file, use
+    /// `samply load --symbolicate-to`.
+    /// This option is only respected on Linux.
+    #[arg(long)]
+    symbolicate_on_save: bool,
+
+    /// Keep only the most recent samples, in per-CPU ring buffers which the kernel
+    /// overwrites once they're full, and read them when recording stops. This works
+    /// like a flight recorder: stop recording right after the interesting moment.
+    /// Use --ring-buffer-size to control how much history is kept (default: 16 MB
+    /// per CPU).
+    /// This option is only respected on Linux.
+    #[arg(long)]
+    overwrite: bool,
+
+    /// The size of the per-CPU perf event ring buffers, in megabytes. Larger buffers
+    /// lose fewer samples at high sampling rates.
+    /// This option is only respected on Linux.
+    #[arg(long, value_name = "MB", value_parser = clap::value_parser!(u32).range(1..=1024))]
+    ring_buffer_size: Option<u32>,
+
+    /// Record like --overwrite, and stop once the recorded process meets a
+    /// condition: cpu>80%:30s for more than 80% of a CPU core during 30
+    /// seconds, or latency>100ms for an OpenTelemetry span which took longer
+    /// than 100ms (latency(NAME)>100ms only looks at spans called NAME; this
+    /// needs --otlp-port). The profile then has the anomaly and the history
+    /// before it, as far as the ring buffers reach. If the recording ends in
+    /// any other way, nothing is saved. This requires --pid.
+    /// This option is only respected on Linux.
+    #[arg(long, value_name = "CONDITION", requires = "pid")]
+    trigger: Option<Trigger>,
+
     #[command(flatten)]
     conversion_args: ConversionArgs,
 
@@ -122,13 +730,46 @@ struct RecordArgs {
         allow_hyphen_values = true,
         trailing_var_arg = true
     )]
-    command: Vec<std::ffi::OsString>,
+    command: Vec<OsString>,
 
-    /// Process ID of existing process to attach to (Linux only).
+    /// Process ID of existing process to attach to (Linux and FreeBSD only).
     #[arg(short, long)]
     pid: Option<u32>,
 }
 
+#[derive(Debug, Args)]
+struct StopArgs {
+    /// The name of the detached recording, or the path of its pidfile.
+    #[arg(value_name = "NAME_OR_PIDFILE")]
+    recording: String,
+
+    /// How long to wait for the recording to save its profile, in seconds.
+    #[arg(long, default_value = "300", value_name = "SECONDS")]
+    timeout: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum CaptureOutputMode {
+    /// Emit a marker for each line of output.
+    Markers,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum UnknownEventsMode {
+    /// Skip them silently.
+    Ignore,
+    /// Write a summary of them next to the loaded file.
+    Dump,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ProfilingMode {
+    /// Sample threads while they're running.
+    Cpu,
+    /// Sample threads while they're running and while they're blocked.
+    Wall,
+}
+
 #[derive(Debug, Args)]
 struct ServerArgs {
     /// Do not open the profiler UI.
@@ -142,6 +783,73 @@ struct ServerArgs {
     /// Print debugging output.
     #[arg(short, long)]
     verbose: bool,
+
+    /// Keep the symbolication results in this directory, so that they can be
+    /// reused when the same profile is opened again.
+    #[arg(long, value_name = "DIR")]
+    symbolication_cache_dir: Option<PathBuf>,
+
+    /// Look for binaries, debug files and breakpad symbol files in this
+    /// directory. Can be specified multiple times.
+    #[arg(long = "symbol-dir", value_name = "DIR")]
+    symbol_dirs: Vec<PathBuf>,
+
+    #[command(flatten)]
+    download_args: DownloadArgs,
+
+    /// Use symbols from binaries and debug files whose build ID doesn't match the
+    /// profiled library, if no matching file is found. This is useful for binaries
+    /// which were rebuilt locally after profiling. The symbols are only correct if
+    /// the code hasn't changed.
+    #[arg(long)]
+    ignore_id_mismatch: bool,
+
+    /// Use PDB files whose GUID matches the profiled library but whose age
+    /// doesn't. The age is bumped on incremental links which don't change the
+    /// code, so such PDBs usually still have correct symbols.
+    #[arg(long)]
+    ignore_pdb_age: bool,
+
+    /// Which architecture to use from universal (fat) binaries and from the dyld
+    /// shared cache, for libraries whose architecture isn't recorded in the
+    /// profile. With --ignore-id-mismatch, this also picks the architecture from
+    /// a rebuilt universal binary. This is only relevant for macOS binaries.
+    #[arg(long, value_name = "ARCH", value_parser = ["arm64", "arm64e", "x86_64", "x86_64h"])]
+    binary_arch: Option<String>,
+
+    /// Allow a profiler front-end at this origin, e.g. a self-hosted one at
+    /// https://profiler.example.com, to call the local server. Can be specified
+    /// multiple times. The profiler which samply opens is always allowed, i.e.
+    /// https://profiler.firefox.com or the one from the PROFILER_URL environment
+    /// variable. Use "*" to allow any origin.
+    #[arg(long = "allowed-origin", value_name = "ORIGIN")]
+    allowed_origins: Vec<String>,
+
+    /// Stop the local server after this many minutes without requests. The
+    /// server can also be stopped with a POST request to its /quit URL, which is
+    /// printed when it starts.
+    #[arg(long, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..))]
+    server_timeout: Option<u64>,
+}
+
+#[derive(Debug, Args)]
+struct DownloadArgs {
+    /// Don't download any symbol or source files, e.g. from the servers in
+    /// DEBUGINFOD_URLS or _NT_SYMBOL_PATH. Files which were downloaded earlier
+    /// are still used from their caches.
+    #[arg(long)]
+    offline: bool,
+
+    /// Only download symbol and source files from this domain and its
+    /// subdomains. Can be specified multiple times. The servers in
+    /// _NT_SYMBOL_PATH are then not downloaded from, because their redirects
+    /// can't be checked.
+    #[arg(
+        long = "allow-download-from",
+        value_name = "DOMAIN",
+        conflicts_with = "offline"
+    )]
+    allowed_download_domains: Vec<String>,
 }
 
 #[derive(Debug, Args, Clone)]
@@ -151,38 +859,360 @@ pub struct ConversionArgs {
     #[arg(long)]
     profile_name: Option<String>,
 
-    /// Merge non-overlapping threads of the same name.
+    /// Merge non-overlapping threads of the same name. This also applies to
+    /// processes, so the short-lived workers of a forking server share a few
+    /// tracks, with a marker for each worker's lifetime.
     #[arg(long)]
     reuse_threads: bool,
 
     /// Fold repeated frames at the base of the stack.
     #[arg(long)]
     fold_recursive_prefix: bool,
+
+    /// Treat successive markers with this name as frame boundaries. This adds a
+    /// "Frame time" track and highlights slow frames.
+    #[arg(long, value_name = "NAME")]
+    frame_marker: Option<String>,
+
+    /// Pair each marker named INPUT with the next marker named PRESENT, and add
+    /// a "Latency" marker which spans from the input to the end of the present.
+    /// For example: --latency-markers KeyDown,Present
+    #[arg(long, value_name = "INPUT,PRESENT")]
+    latency_markers: Option<LatencyMarkerNames>,
+
+    /// Splice the stack of the spawn site under the stacks of spawned tasks,
+    /// based on the rules in this JSON file. Each rule names a spawn marker and
+    /// a run marker, which are matched by the task id in their data, e.g.
+    /// [{"spawn": "TaskSpawn", "run": "TaskRun", "id": "task", "trampoline": "^py::"}]
+    /// The optional trampoline pattern selects the frames to replace.
+    #[arg(long, value_name = "FILE")]
+    stitch_stacks: Option<PathBuf>,
+
+    /// Create a track for each CPU, which contains the samples that were taken on
+    /// that CPU. If the profile has sched:sched_switch events, the track also shows
+    /// which thread was running on the CPU at any given time.
+    #[arg(long)]
+    per_cpu_threads: bool,
+
+    /// Merge all processes with the same name into a single track, with one
+    /// marker per process. This keeps profiles of builds, which run hundreds of
+    /// short-lived rustc or cc1plus processes, usable.
+    #[arg(long)]
+    aggregate_by_name: bool,
+
+    /// What the samples measure. With "wall", threads which are blocked, e.g. on
+    /// I/O or a lock, keep getting a sample every interval while they're off-CPU,
+    /// so that latency shows up and not just CPU hotspots. The running and blocked
+    /// time is told apart with context switch events. When recording, the stack of
+    /// a blocked thread is the one from its most recent on-CPU sample.
+    /// This option is only respected on Linux.
+    #[arg(long, value_enum, value_name = "MODE", default_value = "cpu")]
+    mode: ProfilingMode,
+
+    /// Weigh each sample by the time it stands for, rather than counting samples.
+    /// Off-CPU samples get the duration for which the thread was blocked, and are
+    /// put into an "Off-CPU" category, so that the call tree shows where the time
+    /// was spent waiting. On-CPU samples get the sampling interval. Off-CPU samples
+    /// are emitted with `--mode wall`, or for sched:sched_switch samples.
+    /// This option is only respected on Linux.
+    #[arg(long)]
+    blocked_time_weights: bool,
+
+    /// Keep only the lowest BITS bits of the return addresses in processes
+    /// named PROCESS. On arm64, the higher bits can hold pointer authentication
+    /// codes and memory tags, which are stripped. By default, the number of bits
+    /// is derived from the process's highest code mapping. Use 64 to keep the
+    /// return addresses as they are. Can be specified multiple times.
+    /// This option is only respected on Linux.
+    #[arg(long, value_name = "PROCESS=BITS")]
+    code_address_bits: Vec<CodeAddressBits>,
 }
 
 fn main() {
+    // cargo runs the wrapper as `samply <rustc> <args>...`, which isn't a samply
+    // command line.
+    if is_running_as_rustc_wrapper() {
+        let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+        std::process::exit(run_rustc_wrapper(&args));
+    }
+
     let opt = Opt::parse();
+    // The export token has been read into the arguments. Don't let the samply
+    // processes and the commands which we launch inherit it.
+    std::env::remove_var(EXPORT_TOKEN_ENV_VAR);
     match opt.action {
         Action::Load(load_args) => {
-            let input_file = match File::open(&load_args.file) {
+            if let Some(dir) = &load_args.split_by_process {
+                let conversion_props = load_args.conversion_props();
+                match split_perf_file(&load_args.file, dir, &load_args.processes, conversion_props)
+                {
+                    Ok(0) => {
+                        eprintln!("None of the processes matched, only wrote the manifest.");
+                        std::process::exit(1)
+                    }
+                    Ok(count) => eprintln!("Wrote {count} per-process profiles to {dir:?}."),
+                    Err(err) => {
+                        eprintln!("{err}");
+                        std::process::exit(1)
+                    }
+                }
+                return;
+            }
+            let core_dump = load_args
+                .with_core
+                .as_deref()
+                .map(|path| (read_core_dump(path), path));
+            let converted_temp_file = if load_args.file.is_dir() {
+                match merge_split_profiles(&load_args.file) {
+                    Ok(merged_file) => Some(merged_file),
+                    Err(err) => {
+                        eprintln!("{err}");
+                        std::process::exit(1)
+                    }
+                }
+            } else {
+                let input_file = match File::open(&load_args.file) {
+                    Ok(file) => file,
+                    Err(err) => {
+                        eprintln!("Could not open file {:?}: {}", load_args.file, err);
+                        std::process::exit(1)
+                    }
+                };
+                let conversion_props = load_args.conversion_props();
+                attempt_conversion(
+                    &load_args.file,
+                    &input_file,
+                    conversion_props,
+                    load_args.conversion_args.profile_name.as_deref(),
+                    core_dump
+                        .as_ref()
+                        .map(|(core_dump, path)| (core_dump, *path)),
+                )
+            };
+            if core_dump.is_some() && converted_temp_file.is_none() {
+                eprintln!("Error: --with-core only works with files which samply converts, such as perf.data files.");
+                std::process::exit(1)
+            }
+            // The file is loaded as a profile JSON file. Files with several
+            // profiles are combined into one here, and problems are reported
+            // as warnings before the profiler sees them.
+            let converted_temp_file = match converted_temp_file {
+                Some(temp_file) => Some(temp_file),
+                None => match prepare_profile_for_load(&load_args.file) {
+                    Ok(temp_file) => temp_file,
+                    Err(err) => {
+                        eprintln!("{err}");
+                        std::process::exit(1)
+                    }
+                },
+            };
+            let filename = match &converted_temp_file {
+                Some(temp_file) => temp_file.path(),
+                None => &load_args.file,
+            };
+            let hinted_temp_file = load_args
+                .view_hints
+                .as_deref()
+                .map(|hints_path| apply_view_hints(filename, hints_path));
+            let filename = match &hinted_temp_file {
+                Some((temp_file, _)) => temp_file.path(),
+                None => filename,
+            };
+            let mut server_props = load_args.server_args.server_props();
+            if let Some((_, view_state)) = hinted_temp_file.as_ref() {
+                server_props.view_state = Some(view_state.clone());
+            }
+            if let Some(output) = &load_args.symbolicate_to {
+                if let Err(err) = symbolicate_profile_file(
+                    filename,
+                    output,
+                    server_props.verbose,
+                    &server_props.symbol_dirs,
+                    server_props.id_matching,
+                    &server_props.symbol_downloads,
+                ) {
+                    eprintln!("{err}");
+                    std::process::exit(1)
+                }
+                return;
+            }
+            start_server_main(filename, server_props);
+        }
+
+        Action::List(list_args) => {
+            let profiles = match list_saved_profiles(&list_args.dir) {
+                Ok(profiles) => profiles,
+                Err(err) => {
+                    eprintln!("Could not read directory {:?}: {}", list_args.dir, err);
+                    std::process::exit(1)
+                }
+            };
+            let Some(number) = list_args.open else {
+                if profiles.is_empty() {
+                    eprintln!("No saved profiles found in {:?}.", list_args.dir);
+                }
+                print_saved_profiles(&profiles);
+                return;
+            };
+            let Some(profile) = number.checked_sub(1).and_then(|i| profiles.get(i)) else {
+                eprintln!("There is no saved profile with number {number}.");
+                std::process::exit(1)
+            };
+            let merged_temp_file = if profile.profile_path.is_dir() {
+                match merge_split_profiles(&profile.profile_path) {
+                    Ok(merged_file) => Some(merged_file),
+                    Err(err) => {
+                        eprintln!("{err}");
+                        std::process::exit(1)
+                    }
+                }
+            } else {
+                None
+            };
+            let filename = match &merged_temp_file {
+                Some(temp_file) => temp_file.path(),
+                None => &profile.profile_path,
+            };
+            start_server_main(filename, list_args.server_args.server_props());
+        }
+
+        Action::Serve(serve_args) => {
+            let server_props = ServerProps {
+                open_in_browser: false,
+                ..serve_args.server_args.server_props()
+            };
+            start_api_server_main(server_props);
+        }
+
+        Action::Symbols(SymbolsArgs {
+            action: SymbolsAction::Upload(upload_args),
+        }) => {
+            let props = SymbolUploadProps {
+                url: upload_args.url,
+                token: upload_args.token,
+                target: upload_args.target,
+                zip_output: upload_args.zip_output,
+                verbose: upload_args.verbose,
+                symbol_dirs: upload_args.symbol_dirs,
+                symbol_downloads: upload_args.download_args.symbol_downloads(),
+            };
+            if let Err(err) = upload_symbols_for_profile(&upload_args.profile, &props) {
+                eprintln!("{err}");
+                std::process::exit(1)
+            }
+        }
+
+        Action::Modules(modules_args) => {
+            if let Err(err) = list_profile_modules(&modules_args.profile, modules_args.json) {
+                eprintln!("{err}");
+                std::process::exit(1)
+            }
+        }
+
+        Action::Validate(validate_args) => {
+            if let Err(err) = validate_profile_file(&validate_args.profile) {
+                eprintln!("{err}");
+                std::process::exit(1)
+            }
+        }
+
+        Action::Annotate(annotate_args) => {
+            let props = AnnotateProps {
+                source_dirs: annotate_args.source_dirs,
+                file_count: annotate_args.files,
+                asm: annotate_args.asm,
+                function_count: annotate_args.functions,
+                html_output: annotate_args.html,
+                verbose: annotate_args.verbose,
+                symbol_dirs: annotate_args.symbol_dirs,
+                symbol_downloads: annotate_args.download_args.symbol_downloads(),
+            };
+            if let Err(err) = annotate_profile(&annotate_args.profile, &props) {
+                eprintln!("{err}");
+                std::process::exit(1)
+            }
+        }
+
+        Action::Convert(convert_args) => {
+            let format = convert_args
+                .to
+                .or_else(|| ProfileFormat::from_file_name(&convert_args.output));
+            let Some(format) = format else {
+                eprintln!(
+                    "Error: the format of {:?} isn't known from its name, please specify it with --to.",
+                    convert_args.output
+                );
+                std::process::exit(1)
+            };
+            let input_file = match File::open(&convert_args.input) {
                 Ok(file) => file,
                 Err(err) => {
-                    eprintln!("Could not open file {:?}: {}", load_args.file, err);
+                    eprintln!("Could not open file {:?}: {}", convert_args.input, err);
                     std::process::exit(1)
                 }
             };
-            let conversion_props = load_args.conversion_props();
-            let converted_temp_file =
-                attempt_conversion(&load_args.file, &input_file, conversion_props);
+            let converted_temp_file = attempt_conversion(
+                &convert_args.input,
+                &input_file,
+                convert_args.conversion_props(),
+                convert_args.conversion_args.profile_name.as_deref(),
+                None,
+            );
             let filename = match &converted_temp_file {
                 Some(temp_file) => temp_file.path(),
-                None => &load_args.file,
+                None => &convert_args.input,
+            };
+            let props = ConvertProps {
+                format,
+                verbose: convert_args.verbose,
+                symbol_dirs: convert_args.symbol_dirs,
+                symbol_downloads: convert_args.download_args.symbol_downloads(),
+            };
+            if let Err(err) = convert_profile(filename, &convert_args.output, &props) {
+                eprintln!("{err}");
+                std::process::exit(1)
+            }
+        }
+
+        Action::Bench(BenchArgs {
+            action: BenchAction::Import(import_args),
+        }) => {
+            let props = BenchImportProps {
+                iterations: import_args.iterations,
+                save_baseline: import_args.save_baseline,
+                baseline: import_args.baseline,
+                max_regression_percent: import_args.max_regression,
             };
-            start_server_main(filename, load_args.server_args.server_props());
+            if let Err(err) = bench_import(&import_args.files, &props) {
+                eprintln!("{err}");
+                std::process::exit(1)
+            }
         }
 
-        #[cfg(any(target_os = "android", target_os = "macos", target_os = "linux"))]
-        Action::Record(record_args) => {
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "macos",
+            target_os = "linux"
+        ))]
+        Action::Record(mut record_args) => {
+            let detached_pidfile = detach::detached_pidfile();
+            if let Some(capture_output) = capture_schedule::scheduled_capture_output() {
+                // This process records one capture for --every.
+                record_args.output = capture_output;
+                record_args.every = None;
+                record_args.detach = false;
+                record_args.save_only = true;
+            }
+            if record_args.detach {
+                if detached_pidfile.is_none() {
+                    record_args.start_detached();
+                    return;
+                }
+                record_args.save_only = true;
+            }
+
+            let start_time = SystemTime::now();
             let server_props = if record_args.save_only {
                 None
             } else {
@@ -192,10 +1222,14 @@ fn main() {
             let recording_props = record_args.recording_props();
             let conversion_props = record_args.conversion_props();
 
-            if let Some(pid) = record_args.pid {
+            let exit_code = if let Some(every) = record_args.every {
+                record_args.record_on_schedule(every)
+            } else if let Some(pid) = record_args.pid {
                 profiler::start_profiling_pid(pid, recording_props, conversion_props, server_props);
+                record_args.write_saved_profile_metadata(start_time);
+                0
             } else {
-                let exit_status = match profiler::start_recording(
+                match profiler::start_recording(
                     record_args.command[0].clone(),
                     &record_args.command[1..],
                     record_args.iteration_count,
@@ -203,34 +1237,232 @@ fn main() {
                     conversion_props,
                     server_props,
                 ) {
-                    Ok(exit_status) => exit_status,
+                    Ok(exit_status) => {
+                        record_args.write_saved_profile_metadata(start_time);
+                        exit_status.code().unwrap_or(0)
+                    }
                     Err(err) => {
                         eprintln!("Encountered an error during profiling: {err:?}");
-                        std::process::exit(1);
+                        1
                     }
-                };
-                std::process::exit(exit_status.code().unwrap_or(0));
+                }
+            };
+            // Tell `samply stop` that the profile has been saved.
+            if let Some(pidfile) = detached_pidfile {
+                let _ = std::fs::remove_file(pidfile);
+            }
+            std::process::exit(exit_code);
+        }
+
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "macos",
+            target_os = "linux"
+        ))]
+        Action::Stop(stop_args) => {
+            if let Err(err) = detach::stop_detached_recording(
+                &stop_args.recording,
+                Duration::from_secs(stop_args.timeout),
+            ) {
+                eprintln!("{err}");
+                std::process::exit(1)
             }
         }
     }
 }
 
-impl LoadArgs {
-    fn conversion_props(&self) -> ConversionProps {
-        let profile_name = if let Some(profile_name) = &self.conversion_args.profile_name {
-            profile_name.clone()
-        } else {
-            "Imported perf profile".to_string()
-        };
+impl ConversionArgs {
+    /// The conversion props for these arguments. `default_profile_name` is
+    /// used if no --profile-name was given.
+    fn conversion_props(
+        &self,
+        default_profile_name: String,
+        unknown_events_file: Option<PathBuf>,
+    ) -> ConversionProps {
         ConversionProps {
-            profile_name,
-            reuse_threads: self.conversion_args.reuse_threads,
-            fold_recursive_prefix: self.conversion_args.fold_recursive_prefix,
+            profile_name: self.profile_name.clone().unwrap_or(default_profile_name),
+            reuse_threads: self.reuse_threads,
+            fold_recursive_prefix: self.fold_recursive_prefix,
+            frame_marker: self.frame_marker.clone(),
+            latency_markers: self.latency_markers.clone(),
+            stack_stitching_rules: self.stack_stitching_rules(),
+            per_cpu_threads: self.per_cpu_threads,
+            aggregate_by_name: self.aggregate_by_name,
+            wall_clock: self.mode == ProfilingMode::Wall,
+            blocked_time_weights: self.blocked_time_weights,
+            code_address_bits: self.code_address_bits.clone(),
+            unknown_events_file,
+        }
+    }
+
+    fn stack_stitching_rules(&self) -> Vec<StackStitchingRule> {
+        let Some(path) = &self.stitch_stacks else {
+            return Vec::new();
+        };
+        StackStitchingRule::parse_rules_file(path).unwrap_or_else(|err| {
+            eprintln!("Error: invalid --stitch-stacks file: {err}");
+            std::process::exit(1);
+        })
+    }
+}
+
+impl DownloadArgs {
+    fn symbol_downloads(&self) -> SymbolDownloads {
+        SymbolDownloads {
+            offline: self.offline,
+            allowed_domains: self.allowed_download_domains.clone(),
         }
     }
 }
 
+impl LoadArgs {
+    fn conversion_props(&self) -> ConversionProps {
+        self.conversion_args.conversion_props(
+            "Imported perf profile".to_string(),
+            (self.unknown_events == UnknownEventsMode::Dump).then(|| self.unknown_events_file()),
+        )
+    }
+
+    /// The file next to the loaded file which gets the summary of the unknown
+    /// events, e.g. perf.data.unknown-events.txt.
+    fn unknown_events_file(&self) -> PathBuf {
+        let mut path = self.file.clone().into_os_string();
+        path.push(".unknown-events.txt");
+        PathBuf::from(path)
+    }
+}
+
+impl ConvertArgs {
+    fn conversion_props(&self) -> ConversionProps {
+        self.conversion_args
+            .conversion_props("Imported perf profile".to_string(), None)
+    }
+}
+
 impl RecordArgs {
+    /// Starts this recording again in the background, for --detach.
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "linux"
+    ))]
+    fn start_detached(&self) {
+        let (pidfile, stop_argument) = match &self.pidfile {
+            Some(pidfile) => (pidfile.clone(), pidfile.to_string_lossy().to_string()),
+            None => {
+                let name = self.recording_name();
+                match detach::default_pidfile_path(&name) {
+                    Ok(pidfile) => (pidfile, name),
+                    Err(err) => {
+                        eprintln!("{err}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+        };
+        match detach::spawn_detached(&pidfile, self.export_token.as_deref()) {
+            Ok(pid) => {
+                eprintln!("Recording in the background, with pid {pid}.");
+                eprintln!("  Stop it with: samply stop {stop_argument}");
+                eprintln!("  The output goes to {:?}.", detach::log_path(&pidfile));
+            }
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Records a capture once per `every`, for --every. Returns the exit code.
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "linux"
+    ))]
+    fn record_on_schedule(&self, every: Duration) -> i32 {
+        let (Some(duration), Some(output_dir)) = (self.duration, &self.output_dir) else {
+            panic!("clap should have required --duration and --output-dir with --every");
+        };
+        if every.as_secs_f64() < duration {
+            eprintln!("Error: --every must not be shorter than --duration.");
+            return 1;
+        }
+        let schedule = capture_schedule::CaptureSchedule {
+            every,
+            output_dir: output_dir.clone(),
+            name: self.recording_name(),
+            keep: self.keep_captures as usize,
+        };
+        // Ctrl+C also reaches the samply process which records the current
+        // capture, and which saves it before exiting.
+        let stop = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGINT, stop.clone())
+            .expect("cannot register signal handler");
+        let exporter = self.export_url.as_ref().map(|url| PyroscopeExporter {
+            url: url.clone(),
+            app_name: schedule.name.clone(),
+            token: self.export_token.clone(),
+            max_stacks: self.export_max_stacks as usize,
+            verbose: self.server_args.verbose,
+            symbol_dirs: self.server_args.symbol_dirs.clone(),
+            symbol_downloads: self.server_args.download_args.symbol_downloads(),
+        });
+        schedule.run(&stop, |path| {
+            let start_time = SystemTime::now();
+            if !capture_schedule::record_capture(path) {
+                return false;
+            }
+            if let Some(exporter) = exporter.as_ref().filter(|_| path.exists()) {
+                // The capture is still on disk, so a failed export only loses
+                // this capture in the backend.
+                if let Err(err) = exporter.export(path, start_time, SystemTime::now()) {
+                    eprintln!("{err}");
+                }
+            }
+            true
+        });
+        0
+    }
+
+    /// The name of the recording, which `samply stop` accepts with --detach, and
+    /// which the profiles of --every are named after.
+    #[allow(unused)]
+    fn recording_name(&self) -> String {
+        let name = match (
+            &self.conversion_args.profile_name,
+            self.pid,
+            self.command.first(),
+        ) {
+            (Some(profile_name), _, _) => profile_name.clone(),
+            (None, Some(pid), _) => format!("pid-{pid}"),
+            (None, None, Some(command)) => Path::new(command)
+                .file_name()
+                .unwrap_or(command)
+                .to_string_lossy()
+                .to_string(),
+            (None, None, None) => "recording".to_string(),
+        };
+        name.replace('/', "_")
+    }
+
+    /// Writes the metadata file for `samply list` if the profile was saved for
+    /// later viewing.
+    #[allow(unused)]
+    fn write_saved_profile_metadata(&self, start_time: SystemTime) {
+        // Nothing is saved if --trigger didn't fire.
+        if !self.save_only || !self.output.exists() {
+            return;
+        }
+        let saved_profile =
+            SavedProfile::for_finished_recording(&self.output, &self.command, self.pid, start_time);
+        if let Err(err) = saved_profile.write_sidecar() {
+            eprintln!("Could not write the profile metadata file: {err}");
+        }
+    }
+
     #[allow(unused)]
     pub fn recording_props(&self) -> RecordingProps {
         let time_limit = self.duration.map(Duration::from_secs_f64);
@@ -241,29 +1473,75 @@ impl RecordArgs {
             );
             std::process::exit(1);
         }
+        if matches!(self.trigger, Some(Trigger::Latency { .. })) && self.otlp_port.is_none() {
+            eprintln!("Error: --trigger latency>... needs --otlp-port to receive the spans");
+            std::process::exit(1);
+        }
         let interval = Duration::from_secs_f64(1.0 / self.rate);
+        let output_markers = match self.capture_output {
+            Some(CaptureOutputMode::Markers) => {
+                let filter = self.capture_output_filter.as_deref().map(|filter| {
+                    Regex::new(filter).unwrap_or_else(|err| {
+                        eprintln!("Error: invalid --capture-output-filter: {err}");
+                        std::process::exit(1);
+                    })
+                });
+                Some(OutputMarkerProps { filter })
+            }
+            None => None,
+        };
 
         RecordingProps {
             output_file: self.output.clone(),
             time_limit,
             interval,
             main_thread_only: self.main_thread_only,
+            file_io: self.file_io,
+            vsync: self.vsync,
+            gpu: self.gpu,
+            audio_xruns: self.audio_xruns,
+            process_environment: self.process_env,
+            env_deny_list: self.env_deny.clone(),
+            lock_contention: self.lock_contention,
+            off_cpu_reasons: self.off_cpu_reasons,
+            marker_socket: self.marker_socket,
+            rustc_wrapper: self.rustc_wrapper,
+            otlp_port: self.otlp_port,
+            output_markers,
+            tee_output: self.tee_output.clone(),
+            skip_failed_iterations: self.skip_failed_iterations,
+            warmup_iterations: self.warmup_iterations,
+            record_warmup: self.record_warmup,
+            iteration_report: (self.iteration_report || self.iteration_report_json.is_some()).then(
+                || IterationReportProps {
+                    print_text: self.iteration_report,
+                    json_output: self.iteration_report_json.clone(),
+                    verbose: self.server_args.verbose,
+                    symbol_dirs: self.server_args.symbol_dirs.clone(),
+                    symbol_downloads: self.server_args.download_args.symbol_downloads(),
+                },
+            ),
+            split_processes: self.split_processes,
+            symbolicate_on_save: self.symbolicate_on_save,
+            symbol_dirs: self.server_args.symbol_dirs.clone(),
+            symbol_downloads: self.server_args.download_args.symbol_downloads(),
+            overwrite: self.overwrite || self.trigger.is_some(),
+            ring_buffer_size: self
+                .ring_buffer_size
+                .map(|megabytes| megabytes * 1024 * 1024),
+            trigger: self.trigger.clone(),
         }
     }
 
     #[allow(unused)]
     pub fn conversion_props(&self) -> ConversionProps {
-        let profile_name = match (self.conversion_args.profile_name.clone(), self.pid, self.command.first()) {
-            (Some(profile_name), _, _) => profile_name,
-            (None, Some(pid), _) => format!("PID {pid}"),
-            (None, None, Some(command)) => command.to_string_lossy().to_string(),
-            (None, None, None) => panic!("Either pid or command is guaranteed to be present (clap should have done the validation)"),
+        let default_profile_name = match (self.pid, self.command.first()) {
+            (Some(pid), _) => format!("PID {pid}"),
+            (None, Some(command)) => command.to_string_lossy().to_string(),
+            (None, None) => panic!("Either pid or command is guaranteed to be present (clap should have done the validation)"),
         };
-        ConversionProps {
-            profile_name,
-            reuse_threads: self.conversion_args.reuse_threads,
-            fold_recursive_prefix: self.conversion_args.fold_recursive_prefix,
-        }
+        self.conversion_args
+            .conversion_props(default_profile_name, None)
     }
 }
 
@@ -284,26 +1562,136 @@ impl ServerArgs {
             port_selection,
             verbose: self.verbose,
             open_in_browser,
+            symbolication_cache_dir: self.symbolication_cache_dir.clone(),
+            symbol_dirs: self.symbol_dirs.clone(),
+            symbol_downloads: self.download_args.symbol_downloads(),
+            id_matching: SymbolIdMatching {
+                ignore_id_mismatch: self.ignore_id_mismatch,
+                ignore_pdb_age: self.ignore_pdb_age,
+                binary_arch: self.binary_arch.clone(),
+            },
+            allowed_origins: self.allowed_origins.clone(),
+            idle_timeout: self
+                .server_timeout
+                .map(|minutes| Duration::from_secs(minutes * 60)),
+            view_state: None,
         }
     }
 }
 
+/// How much of a file is read to recognize its format.
+const SNIFF_HEADER_LEN: u64 = 64 * 1024;
+
+/// Converts a perf.data file, a pmcstat log, a heap profile from massif or
+/// heaptrack, a pprof or speedscope profile, collapsed stacks, or aggregated
+/// DTrace stacks into a profile.
+/// Returns `None` if the file is neither, e.g. because it's a profile already.
 fn attempt_conversion(
     filename: &Path,
-    input_file: &File,
+    mut input_file: &File,
     conversion_props: ConversionProps,
+    profile_name: Option<&str>,
+    core_dump: Option<(&CoreDump, &Path)>,
 ) -> Option<NamedTempFile> {
     let path = Path::new(filename)
         .canonicalize()
         .expect("Couldn't form absolute path");
     let reader = BufReader::new(input_file);
     let output_file = tempfile::NamedTempFile::new().ok()?;
-    let profile = import::perf::convert(reader, path.parent(), conversion_props).ok()?;
+    let mut profile = match import::perf::convert(reader, path.parent(), conversion_props) {
+        Ok(profile) => profile,
+        Err(_) => {
+            // Most formats can be recognized from the start of the file, so
+            // the whole file is only read once it's known to be one of them.
+            input_file.rewind().ok()?;
+            let mut header = Vec::new();
+            input_file
+                .take(SNIFF_HEADER_LEN)
+                .read_to_end(&mut header)
+                .ok()?;
+            input_file.rewind().ok()?;
+            let read_all = || {
+                let mut data = Vec::new();
+                let mut file = input_file;
+                file.read_to_end(&mut data).ok()?;
+                Some(data)
+            };
+            if import::pmclog::is_pmclog(&header) {
+                let profile_name = profile_name.unwrap_or("Imported pmcstat profile");
+                import::pmclog::convert(&read_all()?, profile_name, &[]).ok()?
+            } else if import::massif::is_massif(&header) {
+                let profile_name = profile_name.unwrap_or("Imported massif profile");
+                import::massif::convert(BufReader::new(input_file), profile_name).ok()?
+            } else if import::heaptrack::is_heaptrack(&header) {
+                let profile_name = profile_name.unwrap_or("Imported heaptrack profile");
+                import::heaptrack::convert(&read_all()?, profile_name).ok()?
+            } else if import::pprof::is_pprof(&header) {
+                let profile_name = profile_name.unwrap_or("Imported pprof profile");
+                import::pprof::convert(&read_all()?, profile_name).ok()?
+            } else if import::speedscope::is_speedscope(&header) {
+                let profile_name = profile_name.unwrap_or("Imported speedscope profile");
+                import::speedscope::convert(BufReader::new(input_file), profile_name).ok()?
+            } else if import::collapsed::is_collapsed(&header) {
+                let profile_name = profile_name.unwrap_or("Imported collapsed stacks");
+                import::collapsed::convert(BufReader::new(input_file), profile_name).ok()?
+            } else {
+                let profile_name = profile_name.unwrap_or("Imported DTrace profile");
+                import::dtrace::convert(BufReader::new(input_file), profile_name).ok()?
+            }
+        }
+    };
+    if let Some((core_dump, core_path)) = core_dump {
+        core_dump.add_to_profile(&mut profile, &core_path.to_string_lossy());
+    }
     let writer = BufWriter::new(output_file.as_file());
     serde_json::to_writer(writer, &profile).ok()?;
     Some(output_file)
 }
 
+/// Reads the core dump for --with-core, or exits on error.
+fn read_core_dump(path: &Path) -> CoreDump {
+    let result = File::open(path)
+        .and_then(|file| unsafe { memmap2::Mmap::map(&file) })
+        .map_err(|err| err.to_string())
+        .and_then(|mmap| CoreDump::parse(&mmap));
+    result.unwrap_or_else(|err| {
+        eprintln!("Error: could not read the core dump {path:?}: {err}");
+        std::process::exit(1)
+    })
+}
+
+/// Writes a copy of the profile at `profile_path` with the view hints from
+/// `hints_path` into a temporary file, or exits on error. Also returns the
+/// part of the hints which goes into the profiler URL.
+fn apply_view_hints(profile_path: &Path, hints_path: &Path) -> (NamedTempFile, UrlViewState) {
+    let result = ViewHints::from_file(hints_path).and_then(|hints| {
+        let output_file = tempfile::NamedTempFile::new()
+            .map_err(|err| format!("Could not create a temporary file: {err}"))?;
+        hints.apply_to_profile_file(profile_path, output_file.path())?;
+        Ok((output_file, hints.url_state()))
+    });
+    result.unwrap_or_else(|err| {
+        eprintln!("Error: could not apply the view hints: {err}");
+        std::process::exit(1)
+    })
+}
+
+/// Converts the perf.data file at `path` and writes one profile per process
+/// into `dir`. Returns the number of profiles which were written.
+fn split_perf_file(
+    path: &Path,
+    dir: &Path,
+    selected_processes: &[String],
+    conversion_props: ConversionProps,
+) -> Result<usize, String> {
+    let input_file = File::open(path).map_err(|err| format!("Could not open {path:?}: {err}"))?;
+    let profile =
+        import::perf::convert(BufReader::new(input_file), path.parent(), conversion_props)
+            .map_err(|err| format!("Could not import {path:?}: {err}"))?;
+    write_split_profiles(&profile, dir, selected_processes)
+        .map_err(|err| format!("Could not write the profiles to {dir:?}: {err}"))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -314,7 +1702,12 @@ mod test {
         Opt::command().debug_assert();
     }
 
-    #[cfg(any(target_os = "android", target_os = "macos", target_os = "linux"))]
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "linux"
+    ))]
     #[test]
     fn verify_cli_record() {
         let opt = Opt::parse_from(["samply", "record", "rustup", "show"]);
@@ -0,0 +1,147 @@
+use serde_derive::Serialize;
+use serde_json::Value;
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+use crate::profile_json_preparse::read_profile_libs;
+
+/// A library from the profile's lib list, with everything that identifies the
+/// exact binary and debug file, so that they can be fetched later, e.g. from a
+/// symbol server or a build artifact store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileModule {
+    pub name: String,
+    pub path: String,
+    pub debug_name: String,
+    pub debug_path: String,
+    pub breakpad_id: String,
+    pub code_id: Option<String>,
+    pub arch: Option<String>,
+    pub file_size: Option<u64>,
+}
+
+/// Prints the libraries in the profile, as a table or as JSON.
+pub fn list_profile_modules(profile_path: &Path, json: bool) -> Result<(), String> {
+    let libs = read_profile_libs(profile_path)
+        .map_err(|err| format!("Could not read {profile_path:?}: {err}"))?;
+    let modules = profile_modules(&libs);
+    if json {
+        let stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(stdout, &modules)
+            .map_err(|err| format!("Could not write the module list: {err}"))?;
+        println!();
+    } else {
+        print!("{}", modules_table(&modules));
+    }
+    Ok(())
+}
+
+/// The libraries in the profile's lib lists, sorted by name, without
+/// duplicates.
+pub fn profile_modules(libs: &[Value]) -> Vec<ProfileModule> {
+    let string = |lib: &Value, key: &str| lib.get(key).and_then(Value::as_str).map(String::from);
+    let mut modules: Vec<ProfileModule> = libs
+        .iter()
+        .map(|lib| ProfileModule {
+            name: string(lib, "name").unwrap_or_default(),
+            path: string(lib, "path").unwrap_or_default(),
+            debug_name: string(lib, "debugName").unwrap_or_default(),
+            debug_path: string(lib, "debugPath").unwrap_or_default(),
+            breakpad_id: string(lib, "breakpadId").unwrap_or_default(),
+            code_id: string(lib, "codeId"),
+            arch: string(lib, "arch"),
+            file_size: lib.get("fileSize").and_then(Value::as_u64),
+        })
+        .collect();
+    modules.sort_by(|a, b| (&a.name, &a.breakpad_id).cmp(&(&b.name, &b.breakpad_id)));
+    modules.dedup();
+    modules
+}
+
+fn modules_table(modules: &[ProfileModule]) -> String {
+    let rows: Vec<[String; 5]> = modules
+        .iter()
+        .map(|module| {
+            [
+                module.name.clone(),
+                module.breakpad_id.clone(),
+                module.code_id.clone().unwrap_or_else(|| "-".to_string()),
+                module
+                    .file_size
+                    .map_or_else(|| "-".to_string(), |size| size.to_string()),
+                module.path.clone(),
+            ]
+        })
+        .collect();
+    let header = ["NAME", "BREAKPAD ID", "CODE ID", "SIZE", "PATH"].map(String::from);
+    let mut widths = [0; 5];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = format!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {:>w3$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        );
+        let _ = writeln!(table, "{}", line.trim_end());
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::{modules_table, profile_modules};
+    use serde_json::json;
+
+    #[test]
+    fn list_modules() {
+        let profile = json!({
+            "libs": [
+                {
+                    "name": "libc.so.6",
+                    "path": "/usr/lib/libc.so.6",
+                    "debugName": "libc.so.6",
+                    "debugPath": "/usr/lib/libc.so.6",
+                    "breakpadId": "1629FE1B7A1E9E4D8B6F7C7A1F5A2C7E0",
+                    "codeId": "1bfe2916-1e7a-4d9e-8b6f-7c7a1f5a2c7e",
+                    "arch": null,
+                    "fileSize": 2105184
+                },
+                {
+                    "name": "app",
+                    "path": "/home/user/app",
+                    "debugName": "app",
+                    "debugPath": "/home/user/app",
+                    "breakpadId": "000000000000000000000000000000000",
+                    "codeId": null,
+                    "arch": null
+                }
+            ]
+        });
+        let modules = profile_modules(profile["libs"].as_array().unwrap());
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[0].name, "app");
+        assert_eq!(modules[0].file_size, None);
+        assert_eq!(modules[1].file_size, Some(2105184));
+
+        let table = modules_table(&modules);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("NAME       BREAKPAD ID"));
+        assert!(lines[1].starts_with("app        000000000000000000000000000000000  -"));
+        assert!(lines[2].ends_with("  2105184  /usr/lib/libc.so.6"));
+    }
+}
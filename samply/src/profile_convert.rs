@@ -0,0 +1,523 @@
+//! `samply convert`, which writes a profile in one of several formats, so that
+//! it can be opened with other tools. The input is a profile in the Firefox
+//! Profiler's processed format, which every file that samply imports is
+//! converted to first. The stacks are symbolicated on the way, because the
+//! other formats only have function names.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::profile_export::{folded_stacks, frame_names, profile_symbols};
+use crate::profile_symbolication::{
+    json_array, read_profile, symbolicate_profile_file, AddressSymbol,
+};
+use crate::server::{SymbolDownloads, SymbolIdMatching};
+use crate::shared::protobuf::{write_bytes_field, write_packed_field, write_varint_field};
+
+const SPEEDSCOPE_SCHEMA_URL: &str = "https://www.speedscope.app/file-format-schema.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProfileFormat {
+    /// The Firefox Profiler's processed format, which `samply load` opens.
+    Gecko,
+    /// One line per distinct stack with its sample count, as used by
+    /// flamegraph.pl and inferno.
+    Collapsed,
+    /// A gzip-compressed pprof protocol buffer, for `go tool pprof`.
+    Pprof,
+    /// The file format of speedscope.
+    Speedscope,
+}
+
+impl ProfileFormat {
+    /// Guesses the format from the extension of an output file, e.g.
+    /// "profile.pb.gz" or "out.folded".
+    pub fn from_file_name(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        if name.ends_with(".speedscope.json") {
+            Some(ProfileFormat::Speedscope)
+        } else if name.ends_with(".json") {
+            Some(ProfileFormat::Gecko)
+        } else if name.ends_with(".pb.gz") || name.ends_with(".pb") || name.ends_with(".pprof") {
+            Some(ProfileFormat::Pprof)
+        } else if name.ends_with(".folded") || name.ends_with(".collapsed") {
+            Some(ProfileFormat::Collapsed)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct ConvertProps {
+    pub format: ProfileFormat,
+    pub verbose: bool,
+    pub symbol_dirs: Vec<PathBuf>,
+    pub symbol_downloads: SymbolDownloads,
+}
+
+/// Writes the profile at `profile_path` to `output`, in the format from `props`.
+pub fn convert_profile(
+    profile_path: &Path,
+    output: &Path,
+    props: &ConvertProps,
+) -> Result<(), String> {
+    match props.format {
+        ProfileFormat::Gecko => symbolicate_profile_file(
+            profile_path,
+            output,
+            props.verbose,
+            &props.symbol_dirs,
+            SymbolIdMatching::default(),
+            &props.symbol_downloads,
+        ),
+        format => write_symbolicated_stacks(profile_path, output, format, props),
+    }
+}
+
+#[tokio::main]
+async fn write_symbolicated_stacks(
+    profile_path: &Path,
+    output: &Path,
+    format: ProfileFormat,
+    props: &ConvertProps,
+) -> Result<(), String> {
+    let profile = read_profile(profile_path)
+        .map_err(|err| format!("Could not read {profile_path:?}: {err}"))?;
+    if profile
+        .pointer("/meta/preprocessedProfileVersion")
+        .is_none()
+    {
+        return Err(format!(
+            "{profile_path:?} is not a profile in a format which samply can convert."
+        ));
+    }
+    let symbols = profile_symbols(
+        &profile,
+        props.verbose,
+        &props.symbol_dirs,
+        &props.symbol_downloads,
+    )
+    .await?;
+    let data = match format {
+        ProfileFormat::Collapsed => collapsed_stacks(&profile, &symbols),
+        ProfileFormat::Pprof => pprof_profile(&profile, &symbols)
+            .map_err(|err| format!("Could not compress the profile: {err}"))?,
+        ProfileFormat::Speedscope => speedscope_profile(&profile, &symbols)
+            .to_string()
+            .into_bytes(),
+        ProfileFormat::Gecko => unreachable!("Gecko profiles are written by convert_profile"),
+    };
+    std::fs::write(output, data).map_err(|err| format!("Could not write {output:?}: {err}"))
+}
+
+/// Writes the collapsed stacks. The stacks only start with the name of their
+/// process if the profile has several processes, so that converting collapsed
+/// stacks back and forth doesn't add a frame each time.
+fn collapsed_stacks(profile: &Value, symbols: &HashMap<(usize, u32), AddressSymbol>) -> Vec<u8> {
+    let mut process_names: Vec<&str> = json_array(profile, "threads")
+        .iter()
+        .filter_map(|thread| thread["processName"].as_str())
+        .collect();
+    process_names.sort_unstable();
+    process_names.dedup();
+    let stacks = folded_stacks(profile, symbols, process_names.len() > 1);
+    let mut stacks: Vec<(String, u64)> = stacks.into_iter().collect();
+    stacks.sort_unstable();
+    let mut data = Vec::new();
+    for (stack, count) in stacks {
+        let _ = writeln!(data, "{stack} {count}");
+    }
+    data
+}
+
+/// The samples of a thread, with the function names of their stacks.
+struct ThreadSamples {
+    name: String,
+    /// The frames of each sample's stack, from the root to the leaf, as
+    /// indexes into the names from `frame_names`. Samples without a stack are
+    /// left out.
+    stacks: Vec<Vec<usize>>,
+    /// The weight of each sample, in the unit of the profile's weight type.
+    weights: Vec<f64>,
+    /// The time of each sample, in milliseconds.
+    times: Vec<f64>,
+}
+
+fn thread_samples(thread: &Value) -> ThreadSamples {
+    let thread_name = thread["name"].as_str().unwrap_or("");
+    let process_name = thread["processName"].as_str().unwrap_or("");
+    let name = if process_name.is_empty() || process_name == thread_name {
+        thread_name.to_string()
+    } else {
+        format!("{thread_name} ({process_name})")
+    };
+
+    let stack_frames = json_array(&thread["stackTable"], "frame");
+    let stack_prefixes = json_array(&thread["stackTable"], "prefix");
+    let samples = &thread["samples"];
+    let sample_weights = json_array(samples, "weight");
+    let sample_times = json_array(samples, "time");
+    let time_deltas = json_array(samples, "timeDeltas");
+    let mut result = ThreadSamples {
+        name,
+        stacks: Vec::new(),
+        weights: Vec::new(),
+        times: Vec::new(),
+    };
+    let mut time = 0.0;
+    for (i, stack) in json_array(samples, "stack").iter().enumerate() {
+        time = match sample_times.get(i) {
+            Some(sample_time) => sample_time.as_f64().unwrap_or(time),
+            None => time + time_deltas.get(i).and_then(Value::as_f64).unwrap_or(0.0),
+        };
+        let mut frames = Vec::new();
+        let mut stack = stack.as_u64();
+        // Bound the walk in case the prefixes form a cycle.
+        while let Some(index) = stack.filter(|_| frames.len() < stack_frames.len()) {
+            let index = index as usize;
+            if let Some(frame) = stack_frames.get(index).and_then(Value::as_u64) {
+                frames.push(frame as usize);
+            }
+            stack = stack_prefixes.get(index).and_then(Value::as_u64);
+        }
+        if frames.is_empty() {
+            continue;
+        }
+        frames.reverse();
+        result.stacks.push(frames);
+        result
+            .weights
+            .push(sample_weights.get(i).and_then(Value::as_f64).unwrap_or(1.0));
+        result.times.push(time);
+    }
+    result
+}
+
+fn weight_type(profile: &Value) -> &str {
+    json_array(profile, "threads")
+        .first()
+        .and_then(|thread| thread.pointer("/samples/weightType"))
+        .and_then(Value::as_str)
+        .unwrap_or("samples")
+}
+
+fn speedscope_profile(profile: &Value, symbols: &HashMap<(usize, u32), AddressSymbol>) -> Value {
+    let unit = match weight_type(profile) {
+        "tracing-ms" => "milliseconds",
+        "bytes" => "bytes",
+        _ => "none",
+    };
+    let mut frames: Vec<Value> = Vec::new();
+    let mut frame_indexes: HashMap<String, usize> = HashMap::new();
+    let mut profiles = Vec::new();
+    for thread in json_array(profile, "threads") {
+        let samples = thread_samples(thread);
+        if samples.stacks.is_empty() {
+            continue;
+        }
+        // The frames are shared between the threads, by name.
+        let thread_frame_indexes: Vec<usize> = frame_names(profile, thread, symbols)
+            .into_iter()
+            .map(|name| {
+                *frame_indexes.entry(name.clone()).or_insert_with(|| {
+                    frames.push(json!({ "name": name }));
+                    frames.len() - 1
+                })
+            })
+            .collect();
+        let stacks: Vec<Vec<usize>> = samples
+            .stacks
+            .iter()
+            .map(|stack| {
+                stack
+                    .iter()
+                    .map(|frame| thread_frame_indexes[*frame])
+                    .collect()
+            })
+            .collect();
+        let total: f64 = samples.weights.iter().sum();
+        profiles.push(json!({
+            "type": "sampled",
+            "name": samples.name,
+            "unit": unit,
+            "startValue": 0,
+            "endValue": total,
+            "samples": stacks,
+            "weights": samples.weights,
+        }));
+    }
+    json!({
+        "$schema": SPEEDSCOPE_SCHEMA_URL,
+        "name": profile.pointer("/meta/product").and_then(Value::as_str).unwrap_or("samply"),
+        "exporter": "samply",
+        "activeProfileIndex": 0,
+        "shared": { "frames": frames },
+        "profiles": profiles,
+    })
+}
+
+/// Writes the pprof `Profile` message. Each distinct function name gets a
+/// function and a location with the same ID, and the samples get a "thread"
+/// label with the name of their thread.
+fn pprof_profile(
+    profile: &Value,
+    symbols: &HashMap<(usize, u32), AddressSymbol>,
+) -> std::io::Result<Vec<u8>> {
+    let mut strings = StringTable::default();
+    let mut message = Vec::new();
+
+    let interval_ms = profile
+        .pointer("/meta/interval")
+        .and_then(Value::as_f64)
+        .unwrap_or(1.0);
+    // The sample type and unit, and the factor from the weights to the unit.
+    let (kind, unit, factor) = match weight_type(profile) {
+        "tracing-ms" => ("time", "nanoseconds", 1_000_000.0),
+        "bytes" => ("space", "bytes", 1.0),
+        _ => ("samples", "count", 1.0),
+    };
+    let mut value_type = Vec::new();
+    write_varint_field(&mut value_type, 1, strings.index(kind));
+    write_varint_field(&mut value_type, 2, strings.index(unit));
+    write_bytes_field(&mut message, 1, &value_type);
+
+    let thread_label = strings.index("thread");
+    let mut function_ids: HashMap<String, u64> = HashMap::new();
+    let mut end_time: f64 = 0.0;
+    for thread in json_array(profile, "threads") {
+        let samples = thread_samples(thread);
+        let thread_name = strings.index(&samples.name);
+        let thread_function_ids: Vec<u64> = frame_names(profile, thread, symbols)
+            .into_iter()
+            .map(|name| {
+                let next_id = function_ids.len() as u64 + 1;
+                *function_ids.entry(name).or_insert(next_id)
+            })
+            .collect();
+        for ((stack, weight), time) in samples
+            .stacks
+            .iter()
+            .zip(&samples.weights)
+            .zip(&samples.times)
+        {
+            end_time = end_time.max(*time);
+            let mut sample = Vec::new();
+            // The locations are listed from the leaf to the root.
+            let location_ids: Vec<u64> = stack
+                .iter()
+                .rev()
+                .map(|frame| thread_function_ids[*frame])
+                .collect();
+            write_packed_field(&mut sample, 1, &location_ids);
+            write_packed_field(&mut sample, 2, &[(weight * factor).round() as u64]);
+            let mut label = Vec::new();
+            write_varint_field(&mut label, 1, thread_label);
+            write_varint_field(&mut label, 2, thread_name);
+            write_bytes_field(&mut sample, 3, &label);
+            write_bytes_field(&mut message, 2, &sample);
+        }
+    }
+
+    let mut functions: Vec<(&String, &u64)> = function_ids.iter().collect();
+    functions.sort_unstable_by_key(|(_, id)| **id);
+    for (name, id) in functions {
+        let mut line = Vec::new();
+        write_varint_field(&mut line, 1, *id);
+        let mut location = Vec::new();
+        write_varint_field(&mut location, 1, *id);
+        write_bytes_field(&mut location, 4, &line);
+        write_bytes_field(&mut message, 4, &location);
+
+        let name = strings.index(name);
+        let mut function = Vec::new();
+        write_varint_field(&mut function, 1, *id);
+        write_varint_field(&mut function, 2, name);
+        write_varint_field(&mut function, 3, name);
+        write_bytes_field(&mut message, 5, &function);
+    }
+
+    let mut period_type = Vec::new();
+    write_varint_field(&mut period_type, 1, strings.index("cpu"));
+    write_varint_field(&mut period_type, 2, strings.index("nanoseconds"));
+    for string in &strings.strings {
+        write_bytes_field(&mut message, 6, string.as_bytes());
+    }
+    let start_time_ms = profile
+        .pointer("/meta/startTime")
+        .and_then(Value::as_f64)
+        .unwrap_or(0.0);
+    write_varint_field(&mut message, 9, (start_time_ms * 1_000_000.0) as u64);
+    write_varint_field(&mut message, 10, (end_time * 1_000_000.0) as u64);
+    write_bytes_field(&mut message, 11, &period_type);
+    write_varint_field(&mut message, 12, (interval_ms * 1_000_000.0) as u64);
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&message)?;
+    encoder.finish()
+}
+
+/// The string table of a pprof profile, which starts with the empty string.
+struct StringTable {
+    strings: Vec<String>,
+    indexes: HashMap<String, u64>,
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        StringTable {
+            strings: vec![String::new()],
+            indexes: HashMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl StringTable {
+    fn index(&mut self, s: &str) -> u64 {
+        if let Some(index) = self.indexes.get(s) {
+            return *index;
+        }
+        let index = self.strings.len() as u64;
+        self.strings.push(s.to_string());
+        self.indexes.insert(s.to_string(), index);
+        index
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use super::{collapsed_stacks, pprof_profile, speedscope_profile, ProfileFormat};
+    use crate::import::{collapsed, pprof, speedscope};
+    use crate::profile_export::folded_stacks;
+
+    /// The samples in the files in fixtures/convert, which all have the same
+    /// stacks, with their sample counts.
+    const FIXTURE_STACKS: [(&str, u64); 4] = [
+        ("main.main;main.parse", 2),
+        ("main.main;main.parse;main.readLine", 5),
+        ("main.main;main.render;runtime.mallocgc", 7),
+        ("runtime.goexit;main.worker", 3),
+    ];
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../fixtures/convert")
+            .join(name);
+        std::fs::read(path).unwrap()
+    }
+
+    /// The folded stacks of a processed profile, sorted, with their weights
+    /// divided by `unit`.
+    fn stacks(profile: &fxprof_processed_profile::Profile, unit: u64) -> Vec<(String, u64)> {
+        let profile = serde_json::to_value(profile).unwrap();
+        let mut stacks: Vec<(String, u64)> = folded_stacks(&profile, &HashMap::new(), false)
+            .into_iter()
+            .map(|(stack, weight)| (stack, weight / unit))
+            .collect();
+        stacks.sort_unstable();
+        stacks
+    }
+
+    fn expected_stacks() -> Vec<(String, u64)> {
+        FIXTURE_STACKS
+            .iter()
+            .map(|(stack, count)| (stack.to_string(), *count))
+            .collect()
+    }
+
+    #[test]
+    fn formats_from_file_names() {
+        let format = |name: &str| ProfileFormat::from_file_name(Path::new(name));
+        assert_eq!(format("out.json"), Some(ProfileFormat::Gecko));
+        assert_eq!(
+            format("out.speedscope.json"),
+            Some(ProfileFormat::Speedscope)
+        );
+        assert_eq!(format("cpu.pb.gz"), Some(ProfileFormat::Pprof));
+        assert_eq!(format("out.folded"), Some(ProfileFormat::Collapsed));
+        assert_eq!(format("out.data"), None);
+    }
+
+    #[test]
+    fn speedscope_frames_are_shared() {
+        let thread = |name: &str| {
+            json!({
+                "name": name,
+                "processName": "app",
+                "stringArray": ["main", "work"],
+                "funcTable": { "name": [0, 1] },
+                "frameTable": { "func": [0, 1], "address": [-1, -1] },
+                "stackTable": { "frame": [0, 1], "prefix": [null, 0] },
+                "samples": { "stack": [1, null, 0], "time": [0.0, 1.0, 2.0], "weight": null },
+            })
+        };
+        let profile = json!({ "threads": [thread("app"), thread("worker")] });
+        let speedscope = speedscope_profile(&profile, &HashMap::new());
+        assert_eq!(
+            speedscope["shared"]["frames"],
+            json!([{ "name": "main" }, { "name": "work" }])
+        );
+        assert_eq!(speedscope["profiles"][0]["name"], "app");
+        assert_eq!(speedscope["profiles"][1]["name"], "worker (app)");
+        assert_eq!(speedscope["profiles"][1]["samples"], json!([[0, 1], [0]]));
+        assert_eq!(speedscope["profiles"][1]["weights"], json!([1.0, 1.0]));
+    }
+
+    #[test]
+    fn collapsed_round_trip() {
+        let data = fixture("server.collapsed");
+        let profile = collapsed::convert(&data[..], "server").unwrap();
+        assert_eq!(stacks(&profile, 1), expected_stacks());
+        let profile = serde_json::to_value(&profile).unwrap();
+        assert_eq!(collapsed_stacks(&profile, &HashMap::new()), data);
+    }
+
+    #[test]
+    fn pprof_round_trip() {
+        let data = fixture("server.pb.gz");
+        assert!(pprof::is_pprof(&data));
+        // The CPU time is the default sample type. Each sample is 10ms, which
+        // is 10000 in the microseconds of the processed profile.
+        let profile = pprof::convert(&data, "server").unwrap();
+        assert_eq!(stacks(&profile, 10_000), expected_stacks());
+
+        let profile = serde_json::to_value(&profile).unwrap();
+        let exported = pprof_profile(&profile, &HashMap::new()).unwrap();
+        assert!(pprof::is_pprof(&exported));
+        let profile = pprof::convert(&exported, "server").unwrap();
+        assert_eq!(stacks(&profile, 10_000), expected_stacks());
+    }
+
+    #[test]
+    fn speedscope_round_trip() {
+        let data = fixture("server.speedscope.json");
+        assert!(speedscope::is_speedscope(&data));
+        let profile = speedscope::convert(&data[..], "server").unwrap();
+        assert_eq!(stacks(&profile, 1), expected_stacks());
+
+        let profile = serde_json::to_value(&profile).unwrap();
+        let exported = speedscope_profile(&profile, &HashMap::new()).to_string();
+        assert!(speedscope::is_speedscope(exported.as_bytes()));
+        let profile = speedscope::convert(exported.as_bytes(), "server").unwrap();
+        assert_eq!(stacks(&profile, 1), expected_stacks());
+    }
+
+    #[test]
+    fn other_files_are_not_pprof() {
+        assert!(!pprof::is_pprof(&fixture("server.collapsed")));
+        assert!(!pprof::is_pprof(&fixture("server.speedscope.json")));
+        assert!(!pprof::is_pprof(b"\n              libc.so.1`read+0x15\n"));
+        // Only the start of a compressed profile is needed to recognize it.
+        let data = fixture("server.pb.gz");
+        assert!(pprof::is_pprof(&data[..data.len() / 2]));
+    }
+}
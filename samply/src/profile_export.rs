@@ -0,0 +1,285 @@
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::profile_symbolication::{
+    frame_lib_addresses, json_array, lookup_symbols, profile_lib_ids, read_profile, AddressSymbol,
+};
+use crate::server::{symbol_manager_for_parsed_profile, SymbolDownloads, SymbolIdMatching};
+
+/// The name of the stack which the samples of the left-out stacks are added to.
+const OTHER_STACKS_NAME: &str = "[other stacks]";
+
+/// Pushes the captures of `samply record --every` to a continuous profiling
+/// backend, via the ingestion API of Grafana Pyroscope. The profiles are sent as
+/// symbolicated stacks in the "folded" format, one line per distinct stack.
+pub struct PyroscopeExporter {
+    /// The base URL of the server, e.g. http://localhost:4040.
+    pub url: String,
+    /// The application name, under which the profiles show up in the backend.
+    pub app_name: String,
+    pub token: Option<String>,
+    /// Only the stacks with the most samples are sent, so that long captures of
+    /// large programs stay within the backend's limits. The samples of the other
+    /// stacks are attributed to a single stack, so that the total stays right.
+    pub max_stacks: usize,
+    pub verbose: bool,
+    pub symbol_dirs: Vec<PathBuf>,
+    pub symbol_downloads: SymbolDownloads,
+}
+
+impl PyroscopeExporter {
+    /// Symbolicates the capture at `profile_path`, which was recorded between
+    /// `from` and `until`, and uploads it.
+    #[tokio::main]
+    pub async fn export(
+        &self,
+        profile_path: &Path,
+        from: SystemTime,
+        until: SystemTime,
+    ) -> Result<(), String> {
+        let profile = read_profile(profile_path)
+            .map_err(|err| format!("Could not read {profile_path:?}: {err}"))?;
+        let symbols = profile_symbols(
+            &profile,
+            self.verbose,
+            &self.symbol_dirs,
+            &self.symbol_downloads,
+        )
+        .await?;
+
+        let mut stacks = folded_stacks(&profile, &symbols, true);
+        downsample(&mut stacks, self.max_stacks);
+        let mut body = String::new();
+        for (stack, count) in &stacks {
+            let _ = writeln!(body, "{stack} {count}");
+        }
+
+        let interval_ms = profile
+            .pointer("/meta/interval")
+            .and_then(Value::as_f64)
+            .unwrap_or(1.0);
+        let url = format!("{}/ingest", self.url.trim_end_matches('/'));
+        let mut request = reqwest::Client::new()
+            .post(&url)
+            .query(&[
+                ("name", self.app_name.as_str()),
+                ("from", &unix_seconds(from).to_string()),
+                ("until", &unix_seconds(until).to_string()),
+                ("format", "folded"),
+                ("sampleRate", &format!("{}", (1000.0 / interval_ms).round())),
+                ("spyName", "samply"),
+                ("units", "samples"),
+                ("aggregationType", "sum"),
+            ])
+            .header("Content-Type", "text/plain");
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| format!("Could not export the profile to {url}: {err}"))?;
+        let status = response.status();
+        if !status.is_success() {
+            let response_text = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "The profiling backend responded with {status}: {response_text}"
+            ));
+        }
+        if self.verbose {
+            eprintln!(
+                "Exported {} stacks from {profile_path:?} to {url}.",
+                stacks.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Looks up the symbols for the native frames of all threads in the profile.
+pub async fn profile_symbols(
+    profile: &Value,
+    verbose: bool,
+    symbol_dirs: &[PathBuf],
+    symbol_downloads: &SymbolDownloads,
+) -> Result<HashMap<(usize, u32), AddressSymbol>, String> {
+    let mut addresses: Vec<(usize, u32)> = Vec::new();
+    for thread in json_array(profile, "threads") {
+        for (lib, address) in frame_lib_addresses(thread) {
+            addresses.extend(lib.zip(address));
+        }
+    }
+    addresses.sort_unstable();
+    addresses.dedup();
+    let symbol_manager = symbol_manager_for_parsed_profile(
+        profile,
+        verbose,
+        symbol_dirs,
+        SymbolIdMatching::default(),
+        symbol_downloads,
+    );
+    let libs = profile_lib_ids(profile);
+    lookup_symbols(&symbol_manager, &libs, &addresses).await
+}
+
+/// Returns the function name of each frame in the thread's frame table, from
+/// `symbols` for native frames which have a symbol, and from the thread's
+/// function table otherwise.
+pub fn frame_names(
+    profile: &Value,
+    thread: &Value,
+    symbols: &HashMap<(usize, u32), AddressSymbol>,
+) -> Vec<String> {
+    let strings = match profile.pointer("/shared/stringArray") {
+        Some(Value::Array(strings)) => strings.as_slice(),
+        _ => json_array(thread, "stringArray"),
+    };
+    let func_names = json_array(&thread["funcTable"], "name");
+    let frame_funcs = json_array(&thread["frameTable"], "func");
+    frame_lib_addresses(thread)
+        .zip(frame_funcs)
+        .map(|((lib, address), func)| {
+            if let Some(symbol) = lib.zip(address).and_then(|key| symbols.get(&key)) {
+                return symbol.function.clone();
+            }
+            func.as_u64()
+                .and_then(|func| func_names.get(func as usize))
+                .and_then(Value::as_u64)
+                .and_then(|name| strings.get(name as usize))
+                .and_then(Value::as_str)
+                .unwrap_or("<unknown>")
+                .to_string()
+        })
+        .collect()
+}
+
+/// Returns the number of samples (or the sample weight) of each distinct stack
+/// in the profile, as a semicolon-separated list of function names from the
+/// root to the leaf. With `with_process_names`, each stack starts with the
+/// name of its process, so that the processes of a capture can be told apart.
+/// Sample weights which are durations are counted in microseconds.
+pub fn folded_stacks(
+    profile: &Value,
+    symbols: &HashMap<(usize, u32), AddressSymbol>,
+    with_process_names: bool,
+) -> HashMap<String, u64> {
+    let mut stacks: HashMap<String, u64> = HashMap::new();
+    for thread in json_array(profile, "threads") {
+        let frame_names = frame_names(profile, thread, symbols);
+
+        let stack_frames = json_array(&thread["stackTable"], "frame");
+        let stack_prefixes = json_array(&thread["stackTable"], "prefix");
+        let process_name = thread["processName"]
+            .as_str()
+            .unwrap_or("<unknown process>");
+        let samples = &thread["samples"];
+        let weights = json_array(samples, "weight");
+        // Durations are in milliseconds in the profile.
+        let weight_scale = match samples["weightType"].as_str() {
+            Some("tracing-ms") => 1000.0,
+            _ => 1.0,
+        };
+        for (i, stack) in json_array(samples, "stack").iter().enumerate() {
+            let mut names = Vec::new();
+            let mut stack = stack.as_u64();
+            // Bound the walk in case the prefixes form a cycle.
+            while let Some(index) = stack.filter(|_| names.len() < stack_frames.len()) {
+                let index = index as usize;
+                let name = stack_frames
+                    .get(index)
+                    .and_then(Value::as_u64)
+                    .and_then(|frame| frame_names.get(frame as usize));
+                names.push(name.map_or("<unknown>", String::as_str));
+                stack = stack_prefixes.get(index).and_then(Value::as_u64);
+            }
+            if names.is_empty() {
+                continue;
+            }
+            if with_process_names {
+                names.push(process_name);
+            }
+            names.reverse();
+            // Semicolons separate the frames. The count comes after the last
+            // space, so spaces in names are fine.
+            let folded: Vec<String> = names.iter().map(|name| name.replace(';', ":")).collect();
+            let weight = weights
+                .get(i)
+                .and_then(Value::as_f64)
+                .map_or(1, |weight| (weight * weight_scale).round() as u64);
+            *stacks.entry(folded.join(";")).or_default() += weight;
+        }
+    }
+    stacks
+}
+
+/// Keeps the `max_stacks` stacks with the most samples, and adds up the samples
+/// of the others in a single stack.
+fn downsample(stacks: &mut HashMap<String, u64>, max_stacks: usize) {
+    if stacks.len() <= max_stacks {
+        return;
+    }
+    let mut sorted: Vec<(String, u64)> = stacks.drain().collect();
+    sorted.sort_by(|(a_name, a_count), (b_name, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_name.cmp(b_name))
+    });
+    let kept_count = max_stacks.saturating_sub(1);
+    let other_count: u64 = sorted[kept_count..].iter().map(|(_, count)| count).sum();
+    stacks.extend(sorted.into_iter().take(kept_count));
+    *stacks.entry(OTHER_STACKS_NAME.to_string()).or_default() += other_count;
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use std::collections::HashMap;
+
+    use super::{downsample, folded_stacks, OTHER_STACKS_NAME};
+
+    #[test]
+    fn stacks_are_folded() {
+        let profile = json!({
+            "threads": [{
+                "processName": "app",
+                "stringArray": ["main", "work; more", "0x1234"],
+                "funcTable": { "name": [0, 1, 2] },
+                "frameTable": { "func": [0, 1, 2], "address": [-1, -1, -1] },
+                "stackTable": { "frame": [0, 1, 2], "prefix": [null, 0, 0] },
+                "samples": { "stack": [1, 1, 2, 0], "weight": [1, 2, 1, 1] },
+            }],
+        });
+        let stacks = folded_stacks(&profile, &HashMap::new(), true);
+        let mut stacks: Vec<(String, u64)> = stacks.into_iter().collect();
+        stacks.sort();
+        assert_eq!(
+            stacks,
+            vec![
+                ("app;main".to_string(), 1),
+                ("app;main;0x1234".to_string(), 1),
+                ("app;main;work: more".to_string(), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn rare_stacks_are_merged() {
+        let mut stacks: HashMap<String, u64> = [("a", 5), ("b", 3), ("c", 2), ("d", 1)]
+            .into_iter()
+            .map(|(stack, count)| (stack.to_string(), count))
+            .collect();
+        downsample(&mut stacks, 3);
+        assert_eq!(stacks.len(), 3);
+        assert_eq!(stacks["a"], 5);
+        assert_eq!(stacks["b"], 3);
+        assert_eq!(stacks[OTHER_STACKS_NAME], 3);
+    }
+}
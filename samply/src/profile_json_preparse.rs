@@ -0,0 +1,1210 @@
+//! Preprocessing of profile JSON files before samply's commands look at them:
+//! processed profiles in older layouts are upgraded to the one samply reads,
+//! and the structure which samply relies on is checked, so that a broken
+//! profile gets an error which names the offending value, e.g.
+//!
+//! ```text
+//! threads[2].stackTable.prefix[15]: refers to stack 20, which doesn't come before stack 15
+//! ```
+//!
+//! instead of a panic or a silently empty result further down the line.
+//!
+//! Both of the Firefox Profiler's formats are understood: the processed format,
+//! which has `meta.preprocessedProfileVersion` and which samply writes, and the
+//! Gecko format that Firefox itself writes, whose tables have a `schema` and
+//! rows of `data`, and whose child processes are nested in `processes`. Gecko
+//! profiles are only checked, not upgraded: none of samply's commands which
+//! read profiles work on them, and the profiler brings them to its current
+//! version itself when it loads them.
+//!
+//! Files are accepted in the same variants as the profiler accepts them:
+//! gzipped, with a UTF-8 byte order mark, and with several profiles, either in
+//! an array or one after the other as JSON Lines.
+
+use flate2::read::GzDecoder;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{DeserializeOwned, IgnoredAny, MapAccess, SeqAccess};
+use serde::Deserializer;
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+use tempfile::NamedTempFile;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use crate::split_profiles::{is_same_recording, merge_profiles};
+
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Problems beyond the first one of a column are only counted, so that a
+/// profile with a million broken samples doesn't produce a million lines.
+const MAX_PROBLEMS_PER_COLUMN: usize = 1;
+
+/// A problem in the structure of a profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileProblem {
+    /// The path of the offending value, e.g. `threads[2].samples.stack[7]`.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ProfileProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Which of the Firefox Profiler's formats a profile is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileJsonFormat {
+    /// The processed format, with its `meta.preprocessedProfileVersion`.
+    Processed(u64),
+    /// The format which Gecko writes, with its `meta.version`.
+    Gecko(u64),
+}
+
+impl fmt::Display for ProfileJsonFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileJsonFormat::Processed(version) => {
+                write!(f, "processed profile, version {version}")
+            }
+            ProfileJsonFormat::Gecko(version) => write!(f, "Gecko profile, version {version}"),
+        }
+    }
+}
+
+/// The format of the profile, or `None` if it's not a profile in either of the
+/// Firefox Profiler's formats.
+pub fn profile_format(profile: &Value) -> Option<ProfileJsonFormat> {
+    let meta = profile.get("meta")?;
+    if let Some(version) = meta.get("preprocessedProfileVersion") {
+        return version.as_u64().map(ProfileJsonFormat::Processed);
+    }
+    meta.get("version")?.as_u64().map(ProfileJsonFormat::Gecko)
+}
+
+/// How the profiles are laid out in a profile JSON file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileJsonLayout {
+    /// A single profile object, as the profiler and samply write them.
+    Single,
+    /// An array of profiles.
+    Array,
+    /// Several profiles after each other, usually one per line.
+    Lines,
+}
+
+/// Opens a profile JSON file for reading. Gzipped files are recognized by their
+/// content rather than by their extension, and a byte order mark is skipped.
+pub fn open_profile_json(path: &Path) -> std::io::Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut reader: Box<dyn BufRead> = if reader.fill_buf()?.starts_with(GZIP_MAGIC) {
+        Box::new(BufReader::new(GzDecoder::new(reader)))
+    } else {
+        Box::new(reader)
+    };
+    if reader.fill_buf()?.starts_with(UTF8_BOM) {
+        reader.consume(UTF8_BOM.len());
+    }
+    Ok(reader)
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Reads all profiles in a profile JSON file, without looking at them.
+pub fn read_profile_jsons(path: &Path) -> std::io::Result<(Vec<Value>, ProfileJsonLayout)> {
+    let reader = open_profile_json(path)?;
+    let mut values = Vec::new();
+    for value in serde_json::Deserializer::from_reader(reader).into_iter::<Value>() {
+        values.push(value.map_err(|err| invalid_data(format!("Invalid JSON: {err}")))?);
+    }
+    if values.len() > 1 {
+        return Ok((values, ProfileJsonLayout::Lines));
+    }
+    match values.pop() {
+        Some(Value::Array(profiles)) if profiles.is_empty() => Err(invalid_data(
+            "The file has an empty list of profiles".to_string(),
+        )),
+        Some(Value::Array(profiles)) => Ok((profiles, ProfileJsonLayout::Array)),
+        Some(profile) => Ok((vec![profile], ProfileJsonLayout::Single)),
+        None => Err(invalid_data("The file is empty".to_string())),
+    }
+}
+
+/// Finds out how the profiles are laid out in a profile JSON file. The file is
+/// only scanned, none of it is kept in memory.
+fn scan_profile_json_layout(path: &Path) -> std::io::Result<ProfileJsonLayout> {
+    let mut reader = open_profile_json(path)?;
+    let starts_with_array = loop {
+        let buf = reader.fill_buf()?;
+        match buf.iter().position(|byte| !byte.is_ascii_whitespace()) {
+            Some(index) => break buf[index] == b'[',
+            None if buf.is_empty() => break false,
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    };
+    let mut count = 0;
+    for value in serde_json::Deserializer::from_reader(reader).into_iter::<IgnoredAny>() {
+        value.map_err(|err| invalid_data(format!("Invalid JSON: {err}")))?;
+        count += 1;
+    }
+    match count {
+        0 => Err(invalid_data("The file is empty".to_string())),
+        1 if starts_with_array => Ok(ProfileJsonLayout::Array),
+        1 => Ok(ProfileJsonLayout::Single),
+        _ => Ok(ProfileJsonLayout::Lines),
+    }
+}
+
+/// Reads a profile JSON file as a single profile. The profiles of a file with
+/// several processed profiles from the same recording are merged, like the
+/// per-process profiles of `samply record --split-processes`. Otherwise, only
+/// the first profile is used.
+pub fn read_profile_json(path: &Path) -> std::io::Result<Value> {
+    let (profiles, _) = read_profile_jsons(path)?;
+    combine_profiles(path, profiles)
+}
+
+fn combine_profiles(path: &Path, mut profiles: Vec<Value>) -> std::io::Result<Value> {
+    if profiles.len() == 1 {
+        return Ok(profiles.remove(0));
+    }
+    for profile in &mut profiles {
+        upgrade_profile(profile);
+    }
+    let mergeable = profiles.iter().all(|profile| {
+        matches!(
+            profile_format(profile),
+            Some(ProfileJsonFormat::Processed(_))
+        ) && is_same_recording(profile, &profiles[0])
+    });
+    if !mergeable {
+        eprintln!(
+            "{path:?} has {} profiles from different recordings, only using the first one.",
+            profiles.len()
+        );
+        return Ok(profiles.remove(0));
+    }
+    merge_profiles(profiles).map_err(|index| {
+        invalid_data(format!(
+            "Profile {index} can't be merged with the profiles before it"
+        ))
+    })
+}
+
+/// Reads the profiles in a profile JSON file as `T`, in any of the layouts of
+/// [`ProfileJsonLayout`]. The file is scanned rather than parsed into values,
+/// so only the parts of each profile which `T` keeps are held in memory.
+pub fn scan_profile_jsons<T: DeserializeOwned>(reader: impl Read) -> std::io::Result<Vec<T>> {
+    let mut profiles = Vec::new();
+    for values in serde_json::Deserializer::from_reader(reader).into_iter::<ProfileOrArray<T>>() {
+        profiles.extend(values?.0);
+    }
+    Ok(profiles)
+}
+
+/// A single profile, or an array of profiles.
+pub(crate) struct ProfileOrArray<T>(pub Vec<T>);
+
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for ProfileOrArray<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor<T>(PhantomData<T>);
+
+        impl<'de, T: serde::Deserialize<'de>> serde::de::Visitor<'de> for Visitor<T> {
+            type Value = ProfileOrArray<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a profile or an array of profiles")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                let profile = T::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(ProfileOrArray(vec![profile]))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut profiles = Vec::new();
+                while let Some(profile) = seq.next_element()? {
+                    profiles.push(profile);
+                }
+                Ok(ProfileOrArray(profiles))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor(PhantomData))
+    }
+}
+
+/// The lib lists of a profile: its own, those of the threads of older processed
+/// profiles, and those of the child processes of Gecko profiles.
+#[derive(Deserialize)]
+struct ProfileLibs {
+    #[serde(default)]
+    libs: Vec<Value>,
+    #[serde(default)]
+    threads: Vec<ThreadLibs>,
+    #[serde(default)]
+    processes: Vec<ProfileLibs>,
+}
+
+#[derive(Deserialize)]
+struct ThreadLibs {
+    #[serde(default)]
+    libs: Vec<Value>,
+}
+
+impl ProfileLibs {
+    fn collect_into(self, libs: &mut Vec<Value>) {
+        libs.extend(self.libs);
+        for thread in self.threads {
+            libs.extend(thread.libs);
+        }
+        for process in self.processes {
+            process.collect_into(libs);
+        }
+    }
+}
+
+/// Reads the libs of all profiles in a profile JSON file, including the ones
+/// of threads and child processes, without reading the rest of the profiles.
+/// A lib can be listed more than once.
+pub fn read_profile_libs(path: &Path) -> std::io::Result<Vec<Value>> {
+    let mut libs = Vec::new();
+    for profile in scan_profile_jsons::<ProfileLibs>(open_profile_json(path)?)? {
+        profile.collect_into(&mut libs);
+    }
+    Ok(libs)
+}
+
+/// Checks a profile JSON file for `samply load`. A file with a single profile
+/// is only scanned, and is loaded as it is. The profiler gets a single profile,
+/// so a file with several profiles is combined as by [`read_profile_json`] and
+/// written to a temporary file, which is returned.
+///
+/// Problems with the profile are printed as warnings, since the profiler may
+/// still be able to show it. Only a file which can't be opened is an error.
+pub fn prepare_profile_for_load(path: &Path) -> Result<Option<NamedTempFile>, String> {
+    let layout = match scan_profile_json_layout(path) {
+        Ok(layout) => layout,
+        Err(err) if err.kind() == ErrorKind::InvalidData => {
+            eprintln!("Warning: {path:?} doesn't look like a profile JSON file: {err}");
+            return Ok(None);
+        }
+        Err(err) => return Err(format!("Could not load {path:?}: {err}")),
+    };
+    if layout == ProfileJsonLayout::Single {
+        return Ok(None);
+    }
+    let profiles = match read_profile_jsons(path) {
+        Ok((profiles, _)) => profiles,
+        Err(err) => {
+            eprintln!("Warning: {path:?} doesn't look like a profile JSON file: {err}");
+            return Ok(None);
+        }
+    };
+    let mut profile = match combine_profiles(path, profiles) {
+        Ok(profile) => profile,
+        Err(err) => {
+            eprintln!("Warning: Could not combine the profiles in {path:?}: {err}");
+            return Ok(None);
+        }
+    };
+    if let Err(err) = preparse_profile(&mut profile) {
+        eprintln!("Warning: {path:?}: {err}");
+    }
+    let temp_file =
+        NamedTempFile::new().map_err(|err| format!("Could not create a temporary file: {err}"))?;
+    serde_json::to_writer(BufWriter::new(temp_file.as_file()), &profile)
+        .map_err(|err| format!("Could not write the combined profile: {err}"))?;
+    Ok(Some(temp_file))
+}
+
+/// Checks the profile file at `path` for `samply validate`, and prints the
+/// upgrades it needs and its problems, or a summary if it has none. Returns an
+/// error if the file can't be read or if the profile has problems.
+///
+/// The profiles of a file with several profiles are checked one by one, and
+/// their messages start with the profile's index in the file.
+pub fn validate_profile_file(path: &Path) -> Result<(), String> {
+    let (mut profiles, layout) =
+        read_profile_jsons(path).map_err(|err| format!("Could not read {path:?}: {err}"))?;
+    let mut problem_count = 0;
+    for (index, profile) in profiles.iter_mut().enumerate() {
+        let prefix = match layout {
+            ProfileJsonLayout::Single => String::new(),
+            ProfileJsonLayout::Array | ProfileJsonLayout::Lines => format!("Profile {index}: "),
+        };
+        for upgrade in upgrade_profile(profile) {
+            println!("{prefix}Upgrade: {upgrade}.");
+        }
+        let problems = validate_profile(profile);
+        for problem in &problems {
+            println!("{prefix}{problem}");
+        }
+        problem_count += problems.len();
+        if let (true, Some(format)) = (problems.is_empty(), profile_format(profile)) {
+            let name = match layout {
+                ProfileJsonLayout::Single => format!("{path:?}"),
+                ProfileJsonLayout::Array | ProfileJsonLayout::Lines => format!("Profile {index}"),
+            };
+            println!(
+                "{name} is a valid {format}, with {} threads and {} samples.",
+                thread_count(profile),
+                sample_count(profile)
+            );
+        }
+    }
+    match problem_count {
+        0 => Ok(()),
+        1 => Err(format!("{path:?} has 1 problem.")),
+        count => Err(format!("{path:?} has {count} problems.")),
+    }
+}
+
+/// Upgrades the profile and checks it, as done for every profile which samply
+/// reads. The error lists the first few problems.
+pub fn preparse_profile(profile: &mut Value) -> Result<(), String> {
+    upgrade_profile(profile);
+    let problems = validate_profile(profile);
+    let Some(first) = problems.first() else {
+        return Ok(());
+    };
+    let mut message = format!("Invalid profile: {first}");
+    if problems.len() > 1 {
+        message += &format!(
+            " (and {} more problems, run `samply validate` for the full list)",
+            problems.len() - 1
+        );
+    }
+    Err(message)
+}
+
+/// Brings a processed profile from an older layout to the one which samply's
+/// commands read, and returns a description of each upgrade that was applied.
+///
+/// The upgraded profile is still understood by the profiler in its original
+/// version, so `meta.preprocessedProfileVersion` is left alone and the profiler
+/// applies the rest of its own upgrades when it loads the profile. Gecko
+/// profiles are left to the profiler entirely.
+pub fn upgrade_profile(profile: &mut Value) -> Vec<String> {
+    let mut upgrades = Vec::new();
+    if !matches!(
+        profile_format(profile),
+        Some(ProfileJsonFormat::Processed(_))
+    ) {
+        return upgrades;
+    }
+    if share_thread_libs(profile) {
+        upgrades.push("Moved the lib lists of the threads into the profile's lib list".to_string());
+    }
+    upgrades
+}
+
+/// Older processed profiles have a lib list on each thread, which the thread's
+/// `resourceTable.lib` indexes into, and no lib list on the profile. Collects
+/// the libs of all threads into a profile-wide list and points the resources at
+/// it. Every thread gets the full list as its own list too, so that the indexes
+/// are right for both layouts.
+fn share_thread_libs(profile: &mut Value) -> bool {
+    if profile.get("libs").is_some() {
+        return false;
+    }
+    let Some(threads) = profile.get_mut("threads").and_then(Value::as_array_mut) else {
+        return false;
+    };
+    if !threads.iter().any(|thread| thread.get("libs").is_some()) {
+        return false;
+    }
+
+    let mut libs: Vec<Value> = Vec::new();
+    for thread in threads.iter_mut() {
+        let thread_libs = match thread.get("libs") {
+            Some(Value::Array(thread_libs)) => thread_libs.clone(),
+            _ => Vec::new(),
+        };
+        let lib_indexes: Vec<usize> = thread_libs
+            .into_iter()
+            .map(|lib| match libs.iter().position(|known| *known == lib) {
+                Some(index) => index,
+                None => {
+                    libs.push(lib);
+                    libs.len() - 1
+                }
+            })
+            .collect();
+        if let Some(Value::Array(resource_libs)) = thread.pointer_mut("/resourceTable/lib") {
+            for lib in resource_libs {
+                if let Some(&index) = lib.as_u64().and_then(|lib| lib_indexes.get(lib as usize)) {
+                    *lib = Value::from(index);
+                }
+            }
+        }
+    }
+    for thread in threads.iter_mut() {
+        if let Some(thread) = thread.as_object_mut() {
+            thread.insert("libs".to_string(), Value::Array(libs.clone()));
+        }
+    }
+    profile["libs"] = Value::Array(libs);
+    true
+}
+
+/// Checks the parts of the profile which samply and the profiler rely on, and
+/// returns all problems that were found.
+pub fn validate_profile(profile: &Value) -> Vec<ProfileProblem> {
+    let mut checker = Checker::default();
+    let format = profile_format(profile);
+    let Some(profile) = checker.object("(root)".to_string(), Some(profile)) else {
+        return checker.problems;
+    };
+    let Some(meta) = checker.object("meta".to_string(), profile.get("meta")) else {
+        return checker.problems;
+    };
+    match format {
+        Some(ProfileJsonFormat::Processed(_)) => checker.processed_profile(profile),
+        Some(ProfileJsonFormat::Gecko(_)) => checker.gecko_process("", profile),
+        None => {
+            let version = ["preprocessedProfileVersion", "version"]
+                .into_iter()
+                .find_map(|key| Some((key, meta.get(key)?)));
+            match version {
+                Some((key, version)) => checker.report(
+                    join("meta", key),
+                    format!("expected a version number, found {}", kind(version)),
+                ),
+                None => checker.report(
+                    "meta",
+                    "has neither preprocessedProfileVersion nor version, so this isn't a Firefox Profiler profile",
+                ),
+            }
+        }
+    }
+    checker.problems
+}
+
+/// The number of samples in the profile, including the samples of Gecko child
+/// processes.
+fn sample_count(profile: &Value) -> usize {
+    let threads = profile
+        .get("threads")
+        .and_then(Value::as_array)
+        .map_or(&[][..], Vec::as_slice);
+    let own_samples: usize = threads
+        .iter()
+        .map(|thread| {
+            let samples = &thread["samples"];
+            samples["length"]
+                .as_u64()
+                .map(|length| length as usize)
+                .or_else(|| samples["data"].as_array().map(Vec::len))
+                .or_else(|| samples["stack"].as_array().map(Vec::len))
+                .unwrap_or(0)
+        })
+        .sum();
+    let child_samples: usize = profile
+        .get("processes")
+        .and_then(Value::as_array)
+        .map_or(0, |processes| processes.iter().map(sample_count).sum());
+    own_samples + child_samples
+}
+
+/// The thread count of the profile, including the threads of Gecko child
+/// processes.
+fn thread_count(profile: &Value) -> usize {
+    let own_threads = profile
+        .get("threads")
+        .and_then(Value::as_array)
+        .map_or(0, Vec::len);
+    let child_threads: usize = profile
+        .get("processes")
+        .and_then(Value::as_array)
+        .map_or(0, |processes| processes.iter().map(thread_count).sum());
+    own_threads + child_threads
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+/// What the entries of an index column refer to.
+struct Target<'a> {
+    /// The name of one entry, e.g. "stack".
+    name: &'a str,
+    /// The path of the referenced table or array, e.g. "threads[0].stackTable".
+    path: &'a str,
+    len: usize,
+    /// Whether null is allowed, for "no entry".
+    nullable: bool,
+    /// Whether -1 is allowed, for "no entry".
+    minus_one: bool,
+}
+
+#[derive(Default)]
+struct Checker {
+    problems: Vec<ProfileProblem>,
+}
+
+impl Checker {
+    fn report(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.problems.push(ProfileProblem {
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    fn object<'a>(
+        &mut self,
+        path: String,
+        value: Option<&'a Value>,
+    ) -> Option<&'a Map<String, Value>> {
+        match value {
+            Some(Value::Object(object)) => Some(object),
+            Some(other) => {
+                self.report(path, format!("expected an object, found {}", kind(other)));
+                None
+            }
+            None => {
+                self.report(path, "missing");
+                None
+            }
+        }
+    }
+
+    fn array<'a>(&mut self, path: String, value: Option<&'a Value>) -> Option<&'a [Value]> {
+        match value {
+            Some(Value::Array(array)) => Some(array),
+            Some(other) => {
+                self.report(path, format!("expected an array, found {}", kind(other)));
+                None
+            }
+            None => {
+                self.report(path, "missing");
+                None
+            }
+        }
+    }
+
+    fn string_array(&mut self, path: String, value: Option<&Value>) -> Option<usize> {
+        let strings = self.array(path.clone(), value)?;
+        if let Some((index, other)) = strings.iter().enumerate().find(|(_, s)| !s.is_string()) {
+            self.report(
+                format!("{path}[{index}]"),
+                format!("expected a string, found {}", kind(other)),
+            );
+        }
+        Some(strings.len())
+    }
+
+    fn libs(&mut self, path: &str, profile: &Map<String, Value>) -> usize {
+        let path = join(path, "libs");
+        let Some(libs) = self.array(path.clone(), profile.get("libs")) else {
+            return 0;
+        };
+        for (index, lib) in libs.iter().enumerate() {
+            let lib_path = format!("{path}[{index}]");
+            let Some(lib) = self.object(lib_path.clone(), Some(lib)) else {
+                continue;
+            };
+            for key in ["debugName", "breakpadId"] {
+                match lib.get(key) {
+                    Some(Value::String(_)) => {}
+                    Some(other) => self.report(
+                        join(&lib_path, key),
+                        format!("expected a string, found {}", kind(other)),
+                    ),
+                    None => self.report(join(&lib_path, key), "missing"),
+                }
+            }
+        }
+        libs.len()
+    }
+
+    /// Checks that every entry of an index column refers to an existing entry
+    /// of the target. `entry_path` is the path of the entry in the given row.
+    fn indexes(
+        &mut self,
+        column_path: &str,
+        entry_path: impl Fn(usize) -> String,
+        entries: impl Iterator<Item = (usize, Value)>,
+        target: Target,
+    ) {
+        let mut problem_count = 0;
+        for (index, entry) in entries {
+            let message = match &entry {
+                Value::Null if target.nullable => continue,
+                Value::Number(n) if target.minus_one && n.as_i64() == Some(-1) => continue,
+                Value::Number(n) => match n.as_u64() {
+                    Some(i) if (i as usize) < target.len => continue,
+                    Some(i) => format!(
+                        "refers to {} {i}, but {} only has {}",
+                        target.name, target.path, target.len
+                    ),
+                    None => format!("expected a {} index, found {n}", target.name),
+                },
+                other => format!("expected a {} index, found {}", target.name, kind(other)),
+            };
+            problem_count += 1;
+            if problem_count <= MAX_PROBLEMS_PER_COLUMN {
+                self.report(entry_path(index), message);
+            }
+        }
+        if problem_count > MAX_PROBLEMS_PER_COLUMN {
+            self.report(
+                column_path,
+                format!(
+                    "has {} more entries which refer to a missing {}",
+                    problem_count - MAX_PROBLEMS_PER_COLUMN,
+                    target.name
+                ),
+            );
+        }
+    }
+
+    /// Checks an index column of a processed table.
+    fn column_indexes(
+        &mut self,
+        path: &str,
+        entries: impl Iterator<Item = (usize, Value)>,
+        target: Target,
+    ) {
+        self.indexes(path, |index| format!("{path}[{index}]"), entries, target);
+    }
+
+    /// Checks an index column of a Gecko table. A row which is too short has
+    /// null in the missing columns, as in JavaScript.
+    fn row_indexes(&mut self, table_path: &str, column: usize, rows: &[Value], target: Target) {
+        let entries = rows
+            .iter()
+            .map(|row| row.get(column).cloned().unwrap_or(Value::Null))
+            .enumerate();
+        self.indexes(
+            &format!("{table_path}.data[*][{column}]"),
+            |index| format!("{table_path}.data[{index}][{column}]"),
+            entries,
+            target,
+        );
+    }
+
+    fn processed_profile(&mut self, profile: &Map<String, Value>) {
+        let lib_count = self.libs("", profile);
+        let shared_strings = match profile.get("shared") {
+            Some(shared) if shared.get("stringArray").is_some() => {
+                self.string_array("shared.stringArray".to_string(), shared.get("stringArray"))
+            }
+            _ => None,
+        };
+        let Some(threads) = self.array("threads".to_string(), profile.get("threads")) else {
+            return;
+        };
+        for (index, thread) in threads.iter().enumerate() {
+            let path = format!("threads[{index}]");
+            let Some(thread) = self.object(path.clone(), Some(thread)) else {
+                continue;
+            };
+            let string_count = match shared_strings {
+                Some(count) => Some(count),
+                None if thread.get("stringArray").is_none() => {
+                    self.report(
+                        join(&path, "stringArray"),
+                        "missing, and the profile has no shared.stringArray either",
+                    );
+                    None
+                }
+                None => self.string_array(join(&path, "stringArray"), thread.get("stringArray")),
+            };
+            self.processed_thread(&path, thread, string_count, lib_count);
+        }
+    }
+
+    /// Checks a table of the processed format, which is an object with a column
+    /// array per field and a `length`, and returns its length.
+    fn processed_table<'a>(
+        &mut self,
+        path: &str,
+        thread: &'a Map<String, Value>,
+        name: &str,
+        columns: &[&str],
+    ) -> Option<(String, &'a Map<String, Value>, usize)> {
+        let table_path = join(path, name);
+        let table = self.object(table_path.clone(), thread.get(name))?;
+        for column in columns {
+            self.array(join(&table_path, column), table.get(*column));
+        }
+        let length = match table.get("length") {
+            Some(length) => match length.as_u64() {
+                Some(length) => length as usize,
+                None => {
+                    self.report(
+                        join(&table_path, "length"),
+                        format!("expected a number, found {}", kind(length)),
+                    );
+                    return None;
+                }
+            },
+            None => columns
+                .iter()
+                .find_map(|column| table.get(*column)?.as_array().map(Vec::len))
+                .unwrap_or(0),
+        };
+        for (column, value) in table {
+            if let Value::Array(entries) = value {
+                if entries.len() != length {
+                    self.report(
+                        join(&table_path, column),
+                        format!(
+                            "has {} entries, but the table's length is {length}",
+                            entries.len()
+                        ),
+                    );
+                }
+            }
+        }
+        Some((table_path, table, length))
+    }
+
+    fn processed_thread(
+        &mut self,
+        path: &str,
+        thread: &Map<String, Value>,
+        string_count: Option<usize>,
+        lib_count: usize,
+    ) {
+        let samples = self.processed_table(path, thread, "samples", &["stack"]);
+        let stacks = self.processed_table(path, thread, "stackTable", &["frame", "prefix"]);
+        let frames = self.processed_table(path, thread, "frameTable", &["address", "func"]);
+        let funcs = self.processed_table(path, thread, "funcTable", &["name", "resource"]);
+        let resources = self.processed_table(path, thread, "resourceTable", &["lib"]);
+
+        fn column<'a>(
+            table: &'a Map<String, Value>,
+            column: &str,
+        ) -> impl Iterator<Item = (usize, Value)> + 'a {
+            table
+                .get(column)
+                .and_then(Value::as_array)
+                .map_or(&[][..], Vec::as_slice)
+                .iter()
+                .cloned()
+                .enumerate()
+        }
+        let target = |name, path, len| Target {
+            name,
+            path,
+            len,
+            nullable: true,
+            minus_one: false,
+        };
+
+        if let Some((samples_path, samples, _)) = &samples {
+            if !samples.contains_key("time") && !samples.contains_key("timeDeltas") {
+                self.report(samples_path.clone(), "has neither time nor timeDeltas");
+            }
+            if let Some((stacks_path, _, stack_count)) = &stacks {
+                self.column_indexes(
+                    &join(samples_path, "stack"),
+                    column(samples, "stack"),
+                    target("stack", stacks_path, *stack_count),
+                );
+            }
+        }
+        if let Some((stacks_path, stacks, _)) = &stacks {
+            if let Some((frames_path, _, frame_count)) = &frames {
+                self.column_indexes(
+                    &join(stacks_path, "frame"),
+                    column(stacks, "frame"),
+                    Target {
+                        nullable: false,
+                        ..target("frame", frames_path, *frame_count)
+                    },
+                );
+            }
+            // The front-end relies on a stack's prefix coming before the stack.
+            let prefix_path = join(stacks_path, "prefix");
+            for (index, prefix) in column(stacks, "prefix") {
+                if let Some(prefix) = prefix.as_u64().filter(|prefix| *prefix as usize >= index) {
+                    self.report(
+                        format!("{prefix_path}[{index}]"),
+                        format!(
+                            "refers to stack {prefix}, which doesn't come before stack {index}"
+                        ),
+                    );
+                    break;
+                }
+            }
+            self.column_indexes(
+                &prefix_path,
+                column(stacks, "prefix"),
+                target("stack", stacks_path, usize::MAX),
+            );
+        }
+        if let Some((frames_path, frames, _)) = &frames {
+            if let Some((funcs_path, _, func_count)) = &funcs {
+                self.column_indexes(
+                    &join(frames_path, "func"),
+                    column(frames, "func"),
+                    Target {
+                        nullable: false,
+                        ..target("function", funcs_path, *func_count)
+                    },
+                );
+            }
+        }
+        if let Some((funcs_path, funcs, _)) = &funcs {
+            if let Some(string_count) = string_count {
+                self.column_indexes(
+                    &join(funcs_path, "name"),
+                    column(funcs, "name"),
+                    Target {
+                        nullable: false,
+                        ..target("string", "the string array", string_count)
+                    },
+                );
+            }
+            if let Some((resources_path, _, resource_count)) = &resources {
+                self.column_indexes(
+                    &join(funcs_path, "resource"),
+                    column(funcs, "resource"),
+                    Target {
+                        minus_one: true,
+                        ..target("resource", resources_path, *resource_count)
+                    },
+                );
+            }
+        }
+        if let Some((resources_path, resources, _)) = &resources {
+            self.column_indexes(
+                &join(resources_path, "lib"),
+                column(resources, "lib"),
+                target("lib", "libs", lib_count),
+            );
+        }
+    }
+
+    /// Checks a process of a Gecko profile, with its threads and its child
+    /// processes.
+    fn gecko_process(&mut self, path: &str, process: &Map<String, Value>) {
+        self.libs(path, process);
+        if let Some(threads) = self.array(join(path, "threads"), process.get("threads")) {
+            for (index, thread) in threads.iter().enumerate() {
+                let thread_path = join(path, &format!("threads[{index}]"));
+                if let Some(thread) = self.object(thread_path.clone(), Some(thread)) {
+                    self.gecko_thread(&thread_path, thread);
+                }
+            }
+        }
+        if let Some(processes) = process.get("processes") {
+            let processes_path = join(path, "processes");
+            let Some(processes) = self.array(processes_path.clone(), Some(processes)) else {
+                return;
+            };
+            for (index, child) in processes.iter().enumerate() {
+                let child_path = format!("{processes_path}[{index}]");
+                if let Some(child) = self.object(child_path.clone(), Some(child)) {
+                    self.gecko_process(&child_path, child);
+                }
+            }
+        }
+    }
+
+    /// Checks a table of the Gecko format, which has a `schema` that maps the
+    /// column names to indexes and a `data` array of rows, and returns its
+    /// column indexes and rows.
+    fn gecko_table<'a>(
+        &mut self,
+        path: &str,
+        thread: &'a Map<String, Value>,
+        name: &str,
+        columns: &[&str],
+    ) -> Option<(String, Vec<usize>, &'a [Value])> {
+        let table_path = join(path, name);
+        let table = self.object(table_path.clone(), thread.get(name))?;
+        let schema = self.object(join(&table_path, "schema"), table.get("schema"))?;
+        let mut column_indexes = Vec::new();
+        for column in columns {
+            match schema.get(*column).and_then(Value::as_u64) {
+                Some(index) => column_indexes.push(index as usize),
+                None => {
+                    self.report(
+                        join(&table_path, "schema"),
+                        format!("has no {column} column"),
+                    );
+                    return None;
+                }
+            }
+        }
+        let rows = self.array(join(&table_path, "data"), table.get("data"))?;
+        if let Some((index, row)) = rows.iter().enumerate().find(|(_, row)| !row.is_array()) {
+            self.report(
+                format!("{table_path}.data[{index}]"),
+                format!("expected an array, found {}", kind(row)),
+            );
+            return None;
+        }
+        Some((table_path, column_indexes, rows))
+    }
+
+    fn gecko_thread(&mut self, path: &str, thread: &Map<String, Value>) {
+        let string_count = self.string_array(join(path, "stringTable"), thread.get("stringTable"));
+        let samples = self.gecko_table(path, thread, "samples", &["stack"]);
+        let stacks = self.gecko_table(path, thread, "stackTable", &["prefix", "frame"]);
+        let frames = self.gecko_table(path, thread, "frameTable", &["location"]);
+
+        let target = |name, path, len, nullable| Target {
+            name,
+            path,
+            len,
+            nullable,
+            minus_one: false,
+        };
+
+        if let (
+            Some((samples_path, sample_columns, sample_rows)),
+            Some((stacks_path, _, stack_rows)),
+        ) = (&samples, &stacks)
+        {
+            self.row_indexes(
+                samples_path,
+                sample_columns[0],
+                sample_rows,
+                target("stack", stacks_path, stack_rows.len(), true),
+            );
+        }
+        if let Some((stacks_path, stack_columns, stack_rows)) = &stacks {
+            for (index, row) in stack_rows.iter().enumerate() {
+                let prefix = row.get(stack_columns[0]).and_then(Value::as_u64);
+                if let Some(prefix) = prefix.filter(|prefix| *prefix as usize >= index) {
+                    self.report(
+                        format!("{stacks_path}.data[{index}][{}]", stack_columns[0]),
+                        format!(
+                            "refers to stack {prefix}, which doesn't come before stack {index}"
+                        ),
+                    );
+                    break;
+                }
+            }
+            self.row_indexes(
+                stacks_path,
+                stack_columns[0],
+                stack_rows,
+                target("stack", stacks_path, usize::MAX, true),
+            );
+            if let Some((frames_path, _, frame_rows)) = &frames {
+                self.row_indexes(
+                    stacks_path,
+                    stack_columns[1],
+                    stack_rows,
+                    target("frame", frames_path, frame_rows.len(), false),
+                );
+            }
+        }
+        if let (Some((frames_path, frame_columns, frame_rows)), Some(string_count)) =
+            (&frames, string_count)
+        {
+            self.row_indexes(
+                frames_path,
+                frame_columns[0],
+                frame_rows,
+                target("string", "the string table", string_count, false),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{
+        CategoryHandle, CpuDelta, Frame, FrameFlags, FrameInfo, Profile, ReferenceTimestamp,
+        SamplingInterval, Timestamp,
+    };
+    use serde_json::{json, Value};
+
+    use std::io::Write;
+
+    use super::{
+        prepare_profile_for_load, read_profile_json, read_profile_jsons, read_profile_libs,
+        upgrade_profile, validate_profile, ProfileJsonLayout,
+    };
+
+    fn test_profile() -> Value {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let start = Timestamp::from_millis_since_reference(0.0);
+        let process = profile.add_process("test", 1, start);
+        let thread = profile.add_thread(process, 1, start, true);
+        let frames = ["main", "work"].map(|name| FrameInfo {
+            frame: Frame::Label(profile.intern_string(name)),
+            category_pair: CategoryHandle::OTHER.into(),
+            flags: FrameFlags::empty(),
+        });
+        profile.add_sample(thread, start, frames.into_iter(), CpuDelta::ZERO, 1);
+        serde_json::to_value(&profile).unwrap()
+    }
+
+    #[test]
+    fn written_profiles_are_valid() {
+        let mut profile = test_profile();
+        assert_eq!(upgrade_profile(&mut profile), Vec::<String>::new());
+        assert_eq!(validate_profile(&profile), vec![]);
+
+        profile["threads"][0]["stackTable"]["prefix"][0] = json!(1);
+        profile["threads"][0]["samples"]["stack"][0] = json!(7);
+        let problems: Vec<String> = validate_profile(&profile)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "threads[0].samples.stack[0]: refers to stack 7, but threads[0].stackTable only has 2",
+                "threads[0].stackTable.prefix[0]: refers to stack 1, which doesn't come before stack 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_profile_files() {
+        let profile = test_profile().to_string();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(&mut file, Default::default());
+        write!(encoder, "\u{feff}{profile}\n{profile}\n").unwrap();
+        encoder.finish().unwrap();
+        let (profiles, layout) = read_profile_jsons(file.path()).unwrap();
+        assert_eq!((profiles.len(), layout), (2, ProfileJsonLayout::Lines));
+        let merged = read_profile_json(file.path()).unwrap();
+        assert_eq!(merged["threads"].as_array().unwrap().len(), 2);
+        assert_eq!(validate_profile(&merged), vec![]);
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "[{profile}]").unwrap();
+        let (profiles, layout) = read_profile_jsons(file.path()).unwrap();
+        assert_eq!((profiles.len(), layout), (1, ProfileJsonLayout::Array));
+    }
+
+    #[test]
+    fn load_only_rewrites_multi_profile_files() {
+        let profile = test_profile().to_string();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "\n{profile}").unwrap();
+        assert!(prepare_profile_for_load(file.path()).unwrap().is_none());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "{{\"meta\": ").unwrap();
+        assert!(prepare_profile_for_load(file.path()).unwrap().is_none());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, " [{profile}, {profile}]").unwrap();
+        let combined = prepare_profile_for_load(file.path()).unwrap().unwrap();
+        let (profiles, layout) = read_profile_jsons(combined.path()).unwrap();
+        assert_eq!((profiles.len(), layout), (1, ProfileJsonLayout::Single));
+        assert_eq!(profiles[0]["threads"].as_array().unwrap().len(), 2);
+
+        assert!(prepare_profile_for_load(&file.path().with_extension("missing")).is_err());
+    }
+
+    #[test]
+    fn libs_are_scanned() {
+        let lib = |name: &str| json!({ "debugName": name, "breakpadId": "0" });
+        let processed = json!({
+            "meta": { "preprocessedProfileVersion": 40 },
+            "libs": [lib("a")],
+            "threads": [{ "libs": [lib("b")], "samples": { "length": 0 } }],
+        });
+        let gecko = json!({
+            "meta": { "version": 27 },
+            "libs": [lib("c")],
+            "threads": [],
+            "processes": [{ "libs": [lib("d")], "threads": [] }],
+        });
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "{processed}\n[{gecko}]\n").unwrap();
+        let libs = read_profile_libs(file.path()).unwrap();
+        assert_eq!(libs, [lib("a"), lib("b"), lib("c"), lib("d")]);
+    }
+
+    #[test]
+    fn thread_libs_are_shared() {
+        let lib = |name: &str| json!({ "debugName": name, "breakpadId": "0" });
+        let thread = |libs: Vec<serde_json::Value>, resource_libs: Vec<u64>| {
+            json!({
+                "libs": libs,
+                "stringArray": [],
+                "samples": { "length": 0, "stack": [], "time": [] },
+                "stackTable": { "length": 0, "frame": [], "prefix": [] },
+                "frameTable": { "length": 0, "address": [], "func": [] },
+                "funcTable": { "length": 0, "name": [], "resource": [] },
+                "resourceTable": { "length": resource_libs.len(), "lib": resource_libs },
+            })
+        };
+        let mut profile = json!({
+            "meta": { "preprocessedProfileVersion": 40 },
+            "threads": [
+                thread(vec![lib("a"), lib("b")], vec![1, 0]),
+                thread(vec![lib("b"), lib("c")], vec![1, 0]),
+            ],
+        });
+        assert_eq!(upgrade_profile(&mut profile).len(), 1);
+        assert_eq!(profile["libs"], json!([lib("a"), lib("b"), lib("c")]));
+        assert_eq!(profile["threads"][0]["resourceTable"]["lib"], json!([1, 0]));
+        assert_eq!(profile["threads"][1]["resourceTable"]["lib"], json!([2, 1]));
+        assert_eq!(profile["threads"][1]["libs"], profile["libs"]);
+        assert_eq!(validate_profile(&profile), vec![]);
+    }
+
+    #[test]
+    fn gecko_profiles() {
+        let mut profile = json!({
+            "meta": { "version": 27 },
+            "libs": [],
+            "threads": [{
+                "stringTable": ["main", "0x1234"],
+                "samples": { "schema": { "stack": 0, "time": 1 }, "data": [[1, 0.5], [null, 1.5]] },
+                "stackTable": { "schema": { "prefix": 0, "frame": 1 }, "data": [[null, 0], [0, 1]] },
+                "frameTable": { "schema": { "location": 0 }, "data": [[0], [1]] },
+            }],
+            "processes": [],
+        });
+        assert_eq!(validate_profile(&profile), vec![]);
+
+        profile["processes"] = json!([{ "libs": [], "threads": [{ "stringTable": [] }] }]);
+        let problems: Vec<String> = validate_profile(&profile)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "processes[0].threads[0].samples: missing",
+                "processes[0].threads[0].stackTable: missing",
+                "processes[0].threads[0].frameTable: missing",
+            ]
+        );
+    }
+}
@@ -0,0 +1,498 @@
+use serde_json::{json, Map, Value};
+use wholesym::SymbolManager;
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::profile_json_preparse::{preparse_profile, read_profile_json};
+use crate::server::{symbol_manager_for_parsed_profile, SymbolDownloads, SymbolIdMatching};
+use crate::split_profiles::MANIFEST_FILE_NAME;
+
+/// Symbolicates a profile in the Firefox Profiler's processed format, for example
+/// one which was recorded on a different machine, and writes a symbolicated copy
+/// to `output`.
+///
+/// The frames of each thread are looked up with the same symbol sources as the
+/// server uses, and every frame which has a symbol gets a function with that name.
+/// Inlined frames are not expanded.
+#[tokio::main]
+pub async fn symbolicate_profile_file(
+    input: &Path,
+    output: &Path,
+    verbose: bool,
+    symbol_dirs: &[PathBuf],
+    id_matching: SymbolIdMatching,
+    symbol_downloads: &SymbolDownloads,
+) -> Result<(), String> {
+    let mut profile =
+        read_profile(input).map_err(|err| format!("Could not read {input:?}: {err}"))?;
+    if profile
+        .pointer("/meta/preprocessedProfileVersion")
+        .is_none()
+    {
+        return Err(format!(
+            "{input:?} is not a processed profile. Only profiles in the Firefox Profiler's processed format can be symbolicated."
+        ));
+    }
+
+    let symbol_manager = symbol_manager_for_parsed_profile(
+        &profile,
+        verbose,
+        symbol_dirs,
+        id_matching,
+        symbol_downloads,
+    );
+
+    let libs = profile_lib_ids(&profile);
+
+    // Gather the addresses of all frames, per lib.
+    let mut addresses: Vec<(usize, u32)> = Vec::new();
+    for thread in json_array(&profile, "threads") {
+        for (lib, address) in frame_lib_addresses(thread) {
+            addresses.extend(lib.zip(address));
+        }
+    }
+    addresses.sort_unstable();
+    addresses.dedup();
+    if addresses.is_empty() {
+        eprintln!("The profile has no native frames to symbolicate.");
+    }
+
+    let symbols = lookup_symbols(&symbol_manager, &libs, &addresses).await?;
+
+    let shared_string_array = profile.pointer("/shared/stringArray").is_some();
+    let mut threads = match profile.get_mut("threads") {
+        Some(Value::Array(threads)) => std::mem::take(threads),
+        _ => Vec::new(),
+    };
+    for thread in &mut threads {
+        let string_array = if shared_string_array {
+            profile.pointer_mut("/shared/stringArray")
+        } else {
+            thread.get_mut("stringArray")
+        };
+        let mut strings = match string_array {
+            Some(Value::Array(strings)) => std::mem::take(strings),
+            _ => continue,
+        };
+        apply_symbols_to_thread(thread, &symbols, &mut strings);
+        let string_array = if shared_string_array {
+            profile.pointer_mut("/shared/stringArray")
+        } else {
+            thread.get_mut("stringArray")
+        };
+        if let Some(string_array) = string_array {
+            *string_array = Value::Array(strings);
+        }
+    }
+    profile["threads"] = Value::Array(threads);
+    if let Some(meta) = profile.get_mut("meta").and_then(Value::as_object_mut) {
+        meta.insert("symbolicated".to_string(), Value::Bool(true));
+    }
+
+    let file = File::create(output).map_err(|err| format!("Could not create {output:?}: {err}"))?;
+    serde_json::to_writer(BufWriter::new(file), &profile)
+        .map_err(|err| format!("Could not write {output:?}: {err}"))?;
+    eprintln!(
+        "Symbolicated {} of {} addresses.",
+        symbols.len(),
+        addresses.len()
+    );
+    Ok(())
+}
+
+/// Symbolicates a freshly recorded profile in place, so that it can be viewed
+/// on a machine which doesn't have the recording machine's binaries. `path` can
+/// also be a directory of per-process profiles.
+pub fn symbolicate_saved_profile(
+    path: &Path,
+    verbose: bool,
+    symbol_dirs: &[PathBuf],
+    symbol_downloads: &SymbolDownloads,
+) -> Result<(), String> {
+    if !path.is_dir() {
+        return symbolicate_profile_file(
+            path,
+            path,
+            verbose,
+            symbol_dirs,
+            SymbolIdMatching::default(),
+            symbol_downloads,
+        );
+    }
+    let entries =
+        std::fs::read_dir(path).map_err(|err| format!("Could not read {path:?}: {err}"))?;
+    for entry in entries.flatten() {
+        let file = entry.path();
+        if file.extension() == Some(OsStr::new("json"))
+            && file.file_name() != Some(OsStr::new(MANIFEST_FILE_NAME))
+        {
+            symbolicate_profile_file(
+                &file,
+                &file,
+                verbose,
+                symbol_dirs,
+                SymbolIdMatching::default(),
+                symbol_downloads,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a profile JSON file, in any of the variants which the profiler accepts,
+/// see [`read_profile_json`]. Profiles in older layouts are upgraded, and
+/// profiles with a broken structure are rejected with an error which names the
+/// offending value.
+pub fn read_profile(path: &Path) -> std::io::Result<Value> {
+    let mut profile = read_profile_json(path)?;
+    preparse_profile(&mut profile)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    Ok(profile)
+}
+
+/// The debug name and breakpad ID of each lib in the profile, in the order of
+/// the profile's lib list.
+pub fn profile_lib_ids(profile: &Value) -> Vec<(String, String)> {
+    json_array(profile, "libs")
+        .iter()
+        .map(|lib| {
+            let debug_name = lib.get("debugName").and_then(Value::as_str).unwrap_or("");
+            let breakpad_id = lib.get("breakpadId").and_then(Value::as_str).unwrap_or("");
+            (debug_name.to_string(), breakpad_id.to_string())
+        })
+        .collect()
+}
+
+/// Looks up the symbols for the given (lib index, address) pairs with the
+/// symbolication API. Addresses without a symbol are missing from the result.
+pub async fn lookup_symbols(
+    symbol_manager: &SymbolManager,
+    libs: &[(String, String)],
+    addresses: &[(usize, u32)],
+) -> Result<HashMap<(usize, u32), AddressSymbol>, String> {
+    let request = json!({
+        "jobs": [{
+            "memoryMap": libs,
+            "stacks": [addresses.iter().map(|(lib, address)| json!([lib, address])).collect::<Vec<_>>()],
+        }]
+    });
+    let response = symbol_manager
+        .query_json_api("/symbolicate/v5", &request.to_string())
+        .await;
+    parse_symbolication_response(&response, addresses)
+}
+
+/// The symbol for an address, from the symbolication API response.
+pub struct AddressSymbol {
+    pub function: String,
+    /// The lib-relative address of the function's first instruction.
+    pub function_address: Option<u32>,
+    pub function_size: Option<u32>,
+    pub file: Option<String>,
+    pub line: Option<u64>,
+    /// The file and line of the code at the address. For inlined code, this is
+    /// in the innermost inlined function, whereas `file` and `line` are in the
+    /// outer function.
+    pub source_location: Option<(String, u64)>,
+}
+
+/// The names of the errors for libraries in universal binaries whose
+/// architecture couldn't be determined, as the symbolication API reports them.
+const MULTI_ARCH_ERRORS: &[&str] = &[
+    "NoDisambiguatorForFatArchive",
+    "NoMatchMultiArch",
+    "EmptyFatArchive",
+];
+
+/// Prints the errors for libraries in universal binaries whose architecture
+/// couldn't be determined. Other errors, e.g. for missing files, are common and
+/// not worth mentioning.
+fn warn_about_multi_arch_errors(response: &Value) {
+    for (module, message) in multi_arch_errors(response) {
+        eprintln!("Could not get symbols for {module}: {message}");
+    }
+}
+
+fn multi_arch_errors(response: &Value) -> Vec<(&str, &str)> {
+    let Some(module_errors) = response
+        .pointer("/results/0/module_errors")
+        .and_then(Value::as_object)
+    else {
+        return Vec::new();
+    };
+    let mut multi_arch_errors = Vec::new();
+    for (module, errors) in module_errors {
+        for error in errors.as_array().map_or(&[][..], Vec::as_slice) {
+            let name = error.get("name").and_then(Value::as_str).unwrap_or("");
+            if MULTI_ARCH_ERRORS.contains(&name) {
+                let message = error.get("message").and_then(Value::as_str).unwrap_or("");
+                multi_arch_errors.push((module.as_str(), message));
+            }
+        }
+    }
+    multi_arch_errors
+}
+
+fn parse_symbolication_response(
+    response: &str,
+    addresses: &[(usize, u32)],
+) -> Result<HashMap<(usize, u32), AddressSymbol>, String> {
+    let response: Value = serde_json::from_str(response)
+        .map_err(|err| format!("Invalid symbolication response: {err}"))?;
+    if let Some(error) = response.get("error") {
+        return Err(format!("Symbolication failed: {error}"));
+    }
+    warn_about_multi_arch_errors(&response);
+    let frames = response
+        .pointer("/results/0/stacks/0")
+        .and_then(Value::as_array)
+        .map_or(&[][..], Vec::as_slice);
+    let mut symbols = HashMap::new();
+    for (frame, &(lib, address)) in frames.iter().zip(addresses) {
+        let Some(function) = frame.get("function").and_then(Value::as_str) else {
+            continue;
+        };
+        let file = frame.get("file").and_then(Value::as_str).map(String::from);
+        let line = frame.get("line").and_then(Value::as_u64);
+        let innermost = frame
+            .get("inlines")
+            .and_then(Value::as_array)
+            .and_then(|inlines| inlines.first())
+            .unwrap_or(frame);
+        let source_location = innermost
+            .get("file")
+            .and_then(Value::as_str)
+            .zip(innermost.get("line").and_then(Value::as_u64))
+            .map(|(file, line)| (file.to_string(), line));
+        let function_offset = frame
+            .get("function_offset")
+            .and_then(Value::as_str)
+            .and_then(parse_hex_u32);
+        let symbol = AddressSymbol {
+            function: function.to_string(),
+            function_address: function_offset.and_then(|offset| address.checked_sub(offset)),
+            function_size: frame
+                .get("function_size")
+                .and_then(Value::as_str)
+                .and_then(parse_hex_u32),
+            file,
+            line,
+            source_location,
+        };
+        symbols.insert((lib, address), symbol);
+    }
+    Ok(symbols)
+}
+
+/// Parses a "0x"-prefixed hex string, as used for addresses in the JSON API.
+pub fn parse_hex_u32(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+/// Returns the lib index and the address of each frame in the thread's frame
+/// table. Both are `None` for frames which don't belong to a lib.
+pub fn frame_lib_addresses(
+    thread: &Value,
+) -> impl Iterator<Item = (Option<usize>, Option<u32>)> + '_ {
+    let frame_table = &thread["frameTable"];
+    let func_table = &thread["funcTable"];
+    let resource_table = &thread["resourceTable"];
+    let frame_addresses = json_array(frame_table, "address");
+    let frame_funcs = json_array(frame_table, "func");
+    frame_addresses
+        .iter()
+        .zip(frame_funcs)
+        .map(move |(address, func)| {
+            let address = address.as_i64().and_then(|a| u32::try_from(a).ok());
+            let resource = func
+                .as_u64()
+                .and_then(|func| json_array(func_table, "resource").get(func as usize))
+                .and_then(Value::as_u64);
+            let lib = resource
+                .and_then(|resource| json_array(resource_table, "lib").get(resource as usize))
+                .and_then(Value::as_u64)
+                .map(|lib| lib as usize);
+            (lib, address)
+        })
+}
+
+/// Points each symbolicated frame at a function with the symbol's name. Functions
+/// are shared between frames with the same symbol in the same resource.
+fn apply_symbols_to_thread(
+    thread: &mut Value,
+    symbols: &HashMap<(usize, u32), AddressSymbol>,
+    strings: &mut Vec<Value>,
+) {
+    let frames: Vec<(Option<usize>, Option<u32>)> = frame_lib_addresses(thread).collect();
+    let mut string_indexes: HashMap<String, usize> = strings
+        .iter()
+        .enumerate()
+        .filter_map(|(i, s)| Some((s.as_str()?.to_string(), i)))
+        .collect();
+    let mut intern = |s: &str| -> usize {
+        *string_indexes.entry(s.to_string()).or_insert_with(|| {
+            strings.push(Value::String(s.to_string()));
+            strings.len() - 1
+        })
+    };
+
+    let mut new_funcs: HashMap<(u64, String), usize> = HashMap::new();
+    let mut frame_updates = Vec::new();
+    for (frame_index, frame) in frames.into_iter().enumerate() {
+        let (Some(lib), Some(address)) = frame else {
+            continue;
+        };
+        let Some(symbol) = symbols.get(&(lib, address)) else {
+            continue;
+        };
+        let Some(resource) = thread
+            .pointer(&format!("/frameTable/func/{frame_index}"))
+            .and_then(Value::as_u64)
+            .and_then(|func| thread.pointer(&format!("/funcTable/resource/{func}")))
+            .and_then(Value::as_u64)
+        else {
+            continue;
+        };
+        let key = (resource, symbol.function.clone());
+        let func = match new_funcs.get(&key) {
+            Some(func) => *func,
+            None => {
+                let name = intern(&symbol.function);
+                let file_name = symbol.file.as_deref().map(&mut intern);
+                let Some(func_table) = thread["funcTable"].as_object_mut() else {
+                    continue;
+                };
+                let func = push_func(func_table, name, resource, file_name);
+                new_funcs.insert(key, func);
+                func
+            }
+        };
+        frame_updates.push((frame_index, func, symbol.line));
+    }
+
+    let frame_table = &mut thread["frameTable"];
+    for (frame_index, func, line) in frame_updates {
+        frame_table["func"][frame_index] = json!(func);
+        if let (Some(line), Some(Value::Array(lines))) = (line, frame_table.get_mut("line")) {
+            if let Some(frame_line) = lines.get_mut(frame_index) {
+                *frame_line = json!(line);
+            }
+        }
+    }
+}
+
+/// Appends a function to all columns of the func table and returns its index.
+fn push_func(
+    func_table: &mut Map<String, Value>,
+    name: usize,
+    resource: u64,
+    file_name: Option<usize>,
+) -> usize {
+    let func = func_table
+        .get("length")
+        .and_then(Value::as_u64)
+        .unwrap_or_else(|| {
+            let names = func_table.get("name").and_then(Value::as_array);
+            names.map_or(0, Vec::len) as u64
+        }) as usize;
+    func_table.insert("length".to_string(), json!(func + 1));
+    for (key, column) in func_table.iter_mut() {
+        let Value::Array(column) = column else {
+            continue;
+        };
+        let value = match key.as_str() {
+            "name" => json!(name),
+            "resource" => json!(resource),
+            "isJS" | "relevantForJS" => json!(false),
+            "fileName" => json!(file_name),
+            _ => Value::Null,
+        };
+        column.push(value);
+    }
+    func
+}
+
+/// Adds the number of samples (or the sample weight) for each (lib index,
+/// address) pair which is the leaf frame of a sample of `thread`.
+pub fn count_leaf_samples(thread: &Value, counts: &mut HashMap<(usize, u32), u64>) {
+    let frames: Vec<(Option<usize>, Option<u32>)> = frame_lib_addresses(thread).collect();
+    let stack_frames = json_array(&thread["stackTable"], "frame");
+    let samples = &thread["samples"];
+    let weights = json_array(samples, "weight");
+    for (i, stack) in json_array(samples, "stack").iter().enumerate() {
+        let frame = stack
+            .as_u64()
+            .and_then(|stack| stack_frames.get(stack as usize))
+            .and_then(Value::as_u64)
+            .and_then(|frame| frames.get(frame as usize));
+        let Some((Some(lib), Some(address))) = frame else {
+            continue;
+        };
+        let weight = weights.get(i).and_then(Value::as_u64).unwrap_or(1);
+        *counts.entry((*lib, *address)).or_default() += weight;
+    }
+}
+
+pub fn json_array<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value
+        .get(key)
+        .and_then(Value::as_array)
+        .map_or(&[], Vec::as_slice)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn symbols_are_applied_to_frames() {
+        let mut thread = json!({
+            "frameTable": {"address": [0x1000, 0x1010, 0x2000, -1], "func": [0, 0, 0, 1], "line": [null, null, null, null], "length": 4},
+            "funcTable": {"name": [0, 1], "resource": [0, -1], "isJS": [false, true], "relevantForJS": [false, false], "fileName": [null, null], "length": 2},
+            "resourceTable": {"lib": [0], "length": 1},
+        });
+        let addresses = vec![(0, 0x1000), (0, 0x1010), (0, 0x2000)];
+        let response = r#"{"results":[{"stacks":[[
+            {"function":"main","function_offset":"0x0","file":"main.c","line":3},
+            {"function":"main","function_offset":"0x10","file":"main.c","line":5},
+            {"module":"a.so"}
+        ]]}]}"#;
+        let symbols = parse_symbolication_response(response, &addresses).unwrap();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[&(0, 0x1010)].function_address, Some(0x1000));
+
+        let mut strings = vec![json!("0x1000"), json!("js")];
+        apply_symbols_to_thread(&mut thread, &symbols, &mut strings);
+        assert_eq!(
+            strings,
+            vec![json!("0x1000"), json!("js"), json!("main"), json!("main.c")]
+        );
+        // Both frames of main share a new function, the unknown frame and
+        // the JS frame keep theirs.
+        assert_eq!(thread["frameTable"]["func"], json!([2, 2, 0, 1]));
+        assert_eq!(thread["frameTable"]["line"], json!([3, 5, null, null]));
+        assert_eq!(thread["funcTable"]["name"], json!([0, 1, 2]));
+        assert_eq!(thread["funcTable"]["fileName"], json!([null, null, 3]));
+        assert_eq!(thread["funcTable"]["length"], json!(3));
+    }
+
+    #[test]
+    fn multi_arch_errors_are_found_by_name() {
+        let response = json!({"results": [{"module_errors": {
+            "libfoo.dylib/0123": [
+                {"name": "NoMatchMultiArch", "message": "No architecture matches"},
+            ],
+            "libbar.dylib/4567": [
+                {"name": "UnmatchedDebugId", "message": "not a universal binary"},
+            ],
+        }}]});
+        assert_eq!(
+            multi_arch_errors(&response),
+            vec![("libfoo.dylib/0123", "No architecture matches")]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! A RUSTC_WRAPPER for `samply record --rustc-wrapper cargo build`.
+//!
+//! cargo runs the wrapper as `<wrapper> <rustc> <args>...` for every crate it
+//! compiles. samply points RUSTC_WRAPPER at its own executable and sets
+//! [`RUSTC_WRAPPER_ENV_VAR`], so that the samply process which cargo launches
+//! knows to act as the wrapper rather than parse a samply command line.
+//!
+//! The wrapper stays alive while rustc runs. It renames itself to the name of
+//! the crate that's being compiled, so the rustc process shows up in the
+//! profile as a child of a process with that name, and once rustc exits, it
+//! sends a marker which spans the compilation over the marker socket.
+
+use std::ffi::OsString;
+use std::process::Command;
+
+/// Set in the environment of the recorded command, to make the samply
+/// executable behave as a rustc wrapper when cargo runs it.
+pub const RUSTC_WRAPPER_ENV_VAR: &str = "SAMPLY_RUSTC_WRAPPER";
+
+/// If RUSTC_WRAPPER was already set (e.g. to sccache), it's moved to this
+/// variable and the wrapper runs rustc through it.
+pub const INNER_RUSTC_WRAPPER_ENV_VAR: &str = "SAMPLY_INNER_RUSTC_WRAPPER";
+
+/// Returns whether this process was launched by cargo as a rustc wrapper.
+pub fn is_running_as_rustc_wrapper() -> bool {
+    std::env::var_os(RUSTC_WRAPPER_ENV_VAR).is_some()
+}
+
+/// Sets up the environment so that cargo commands which are launched from
+/// this process run every rustc invocation through [`run_rustc_wrapper`].
+pub fn set_rustc_wrapper_env_vars() -> std::io::Result<()> {
+    let samply_path = std::env::current_exe()?;
+    if let Some(inner_wrapper) = std::env::var_os("RUSTC_WRAPPER").filter(|w| !w.is_empty()) {
+        std::env::set_var(INNER_RUSTC_WRAPPER_ENV_VAR, inner_wrapper);
+    }
+    std::env::set_var("RUSTC_WRAPPER", samply_path);
+    std::env::set_var(RUSTC_WRAPPER_ENV_VAR, "1");
+    Ok(())
+}
+
+/// Runs the rustc command line in `args` and returns its exit code. The
+/// wrapper's own environment variables aren't passed on to rustc.
+pub fn run_rustc_wrapper(args: &[OsString]) -> i32 {
+    let Some((rustc, rustc_args)) = args.split_first() else {
+        eprintln!("samply: {RUSTC_WRAPPER_ENV_VAR} is set, but no rustc command was given.");
+        return 1;
+    };
+    let mut command = match std::env::var_os(INNER_RUSTC_WRAPPER_ENV_VAR) {
+        Some(inner_wrapper) => {
+            let mut command = Command::new(inner_wrapper);
+            command.arg(rustc);
+            command
+        }
+        None => Command::new(rustc),
+    };
+    command.args(rustc_args);
+    // Only cargo needs to run samply as the wrapper. Anything which rustc or
+    // the inner wrapper launches shouldn't act as a wrapper if it happens to
+    // be samply.
+    command
+        .env_remove(RUSTC_WRAPPER_ENV_VAR)
+        .env_remove(INNER_RUSTC_WRAPPER_ENV_VAR);
+
+    // Invocations without a crate name are queries like `rustc -vV`, which
+    // aren't worth a marker.
+    let crate_name = crate_name_from_args(rustc_args);
+    if let Some(crate_name) = &crate_name {
+        set_process_name(crate_name);
+    }
+
+    let start_time = monotonic_time_ns();
+    let status = match command.status() {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("samply: Could not run {rustc:?}: {err}");
+            return 1;
+        }
+    };
+    let end_time = monotonic_time_ns();
+
+    if let (Some(crate_name), Some(start_time), Some(end_time)) = (crate_name, start_time, end_time)
+    {
+        send_marker(start_time, end_time, &crate_name);
+    }
+    status.code().unwrap_or(1)
+}
+
+/// Finds the value of `--crate-name` in rustc's arguments.
+fn crate_name_from_args(args: &[OsString]) -> Option<String> {
+    let mut args = args.iter().map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        match arg {
+            Some("--crate-name") => return args.next().flatten().map(String::from),
+            Some(arg) => {
+                if let Some(name) = arg.strip_prefix("--crate-name=") {
+                    return Some(name.to_string());
+                }
+            }
+            None => {}
+        }
+    }
+    None
+}
+
+/// Renames this process, so that the recorded process tree shows which crate
+/// each rustc process compiles. Linux truncates the name to 15 bytes.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn set_process_name(name: &str) {
+    let Ok(name) = std::ffi::CString::new(name) else {
+        return;
+    };
+    unsafe {
+        libc::prctl(libc::PR_SET_NAME, name.as_ptr(), 0, 0, 0);
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn set_process_name(_name: &str) {}
+
+/// The current `CLOCK_MONOTONIC` time, which is what marker times are in.
+#[cfg(unix)]
+fn monotonic_time_ns() -> Option<u64> {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) } != 0 {
+        return None;
+    }
+    Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
+
+#[cfg(not(unix))]
+fn monotonic_time_ns() -> Option<u64> {
+    None
+}
+
+/// Sends an interval marker for this process to the marker socket of the
+/// samply process which is recording the build, if there is one.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn send_marker(start_time: u64, end_time: u64, name: &str) {
+    use crate::linux::marker_socket::MARKER_SOCKET_ENV_VAR;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    let Some(socket_path) = std::env::var_os(MARKER_SOCKET_ENV_VAR) else {
+        return;
+    };
+    let Ok(mut stream) = UnixStream::connect(socket_path) else {
+        return;
+    };
+    let pid = std::process::id();
+    let _ = write!(stream, "{pid} {pid}\n{start_time} {end_time} {name}\n");
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn send_marker(_start_time: u64, _end_time: u64, _name: &str) {}
+
+#[cfg(test)]
+mod test {
+    use super::crate_name_from_args;
+
+    use std::ffi::OsString;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn crate_name() {
+        assert_eq!(
+            crate_name_from_args(&args(&[
+                "--edition=2021",
+                "--crate-name",
+                "serde",
+                "src/lib.rs"
+            ])),
+            Some("serde".to_string())
+        );
+        assert_eq!(
+            crate_name_from_args(&args(&["--crate-name=build_script_build"])),
+            Some("build_script_build".to_string())
+        );
+        assert_eq!(crate_name_from_args(&args(&["-vV"])), None);
+        assert_eq!(crate_name_from_args(&args(&["--crate-name"])), None);
+    }
+}
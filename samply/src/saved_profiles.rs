@@ -0,0 +1,221 @@
+use serde_json::{json, Value};
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The suffix of the metadata files which `samply record --save-only` writes
+/// next to the profile.
+const SIDECAR_SUFFIX: &str = ".meta.json";
+
+/// A summary of a saved recording, read from its sidecar metadata file.
+pub struct SavedProfile {
+    pub profile_path: PathBuf,
+    pub command: String,
+    /// Seconds since the Unix epoch.
+    pub recorded_at: u64,
+    pub duration: Duration,
+    pub machine: Option<String>,
+}
+
+impl SavedProfile {
+    /// Describes a recording which has just finished, and which was started
+    /// at `start_time`.
+    pub fn for_finished_recording(
+        profile_path: &Path,
+        command: &[OsString],
+        pid: Option<u32>,
+        start_time: SystemTime,
+    ) -> Self {
+        let command = match pid {
+            Some(pid) => format!("--pid {pid}"),
+            None => command
+                .iter()
+                .map(|arg| arg.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        Self {
+            profile_path: profile_path.to_owned(),
+            command,
+            recorded_at: start_time
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            duration: start_time.elapsed().unwrap_or_default(),
+            machine: hostname(),
+        }
+    }
+
+    fn sidecar_path(profile_path: &Path) -> PathBuf {
+        let mut path = profile_path.as_os_str().to_owned();
+        path.push(SIDECAR_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    /// Writes the sidecar metadata file next to the profile.
+    pub fn write_sidecar(&self) -> std::io::Result<()> {
+        let file_name = self.profile_path.file_name().unwrap_or_default();
+        let contents = json!({
+            "profile": file_name.to_string_lossy(),
+            "command": self.command,
+            "recordedAt": self.recorded_at,
+            "duration": self.duration.as_secs_f64(),
+            "machine": self.machine,
+            "samplyVersion": env!("CARGO_PKG_VERSION"),
+        });
+        std::fs::write(Self::sidecar_path(&self.profile_path), contents.to_string())
+    }
+
+    fn read_sidecar(sidecar_path: &Path) -> Option<Self> {
+        let contents = std::fs::read(sidecar_path).ok()?;
+        let value: Value = serde_json::from_slice(&contents).ok()?;
+        let profile = value.get("profile")?.as_str()?;
+        Some(Self {
+            profile_path: sidecar_path.with_file_name(profile),
+            command: value.get("command")?.as_str()?.to_string(),
+            recorded_at: value.get("recordedAt")?.as_u64()?,
+            duration: Duration::from_secs_f64(value.get("duration")?.as_f64()?),
+            machine: value
+                .get("machine")
+                .and_then(Value::as_str)
+                .map(String::from),
+        })
+    }
+}
+
+/// Finds the saved recordings in `dir` whose profile still exists, most recent
+/// first.
+pub fn list_saved_profiles(dir: &Path) -> std::io::Result<Vec<SavedProfile>> {
+    let mut profiles: Vec<SavedProfile> = std::fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if !path.to_string_lossy().ends_with(SIDECAR_SUFFIX) {
+                return None;
+            }
+            SavedProfile::read_sidecar(&path)
+        })
+        .filter(|profile| profile.profile_path.exists())
+        .collect();
+    profiles.sort_by_key(|profile| std::cmp::Reverse(profile.recorded_at));
+    Ok(profiles)
+}
+
+/// Prints a numbered table of the saved recordings. The numbers can be passed
+/// to `samply list --open`.
+pub fn print_saved_profiles(profiles: &[SavedProfile]) {
+    for (index, profile) in profiles.iter().enumerate() {
+        println!(
+            "{:>3}  {}  {:>8.1}s  {}  {}",
+            index + 1,
+            format_utc_date(profile.recorded_at),
+            profile.duration.as_secs_f64(),
+            profile.machine.as_deref().unwrap_or("-"),
+            profile.profile_path.display()
+        );
+        println!("     {}", profile.command);
+    }
+}
+
+/// Formats seconds since the Unix epoch as "YYYY-MM-DD HH:MM UTC".
+fn format_utc_date(seconds: u64) -> String {
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let seconds_in_day = seconds % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        seconds_in_day / 3600,
+        seconds_in_day % 3600 / 60
+    )
+}
+
+/// Formats seconds since the Unix epoch as "YYYYMMDDTHHMMSSZ", the ISO 8601
+/// basic format, which sorts by time and can be used in file names.
+pub fn format_utc_timestamp(seconds: u64) -> String {
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let seconds_in_day = seconds % 86400;
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        seconds_in_day / 3600,
+        seconds_in_day % 3600 / 60,
+        seconds_in_day % 60
+    )
+}
+
+/// Returns the year, month and day of the day which is `days` days after
+/// 1970-01-01, using Howard Hinnant's civil_from_days algorithm.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_utc_date, format_utc_timestamp};
+
+    #[test]
+    fn utc_dates() {
+        assert_eq!(format_utc_date(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_utc_date(951_782_400), "2000-02-29 00:00 UTC");
+        assert_eq!(
+            format_utc_date(1_792_152_000 + 3723),
+            "2026-10-16 13:02 UTC"
+        );
+        assert_eq!(
+            format_utc_timestamp(1_792_152_000 + 3723),
+            "20261016T130203Z"
+        );
+    }
+
+    #[test]
+    fn utc_dates_around_the_epoch() {
+        assert_eq!(format_utc_timestamp(0), "19700101T000000Z");
+        assert_eq!(format_utc_timestamp(86_399), "19700101T235959Z");
+        assert_eq!(format_utc_timestamp(86_400), "19700102T000000Z");
+    }
+
+    #[test]
+    fn utc_dates_in_leap_years() {
+        assert_eq!(format_utc_date(1_709_209_800), "2024-02-29 12:30 UTC");
+        assert_eq!(
+            format_utc_date(1_709_209_800 + 43_200),
+            "2024-03-01 00:30 UTC"
+        );
+        // 2023 is not a leap year.
+        assert_eq!(format_utc_timestamp(1_677_628_799), "20230228T235959Z");
+        assert_eq!(format_utc_timestamp(1_677_628_800), "20230301T000000Z");
+        // Day 366 of a leap year.
+        assert_eq!(format_utc_timestamp(1_735_689_599), "20241231T235959Z");
+        assert_eq!(format_utc_timestamp(1_735_689_600), "20250101T000000Z");
+        // 2000 is a leap year, 2100 isn't.
+        assert_eq!(format_utc_date(978_220_800), "2000-12-31 00:00 UTC");
+        assert_eq!(format_utc_date(4_107_456_000), "2100-02-28 00:00 UTC");
+        assert_eq!(
+            format_utc_date(4_107_456_000 + 86_400),
+            "2100-03-01 00:00 UTC"
+        );
+    }
+}
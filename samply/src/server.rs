@@ -1,4 +1,3 @@
-use flate2::read::GzDecoder;
 use futures_util::TryStreamExt;
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Either, StreamBody};
@@ -10,39 +9,128 @@ use hyper_util::rt::TokioIo;
 use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 use rand::RngCore;
 use serde_derive::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::net::TcpListener;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinSet;
 use tokio_util::io::ReaderStream;
 use wholesym::debugid::DebugId;
 use wholesym::{CodeId, LibraryInfo, SymbolManager, SymbolManagerConfig};
 
 use std::collections::HashMap;
-use std::ffi::{OsStr, OsString};
-use std::io::BufReader;
+use std::ffi::OsStr;
+use std::io::SeekFrom;
 use std::net::SocketAddr;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::profile_json_preparse::{open_profile_json, scan_profile_jsons, ProfileOrArray};
+use crate::server_metrics::ServerMetrics;
+use crate::symbolication_cache::SymbolicationCache;
+use crate::view_hints::UrlViewState;
 
 #[derive(Clone, Debug)]
 pub struct ServerProps {
     pub port_selection: PortSelection,
     pub verbose: bool,
     pub open_in_browser: bool,
+    pub symbolication_cache_dir: Option<PathBuf>,
+    pub symbol_dirs: Vec<PathBuf>,
+    pub id_matching: SymbolIdMatching,
+    pub symbol_downloads: SymbolDownloads,
+    /// The origins of the profiler front-ends which may call the API, or "*"
+    /// for any origin, in addition to the profiler which is opened.
+    pub allowed_origins: Vec<String>,
+    /// Stop the server if it doesn't receive any requests for this long.
+    pub idle_timeout: Option<Duration>,
+    /// The panel, searches and range which the profiler opens with. If `None`,
+    /// they're read from the profile's meta.
+    pub view_state: Option<UrlViewState>,
+}
+
+/// Lets the server be stopped with a request to `/quit`, or once it has been
+/// idle for a while, so that servers which were started by `samply record`
+/// don't pile up on shared machines.
+struct ServerLifetime {
+    quit_requested: Notify,
+    last_request: Mutex<Instant>,
+}
+
+impl ServerLifetime {
+    fn new() -> Self {
+        Self {
+            quit_requested: Notify::new(),
+            last_request: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn request_received(&self) {
+        *self.last_request.lock().unwrap() = Instant::now();
+    }
+
+    /// Returns once there has been no request for `idle_timeout`.
+    async fn wait_until_idle(&self, idle_timeout: Duration) {
+        loop {
+            let deadline = *self.last_request.lock().unwrap() + idle_timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+/// How strictly the IDs of symbol files need to match the libraries in the
+/// profile. Files with a matching ID are always preferred.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolIdMatching {
+    /// Fall back to files whose build ID / debug ID doesn't match at all.
+    pub ignore_id_mismatch: bool,
+    /// Accept PDB files whose GUID matches but whose age doesn't.
+    pub ignore_pdb_age: bool,
+    /// The architecture to use from universal binaries and the dyld shared
+    /// cache, for libraries whose architecture isn't in the profile.
+    pub binary_arch: Option<String>,
+}
+
+/// Where symbol files and source files may be downloaded from, for machines
+/// which must not make unexpected network requests.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolDownloads {
+    /// Don't download anything. Files which were downloaded earlier are still
+    /// used.
+    pub offline: bool,
+    /// If not empty, only download from these domains and their subdomains.
+    pub allowed_domains: Vec<String>,
 }
 
 #[tokio::main]
 pub async fn start_server_main(file: &Path, props: ServerProps) {
-    start_server(
-        Some(file),
-        props.port_selection,
-        props.verbose,
-        props.open_in_browser,
-    )
-    .await;
+    start_server(Some(file), props).await;
 }
 
+/// Starts a server which only answers the symbolication, source and assembly
+/// API requests. Its URL can be used as the symbol server in the profiler UI,
+/// for profiles which weren't loaded through samply.
+#[tokio::main]
+pub async fn start_api_server_main(props: ServerProps) {
+    start_server(None, props).await;
+}
+
+const DEFAULT_PROFILER_ORIGIN: &str = "https://profiler.firefox.com";
+
 const BAD_CHARS: &AsciiSet = &CONTROLS.add(b':').add(b'/');
+const QUERY_VALUE_BAD_CHARS: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'+')
+    .add(b'=');
 
 #[derive(Clone, Debug)]
 pub enum PortSelection {
@@ -62,33 +150,32 @@ impl PortSelection {
     }
 }
 
-async fn start_server(
-    profile_filename: Option<&Path>,
-    port_selection: PortSelection,
-    verbose: bool,
-    open_in_browser: bool,
-) {
-    let libinfo_map = if let Some(profile_filename) = profile_filename {
-        // Read the profile.json file and parse it as JSON.
-        // Build a map (debugName, breakpadID) -> debugPath from the information
-        // in profile(\.processes\[\d+\])*(\.threads\[\d+\])?\.libs.
-        let file = std::fs::File::open(profile_filename).expect("couldn't read file");
-        let reader = BufReader::new(file);
-
-        // Handle .gz profiles
-        if profile_filename.extension() == Some(&OsString::from("gz")) {
-            let decoder = GzDecoder::new(reader);
-            let reader = BufReader::new(decoder);
-            parse_libinfo_map_from_profile(reader).expect("couldn't parse json")
-        } else {
-            parse_libinfo_map_from_profile(reader).expect("couldn't parse json")
-        }
-    } else {
-        HashMap::new()
-    };
-
+async fn start_server(profile_filename: Option<&Path>, props: ServerProps) {
+    let ServerProps {
+        port_selection,
+        verbose,
+        open_in_browser,
+        symbolication_cache_dir,
+        symbol_dirs,
+        id_matching,
+        symbol_downloads,
+        mut allowed_origins,
+        idle_timeout,
+        view_state,
+    } = props;
     let (listener, addr) = make_listener(port_selection).await;
 
+    let env_profiler_override = std::env::var("PROFILER_URL").ok();
+    let profiler_origin = match &env_profiler_override {
+        Some(s) => s.trim_end_matches('/'),
+        None => DEFAULT_PROFILER_ORIGIN,
+    };
+    // The profiler which is opened must be able to call the API.
+    let opened_origin = url_origin(profiler_origin);
+    if !is_allowed_origin(opened_origin, &allowed_origins) {
+        allowed_origins.push(opened_origin.to_string());
+    }
+
     let token = generate_token();
     let path_prefix = format!("/{token}");
     let server_origin = format!("http://{addr}");
@@ -100,17 +187,18 @@ async fn start_server(
     let profiler_url = if profile_filename.is_some() {
         let profile_url = format!("{symbol_server_url}/profile.json");
 
-        let env_profiler_override = std::env::var("PROFILER_URL").ok();
-        let profiler_origin = match &env_profiler_override {
-            Some(s) => s.trim_end_matches('/'),
-            None => "https://profiler.firefox.com",
-        };
-
         let encoded_profile_url = utf8_percent_encode(&profile_url, BAD_CHARS).to_string();
         let encoded_symbol_server_url =
             utf8_percent_encode(&symbol_server_url, BAD_CHARS).to_string();
+        // The profile can ask for a panel, a search and a range to be preselected.
+        let view_state = view_state
+            .or_else(|| profile_filename.and_then(UrlViewState::from_profile_file))
+            .unwrap_or_default();
+        let view_path = view_state.url_path();
+        let view_query =
+            view_state.url_query(|s| utf8_percent_encode(s, QUERY_VALUE_BAD_CHARS).to_string());
         let profiler_url = format!(
-            "{profiler_origin}/from-url/{encoded_profile_url}/?symbolServer={encoded_symbol_server_url}"
+            "{profiler_origin}/from-url/{encoded_profile_url}/{view_path}?symbolServer={encoded_symbol_server_url}{view_query}"
         );
         template_values.insert("PROFILER_URL", profiler_url.clone());
         template_values.insert("PROFILE_URL", profile_url);
@@ -121,38 +209,50 @@ async fn start_server(
 
     let template_values = Arc::new(template_values);
 
-    let mut config = SymbolManagerConfig::new()
-        .verbose(verbose)
-        .respect_nt_symbol_path(true)
-        .use_debuginfod(std::env::var("SAMPLY_USE_DEBUGINFOD").is_ok())
-        .use_spotlight(true);
-    if let Some(home_dir) = dirs::home_dir() {
-        config = config.debuginfod_cache_dir_if_not_installed(home_dir.join("sym"));
-    }
-    // TODO: Read breakpad symbol server config from some kind of config file, and call breakpad_symbols_server
-    // TODO: On Windows, put https://msdl.microsoft.com/download/symbols into the config file.
-    // There's a privacy tradeoff here; some people may not want library names and debug IDs to be sent to Microsoft servers.
-    //     .default_nt_symbol_path("srv**https://msdl.microsoft.com/download/symbols")
-
-    let mut symbol_manager = SymbolManager::with_config(config);
-    for lib_info in libinfo_map.into_values() {
-        symbol_manager.add_known_library(lib_info);
-    }
+    let symbol_manager = symbol_manager_for_profile(
+        profile_filename,
+        verbose,
+        &symbol_dirs,
+        id_matching,
+        &symbol_downloads,
+    );
     let symbol_manager = Arc::new(symbol_manager);
+    let symbolication_cache = Arc::new(SymbolicationCache::new(symbolication_cache_dir));
+    let lifetime = Arc::new(ServerLifetime::new());
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
 
-    let server = tokio::task::spawn(run_server(
+    let mut server = tokio::task::spawn(run_server(
         listener,
         symbol_manager,
+        symbolication_cache,
         profile_filename.map(PathBuf::from),
         template_values,
         path_prefix,
+        Arc::new(allowed_origins),
+        Arc::new(ServerMetrics::default()),
+        lifetime.clone(),
+        shutdown_receiver,
     ));
 
-    eprintln!("Local server listening at {server_origin}");
-    if !open_in_browser {
-        if let Some(profiler_url) = &profiler_url {
+    eprintln!(
+        "Local server listening at {server_origin} (pid {})",
+        std::process::id()
+    );
+    match &profiler_url {
+        Some(profiler_url) if !open_in_browser => {
             eprintln!("  Open the profiler at {profiler_url}");
         }
+        Some(_) => {}
+        None => {
+            eprintln!("  Use {symbol_server_url} as the symbol server URL in the profiler");
+        }
+    }
+    eprintln!("  Stop it with: curl -X POST {symbol_server_url}/quit");
+    if let Some(idle_timeout) = idle_timeout {
+        eprintln!(
+            "  It stops by itself after {} minutes without requests.",
+            idle_timeout.as_secs() / 60
+        );
     }
     eprintln!("Press Ctrl+C to stop.");
 
@@ -162,21 +262,189 @@ async fn start_server(
         }
     }
 
-    // Run this server for... forever!
-    if let Err(e) = server.await {
-        eprintln!("server error: {e}");
+    let wait_until_idle = async {
+        match idle_timeout {
+            Some(idle_timeout) => lifetime.wait_until_idle(idle_timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        result = &mut server => {
+            if let Err(e) = result {
+                eprintln!("server error: {e}");
+            }
+            return;
+        }
+        _ = lifetime.quit_requested.notified() => {
+            eprintln!("Received a quit request, stopping the server.");
+        }
+        _ = wait_until_idle => {
+            eprintln!("The server was idle for too long, stopping it.");
+        }
+    }
+
+    // Stop accepting connections, and let the open ones finish the responses
+    // they're sending, including the response to the quit request.
+    let _ = shutdown_sender.send(true);
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, server).await {
+        Ok(Ok(Ok(()))) => {}
+        Ok(Ok(Err(e))) => eprintln!("server error: {e}"),
+        Ok(Err(e)) => eprintln!("server error: {e}"),
+        Err(_) => eprintln!(
+            "Some responses were still being sent after {} seconds, stopping anyway.",
+            SHUTDOWN_TIMEOUT.as_secs()
+        ),
+    }
+}
+
+/// How long the server waits for open connections to finish their responses
+/// once it has been asked to stop.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Creates a symbol manager which knows about the libraries in the profile.
+pub fn symbol_manager_for_profile(
+    profile_filename: Option<&Path>,
+    verbose: bool,
+    symbol_dirs: &[PathBuf],
+    id_matching: SymbolIdMatching,
+    symbol_downloads: &SymbolDownloads,
+) -> SymbolManager {
+    let libinfo_map = if let Some(profile_filename) = profile_filename {
+        // Read the profile.json file and parse it as JSON.
+        // Build a map (debugName, breakpadID) -> debugPath from the information
+        // in profile(\.processes\[\d+\])*(\.threads\[\d+\])?\.libs.
+        // A profile which can't be scanned is still served, the profiler will
+        // report what's wrong with it.
+        match open_profile_json(profile_filename).and_then(parse_libinfo_map_from_profile) {
+            Ok(libinfo_map) => libinfo_map,
+            Err(err) => {
+                eprintln!("Warning: Could not read the libraries of {profile_filename:?}: {err}");
+                HashMap::new()
+            }
+        }
+    } else {
+        HashMap::new()
+    };
+    symbol_manager_with_libs(
+        libinfo_map,
+        verbose,
+        symbol_dirs,
+        id_matching,
+        symbol_downloads,
+    )
+}
+
+/// Like [`symbol_manager_for_profile`], for a profile which has already been
+/// read, so that the file doesn't need to be parsed a second time.
+pub fn symbol_manager_for_parsed_profile(
+    profile: &serde_json::Value,
+    verbose: bool,
+    symbol_dirs: &[PathBuf],
+    id_matching: SymbolIdMatching,
+    symbol_downloads: &SymbolDownloads,
+) -> SymbolManager {
+    symbol_manager_with_libs(
+        libinfo_map_from_parsed_profile(profile),
+        verbose,
+        symbol_dirs,
+        id_matching,
+        symbol_downloads,
+    )
+}
+
+fn symbol_manager_with_libs(
+    libinfo_map: HashMap<(String, DebugId), LibraryInfo>,
+    verbose: bool,
+    symbol_dirs: &[PathBuf],
+    id_matching: SymbolIdMatching,
+    symbol_downloads: &SymbolDownloads,
+) -> SymbolManager {
+    let mut config = SymbolManagerConfig::new()
+        .verbose(verbose)
+        .respect_nt_symbol_path(true)
+        .use_debuginfod(std::env::var("SAMPLY_USE_DEBUGINFOD").is_ok())
+        .use_spotlight(true)
+        .ignore_debug_id_mismatch(id_matching.ignore_id_mismatch)
+        .ignore_pdb_age(id_matching.ignore_pdb_age)
+        .offline(symbol_downloads.offline);
+    for domain in &symbol_downloads.allowed_domains {
+        config = config.allowed_download_domain(domain);
+    }
+    if let Some(home_dir) = dirs::home_dir() {
+        config = config.debuginfod_cache_dir_if_not_installed(home_dir.join("sym"));
+    }
+    if let Some(cache_dir) = dirs::cache_dir() {
+        config = config.dyld_cache_symbols_dir(cache_dir.join("samply").join("dyld-cache-symbols"));
+    }
+    // TODO: Read breakpad symbol server config from some kind of config file, and call breakpad_symbols_server
+    // TODO: On Windows, put https://msdl.microsoft.com/download/symbols into the config file.
+    // There's a privacy tradeoff here; some people may not want library names and debug IDs to be sent to Microsoft servers.
+    //     .default_nt_symbol_path("srv**https://msdl.microsoft.com/download/symbols")
+
+    for dir in symbol_dirs {
+        config = config.breakpad_symbols_dir(dir);
+    }
+
+    let mut symbol_manager = SymbolManager::with_config(config);
+    for mut lib_info in libinfo_map.into_values() {
+        find_lib_in_symbol_dirs(&mut lib_info, symbol_dirs);
+        if lib_info.arch.is_none() {
+            lib_info.arch = id_matching.binary_arch.clone();
+        }
+        symbol_manager.add_known_library(lib_info);
+    }
+    symbol_manager
+}
+
+/// If the profile was recorded on a different machine, the library paths in
+/// the profile don't exist on this machine. Look for the libraries in the
+/// symbol directories, by debug name and by name.
+fn find_lib_in_symbol_dirs(lib_info: &mut LibraryInfo, symbol_dirs: &[PathBuf]) {
+    let find = |name: &Option<String>| -> Option<String> {
+        let name = name.as_deref()?;
+        symbol_dirs
+            .iter()
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .map(|path| path.to_string_lossy().into_owned())
+    };
+    if let Some(debug_path) = find(&lib_info.debug_name) {
+        lib_info.debug_path = Some(debug_path);
+    }
+    if let Some(path) = find(&lib_info.name) {
+        lib_info.path = Some(path);
     }
 }
 
+/// Collects the libs of all profiles in the file, which can have several of
+/// them, in an array or one after the other. Only the lib lists are kept while
+/// the JSON is scanned, the rest of each profile is skipped.
 fn parse_libinfo_map_from_profile(
     reader: impl std::io::Read,
 ) -> Result<HashMap<(String, DebugId), LibraryInfo>, std::io::Error> {
-    let profile: ProfileJsonProcess = serde_json::from_reader(reader)?;
     let mut libinfo_map = HashMap::new();
-    add_to_libinfo_map_recursive(&profile, &mut libinfo_map);
+    for profile in scan_profile_jsons::<ProfileJsonProcess>(reader)? {
+        add_to_libinfo_map_recursive(&profile, &mut libinfo_map);
+    }
     Ok(libinfo_map)
 }
 
+/// Collects the libs of a profile, or an array of profiles, which has already
+/// been parsed.
+fn libinfo_map_from_parsed_profile(
+    profile: &serde_json::Value,
+) -> HashMap<(String, DebugId), LibraryInfo> {
+    let mut libinfo_map = HashMap::new();
+    if let Ok(profiles) =
+        <ProfileOrArray<ProfileJsonProcess> as serde::Deserialize>::deserialize(profile)
+    {
+        for profile in profiles.0 {
+            add_to_libinfo_map_recursive(&profile, &mut libinfo_map);
+        }
+    }
+    libinfo_map
+}
+
 #[derive(Deserialize, Default, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 struct ProfileJsonProcess {
@@ -263,6 +531,8 @@ const TEMPLATE_WITH_PROFILE: &str = r#"
     <li><a download href="PROFILE_URL">Download the raw profile JSON</a></li>
     <li>Obtain symbols by POSTing to <code>PATH_PREFIX/symbolicate/v5</code>, with the format specified by the <a href="https://tecken.readthedocs.io/en/latest/symbolication.html">Mozilla symbolication API documentation</a>.</li>
     <li>Obtain source code by POSTing to <code>PATH_PREFIX/source/v1</code>, with the format specified in this <a href="https://github.com/mstange/profiler-get-symbols/issues/24#issuecomment-989985588">github comment</a>.</li>
+    <li>Stop the server by POSTing to <code>PATH_PREFIX/quit</code>.</li>
+    <li>Monitor the server with the <a href="/metrics">metrics</a> in the Prometheus text format.</li>
 </ul>
 "#;
 
@@ -277,33 +547,54 @@ const TEMPLATE_WITHOUT_PROFILE: &str = r#"
 <ul>
     <li>Obtain symbols by POSTing to <code>PATH_PREFIX/symbolicate/v5</code>, with the format specified by the <a href="https://tecken.readthedocs.io/en/latest/symbolication.html">Mozilla symbolication API documentation</a>.</li>
     <li>Obtain source code by POSTing to <code>PATH_PREFIX/source/v1</code>, with the format specified in this <a href="https://github.com/mstange/profiler-get-symbols/issues/24#issuecomment-989985588">github comment</a>.</li>
+    <li>Stop the server by POSTing to <code>PATH_PREFIX/quit</code>.</li>
+    <li>Monitor the server with the <a href="/metrics">metrics</a> in the Prometheus text format.</li>
 </ul>
 "#;
 
+#[allow(clippy::too_many_arguments)]
 async fn run_server(
     listener: TcpListener,
     symbol_manager: Arc<SymbolManager>,
+    symbolication_cache: Arc<SymbolicationCache>,
     profile_filename: Option<PathBuf>,
     template_values: Arc<HashMap<&'static str, String>>,
     path_prefix: String,
+    allowed_origins: Arc<Vec<String>>,
+    metrics: Arc<ServerMetrics>,
+    lifetime: Arc<ServerLifetime>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // We start a loop to continuously accept incoming connections
+    let mut connections = JoinSet::new();
+
+    // We start a loop to continuously accept incoming connections, until
+    // we're told to shut down.
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.changed() => break,
+            // Forget about the connections which have been closed.
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+        };
 
         // Use an adapter to access something implementing `tokio::io` traits as if they implement
         // `hyper::rt` IO traits.
         let io = TokioIo::new(stream);
 
         let symbol_manager = symbol_manager.clone();
+        let symbolication_cache = symbolication_cache.clone();
         let profile_filename = profile_filename.clone();
         let template_values = template_values.clone();
         let path_prefix = path_prefix.clone();
+        let allowed_origins = allowed_origins.clone();
+        let metrics = metrics.clone();
+        let lifetime = lifetime.clone();
+        let mut shutdown = shutdown.clone();
 
         // Spawn a tokio task to serve multiple connections concurrently
-        tokio::task::spawn(async move {
+        connections.spawn(async move {
             // Finally, we bind the incoming connection to our service
-            if let Err(err) = http1::Builder::new()
+            let connection = http1::Builder::new()
                 // `service_fn` converts our function in a `Service`
                 .serve_connection(
                     io,
@@ -312,26 +603,51 @@ async fn run_server(
                             req,
                             template_values.clone(),
                             symbol_manager.clone(),
+                            symbolication_cache.clone(),
                             profile_filename.clone(),
                             path_prefix.clone(),
+                            allowed_origins.clone(),
+                            metrics.clone(),
+                            lifetime.clone(),
                         )
                     }),
-                )
-                .await
-            {
+                );
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown.changed() => {
+                    // Finish the request which is in progress, if any, and
+                    // then close the connection.
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(err) = result {
                 println!("Error serving connection: {:?}", err);
             }
         });
     }
+
+    while connections.join_next().await.is_some() {}
+    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn symbolication_service(
     req: Request<hyper::body::Incoming>,
     template_values: Arc<HashMap<&'static str, String>>,
     symbol_manager: Arc<SymbolManager>,
+    symbolication_cache: Arc<SymbolicationCache>,
     profile_filename: Option<PathBuf>,
     path_prefix: String,
+    allowed_origins: Arc<Vec<String>>,
+    metrics: Arc<ServerMetrics>,
+    lifetime: Arc<ServerLifetime>,
 ) -> Result<Response<Either<String, BoxBody<Bytes, std::io::Error>>>, hyper::Error> {
+    // Monitoring shouldn't keep an otherwise unused server alive.
+    if req.uri().path() != "/metrics" {
+        lifetime.request_received();
+    }
     let has_profile = profile_filename.is_some();
     let method = req.method();
     let path = req.uri().path();
@@ -352,6 +668,16 @@ async fn symbolication_service(
                 *response.body_mut() =
                     Either::Left(substitute_template(template, &template_values));
             }
+            (&Method::GET, "/metrics") => {
+                // The metrics don't reveal anything about the profile, so they
+                // are served without the secret prefix, at a fixed URL.
+                response.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                *response.body_mut() =
+                    Either::Left(metrics.render(symbol_manager.downloaded_bytes()));
+            }
             _ => {
                 *response.status_mut() = StatusCode::NOT_FOUND;
             }
@@ -360,13 +686,31 @@ async fn symbolication_service(
     };
 
     // If we get here, then the secret prefix was part of the URL.
-    // This part is open to the public: we allow requests across origins.
-    // For background on CORS, see this document:
+    // This part is open to the allowed profiler front-ends: we allow requests
+    // from their origins. For background on CORS, see this document:
     // https://w3c.github.io/webappsec-cors-for-developers/#cors
-    response.headers_mut().insert(
-        header::ACCESS_CONTROL_ALLOW_ORIGIN,
-        header::HeaderValue::from_static("*"),
-    );
+    if allowed_origins.iter().any(|origin| origin == "*") {
+        response.headers_mut().insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            header::HeaderValue::from_static("*"),
+        );
+    } else {
+        // The response depends on the origin, so caches must not share it
+        // between origins.
+        response
+            .headers_mut()
+            .insert(header::VARY, header::HeaderValue::from_static("Origin"));
+        if let Some(origin) = req.headers().get(header::ORIGIN) {
+            let is_allowed = origin
+                .to_str()
+                .map_or(false, |origin| is_allowed_origin(origin, &allowed_origins));
+            if is_allowed {
+                response
+                    .headers_mut()
+                    .insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            }
+        }
+    }
 
     match (method, path_without_prefix, profile_filename) {
         (&Method::OPTIONS, _, _) => {
@@ -380,7 +724,7 @@ async fn symbolication_service(
                 // Reassure the client that we are CORS-aware and that it's free to request whatever.
                 response.headers_mut().insert(
                     header::ACCESS_CONTROL_ALLOW_METHODS,
-                    header::HeaderValue::from_static("POST, GET, OPTIONS"),
+                    header::HeaderValue::from_static("POST, GET, HEAD, OPTIONS"),
                 );
                 response.headers_mut().insert(
                     header::ACCESS_CONTROL_MAX_AGE,
@@ -397,12 +741,13 @@ async fn symbolication_service(
                 // This is a regular OPTIONS request. Just send an Allow header with the allowed methods.
                 response.headers_mut().insert(
                     header::ALLOW,
-                    header::HeaderValue::from_static("POST, GET, OPTIONS"),
+                    header::HeaderValue::from_static("POST, GET, HEAD, OPTIONS"),
                 );
             }
         }
-        (&Method::GET, "/profile.json", Some(profile_filename)) => {
-            if profile_filename.extension() == Some(OsStr::new("gz")) {
+        (&Method::GET | &Method::HEAD, "/profile.json", Some(profile_filename)) => {
+            let is_gzipped = profile_filename.extension() == Some(OsStr::new("gz"));
+            if is_gzipped {
                 response.headers_mut().insert(
                     header::CONTENT_ENCODING,
                     header::HeaderValue::from_static("gzip"),
@@ -415,28 +760,109 @@ async fn symbolication_service(
 
             // Stream the file. This follows the send_file example from the hyper repo.
             // https://github.com/hyperium/hyper/blob/7206fe30302937075c51c16a69d1eb3bbce6a671/examples/send_file.rs
-            let file = tokio::fs::File::open(&profile_filename)
+            let mut file = tokio::fs::File::open(&profile_filename)
                 .await
                 .expect("couldn't open profile file");
+            let file_len = file
+                .metadata()
+                .await
+                .expect("couldn't get the profile file's metadata")
+                .len();
+
+            // Support byte range requests so that clients can load huge profiles in
+            // chunks. Ranges of gzipped files would be ranges of the compressed
+            // bytes, which isn't useful, so those are always sent in full.
+            let mut range = 0..file_len;
+            if !is_gzipped {
+                response.headers_mut().insert(
+                    header::ACCEPT_RANGES,
+                    header::HeaderValue::from_static("bytes"),
+                );
+                response.headers_mut().insert(
+                    header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                    header::HeaderValue::from_static(
+                        "Accept-Ranges, Content-Range, Content-Length",
+                    ),
+                );
+                let requested_range = req
+                    .headers()
+                    .get(header::RANGE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| parse_byte_range(value, file_len));
+                match requested_range {
+                    Some(Ok(requested_range)) => {
+                        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                        response.headers_mut().insert(
+                            header::CONTENT_RANGE,
+                            header::HeaderValue::from_str(&format!(
+                                "bytes {}-{}/{file_len}",
+                                requested_range.start,
+                                requested_range.end - 1
+                            ))
+                            .unwrap(),
+                        );
+                        range = requested_range;
+                    }
+                    Some(Err(())) => {
+                        *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                        response.headers_mut().insert(
+                            header::CONTENT_RANGE,
+                            header::HeaderValue::from_str(&format!("bytes */{file_len}")).unwrap(),
+                        );
+                        return Ok(response);
+                    }
+                    None => {}
+                }
+            }
+            response.headers_mut().insert(
+                header::CONTENT_LENGTH,
+                header::HeaderValue::from(range.end - range.start),
+            );
+            if method == Method::HEAD {
+                return Ok(response);
+            }
+
+            file.seek(SeekFrom::Start(range.start))
+                .await
+                .expect("couldn't seek in profile file");
 
             // Wrap in a tokio_util::io::ReaderStream
-            let reader_stream = ReaderStream::new(file);
+            let reader_stream = ReaderStream::new(file.take(range.end - range.start));
 
             let stream_body = StreamBody::new(reader_stream.map_ok(Frame::data));
             *response.body_mut() = Either::Right(stream_body.boxed());
         }
+        (&Method::POST, "/quit", _) => {
+            lifetime.quit_requested.notify_one();
+            *response.body_mut() = Either::Left("Stopping the server.\n".to_string());
+        }
         (&Method::POST, path, _) => {
             response.headers_mut().insert(
                 header::CONTENT_TYPE,
                 header::HeaderValue::from_static("application/json"),
             );
             let path = path.to_string();
+            let start_time = Instant::now();
             // Await the full body to be concatenated into a `Collected<Bytes>`.
             let full_body = req.into_body().collect().await?;
             // Convert the `Collected<Bytes>` into a `String`.
             let full_body =
                 String::from_utf8(full_body.to_bytes().to_vec()).expect("invalid utf-8");
-            let response_json = symbol_manager.query_json_api(&path, &full_body).await;
+            let response_json = if SymbolicationCache::is_cacheable(&path) {
+                let cached_response = symbolication_cache.get(&path, &full_body);
+                metrics.record_cache_lookup(cached_response.is_some());
+                match cached_response {
+                    Some(cached_response) => cached_response.to_string(),
+                    None => {
+                        let response_json = symbol_manager.query_json_api(&path, &full_body).await;
+                        symbolication_cache.insert(&path, &full_body, &response_json);
+                        response_json
+                    }
+                }
+            } else {
+                symbol_manager.query_json_api(&path, &full_body).await
+            };
+            metrics.record_request(&path, start_time.elapsed());
 
             *response.body_mut() = Either::Left(response_json);
         }
@@ -448,6 +874,60 @@ async fn symbolication_service(
     Ok(response)
 }
 
+/// Returns the origin of `url`, i.e. the scheme, the host and the port.
+fn url_origin(url: &str) -> &str {
+    let host_start = url.find("://").map_or(0, |i| i + 3);
+    match url[host_start..].find('/') {
+        Some(path_start) => &url[..host_start + path_start],
+        None => url,
+    }
+}
+
+/// Whether `origin` is one of the allowed origins. Origins are compared
+/// case-insensitively, and a trailing slash in the allowed ones is ignored.
+fn is_allowed_origin(origin: &str, allowed_origins: &[String]) -> bool {
+    allowed_origins.iter().any(|allowed| {
+        allowed == "*" || url_origin(allowed.trim_end_matches('/')).eq_ignore_ascii_case(origin)
+    })
+}
+
+/// Parses the value of a `Range` header with a single byte range, such as
+/// `bytes=0-499`, `bytes=500-` or `bytes=-500`.
+///
+/// Returns `None` if the header should be ignored, in which case the whole file
+/// is sent. Multiple ranges are not supported and are ignored as well, and so
+/// are invalid ranges whose end is before their start. Returns `Some(Err(()))`
+/// if the range lies outside of the file.
+fn parse_byte_range(value: &str, file_len: u64) -> Option<Result<Range<u64>, ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        let suffix_len: u64 = end.parse().ok()?;
+        file_len.saturating_sub(suffix_len)..file_len
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = match end {
+            "" => file_len,
+            end => {
+                let end: u64 = end.parse().ok()?;
+                if end < start {
+                    return None;
+                }
+                end.saturating_add(1).min(file_len)
+            }
+        };
+        start..end
+    };
+    if range.start >= range.end {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
+}
+
 fn substitute_template(template: &str, template_values: &HashMap<&'static str, String>) -> String {
     let mut s = template.to_string();
     for (key, value) in template_values {
@@ -530,5 +1010,107 @@ mod test {
         assert_eq!(p.threads[0].libs.len(), 1);
         assert_eq!(p.threads[0].libs[0], ProfileJsonLib::default());
         assert!(p.processes.is_empty());
+
+        let lib = r#"{"debugName":"a.pdb","breakpadId":"BBCAAEF8A5C7FB1F4C4C44205044422E1"}"#;
+        let json = format!(r#"[{{"libs":[{lib}]}}]{{"threads":[{{"libs":[{lib}]}}]}}"#);
+        let map = parse_libinfo_map_from_profile(json.as_bytes()).unwrap();
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn libinfo_map_from_parsed_profiles() {
+        let json = r#"{
+            "libs": [{"debugName":"a.pdb","breakpadId":"BBCAAEF8A5C7FB1F4C4C44205044422E1"}],
+            "processes": [{
+                "threads": [{"libs": [{"debugName":"b.so","breakpadId":"0123456789ABCDEF0123456789ABCDEF0","path":"/b.so"}]}]
+            }]
+        }"#;
+        let profile: serde_json::Value = serde_json::from_str(json).unwrap();
+        let map = libinfo_map_from_parsed_profile(&profile);
+        assert_eq!(
+            map,
+            parse_libinfo_map_from_profile(json.as_bytes()).unwrap()
+        );
+        assert_eq!(map.len(), 2);
+        let array = serde_json::Value::Array(vec![profile]);
+        assert_eq!(libinfo_map_from_parsed_profile(&array), map);
+    }
+
+    #[test]
+    fn allowed_origins() {
+        let allowed = vec![
+            "https://profiler.firefox.com".to_string(),
+            "http://localhost:4242/".to_string(),
+        ];
+        assert!(is_allowed_origin("https://profiler.firefox.com", &allowed));
+        assert!(is_allowed_origin("http://localhost:4242", &allowed));
+        assert!(!is_allowed_origin("http://localhost:4243", &allowed));
+        assert!(!is_allowed_origin("https://example.com", &allowed));
+        assert!(is_allowed_origin("https://example.com", &["*".to_string()]));
+        assert_eq!(
+            url_origin("https://deploy-preview-1--perf-html.netlify.app/from-url/x"),
+            "https://deploy-preview-1--perf-html.netlify.app"
+        );
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        use tokio::io::AsyncWriteExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let lifetime = Arc::new(ServerLifetime::new());
+        let started = *lifetime.last_request.lock().unwrap();
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let server = tokio::task::spawn(run_server(
+            listener,
+            Arc::new(SymbolManager::with_config(SymbolManagerConfig::default())),
+            Arc::new(SymbolicationCache::new(None)),
+            None,
+            Arc::new(HashMap::new()),
+            "/prefix".to_string(),
+            Arc::new(Vec::new()),
+            Arc::new(ServerMetrics::default()),
+            lifetime.clone(),
+            shutdown_receiver,
+        ));
+
+        // Keep the connection open after the response.
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        let len = stream.read(&mut buf).await.unwrap();
+        assert!(buf[..len].starts_with(b"HTTP/1.1 200 OK"));
+        // Monitoring doesn't count as activity.
+        assert_eq!(*lifetime.last_request.lock().unwrap(), started);
+
+        shutdown_sender.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), server)
+            .await
+            .expect("the server should stop")
+            .unwrap()
+            .unwrap();
+        // The idle connection has been closed.
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn parse_byte_ranges() {
+        assert_eq!(parse_byte_range("bytes=0-499", 1000), Some(Ok(0..500)));
+        assert_eq!(parse_byte_range("bytes=500-", 1000), Some(Ok(500..1000)));
+        assert_eq!(parse_byte_range("bytes=-300", 1000), Some(Ok(700..1000)));
+        assert_eq!(
+            parse_byte_range("bytes=900-2000", 1000),
+            Some(Ok(900..1000))
+        );
+        assert_eq!(parse_byte_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_byte_range("bytes=1000-1200", 1000), Some(Err(())));
+        assert_eq!(parse_byte_range("bytes=500-100", 1000), None);
+        assert_eq!(parse_byte_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_byte_range("items=0-5", 1000), None);
+        assert_eq!(parse_byte_range("bytes=abc-", 1000), None);
     }
 }
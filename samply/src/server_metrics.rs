@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The API paths which get their own label. Requests to other paths are
+/// counted as "other", so that arbitrary URLs can't create new time series.
+const API_PATHS: &[&str] = &["/symbolicate/v5", "/source/v1", "/asm/v1"];
+
+/// The upper bounds of the request latency histogram buckets, in seconds.
+/// Symbolication requests which need to download or parse large debug files
+/// can take many seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+
+/// Counters for the API requests to the local server, which are served at
+/// `/metrics` in the Prometheus text format. This allows monitoring a
+/// long-running `samply serve` which is used by a whole team.
+#[derive(Default)]
+pub struct ServerMetrics {
+    requests: Mutex<BTreeMap<&'static str, RequestStats>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct RequestStats {
+    /// The number of requests per latency bucket, not cumulative. The last
+    /// entry is for the requests which took longer than the last bucket.
+    bucket_counts: [u64; LATENCY_BUCKETS.len() + 1],
+    count: u64,
+    latency_sum: Duration,
+}
+
+impl ServerMetrics {
+    pub fn record_request(&self, path: &str, latency: Duration) {
+        let label = API_PATHS
+            .iter()
+            .find(|api_path| **api_path == path)
+            .copied()
+            .unwrap_or("other");
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| latency.as_secs_f64() <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        let mut requests = self.requests.lock().unwrap();
+        let stats = requests.entry(label).or_default();
+        stats.bucket_counts[bucket] += 1;
+        stats.count += 1;
+        stats.latency_sum += latency;
+    }
+
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let counter = match hit {
+            true => &self.cache_hits,
+            false => &self.cache_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the metrics in the Prometheus text exposition format.
+    pub fn render(&self, downloaded_bytes: u64) -> String {
+        let mut s = String::new();
+        let requests = self.requests.lock().unwrap();
+
+        let _ = writeln!(
+            s,
+            "# HELP samply_api_requests_total The number of API requests, by path."
+        );
+        let _ = writeln!(s, "# TYPE samply_api_requests_total counter");
+        for (path, stats) in requests.iter() {
+            let _ = writeln!(
+                s,
+                "samply_api_requests_total{{path=\"{path}\"}} {}",
+                stats.count
+            );
+        }
+
+        let _ = writeln!(
+            s,
+            "# HELP samply_api_request_duration_seconds The time it took to answer API requests, by path."
+        );
+        let _ = writeln!(s, "# TYPE samply_api_request_duration_seconds histogram");
+        for (path, stats) in requests.iter() {
+            let mut cumulative_count = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(stats.bucket_counts.iter()) {
+                cumulative_count += count;
+                let _ = writeln!(
+                    s,
+                    "samply_api_request_duration_seconds_bucket{{path=\"{path}\",le=\"{bound}\"}} {cumulative_count}"
+                );
+            }
+            let _ = writeln!(
+                s,
+                "samply_api_request_duration_seconds_bucket{{path=\"{path}\",le=\"+Inf\"}} {}",
+                stats.count
+            );
+            let _ = writeln!(
+                s,
+                "samply_api_request_duration_seconds_sum{{path=\"{path}\"}} {}",
+                stats.latency_sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                s,
+                "samply_api_request_duration_seconds_count{{path=\"{path}\"}} {}",
+                stats.count
+            );
+        }
+
+        let counters = [
+            (
+                "samply_symbolication_cache_hits_total",
+                "The number of symbolication requests which were answered from the cache.",
+                self.cache_hits.load(Ordering::Relaxed),
+            ),
+            (
+                "samply_symbolication_cache_misses_total",
+                "The number of symbolication requests which had to look up symbols.",
+                self.cache_misses.load(Ordering::Relaxed),
+            ),
+            (
+                "samply_downloaded_bytes_total",
+                "The number of bytes which were downloaded from symbol servers and source URLs.",
+                downloaded_bytes,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(s, "# HELP {name} {help}");
+            let _ = writeln!(s, "# TYPE {name} counter");
+            let _ = writeln!(s, "{name} {value}");
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::ServerMetrics;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = ServerMetrics::default();
+        metrics.record_request("/symbolicate/v5", Duration::from_millis(3));
+        metrics.record_request("/symbolicate/v5", Duration::from_millis(70));
+        metrics.record_request("/unknown", Duration::from_secs(100));
+        metrics.record_cache_lookup(true);
+        metrics.record_cache_lookup(false);
+        metrics.record_cache_lookup(false);
+        let text = metrics.render(1234);
+        let lines: Vec<&str> = text.lines().collect();
+        for expected_line in [
+            "samply_api_requests_total{path=\"/symbolicate/v5\"} 2",
+            "samply_api_requests_total{path=\"other\"} 1",
+            "samply_api_request_duration_seconds_bucket{path=\"/symbolicate/v5\",le=\"0.005\"} 1",
+            "samply_api_request_duration_seconds_bucket{path=\"/symbolicate/v5\",le=\"0.05\"} 1",
+            "samply_api_request_duration_seconds_bucket{path=\"/symbolicate/v5\",le=\"0.1\"} 2",
+            "samply_api_request_duration_seconds_bucket{path=\"other\",le=\"60\"} 0",
+            "samply_api_request_duration_seconds_bucket{path=\"other\",le=\"+Inf\"} 1",
+            "samply_api_request_duration_seconds_sum{path=\"/symbolicate/v5\"} 0.073",
+            "samply_symbolication_cache_hits_total 1",
+            "samply_symbolication_cache_misses_total 2",
+            "samply_downloaded_bytes_total 1234",
+        ] {
+            assert!(lines.contains(&expected_line), "missing {expected_line:?}");
+        }
+    }
+}
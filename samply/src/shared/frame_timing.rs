@@ -0,0 +1,162 @@
+use fxprof_processed_profile::{
+    CategoryHandle, MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema,
+    MarkerSchemaField, MarkerStaticField, MarkerTiming, ProcessHandle, Profile, ProfilerMarker,
+};
+use serde_json::json;
+
+use super::process_sample_data::MarkerSpanOnThread;
+
+/// Frames which take longer than this multiple of the median frame time get a
+/// "Slow frame" marker.
+const SLOW_FRAME_FACTOR: u64 = 2;
+
+/// Treat successive occurrences of the marker named `frame_marker_name` as frame
+/// boundaries. This adds a "Frame time" counter to the process, and a "Slow frame"
+/// marker for every frame which took much longer than the typical frame.
+pub fn add_frame_timing(
+    profile: &mut Profile,
+    process: ProcessHandle,
+    marker_spans: &[MarkerSpanOnThread],
+    frame_marker_name: &str,
+) {
+    let mut frame_markers: Vec<&MarkerSpanOnThread> = marker_spans
+        .iter()
+        .filter(|span| span.name == frame_marker_name)
+        .collect();
+    if frame_markers.len() < 2 {
+        return;
+    }
+    frame_markers.sort_by_key(|span| span.start_time);
+
+    // (thread of the frame's marker, start, end, duration in ns)
+    let frames: Vec<_> = frame_markers
+        .windows(2)
+        .map(|pair| {
+            let (start, end) = (pair[0].start_time, pair[1].start_time);
+            let duration_ns = end
+                .nanos_since_reference()
+                .saturating_sub(start.nanos_since_reference());
+            (pair[0].thread_handle, start, end, duration_ns)
+        })
+        .collect();
+
+    let durations: Vec<u64> = frames.iter().map(|frame| frame.3).collect();
+    let median_ns = median(durations);
+
+    let counter = profile.add_counter(
+        process,
+        "Frame time",
+        "Graphics",
+        &format!("Time between successive \"{frame_marker_name}\" markers"),
+    );
+    // Counter samples are deltas, so that the accumulated value is the duration
+    // of the frame which ends at the sample's timestamp.
+    let mut previous_duration_ms = 0.0;
+    for &(thread, start, end, duration_ns) in &frames {
+        let duration_ms = duration_ns as f64 / 1_000_000.0;
+        profile.add_counter_sample(counter, end, duration_ms - previous_duration_ms, 1);
+        previous_duration_ms = duration_ms;
+
+        if is_slow_frame(duration_ns, median_ns) {
+            profile.add_marker(
+                thread,
+                CategoryHandle::OTHER,
+                "Slow frame",
+                SlowFrameMarker {
+                    duration_ms,
+                    median_ms: median_ns as f64 / 1_000_000.0,
+                },
+                MarkerTiming::Interval(start, end),
+            );
+        }
+    }
+}
+
+/// The median of `durations`, which must not be empty.
+fn median(mut durations: Vec<u64>) -> u64 {
+    durations.sort_unstable();
+    durations[durations.len() / 2]
+}
+
+/// Whether a frame which took `duration_ns` should get a "Slow frame" marker.
+/// A median of zero means that most frame markers share their timestamp with
+/// the previous one; no frame counts as slow then.
+fn is_slow_frame(duration_ns: u64, median_ns: u64) -> bool {
+    median_ns != 0 && duration_ns > median_ns.saturating_mul(SLOW_FRAME_FACTOR)
+}
+
+#[derive(Debug, Clone)]
+pub struct SlowFrameMarker {
+    pub duration_ms: f64,
+    pub median_ms: f64,
+}
+
+impl ProfilerMarker for SlowFrameMarker {
+    const MARKER_TYPE_NAME: &'static str = "SlowFrame";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "duration": self.duration_ms,
+            "median": self.median_ms,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.duration}"),
+            tooltip_label: Some("Slow frame: {marker.data.duration}"),
+            table_label: Some("{marker.data.duration} (median {marker.data.median})"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "duration",
+                    label: "Frame time",
+                    format: MarkerFieldFormat::Milliseconds,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "median",
+                    label: "Median frame time",
+                    format: MarkerFieldFormat::Milliseconds,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted for frames which took more than twice as long as the median frame, based on the --frame-marker markers.",
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn median_of_unsorted_durations() {
+        assert_eq!(median(vec![30, 10, 20]), 20);
+        assert_eq!(median(vec![16, 17, 16, 50]), 17);
+        assert_eq!(median(vec![5]), 5);
+    }
+
+    #[test]
+    fn slow_frames() {
+        assert!(!is_slow_frame(16, 16));
+        assert!(!is_slow_frame(32, 16));
+        assert!(is_slow_frame(33, 16));
+    }
+
+    #[test]
+    fn zero_median_flags_nothing() {
+        assert_eq!(median(vec![0, 0, 0, 40]), 0);
+        assert!(!is_slow_frame(40, 0));
+        assert!(!is_slow_frame(0, 0));
+    }
+}
@@ -15,6 +15,102 @@ pub enum JsName {
     NonSelfHosted(StringHandle),
 }
 
+/// The kinds of work a managed runtime does besides running the program's
+/// code. Frames of each kind get the same category no matter which runtime
+/// they come from, so that runtime overhead can be found with one filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeKind {
+    /// Garbage collection, including write barriers.
+    Gc,
+    /// Compiling code at runtime.
+    JitCompiler,
+    /// Other runtime code, e.g. stubs, type loading or safepoints.
+    Runtime,
+}
+
+impl RuntimeKind {
+    /// Prefixes of JIT symbol names, from perf maps and jitdump files, which
+    /// mark runtime code. Names are only matched at the start, so that
+    /// methods of the program which happen to contain these words aren't
+    /// treated as runtime code.
+    const JIT_SYMBOL_PREFIXES: &'static [(&'static str, RuntimeKind)] = &[
+        // HotSpot, with -XX:+DumpPerfMapAtExit or jcmd Compiler.perfmap.
+        ("StubRoutines", RuntimeKind::Runtime),
+        ("RuntimeStub", RuntimeKind::Runtime),
+        ("I2C/C2I adapters", RuntimeKind::Runtime),
+        ("SafepointBlob", RuntimeKind::Runtime),
+        ("DeoptimizationBlob", RuntimeKind::Runtime),
+        ("UncommonTrapBlob", RuntimeKind::Runtime),
+        ("ExceptionBlob", RuntimeKind::Runtime),
+        ("MethodHandlesAdapterBlob", RuntimeKind::Runtime),
+        ("vtable chunks", RuntimeKind::Runtime),
+        ("itable chunks", RuntimeKind::Runtime),
+        // V8, with --perf-prof or --perf-basic-prof. Builtins have their own
+        // "Builtin" category.
+        ("Stub:", RuntimeKind::Runtime),
+        ("Handler:", RuntimeKind::Runtime),
+    ];
+
+    /// Prefixes of the names of CoreCLR stubs for GC helpers, after the
+    /// "stub<N> " prefix, e.g. "stub<5> JIT_WriteBarrier<JitHelper>". All
+    /// other stubs are [`RuntimeKind::Runtime`].
+    const CORECLR_GC_STUB_PREFIXES: &'static [&'static str] = &[
+        "JIT_WriteBarrier",
+        "JIT_CheckedWriteBarrier",
+        "JIT_ByRefWriteBarrier",
+        "JIT_New",
+    ];
+
+    /// The file names of the runtimes' native libraries, without the "lib"
+    /// prefix and the extension.
+    const LIB_STEMS: &'static [(&'static str, RuntimeKind)] = &[
+        ("clrgc", RuntimeKind::Gc),
+        ("clrjit", RuntimeKind::JitCompiler),
+        ("coreclr", RuntimeKind::Runtime),
+        ("monosgen-2.0", RuntimeKind::Runtime),
+        ("jvm", RuntimeKind::Runtime),
+    ];
+
+    pub fn for_jit_symbol(name: &str) -> Option<Self> {
+        if let Some(stub_name) = coreclr_stub_name(name) {
+            let is_gc_stub = Self::CORECLR_GC_STUB_PREFIXES
+                .iter()
+                .any(|prefix| stub_name.starts_with(prefix));
+            return Some(if is_gc_stub {
+                RuntimeKind::Gc
+            } else {
+                RuntimeKind::Runtime
+            });
+        }
+        Self::JIT_SYMBOL_PREFIXES
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix))
+            .map(|(_, kind)| *kind)
+    }
+
+    /// `lib_name` is the file name, e.g. "libjvm.so" or "coreclr.dll".
+    pub fn for_lib_name(lib_name: &str) -> Option<Self> {
+        let name = lib_name.strip_prefix("lib").unwrap_or(lib_name);
+        let stem = [".so", ".dylib", ".dll"]
+            .iter()
+            .find_map(|extension| name.strip_suffix(extension))?;
+        Self::LIB_STEMS
+            .iter()
+            .find(|(lib_stem, _)| *lib_stem == stem)
+            .map(|(_, kind)| *kind)
+    }
+}
+
+/// Returns the name of a CoreCLR stub from a perf map written with
+/// DOTNET_PerfMapEnabled, i.e. "JIT_New" for "stub<3> JIT_New".
+fn coreclr_stub_name(name: &str) -> Option<&str> {
+    let (index, stub_name) = name.strip_prefix("stub<")?.split_once("> ")?;
+    if index.is_empty() || !index.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(stub_name)
+}
+
 #[derive(Debug, Clone)]
 pub struct JitCategoryManager {
     categories: Vec<LazilyCreatedCategory>,
@@ -23,6 +119,15 @@ pub struct JitCategoryManager {
     wasm_liftoff_category: LazilyCreatedCategory,
     wasm_turbofan_category: LazilyCreatedCategory,
     generic_jit_category: LazilyCreatedCategory,
+    gc_category: LazilyCreatedCategory,
+    jit_compiler_category: LazilyCreatedCategory,
+    runtime_category: LazilyCreatedCategory,
+}
+
+impl Default for JitCategoryManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl JitCategoryManager {
@@ -98,9 +203,35 @@ impl JitCategoryManager {
                 CategoryColor::Green,
             ),
             generic_jit_category: LazilyCreatedCategory::new("JIT", CategoryColor::Purple),
+            gc_category: LazilyCreatedCategory::new("GC", CategoryColor::Brown),
+            jit_compiler_category: LazilyCreatedCategory::new(
+                "JIT compiler",
+                CategoryColor::Purple,
+            ),
+            runtime_category: LazilyCreatedCategory::new("Runtime", CategoryColor::Gray),
         }
     }
 
+    /// The category for runtime frames of this kind.
+    pub fn runtime_category(&mut self, kind: RuntimeKind, profile: &mut Profile) -> CategoryHandle {
+        match kind {
+            RuntimeKind::Gc => self.gc_category.get(profile),
+            RuntimeKind::JitCompiler => self.jit_compiler_category.get(profile),
+            RuntimeKind::Runtime => self.runtime_category.get(profile),
+        }
+    }
+
+    /// The category for the frames of a native library, if it belongs to a
+    /// managed runtime, e.g. libjvm.so or libcoreclr.so.
+    pub fn classify_runtime_lib(
+        &mut self,
+        lib_name: &str,
+        profile: &mut Profile,
+    ) -> Option<CategoryPairHandle> {
+        let kind = RuntimeKind::for_lib_name(lib_name)?;
+        Some(self.runtime_category(kind, profile).into())
+    }
+
     /// Get the category and JS function name for a function from JIT code.
     ///
     /// The category is only created in the profile once a function with that
@@ -188,6 +319,10 @@ impl JitCategoryManager {
         // "run_wasm_sm.js line 41 > WebAssembly.Module:916249: Function Element.updateChild"
         // "run_wasm_sm.js line 41 > WebAssembly.Module:825626: Function wasm-function[1491]"
 
+        if let Some(kind) = RuntimeKind::for_jit_symbol(name) {
+            return (self.runtime_category(kind, profile).into(), None);
+        }
+
         let category = self.generic_jit_category.get(profile);
         (category.into(), None)
     }
@@ -222,7 +357,7 @@ impl JitCategoryManager {
 }
 
 #[derive(Debug, Clone)]
-struct LazilyCreatedCategory {
+pub struct LazilyCreatedCategory {
     name: &'static str,
     color: CategoryColor,
     handle: Option<CategoryHandle>,
@@ -269,4 +404,63 @@ mod test {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn runtime_frames() {
+        assert_eq!(
+            RuntimeKind::for_jit_symbol("stub<12> JIT_WriteBarrier<JitHelper>"),
+            Some(RuntimeKind::Gc)
+        );
+        assert_eq!(
+            RuntimeKind::for_jit_symbol("stub<3> AllocateTemporaryEntryPoints<PRESTUB_METHOD>"),
+            Some(RuntimeKind::Runtime)
+        );
+        assert_eq!(
+            RuntimeKind::for_jit_symbol("I2C/C2I adapters(0xab)"),
+            Some(RuntimeKind::Runtime)
+        );
+        assert_eq!(
+            RuntimeKind::for_jit_symbol("stub<7> JIT_CheckedWriteBarrier"),
+            Some(RuntimeKind::Gc)
+        );
+        assert_eq!(
+            RuntimeKind::for_jit_symbol("Stub:CEntryStub"),
+            Some(RuntimeKind::Runtime)
+        );
+        assert_eq!(
+            RuntimeKind::for_jit_symbol("void [App] App.Program::Main(string[])[Optimized]"),
+            None
+        );
+        // The patterns only match at the start of the name.
+        assert_eq!(
+            RuntimeKind::for_jit_symbol("void [App] App.Cache::WriteBarrierTest()[Optimized]"),
+            None
+        );
+        assert_eq!(
+            RuntimeKind::for_jit_symbol("Lcom/example/RuntimeStubFactory;::create"),
+            None
+        );
+        assert_eq!(
+            RuntimeKind::for_jit_symbol("LazyCompile:*Stub: foo.js:1"),
+            None
+        );
+        assert_eq!(
+            RuntimeKind::for_jit_symbol("instance void [App] stub<T>::Run()"),
+            None
+        );
+        assert_eq!(RuntimeKind::for_jit_symbol("stub<x> JIT_New"), None);
+        assert_eq!(
+            RuntimeKind::for_lib_name("libjvm.so"),
+            Some(RuntimeKind::Runtime)
+        );
+        assert_eq!(
+            RuntimeKind::for_lib_name("libclrjit.so"),
+            Some(RuntimeKind::JitCompiler)
+        );
+        assert_eq!(
+            RuntimeKind::for_lib_name("clrgc.dll"),
+            Some(RuntimeKind::Gc)
+        );
+        assert_eq!(RuntimeKind::for_lib_name("libjvmti.so"), None);
+    }
 }
@@ -3,6 +3,8 @@ use fxprof_processed_profile::{
 };
 use linux_perf_data::jitdump::{JitDumpReader, JitDumpRecord, JitDumpRecordType};
 
+use std::fs::File;
+use std::io::{Read, Seek};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -18,7 +20,13 @@ use super::utils::open_file_with_fallback;
 #[derive(Debug)]
 pub struct JitDumpManager {
     pending_jitdump_paths: Vec<(ThreadHandle, PathBuf, Option<PathBuf>)>,
-    processors: Vec<SingleJitDumpProcessor>,
+    processors: Vec<SingleJitDumpProcessor<File>>,
+}
+
+impl Default for JitDumpManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl JitDumpManager {
@@ -51,7 +59,7 @@ impl JitDumpManager {
                 fn jitdump_reader_for_path(
                     path: &Path,
                     fallback_dir: Option<&Path>,
-                ) -> Option<(JitDumpReader<std::fs::File>, PathBuf)> {
+                ) -> Option<(JitDumpReader<File>, PathBuf)> {
                     let (file, path) = open_file_with_fallback(path, fallback_dir).ok()?;
                     let reader = JitDumpReader::new(file).ok()?;
                     Some((reader, path))
@@ -96,10 +104,11 @@ impl JitDumpManager {
     }
 }
 
+/// Turns the records of one jitdump file into lib mapping ops and a symbol table.
 #[derive(Debug)]
-struct SingleJitDumpProcessor {
+pub struct SingleJitDumpProcessor<R: Read + Seek> {
     /// Some() until a JIT_CODE_CLOSE record is encountered.
-    reader: Option<JitDumpReader<std::fs::File>>,
+    reader: Option<JitDumpReader<R>>,
     lib_handle: LibraryHandle,
     lib_mapping_ops: LibMappingOpQueue,
     symbols: Vec<Symbol>,
@@ -115,9 +124,9 @@ struct SingleJitDumpProcessor {
     cumulative_address: u32,
 }
 
-impl SingleJitDumpProcessor {
+impl<R: Read + Seek> SingleJitDumpProcessor<R> {
     pub fn new(
-        reader: JitDumpReader<std::fs::File>,
+        reader: JitDumpReader<R>,
         lib_handle: LibraryHandle,
         thread_handle: ThreadHandle,
     ) -> Self {
@@ -165,11 +174,15 @@ impl SingleJitDumpProcessor {
             };
             match raw_jitdump_record.parse() {
                 Ok(JitDumpRecord::CodeLoad(record)) => {
+                    // The file may be truncated or corrupt, so don't trust the
+                    // addresses and sizes to stay in range.
                     let start_avma = record.code_addr;
-                    let end_avma = start_avma + record.code_bytes.len() as u64;
+                    let end_avma = start_avma.saturating_add(record.code_bytes.len() as u64);
 
                     let relative_address_at_start = self.cumulative_address;
-                    self.cumulative_address += record.code_bytes.len() as u32;
+                    self.cumulative_address = self
+                        .cumulative_address
+                        .saturating_add(record.code_bytes.len() as u32);
 
                     let symbol_name = record.function_name.as_slice();
                     let symbol_name = std::str::from_utf8(&symbol_name).unwrap_or("");
@@ -221,7 +234,7 @@ impl SingleJitDumpProcessor {
                         LibMappingOp::Move(LibMappingMove {
                             old_start_avma: record.old_code_addr,
                             new_start_avma: record.new_code_addr,
-                            new_end_avma: record.new_code_addr + record.code_size,
+                            new_end_avma: record.new_code_addr.saturating_add(record.code_size),
                         }),
                     );
                     // TODO: Remove from + add to unwinder
@@ -0,0 +1,184 @@
+use fxprof_processed_profile::{
+    CategoryHandle, MarkerFieldFormat, MarkerLocation, MarkerTiming, Profile, RuntimeMarkerField,
+    RuntimeMarkerSchema, ThreadHandle, Timestamp,
+};
+use serde_json::{Map, Value};
+
+use std::collections::HashMap;
+
+use super::timestamp_converter::TimestampConverter;
+
+/// The marker type for JSON markers which don't specify a `"type"`.
+const DEFAULT_JSON_MARKER_TYPE: &str = "JsonMarker";
+
+/// A marker with arbitrary fields, from a JSON line in a marker file.
+///
+/// ```json
+/// {"name": "Request", "type": "HttpRequest", "start": 123, "end": 456, "data": {"url": "https://example.com/", "status": 200}}
+/// ```
+///
+/// `start` and `end` are timestamps in nanoseconds, in the same clock as the
+/// samples. Markers without an `end` are instant markers. The marker schema for
+/// each `type` is derived from the fields which are present in `data`.
+#[derive(Debug, Clone)]
+pub struct JsonMarker {
+    pub type_name: String,
+    pub name: String,
+    pub start_time: Timestamp,
+    pub end_time: Option<Timestamp>,
+    pub data: Map<String, Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct JsonMarkerOnThread {
+    pub thread_handle: ThreadHandle,
+    pub marker: JsonMarker,
+}
+
+pub fn parse_json_marker_line(
+    line: &str,
+    timestamp_converter: &TimestampConverter,
+) -> Option<JsonMarker> {
+    let Value::Object(mut object) = serde_json::from_str(line).ok()? else {
+        return None;
+    };
+    let name = object.get("name")?.as_str()?.to_owned();
+    let type_name = match object.get("type") {
+        Some(type_name) => type_name.as_str()?.to_owned(),
+        None => DEFAULT_JSON_MARKER_TYPE.to_owned(),
+    };
+    let start_time = timestamp_converter.convert_time(object.get("start")?.as_u64()?);
+    let end_time = match object.get("end") {
+        Some(end) => Some(timestamp_converter.convert_time(end.as_u64()?)),
+        None => None,
+    };
+    let data = match object.remove("data") {
+        Some(Value::Object(data)) => data,
+        Some(_) => return None,
+        None => Map::new(),
+    };
+    Some(JsonMarker {
+        type_name,
+        name,
+        start_time,
+        end_time,
+        data,
+    })
+}
+
+/// The kind of values which were seen for a field, across all markers of a type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FieldKind {
+    Integer,
+    Decimal,
+    Url,
+    String,
+}
+
+impl FieldKind {
+    fn for_value(value: &Value) -> Self {
+        match value {
+            Value::Number(n) if n.is_i64() || n.is_u64() => FieldKind::Integer,
+            Value::Number(_) => FieldKind::Decimal,
+            Value::String(s) if s.starts_with("http://") || s.starts_with("https://") => {
+                FieldKind::Url
+            }
+            _ => FieldKind::String,
+        }
+    }
+
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (FieldKind::Integer | FieldKind::Decimal, FieldKind::Integer | FieldKind::Decimal) => {
+                FieldKind::Decimal
+            }
+            _ => FieldKind::String,
+        }
+    }
+
+    fn format(self) -> MarkerFieldFormat {
+        match self {
+            FieldKind::Integer => MarkerFieldFormat::Integer,
+            FieldKind::Decimal => MarkerFieldFormat::Decimal,
+            FieldKind::Url => MarkerFieldFormat::Url,
+            FieldKind::String => MarkerFieldFormat::String,
+        }
+    }
+}
+
+/// Add the markers to the profile, with one marker schema per marker type. The
+/// schema lists every field that was seen on any marker of that type.
+pub fn add_json_markers(profile: &mut Profile, markers: Vec<JsonMarkerOnThread>) {
+    // type name -> fields, in the order of their first appearance
+    let mut fields_per_type: HashMap<&str, Vec<(&str, FieldKind)>> = HashMap::new();
+    for JsonMarkerOnThread { marker, .. } in &markers {
+        let fields = fields_per_type.entry(&marker.type_name).or_default();
+        for (key, value) in &marker.data {
+            if key == "type" {
+                continue;
+            }
+            let kind = FieldKind::for_value(value);
+            match fields.iter_mut().find(|(k, _)| k == key) {
+                Some((_, existing_kind)) => *existing_kind = existing_kind.merge(kind),
+                None => fields.push((key, kind)),
+            }
+        }
+    }
+
+    let schemas: HashMap<String, RuntimeMarkerSchema> = fields_per_type
+        .into_iter()
+        .map(|(type_name, fields)| (type_name.to_owned(), make_schema(type_name, &fields)))
+        .collect();
+
+    for JsonMarkerOnThread {
+        thread_handle,
+        marker,
+    } in markers
+    {
+        let schema = &schemas[&marker.type_name];
+        let mut data = Map::new();
+        for (key, value) in marker.data {
+            // Non-scalar values are shown as their JSON text.
+            let value = match value {
+                Value::Array(_) | Value::Object(_) | Value::Bool(_) => {
+                    Value::String(value.to_string())
+                }
+                value => value,
+            };
+            data.insert(key, value);
+        }
+        data.insert("type".to_owned(), Value::String(marker.type_name));
+        let timing = match marker.end_time {
+            Some(end_time) => MarkerTiming::Interval(marker.start_time, end_time),
+            None => MarkerTiming::Instant(marker.start_time),
+        };
+        profile.add_marker_with_schema(
+            thread_handle,
+            CategoryHandle::OTHER,
+            &marker.name,
+            schema,
+            Value::Object(data),
+            timing,
+        );
+    }
+}
+
+fn make_schema(type_name: &str, fields: &[(&str, FieldKind)]) -> RuntimeMarkerSchema {
+    RuntimeMarkerSchema {
+        type_name: type_name.to_owned(),
+        locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+        chart_label: None,
+        tooltip_label: Some("{marker.name}".to_owned()),
+        table_label: Some("{marker.name}".to_owned()),
+        fields: fields
+            .iter()
+            .map(|&(key, kind)| RuntimeMarkerField {
+                key: key.to_owned(),
+                label: key.to_owned(),
+                format: kind.format(),
+                searchable: matches!(kind, FieldKind::String | FieldKind::Url),
+            })
+            .collect(),
+    }
+}
@@ -0,0 +1,124 @@
+use super::jit_category_manager::RuntimeKind;
+
+/// The kinds of service threads which a HotSpot JVM starts next to the
+/// application's threads. A JVM can have hundreds of these, so their tracks
+/// are hidden by default and their samples get the category of the
+/// corresponding [`RuntimeKind`].
+///
+/// The patterns match the native thread names, which Linux truncates to 15
+/// bytes, e.g. "C2 CompilerThread0" becomes "C2 CompilerThre".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JvmThreadKind {
+    /// Garbage collector threads, for all of HotSpot's collectors.
+    Gc,
+    /// JIT compiler threads.
+    Compiler,
+    /// Other runtime threads, e.g. for safepoints, finalization or JFR.
+    Runtime,
+}
+
+impl JvmThreadKind {
+    const GC_PREFIXES: &'static [&'static str] = &[
+        "GC Thread#",
+        "ParGC Thread#",
+        "G1 ",
+        "ZDirector",
+        "ZDriver",
+        "ZStat",
+        "ZUnmapper",
+        "ZUncommitter",
+        "ZWorker",
+        "Shenandoah",
+    ];
+    const COMPILER_PREFIXES: &'static [&'static str] = &[
+        "C1 CompilerThre",
+        "C2 CompilerThre",
+        "JVMCI",
+        "Sweeper thread",
+    ];
+    const RUNTIME_PREFIXES: &'static [&'static str] = &[
+        "VM Thread",
+        "VM Periodic Tas",
+        "Reference Handl",
+        "Finalizer",
+        "Signal Dispatch",
+        "Service Thread",
+        "Monitor Deflati",
+        "Notification Th",
+        "Common-Cleaner",
+        "Attach Listener",
+        "JFR ",
+    ];
+
+    pub fn classify(thread_name: &str) -> Option<Self> {
+        let matches = |prefixes: &[&str]| {
+            prefixes
+                .iter()
+                .any(|prefix| thread_name.starts_with(prefix))
+        };
+        if matches(Self::GC_PREFIXES) {
+            Some(JvmThreadKind::Gc)
+        } else if matches(Self::COMPILER_PREFIXES) {
+            Some(JvmThreadKind::Compiler)
+        } else if matches(Self::RUNTIME_PREFIXES) {
+            Some(JvmThreadKind::Runtime)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether a process with these thread names is a JVM. Names like "Finalizer"
+/// are only classified in JVMs, so that other programs' threads with the same
+/// names are left alone.
+pub fn is_jvm_process<'a>(mut thread_names: impl Iterator<Item = &'a str>) -> bool {
+    thread_names.any(|name| {
+        name == "VM Thread"
+            || name.starts_with("C1 CompilerThre")
+            || name.starts_with("C2 CompilerThre")
+    })
+}
+
+impl From<JvmThreadKind> for RuntimeKind {
+    fn from(kind: JvmThreadKind) -> Self {
+        match kind {
+            JvmThreadKind::Gc => RuntimeKind::Gc,
+            JvmThreadKind::Compiler => RuntimeKind::JitCompiler,
+            JvmThreadKind::Runtime => RuntimeKind::Runtime,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_jvm_process, JvmThreadKind};
+
+    #[test]
+    fn classify_thread_names() {
+        assert_eq!(
+            JvmThreadKind::classify("GC Thread#3"),
+            Some(JvmThreadKind::Gc)
+        );
+        assert_eq!(
+            JvmThreadKind::classify("G1 Conc#0"),
+            Some(JvmThreadKind::Gc)
+        );
+        assert_eq!(
+            JvmThreadKind::classify("ZWorker#1"),
+            Some(JvmThreadKind::Gc)
+        );
+        assert_eq!(
+            JvmThreadKind::classify("C2 CompilerThre"),
+            Some(JvmThreadKind::Compiler)
+        );
+        assert_eq!(
+            JvmThreadKind::classify("Reference Handl"),
+            Some(JvmThreadKind::Runtime)
+        );
+        assert_eq!(JvmThreadKind::classify("java"), None);
+        assert_eq!(JvmThreadKind::classify("pool-1-thread-1"), None);
+
+        assert!(is_jvm_process(["java", "VM Thread"].into_iter()));
+        assert!(!is_jvm_process(["python3", "Finalizer"].into_iter()));
+    }
+}
@@ -0,0 +1,222 @@
+use fxprof_processed_profile::{
+    CategoryHandle, MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema,
+    MarkerSchemaField, MarkerStaticField, MarkerTiming, Profile, ProfilerMarker,
+};
+use serde_json::json;
+
+use super::process_sample_data::MarkerSpanOnThread;
+use super::recording_props::LatencyMarkerNames;
+
+/// Pairs every marker named `names.input` with the first marker named
+/// `names.present` which starts at or after it, and adds a "Latency" marker
+/// from the start of the input marker to the end of the present marker. The
+/// latency marker goes on the thread of the input marker.
+///
+/// Several input markers can be paired with the same present marker, e.g. when
+/// multiple input events are handled in one frame. Input markers after the last
+/// present marker don't get a latency marker.
+pub fn add_latency_markers(
+    profile: &mut Profile,
+    marker_spans: &[MarkerSpanOnThread],
+    names: &LatencyMarkerNames,
+) {
+    let mut present_markers: Vec<&MarkerSpanOnThread> = marker_spans
+        .iter()
+        .filter(|span| span.name == names.present)
+        .collect();
+    if present_markers.is_empty() {
+        return;
+    }
+    present_markers.sort_by_key(|span| span.start_time);
+
+    for input in marker_spans.iter().filter(|span| span.name == names.input) {
+        let next_present_index =
+            present_markers.partition_point(|present| present.start_time < input.start_time);
+        let Some(present) = present_markers.get(next_present_index) else {
+            continue;
+        };
+        let latency_ns = present
+            .end_time
+            .nanos_since_reference()
+            .saturating_sub(input.start_time.nanos_since_reference());
+        profile.add_marker(
+            input.thread_handle,
+            CategoryHandle::OTHER,
+            "Latency",
+            LatencyMarker {
+                latency_ms: latency_ns as f64 / 1_000_000.0,
+                input: input.name.clone(),
+                present: present.name.clone(),
+            },
+            MarkerTiming::Interval(input.start_time, present.end_time),
+        );
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LatencyMarker {
+    pub latency_ms: f64,
+    pub input: String,
+    pub present: String,
+}
+
+impl ProfilerMarker for LatencyMarker {
+    const MARKER_TYPE_NAME: &'static str = "Latency";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "latency": self.latency_ms,
+            "input": self.input,
+            "present": self.present,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.latency}"),
+            tooltip_label: Some("Latency: {marker.data.latency}"),
+            table_label: Some(
+                "{marker.data.input} to {marker.data.present}: {marker.data.latency}",
+            ),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "latency",
+                    label: "Latency",
+                    format: MarkerFieldFormat::Milliseconds,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "input",
+                    label: "Input marker",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "present",
+                    label: "Present marker",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "The time from an input marker to the end of the next present marker, based on the --latency-markers names.",
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{ReferenceTimestamp, SamplingInterval, ThreadHandle, Timestamp};
+
+    use super::*;
+
+    fn span(
+        thread_handle: ThreadHandle,
+        name: &str,
+        start_ms: u64,
+        end_ms: u64,
+    ) -> MarkerSpanOnThread {
+        MarkerSpanOnThread {
+            thread_handle,
+            start_time: Timestamp::from_nanos_since_reference(start_ms * 1_000_000),
+            end_time: Timestamp::from_nanos_since_reference(end_ms * 1_000_000),
+            name: name.to_string(),
+        }
+    }
+
+    /// The (start, end, latency, thread index) of each latency marker.
+    fn latency_markers(spans: &[(usize, &str, u64, u64)]) -> Vec<(f64, f64, f64, usize)> {
+        let mut profile = Profile::new(
+            "",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let process = profile.add_process("app", 1, Timestamp::from_millis_since_reference(0.0));
+        let threads = [
+            profile.add_thread(
+                process,
+                1,
+                Timestamp::from_millis_since_reference(0.0),
+                true,
+            ),
+            profile.add_thread(
+                process,
+                2,
+                Timestamp::from_millis_since_reference(0.0),
+                false,
+            ),
+        ];
+        let spans: Vec<MarkerSpanOnThread> = spans
+            .iter()
+            .map(|&(thread, name, start, end)| span(threads[thread], name, start, end))
+            .collect();
+        let names = LatencyMarkerNames {
+            input: "KeyDown".to_string(),
+            present: "Present".to_string(),
+        };
+        add_latency_markers(&mut profile, &spans, &names);
+
+        let profile = serde_json::to_value(&profile).unwrap();
+        let mut markers = Vec::new();
+        for (thread_index, thread) in profile["threads"].as_array().unwrap().iter().enumerate() {
+            let table = &thread["markers"];
+            for (i, data) in table["data"].as_array().unwrap().iter().enumerate() {
+                assert_eq!(data["type"], "Latency");
+                assert_eq!(data["input"], "KeyDown");
+                assert_eq!(data["present"], "Present");
+                markers.push((
+                    table["startTime"][i].as_f64().unwrap(),
+                    table["endTime"][i].as_f64().unwrap(),
+                    data["latency"].as_f64().unwrap(),
+                    thread_index,
+                ));
+            }
+        }
+        markers
+    }
+
+    #[test]
+    fn inputs_are_paired_with_the_next_present() {
+        let markers = latency_markers(&[
+            (1, "KeyDown", 10, 11),
+            (0, "Present", 20, 25),
+            (1, "KeyDown", 30, 31),
+            (1, "KeyDown", 32, 33),
+            (0, "Present", 8, 9),
+            (0, "Present", 40, 46),
+            (1, "KeyDown", 50, 51),
+        ]);
+        // Both inputs before the present at 40ms get the same present, and the
+        // input after the last present gets no marker. The markers go on the
+        // thread of the input.
+        assert_eq!(
+            markers,
+            vec![
+                (10.0, 25.0, 15.0, 1),
+                (30.0, 46.0, 16.0, 1),
+                (32.0, 46.0, 14.0, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn present_at_the_same_time_as_the_input() {
+        let markers = latency_markers(&[(0, "Present", 10, 12), (0, "KeyDown", 10, 10)]);
+        assert_eq!(markers, vec![(10.0, 12.0, 2.0, 0)]);
+    }
+
+    #[test]
+    fn no_present_markers() {
+        assert!(latency_markers(&[(0, "KeyDown", 10, 11)]).is_empty());
+        assert!(latency_markers(&[(0, "Present", 10, 11)]).is_empty());
+    }
+}
@@ -4,7 +4,7 @@ use fxprof_processed_profile::{CategoryPairHandle, LibMappings, LibraryHandle};
 
 use super::jit_category_manager::JsFrame;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub struct LibMappingInfo {
     pub lib_handle: LibraryHandle,
     pub category: Option<CategoryPairHandle>,
@@ -42,7 +42,10 @@ pub struct LibMappingsHierarchy {
 impl LibMappingsHierarchy {
     pub fn new(regular_lib_mappings_ops: LibMappingOpQueue) -> Self {
         Self {
-            regular_libs: (LibMappings::default(), regular_lib_mappings_ops.into_iter()),
+            regular_libs: (
+                LibMappings::default(),
+                regular_lib_mappings_ops.into_op_iter(),
+            ),
             jitdumps: Vec::new(),
             perf_map: None,
         }
@@ -50,22 +53,28 @@ impl LibMappingsHierarchy {
 
     pub fn add_jitdump_lib_mappings_ops(&mut self, lib_mappings_ops: LibMappingOpQueue) {
         self.jitdumps
-            .push((LibMappings::default(), lib_mappings_ops.into_iter()));
+            .push((LibMappings::default(), lib_mappings_ops.into_op_iter()));
     }
 
     pub fn add_perf_map_mappings(&mut self, mappings: LibMappings<LibMappingInfo>) {
         self.perf_map = Some(mappings);
     }
 
-    pub fn process_ops(&mut self, timestamp: u64) {
+    /// Applies the mapping changes up to `timestamp`. Returns whether any
+    /// mappings changed.
+    pub fn process_ops(&mut self, timestamp: u64) -> bool {
+        let mut changed = false;
         while let Some(op) = self.regular_libs.1.next_op_if_at_or_before(timestamp) {
             op.apply_to(&mut self.regular_libs.0);
+            changed = true;
         }
         for (mappings, ops) in &mut self.jitdumps {
             while let Some(op) = ops.next_op_if_at_or_before(timestamp) {
                 op.apply_to(mappings);
+                changed = true;
             }
         }
+        changed
     }
 
     pub fn convert_address(&self, address: u64) -> Option<(u32, &LibMappingInfo)> {
@@ -94,7 +103,7 @@ impl LibMappingOpQueue {
         self.0.push((timestamp, op));
     }
 
-    pub fn into_iter(self) -> LibMappingOpQueueIter {
+    pub fn into_op_iter(self) -> LibMappingOpQueueIter {
         LibMappingOpQueueIter(self.0.into_iter().peekable())
     }
 }
@@ -0,0 +1,121 @@
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use serde_json::json;
+
+/// A library which was mapped into or removed from a process. Applications
+/// which load plugins on demand can load and unload the same libraries many
+/// times, which is otherwise only visible as changing symbols.
+///
+/// Perf doesn't record unmaps, so on Linux there are only load markers.
+#[derive(Debug, Clone)]
+pub struct LibraryLoadMarker {
+    pub event: LibraryEvent,
+    pub name: String,
+    pub path: String,
+    pub base_address: u64,
+    /// The ELF build ID or the mach-O UUID.
+    pub build_id: Option<String>,
+    /// The file version and product version from the version resource of a
+    /// PE image, e.g. "10.0.19041.1".
+    pub file_version: Option<String>,
+    pub product_version: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibraryEvent {
+    Load,
+    Unload,
+}
+
+impl LibraryLoadMarker {
+    /// The marker name, which the marker chart shows in its first column.
+    pub fn marker_name(&self) -> &'static str {
+        match self.event {
+            LibraryEvent::Load => "Library load",
+            LibraryEvent::Unload => "Library unload",
+        }
+    }
+}
+
+impl ProfilerMarker for LibraryLoadMarker {
+    const MARKER_TYPE_NAME: &'static str = "LibraryLoad";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        let event = match self.event {
+            LibraryEvent::Load => "load",
+            LibraryEvent::Unload => "unload",
+        };
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "event": event,
+            "name": self.name,
+            "path": self.path,
+            "baseAddress": format!("{:#x}", self.base_address),
+            "buildId": self.build_id,
+            "fileVersion": self.file_version,
+            "productVersion": self.product_version,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.name}"),
+            tooltip_label: Some("{marker.data.event} {marker.data.name}"),
+            table_label: Some(
+                "{marker.data.event} {marker.data.name} at {marker.data.baseAddress}",
+            ),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "event",
+                    label: "Event",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "name",
+                    label: "Name",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "path",
+                    label: "Path",
+                    format: MarkerFieldFormat::FilePath,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "baseAddress",
+                    label: "Base address",
+                    format: MarkerFieldFormat::String,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "buildId",
+                    label: "Build ID",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "fileVersion",
+                    label: "File version",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "productVersion",
+                    label: "Product version",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "A library was mapped into or removed from the process.",
+                }),
+            ],
+        }
+    }
+}
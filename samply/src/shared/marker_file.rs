@@ -4,6 +4,7 @@ use std::fs::File;
 use std::io::{BufRead, BufReader, Lines};
 use std::path::Path;
 
+use super::json_markers::{parse_json_marker_line, JsonMarker};
 use super::timestamp_converter::TimestampConverter;
 use super::utils::open_file_with_fallback;
 
@@ -14,6 +15,67 @@ pub struct MarkerSpan {
     pub name: String,
 }
 
+/// A sample of a named counter, e.g. a queue depth or a cache hit rate. The
+/// value is the absolute value of the counter at `time`, not a delta.
+#[derive(Debug, Clone)]
+pub struct CounterSample {
+    pub time: Timestamp,
+    pub name: String,
+    pub value: f64,
+}
+
+/// A single line of a marker file.
+///
+/// Marker spans have the format `<start> <end> <name>`, and counter samples
+/// have the format `counter <time> <value> <name>`. Lines which start with `{`
+/// are markers with custom fields, see [`JsonMarker`]. Timestamps are in
+/// nanoseconds, in the same clock as the samples.
+#[derive(Debug, Clone)]
+pub enum MarkerFileEntry {
+    Span(MarkerSpan),
+    CounterSample(CounterSample),
+    JsonMarker(JsonMarker),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MarkerFileContents {
+    /// Sorted by start time.
+    pub marker_spans: Vec<MarkerSpan>,
+    /// Sorted by time.
+    pub counter_samples: Vec<CounterSample>,
+    pub json_markers: Vec<JsonMarker>,
+}
+
+pub fn process_marker_file_line(
+    line: &str,
+    timestamp_converter: &TimestampConverter,
+) -> Option<MarkerFileEntry> {
+    if line.starts_with('{') {
+        return parse_json_marker_line(line, timestamp_converter).map(MarkerFileEntry::JsonMarker);
+    }
+    match line.strip_prefix("counter ") {
+        Some(rest) => process_counter_sample_line(rest, timestamp_converter)
+            .map(MarkerFileEntry::CounterSample),
+        None => process_marker_span_line(line, timestamp_converter).map(MarkerFileEntry::Span),
+    }
+}
+
+fn process_counter_sample_line(
+    line: &str,
+    timestamp_converter: &TimestampConverter,
+) -> Option<CounterSample> {
+    let mut split = line.splitn(3, ' ');
+    let time = split.next()?;
+    let value = split.next()?;
+    let name = split.next()?.to_owned();
+    if name.is_empty() {
+        return None;
+    }
+    let time = timestamp_converter.convert_time(time.parse::<u64>().ok()?);
+    let value = value.parse::<f64>().ok()?;
+    Some(CounterSample { time, name, value })
+}
+
 fn process_marker_span_line(
     line: &str,
     timestamp_converter: &TimestampConverter,
@@ -49,11 +111,11 @@ impl MarkerFile {
 }
 
 impl Iterator for MarkerFile {
-    type Item = MarkerSpan;
+    type Item = MarkerFileEntry;
 
     fn next(&mut self) -> Option<Self::Item> {
         let line = self.lines.next()?.ok()?;
-        process_marker_span_line(&line, &self.timestamp_converter)
+        process_marker_file_line(&line, &self.timestamp_converter)
     }
 }
 
@@ -61,10 +123,107 @@ pub fn get_markers(
     marker_file: &Path,
     extra_dir: Option<&Path>,
     timestamp_converter: TimestampConverter,
-) -> Result<Vec<MarkerSpan>, std::io::Error> {
+) -> Result<MarkerFileContents, std::io::Error> {
     let (f, _true_path) = open_file_with_fallback(marker_file, extra_dir)?;
     let marker_file = MarkerFile::parse(f, timestamp_converter);
-    let mut marker_spans: Vec<MarkerSpan> = marker_file.collect();
-    marker_spans.sort_by_key(|m| m.start_time);
-    Ok(marker_spans)
+    let mut contents = MarkerFileContents::default();
+    for entry in marker_file {
+        match entry {
+            MarkerFileEntry::Span(span) => contents.marker_spans.push(span),
+            MarkerFileEntry::CounterSample(sample) => contents.counter_samples.push(sample),
+            MarkerFileEntry::JsonMarker(marker) => contents.json_markers.push(marker),
+        }
+    }
+    contents.marker_spans.sort_by_key(|m| m.start_time);
+    contents.counter_samples.sort_by_key(|s| s.time);
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    const CONVERTER: TimestampConverter = TimestampConverter {
+        reference_raw: 1_000,
+        raw_to_ns_factor: 1,
+    };
+
+    fn ns(nanos: u64) -> Timestamp {
+        Timestamp::from_nanos_since_reference(nanos)
+    }
+
+    #[test]
+    fn counter_sample_line() {
+        let Some(MarkerFileEntry::CounterSample(sample)) =
+            process_marker_file_line("counter 1500 42.5 Queue depth", &CONVERTER)
+        else {
+            panic!("not a counter sample");
+        };
+        assert_eq!(sample.time, ns(500));
+        assert_eq!(sample.value, 42.5);
+        assert_eq!(sample.name, "Queue depth");
+
+        let Some(MarkerFileEntry::CounterSample(sample)) =
+            process_marker_file_line("counter 2000 -3 Balance", &CONVERTER)
+        else {
+            panic!("not a counter sample");
+        };
+        assert_eq!(sample.value, -3.0);
+    }
+
+    #[test]
+    fn invalid_counter_sample_lines() {
+        for line in [
+            "counter 1500 many Queue depth",
+            "counter 1500 42",
+            "counter 1500 42 ",
+            "counter soon 42 Queue depth",
+            "counter",
+        ] {
+            assert!(
+                process_marker_file_line(line, &CONVERTER).is_none(),
+                "{line:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn markers_and_counters() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "3000 3500 Second span").unwrap();
+        writeln!(file, "counter 2500 2 Requests").unwrap();
+        writeln!(file, "1000 2000 First span").unwrap();
+        writeln!(file, "counter 1500 1 Requests").unwrap();
+        writeln!(file, "counter 4000 0.5 Hit rate").unwrap();
+        let contents = get_markers(file.path(), None, CONVERTER).unwrap();
+
+        let spans: Vec<_> = contents
+            .marker_spans
+            .iter()
+            .map(|span| (span.start_time, span.end_time, span.name.as_str()))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (ns(0), ns(1000), "First span"),
+                (ns(2000), ns(2500), "Second span"),
+            ]
+        );
+        let samples: Vec<_> = contents
+            .counter_samples
+            .iter()
+            .map(|sample| (sample.time, sample.value, sample.name.as_str()))
+            .collect();
+        assert_eq!(
+            samples,
+            vec![
+                (ns(500), 1.0, "Requests"),
+                (ns(1500), 2.0, "Requests"),
+                (ns(3000), 0.5, "Hit rate"),
+            ]
+        );
+        assert!(contents.json_markers.is_empty());
+    }
 }
@@ -1,15 +1,24 @@
+pub mod frame_timing;
 pub mod jit_category_manager;
 pub mod jit_function_add_marker;
 pub mod jit_function_recycler;
 pub mod jitdump_manager;
+pub mod json_markers;
+pub mod jvm_threads;
+pub mod latency_markers;
 pub mod lib_mappings;
+pub mod library_load_marker;
 pub mod marker_file;
+pub mod off_cpu_reason;
 pub mod perf_map;
 pub mod process_sample_data;
+pub mod protobuf;
 pub mod recording_props;
 pub mod recycling;
 pub mod stack_converter;
 pub mod stack_depth_limiting_frame_iter;
+pub mod stack_quality;
+pub mod stack_stitching;
 pub mod timestamp_converter;
 pub mod types;
 pub mod unresolved_samples;
@@ -0,0 +1,57 @@
+use fxprof_processed_profile::{CategoryColor, CategoryPairHandle, Profile};
+
+/// Why a thread was off-CPU. This is worked out from the syscall the thread
+/// was blocked in when it was switched out, or from how it was switched out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OffCpuReason {
+    /// Reading or writing a file or socket, or waiting for a disk flush.
+    Io,
+    /// Waiting for file descriptors to become ready, e.g. in epoll_wait.
+    Polling,
+    /// Waiting for a lock or condition variable, i.e. a futex wait.
+    Lock,
+    /// Sleeping for a fixed time.
+    Sleep,
+    /// Still runnable, but another thread was given the CPU.
+    Preempted,
+    /// Blocked for a reason which isn't known, e.g. in a syscall which isn't
+    /// traced.
+    Unknown,
+}
+
+/// The Off-CPU category, with a subcategory per [`OffCpuReason`]. Off-CPU
+/// samples whose reason is unknown get the category's "Other" subcategory.
+#[derive(Debug, Clone, Copy)]
+pub struct OffCpuCategories {
+    unknown: CategoryPairHandle,
+    io: CategoryPairHandle,
+    polling: CategoryPairHandle,
+    lock: CategoryPairHandle,
+    sleep: CategoryPairHandle,
+    preempted: CategoryPairHandle,
+}
+
+impl OffCpuCategories {
+    pub fn new(profile: &mut Profile) -> Self {
+        let category = profile.add_category("Off-CPU", CategoryColor::LightBlue);
+        Self {
+            unknown: category.into(),
+            io: profile.add_subcategory(category, "I/O"),
+            polling: profile.add_subcategory(category, "Polling"),
+            lock: profile.add_subcategory(category, "Lock"),
+            sleep: profile.add_subcategory(category, "Sleep"),
+            preempted: profile.add_subcategory(category, "Preempted"),
+        }
+    }
+
+    pub fn get(&self, reason: OffCpuReason) -> CategoryPairHandle {
+        match reason {
+            OffCpuReason::Io => self.io,
+            OffCpuReason::Polling => self.polling,
+            OffCpuReason::Lock => self.lock,
+            OffCpuReason::Sleep => self.sleep,
+            OffCpuReason::Preempted => self.preempted,
+            OffCpuReason::Unknown => self.unknown,
+        }
+    }
+}
@@ -21,6 +21,25 @@ fn process_perf_map_line(line: &str) -> Option<(u64, u64, &str)> {
     Some((addr, len, symbol_name))
 }
 
+/// Parses a `# Thread.setName <tid> <name>` line. perf ignores lines which
+/// don't start with an address, so agents can use these lines to tell us the
+/// full names of Java threads, which Linux truncates to 15 bytes.
+fn process_perf_map_thread_name_line(line: &str) -> Option<(u32, &str)> {
+    let rest = line.strip_prefix("# Thread.setName ")?;
+    let (tid, name) = rest.split_once(' ')?;
+    let tid = tid.parse().ok()?;
+    if name.is_empty() {
+        return None;
+    }
+    Some((tid, name))
+}
+
+pub struct PerfMap {
+    pub mappings: LibMappings<LibMappingInfo>,
+    /// The thread names from `Thread.setName` lines, by tid, in file order.
+    pub thread_names: Vec<(u32, String)>,
+}
+
 /// Tries to load a perf mapping file that could have been generated by the process during
 /// execution.
 pub fn try_load_perf_map(
@@ -28,7 +47,7 @@ pub fn try_load_perf_map(
     profile: &mut Profile,
     jit_category_manager: &mut JitCategoryManager,
     mut recycler: Option<&mut JitFunctionRecycler>,
-) -> Option<LibMappings<LibMappingInfo>> {
+) -> Option<PerfMap> {
     let name = format!("perf-{}.map", pid);
     let path = format!("/tmp/{name}");
     let Ok(content) = std::fs::read_to_string(&path) else {
@@ -120,5 +139,39 @@ pub fn try_load_perf_map(
 
     profile.set_lib_symbol_table(lib_handle, Arc::new(SymbolTable::new(symbols)));
 
-    Some(mappings)
+    let thread_names = content
+        .lines()
+        .filter_map(process_perf_map_thread_name_line)
+        .map(|(tid, name)| (tid, name.to_owned()))
+        .collect();
+
+    Some(PerfMap {
+        mappings,
+        thread_names,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{process_perf_map_line, process_perf_map_thread_name_line};
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(
+            process_perf_map_line("7f3a1c000 80 Ljava/lang/String;::hashCode"),
+            Some((0x7f3a1c000, 0x80, "Ljava/lang/String;::hashCode"))
+        );
+        assert_eq!(
+            process_perf_map_thread_name_line("# Thread.setName 4711 http-nio-8080-exec-1"),
+            Some((4711, "http-nio-8080-exec-1"))
+        );
+        assert_eq!(
+            process_perf_map_line("# Thread.setName 4711 http-nio-8080-exec-1"),
+            None
+        );
+        assert_eq!(
+            process_perf_map_thread_name_line("# Thread.setName 4711 "),
+            None
+        );
+    }
 }
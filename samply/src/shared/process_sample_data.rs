@@ -1,18 +1,28 @@
 use fxprof_processed_profile::{
-    CategoryHandle, CategoryPairHandle, LibMappings, MarkerDynamicField, MarkerFieldFormat,
-    MarkerLocation, MarkerSchema, MarkerSchemaField, MarkerStaticField, MarkerTiming, Profile,
-    ProfilerMarker, ThreadHandle, Timestamp,
+    CategoryHandle, CategoryPairHandle, CounterHandle, LibMappings, MarkerDynamicField,
+    MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField, MarkerStaticField,
+    MarkerTiming, ProcessHandle, Profile, ProfilerMarker, StackHandle, ThreadHandle, Timestamp,
 };
 use serde_json::json;
 
+use std::collections::HashMap;
+
 use super::{
+    frame_timing::add_frame_timing,
+    json_markers::{add_json_markers, JsonMarkerOnThread},
+    latency_markers::add_latency_markers,
     lib_mappings::{LibMappingInfo, LibMappingOpQueue, LibMappingsHierarchy},
-    stack_converter::StackConverter,
+    marker_file::{CounterSample, MarkerFileContents, MarkerFileEntry},
+    off_cpu_reason::{OffCpuCategories, OffCpuReason},
+    recording_props::LatencyMarkerNames,
+    stack_converter::{FrameResolutionCache, StackConverter},
     stack_depth_limiting_frame_iter::StackDepthLimitingFrameIter,
-    types::StackFrame,
+    stack_quality::{StackQualityStats, UserStackStart},
+    stack_stitching::{SpawnSite, StackStitcher, StackStitchingRule},
+    types::{FastHashMap, StackFrame},
     unresolved_samples::{
-        OtherEventMarkerData, RssStatMarkerData, SampleData, SampleOrMarker,
-        UnresolvedSampleOrMarker, UnresolvedSamples, UnresolvedStacks,
+        LockWaitMarkerData, OtherEventMarkerData, RssStatMarkerData, SampleData, SampleOrMarker,
+        UnresolvedSampleOrMarker, UnresolvedSamples, UnresolvedStackHandle, UnresolvedStacks,
     },
 };
 
@@ -24,6 +34,63 @@ pub struct MarkerSpanOnThread {
     pub name: String,
 }
 
+/// The markers and counter samples of a process which come from marker files,
+/// or which were sent to us in some other way while the process was running.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessMarkerData {
+    pub marker_spans: Vec<MarkerSpanOnThread>,
+    pub counter_samples: Vec<CounterSample>,
+    pub json_markers: Vec<JsonMarkerOnThread>,
+}
+
+impl ProcessMarkerData {
+    pub fn add_entry(&mut self, thread_handle: ThreadHandle, entry: MarkerFileEntry) {
+        match entry {
+            MarkerFileEntry::Span(span) => self.marker_spans.push(MarkerSpanOnThread {
+                thread_handle,
+                start_time: span.start_time,
+                end_time: span.end_time,
+                name: span.name,
+            }),
+            MarkerFileEntry::CounterSample(sample) => self.counter_samples.push(sample),
+            MarkerFileEntry::JsonMarker(marker) => self.json_markers.push(JsonMarkerOnThread {
+                thread_handle,
+                marker,
+            }),
+        }
+    }
+
+    pub fn add_marker_file_contents(
+        &mut self,
+        thread_handle: ThreadHandle,
+        contents: MarkerFileContents,
+    ) {
+        self.marker_spans
+            .extend(
+                contents
+                    .marker_spans
+                    .into_iter()
+                    .map(|span| MarkerSpanOnThread {
+                        thread_handle,
+                        start_time: span.start_time,
+                        end_time: span.end_time,
+                        name: span.name,
+                    }),
+            );
+        self.counter_samples.extend(contents.counter_samples);
+        self.json_markers
+            .extend(
+                contents
+                    .json_markers
+                    .into_iter()
+                    .map(|marker| JsonMarkerOnThread {
+                        thread_handle,
+                        marker,
+                    }),
+            );
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum RssStatMember {
     ResidentFileMappingPages,
@@ -34,30 +101,56 @@ pub enum RssStatMember {
 
 #[derive(Debug, Clone)]
 pub struct ProcessSampleData {
+    process: ProcessHandle,
     unresolved_samples: UnresolvedSamples,
     regular_lib_mapping_op_queue: LibMappingOpQueue,
     jitdump_lib_mapping_op_queues: Vec<LibMappingOpQueue>,
     perf_map_mappings: Option<LibMappings<LibMappingInfo>>,
-    marker_spans: Vec<MarkerSpanOnThread>,
+    markers: ProcessMarkerData,
+    /// Threads whose user frames don't use the regular user category, e.g.
+    /// JVM GC threads.
+    thread_user_categories: FastHashMap<ThreadHandle, CategoryPairHandle>,
+    /// The categories for the frames of off-CPU samples, if they get their own.
+    off_cpu_categories: Option<OffCpuCategories>,
 }
 
 impl ProcessSampleData {
     pub fn new(
+        process: ProcessHandle,
         unresolved_samples: UnresolvedSamples,
         regular_lib_mapping_op_queue: LibMappingOpQueue,
         jitdump_lib_mapping_op_queues: Vec<LibMappingOpQueue>,
         perf_map_mappings: Option<LibMappings<LibMappingInfo>>,
-        marker_spans: Vec<MarkerSpanOnThread>,
+        markers: ProcessMarkerData,
     ) -> Self {
         Self {
+            process,
             unresolved_samples,
             regular_lib_mapping_op_queue,
             jitdump_lib_mapping_op_queues,
             perf_map_mappings,
-            marker_spans,
+            markers,
+            thread_user_categories: FastHashMap::default(),
+            off_cpu_categories: None,
         }
     }
 
+    pub fn set_thread_user_category(&mut self, thread: ThreadHandle, category: CategoryPairHandle) {
+        self.thread_user_categories.insert(thread, category);
+    }
+
+    pub fn set_off_cpu_categories(&mut self, categories: OffCpuCategories) {
+        self.off_cpu_categories = Some(categories);
+    }
+
+    pub fn has_off_cpu_samples(&self) -> bool {
+        self.unresolved_samples.has_off_cpu_samples()
+    }
+
+    pub fn process(&self) -> ProcessHandle {
+        self.process
+    }
+
     pub fn is_empty(&self) -> bool {
         self.unresolved_samples.is_empty()
     }
@@ -68,16 +161,27 @@ impl ProcessSampleData {
         profile: &mut Profile,
         user_category: CategoryPairHandle,
         kernel_category: CategoryPairHandle,
-        stack_frame_scratch_buf: &mut Vec<StackFrame>,
+        stack_frame_scratch_buf: &mut Vec<(UnresolvedStackHandle, StackFrame)>,
         stacks: &UnresolvedStacks,
         event_names: &[String],
-    ) {
+        frame_marker: Option<&str>,
+        latency_markers: Option<&LatencyMarkerNames>,
+        stack_stitching_rules: &[StackStitchingRule],
+    ) -> StackQualityStats {
         let ProcessSampleData {
+            process,
             unresolved_samples,
             regular_lib_mapping_op_queue,
             jitdump_lib_mapping_op_queues,
             perf_map_mappings,
-            marker_spans,
+            markers:
+                ProcessMarkerData {
+                    marker_spans,
+                    mut counter_samples,
+                    json_markers,
+                },
+            thread_user_categories,
+            off_cpu_categories,
         } = self;
         let mut lib_mappings_hierarchy = LibMappingsHierarchy::new(regular_lib_mapping_op_queue);
         for jitdump_lib_mapping_ops in jitdump_lib_mapping_op_queues {
@@ -86,10 +190,191 @@ impl ProcessSampleData {
         if let Some(perf_map_mappings) = perf_map_mappings {
             lib_mappings_hierarchy.add_perf_map_mappings(perf_map_mappings);
         }
-        let stack_converter = StackConverter::new(user_category, kernel_category);
+        let thread_user_category = |thread: ThreadHandle| {
+            thread_user_categories
+                .get(&thread)
+                .copied()
+                .unwrap_or(user_category)
+        };
+        let stack_converter_for_sample =
+            |thread: ThreadHandle, off_cpu: Option<OffCpuReason>| match (
+                off_cpu,
+                off_cpu_categories,
+            ) {
+                (Some(reason), Some(off_cpu_categories)) => {
+                    let off_cpu_category = off_cpu_categories.get(reason);
+                    (
+                        StackConverter::new(off_cpu_category, off_cpu_category),
+                        off_cpu_category,
+                    )
+                }
+                _ => {
+                    let user_category = thread_user_category(thread);
+                    (
+                        StackConverter::new(user_category, kernel_category),
+                        user_category,
+                    )
+                }
+            };
+        // Most samples have a stack which was already seen on the same thread.
+        // Converting such a stack again would give the same profile stack, as long
+        // as the lib mappings haven't changed in the meantime.
+        type StackCacheKey = (ThreadHandle, UnresolvedStackHandle, Option<OffCpuReason>);
+        type CachedStack = (Option<StackHandle>, Option<UserStackStart>);
+        let mut stack_cache: FastHashMap<StackCacheKey, CachedStack> = FastHashMap::default();
+        // The same for the stitched stacks of spawned tasks, which also depend
+        // on the spawn site. `None` means that the stack isn't stitched.
+        type StitchedStackCacheKey = (
+            ThreadHandle,
+            UnresolvedStackHandle,
+            Option<OffCpuReason>,
+            SpawnSite,
+        );
+        let mut stitched_stack_cache: FastHashMap<StitchedStackCacheKey, Option<CachedStack>> =
+            FastHashMap::default();
+        let mut stack_quality = StackQualityStats::default();
+        let mut resolution_cache = FrameResolutionCache::default();
+        let mut stack_stitcher = StackStitcher::new(stack_stitching_rules, &json_markers);
         let samples = unresolved_samples.into_inner();
         for sample in samples {
-            lib_mappings_hierarchy.process_ops(sample.timestamp_mono);
+            if lib_mappings_hierarchy.process_ops(sample.timestamp_mono) {
+                stack_cache.clear();
+                stitched_stack_cache.clear();
+                resolution_cache.clear();
+            }
+            let spawn_site = match (&mut stack_stitcher, &sample.sample_or_marker) {
+                (Some(stitcher), SampleOrMarker::Sample(_)) => {
+                    stitcher.add_sample(sample.thread_handle, sample.timestamp, sample.stack)
+                }
+                _ => None,
+            };
+            if let (
+                Some(spawn_site),
+                Some(stitcher),
+                SampleOrMarker::Sample(SampleData {
+                    cpu_delta,
+                    weight,
+                    off_cpu,
+                }),
+                None,
+            ) = (
+                spawn_site,
+                &stack_stitcher,
+                &sample.sample_or_marker,
+                &sample.extra_label_frame,
+            ) {
+                let cache_key = (sample.thread_handle, sample.stack, *off_cpu, spawn_site);
+                let stitched = match stitched_stack_cache.get(&cache_key) {
+                    Some(entry) => *entry,
+                    None => {
+                        stack_frame_scratch_buf.clear();
+                        stacks.convert_back(spawn_site.stack, stack_frame_scratch_buf);
+                        let (spawn_stack_converter, _) =
+                            stack_converter_for_sample(spawn_site.thread, None);
+                        let spawn_frames = spawn_stack_converter
+                            .convert_stack(
+                                stack_frame_scratch_buf,
+                                &lib_mappings_hierarchy,
+                                &mut resolution_cache,
+                                None,
+                            )
+                            .collect();
+
+                        stack_frame_scratch_buf.clear();
+                        stacks.convert_back(sample.stack, stack_frame_scratch_buf);
+                        let stack_start = UserStackStart::for_stack(
+                            stack_frame_scratch_buf,
+                            &lib_mappings_hierarchy,
+                        );
+                        let (stack_converter, user_category) =
+                            stack_converter_for_sample(sample.thread_handle, *off_cpu);
+                        let task_frames = stack_converter
+                            .convert_stack(
+                                stack_frame_scratch_buf,
+                                &lib_mappings_hierarchy,
+                                &mut resolution_cache,
+                                None,
+                            )
+                            .collect();
+                        let stitched = stitcher
+                            .stitch(
+                                profile,
+                                spawn_site,
+                                spawn_frames,
+                                task_frames,
+                                user_category,
+                            )
+                            .map(|frames| {
+                                let frames = StackDepthLimitingFrameIter::new(
+                                    profile,
+                                    frames.into_iter(),
+                                    user_category,
+                                );
+                                let stack =
+                                    profile.intern_stack_frames(sample.thread_handle, frames);
+                                (stack, stack_start)
+                            });
+                        stitched_stack_cache.insert(cache_key, stitched);
+                        stitched
+                    }
+                };
+                if let Some((stack, stack_start)) = stitched {
+                    stack_quality.add_sample(stack_start);
+                    profile.add_sample_with_stack(
+                        sample.thread_handle,
+                        sample.timestamp,
+                        stack,
+                        *cpu_delta,
+                        *weight,
+                    );
+                    continue;
+                }
+            }
+            if let (
+                SampleOrMarker::Sample(SampleData {
+                    cpu_delta,
+                    weight,
+                    off_cpu,
+                }),
+                None,
+            ) = (&sample.sample_or_marker, &sample.extra_label_frame)
+            {
+                let cache_key = (sample.thread_handle, sample.stack, *off_cpu);
+                let (stack, stack_start) = match stack_cache.get(&cache_key) {
+                    Some(entry) => *entry,
+                    None => {
+                        stack_frame_scratch_buf.clear();
+                        stacks.convert_back(sample.stack, stack_frame_scratch_buf);
+                        let stack_start = UserStackStart::for_stack(
+                            stack_frame_scratch_buf,
+                            &lib_mappings_hierarchy,
+                        );
+                        let (stack_converter, user_category) =
+                            stack_converter_for_sample(sample.thread_handle, *off_cpu);
+                        let frames = stack_converter.convert_stack(
+                            stack_frame_scratch_buf,
+                            &lib_mappings_hierarchy,
+                            &mut resolution_cache,
+                            None,
+                        );
+                        let frames =
+                            StackDepthLimitingFrameIter::new(profile, frames, user_category);
+                        let stack = profile.intern_stack_frames(sample.thread_handle, frames);
+                        stack_cache.insert(cache_key, (stack, stack_start));
+                        (stack, stack_start)
+                    }
+                };
+                stack_quality.add_sample(stack_start);
+                profile.add_sample_with_stack(
+                    sample.thread_handle,
+                    sample.timestamp,
+                    stack,
+                    *cpu_delta,
+                    *weight,
+                );
+                continue;
+            }
+
             let UnresolvedSampleOrMarker {
                 thread_handle,
                 timestamp,
@@ -101,14 +386,23 @@ impl ProcessSampleData {
 
             stack_frame_scratch_buf.clear();
             stacks.convert_back(stack, stack_frame_scratch_buf);
+            let off_cpu = match &sample_or_marker {
+                SampleOrMarker::Sample(SampleData { off_cpu, .. }) => *off_cpu,
+                _ => None,
+            };
+            let (stack_converter, user_category) =
+                stack_converter_for_sample(thread_handle, off_cpu);
             let frames = stack_converter.convert_stack(
                 stack_frame_scratch_buf,
                 &lib_mappings_hierarchy,
+                &mut resolution_cache,
                 extra_label_frame,
             );
             let frames = StackDepthLimitingFrameIter::new(profile, frames, user_category);
             match sample_or_marker {
-                SampleOrMarker::Sample(SampleData { cpu_delta, weight }) => {
+                SampleOrMarker::Sample(SampleData {
+                    cpu_delta, weight, ..
+                }) => {
                     profile.add_sample(thread_handle, timestamp, frames, cpu_delta, weight);
                 }
                 SampleOrMarker::RssStatMarker(RssStatMarkerData {
@@ -132,6 +426,16 @@ impl ProcessSampleData {
                         frames,
                     );
                 }
+                SampleOrMarker::LockWaitMarker(LockWaitMarkerData { address, end_time }) => {
+                    profile.add_marker_with_stack(
+                        thread_handle,
+                        CategoryHandle::OTHER,
+                        "Lock wait",
+                        LockWaitMarker { address },
+                        MarkerTiming::Interval(timestamp, end_time),
+                        frames,
+                    );
+                }
                 SampleOrMarker::OtherEventMarker(OtherEventMarkerData { attr_index }) => {
                     if let Some(name) = event_names.get(attr_index) {
                         let timing = MarkerTiming::Instant(timestamp);
@@ -148,7 +452,7 @@ impl ProcessSampleData {
             }
         }
 
-        for marker in marker_spans {
+        for marker in &marker_spans {
             profile.add_marker(
                 marker.thread_handle,
                 CategoryHandle::OTHER,
@@ -157,6 +461,34 @@ impl ProcessSampleData {
                 MarkerTiming::Interval(marker.start_time, marker.end_time),
             );
         }
+
+        if let Some(frame_marker) = frame_marker {
+            add_frame_timing(profile, process, &marker_spans, frame_marker);
+        }
+
+        if let Some(latency_markers) = latency_markers {
+            add_latency_markers(profile, &marker_spans, latency_markers);
+        }
+
+        add_json_markers(profile, json_markers);
+
+        // Counter samples in marker files are absolute values, but the profile
+        // stores the change since the previous sample.
+        // Samples from different marker files are interleaved in time.
+        counter_samples.sort_by_key(|sample| sample.time);
+        let mut counters: HashMap<String, (CounterHandle, f64)> = HashMap::new();
+        for sample in counter_samples {
+            let (counter, previous_value) =
+                counters.entry(sample.name).or_insert_with_key(|name| {
+                    let counter =
+                        profile.add_counter(process, name, "User", "Counter from a marker file");
+                    (counter, 0.0)
+                });
+            profile.add_counter_sample(*counter, sample.time, sample.value - *previous_value, 1);
+            *previous_value = sample.value;
+        }
+
+        stack_quality
     }
 }
 
@@ -203,6 +535,50 @@ impl ProfilerMarker for RssStatMarker {
     }
 }
 
+/// A thread blocked in a futex wait, i.e. waited for a contended lock or a
+/// condition variable.
+#[derive(Debug, Clone)]
+pub struct LockWaitMarker {
+    pub address: u64,
+}
+
+impl ProfilerMarker for LockWaitMarker {
+    const MARKER_TYPE_NAME: &'static str = "LockWait";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "address": format!("{:#x}", self.address),
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.address}"),
+            tooltip_label: Some("Lock wait on {marker.data.address}"),
+            table_label: Some("Lock wait on {marker.data.address}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "address",
+                    label: "Futex address",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "The thread was blocked in a futex wait syscall, waiting for a contended lock or a condition variable. The stack is where the wait started.",
+                }),
+            ],
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct OtherEventMarker;
 
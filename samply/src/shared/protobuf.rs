@@ -0,0 +1,175 @@
+//! A minimal protocol buffer codec, for reading and writing pprof profiles
+//! without generated code. Messages are read field by field with [`Fields`],
+//! and written by appending fields to a `Vec<u8>`.
+//!
+//! See <https://protobuf.dev/programming-guides/encoding/> for the wire format.
+
+/// An error from decoding a malformed message.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("{0}")]
+pub struct DecodeError(pub &'static str);
+
+/// A field of a protocol buffer message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> FieldValue<'a> {
+    pub fn as_u64(self) -> u64 {
+        match self {
+            FieldValue::Varint(value) | FieldValue::Fixed64(value) => value,
+            FieldValue::Fixed32(value) => value.into(),
+            FieldValue::Bytes(_) => 0,
+        }
+    }
+
+    pub fn as_i64(self) -> i64 {
+        self.as_u64() as i64
+    }
+
+    pub fn as_bytes(self) -> &'a [u8] {
+        match self {
+            FieldValue::Bytes(bytes) => bytes,
+            _ => &[],
+        }
+    }
+
+    /// The values of a repeated integer field, which can be packed into a
+    /// single length-delimited field, or be a single varint.
+    pub fn push_varints(self, values: &mut Vec<u64>) -> Result<(), DecodeError> {
+        match self {
+            FieldValue::Bytes(mut bytes) => {
+                while !bytes.is_empty() {
+                    values.push(read_varint(&mut bytes)?);
+                }
+            }
+            value => values.push(value.as_u64()),
+        }
+        Ok(())
+    }
+}
+
+pub fn read_varint(data: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().ok_or(DecodeError("truncated varint"))?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(DecodeError("varint is too long"))
+}
+
+/// Iterates over the fields of a protocol buffer message.
+pub struct Fields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Fields { data }
+    }
+
+    pub fn next_field(&mut self) -> Result<Option<(u64, FieldValue<'a>)>, DecodeError> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+        let key = read_varint(&mut self.data)?;
+        let truncated = DecodeError("truncated field");
+        let value = match key & 7 {
+            0 => FieldValue::Varint(read_varint(&mut self.data)?),
+            1 => {
+                let bytes = self.data.get(..8).ok_or(truncated)?;
+                self.data = &self.data[8..];
+                FieldValue::Fixed64(u64::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            2 => {
+                let len = usize::try_from(read_varint(&mut self.data)?)
+                    .map_err(|_| DecodeError("field is too long"))?;
+                let bytes = self.data.get(..len).ok_or(truncated)?;
+                self.data = &self.data[len..];
+                FieldValue::Bytes(bytes)
+            }
+            5 => {
+                let bytes = self.data.get(..4).ok_or(truncated)?;
+                self.data = &self.data[4..];
+                FieldValue::Fixed32(u32::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            _ => return Err(DecodeError("unsupported wire type")),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+pub fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+pub fn write_varint_field(data: &mut Vec<u8>, number: u64, value: u64) {
+    write_varint(data, number << 3);
+    write_varint(data, value);
+}
+
+pub fn write_bytes_field(data: &mut Vec<u8>, number: u64, bytes: &[u8]) {
+    write_varint(data, (number << 3) | 2);
+    write_varint(data, bytes.len() as u64);
+    data.extend_from_slice(bytes);
+}
+
+pub fn write_packed_field(data: &mut Vec<u8>, number: u64, values: &[u64]) {
+    let mut packed = Vec::new();
+    for value in values {
+        write_varint(&mut packed, *value);
+    }
+    write_bytes_field(data, number, &packed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn varints() {
+        let mut data: &[u8] = &[0xac, 0x02, 0x01];
+        assert_eq!(read_varint(&mut data).unwrap(), 300);
+        assert_eq!(read_varint(&mut data).unwrap(), 1);
+        assert!(read_varint(&mut data).is_err());
+
+        let mut data = Vec::new();
+        write_varint(&mut data, 1);
+        write_varint(&mut data, 300);
+        assert_eq!(data, vec![0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn packed_fields() {
+        // Field 1 with the packed values 3 and 270, then field 2 with the varint 7.
+        let data = [0x0a, 0x03, 0x03, 0x8e, 0x02, 0x10, 0x07];
+        let mut fields = Fields::new(&data);
+        let (number, value) = fields.next_field().unwrap().unwrap();
+        assert_eq!(number, 1);
+        let mut values = Vec::new();
+        value.push_varints(&mut values).unwrap();
+        assert_eq!(values, vec![3, 270]);
+        assert_eq!(
+            fields.next_field().unwrap(),
+            Some((2, FieldValue::Varint(7)))
+        );
+        assert_eq!(fields.next_field().unwrap(), None);
+
+        let mut written = Vec::new();
+        write_packed_field(&mut written, 1, &[3, 270]);
+        write_varint_field(&mut written, 2, 7);
+        assert_eq!(written, data);
+    }
+}
@@ -1,10 +1,79 @@
-use std::{path::PathBuf, time::Duration};
+use regex::Regex;
+
+use super::stack_stitching::StackStitchingRule;
+use crate::capture_schedule::parse_interval;
+use crate::iteration_report::IterationReportProps;
+use crate::server::SymbolDownloads;
+
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
 pub struct RecordingProps {
     pub output_file: PathBuf,
     pub time_limit: Option<Duration>,
     pub interval: Duration,
     pub main_thread_only: bool,
+    /// Emit markers for file I/O syscalls (macOS only, requires root).
+    pub file_io: bool,
+    /// Record display vblank events as markers (Linux only).
+    pub vsync: bool,
+    /// Record GPU jobs from the DRM scheduler as markers (Linux only).
+    pub gpu: bool,
+    /// Record ALSA xruns as markers (Linux only).
+    pub audio_xruns: bool,
+    /// Record the environment of each profiled process (Linux only).
+    pub process_environment: bool,
+    /// Environment variables whose names contain one of these words are not
+    /// recorded, in addition to the built-in ones which look like secrets.
+    pub env_deny_list: Vec<String>,
+    /// Record futex waits as markers, with a contention summary per lock
+    /// (Linux only).
+    pub lock_contention: bool,
+    /// Trace blocking syscalls, to tell why threads were off-CPU (Linux only).
+    pub off_cpu_reasons: bool,
+    /// Let launched processes send markers over a socket (Linux only).
+    pub marker_socket: bool,
+    /// Run rustc invocations of the launched command through samply, so that
+    /// each one is named after the crate it compiles (Linux only).
+    pub rustc_wrapper: bool,
+    /// Accept OpenTelemetry spans on this port and turn them into markers (Linux only).
+    pub otlp_port: Option<u16>,
+    /// Capture the launched command's stdout / stderr and emit a marker for each
+    /// line which matches the filter, or for every line if there's no filter
+    /// (Linux only).
+    pub output_markers: Option<OutputMarkerProps>,
+    /// Also write the launched command's stdout / stderr to `<path>.stdout` and
+    /// `<path>.stderr` (Linux only).
+    pub tee_output: Option<PathBuf>,
+    /// Keep running iterations after one has failed, and leave the failed
+    /// iterations out of the profile (Linux only).
+    pub skip_failed_iterations: bool,
+    /// Run the command this many times before the recorded iterations.
+    pub warmup_iterations: u32,
+    /// Record the warm-up iterations, grouped under a "warmup" process (Linux only).
+    pub record_warmup: bool,
+    /// Compare the iterations after recording.
+    pub iteration_report: Option<IterationReportProps>,
+    /// Write one profile per process into the output directory (Linux only).
+    pub split_processes: bool,
+    /// Symbolicate the saved profile, so that it can be viewed without access
+    /// to this machine's binaries (Linux only).
+    pub symbolicate_on_save: bool,
+    /// Additional directories to look for symbols in when symbolicating.
+    pub symbol_dirs: Vec<PathBuf>,
+    /// Where symbol files may be downloaded from when symbolicating.
+    pub symbol_downloads: SymbolDownloads,
+    /// Keep only the most recent samples, in ring buffers which are read once
+    /// recording stops (Linux only).
+    pub overwrite: bool,
+    /// The minimum size of the per-CPU ring buffers in bytes (Linux only).
+    pub ring_buffer_size: Option<u32>,
+    /// Only save the profile once this condition has been met, and stop
+    /// recording at that point (Linux only).
+    pub trigger: Option<Trigger>,
+}
+
+pub struct OutputMarkerProps {
+    pub filter: Option<Regex>,
 }
 
 pub struct ConversionProps {
@@ -13,4 +82,237 @@ pub struct ConversionProps {
     pub reuse_threads: bool,
     /// Fold repeated frames at the base of the stack.
     pub fold_recursive_prefix: bool,
+    /// Treat successive markers with this name as frame boundaries, and add a
+    /// frame time track.
+    pub frame_marker: Option<String>,
+    /// Add a latency marker from each input marker to the next present marker.
+    pub latency_markers: Option<LatencyMarkerNames>,
+    /// Splice the stacks of spawn sites under the stacks of the tasks they
+    /// spawned, based on JSON markers.
+    pub stack_stitching_rules: Vec<StackStitchingRule>,
+    /// Create a track for each CPU, with the samples and the threads that ran on it.
+    pub per_cpu_threads: bool,
+    /// Merge all processes with the same name into one track, with a marker
+    /// for each process.
+    pub aggregate_by_name: bool,
+    /// Emit a sample at every sampling interval for threads which are off-CPU,
+    /// so that blocked time shows up next to running time.
+    pub wall_clock: bool,
+    /// Weigh samples by time: off-CPU samples by how long the thread was blocked,
+    /// and on-CPU samples by the sampling interval, both in microseconds.
+    pub blocked_time_weights: bool,
+    /// The number of address bits in the return addresses of the processes with
+    /// these names. By default, it's derived from the process's mappings.
+    pub code_address_bits: Vec<CodeAddressBits>,
+    /// Write a summary of the events which aren't interpreted to this file.
+    pub unknown_events_file: Option<PathBuf>,
+}
+
+/// The names of the markers which are paired up for latency markers, given as
+/// `INPUT,PRESENT` on the command line.
+#[derive(Debug, Clone)]
+pub struct LatencyMarkerNames {
+    pub input: String,
+    pub present: String,
+}
+
+impl FromStr for LatencyMarkerNames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(',') {
+            Some((input, present)) if !input.is_empty() && !present.is_empty() => Ok(Self {
+                input: input.to_string(),
+                present: present.to_string(),
+            }),
+            _ => Err(format!(
+                "expected the input and present marker names separated by a comma, got \"{s}\""
+            )),
+        }
+    }
+}
+
+/// How many of the low bits of a return address are the address, for the
+/// processes with a given name, given as `NAME=BITS` on the command line. On
+/// arm64, the bits above can hold a pointer authentication code or a memory
+/// tag, and are stripped. 64 bits means nothing is stripped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeAddressBits {
+    pub process_name: String,
+    pub bits: u32,
+}
+
+impl CodeAddressBits {
+    /// The mask which keeps the address bits.
+    pub fn mask(&self) -> u64 {
+        u64::MAX >> (64 - self.bits)
+    }
+}
+
+impl FromStr for CodeAddressBits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("expected a process name and a bit count like app=48, got \"{s}\"");
+        let (process_name, bits) = s.rsplit_once('=').ok_or_else(invalid)?;
+        let bits: u32 = bits.parse().map_err(|_| invalid())?;
+        if process_name.is_empty() || !(1..=64).contains(&bits) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            process_name: process_name.to_string(),
+            bits,
+        })
+    }
+}
+
+/// A condition which ends a flight recorder recording, given on the command
+/// line as e.g. `cpu>80%:30s` or `latency>100ms`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trigger {
+    /// The recorded process used more than `cpu_percent` of a CPU core for
+    /// `sustained_for`, e.g. `cpu>80%:30s`. Without the duration, a single
+    /// second of high CPU usage is enough.
+    Cpu {
+        /// The CPU usage of the process, where 100% is one fully busy core.
+        cpu_percent: f64,
+        sustained_for: Duration,
+    },
+    /// The recorded process reported an OpenTelemetry span which took longer
+    /// than `threshold`, e.g. `latency>100ms`. With `latency(NAME)>100ms`,
+    /// only spans called NAME count.
+    Latency {
+        threshold: Duration,
+        span_name: Option<String>,
+    },
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(latency) = s.strip_prefix("latency") {
+            return parse_latency_trigger(latency).ok_or_else(|| {
+                format!(
+                    "expected a condition like latency>100ms or latency(NAME)>100ms, got \"{s}\""
+                )
+            });
+        }
+        let invalid = || format!("expected a condition like cpu>80%:30s, got \"{s}\"");
+        let (condition, sustained_for) = match s.split_once(':') {
+            Some((condition, duration)) => (condition, parse_interval(duration)?),
+            None => (s, Duration::ZERO),
+        };
+        let threshold = condition.strip_prefix("cpu>").ok_or_else(invalid)?;
+        let threshold = threshold.strip_suffix('%').unwrap_or(threshold);
+        let cpu_percent: f64 = threshold.parse().map_err(|_| invalid())?;
+        if !cpu_percent.is_finite() || cpu_percent < 0.0 {
+            return Err(invalid());
+        }
+        Ok(Self::Cpu {
+            cpu_percent,
+            sustained_for,
+        })
+    }
+}
+
+/// Parses what follows "latency" in `latency(NAME)>100ms`.
+fn parse_latency_trigger(s: &str) -> Option<Trigger> {
+    let (span_name, threshold) = match s.strip_prefix('(') {
+        Some(rest) => {
+            let (span_name, threshold) = rest.split_once(")>")?;
+            if span_name.is_empty() {
+                return None;
+            }
+            (Some(span_name.to_string()), threshold)
+        }
+        None => (None, s.strip_prefix('>')?),
+    };
+    Some(Trigger::Latency {
+        threshold: parse_latency(threshold)?,
+        span_name,
+    })
+}
+
+/// Parses a duration like 100ms, 500us or 2s. Spans shorter than a microsecond
+/// aren't interesting enough to trigger on, so nanoseconds aren't supported.
+fn parse_latency(s: &str) -> Option<Duration> {
+    let (number, unit) = if let Some(number) = s.strip_suffix("ms") {
+        (number, Duration::from_millis(1))
+    } else if let Some(number) = s.strip_suffix("us") {
+        (number, Duration::from_micros(1))
+    } else {
+        (s.strip_suffix('s')?, Duration::from_secs(1))
+    };
+    let number: f64 = number.parse().ok()?;
+    if !number.is_finite() || number <= 0.0 {
+        return None;
+    }
+    Some(unit.mul_f64(number))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{CodeAddressBits, Trigger};
+
+    #[test]
+    fn parse_trigger() {
+        assert_eq!(
+            "cpu>80%:30s".parse(),
+            Ok(Trigger::Cpu {
+                cpu_percent: 80.0,
+                sustained_for: Duration::from_secs(30),
+            })
+        );
+        assert_eq!(
+            "cpu>150".parse(),
+            Ok(Trigger::Cpu {
+                cpu_percent: 150.0,
+                sustained_for: Duration::ZERO,
+            })
+        );
+        assert!("cpu<80%".parse::<Trigger>().is_err());
+        assert!("cpu>80%:".parse::<Trigger>().is_err());
+        assert_eq!(
+            "latency>100ms".parse(),
+            Ok(Trigger::Latency {
+                threshold: Duration::from_millis(100),
+                span_name: None,
+            })
+        );
+        assert_eq!(
+            "latency(GET /search)>1.5s".parse(),
+            Ok(Trigger::Latency {
+                threshold: Duration::from_millis(1500),
+                span_name: Some("GET /search".to_string()),
+            })
+        );
+        assert_eq!(
+            "latency>250us".parse(),
+            Ok(Trigger::Latency {
+                threshold: Duration::from_micros(250),
+                span_name: None,
+            })
+        );
+        assert!("latency>100".parse::<Trigger>().is_err());
+        assert!("latency>0ms".parse::<Trigger>().is_err());
+        assert!("latency()>100ms".parse::<Trigger>().is_err());
+        assert!("latency<100ms".parse::<Trigger>().is_err());
+    }
+
+    #[test]
+    fn parse_code_address_bits() {
+        let bits: CodeAddressBits = "my=app=48".parse().unwrap();
+        assert_eq!(bits.process_name, "my=app");
+        assert_eq!(bits.mask(), 0xffff_ffff_ffff);
+        let bits: CodeAddressBits = "app=64".parse().unwrap();
+        assert_eq!(bits.mask(), u64::MAX);
+        assert!("app".parse::<CodeAddressBits>().is_err());
+        assert!("=48".parse::<CodeAddressBits>().is_err());
+        assert!("app=0".parse::<CodeAddressBits>().is_err());
+        assert!("app=65".parse::<CodeAddressBits>().is_err());
+    }
 }
@@ -1,8 +1,9 @@
 use fxprof_processed_profile::{CategoryPairHandle, Frame, FrameFlags, FrameInfo};
 
 use super::jit_category_manager::{JsFrame, JsName};
-use super::lib_mappings::LibMappingsHierarchy;
-use super::types::{StackFrame, StackMode};
+use super::lib_mappings::{LibMappingInfo, LibMappingsHierarchy};
+use super::types::{FastHashMap, StackFrame, StackMode};
+use super::unresolved_samples::UnresolvedStackHandle;
 
 #[derive(Debug, Clone, Copy)]
 pub struct StackConverter {
@@ -10,9 +11,51 @@ pub struct StackConverter {
     kernel_category: CategoryPairHandle,
 }
 
+/// Remembers which library and relative address the frame of each unresolved
+/// stack node was resolved to.
+///
+/// Unresolved stacks share their prefix nodes, so once the return address chain
+/// of one sample has been resolved, the addresses don't need to be looked up
+/// again for other samples with the same chain, even if they're on a different
+/// thread or have a different leaf frame. The cache must be cleared when the
+/// lib mappings change. It is also cleared when it reaches
+/// [`MAX_RESOLVED_FRAMES`](Self::MAX_RESOLVED_FRAMES) entries, so that processes
+/// with many distinct stacks don't keep all of their resolutions in memory.
+#[derive(Debug, Clone, Default)]
+pub struct FrameResolutionCache {
+    resolved_frames: FastHashMap<UnresolvedStackHandle, Option<(u32, LibMappingInfo)>>,
+}
+
+impl FrameResolutionCache {
+    pub const MAX_RESOLVED_FRAMES: usize = 1 << 20;
+
+    pub fn clear(&mut self) {
+        self.resolved_frames.clear();
+    }
+
+    fn resolve(
+        &mut self,
+        node: UnresolvedStackHandle,
+        lookup_address: u64,
+        lib_mappings: &LibMappingsHierarchy,
+    ) -> Option<(u32, LibMappingInfo)> {
+        if self.resolved_frames.len() >= Self::MAX_RESOLVED_FRAMES
+            && !self.resolved_frames.contains_key(&node)
+        {
+            self.resolved_frames.clear();
+        }
+        *self.resolved_frames.entry(node).or_insert_with(|| {
+            lib_mappings
+                .convert_address(lookup_address)
+                .map(|(relative_address, info)| (relative_address, *info))
+        })
+    }
+}
+
 pub struct ConvertedStackIter<'a> {
-    inner: std::iter::Rev<std::slice::Iter<'a, StackFrame>>,
+    inner: std::iter::Rev<std::slice::Iter<'a, (UnresolvedStackHandle, StackFrame)>>,
     lib_mappings: &'a LibMappingsHierarchy,
+    resolution_cache: &'a mut FrameResolutionCache,
     user_category: CategoryPairHandle,
     kernel_category: CategoryPairHandle,
     pending_frame: Option<FrameInfo>,
@@ -36,8 +79,8 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
             if let Some(pending_frame) = self.pending_frame.take() {
                 return Some(pending_frame);
             }
-            let frame = self.inner.next()?;
-            let (mode, addr, lookup_address, from_ip) = match *frame {
+            let (node, frame) = *self.inner.next()?;
+            let (mode, addr, lookup_address, from_ip) = match frame {
                 StackFrame::InstructionPointer(addr, mode) => (mode, addr, addr, true),
                 StackFrame::ReturnAddress(addr, mode) => {
                     (mode, addr, addr.saturating_sub(1), false)
@@ -45,35 +88,40 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
                 StackFrame::TruncatedStackMarker => continue,
             };
             let (location, category, js_frame) = match mode {
-                StackMode::User => match self.lib_mappings.convert_address(lookup_address) {
-                    Some((relative_lookup_address, info)) => {
-                        let location = if from_ip {
-                            let relative_address = relative_lookup_address;
-                            Frame::RelativeAddressFromInstructionPointer(
-                                info.lib_handle,
-                                relative_address,
+                StackMode::User => {
+                    match self
+                        .resolution_cache
+                        .resolve(node, lookup_address, self.lib_mappings)
+                    {
+                        Some((relative_lookup_address, info)) => {
+                            let location = if from_ip {
+                                let relative_address = relative_lookup_address;
+                                Frame::RelativeAddressFromInstructionPointer(
+                                    info.lib_handle,
+                                    relative_address,
+                                )
+                            } else {
+                                let relative_address = relative_lookup_address + 1;
+                                Frame::RelativeAddressFromReturnAddress(
+                                    info.lib_handle,
+                                    relative_address,
+                                )
+                            };
+                            (
+                                location,
+                                info.category.unwrap_or(self.user_category),
+                                info.js_frame,
                             )
-                        } else {
-                            let relative_address = relative_lookup_address + 1;
-                            Frame::RelativeAddressFromReturnAddress(
-                                info.lib_handle,
-                                relative_address,
-                            )
-                        };
-                        (
-                            location,
-                            info.category.unwrap_or(self.user_category),
-                            info.js_frame,
-                        )
-                    }
-                    None => {
-                        let location = match from_ip {
-                            true => Frame::InstructionPointer(addr),
-                            false => Frame::ReturnAddress(addr),
-                        };
-                        (location, self.user_category, None)
+                        }
+                        None => {
+                            let location = match from_ip {
+                                true => Frame::InstructionPointer(addr),
+                                false => Frame::ReturnAddress(addr),
+                            };
+                            (location, self.user_category, None)
+                        }
                     }
-                },
+                }
                 StackMode::Kernel => {
                     let location = match from_ip {
                         true => Frame::InstructionPointer(addr),
@@ -139,15 +187,19 @@ impl StackConverter {
         }
     }
 
+    /// `stack` is ordered from callee-most to caller-most, as returned by
+    /// [`UnresolvedStacks::convert_back`](super::unresolved_samples::UnresolvedStacks::convert_back).
     pub fn convert_stack<'a>(
         &self,
-        stack: &'a [StackFrame],
+        stack: &'a [(UnresolvedStackHandle, StackFrame)],
         lib_mappings: &'a LibMappingsHierarchy,
+        resolution_cache: &'a mut FrameResolutionCache,
         extra_first_frame: Option<FrameInfo>,
     ) -> impl Iterator<Item = FrameInfo> + 'a {
         ConvertedStackIter {
             inner: stack.iter().rev(),
             lib_mappings,
+            resolution_cache,
             user_category: self.user_category,
             kernel_category: self.kernel_category,
             pending_frame: extra_first_frame,
@@ -155,3 +207,101 @@ impl StackConverter {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{
+        CategoryColor, LibraryInfo, Profile, ReferenceTimestamp, SamplingInterval,
+    };
+
+    use super::*;
+    use crate::shared::lib_mappings::{LibMappingAdd, LibMappingOp, LibMappingOpQueue};
+    use crate::shared::unresolved_samples::UnresolvedStacks;
+
+    #[test]
+    fn cached_resolution_matches_uncached_resolution() {
+        let mut profile = Profile::new(
+            "",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let lib_handle = profile.add_lib(LibraryInfo {
+            name: "libfoo.so".to_string(),
+            debug_name: "libfoo.so".to_string(),
+            path: "/usr/lib/libfoo.so".to_string(),
+            debug_path: "/usr/lib/libfoo.so".to_string(),
+            debug_id: Default::default(),
+            code_id: None,
+            arch: None,
+            symbol_table: None,
+        });
+        let mut ops = LibMappingOpQueue::default();
+        ops.push(
+            0,
+            LibMappingOp::Add(LibMappingAdd {
+                start_avma: 0x1000,
+                end_avma: 0x2000,
+                relative_address_at_start: 0x400,
+                info: LibMappingInfo::new_lib(lib_handle),
+            }),
+        );
+        let mut lib_mappings = LibMappingsHierarchy::new(ops);
+        lib_mappings.process_ops(0);
+
+        // Three samples which share the caller frames, with one frame outside
+        // of any library and one kernel frame.
+        let mut stacks = UnresolvedStacks::default();
+        let samples = [
+            vec![
+                StackFrame::ReturnAddress(0x1010, StackMode::User),
+                StackFrame::ReturnAddress(0x1234, StackMode::User),
+                StackFrame::InstructionPointer(0x1800, StackMode::User),
+            ],
+            vec![
+                StackFrame::ReturnAddress(0x1010, StackMode::User),
+                StackFrame::ReturnAddress(0x1234, StackMode::User),
+                StackFrame::ReturnAddress(0x5000, StackMode::User),
+                StackFrame::InstructionPointer(0xffff_8000_0000_1000, StackMode::Kernel),
+            ],
+            vec![
+                StackFrame::ReturnAddress(0x1010, StackMode::User),
+                StackFrame::InstructionPointer(0x1001, StackMode::User),
+            ],
+        ]
+        .map(|frames| stacks.convert(frames.into_iter()));
+
+        let converter = StackConverter::new(user_category, kernel_category);
+        let mut shared_cache = FrameResolutionCache::default();
+        let mut buf = Vec::new();
+        for stack in samples {
+            buf.clear();
+            stacks.convert_back(stack, &mut buf);
+            let cached: Vec<FrameInfo> = converter
+                .convert_stack(&buf, &lib_mappings, &mut shared_cache, None)
+                .collect();
+            let mut fresh_cache = FrameResolutionCache::default();
+            let uncached: Vec<FrameInfo> = converter
+                .convert_stack(&buf, &lib_mappings, &mut fresh_cache, None)
+                .collect();
+            assert_eq!(cached, uncached);
+            assert_eq!(cached.len(), buf.len());
+        }
+
+        buf.clear();
+        stacks.convert_back(samples[0], &mut buf);
+        let frames: Vec<Frame> = converter
+            .convert_stack(&buf, &lib_mappings, &mut shared_cache, None)
+            .map(|frame_info| frame_info.frame)
+            .collect();
+        assert_eq!(
+            frames,
+            vec![
+                Frame::RelativeAddressFromReturnAddress(lib_handle, 0x410),
+                Frame::RelativeAddressFromReturnAddress(lib_handle, 0x634),
+                Frame::RelativeAddressFromInstructionPointer(lib_handle, 0xc00),
+            ]
+        );
+    }
+}
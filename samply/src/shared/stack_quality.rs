@@ -0,0 +1,297 @@
+use fxprof_processed_profile::{LibraryHandle, Profile};
+
+use super::lib_mappings::LibMappingsHierarchy;
+use super::types::{FastHashMap, FastHashSet, StackFrame, StackMode};
+use super::unresolved_samples::UnresolvedStackHandle;
+
+/// User stacks with at most this many frames are counted as truncated. Even
+/// the shallowest real stacks have a few frames below the thread's entry
+/// point, e.g. _start, __libc_start_main and main.
+const SHORT_USER_STACK_FRAME_COUNT: usize = 2;
+
+/// Libraries with fewer samples than this don't get a warning, because a few
+/// short stacks can be legitimate.
+const MIN_SAMPLE_COUNT_FOR_WARNING: u64 = 20;
+
+/// The share of truncated stacks above which a library gets a warning.
+const TRUNCATED_FRACTION_FOR_WARNING: f64 = 0.5;
+
+/// The library which a sample's user stack starts in, i.e. the library of
+/// the innermost user frame, and whether the stack is suspiciously short.
+///
+/// Stacks which are cut off because they're deeper than the copied stack
+/// bytes aren't counted as truncated, only ones which stop right away.
+#[derive(Debug, Clone, Copy)]
+pub struct UserStackStart {
+    lib: LibraryHandle,
+    is_truncated: bool,
+}
+
+impl UserStackStart {
+    /// `frames` is an unresolved stack as returned by
+    /// `UnresolvedStacks::convert_back`, i.e. with the innermost frame first.
+    /// Returns `None` if the stack has no user frames, or if the innermost one
+    /// isn't in a regular library, e.g. because it's in JIT code or in
+    /// a managed runtime.
+    pub fn for_stack(
+        frames: &[(UnresolvedStackHandle, StackFrame)],
+        lib_mappings: &LibMappingsHierarchy,
+    ) -> Option<Self> {
+        let mut user_addresses = frames.iter().filter_map(|(_, frame)| match *frame {
+            StackFrame::InstructionPointer(address, StackMode::User)
+            | StackFrame::ReturnAddress(address, StackMode::User) => Some(address),
+            _ => None,
+        });
+        let (_, info) = lib_mappings.convert_address(user_addresses.next()?)?;
+        if info.category.is_some() {
+            // JIT code and managed runtimes have their own categories, and
+            // are often called from frames which can't be unwound anyway.
+            return None;
+        }
+        let user_frame_count = 1 + user_addresses.count();
+        Some(Self {
+            lib: info.lib_handle,
+            is_truncated: user_frame_count <= SHORT_USER_STACK_FRAME_COUNT,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct LibSampleCounts {
+    sample_count: u64,
+    truncated_count: u64,
+}
+
+/// Counts, per library, the samples whose user stack starts in the library,
+/// and how many of them are truncated. If most of them are, the library was
+/// probably compiled without frame pointers or unwind info, and the profile
+/// shows one-frame stacks for it.
+#[derive(Debug, Clone, Default)]
+pub struct StackQualityStats {
+    libs: FastHashMap<LibraryHandle, LibSampleCounts>,
+}
+
+impl StackQualityStats {
+    pub fn add_sample(&mut self, stack_start: Option<UserStackStart>) {
+        let Some(UserStackStart { lib, is_truncated }) = stack_start else {
+            return;
+        };
+        let counts = self.libs.entry(lib).or_default();
+        counts.sample_count += 1;
+        if is_truncated {
+            counts.truncated_count += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: StackQualityStats) {
+        for (lib, counts) in other.libs {
+            let merged = self.libs.entry(lib).or_default();
+            merged.sample_count += counts.sample_count;
+            merged.truncated_count += counts.truncated_count;
+        }
+    }
+
+    /// Adds a "Warnings" section to the profile's metadata for the libraries
+    /// whose stacks are mostly truncated, and for the ones in
+    /// `libs_without_debug_info` which have samples. Returns the warnings, so
+    /// that they can also be printed.
+    pub fn add_warnings(
+        &self,
+        profile: &mut Profile,
+        libs_without_debug_info: &FastHashSet<LibraryHandle>,
+    ) -> Vec<String> {
+        let mut libs: Vec<(&LibraryHandle, &LibSampleCounts)> = self.libs.iter().collect();
+        libs.sort_by_key(|(_, counts)| std::cmp::Reverse(counts.sample_count));
+
+        let mut warnings = Vec::new();
+        for (lib, counts) in libs {
+            let name = profile.lib_info(*lib).name.clone();
+            if counts.sample_count >= MIN_SAMPLE_COUNT_FOR_WARNING {
+                let truncated_fraction = counts.truncated_count as f64 / counts.sample_count as f64;
+                if truncated_fraction > TRUNCATED_FRACTION_FOR_WARNING {
+                    let warning = format!(
+                        "{:.0}% of the {} samples in {name} have truncated stacks. It was probably compiled without frame pointers or unwind info. Build it with -C force-frame-pointers=yes (Rust) or -fno-omit-frame-pointer (C/C++).",
+                        truncated_fraction * 100.0,
+                        counts.sample_count,
+                    );
+                    profile.add_extra_info("Warnings", &name, &warning);
+                    warnings.push(warning);
+                }
+            }
+            if libs_without_debug_info.contains(lib) {
+                let warning = format!(
+                    "{name} has no debug info, so its frames won't show inlined functions or line numbers. Build it with debug = true in the cargo profile (Rust) or with -g (C/C++)."
+                );
+                profile.add_extra_info("Warnings", &name, &warning);
+                warnings.push(warning);
+            }
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use debugid::DebugId;
+    use fxprof_processed_profile::{LibraryInfo, ReferenceTimestamp, SamplingInterval};
+
+    use super::*;
+    use crate::shared::lib_mappings::{
+        LibMappingAdd, LibMappingInfo, LibMappingOp, LibMappingOpQueue,
+    };
+
+    const LIB_START: u64 = 0x1000;
+    const LIB_END: u64 = 0x2000;
+
+    fn profile_with_lib(name: &str) -> (Profile, LibraryHandle) {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let lib = profile.add_lib(LibraryInfo {
+            name: name.to_string(),
+            debug_name: name.to_string(),
+            path: format!("/usr/lib/{name}"),
+            debug_path: format!("/usr/lib/{name}"),
+            debug_id: DebugId::nil(),
+            code_id: None,
+            arch: None,
+            symbol_table: None,
+        });
+        (profile, lib)
+    }
+
+    fn mappings_for_lib(lib: LibraryHandle) -> LibMappingsHierarchy {
+        let mut ops = LibMappingOpQueue::default();
+        ops.push(
+            0,
+            LibMappingOp::Add(LibMappingAdd {
+                start_avma: LIB_START,
+                end_avma: LIB_END,
+                relative_address_at_start: 0,
+                info: LibMappingInfo::new_lib(lib),
+            }),
+        );
+        let mut mappings = LibMappingsHierarchy::new(ops);
+        mappings.process_ops(0);
+        mappings
+    }
+
+    fn user_stack(addresses: &[u64]) -> Vec<(UnresolvedStackHandle, StackFrame)> {
+        let mut frames = vec![(
+            UnresolvedStackHandle::EMPTY,
+            StackFrame::InstructionPointer(0xffff_ffff_8100_0000, StackMode::Kernel),
+        )];
+        for (i, address) in addresses.iter().enumerate() {
+            let frame = if i == 0 {
+                StackFrame::InstructionPointer(*address, StackMode::User)
+            } else {
+                StackFrame::ReturnAddress(*address, StackMode::User)
+            };
+            frames.push((UnresolvedStackHandle::EMPTY, frame));
+        }
+        frames
+    }
+
+    fn add_samples(
+        stats: &mut StackQualityStats,
+        mappings: &LibMappingsHierarchy,
+        count: usize,
+        addresses: &[u64],
+    ) {
+        for _ in 0..count {
+            stats.add_sample(UserStackStart::for_stack(&user_stack(addresses), mappings));
+        }
+    }
+
+    #[test]
+    fn stack_start_is_attributed_to_the_innermost_user_frame() {
+        let (_, lib) = profile_with_lib("libfoo.so");
+        let mappings = mappings_for_lib(lib);
+
+        let start = UserStackStart::for_stack(&user_stack(&[0x1100, 0x1200]), &mappings).unwrap();
+        assert_eq!(start.lib, lib);
+        assert!(start.is_truncated);
+
+        let start =
+            UserStackStart::for_stack(&user_stack(&[0x1100, 0x1200, 0x1300]), &mappings).unwrap();
+        assert!(!start.is_truncated);
+
+        // No user frames, or an innermost frame outside of any library.
+        assert!(UserStackStart::for_stack(&user_stack(&[]), &mappings).is_none());
+        assert!(UserStackStart::for_stack(&user_stack(&[0x5000, 0x1100]), &mappings).is_none());
+    }
+
+    #[test]
+    fn warns_about_libraries_with_mostly_truncated_stacks() {
+        let (mut profile, lib) = profile_with_lib("libfoo.so");
+        let mappings = mappings_for_lib(lib);
+        let mut stats = StackQualityStats::default();
+        add_samples(&mut stats, &mappings, 15, &[0x1100]);
+        add_samples(&mut stats, &mappings, 5, &[0x1100, 0x1200, 0x1300, 0x1400]);
+
+        let warnings = stats.add_warnings(&mut profile, &FastHashSet::default());
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("75% of the 20 samples in libfoo.so"));
+        let meta = serde_json::to_value(&profile).unwrap()["meta"].clone();
+        assert!(meta["extra"].to_string().contains("Warnings"));
+    }
+
+    #[test]
+    fn no_warning_below_the_sample_count_or_fraction_threshold() {
+        let (mut profile, lib) = profile_with_lib("libfoo.so");
+        let mappings = mappings_for_lib(lib);
+
+        let mut stats = StackQualityStats::default();
+        add_samples(&mut stats, &mappings, 19, &[0x1100]);
+        assert!(stats
+            .add_warnings(&mut profile, &FastHashSet::default())
+            .is_empty());
+
+        // Exactly half truncated isn't "most".
+        let mut stats = StackQualityStats::default();
+        add_samples(&mut stats, &mappings, 10, &[0x1100]);
+        add_samples(&mut stats, &mappings, 10, &[0x1100, 0x1200, 0x1300]);
+        assert!(stats
+            .add_warnings(&mut profile, &FastHashSet::default())
+            .is_empty());
+    }
+
+    #[test]
+    fn deep_stacks_cut_off_by_the_stack_size_are_not_truncated() {
+        let (mut profile, lib) = profile_with_lib("libfoo.so");
+        let mappings = mappings_for_lib(lib);
+        let mut stats = StackQualityStats::default();
+        let deep_stack: Vec<u64> = (0..100).map(|i| 0x1100 + i * 8).collect();
+        for _ in 0..50 {
+            let mut frames = user_stack(&deep_stack);
+            frames.push((
+                UnresolvedStackHandle::EMPTY,
+                StackFrame::TruncatedStackMarker,
+            ));
+            stats.add_sample(UserStackStart::for_stack(&frames, &mappings));
+        }
+        assert!(stats
+            .add_warnings(&mut profile, &FastHashSet::default())
+            .is_empty());
+    }
+
+    #[test]
+    fn merged_stats_and_missing_debug_info_produce_warnings() {
+        let (mut profile, lib) = profile_with_lib("libfoo.so");
+        let mappings = mappings_for_lib(lib);
+        let mut stats = StackQualityStats::default();
+        let mut other = StackQualityStats::default();
+        add_samples(&mut stats, &mappings, 10, &[0x1100]);
+        add_samples(&mut other, &mappings, 10, &[0x1100]);
+        stats.merge(other);
+
+        let mut libs_without_debug_info = FastHashSet::default();
+        libs_without_debug_info.insert(lib);
+        let warnings = stats.add_warnings(&mut profile, &libs_without_debug_info);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("100% of the 20 samples in libfoo.so"));
+        assert!(warnings[1].starts_with("libfoo.so has no debug info"));
+    }
+}
@@ -0,0 +1,550 @@
+use fxprof_processed_profile::{
+    CategoryPairHandle, Frame, FrameFlags, FrameInfo, Profile, ThreadHandle, Timestamp,
+};
+use regex::Regex;
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+use std::path::Path;
+
+use super::json_markers::JsonMarkerOnThread;
+use super::types::FastHashMap;
+use super::unresolved_samples::UnresolvedStackHandle;
+
+/// The spawn site is the stack of the last sample on the spawning thread
+/// before the spawn marker. Older samples are too likely to show something else.
+const MAX_SPAWN_SITE_SAMPLE_AGE_NS: u64 = 10_000_000;
+
+/// A rule for splicing a logical parent stack under the stacks of some samples.
+///
+/// A task which is spawned on one thread and runs on another, e.g. on an
+/// executor thread or from an event loop callback, is identified by two JSON
+/// markers with the same id: an instant marker named `spawn_marker` on the
+/// spawning thread, and an interval marker named `run_marker` on the thread
+/// which runs the task. The samples which fall into the run marker get the
+/// stack of the spawn site as their parent, instead of the frames of the
+/// executor or the interpreter which called the task.
+#[derive(Debug, Clone)]
+pub struct StackStitchingRule {
+    pub spawn_marker: String,
+    pub run_marker: String,
+    /// The marker data field which holds the task id.
+    pub id_field: String,
+    /// The innermost frame which matches this pattern, and all the frames
+    /// which called it, are replaced with the spawn site. Samples without a
+    /// matching frame are left alone. If there's no pattern, the whole stack
+    /// is kept and the spawn site goes underneath it.
+    pub trampoline: Option<Regex>,
+}
+
+#[derive(Deserialize)]
+struct StackStitchingRuleJson {
+    spawn: String,
+    run: String,
+    #[serde(default = "default_id_field")]
+    id: String,
+    trampoline: Option<String>,
+}
+
+fn default_id_field() -> String {
+    "id".to_string()
+}
+
+impl StackStitchingRule {
+    /// Reads rules from a JSON file, for example:
+    ///
+    /// ```json
+    /// [{"spawn": "TaskSpawn", "run": "TaskRun", "id": "task", "trampoline": "^py::"}]
+    /// ```
+    ///
+    /// `id` defaults to `"id"`, and `trampoline` is optional.
+    pub fn parse_rules_file(path: &Path) -> Result<Vec<Self>, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {err}", path.display()))?;
+        Self::parse_rules(&contents).map_err(|err| format!("Invalid {}: {err}", path.display()))
+    }
+
+    fn parse_rules(json: &str) -> Result<Vec<Self>, String> {
+        let rules: Vec<StackStitchingRuleJson> =
+            serde_json::from_str(json).map_err(|err| err.to_string())?;
+        rules
+            .into_iter()
+            .map(|rule| {
+                let trampoline = match rule.trampoline {
+                    Some(pattern) => Some(Regex::new(&pattern).map_err(|err| err.to_string())?),
+                    None => None,
+                };
+                Ok(Self {
+                    spawn_marker: rule.spawn,
+                    run_marker: rule.run,
+                    id_field: rule.id,
+                    trampoline,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Where a task was spawned: the thread, and its stack at that time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpawnSite {
+    pub thread: ThreadHandle,
+    pub stack: UnresolvedStackHandle,
+    rule_index: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Spawn {
+    time: Timestamp,
+    thread: ThreadHandle,
+    rule_index: usize,
+    id: String,
+}
+
+#[derive(Debug, Clone)]
+struct Run {
+    start: Timestamp,
+    end: Timestamp,
+    rule_index: usize,
+    id: String,
+}
+
+/// Finds the spawn site for the samples of a process, based on the
+/// [`StackStitchingRule`]s and the process's JSON markers.
+///
+/// The samples have to be passed to [`add_sample`](Self::add_sample) in
+/// timestamp order, because the spawn site stacks are picked up on the fly.
+pub struct StackStitcher<'a> {
+    rules: &'a [StackStitchingRule],
+    /// Sorted by time. The ones before `next_spawn` have been looked up.
+    spawns: Vec<Spawn>,
+    next_spawn: usize,
+    /// Sorted by start time, per thread.
+    runs: FastHashMap<ThreadHandle, Vec<Run>>,
+    last_samples: FastHashMap<ThreadHandle, (Timestamp, UnresolvedStackHandle)>,
+    spawn_sites: FastHashMap<(usize, String), SpawnSite>,
+}
+
+impl<'a> StackStitcher<'a> {
+    /// Returns `None` if there are no spawn or run markers for the rules.
+    pub fn new(
+        rules: &'a [StackStitchingRule],
+        json_markers: &[JsonMarkerOnThread],
+    ) -> Option<Self> {
+        let mut spawns = Vec::new();
+        let mut runs: FastHashMap<ThreadHandle, Vec<Run>> = FastHashMap::default();
+        for JsonMarkerOnThread {
+            thread_handle,
+            marker,
+        } in json_markers
+        {
+            for (rule_index, rule) in rules.iter().enumerate() {
+                let Some(id) = marker.data.get(&rule.id_field).map(id_string) else {
+                    continue;
+                };
+                if marker.name == rule.spawn_marker {
+                    spawns.push(Spawn {
+                        time: marker.start_time,
+                        thread: *thread_handle,
+                        rule_index,
+                        id,
+                    });
+                } else if let (true, Some(end)) = (marker.name == rule.run_marker, marker.end_time)
+                {
+                    runs.entry(*thread_handle).or_default().push(Run {
+                        start: marker.start_time,
+                        end,
+                        rule_index,
+                        id,
+                    });
+                }
+            }
+        }
+        if spawns.is_empty() || runs.is_empty() {
+            return None;
+        }
+        spawns.sort_by_key(|spawn| spawn.time);
+        for thread_runs in runs.values_mut() {
+            thread_runs.sort_by_key(|run| run.start);
+        }
+        Some(Self {
+            rules,
+            spawns,
+            next_spawn: 0,
+            runs,
+            last_samples: FastHashMap::default(),
+            spawn_sites: FastHashMap::default(),
+        })
+    }
+
+    /// Records the stack of a sample, and returns the spawn site of the task
+    /// which the sample belongs to, if any.
+    pub fn add_sample(
+        &mut self,
+        thread: ThreadHandle,
+        timestamp: Timestamp,
+        stack: UnresolvedStackHandle,
+    ) -> Option<SpawnSite> {
+        // All samples up to the time of these spawn markers have been seen.
+        while let Some(spawn) = self.spawns.get(self.next_spawn) {
+            if spawn.time >= timestamp {
+                break;
+            }
+            self.next_spawn += 1;
+            let Some(&(sample_time, stack)) = self.last_samples.get(&spawn.thread) else {
+                continue;
+            };
+            let age = spawn
+                .time
+                .nanos_since_reference()
+                .saturating_sub(sample_time.nanos_since_reference());
+            if age <= MAX_SPAWN_SITE_SAMPLE_AGE_NS {
+                self.spawn_sites.insert(
+                    (spawn.rule_index, spawn.id.clone()),
+                    SpawnSite {
+                        thread: spawn.thread,
+                        stack,
+                        rule_index: spawn.rule_index,
+                    },
+                );
+            }
+        }
+        self.last_samples.insert(thread, (timestamp, stack));
+
+        // Runs on the same thread aren't expected to overlap. For nested runs,
+        // only the innermost one is found.
+        let runs = self.runs.get(&thread)?;
+        let run = runs[..runs.partition_point(|run| run.start <= timestamp)].last()?;
+        if run.end < timestamp {
+            return None;
+        }
+        self.spawn_sites
+            .get(&(run.rule_index, run.id.clone()))
+            .copied()
+    }
+
+    /// Puts together the stack of a sample which belongs to a spawned task:
+    /// the frames of the spawn site, a label frame with the name of the run
+    /// marker, and the frames of the task. Both frame lists start with the
+    /// root frame. Returns `None` if the rule has a trampoline pattern which
+    /// doesn't match any of the task's frames.
+    pub fn stitch(
+        &self,
+        profile: &mut Profile,
+        site: SpawnSite,
+        spawn_frames: Vec<FrameInfo>,
+        task_frames: Vec<FrameInfo>,
+        category_pair: CategoryPairHandle,
+    ) -> Option<Vec<FrameInfo>> {
+        let rule = &self.rules[site.rule_index];
+        let task_start = match &rule.trampoline {
+            Some(trampoline) => {
+                task_frames.iter().rposition(|frame| {
+                    frame_name(profile, frame).map_or(false, |name| trampoline.is_match(name))
+                })? + 1
+            }
+            None => 0,
+        };
+        let label = FrameInfo {
+            frame: Frame::Label(profile.intern_string(&rule.run_marker)),
+            category_pair,
+            flags: FrameFlags::empty(),
+        };
+        let mut frames = spawn_frames;
+        frames.push(label);
+        frames.extend_from_slice(&task_frames[task_start..]);
+        Some(frames)
+    }
+}
+
+/// Marker ids can be strings or numbers.
+fn id_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// The name which trampoline patterns are matched against. Only JIT frames
+/// have a function name at this point, because native code is symbolicated
+/// later, so other frames use the name of their library.
+fn frame_name<'p>(profile: &'p Profile, frame: &FrameInfo) -> Option<&'p str> {
+    let (lib, relative_address) = match frame.frame {
+        Frame::RelativeAddressFromInstructionPointer(lib, address) => (lib, address),
+        Frame::RelativeAddressFromReturnAddress(lib, address) => (lib, address.saturating_sub(1)),
+        _ => return None,
+    };
+    let lib_info = profile.lib_info(lib);
+    let symbol = lib_info
+        .symbol_table
+        .as_deref()
+        .and_then(|symbol_table| symbol_table.lookup(relative_address));
+    Some(match symbol {
+        Some(symbol) => &symbol.name,
+        None => &lib_info.name,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use debugid::DebugId;
+    use fxprof_processed_profile::{
+        CategoryColor, LibraryInfo, ReferenceTimestamp, SamplingInterval, Symbol, SymbolTable,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::shared::json_markers::JsonMarker;
+    use crate::shared::types::{StackFrame, StackMode};
+    use crate::shared::unresolved_samples::UnresolvedStacks;
+
+    fn ms(millis: u64) -> Timestamp {
+        Timestamp::from_nanos_since_reference(millis * 1_000_000)
+    }
+
+    fn profile_with_threads() -> (Profile, ThreadHandle, ThreadHandle) {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let process = profile.add_process("app", 1, ms(0));
+        let main_thread = profile.add_thread(process, 1, ms(0), true);
+        let executor_thread = profile.add_thread(process, 2, ms(0), false);
+        (profile, main_thread, executor_thread)
+    }
+
+    fn marker(
+        thread_handle: ThreadHandle,
+        name: &str,
+        start: u64,
+        end: Option<u64>,
+        data: Value,
+    ) -> JsonMarkerOnThread {
+        let Value::Object(data) = data else {
+            panic!("marker data must be an object");
+        };
+        JsonMarkerOnThread {
+            thread_handle,
+            marker: JsonMarker {
+                type_name: name.to_string(),
+                name: name.to_string(),
+                start_time: ms(start),
+                end_time: end.map(ms),
+                data,
+            },
+        }
+    }
+
+    fn rule(trampoline: Option<&str>) -> StackStitchingRule {
+        StackStitchingRule {
+            spawn_marker: "TaskSpawn".to_string(),
+            run_marker: "TaskRun".to_string(),
+            id_field: "id".to_string(),
+            trampoline: trampoline.map(|pattern| Regex::new(pattern).unwrap()),
+        }
+    }
+
+    fn stack(stacks: &mut UnresolvedStacks, addresses: &[u64]) -> UnresolvedStackHandle {
+        stacks.convert(
+            addresses
+                .iter()
+                .map(|address| StackFrame::ReturnAddress(*address, StackMode::User)),
+        )
+    }
+
+    #[test]
+    fn samples_in_run_markers_get_the_spawn_site() {
+        let (_, main_thread, executor_thread) = profile_with_threads();
+        let rules = [rule(None)];
+        let markers = [
+            marker(main_thread, "TaskSpawn", 10, None, json!({"id": 7})),
+            marker(executor_thread, "TaskRun", 20, Some(30), json!({"id": 7})),
+            // The run of a task whose spawn wasn't seen.
+            marker(executor_thread, "TaskRun", 40, Some(50), json!({"id": 8})),
+        ];
+        let mut stacks = UnresolvedStacks::default();
+        let spawn_stack = stack(&mut stacks, &[0x10, 0x20]);
+        let task_stack = stack(&mut stacks, &[0x30, 0x40]);
+
+        let mut stitcher = StackStitcher::new(&rules, &markers).unwrap();
+        assert!(stitcher
+            .add_sample(main_thread, ms(9), spawn_stack)
+            .is_none());
+        // Before, during, after and in an unknown run.
+        assert!(stitcher
+            .add_sample(executor_thread, ms(15), task_stack)
+            .is_none());
+        let site = stitcher
+            .add_sample(executor_thread, ms(25), task_stack)
+            .unwrap();
+        assert_eq!(site.thread, main_thread);
+        assert_eq!(site.stack, spawn_stack);
+        assert!(stitcher
+            .add_sample(executor_thread, ms(35), task_stack)
+            .is_none());
+        assert!(stitcher
+            .add_sample(executor_thread, ms(45), task_stack)
+            .is_none());
+    }
+
+    #[test]
+    fn old_spawn_site_samples_and_other_rules_are_ignored() {
+        let (_, main_thread, executor_thread) = profile_with_threads();
+        let mut other_rule = rule(None);
+        other_rule.spawn_marker = "Post".to_string();
+        other_rule.run_marker = "Callback".to_string();
+        let rules = [rule(None), other_rule];
+        let markers = [
+            // The last sample on the spawning thread is 20ms older.
+            marker(main_thread, "TaskSpawn", 30, None, json!({"id": "a"})),
+            marker(executor_thread, "TaskRun", 40, Some(50), json!({"id": "a"})),
+            // The same id, but for the other rule.
+            marker(main_thread, "Post", 60, None, json!({"id": "b"})),
+            marker(executor_thread, "TaskRun", 70, Some(80), json!({"id": "b"})),
+        ];
+        let mut stacks = UnresolvedStacks::default();
+        let spawn_stack = stack(&mut stacks, &[0x10]);
+        let task_stack = stack(&mut stacks, &[0x30]);
+
+        let mut stitcher = StackStitcher::new(&rules, &markers).unwrap();
+        stitcher.add_sample(main_thread, ms(10), spawn_stack);
+        assert!(stitcher
+            .add_sample(executor_thread, ms(45), task_stack)
+            .is_none());
+        stitcher.add_sample(main_thread, ms(59), spawn_stack);
+        assert!(stitcher
+            .add_sample(executor_thread, ms(75), task_stack)
+            .is_none());
+    }
+
+    #[test]
+    fn no_stitcher_without_markers_for_the_rules() {
+        let (_, main_thread, _) = profile_with_threads();
+        let rules = [rule(None)];
+        let markers = [marker(main_thread, "TaskSpawn", 10, None, json!({"id": 1}))];
+        assert!(StackStitcher::new(&rules, &markers).is_none());
+        assert!(StackStitcher::new(&rules, &[]).is_none());
+    }
+
+    #[test]
+    fn stitch_replaces_the_frames_up_to_the_trampoline() {
+        let (mut profile, main_thread, executor_thread) = profile_with_threads();
+        let lib = profile.add_lib(LibraryInfo {
+            name: "libpython.so".to_string(),
+            debug_name: "libpython.so".to_string(),
+            path: "/usr/lib/libpython.so".to_string(),
+            debug_path: "/usr/lib/libpython.so".to_string(),
+            debug_id: DebugId::nil(),
+            code_id: None,
+            arch: None,
+            symbol_table: Some(Arc::new(SymbolTable::new(vec![
+                Symbol {
+                    address: 0x100,
+                    size: Some(0x100),
+                    name: "event_loop".to_string(),
+                },
+                Symbol {
+                    address: 0x200,
+                    size: Some(0x100),
+                    name: "py::run_task".to_string(),
+                },
+                Symbol {
+                    address: 0x300,
+                    size: Some(0x100),
+                    name: "task_body".to_string(),
+                },
+            ]))),
+        });
+        let category = profile.add_category("Other", CategoryColor::Gray).into();
+        let frame = |address: u32| FrameInfo {
+            frame: Frame::RelativeAddressFromReturnAddress(lib, address),
+            category_pair: category,
+            flags: FrameFlags::empty(),
+        };
+        let label = FrameInfo {
+            frame: Frame::Label(profile.intern_string("TaskRun")),
+            category_pair: category,
+            flags: FrameFlags::empty(),
+        };
+        let markers = [
+            marker(main_thread, "TaskSpawn", 10, None, json!({"id": 1})),
+            marker(executor_thread, "TaskRun", 20, Some(30), json!({"id": 1})),
+        ];
+        let mut stacks = UnresolvedStacks::default();
+        let spawn_stack = stack(&mut stacks, &[0x10]);
+        let task_stack = stack(&mut stacks, &[0x30]);
+        let spawn_frames = vec![frame(0x110)];
+        let task_frames = vec![frame(0x120), frame(0x210), frame(0x310)];
+
+        let with_trampoline = [rule(Some("^py::"))];
+        let mut stitcher = StackStitcher::new(&with_trampoline, &markers).unwrap();
+        stitcher.add_sample(main_thread, ms(9), spawn_stack);
+        let site = stitcher
+            .add_sample(executor_thread, ms(25), task_stack)
+            .unwrap();
+        let frames = stitcher
+            .stitch(
+                &mut profile,
+                site,
+                spawn_frames.clone(),
+                task_frames.clone(),
+                category,
+            )
+            .unwrap();
+        assert_eq!(frames, vec![frame(0x110), label.clone(), frame(0x310)]);
+
+        // Without a matching frame, the stack is left alone.
+        assert!(stitcher
+            .stitch(
+                &mut profile,
+                site,
+                spawn_frames.clone(),
+                vec![frame(0x120), frame(0x310)],
+                category,
+            )
+            .is_none());
+
+        // Without a trampoline, the whole task stack is kept.
+        let without_trampoline = [rule(None)];
+        let stitcher = StackStitcher::new(&without_trampoline, &markers).unwrap();
+        let frames = stitcher
+            .stitch(&mut profile, site, spawn_frames, task_frames, category)
+            .unwrap();
+        assert_eq!(
+            frames,
+            vec![
+                frame(0x110),
+                label,
+                frame(0x120),
+                frame(0x210),
+                frame(0x310)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rules = StackStitchingRule::parse_rules(
+            r#"[{"spawn": "TaskSpawn", "run": "TaskRun", "trampoline": "^py::"}, {"spawn": "Post", "run": "Callback", "id": "callback"}]"#,
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].id_field, "id");
+        assert!(rules[0]
+            .trampoline
+            .as_ref()
+            .unwrap()
+            .is_match("py::run:app.py"));
+        assert_eq!(rules[1].id_field, "callback");
+        assert!(rules[1].trampoline.is_none());
+
+        assert!(StackStitchingRule::parse_rules(r#"[{"spawn": "TaskSpawn"}]"#).is_err());
+        assert!(StackStitchingRule::parse_rules(
+            r#"[{"spawn": "A", "run": "B", "trampoline": "("}]"#
+        )
+        .is_err());
+    }
+}
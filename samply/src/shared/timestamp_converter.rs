@@ -11,7 +11,9 @@ pub struct TimestampConverter {
 impl TimestampConverter {
     pub fn convert_time(&self, ktime_ns: u64) -> Timestamp {
         Timestamp::from_nanos_since_reference(
-            ktime_ns.saturating_sub(self.reference_raw) * self.raw_to_ns_factor,
+            ktime_ns
+                .saturating_sub(self.reference_raw)
+                .saturating_mul(self.raw_to_ns_factor),
         )
     }
 }
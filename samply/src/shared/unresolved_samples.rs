@@ -2,6 +2,7 @@ use std::collections::hash_map::Entry;
 
 use fxprof_processed_profile::{CpuDelta, FrameInfo, ThreadHandle, Timestamp};
 
+use super::off_cpu_reason::OffCpuReason;
 use super::process_sample_data::RssStatMember;
 use super::types::{FastHashMap, StackFrame, StackMode};
 
@@ -9,6 +10,7 @@ use super::types::{FastHashMap, StackFrame, StackMode};
 pub struct UnresolvedSamples {
     samples_and_markers: Vec<UnresolvedSampleOrMarker>,
     prev_sample_info_per_thread: FastHashMap<ThreadHandle, PreviousSampleInfo>,
+    has_off_cpu_samples: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -26,6 +28,11 @@ impl UnresolvedSamples {
         self.samples_and_markers.is_empty()
     }
 
+    /// Whether any samples were added with [`UnresolvedSamples::add_off_cpu_sample`].
+    pub fn has_off_cpu_samples(&self) -> bool {
+        self.has_off_cpu_samples
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn add_sample(
         &mut self,
@@ -37,6 +44,60 @@ impl UnresolvedSamples {
         weight: i32,
         extra_label_frame: Option<FrameInfo>,
     ) {
+        self.push_sample(
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            stack,
+            extra_label_frame,
+            SampleData {
+                cpu_delta,
+                weight,
+                off_cpu: None,
+            },
+        );
+    }
+
+    /// Adds a sample for time during which the thread was blocked. Its frames
+    /// are put into the Off-CPU category, with the subcategory for `reason`,
+    /// because the sample stands for time which was spent waiting rather than
+    /// running.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_off_cpu_sample(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        cpu_delta: CpuDelta,
+        weight: i32,
+        reason: OffCpuReason,
+    ) {
+        self.has_off_cpu_samples = true;
+        self.push_sample(
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            stack,
+            None,
+            SampleData {
+                cpu_delta,
+                weight,
+                off_cpu: Some(reason),
+            },
+        );
+    }
+
+    fn push_sample(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        extra_label_frame: Option<FrameInfo>,
+        sample_data: SampleData,
+    ) {
+        let cpu_delta = sample_data.cpu_delta;
         let sample_index = self.samples_and_markers.len();
         self.samples_and_markers.push(UnresolvedSampleOrMarker {
             thread_handle,
@@ -44,7 +105,7 @@ impl UnresolvedSamples {
             timestamp_mono,
             stack,
             extra_label_frame,
-            sample_or_marker: SampleOrMarker::Sample(SampleData { weight, cpu_delta }),
+            sample_or_marker: SampleOrMarker::Sample(sample_data),
         });
         self.prev_sample_info_per_thread.insert(
             thread_handle,
@@ -87,6 +148,7 @@ impl UnresolvedSamples {
                         sample_or_marker: SampleOrMarker::Sample(SampleData {
                             weight,
                             cpu_delta: CpuDelta::ZERO,
+                            off_cpu: None,
                         }),
                     });
                     sample_info.prev_sample_index_if_zero_cpu = Some(sample_index);
@@ -104,6 +166,7 @@ impl UnresolvedSamples {
                     sample_or_marker: SampleOrMarker::Sample(SampleData {
                         weight,
                         cpu_delta: CpuDelta::ZERO,
+                        off_cpu: None,
                     }),
                 });
                 entry.insert(PreviousSampleInfo {
@@ -139,6 +202,30 @@ impl UnresolvedSamples {
         });
     }
 
+    /// Adds a futex wait from `timestamp` to `end_time`. The stack is the one
+    /// from when the wait started.
+    pub fn add_lock_wait_marker(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        address: u64,
+        end_time: Timestamp,
+    ) {
+        self.samples_and_markers.push(UnresolvedSampleOrMarker {
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            stack,
+            extra_label_frame: None,
+            sample_or_marker: SampleOrMarker::LockWaitMarker(LockWaitMarkerData {
+                address,
+                end_time,
+            }),
+        });
+    }
+
     pub fn add_other_event_marker(
         &mut self,
         thread_handle: ThreadHandle,
@@ -172,6 +259,7 @@ pub struct UnresolvedSampleOrMarker {
 pub enum SampleOrMarker {
     Sample(SampleData),
     RssStatMarker(RssStatMarkerData),
+    LockWaitMarker(LockWaitMarkerData),
     OtherEventMarker(OtherEventMarkerData),
 }
 
@@ -179,6 +267,9 @@ pub enum SampleOrMarker {
 pub struct SampleData {
     pub cpu_delta: CpuDelta,
     pub weight: i32,
+    /// Set if the thread was blocked during this sample, see
+    /// [`UnresolvedSamples::add_off_cpu_sample`].
+    pub off_cpu: Option<OffCpuReason>,
 }
 
 #[derive(Debug, Clone)]
@@ -188,6 +279,12 @@ pub struct RssStatMarkerData {
     pub delta: i64,
 }
 
+#[derive(Debug, Clone)]
+pub struct LockWaitMarkerData {
+    pub address: u64,
+    pub end_time: Timestamp,
+}
+
 #[derive(Debug, Clone)]
 pub struct OtherEventMarkerData {
     pub attr_index: usize,
@@ -256,11 +353,17 @@ impl UnresolvedStacks {
         prefix
     }
 
-    // Appends the stack to `buf`, starting with the callee-most frame.
-    pub fn convert_back(&self, mut stack_index: UnresolvedStackHandle, buf: &mut Vec<StackFrame>) {
+    /// Appends the stack to `buf`, starting with the callee-most frame. Each frame
+    /// is accompanied by the handle of the stack node which it's the callee-most
+    /// frame of.
+    pub fn convert_back(
+        &self,
+        mut stack_index: UnresolvedStackHandle,
+        buf: &mut Vec<(UnresolvedStackHandle, StackFrame)>,
+    ) {
         while stack_index != UnresolvedStackHandle::EMPTY {
             let (prefix, frame) = self.stacks[stack_index.0 as usize];
-            buf.push(frame);
+            buf.push((stack_index, frame));
             stack_index = prefix;
         }
     }
@@ -1,3 +1,4 @@
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 
 use debugid::CodeId;
@@ -19,6 +20,31 @@ pub fn open_file_with_fallback(
     }
 }
 
+/// Look up a binary in perf's build-id cache (`$PERF_BUILDID_DIR`, or `~/.debug`).
+/// `perf record` and `perf archive` put a copy of every binary that was mapped
+/// during the recording there, so this finds the exact file even if the binary
+/// on disk has been replaced since.
+pub fn open_file_from_perf_build_id_cache(build_id: &[u8]) -> Option<(std::fs::File, PathBuf)> {
+    let cache_dir = match std::env::var_os("PERF_BUILDID_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => dirs::home_dir()?.join(".debug"),
+    };
+    let build_id_hex: String = build_id.iter().map(|b| format!("{b:02x}")).collect();
+    if build_id_hex.len() < 3 {
+        return None;
+    }
+    let link = cache_dir
+        .join(".build-id")
+        .join(&build_id_hex[..2])
+        .join(&build_id_hex[2..]);
+    // Newer perf versions link to a directory which contains the binary as `elf`,
+    // older versions link to the binary directly.
+    [link.join("elf"), link]
+        .into_iter()
+        .filter(|path| path.is_file())
+        .find_map(|path| Some((std::fs::File::open(&path).ok()?, path)))
+}
+
 pub fn lib_handle_for_jitdump(
     path: &Path,
     header: &JitDumpHeader,
@@ -45,3 +71,52 @@ pub fn lib_handle_for_jitdump(
         symbol_table: None,
     })
 }
+
+/// Runs the command a number of times without recording it, so that caches and
+/// JITs are warm when the recorded iterations start.
+pub struct WarmupIterations {
+    command_name: OsString,
+    command_args: Vec<OsString>,
+    count: u32,
+    /// Our environment from before we added the variables for the recorded
+    /// runs, such as the marker socket path or the rustc wrapper. The warm-up
+    /// runs shouldn't report to us.
+    env: Vec<(OsString, OsString)>,
+}
+
+impl WarmupIterations {
+    /// Must be called before the environment is set up for the recorded runs.
+    pub fn new(command_name: &OsStr, command_args: &[OsString], count: u32) -> Self {
+        Self {
+            command_name: command_name.to_owned(),
+            command_args: command_args.to_vec(),
+            count,
+            env: std::env::vars_os().collect(),
+        }
+    }
+
+    /// A failing warm-up iteration is reported, but doesn't stop the recording.
+    pub fn run(&self) {
+        let count = self.count;
+        for i in 1..=count {
+            eprintln!("Running warm-up iteration {i} of {count} without recording...");
+            match std::process::Command::new(&self.command_name)
+                .args(&self.command_args)
+                .env_clear()
+                .envs(self.env.iter().map(|(name, val)| (name, val)))
+                .status()
+            {
+                Ok(exit_status) if !exit_status.success() => {
+                    eprintln!(
+                        "Warm-up iteration {i} exited with non-success exit status: \"{exit_status}\""
+                    );
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    eprintln!("Could not run warm-up iteration {i}: {err}");
+                    return;
+                }
+            }
+        }
+    }
+}
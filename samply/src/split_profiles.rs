@@ -0,0 +1,327 @@
+use fxprof_processed_profile::{ProcessHandle, Profile};
+use serde_json::{json, Value};
+use tempfile::NamedTempFile;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// The name of the file which lists the per-process profiles in a directory
+/// written by [`write_split_profiles`].
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Writes one profile file per process into `dir`, which is created if needed,
+/// and a manifest which lists the processes and their files.
+///
+/// If `selected_processes` is non-empty, only the processes whose pid or name
+/// is in the list are written.
+///
+/// The files all contain the same libraries, categories and marker schemas, and
+/// can be loaded together with `samply load <dir>`.
+pub fn write_split_profiles(
+    profile: &Profile,
+    dir: &Path,
+    selected_processes: &[String],
+) -> std::io::Result<usize> {
+    let processes: Vec<ProcessHandle> = profile
+        .process_handles()
+        .filter(|&process| {
+            selected_processes.is_empty()
+                || selected_processes.iter().any(|selected| {
+                    selected == profile.process_pid(process)
+                        || selected == profile.process_name(process)
+                })
+        })
+        .collect();
+    write_process_profiles(profile, dir, &processes)
+}
+
+/// Like [`write_split_profiles`], but writes exactly the given processes.
+pub fn write_process_profiles(
+    profile: &Profile,
+    dir: &Path,
+    processes: &[ProcessHandle],
+) -> std::io::Result<usize> {
+    std::fs::create_dir_all(dir)?;
+    let mut manifest_entries = Vec::new();
+    for &process in processes {
+        let pid = profile.process_pid(process);
+        let name = profile.process_name(process);
+        let file_name = format!("{pid}-{}.json", sanitize_file_name(name));
+        let writer = BufWriter::new(File::create(dir.join(&file_name))?);
+        serde_json::to_writer(writer, &profile.process_subset(&[process]))?;
+        manifest_entries.push(json!({
+            "pid": pid,
+            "name": name,
+            "file": file_name,
+        }));
+    }
+    let process_count = manifest_entries.len();
+    let manifest = json!({ "processes": manifest_entries });
+    let writer = BufWriter::new(File::create(dir.join(MANIFEST_FILE_NAME))?);
+    serde_json::to_writer_pretty(writer, &manifest)?;
+    Ok(process_count)
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .take(64)
+        .collect();
+    if name.is_empty() {
+        "process".to_string()
+    } else {
+        name
+    }
+}
+
+/// Combines the per-process profiles in `dir` into a single profile, and writes
+/// it to a temporary file.
+///
+/// The profiles need to have the same start time and categories, which is the
+/// case for the files written by [`write_split_profiles`]. Libraries are merged,
+/// so profiles with different library lists are fine.
+pub fn merge_split_profiles(dir: &Path) -> Result<NamedTempFile, String> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|err| format!("Could not read directory {dir:?}: {err}"))?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .filter(|path| {
+            path.file_name()
+                .map_or(false, |name| name != MANIFEST_FILE_NAME)
+        })
+        .collect();
+    paths.sort();
+
+    let mut profiles = Vec::new();
+    for path in &paths {
+        let file = File::open(path).map_err(|err| format!("Could not open {path:?}: {err}"))?;
+        let profile: Value = serde_json::from_reader(BufReader::new(file))
+            .map_err(|err| format!("Could not parse {path:?}: {err}"))?;
+        profiles.push(profile);
+    }
+    if profiles.is_empty() {
+        return Err(format!("There are no profiles in {dir:?}."));
+    }
+    let merged = merge_profiles(profiles).map_err(|index| {
+        format!(
+            "{:?} doesn't belong to the same recording as the other profiles in {dir:?}.",
+            paths[index]
+        )
+    })?;
+
+    let temp_file =
+        NamedTempFile::new().map_err(|err| format!("Could not create a temporary file: {err}"))?;
+    serde_json::to_writer(BufWriter::new(temp_file.as_file()), &merged)
+        .map_err(|err| format!("Could not write the merged profile: {err}"))?;
+    Ok(temp_file)
+}
+
+/// Whether two processed profiles can be merged, because they have the same
+/// start time and categories.
+pub fn is_same_recording(profile: &Value, other: &Value) -> bool {
+    profile["meta"]["startTime"] == other["meta"]["startTime"]
+        && profile["meta"]["categories"] == other["meta"]["categories"]
+}
+
+/// Combines processed profiles of the same recording into one profile, with
+/// the threads and counters of all of them and a merged lib list. Returns the
+/// index of the first profile which doesn't belong to the recording of the
+/// first one as the error. `profiles` must not be empty.
+pub fn merge_profiles(profiles: Vec<Value>) -> Result<Value, usize> {
+    let mut merged: Option<Value> = None;
+    let mut merged_libs: Vec<Value> = Vec::new();
+    let mut lib_indexes: HashMap<String, usize> = HashMap::new();
+    for (index, mut profile) in profiles.into_iter().enumerate() {
+        if let Some(merged) = &merged {
+            if !is_same_recording(merged, &profile) {
+                return Err(index);
+            }
+        }
+
+        // Map this profile's lib indexes to the indexes in the merged lib list.
+        let lib_index_map: Vec<usize> = take_array(&mut profile, "libs")
+            .into_iter()
+            .map(|lib| {
+                *lib_indexes.entry(lib.to_string()).or_insert_with(|| {
+                    merged_libs.push(lib);
+                    merged_libs.len() - 1
+                })
+            })
+            .collect();
+        let mut threads = take_array(&mut profile, "threads");
+        for thread in &mut threads {
+            remap_lib_column(&mut thread["resourceTable"], "lib", &lib_index_map);
+            remap_lib_column(&mut thread["nativeSymbols"], "libIndex", &lib_index_map);
+        }
+        let mut counters = take_array(&mut profile, "counters");
+
+        let Some(merged) = &mut merged else {
+            profile["threads"] = Value::Array(threads);
+            profile["counters"] = Value::Array(counters);
+            merged = Some(profile);
+            continue;
+        };
+        let thread_offset = merged["threads"].as_array().map_or(0, Vec::len) as u64;
+        merge_thread_index_lists(merged, &profile, thread_offset, threads.len() as u64);
+        for counter in &mut counters {
+            if let Some(index) = counter["mainThreadIndex"].as_u64() {
+                counter["mainThreadIndex"] = Value::from(index + thread_offset);
+            }
+        }
+        if let Some(merged_threads) = merged["threads"].as_array_mut() {
+            merged_threads.extend(threads);
+        }
+        if let Some(merged_counters) = merged["counters"].as_array_mut() {
+            merged_counters.extend(counters);
+        }
+        merge_marker_schemas(merged, &profile);
+    }
+
+    let mut merged = merged.expect("merge_profiles needs at least one profile");
+    merged["libs"] = Value::Array(merged_libs);
+    Ok(merged)
+}
+
+fn take_array(value: &mut Value, key: &str) -> Vec<Value> {
+    match value.get_mut(key) {
+        Some(Value::Array(array)) => std::mem::take(array),
+        _ => Vec::new(),
+    }
+}
+
+fn remap_lib_column(table: &mut Value, column: &str, lib_index_map: &[usize]) {
+    let Some(Value::Array(column)) = table.get_mut(column) else {
+        return;
+    };
+    for lib in column {
+        if let Some(&new_index) = lib.as_u64().and_then(|i| lib_index_map.get(i as usize)) {
+            *lib = Value::from(new_index);
+        }
+    }
+}
+
+/// The fields of the meta which list thread indexes, and whether a missing
+/// list means "all threads".
+const THREAD_INDEX_META_FIELDS: &[(&str, bool)] = &[
+    ("initialVisibleThreads", true),
+    ("initialSelectedThreads", false),
+];
+
+/// Adds the thread indexes from the meta of `profile`, whose threads are
+/// appended to the merged threads at `thread_offset`, to the thread index
+/// lists in the merged meta. A list is only written if one of the profiles
+/// has it, and a profile without `initialVisibleThreads` has all of its
+/// threads visible.
+fn merge_thread_index_lists(
+    merged: &mut Value,
+    profile: &Value,
+    thread_offset: u64,
+    thread_count: u64,
+) {
+    for &(field, missing_means_all) in THREAD_INDEX_META_FIELDS {
+        let merged_list = merged["meta"][field].as_array();
+        let profile_list = profile["meta"][field].as_array();
+        if merged_list.is_none() && profile_list.is_none() {
+            continue;
+        }
+        let indexes = |list: Option<&Vec<Value>>, count: u64| -> Vec<u64> {
+            match list {
+                Some(list) => list.iter().filter_map(Value::as_u64).collect(),
+                None if missing_means_all => (0..count).collect(),
+                None => Vec::new(),
+            }
+        };
+        let mut merged_indexes = indexes(merged_list, thread_offset);
+        merged_indexes.extend(
+            indexes(profile_list, thread_count)
+                .into_iter()
+                .map(|index| index + thread_offset),
+        );
+        merged_indexes.sort_unstable();
+        merged_indexes.dedup();
+        merged["meta"][field] = json!(merged_indexes);
+    }
+}
+
+fn merge_marker_schemas(merged: &mut Value, profile: &Value) {
+    let Some(schemas) = profile["meta"]["markerSchema"].as_array() else {
+        return;
+    };
+    let Some(merged_schemas) = merged["meta"]["markerSchema"].as_array_mut() else {
+        return;
+    };
+    for schema in schemas {
+        if !merged_schemas
+            .iter()
+            .any(|merged_schema| merged_schema["name"] == schema["name"])
+        {
+            merged_schemas.push(schema.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn profile_with_threads(thread_names: &[&str], meta: Value) -> Value {
+        let mut profile = json!({
+            "meta": {
+                "startTime": 1000.0,
+                "categories": [],
+                "markerSchema": [],
+            },
+            "libs": [],
+            "threads": thread_names
+                .iter()
+                .map(|name| json!({ "name": name }))
+                .collect::<Vec<_>>(),
+            "counters": [],
+        });
+        for (key, value) in meta.as_object().unwrap() {
+            profile["meta"][key] = value.clone();
+        }
+        profile
+    }
+
+    #[test]
+    fn thread_index_lists_are_offset_and_merged() {
+        let first = profile_with_threads(
+            &["a0", "a1", "a2"],
+            json!({ "initialVisibleThreads": [0, 2], "initialSelectedThreads": [2] }),
+        );
+        let second = profile_with_threads(
+            &["b0", "b1"],
+            json!({ "initialVisibleThreads": [1], "initialSelectedThreads": [1] }),
+        );
+        let third = profile_with_threads(&["c0", "c1"], json!({}));
+        let merged = merge_profiles(vec![first, second, third]).unwrap();
+        assert_eq!(merged["threads"].as_array().unwrap().len(), 7);
+        assert_eq!(
+            merged["meta"]["initialVisibleThreads"],
+            json!([0, 2, 4, 5, 6])
+        );
+        assert_eq!(merged["meta"]["initialSelectedThreads"], json!([2, 4]));
+    }
+
+    #[test]
+    fn missing_thread_index_lists_stay_missing() {
+        let first = profile_with_threads(&["a0"], json!({}));
+        let second = profile_with_threads(&["b0", "b1"], json!({}));
+        let merged = merge_profiles(vec![first, second]).unwrap();
+        assert!(merged["meta"].get("initialVisibleThreads").is_none());
+        assert!(merged["meta"].get("initialSelectedThreads").is_none());
+
+        let first = profile_with_threads(&["a0", "a1"], json!({}));
+        let second = profile_with_threads(&["b0", "b1"], json!({ "initialVisibleThreads": [0] }));
+        let merged = merge_profiles(vec![first, second]).unwrap();
+        assert_eq!(merged["meta"]["initialVisibleThreads"], json!([0, 1, 2]));
+        assert!(merged["meta"].get("initialSelectedThreads").is_none());
+    }
+}
@@ -0,0 +1,440 @@
+use debugid::DebugId;
+use flate2::Crc;
+use serde_json::Value;
+use wholesym::FunctionInfo;
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use crate::profile_symbolication::read_profile;
+use crate::server::{symbol_manager_for_parsed_profile, SymbolDownloads, SymbolIdMatching};
+
+/// The kind of symbol server that symbols are uploaded to. Both accept a zip
+/// file with Breakpad symbol files; they differ in how the token is passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UploadTarget {
+    /// Mozilla's Tecken symbol server, which expects an "Auth-Token" header.
+    Tecken,
+    /// Sentry's debug file upload endpoint, which expects a bearer token.
+    Sentry,
+}
+
+pub struct SymbolUploadProps {
+    pub url: Option<String>,
+    pub token: Option<String>,
+    pub target: UploadTarget,
+    pub zip_output: Option<PathBuf>,
+    pub verbose: bool,
+    pub symbol_dirs: Vec<PathBuf>,
+    pub symbol_downloads: SymbolDownloads,
+}
+
+/// Creates Breakpad symbol files for all libraries in the profile which have
+/// symbols on this machine, and uploads them as a zip file. This way, the profile
+/// can be symbolicated by the symbol server when it's opened on another machine.
+#[tokio::main]
+pub async fn upload_symbols_for_profile(
+    profile_path: &Path,
+    props: &SymbolUploadProps,
+) -> Result<(), String> {
+    let profile = read_profile(profile_path)
+        .map_err(|err| format!("Could not read {profile_path:?}: {err}"))?;
+    let os = breakpad_os_name(&profile);
+    let symbol_manager = symbol_manager_for_parsed_profile(
+        &profile,
+        props.verbose,
+        &props.symbol_dirs,
+        SymbolIdMatching::default(),
+        &props.symbol_downloads,
+    );
+
+    let mut zip = ZipWriter::default();
+    let mut libs = profile_libs(&profile);
+    libs.sort_by(|a, b| (&a.debug_name, a.debug_id).cmp(&(&b.debug_name, b.debug_id)));
+    libs.dedup_by(|a, b| a.debug_name == b.debug_name && a.debug_id == b.debug_id);
+    for lib in &libs {
+        let symbol_map = match symbol_manager
+            .load_symbol_map(&lib.debug_name, lib.debug_id)
+            .await
+        {
+            Ok(symbol_map) => symbol_map,
+            Err(err) => {
+                eprintln!(
+                    "Skipping {} ({}): {err}",
+                    lib.debug_name,
+                    lib.debug_id.breakpad()
+                );
+                continue;
+            }
+        };
+        let arch = lib.arch.as_deref().unwrap_or(std::env::consts::ARCH);
+        let mut sym_file = format!(
+            "MODULE {os} {} {} {}\n",
+            breakpad_arch(arch),
+            lib.debug_id.breakpad(),
+            lib.debug_name
+        );
+        if let Some(code_id) = &lib.code_id {
+            let _ = writeln!(sym_file, "INFO CODE_ID {code_id}");
+        }
+        let function_count = write_functions(&mut sym_file, symbol_map.iter_functions(true));
+        let sym_file_name = match lib.debug_name.strip_suffix(".pdb") {
+            Some(stem) => format!("{stem}.sym"),
+            None => format!("{}.sym", lib.debug_name),
+        };
+        let path_in_zip = format!(
+            "{}/{}/{sym_file_name}",
+            lib.debug_name,
+            lib.debug_id.breakpad()
+        );
+        eprintln!("Adding {path_in_zip} ({function_count} functions)");
+        zip.add_file(&path_in_zip, sym_file.as_bytes())?;
+    }
+    if zip.file_count == 0 {
+        return Err("None of the libraries in the profile have symbols on this machine.".into());
+    }
+    let zip_data = zip.finish()?;
+
+    if let Some(zip_output) = &props.zip_output {
+        std::fs::write(zip_output, &zip_data)
+            .map_err(|err| format!("Could not write {zip_output:?}: {err}"))?;
+        eprintln!("Saved the symbol files to {zip_output:?}.");
+    }
+    let Some(url) = &props.url else {
+        return Ok(());
+    };
+    upload_zip(url, props.token.as_deref(), props.target, zip_data).await
+}
+
+async fn upload_zip(
+    url: &str,
+    token: Option<&str>,
+    target: UploadTarget,
+    zip_data: Vec<u8>,
+) -> Result<(), String> {
+    let boundary = format!("samply-{:016x}", rand::random::<u64>());
+    let mut body = format!(
+        "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"symbols.zip\"\r\nContent-Type: application/zip\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(&zip_data);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    let mut request = reqwest::Client::new().post(url).header(
+        "Content-Type",
+        format!("multipart/form-data; boundary={boundary}"),
+    );
+    if let Some(token) = token {
+        request = match target {
+            UploadTarget::Tecken => request.header("Auth-Token", token),
+            UploadTarget::Sentry => request.bearer_auth(token),
+        };
+    }
+    let response = request
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| format!("Could not upload the symbols to {url}: {err}"))?;
+    let status = response.status();
+    let response_text = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(format!(
+            "The symbol server responded with {status}: {response_text}"
+        ));
+    }
+    eprintln!("Uploaded the symbols to {url}.");
+    Ok(())
+}
+
+struct ProfileLib {
+    debug_name: String,
+    debug_id: DebugId,
+    code_id: Option<String>,
+    arch: Option<String>,
+}
+
+fn profile_libs(profile: &Value) -> Vec<ProfileLib> {
+    let libs = profile
+        .get("libs")
+        .and_then(Value::as_array)
+        .map_or(&[][..], Vec::as_slice);
+    libs.iter()
+        .filter_map(|lib| {
+            let debug_name = lib.get("debugName")?.as_str()?;
+            let breakpad_id = lib.get("breakpadId")?.as_str()?;
+            Some(ProfileLib {
+                debug_name: debug_name.to_string(),
+                debug_id: DebugId::from_breakpad(breakpad_id).ok()?,
+                code_id: lib.get("codeId").and_then(Value::as_str).map(String::from),
+                arch: lib.get("arch").and_then(Value::as_str).map(String::from),
+            })
+        })
+        .collect()
+}
+
+/// The OS name in the MODULE line of Breakpad symbol files.
+fn breakpad_os_name(profile: &Value) -> &'static str {
+    let os = profile
+        .pointer("/meta/oscpu")
+        .and_then(Value::as_str)
+        .unwrap_or("");
+    if os.contains("Windows") {
+        "windows"
+    } else if os.contains("macOS") || os.contains("Darwin") {
+        "mac"
+    } else {
+        "Linux"
+    }
+}
+
+/// The architecture name in the MODULE line of Breakpad symbol files, for an
+/// architecture name as used in profiles or by Rust.
+fn breakpad_arch(arch: &str) -> &str {
+    match arch {
+        "aarch64" | "arm64e" => "arm64",
+        "x86" | "i386" | "i686" => "x86",
+        "arm" | "armv7" => "arm",
+        other => other,
+    }
+}
+
+/// Appends the FILE, FUNC and line records for the functions to a Breakpad
+/// symbol file, and returns the number of functions. Functions without a known
+/// size are written as PUBLIC records.
+fn write_functions(sym_file: &mut String, functions: impl Iterator<Item = FunctionInfo>) -> usize {
+    let mut functions: Vec<FunctionInfo> = functions.collect();
+    functions.sort_by_key(|function| function.symbol.address);
+    functions.dedup_by_key(|function| function.symbol.address);
+
+    let mut file_indexes: HashMap<String, usize> = HashMap::new();
+    let mut files = String::new();
+    let mut records = String::new();
+    for function in &functions {
+        let symbol = &function.symbol;
+        let size = match symbol.size {
+            Some(size) if size > 0 => size,
+            _ => {
+                let _ = writeln!(records, "PUBLIC {:x} 0 {}", symbol.address, symbol.name);
+                continue;
+            }
+        };
+        let _ = writeln!(
+            records,
+            "FUNC {:x} {size:x} 0 {}",
+            symbol.address, symbol.name
+        );
+        for line in function.lines.iter().flatten() {
+            let (Some(file_path), Some(line_number)) = (&line.file_path, line.line_number) else {
+                continue;
+            };
+            let path = match file_path.mapped_path() {
+                Some(mapped_path) => mapped_path.to_special_path_str(),
+                None => file_path.raw_path().to_string(),
+            };
+            let next_index = file_indexes.len();
+            let file_index = *file_indexes.entry(path).or_insert_with_key(|path| {
+                let _ = writeln!(files, "FILE {next_index} {path}");
+                next_index
+            });
+            let _ = writeln!(
+                records,
+                "{:x} {:x} {line_number} {file_index}",
+                line.address, line.size
+            );
+        }
+    }
+    sym_file.push_str(&files);
+    sym_file.push_str(&records);
+    functions.len()
+}
+
+/// Writes a zip archive with uncompressed entries. This is all that's needed to
+/// package Breakpad symbol files for a symbol server.
+#[derive(Default)]
+struct ZipWriter {
+    data: Vec<u8>,
+    central_directory: Vec<u8>,
+    file_count: u16,
+}
+
+impl ZipWriter {
+    fn add_file(&mut self, name: &str, contents: &[u8]) -> Result<(), String> {
+        let (Ok(offset), Ok(size)) = (
+            u32::try_from(self.data.len()),
+            u32::try_from(contents.len()),
+        ) else {
+            return Err("The symbol files are too large for a zip file.".into());
+        };
+        let Ok(name_len) = u16::try_from(name.len()) else {
+            return Err(format!("The file name {name} is too long for a zip file."));
+        };
+        // Zip files without the zip64 extension can't have more entries.
+        let Some(file_count) = self.file_count.checked_add(1) else {
+            return Err("There are too many symbol files for a zip file.".into());
+        };
+        let mut crc = Crc::new();
+        crc.update(contents);
+        let crc = crc.sum();
+
+        // Local file header
+        self.data.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        self.data.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        self.data.extend_from_slice(&0u16.to_le_bytes()); // flags
+        self.data.extend_from_slice(&0u16.to_le_bytes()); // compression: stored
+        self.data.extend_from_slice(&[0; 4]); // modification time and date
+        self.data.extend_from_slice(&crc.to_le_bytes());
+        self.data.extend_from_slice(&size.to_le_bytes());
+        self.data.extend_from_slice(&size.to_le_bytes());
+        self.data.extend_from_slice(&name_len.to_le_bytes());
+        self.data.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.extend_from_slice(contents);
+
+        // Central directory entry
+        let entry = &mut self.central_directory;
+        entry.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        entry.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        entry.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        entry.extend_from_slice(&0u16.to_le_bytes()); // flags
+        entry.extend_from_slice(&0u16.to_le_bytes()); // compression: stored
+        entry.extend_from_slice(&[0; 4]); // modification time and date
+        entry.extend_from_slice(&crc.to_le_bytes());
+        entry.extend_from_slice(&size.to_le_bytes());
+        entry.extend_from_slice(&size.to_le_bytes());
+        entry.extend_from_slice(&name_len.to_le_bytes());
+        entry.extend_from_slice(&[0; 12]); // extra, comment, disk, attributes
+        entry.extend_from_slice(&offset.to_le_bytes());
+        entry.extend_from_slice(name.as_bytes());
+
+        self.file_count = file_count;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, String> {
+        let (Ok(directory_offset), Ok(directory_size)) = (
+            u32::try_from(self.data.len()),
+            u32::try_from(self.central_directory.len()),
+        ) else {
+            return Err("The symbol files are too large for a zip file.".into());
+        };
+        self.data.extend_from_slice(&self.central_directory);
+        // End of central directory record
+        self.data.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        self.data.extend_from_slice(&[0; 4]); // disk numbers
+        self.data.extend_from_slice(&self.file_count.to_le_bytes());
+        self.data.extend_from_slice(&self.file_count.to_le_bytes());
+        self.data.extend_from_slice(&directory_size.to_le_bytes());
+        self.data.extend_from_slice(&directory_offset.to_le_bytes());
+        self.data.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        Ok(self.data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use wholesym::{LineRange, SourceFilePath, SymbolInfo};
+
+    use super::*;
+
+    fn function(
+        address: u32,
+        size: Option<u32>,
+        name: &str,
+        lines: &[(u32, u32, &str, u32)],
+    ) -> FunctionInfo {
+        FunctionInfo {
+            symbol: SymbolInfo {
+                address,
+                size,
+                name: name.to_string(),
+            },
+            lines: Some(
+                lines
+                    .iter()
+                    .map(|(address, size, file, line)| LineRange {
+                        address: *address,
+                        size: *size,
+                        file_path: Some(SourceFilePath::new(file.to_string(), None)),
+                        line_number: Some(*line),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn sym_file_records() {
+        let functions = vec![
+            function(
+                0x2000,
+                Some(0x10),
+                "second",
+                &[(0x2000, 0x10, "/src/b.c", 7)],
+            ),
+            function(
+                0x1000,
+                Some(0x20),
+                "first",
+                &[(0x1000, 0x8, "/src/a.c", 1), (0x1008, 0x18, "/src/b.c", 2)],
+            ),
+            function(0x3000, None, "no_size", &[]),
+        ];
+        let mut sym_file = String::new();
+        let count = write_functions(&mut sym_file, functions.into_iter());
+        assert_eq!(count, 3);
+        assert_eq!(
+            sym_file,
+            "FILE 0 /src/a.c\n\
+             FILE 1 /src/b.c\n\
+             FUNC 1000 20 0 first\n\
+             1000 8 1 0\n\
+             1008 18 2 1\n\
+             FUNC 2000 10 0 second\n\
+             2000 10 7 1\n\
+             PUBLIC 3000 0 no_size\n"
+        );
+    }
+
+    #[test]
+    fn breakpad_arch_names() {
+        assert_eq!(breakpad_arch("aarch64"), "arm64");
+        assert_eq!(breakpad_arch("arm64e"), "arm64");
+        assert_eq!(breakpad_arch("x86_64"), "x86_64");
+        assert_eq!(breakpad_arch("i686"), "x86");
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        let mut zip = ZipWriter::default();
+        zip.add_file("a.so/0123/a.so.sym", b"MODULE Linux x86_64 0123 a.so\n")
+            .unwrap();
+        zip.add_file("b.pdb/4567/b.sym", b"").unwrap();
+        let data = zip.finish().unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data)).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut contents = String::new();
+        archive
+            .by_name("a.so/0123/a.so.sym")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "MODULE Linux x86_64 0123 a.so\n");
+        assert_eq!(archive.by_name("b.pdb/4567/b.sym").unwrap().size(), 0);
+    }
+
+    #[test]
+    fn zip_entry_limit() {
+        let mut zip = ZipWriter::default();
+        for i in 0..u16::MAX {
+            zip.add_file(&format!("{i}"), b"").unwrap();
+        }
+        assert!(zip.add_file("one too many", b"").is_err());
+        let data = zip.finish().unwrap();
+        let archive = zip::ZipArchive::new(std::io::Cursor::new(data)).unwrap();
+        assert_eq!(archive.len(), usize::from(u16::MAX));
+    }
+}
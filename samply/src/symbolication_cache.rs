@@ -0,0 +1,242 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// The total size of the responses which are kept in memory.
+const MEMORY_CACHE_MAX_BYTES: usize = 256 * 1024 * 1024;
+
+/// Caches the responses to symbolication API requests, so that reloading a
+/// profile or clicking around in the profiler doesn't redo the symbol lookups.
+///
+/// Entries are keyed by the SHA-256 hash of the API path and the request body,
+/// which contains the debug IDs of the libraries and the addresses to look up.
+/// The hash is stable across samply versions, so that entries in the cache
+/// directory stay valid. Responses are kept in memory with LRU eviction, and
+/// are also persisted to the cache directory if one is given.
+///
+/// Only responses which found symbols for all requested libraries are cached.
+/// This way, a lookup which failed because symbols weren't available yet is
+/// retried.
+pub struct SymbolicationCache {
+    memory: Mutex<MemoryCache>,
+    dir: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct MemoryCache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Least recently used first.
+    order: VecDeque<CacheKey>,
+    total_bytes: usize,
+}
+
+#[derive(Clone)]
+struct CacheEntry {
+    path: String,
+    request: String,
+    response: Arc<String>,
+}
+
+impl SymbolicationCache {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        if let Some(dir) = &dir {
+            if let Err(err) = std::fs::create_dir_all(dir) {
+                eprintln!("Could not create the symbolication cache directory {dir:?}: {err}");
+            }
+        }
+        Self {
+            memory: Mutex::new(MemoryCache::default()),
+            dir,
+        }
+    }
+
+    /// Whether responses for this API path can be cached. Source file and
+    /// assembly responses aren't cached because the files on disk can change.
+    pub fn is_cacheable(path: &str) -> bool {
+        path.starts_with("/symbolicate/")
+    }
+
+    pub fn get(&self, path: &str, request: &str) -> Option<Arc<String>> {
+        let key = cache_key(path, request);
+        if let Some(response) = self.memory.lock().unwrap().get(key, path, request) {
+            return Some(response);
+        }
+        let entry = self.read_from_disk(&key, path, request)?;
+        let response = entry.response.clone();
+        self.memory.lock().unwrap().insert(key, entry);
+        Some(response)
+    }
+
+    pub fn insert(&self, path: &str, request: &str, response: &str) {
+        if !all_modules_found(response) {
+            return;
+        }
+        let key = cache_key(path, request);
+        let entry = CacheEntry {
+            path: path.to_string(),
+            request: request.to_string(),
+            response: Arc::new(response.to_string()),
+        };
+        if self.dir.is_some() {
+            self.write_to_disk(&key, &entry);
+        }
+        self.memory.lock().unwrap().insert(key, entry);
+    }
+
+    fn file_path(&self, key: &CacheKey) -> Option<PathBuf> {
+        let name: String = key.iter().map(|byte| format!("{byte:02x}")).collect();
+        Some(self.dir.as_ref()?.join(format!("{name}.json")))
+    }
+
+    fn read_from_disk(&self, key: &CacheKey, path: &str, request: &str) -> Option<CacheEntry> {
+        let contents = std::fs::read(self.file_path(key)?).ok()?;
+        let value: Value = serde_json::from_slice(&contents).ok()?;
+        // Guard against hash collisions.
+        if value.get("path")?.as_str()? != path || value.get("request")?.as_str()? != request {
+            return None;
+        }
+        Some(CacheEntry {
+            path: path.to_string(),
+            request: request.to_string(),
+            response: Arc::new(value.get("response")?.as_str()?.to_string()),
+        })
+    }
+
+    fn write_to_disk(&self, key: &CacheKey, entry: &CacheEntry) {
+        let Some(file_path) = self.file_path(key) else {
+            return;
+        };
+        let contents = json!({
+            "path": entry.path,
+            "request": entry.request,
+            "response": *entry.response,
+        });
+        // Write to a temporary file first, so that concurrent readers never
+        // see a partially written entry.
+        let temp_path = file_path.with_extension("json.tmp");
+        let result = std::fs::write(&temp_path, contents.to_string())
+            .and_then(|()| std::fs::rename(&temp_path, &file_path));
+        if let Err(err) = result {
+            eprintln!("Could not write symbolication cache entry {file_path:?}: {err}");
+            let _ = std::fs::remove_file(&temp_path);
+        }
+    }
+}
+
+impl MemoryCache {
+    fn get(&mut self, key: CacheKey, path: &str, request: &str) -> Option<Arc<String>> {
+        let entry = self.entries.get(&key)?;
+        if entry.path != path || entry.request != request {
+            return None;
+        }
+        let response = entry.response.clone();
+        self.touch(key);
+        Some(response)
+    }
+
+    fn insert(&mut self, key: CacheKey, entry: CacheEntry) {
+        let size = entry_size(&entry);
+        if let Some(old_entry) = self.entries.insert(key, entry) {
+            self.total_bytes -= entry_size(&old_entry);
+            self.touch(key);
+        } else {
+            self.order.push_back(key);
+        }
+        self.total_bytes += size;
+        while self.total_bytes > MEMORY_CACHE_MAX_BYTES && self.order.len() > 1 {
+            let Some(evicted_key) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&evicted_key) {
+                self.total_bytes -= entry_size(&evicted);
+            }
+        }
+    }
+
+    fn touch(&mut self, key: CacheKey) {
+        if let Some(pos) = self.order.iter().position(|k| *k == key) {
+            self.order.remove(pos);
+        }
+        self.order.push_back(key);
+    }
+}
+
+fn entry_size(entry: &CacheEntry) -> usize {
+    entry.path.len() + entry.request.len() + entry.response.len()
+}
+
+type CacheKey = [u8; 32];
+
+fn cache_key(path: &str, request: &str) -> CacheKey {
+    let mut hasher = Sha256::new();
+    // Include the length so that the boundary between the path and the
+    // request can't be moved.
+    hasher.update((path.len() as u64).to_le_bytes());
+    hasher.update(path.as_bytes());
+    hasher.update(request.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Checks the `found_modules` of all results in a `/symbolicate/v5` response.
+fn all_modules_found(response: &str) -> bool {
+    let Ok(response) = serde_json::from_str::<Value>(response) else {
+        return false;
+    };
+    let Some(results) = response.get("results").and_then(Value::as_array) else {
+        return false;
+    };
+    results.iter().all(|result| {
+        result
+            .get("found_modules")
+            .and_then(Value::as_object)
+            .map_or(false, |found_modules| {
+                found_modules
+                    .values()
+                    .all(|found| found.as_bool() == Some(true))
+            })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PATH: &str = "/symbolicate/v5";
+    const REQUEST: &str = r#"{"memoryMap":[["libxul.so","ABCD0"]],"stacks":[[[0,4096]]]}"#;
+    const FOUND: &str = r#"{"results":[{"stacks":[],"found_modules":{"libxul.so/ABCD0":true}}]}"#;
+    const NOT_FOUND: &str =
+        r#"{"results":[{"stacks":[],"found_modules":{"libxul.so/ABCD0":false}}]}"#;
+
+    #[test]
+    fn only_successful_responses_are_cached() {
+        let cache = SymbolicationCache::new(None);
+        cache.insert(PATH, REQUEST, NOT_FOUND);
+        assert!(cache.get(PATH, REQUEST).is_none());
+        cache.insert(PATH, REQUEST, FOUND);
+        assert_eq!(cache.get(PATH, REQUEST).unwrap().as_str(), FOUND);
+        assert!(cache.get("/symbolicate/v6", REQUEST).is_none());
+    }
+
+    #[test]
+    fn responses_are_persisted_under_a_stable_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = SymbolicationCache::new(Some(dir.path().to_owned()));
+        cache.insert(PATH, REQUEST, FOUND);
+        cache.insert(PATH, "{}", NOT_FOUND);
+        let file_names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(file_names.len(), 1);
+        assert_eq!(file_names[0].len(), 64 + ".json".len());
+
+        // A new cache, as in a later session, finds the entry on disk.
+        let cache = SymbolicationCache::new(Some(dir.path().to_owned()));
+        assert_eq!(cache.get(PATH, REQUEST).unwrap().as_str(), FOUND);
+        assert_eq!(cache_key(PATH, REQUEST), cache_key(PATH, REQUEST));
+        assert_ne!(cache_key(PATH, REQUEST), cache_key(REQUEST, PATH));
+    }
+}
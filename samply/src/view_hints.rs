@@ -0,0 +1,446 @@
+//! Hints for the initial state of the profiler UI, which are stored in the
+//! profile, so that a profile opens with the threads, panel and range that a
+//! team usually looks at.
+//!
+//! The thread hints go into the `initialVisibleThreads` and
+//! `initialSelectedThreads` fields of the profile's meta, which the profiler
+//! reads itself. The rest is URL state in the profiler, so it's kept in
+//! `meta.samplyViewHints`, and the server puts it into the profiler URL when
+//! it opens the profile. Marker annotations change the labels and the
+//! searchable fields in the profile's marker schemas.
+
+use regex::Regex;
+use serde::de::{Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use crate::profile_json_preparse::open_profile_json;
+use crate::profile_symbolication::read_profile;
+
+/// The contents of a `--view-hints` file, for example:
+///
+/// ```json
+/// {
+///   "hiddenThreads": ["^kworker/", "^rcu_"],
+///   "selectedThreads": ["^Renderer$"],
+///   "view": "marker-chart",
+///   "markerSearch": "DOMEvent",
+///   "range": { "start": 1200, "end": 1500 },
+///   "markerAnnotations": [
+///     { "marker": "DOMEvent", "chartLabel": "{marker.data.eventType}", "searchable": ["eventType"] }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ViewHints {
+    /// Hide the threads whose name or process name matches one of these
+    /// regular expressions.
+    #[serde(default)]
+    pub hidden_threads: Vec<String>,
+    /// Select the threads whose name or process name matches one of these
+    /// regular expressions.
+    #[serde(default)]
+    pub selected_threads: Vec<String>,
+    /// The fields of [`UrlViewState`].
+    #[serde(default)]
+    pub view: Option<String>,
+    #[serde(default)]
+    pub search: Option<String>,
+    #[serde(default)]
+    pub marker_search: Option<String>,
+    #[serde(default)]
+    pub invert_call_stack: bool,
+    #[serde(default)]
+    pub range: Option<ViewRange>,
+    /// Changes to the marker schemas of the profile.
+    #[serde(default)]
+    pub marker_annotations: Vec<MarkerAnnotation>,
+}
+
+/// Changes to how the markers of one marker schema are shown. The labels are
+/// templates like "{marker.data.eventType}", as in the marker schema.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MarkerAnnotation {
+    /// The name of the marker schema.
+    pub marker: String,
+    #[serde(default)]
+    pub chart_label: Option<String>,
+    #[serde(default)]
+    pub tooltip_label: Option<String>,
+    #[serde(default)]
+    pub table_label: Option<String>,
+    /// The keys of the fields which the marker search should match.
+    #[serde(default)]
+    pub searchable: Vec<String>,
+}
+
+/// The part of the hints which ends up in the profiler URL.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UrlViewState {
+    /// The panel to show, e.g. "calltree", "flame-graph", "stack-chart",
+    /// "marker-chart" or "marker-table".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub view: Option<String>,
+    /// The search string of the call tree.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub search: Option<String>,
+    /// The search string of the marker chart and marker table.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub marker_search: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub invert_call_stack: bool,
+    /// The range to zoom into.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<ViewRange>,
+}
+
+/// A range in milliseconds since the start of the profile.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct ViewRange {
+    pub start: f64,
+    pub end: f64,
+}
+
+const META_KEY: &str = "samplyViewHints";
+
+/// The panels of the profiler, as they appear in its URLs.
+const VIEWS: &[&str] = &[
+    "calltree",
+    "flame-graph",
+    "stack-chart",
+    "marker-chart",
+    "marker-table",
+    "network-chart",
+    "js-tracer",
+];
+
+impl ViewHints {
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|err| format!("Could not open {path:?}: {err}"))?;
+        let hints: ViewHints = serde_json::from_reader(BufReader::new(file))
+            .map_err(|err| format!("Could not parse {path:?}: {err}"))?;
+        if let Some(view) = &hints.view {
+            if !VIEWS.contains(&view.as_str()) {
+                return Err(format!(
+                    "Unknown view \"{view}\" in {path:?}, expected one of {}",
+                    VIEWS.join(", ")
+                ));
+            }
+        }
+        if let Some(range) = hints.range {
+            if !(range.start >= 0.0 && range.end > range.start) {
+                return Err(format!(
+                    "Invalid range in {path:?}, the end must come after the start"
+                ));
+            }
+        }
+        Ok(hints)
+    }
+
+    /// Applies the hints to the profile at `input` and writes the result to `output`.
+    pub fn apply_to_profile_file(&self, input: &Path, output: &Path) -> Result<(), String> {
+        let mut profile =
+            read_profile(input).map_err(|err| format!("Could not read {input:?}: {err}"))?;
+        self.apply_to_profile(&mut profile)?;
+        let file =
+            File::create(output).map_err(|err| format!("Could not create {output:?}: {err}"))?;
+        serde_json::to_writer(BufWriter::new(file), &profile)
+            .map_err(|err| format!("Could not write {output:?}: {err}"))
+    }
+
+    pub fn apply_to_profile(&self, profile: &mut Value) -> Result<(), String> {
+        let hidden = compile_patterns(&self.hidden_threads)?;
+        let selected = compile_patterns(&self.selected_threads)?;
+        let threads = profile
+            .get("threads")
+            .and_then(Value::as_array)
+            .ok_or("The profile has no threads")?;
+        let matches = |patterns: &[Regex], thread: &Value| {
+            ["name", "processName"].iter().any(|key| {
+                let name = thread.get(key).and_then(Value::as_str).unwrap_or("");
+                patterns.iter().any(|pattern| pattern.is_match(name))
+            })
+        };
+        let selected_threads: Vec<usize> = (0..threads.len())
+            .filter(|&index| matches(&selected, &threads[index]))
+            .collect();
+        let is_hidden: Vec<bool> = threads
+            .iter()
+            .map(|thread| matches(&hidden, thread))
+            .collect();
+        let thread_count = threads.len();
+        let meta = profile
+            .get_mut("meta")
+            .and_then(Value::as_object_mut)
+            .ok_or("The profile has no meta")?;
+        let visible_threads: Vec<usize> =
+            match meta.get("initialVisibleThreads").and_then(Value::as_array) {
+                Some(visible) => visible
+                    .iter()
+                    .filter_map(|v| v.as_u64())
+                    .map(|v| v as usize)
+                    .collect(),
+                None => (0..thread_count).collect(),
+            };
+        let visible_threads: Vec<usize> = visible_threads
+            .into_iter()
+            .filter(|&index| {
+                selected_threads.contains(&index) || is_hidden.get(index) == Some(&false)
+            })
+            .collect();
+        if visible_threads.is_empty() {
+            return Err("The hidden threads would hide all threads".to_string());
+        }
+        if !hidden.is_empty() {
+            meta.insert("initialVisibleThreads".to_string(), json!(visible_threads));
+        }
+        if !selected_threads.is_empty() {
+            meta.insert(
+                "initialSelectedThreads".to_string(),
+                json!(selected_threads),
+            );
+        }
+        for annotation in &self.marker_annotations {
+            annotate_marker_schema(meta, annotation)?;
+        }
+        let url_state = self.url_state();
+        if url_state != UrlViewState::default() {
+            meta.insert(META_KEY.to_string(), json!(url_state));
+        }
+        Ok(())
+    }
+
+    /// The part of the hints which ends up in the profiler URL.
+    pub fn url_state(&self) -> UrlViewState {
+        UrlViewState {
+            view: self.view.clone(),
+            search: self.search.clone(),
+            marker_search: self.marker_search.clone(),
+            invert_call_stack: self.invert_call_stack,
+            range: self.range,
+        }
+    }
+}
+
+fn annotate_marker_schema(
+    meta: &mut Map<String, Value>,
+    annotation: &MarkerAnnotation,
+) -> Result<(), String> {
+    let marker = &annotation.marker;
+    let schema = meta
+        .get_mut("markerSchema")
+        .and_then(Value::as_array_mut)
+        .and_then(|schemas| {
+            schemas
+                .iter_mut()
+                .find(|schema| schema.get("name").and_then(Value::as_str) == Some(marker))
+        })
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| format!("The profile has no markers of type \"{marker}\""))?;
+    for (key, label) in [
+        ("chartLabel", &annotation.chart_label),
+        ("tooltipLabel", &annotation.tooltip_label),
+        ("tableLabel", &annotation.table_label),
+    ] {
+        if let Some(label) = label {
+            schema.insert(key.to_string(), json!(label));
+        }
+    }
+    for field_key in &annotation.searchable {
+        let field = schema
+            .get_mut("data")
+            .and_then(Value::as_array_mut)
+            .and_then(|fields| {
+                fields.iter_mut().find(|field| {
+                    field.get("key").and_then(Value::as_str) == Some(field_key)
+                        && field.get("format").is_some()
+                })
+            })
+            .and_then(Value::as_object_mut)
+            .ok_or_else(|| format!("The \"{marker}\" markers have no field \"{field_key}\""))?;
+        field.insert("searchable".to_string(), json!(true));
+    }
+    Ok(())
+}
+
+fn compile_patterns(patterns: &[String]) -> Result<Vec<Regex>, String> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern)
+                .map_err(|err| format!("Invalid thread pattern \"{pattern}\": {err}"))
+        })
+        .collect()
+}
+
+impl UrlViewState {
+    /// Reads the URL hints from the meta of the profile at `path`, if it has any.
+    /// Only the file up to the end of the meta is parsed, so this is cheap for
+    /// samply's profiles, which have the threads after the meta.
+    pub fn from_profile_file(path: &Path) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct Meta {
+            #[serde(rename = "samplyViewHints")]
+            view_hints: Option<UrlViewState>,
+        }
+
+        struct MetaVisitor<'a>(&'a mut Option<Meta>);
+
+        impl<'de, 'a> Visitor<'de> for MetaVisitor<'a> {
+            type Value = ();
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a profile object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+                while let Some(key) = map.next_key::<String>()? {
+                    if key == "meta" {
+                        *self.0 = Some(map.next_value()?);
+                        // Stop here instead of parsing the rest of the profile.
+                        return Err(serde::de::Error::custom("stopped after the meta"));
+                    }
+                    map.next_value::<IgnoredAny>()?;
+                }
+                Ok(())
+            }
+        }
+
+        let mut meta = None;
+        let mut deserializer = serde_json::Deserializer::from_reader(open_profile_json(path).ok()?);
+        let _ = (&mut deserializer).deserialize_map(MetaVisitor(&mut meta));
+        meta?.view_hints
+    }
+
+    /// The path after the profile URL, e.g. "marker-chart/", or "" for the
+    /// default panel.
+    pub fn url_path(&self) -> String {
+        match &self.view {
+            Some(view) => format!("{view}/"),
+            None => String::new(),
+        }
+    }
+
+    /// The query parameters for the profiler URL, each starting with "&".
+    pub fn url_query(&self, encode: impl Fn(&str) -> String) -> String {
+        let mut query = String::new();
+        if let Some(search) = &self.search {
+            query.push_str(&format!("&search={}", encode(search)));
+        }
+        if let Some(marker_search) = &self.marker_search {
+            query.push_str(&format!("&markerSearch={}", encode(marker_search)));
+        }
+        if self.invert_call_stack {
+            query.push_str("&invertCallstack");
+        }
+        if let Some(range) = self.range {
+            // The compact range format of URL version 5 and up: the start and
+            // the duration, in milliseconds.
+            let start = range.start.floor() as u64;
+            let duration = (range.end.ceil() as u64).saturating_sub(start).max(1);
+            query.push_str(&format!("&v=5&range={start}m{duration}"));
+        }
+        query
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{UrlViewState, ViewHints, ViewRange};
+    use serde_json::json;
+
+    use std::io::Write;
+
+    #[test]
+    fn apply_view_hints() {
+        let hints: ViewHints = serde_json::from_value(json!({
+            "hiddenThreads": ["^kworker/"],
+            "selectedThreads": ["^Renderer$"],
+            "view": "marker-chart",
+            "range": { "start": 1200.5, "end": 1500 },
+        }))
+        .unwrap();
+        let mut profile = json!({
+            "meta": {},
+            "threads": [
+                { "name": "app", "processName": "app" },
+                { "name": "kworker/0:1", "processName": "kworker/0:1" },
+                { "name": "Renderer", "processName": "app" },
+            ],
+        });
+        hints.apply_to_profile(&mut profile).unwrap();
+        assert_eq!(profile["meta"]["initialVisibleThreads"], json!([0, 2]));
+        assert_eq!(profile["meta"]["initialSelectedThreads"], json!([2]));
+        assert_eq!(
+            profile["meta"]["samplyViewHints"],
+            json!({ "view": "marker-chart", "range": { "start": 1200.5, "end": 1500.0 } })
+        );
+
+        let url_state: UrlViewState =
+            serde_json::from_value(profile["meta"]["samplyViewHints"].clone()).unwrap();
+        assert_eq!(
+            url_state.range,
+            Some(ViewRange {
+                start: 1200.5,
+                end: 1500.0
+            })
+        );
+        assert_eq!(url_state.url_path(), "marker-chart/");
+        assert_eq!(
+            url_state.url_query(|s| s.to_string()),
+            "&v=5&range=1200m300"
+        );
+    }
+
+    #[test]
+    fn annotate_markers() {
+        let hints: ViewHints = serde_json::from_value(json!({
+            "markerAnnotations": [{
+                "marker": "DOMEvent",
+                "chartLabel": "{marker.data.eventType}",
+                "searchable": ["eventType"],
+            }],
+        }))
+        .unwrap();
+        let mut profile = json!({
+            "meta": {
+                "markerSchema": [{
+                    "name": "DOMEvent",
+                    "display": ["marker-chart"],
+                    "data": [{ "key": "eventType", "format": "string", "searchable": false }],
+                }],
+            },
+            "threads": [{ "name": "app", "processName": "app" }],
+        });
+        hints.apply_to_profile(&mut profile).unwrap();
+        let schema = &profile["meta"]["markerSchema"][0];
+        assert_eq!(schema["chartLabel"], "{marker.data.eventType}");
+        assert_eq!(schema["data"][0]["searchable"], true);
+        assert!(profile["meta"].get("samplyViewHints").is_none());
+
+        let unknown_marker: ViewHints = serde_json::from_value(json!({
+            "markerAnnotations": [{ "marker": "Paint", "tableLabel": "paint" }],
+        }))
+        .unwrap();
+        assert!(unknown_marker.apply_to_profile(&mut profile).is_err());
+    }
+
+    #[test]
+    fn read_url_state_from_meta_only() {
+        // Everything after the meta is left unparsed, so the broken threads
+        // don't matter.
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(br#"{"meta": {"samplyViewHints": {"view": "flame-graph"}}, "threads": [}"#)
+            .unwrap();
+        let url_state = UrlViewState::from_profile_file(file.path()).unwrap();
+        assert_eq!(url_state.view.as_deref(), Some("flame-graph"));
+    }
+}
@@ -0,0 +1,27 @@
+/// This needs perf events, which CI machines and containers often don't allow.
+/// Run it with `cargo test -- --ignored`.
+#[cfg(target_os = "linux")]
+#[test]
+#[ignore]
+fn record_launched_command_with_duration() {
+    use std::process::Command;
+
+    let dir = tempfile::tempdir().unwrap();
+    let output_file = dir.path().join("profile.json");
+    // The recording stops after one second, while the first iteration is still
+    // running. The remaining iterations must not be started.
+    let output = Command::new(env!("CARGO_BIN_EXE_samply"))
+        .args(["record", "--save-only", "--duration", "1"])
+        .args(["--iteration-count", "3", "--output"])
+        .arg(&output_file)
+        .args(["--", "sleep", "2"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "samply failed: {stderr}");
+    assert!(!stderr.contains("panicked"), "samply panicked: {stderr}");
+    assert!(!stderr.contains("Running iteration 3"), "{stderr}");
+    let profile: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&output_file).unwrap()).unwrap();
+    assert!(profile["threads"].is_array());
+}
@@ -206,4 +206,8 @@ impl FileLocation for FileLocationType {
     fn location_for_breakpad_symindex(&self) -> Option<Self> {
         Some(Self(self.0.with_extension("symindex")))
     }
+
+    fn location_for_def_file(&self) -> Option<Self> {
+        Some(Self(self.0.with_extension("def")))
+    }
 }
@@ -1,5 +1,6 @@
 use std::{collections::HashMap, path::PathBuf};
 
+use samply_symbols::InlineFrameLimit;
 use symsrv::{parse_nt_symbol_path, NtSymbolPathEntry};
 
 /// The configuration of a [`SymbolManager`](crate::SymbolManager).
@@ -14,13 +15,94 @@ pub struct SymbolManagerConfig {
     pub(crate) breakpad_directories_readonly: Vec<PathBuf>,
     pub(crate) breakpad_servers: Vec<(String, PathBuf)>,
     pub(crate) breakpad_symindex_cache_dir: Option<PathBuf>,
+    pub(crate) dyld_cache_symbols_dir: Option<PathBuf>,
     pub(crate) windows_servers: Vec<(String, PathBuf)>,
     pub(crate) use_debuginfod: bool,
     pub(crate) use_spotlight: bool,
     pub(crate) debuginfod_cache_dir_if_not_installed: Option<PathBuf>,
     pub(crate) debuginfod_servers: Vec<(String, PathBuf)>,
+    pub(crate) ignore_debug_id_mismatch: bool,
+    pub(crate) ignore_pdb_age: bool,
+    pub(crate) inline_frame_limit: InlineFrameLimit,
+    pub(crate) download_policy: DownloadPolicy,
 }
 
+/// Which URLs files may be downloaded from.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DownloadPolicy {
+    offline: bool,
+    /// Lowercase domain names. Empty if all domains are allowed.
+    allowed_domains: Vec<String>,
+}
+
+impl DownloadPolicy {
+    /// Whether a download from `url` is allowed. If it isn't, returns the reason.
+    pub(crate) fn check(&self, url: &str) -> Result<(), String> {
+        if self.offline {
+            return Err(format!("Not downloading {url} in offline mode"));
+        }
+        if self.allowed_domains.is_empty() {
+            return Ok(());
+        }
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+            .unwrap_or_default();
+        let is_allowed = self.allowed_domains.iter().any(|domain| {
+            host == *domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .map_or(false, |subdomain| subdomain.ends_with('.'))
+        });
+        if is_allowed {
+            Ok(())
+        } else {
+            Err(format!(
+                "Not downloading {url}, because its domain is not in the list of allowed download domains"
+            ))
+        }
+    }
+
+    /// Like [`check`](Self::check), for the downloads of symsrv. symsrv uses
+    /// its own HTTP client, whose redirects can't be checked, so a server could
+    /// send the download on to any domain. That's why nothing is downloaded
+    /// with symsrv while downloads are restricted to some domains.
+    pub(crate) fn check_symsrv(&self, url: &str) -> Result<(), String> {
+        self.check(url)?;
+        if !self.allowed_domains.is_empty() {
+            return Err(format!(
+                "Not downloading from {url}, because the redirects of Windows symbol servers can't be checked against the list of allowed download domains"
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Creates a client for downloads under this policy. Every redirect is
+    /// checked against the policy too, so that an allowed server can't send
+    /// the download on to a domain which isn't allowed.
+    pub(crate) fn http_client(&self) -> reqwest::Result<reqwest::Client> {
+        let policy = self.clone();
+        let redirect_policy = reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() > MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            match policy.check(attempt.url().as_str()) {
+                Ok(()) => attempt.follow(),
+                Err(reason) => attempt.error(reason),
+            }
+        });
+        reqwest::Client::builder().redirect(redirect_policy).build()
+    }
+}
+
+/// The number of redirects which are followed, as many as reqwest follows by
+/// default.
+const MAX_REDIRECTS: usize = 10;
+
 impl SymbolManagerConfig {
     /// Create a new `SymbolManagerConfig` in its default state.
     pub fn new() -> Self {
@@ -65,6 +147,32 @@ impl SymbolManagerConfig {
     }
 
     pub(crate) fn effective_nt_symbol_path(&self) -> Option<Vec<NtSymbolPathEntry>> {
+        let mut path = self.configured_nt_symbol_path()?;
+        // Keep the caches of the servers whose downloads aren't allowed, so
+        // that the files which were downloaded earlier can still be used.
+        for entry in &mut path {
+            if let NtSymbolPathEntry::Chain { urls, .. } = entry {
+                urls.retain(|url| match self.download_policy.check_symsrv(url) {
+                    Ok(()) => true,
+                    Err(reason) => {
+                        if self.verbose {
+                            eprintln!("{reason}.");
+                        }
+                        false
+                    }
+                });
+            }
+        }
+        if self.download_policy.is_offline() {
+            // Entries like \\server\share are on the network too.
+            path.retain(|entry| {
+                !matches!(entry, NtSymbolPathEntry::LocalOrShare(dir) if dir.to_string_lossy().starts_with(r"\\"))
+            });
+        }
+        Some(path)
+    }
+
+    fn configured_nt_symbol_path(&self) -> Option<Vec<NtSymbolPathEntry>> {
         let respected_env_value = if self.respect_nt_symbol_path {
             std::env::var("_NT_SYMBOL_PATH").ok()
         } else {
@@ -119,6 +227,18 @@ impl SymbolManagerConfig {
         self
     }
 
+    /// Set a directory to cache the symbols of macOS system libraries in. Loading
+    /// symbols from the dyld shared cache is slow, so once they have been loaded,
+    /// they are written to this directory as Breakpad symbol files, in a
+    /// subdirectory for the UUID of the dyld shared cache. Later lookups for the
+    /// same library on the same OS version use these files instead.
+    ///
+    /// This directory is used for both reading and writing.
+    pub fn dyld_cache_symbols_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dyld_cache_symbols_dir = Some(dir.into());
+        self
+    }
+
     /// Add a server to search for Windows symbol files (pdb / exe / dll), along with a local cache directory.
     ///
     /// This method can be called multiple times; the servers and caches will be tried in the order of those calls.
@@ -173,4 +293,152 @@ impl SymbolManagerConfig {
         self.use_spotlight = use_spotlight;
         self
     }
+
+    /// Whether to use symbol files and binaries whose debug ID doesn't match the
+    /// requested one, if no matching file is found. This makes it possible to get
+    /// symbols from a binary which was rebuilt after profiling. A warning is
+    /// printed whenever a mismatched file is used.
+    pub fn ignore_debug_id_mismatch(mut self, ignore: bool) -> Self {
+        self.ignore_debug_id_mismatch = ignore;
+        self
+    }
+
+    /// Whether to accept PDB files whose GUID matches but whose age doesn't.
+    /// This allows using PDBs which were rewritten by the linker after the binary
+    /// was built, for example by an incremental link which produced identical code.
+    pub fn ignore_pdb_age(mut self, ignore: bool) -> Self {
+        self.ignore_pdb_age = ignore;
+        self
+    }
+
+    /// Limits how many inline frames are returned per address. The default is
+    /// [`InlineFrameLimit::Unlimited`]. Use [`InlineFrameLimit::SkipInlineResolution`]
+    /// to get only the symbols, without reading any debug info, which is much
+    /// faster for huge profiles.
+    pub fn inline_frame_limit(mut self, inline_frame_limit: InlineFrameLimit) -> Self {
+        self.inline_frame_limit = inline_frame_limit;
+        self
+    }
+
+    /// Whether to turn off all downloads. Symbol files and source files are then
+    /// only read from local directories and from the cache directories of the
+    /// configured servers, so files which were downloaded before are still found.
+    /// This includes the servers from `_NT_SYMBOL_PATH` and `DEBUGINFOD_URLS`.
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.download_policy.offline = offline;
+        self
+    }
+
+    /// Only download symbol files and source files from this domain and its
+    /// subdomains, e.g. `example.com` allows `symbols.example.com`.
+    ///
+    /// This method can be called multiple times to allow several domains. If it's
+    /// never called, downloads from all domains are allowed.
+    ///
+    /// Once a domain is allowed, the servers of the Windows symbol path aren't
+    /// downloaded from at all, because their redirects can't be checked. Their
+    /// cache directories are still used.
+    pub fn allowed_download_domain(mut self, domain: impl Into<String>) -> Self {
+        let domain: String = domain.into();
+        self.download_policy
+            .allowed_domains
+            .push(domain.trim_start_matches('.').to_ascii_lowercase());
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn policy(domains: &[&str]) -> DownloadPolicy {
+        domains
+            .iter()
+            .fold(SymbolManagerConfig::new(), |config, domain| {
+                config.allowed_download_domain(*domain)
+            })
+            .download_policy
+    }
+
+    #[test]
+    fn allowed_download_domains() {
+        let policy = policy(&["example.com", ".Symbols.Example.org"]);
+        assert!(policy.check("https://example.com/a.sym").is_ok());
+        assert!(policy.check("https://symbols.example.com/a.sym").is_ok());
+        assert!(policy.check("https://a.b.example.com/a.sym").is_ok());
+        assert!(policy.check("https://EXAMPLE.com/a.sym").is_ok());
+        assert!(policy.check("https://symbols.example.org/a.sym").is_ok());
+        assert!(policy.check("https://evil-example.com/a.sym").is_err());
+        assert!(policy.check("https://example.com.evil.net/a.sym").is_err());
+        assert!(policy.check("https://example.org/a.sym").is_err());
+        assert!(policy.check("not a url").is_err());
+        assert!(DownloadPolicy::default()
+            .check("https://anywhere.net/a.sym")
+            .is_ok());
+    }
+
+    #[test]
+    fn offline_mode() {
+        let policy = SymbolManagerConfig::new()
+            .offline(true)
+            .allowed_download_domain("example.com")
+            .download_policy;
+        assert!(policy.is_offline());
+        assert!(policy.check("https://example.com/a.sym").is_err());
+        assert!(policy.check("https://anywhere.net/a.sym").is_err());
+    }
+
+    #[test]
+    fn symsrv_downloads_with_allowed_domains() {
+        let server = "https://symbols.example.com/symbols";
+        let urls = |config: SymbolManagerConfig| -> Vec<String> {
+            config
+                .windows_symbols_server(server, "/tmp/cache")
+                .effective_nt_symbol_path()
+                .unwrap()
+                .into_iter()
+                .flat_map(|entry| match entry {
+                    NtSymbolPathEntry::Chain { urls, .. } => urls,
+                    _ => Vec::new(),
+                })
+                .collect()
+        };
+        assert_eq!(urls(SymbolManagerConfig::new()), [server]);
+        // symsrv would follow a redirect from the allowed server to any domain,
+        // so its servers aren't used at all.
+        let config = SymbolManagerConfig::new().allowed_download_domain("example.com");
+        assert!(config
+            .download_policy
+            .check_symsrv("https://evil.net/a.pdb")
+            .is_err());
+        assert_eq!(urls(config), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn redirects_are_checked() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 302 Found\r\nLocation: http://localhost:{port}/a.sym\r\nContent-Length: 0\r\n\r\n"
+            )
+            .unwrap();
+        });
+
+        let client = policy(&["127.0.0.1"]).http_client().unwrap();
+        let err = client
+            .get(format!("http://127.0.0.1:{port}/a.sym"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_redirect());
+        server.join().unwrap();
+    }
 }
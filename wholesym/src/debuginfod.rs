@@ -1,4 +1,8 @@
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::config::DownloadPolicy;
 
 pub struct DebuginfodSymbolCache(DebuginfodSymbolCacheInner);
 
@@ -13,6 +17,8 @@ impl DebuginfodSymbolCache {
         debuginfod_cache_dir_if_not_installed: Option<PathBuf>,
         mut servers_and_caches: Vec<(String, PathBuf)>,
         verbose: bool,
+        download_policy: DownloadPolicy,
+        downloaded_bytes: Arc<AtomicU64>,
     ) -> Self {
         let is_debuginfod_installed = false;
         if is_debuginfod_installed {
@@ -33,6 +39,8 @@ impl DebuginfodSymbolCache {
                 ManualDebuginfodSymbolCache {
                     servers_and_caches,
                     verbose,
+                    download_policy,
+                    downloaded_bytes,
                 },
             ))
         }
@@ -79,6 +87,8 @@ impl OfficialDebuginfodSymbolCache {
 struct ManualDebuginfodSymbolCache {
     servers_and_caches: Vec<(String, PathBuf)>,
     verbose: bool,
+    download_policy: DownloadPolicy,
+    downloaded_bytes: Arc<AtomicU64>,
 }
 
 impl ManualDebuginfodSymbolCache {
@@ -116,10 +126,17 @@ impl ManualDebuginfodSymbolCache {
         cache_dir: &Path,
     ) -> Result<PathBuf, Box<dyn std::error::Error>> {
         let url = format!("{server_base_url}/buildid/{buildid}/{file_type}");
+        if let Err(reason) = self.download_policy.check(&url) {
+            if self.verbose {
+                eprintln!("{reason}.");
+            }
+            return Err(reason.into());
+        }
         if self.verbose {
             eprintln!("Downloading {url}...");
         }
-        let sym_file_response = reqwest::get(&url).await?.error_for_status()?;
+        let client = self.download_policy.http_client()?;
+        let sym_file_response = client.get(&url).send().await?.error_for_status()?;
         let mut stream = sym_file_response.bytes_stream();
         let dest_path = cache_dir.join(buildid).join(file_type);
         if let Some(dir) = dest_path.parent() {
@@ -132,7 +149,10 @@ impl ManualDebuginfodSymbolCache {
         let mut writer = tokio::io::BufWriter::new(file);
         use futures_util::StreamExt;
         while let Some(item) = stream.next().await {
-            tokio::io::copy(&mut item?.as_ref(), &mut writer).await?;
+            let item = item?;
+            self.downloaded_bytes
+                .fetch_add(item.len() as u64, Ordering::Relaxed);
+            tokio::io::copy(&mut item.as_ref(), &mut writer).await?;
         }
         drop(writer);
         Ok(dest_path)
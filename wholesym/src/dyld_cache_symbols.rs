@@ -0,0 +1,204 @@
+//! Breakpad symbol files for the images in the macOS dyld shared cache.
+//!
+//! Getting the symbols of a system library from the dyld shared cache means
+//! parsing the cache and its subcaches, which takes a while for every library
+//! and every run. So the symbols are written out as Breakpad symbol files, in a
+//! directory per cache UUID, and later lookups on the same OS version use those.
+
+use debugid::DebugId;
+use samply_symbols::SymbolMap;
+use uuid::Uuid;
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::helper::WholesymFileLocation;
+
+/// The offset of the `uuid` field in `dyld_cache_header`.
+const HEADER_UUID_OFFSET: usize = 0x58;
+
+/// Reads the UUID of the dyld shared cache file at `path`, or returns `None`
+/// if it isn't one.
+pub fn dyld_cache_uuid(path: &Path) -> Option<Uuid> {
+    let is_dyld_cache = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| name.starts_with("dyld_shared_cache_"));
+    if !is_dyld_cache {
+        return None;
+    }
+    let mut header = [0; HEADER_UUID_OFFSET + 16];
+    File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if !header.starts_with(b"dyld_v1") {
+        return None;
+    }
+    let uuid = Uuid::from_slice(&header[HEADER_UUID_OFFSET..]).ok()?;
+    if uuid.is_nil() {
+        return None;
+    }
+    Some(uuid)
+}
+
+/// The UUIDs of the dyld shared caches which were looked at so far, so that
+/// each cache's header is only read once.
+#[derive(Debug, Default)]
+pub struct DyldCacheUuids {
+    uuids: Mutex<HashMap<PathBuf, Option<Uuid>>>,
+}
+
+impl DyldCacheUuids {
+    pub fn uuid(&self, dyld_cache_path: &Path) -> Option<Uuid> {
+        let mut uuids = self.uuids.lock().unwrap();
+        *uuids
+            .entry(dyld_cache_path.to_owned())
+            .or_insert_with(|| dyld_cache_uuid(dyld_cache_path))
+    }
+}
+
+/// The path of the cached symbol file in `dir` for the library with the
+/// breakpad-style `rel_path`, in the dyld shared cache with `dyld_cache_uuid`.
+pub fn cached_sym_file_path(dir: &Path, dyld_cache_uuid: Uuid, rel_path: &str) -> PathBuf {
+    dir.join(dyld_cache_uuid.simple().to_string())
+        .join(rel_path)
+}
+
+/// The architecture in the file name of the dyld shared cache, e.g. "arm64e"
+/// for dyld_shared_cache_arm64e.
+pub fn dyld_cache_arch(dyld_cache_path: &Path) -> Option<&str> {
+    dyld_cache_path
+        .file_name()?
+        .to_str()?
+        .strip_prefix("dyld_shared_cache_")
+}
+
+/// Writes the symbols of `symbol_map` as a Breakpad symbol file to `path`.
+pub fn write_sym_file(
+    path: &Path,
+    debug_name: &str,
+    debug_id: DebugId,
+    arch: &str,
+    symbol_map: &SymbolMap<WholesymFileLocation>,
+) -> std::io::Result<()> {
+    let contents = sym_file_contents(debug_name, debug_id, arch, symbol_map.iter_symbols());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Write to a temporary file first, so that concurrent readers never see a
+    // partially written file.
+    let temp_path = path.with_extension("sym.tmp");
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path).map_err(|err| {
+        let _ = fs::remove_file(&temp_path);
+        err
+    })
+}
+
+/// The contents of a Breakpad symbol file with only PUBLIC records, sorted by address.
+fn sym_file_contents<'a>(
+    debug_name: &str,
+    debug_id: DebugId,
+    arch: &str,
+    symbols: impl Iterator<Item = (u32, std::borrow::Cow<'a, str>)>,
+) -> String {
+    let mut symbols: Vec<_> = symbols.collect();
+    symbols.sort_by_key(|(address, _)| *address);
+
+    let mut contents = String::new();
+    let _ = writeln!(
+        contents,
+        "MODULE mac {arch} {} {debug_name}",
+        debug_id.breakpad()
+    );
+    for (address, name) in symbols {
+        let _ = writeln!(contents, "PUBLIC {address:x} 0 {name}");
+    }
+    contents
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::borrow::Cow;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "wholesym-dyld-cache-test-{name}-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn header_with_uuid(uuid: Uuid) -> Vec<u8> {
+        let mut header = b"dyld_v1  arm64e\0".to_vec();
+        header.resize(HEADER_UUID_OFFSET, 0);
+        header.extend_from_slice(uuid.as_bytes());
+        header.resize(0x200, 0);
+        header
+    }
+
+    #[test]
+    fn read_dyld_cache_uuid() {
+        let dir = temp_dir("uuid");
+        let uuid = Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+        let cache_path = dir.join("dyld_shared_cache_arm64e");
+        fs::write(&cache_path, header_with_uuid(uuid)).unwrap();
+        let other_name = dir.join("libfoo.dylib");
+        fs::write(&other_name, header_with_uuid(uuid)).unwrap();
+        let nil_uuid = dir.join("dyld_shared_cache_x86_64");
+        fs::write(&nil_uuid, header_with_uuid(Uuid::nil())).unwrap();
+        let not_a_cache = dir.join("dyld_shared_cache_x86_64h");
+        fs::write(&not_a_cache, b"not a dyld cache").unwrap();
+
+        let results = (
+            dyld_cache_uuid(&cache_path),
+            dyld_cache_uuid(&other_name),
+            dyld_cache_uuid(&nil_uuid),
+            dyld_cache_uuid(&not_a_cache),
+        );
+
+        // The header is only read the first time.
+        let uuids = DyldCacheUuids::default();
+        let first = uuids.uuid(&cache_path);
+        fs::remove_file(&cache_path).unwrap();
+        let second = uuids.uuid(&cache_path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(results, (Some(uuid), None, None, None));
+        assert_eq!((first, second), (Some(uuid), Some(uuid)));
+    }
+
+    #[test]
+    fn sym_file_paths() {
+        let uuid = Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+        assert_eq!(
+            cached_sym_file_path(Path::new("/cache"), uuid, "libc.dylib/ABC0/libc.dylib.sym"),
+            Path::new("/cache/0123456789abcdef0123456789abcdef/libc.dylib/ABC0/libc.dylib.sym")
+        );
+        assert_eq!(
+            dyld_cache_arch(Path::new("/System/Library/dyld/dyld_shared_cache_arm64e")),
+            Some("arm64e")
+        );
+        assert_eq!(dyld_cache_arch(Path::new("/usr/lib/libc.dylib")), None);
+    }
+
+    #[test]
+    fn sym_file_has_sorted_public_records() {
+        let debug_id = DebugId::from_breakpad("0123456789ABCDEF0123456789ABCDEF0").unwrap();
+        let symbols = vec![
+            (0x2000, Cow::Borrowed("_free")),
+            (0x1000, Cow::Borrowed("_malloc")),
+        ];
+        assert_eq!(
+            sym_file_contents("libc.dylib", debug_id, "arm64e", symbols.into_iter()),
+            "MODULE mac arm64e 0123456789ABCDEF0123456789ABCDEF0 libc.dylib\n\
+             PUBLIC 1000 0 _malloc\n\
+             PUBLIC 2000 0 _free\n"
+        );
+    }
+}
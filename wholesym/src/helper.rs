@@ -12,10 +12,19 @@ use std::{
     fs::{self, File},
     path::{Path, PathBuf},
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 
-use crate::{config::SymbolManagerConfig, debuginfod::DebuginfodSymbolCache};
+use crate::{
+    config::SymbolManagerConfig,
+    debuginfod::DebuginfodSymbolCache,
+    dyld_cache_symbols::{self, DyldCacheUuids},
+    moria_mac::XcodeDsymIndex,
+    windows_paths, wsl,
+};
 
 use bytes::Bytes;
 
@@ -80,8 +89,14 @@ impl FileLocation for WholesymFileLocation {
 
     fn location_for_pdb_from_binary(&self, pdb_path_in_binary: &str) -> Option<Self> {
         // We only respect absolute paths to PDB files if those paths were found in a local binary.
+        // A C:\ path in a binary which is analyzed inside WSL refers to /mnt/c/.
         match self {
-            Self::LocalFile(_) => Some(Self::LocalFile(pdb_path_in_binary.into())),
+            Self::LocalFile(_) => Some(Self::LocalFile(
+                wsl::translated_paths(pdb_path_in_binary)
+                    .into_iter()
+                    .next()
+                    .unwrap_or_else(|| pdb_path_in_binary.into()),
+            )),
             _ => None,
         }
     }
@@ -132,6 +147,16 @@ impl FileLocation for WholesymFileLocation {
             _ => None,
         }
     }
+
+    fn location_for_def_file(&self) -> Option<Self> {
+        // Module-definition files are only looked for next to local binaries.
+        match self {
+            Self::LocalFile(binary_path) => {
+                Some(Self::LocalFile(binary_path.with_extension("def")))
+            }
+            _ => None,
+        }
+    }
 }
 
 impl std::fmt::Display for WholesymFileLocation {
@@ -202,7 +227,12 @@ pub struct Helper {
     symsrv_downloader: Option<SymsrvDownloader>,
     debuginfod_symbol_cache: Option<DebuginfodSymbolCache>,
     known_libs: Mutex<KnownLibs>,
+    /// Built on first use, see [`XcodeDsymIndex`].
+    xcode_dsyms: Mutex<Option<XcodeDsymIndex>>,
+    dyld_cache_uuids: DyldCacheUuids,
     config: SymbolManagerConfig,
+    /// The number of bytes which were downloaded so far.
+    downloaded_bytes: Arc<AtomicU64>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -215,13 +245,15 @@ struct KnownLibs {
 
 impl Helper {
     pub fn with_config(config: SymbolManagerConfig) -> Self {
+        let downloaded_bytes = Arc::new(AtomicU64::new(0));
         let symsrv_downloader = match config.effective_nt_symbol_path() {
             Some(nt_symbol_path) => {
                 let mut downloader = SymsrvDownloader::new(nt_symbol_path);
                 downloader.set_default_downstream_store(symsrv::get_home_sym_dir());
-                if config.verbose {
-                    downloader.set_observer(Some(Arc::new(VerboseSymsrvObserver::new())));
-                }
+                downloader.set_observer(Some(Arc::new(HelperSymsrvObserver::new(
+                    config.verbose,
+                    downloaded_bytes.clone(),
+                ))));
                 Some(downloader)
             }
             None => None,
@@ -231,6 +263,8 @@ impl Helper {
                 config.debuginfod_cache_dir_if_not_installed.clone(),
                 config.debuginfod_servers.clone(),
                 config.verbose,
+                config.download_policy.clone(),
+                downloaded_bytes.clone(),
             ))
         } else {
             None
@@ -239,10 +273,19 @@ impl Helper {
             symsrv_downloader,
             debuginfod_symbol_cache,
             known_libs: Mutex::new(Default::default()),
+            xcode_dsyms: Mutex::new(None),
+            dyld_cache_uuids: DyldCacheUuids::default(),
             config,
+            downloaded_bytes,
         }
     }
 
+    /// The number of bytes which were downloaded from symbol servers and source
+    /// file URLs so far.
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.downloaded_bytes.load(Ordering::Relaxed)
+    }
+
     pub fn add_known_lib(&self, lib_info: LibraryInfo) {
         let mut known_libs = self.known_libs.lock().unwrap();
         let lib_info = Arc::new(lib_info);
@@ -298,7 +341,11 @@ impl Helper {
                 if self.config.verbose {
                     eprintln!("Trying to get file {url} from a URL");
                 }
-                let bytes = reqwest::get(&url).await?.bytes().await?;
+                self.config.download_policy.check(&url)?;
+                let client = self.config.download_policy.http_client()?;
+                let bytes = client.get(&url).send().await?.bytes().await?;
+                self.downloaded_bytes
+                    .fetch_add(bytes.len() as u64, Ordering::Relaxed);
                 Ok(FileContents::Bytes(bytes))
             }
             WholesymFileLocation::SymsrvFile(filename, hash) => {
@@ -382,10 +429,17 @@ impl Helper {
         cache_dir: &Path,
     ) -> FileAndPathHelperResult<FileContents> {
         let url = format!("{server_base_url}/{rel_path}");
+        if let Err(reason) = self.config.download_policy.check(&url) {
+            if self.config.verbose {
+                eprintln!("{reason}.");
+            }
+            return Err(reason.into());
+        }
         if self.config.verbose {
             eprintln!("Downloading {url}...");
         }
-        let sym_file_response = reqwest::get(&url).await?.error_for_status()?;
+        let client = self.config.download_policy.http_client()?;
+        let sym_file_response = client.get(&url).send().await?.error_for_status()?;
         let mut stream = sym_file_response.bytes_stream();
         let dest_path = cache_dir.join(rel_path);
         if let Some(dir) = dest_path.parent() {
@@ -400,6 +454,8 @@ impl Helper {
         let mut parser = BreakpadIndexParser::new();
         while let Some(item) = stream.next().await {
             let item = item?;
+            self.downloaded_bytes
+                .fetch_add(item.len() as u64, Ordering::Relaxed);
             let mut item_slice = item.as_ref();
             parser.consume(item_slice);
             tokio::io::copy(&mut item_slice, &mut writer).await?;
@@ -487,7 +543,68 @@ impl Helper {
         Ok(())
     }
 
-    fn fill_in_library_info_details(&self, info: &mut LibraryInfo) {
+    /// The path of the Breakpad symbol file with the symbols of `info`, from the
+    /// dyld shared cache at `dyld_cache_path`, if a directory for them is configured.
+    fn dyld_cache_sym_file_path(
+        &self,
+        dyld_cache_path: &WholesymFileLocation,
+        info: &LibraryInfo,
+    ) -> Option<(PathBuf, String)> {
+        let dir = self.config.dyld_cache_symbols_dir.as_deref()?;
+        let dyld_cache_path = match dyld_cache_path {
+            WholesymFileLocation::LocalFile(path) => path,
+            _ => return None,
+        };
+        let rel_path = breakpad_rel_path(info.debug_name.as_deref()?, info.debug_id?);
+        let dyld_cache_uuid = self.dyld_cache_uuids.uuid(dyld_cache_path)?;
+        let path = dyld_cache_symbols::cached_sym_file_path(dir, dyld_cache_uuid, &rel_path);
+        Some((path, rel_path))
+    }
+
+    /// Writes the symbols of a library which were loaded from the dyld shared
+    /// cache to the dyld cache symbols directory, if one is configured.
+    pub fn store_dyld_cache_symbols(
+        &self,
+        info: &LibraryInfo,
+        symbol_map: &samply_symbols::SymbolMap<WholesymFileLocation>,
+    ) {
+        if info.debug_id != Some(symbol_map.debug_id()) {
+            return;
+        }
+        let dyld_cache_path = symbol_map.debug_file_location();
+        let (path, _rel_path) = match self.dyld_cache_sym_file_path(dyld_cache_path, info) {
+            Some(path) => path,
+            None => return,
+        };
+        if path.exists() {
+            return;
+        }
+        let arch = match (&info.arch, dyld_cache_path) {
+            (Some(arch), _) => arch.as_str(),
+            (None, WholesymFileLocation::LocalFile(dyld_cache_path)) => {
+                dyld_cache_symbols::dyld_cache_arch(dyld_cache_path).unwrap_or("unknown")
+            }
+            (None, _) => "unknown",
+        };
+        if self.config.verbose {
+            eprintln!("Writing the symbols from the dyld shared cache to {path:?}.");
+        }
+        if let Err(err) = dyld_cache_symbols::write_sym_file(
+            &path,
+            info.debug_name.as_deref().unwrap_or_default(),
+            symbol_map.debug_id(),
+            arch,
+            symbol_map,
+        ) {
+            if self.config.verbose {
+                eprintln!("Could not write {path:?}: {err}");
+            }
+        }
+    }
+
+    /// Adds the details which are known about the library, e.g. its paths and
+    /// its architecture, from the libraries which were passed to `add_known_lib`.
+    pub fn fill_in_library_info_details(&self, info: &mut LibraryInfo) {
         let known_libs = self.known_libs.lock().unwrap();
 
         // Look up (debugName, breakpadId) in the known libs.
@@ -531,6 +648,11 @@ impl<'h> FileAndPathHelper<'h> for Helper {
         self.fill_in_library_info_details(&mut info);
 
         let mut got_dsym = false;
+        let is_system_lib = info
+            .path
+            .as_deref()
+            .or(info.debug_path.as_deref())
+            .map_or(false, crate::moria_mac::is_system_path);
 
         if let (Some(debug_path), Some(debug_name)) = (&info.debug_path, &info.debug_name) {
             if let Some(debug_id) = info.debug_id {
@@ -564,32 +686,42 @@ impl<'h> FileAndPathHelper<'h> for Helper {
 
             if debug_path.ends_with(".pdb") {
                 // Get symbols from the pdb file.
-                paths.push(CandidatePathInfo::SingleFile(
-                    WholesymFileLocation::LocalFile(debug_path.into()),
-                ));
+                push_local_file_candidates(&mut paths, debug_path);
             }
         }
 
         if let (Some(path), Some(debug_name)) = (&info.path, &info.debug_name) {
-            if info.name.as_deref() != Some(debug_name) {
+            if info.name.as_deref() != Some(debug_name)
+                && !windows_paths::is_unreadable_windows_apps_path(path)
+            {
                 // Also look for the debug file right next to the binary.
-                let binary_path = Path::new(path);
+                let path =
+                    windows_paths::win32_path_for_nt_path(path).unwrap_or_else(|| path.clone());
+                let binary_path = Path::new(&path);
                 if let Some(parent) = binary_path.parent() {
                     let debug_path = parent.join(debug_name);
                     paths.push(CandidatePathInfo::SingleFile(
                         WholesymFileLocation::LocalFile(debug_path),
                     ));
                 }
+                for translated_binary_path in wsl::translated_paths(&path) {
+                    if let Some(parent) = translated_binary_path.parent() {
+                        paths.push(CandidatePathInfo::SingleFile(
+                            WholesymFileLocation::LocalFile(parent.join(debug_name)),
+                        ));
+                    }
+                }
             }
         }
 
-        if !got_dsym && self.config.use_spotlight {
+        if !got_dsym && !is_system_lib && self.config.use_spotlight {
             if let Some(debug_id) = info.debug_id {
                 // Try a little harder to find a dSYM, just from the UUID. We can do this
                 // even if we don't have an entry for this library in the libinfo map.
                 if let Ok(dsym_path) =
                     crate::moria_mac::locate_dsym_using_spotlight(debug_id.uuid())
                 {
+                    got_dsym = true;
                     paths.push(CandidatePathInfo::SingleFile(
                         WholesymFileLocation::LocalFile(dsym_path.clone()),
                     ));
@@ -608,6 +740,19 @@ impl<'h> FileAndPathHelper<'h> for Helper {
             }
         }
 
+        if !got_dsym && !is_system_lib && cfg!(target_os = "macos") {
+            if let Some(debug_id) = info.debug_id {
+                // Spotlight may not have indexed the dSYM, e.g. right after a build.
+                let mut xcode_dsyms = self.xcode_dsyms.lock().unwrap();
+                let xcode_dsyms = xcode_dsyms.get_or_insert_with(XcodeDsymIndex::for_home_dir);
+                if let Some(dwarf_path) = xcode_dsyms.dwarf_file(debug_id.uuid()) {
+                    paths.push(CandidatePathInfo::SingleFile(
+                        WholesymFileLocation::LocalFile(dwarf_path.to_owned()),
+                    ));
+                }
+            }
+        }
+
         // Find debuginfo in /usr/lib/debug/.build-id/ etc.
         // <https://sourceware.org/gdb/onlinedocs/gdb/Separate-Debug-Files.html>
         if let Some(CodeId::ElfBuildId(build_id)) = &info.code_id {
@@ -622,12 +767,7 @@ impl<'h> FileAndPathHelper<'h> for Helper {
         }
 
         if let (Some(debug_name), Some(debug_id)) = (&info.debug_name, info.debug_id) {
-            let rel_path = format!(
-                "{}/{}/{}.sym",
-                debug_name,
-                debug_id.breakpad(),
-                debug_name.trim_end_matches(".pdb")
-            );
+            let rel_path = breakpad_rel_path(debug_name, debug_id);
 
             // Search breakpad symbol directories.
             for dir in &self.config.breakpad_directories_readonly {
@@ -677,16 +817,24 @@ impl<'h> FileAndPathHelper<'h> for Helper {
 
         if let Some(path) = &info.path {
             // Fall back to getting symbols from the binary itself.
-            paths.push(CandidatePathInfo::SingleFile(
-                WholesymFileLocation::LocalFile(path.into()),
-            ));
+            push_local_file_candidates(&mut paths, path);
 
             // For macOS system libraries, also consult the dyld shared cache.
             if path.starts_with("/usr/") || path.starts_with("/System/") {
                 for dyld_cache_path in get_dyld_shared_cache_paths(info.arch.as_deref()) {
+                    // Prefer the symbols which were saved from this cache earlier.
+                    if let Some((sym_path, rel_path)) =
+                        self.dyld_cache_sym_file_path(&dyld_cache_path, &info)
+                    {
+                        if sym_path.exists() {
+                            paths.push(CandidatePathInfo::SingleFile(
+                                WholesymFileLocation::LocalBreakpadFile(sym_path, rel_path),
+                            ));
+                        }
+                    }
                     paths.push(CandidatePathInfo::InDyldCache {
                         dyld_cache_path,
-                        dylib_path: path.clone(),
+                        dylib_path: path_in_dyld_cache(path).to_owned(),
                     });
                 }
             }
@@ -723,6 +871,13 @@ impl<'h> FileAndPathHelper<'h> for Helper {
                     .join(absolute_original_file_parent.strip_prefix("/")?)
                     .join(debug_link_name),
             ),
+            // NetBSD installs the debug files of the base system here, e.g.
+            // /usr/libdata/debug/bin/ls.debug for /bin/ls.
+            WholesymFileLocation::LocalFile(
+                Path::new("/usr/libdata/debug")
+                    .join(absolute_original_file_parent.strip_prefix("/")?)
+                    .join(debug_link_name),
+            ),
         ])
     }
 
@@ -737,9 +892,7 @@ impl<'h> FileAndPathHelper<'h> for Helper {
 
         // Begin with the binary itself.
         if let Some(path) = &info.path {
-            paths.push(CandidatePathInfo::SingleFile(
-                WholesymFileLocation::LocalFile(path.into()),
-            ));
+            push_local_file_candidates(&mut paths, path);
         }
 
         if let (Some(_symbol_cache), Some(name), Some(CodeId::PeCodeId(code_id))) =
@@ -765,7 +918,7 @@ impl<'h> FileAndPathHelper<'h> for Helper {
                 for dyld_cache_path in get_dyld_shared_cache_paths(info.arch.as_deref()) {
                     paths.push(CandidatePathInfo::InDyldCache {
                         dyld_cache_path,
-                        dylib_path: path.clone(),
+                        dylib_path: path_in_dyld_cache(path).to_owned(),
                     });
                 }
             }
@@ -829,6 +982,16 @@ impl<'h> FileAndPathHelper<'h> for Helper {
     }
 }
 
+/// The path of a library's Breakpad symbol file, relative to a symbol directory.
+fn breakpad_rel_path(debug_name: &str, debug_id: DebugId) -> String {
+    format!(
+        "{}/{}/{}.sym",
+        debug_name,
+        debug_id.breakpad(),
+        debug_name.trim_end_matches(".pdb")
+    )
+}
+
 /// Return a Vec containing the potential paths where a dyld shared cache
 /// which contains an object of the given architecture might be found.
 ///
@@ -870,25 +1033,73 @@ fn get_dyld_shared_cache_paths(arch: Option<&str>) -> Vec<WholesymFileLocation>
     vec
 }
 
+/// The install name under which the library at `path` is stored in the dyld
+/// shared cache. Since macOS 13, some libraries are loaded from the OS cryptex,
+/// so their paths start with /System/Cryptexes/OS or its real location in the
+/// Preboot volume, but the cache lists them under their regular path.
+fn path_in_dyld_cache(path: &str) -> &str {
+    const CRYPTEX_PREFIXES: &[&str] = &[
+        "/System/Volumes/Preboot/Cryptexes/OS/",
+        "/System/Cryptexes/OS/",
+    ];
+    CRYPTEX_PREFIXES
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix))
+        .map_or(path, |rest| &path[path.len() - rest.len() - 1..])
+}
+
+/// Adds the local file at `path`, and the same file as seen from the other side
+/// of the WSL boundary if `path` is a WSL path on Windows or a Windows path in WSL.
+///
+/// Files of packaged Windows apps are skipped because they can't be read, and
+/// NT paths are translated first.
+fn push_local_file_candidates(
+    paths: &mut Vec<CandidatePathInfo<WholesymFileLocation>>,
+    path: &str,
+) {
+    if windows_paths::is_unreadable_windows_apps_path(path) {
+        return;
+    }
+    let win32_path = windows_paths::win32_path_for_nt_path(path);
+    let path = win32_path.as_deref().unwrap_or(path);
+    paths.push(CandidatePathInfo::SingleFile(
+        WholesymFileLocation::LocalFile(path.into()),
+    ));
+    for translated_path in wsl::translated_paths(path) {
+        paths.push(CandidatePathInfo::SingleFile(
+            WholesymFileLocation::LocalFile(translated_path),
+        ));
+    }
+}
+
 /// Used to filter out files like `jitted-12345-12.so`, to avoid hammering debuginfod servers.
 fn might_be_perf_jit_so_file(info: &LibraryInfo) -> bool {
     matches!(&info.name, Some(name) if name.starts_with("jitted-") && name.ends_with(".so"))
 }
 
-struct VerboseSymsrvObserver {
+/// Counts the downloaded bytes, and prints the progress of downloads and file
+/// lookups if `verbose` is set.
+struct HelperSymsrvObserver {
+    verbose: bool,
+    downloaded_bytes: Arc<AtomicU64>,
     urls: Mutex<HashMap<u64, String>>,
 }
 
-impl VerboseSymsrvObserver {
-    fn new() -> Self {
+impl HelperSymsrvObserver {
+    fn new(verbose: bool, downloaded_bytes: Arc<AtomicU64>) -> Self {
         Self {
+            verbose,
+            downloaded_bytes,
             urls: Mutex::new(HashMap::new()),
         }
     }
 }
 
-impl SymsrvObserver for VerboseSymsrvObserver {
+impl SymsrvObserver for HelperSymsrvObserver {
     fn on_new_download_before_connect(&self, download_id: u64, url: &str) {
+        if !self.verbose {
+            return;
+        }
         eprintln!("Connecting to {}...", url);
         self.urls
             .lock()
@@ -897,6 +1108,9 @@ impl SymsrvObserver for VerboseSymsrvObserver {
     }
 
     fn on_download_started(&self, download_id: u64) {
+        if !self.verbose {
+            return;
+        }
         let urls = self.urls.lock().unwrap();
         let url = urls.get(&download_id).unwrap();
         eprintln!("Downloading from {}...", url);
@@ -913,20 +1127,31 @@ impl SymsrvObserver for VerboseSymsrvObserver {
     fn on_download_completed(
         &self,
         download_id: u64,
-        _uncompressed_size_in_bytes: u64,
+        uncompressed_size_in_bytes: u64,
         _time_until_headers: std::time::Duration,
         _time_until_completed: std::time::Duration,
     ) {
+        self.downloaded_bytes
+            .fetch_add(uncompressed_size_in_bytes, Ordering::Relaxed);
+        if !self.verbose {
+            return;
+        }
         let url = self.urls.lock().unwrap().remove(&download_id).unwrap();
         eprintln!("Finished download from {}.", url);
     }
 
     fn on_download_failed(&self, download_id: u64, reason: symsrv::DownloadError) {
+        if !self.verbose {
+            return;
+        }
         let url = self.urls.lock().unwrap().remove(&download_id).unwrap();
         eprintln!("Failed to download from {url}: {reason}.");
     }
 
     fn on_download_canceled(&self, download_id: u64) {
+        if !self.verbose {
+            return;
+        }
         let url = self.urls.lock().unwrap().remove(&download_id).unwrap();
         eprintln!("Canceled download from {}.", url);
     }
@@ -951,9 +1176,40 @@ impl SymsrvObserver for VerboseSymsrvObserver {
 
     fn on_file_created(&self, _path: &Path, _size_in_bytes: u64) {}
     fn on_file_accessed(&self, path: &Path) {
-        eprintln!("Checking if {path:?} exists... yes");
+        if self.verbose {
+            eprintln!("Checking if {path:?} exists... yes");
+        }
     }
     fn on_file_missed(&self, path: &Path) {
-        eprintln!("Checking if {path:?} exists... no");
+        if self.verbose {
+            eprintln!("Checking if {path:?} exists... no");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cryptex_paths_in_dyld_cache() {
+        assert_eq!(
+            path_in_dyld_cache(
+                "/System/Cryptexes/OS/System/Library/Frameworks/WebKit.framework/WebKit"
+            ),
+            "/System/Library/Frameworks/WebKit.framework/WebKit"
+        );
+        assert_eq!(
+            path_in_dyld_cache("/System/Volumes/Preboot/Cryptexes/OS/usr/lib/libobjc.A.dylib"),
+            "/usr/lib/libobjc.A.dylib"
+        );
+        assert_eq!(
+            path_in_dyld_cache("/usr/lib/libSystem.B.dylib"),
+            "/usr/lib/libSystem.B.dylib"
+        );
+        assert_eq!(
+            path_in_dyld_cache("/System/Cryptexes/App/usr/lib/libfoo.dylib"),
+            "/System/Cryptexes/App/usr/lib/libfoo.dylib"
+        );
     }
 }
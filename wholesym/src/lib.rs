@@ -142,17 +142,21 @@ pub use debugid;
 
 mod config;
 mod debuginfod;
+mod dyld_cache_symbols;
 mod helper;
 mod moria_mac;
 #[cfg(target_os = "macos")]
 mod moria_mac_spotlight;
 mod symbol_manager;
+mod windows_paths;
+mod wsl;
 
 pub use config::SymbolManagerConfig;
 pub use samply_symbols;
 pub use samply_symbols::{
     AddressInfo, CodeId, ElfBuildId, Error, ExternalFileAddressInFileRef, ExternalFileAddressRef,
-    ExternalFileRef, ExternalFileSymbolMap, FrameDebugInfo, FramesLookupResult, LibraryInfo,
-    MappedPath, MultiArchDisambiguator, PeCodeId, SourceFilePath, SymbolInfo,
+    ExternalFileRef, ExternalFileSymbolMap, FrameDebugInfo, FramesLookupResult, FunctionInfo,
+    InlineFrameLimit, LibraryInfo, LineRange, MappedPath, MultiArchDisambiguator, PeCodeId,
+    SourceFilePath, SymbolInfo,
 };
 pub use symbol_manager::{SymbolFileOrigin, SymbolManager, SymbolMap};
@@ -3,8 +3,10 @@
 
 #![warn(clippy::all)]
 
+use object::read::macho::{FatArch, MachOFatFile32, MachOFatFile64};
 use object::Object;
 use samply_symbols::object;
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 use uuid::Uuid;
@@ -58,6 +60,124 @@ pub fn locate_dsym_fastpath(path: &Path, uuid: Uuid) -> Option<PathBuf> {
     try_match_dsym_in_dir(&deps_dir, uuid).or_else(|| try_match_dsym_in_dir(&examples_dir, uuid))
 }
 
+/// Whether `path` is where macOS keeps its own binaries. Apple doesn't ship dSYMs
+/// for them, so there's no point in looking for one.
+pub fn is_system_path(path: &str) -> bool {
+    const SYSTEM_DIRS: &[&str] = &[
+        "/System/",
+        "/usr/lib/",
+        "/usr/libexec/",
+        "/usr/bin/",
+        "/usr/sbin/",
+        "/bin/",
+        "/sbin/",
+        "/Library/Apple/",
+    ];
+    SYSTEM_DIRS.iter().any(|dir| path.starts_with(dir))
+}
+
+/// The dSYM files in Xcode's DerivedData and Archives directories, by UUID.
+///
+/// Xcode puts the dSYM files of the apps it builds into DerivedData, and the ones
+/// of archived apps into the .xcarchive bundles in Archives. Spotlight finds them
+/// too, but only once they've been indexed, and not at all if indexing is turned
+/// off. These directories can hold many dSYMs, so they're scanned only once, and
+/// then looked up by UUID.
+#[derive(Debug, Default)]
+pub struct XcodeDsymIndex {
+    dwarf_files: HashMap<Uuid, PathBuf>,
+}
+
+impl XcodeDsymIndex {
+    /// Scans `~/Library/Developer/Xcode`.
+    pub fn for_home_dir() -> Self {
+        match std::env::var_os("HOME") {
+            Some(home) => Self::for_xcode_dir(&Path::new(&home).join("Library/Developer/Xcode")),
+            None => Self::default(),
+        }
+    }
+
+    pub fn for_xcode_dir(xcode_dir: &Path) -> Self {
+        // DerivedData/<project>-<hash>/Build/Products/<configuration>-<platform>/*.dSYM
+        let derived_data_dirs = subdirs(&xcode_dir.join("DerivedData"))
+            .flat_map(|project_dir| subdirs(&project_dir.join("Build").join("Products")));
+        // Archives/<date>/<name> <time>.xcarchive/dSYMs/*.dSYM
+        let archive_dirs = subdirs(&xcode_dir.join("Archives"))
+            .flat_map(|date_dir| subdirs(&date_dir))
+            .map(|archive_dir| archive_dir.join("dSYMs"));
+
+        let mut dwarf_files = HashMap::new();
+        let dsym_dirs = derived_data_dirs
+            .chain(archive_dirs)
+            .flat_map(|dir| subdirs(&dir))
+            .filter(|dir| dir.extension() == Some(std::ffi::OsStr::new("dSYM")));
+        for dsym_dir in dsym_dirs {
+            let dwarf_dir = dsym_dir.join("Contents/Resources/DWARF");
+            for dwarf_file in fs::read_dir(dwarf_dir).into_iter().flatten().flatten() {
+                let dwarf_file = dwarf_file.path();
+                for uuid in macho_uuids(&dwarf_file) {
+                    dwarf_files
+                        .entry(uuid)
+                        .or_insert_with(|| dwarf_file.clone());
+                }
+            }
+        }
+        Self { dwarf_files }
+    }
+
+    /// The path of the DWARF file in the dSYM with this UUID.
+    pub fn dwarf_file(&self, uuid: Uuid) -> Option<&Path> {
+        self.dwarf_files.get(&uuid).map(PathBuf::as_path)
+    }
+}
+
+/// The UUIDs of the mach-O file at `path`, or of all its architectures if it's
+/// a fat file.
+fn macho_uuids(path: &Path) -> Vec<Uuid> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+    let mmap = match unsafe { memmap2::Mmap::map(&file) } {
+        Ok(mmap) => mmap,
+        Err(_) => return Vec::new(),
+    };
+    let data = &mmap[..];
+    let arch_data: Vec<&[u8]> = match object::FileKind::parse(data) {
+        Ok(object::FileKind::MachOFat32) => match MachOFatFile32::parse(data) {
+            Ok(fat_file) => fat_file
+                .arches()
+                .iter()
+                .filter_map(|arch| arch.data(data).ok())
+                .collect(),
+            Err(_) => Vec::new(),
+        },
+        Ok(object::FileKind::MachOFat64) => match MachOFatFile64::parse(data) {
+            Ok(fat_file) => fat_file
+                .arches()
+                .iter()
+                .filter_map(|arch| arch.data(data).ok())
+                .collect(),
+            Err(_) => Vec::new(),
+        },
+        _ => vec![data],
+    };
+    arch_data
+        .into_iter()
+        .filter_map(|data| object::File::parse(data).ok()?.mach_uuid().ok().flatten())
+        .map(Uuid::from_bytes)
+        .collect()
+}
+
+fn subdirs(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+}
+
 fn try_match_dsym_in_dir(dir: &Path, uuid: Uuid) -> Option<PathBuf> {
     for entry in fs::read_dir(dir).ok()? {
         let item = entry.ok()?.path();
@@ -96,3 +216,49 @@ fn try_match_dsym(dsym_dir: &Path, uuid: Uuid) -> Option<PathBuf> {
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn system_paths() {
+        assert!(is_system_path("/usr/lib/libSystem.B.dylib"));
+        assert!(is_system_path(
+            "/System/Library/Frameworks/AppKit.framework/AppKit"
+        ));
+        assert!(!is_system_path("/usr/local/lib/libfoo.dylib"));
+        assert!(!is_system_path("/Users/me/Library/Developer/app"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn index_xcode_dsyms() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../fixtures/macos-ci/libmozglue.dylib.dSYM")
+            .canonicalize()
+            .unwrap();
+        let dwarf_file = fixture.join("Contents/Resources/DWARF/libmozglue.dylib");
+        let uuid = macho_uuids(&dwarf_file)[0];
+
+        let xcode_dir =
+            std::env::temp_dir().join(format!("wholesym-xcode-test-{}", std::process::id()));
+        let products_dir = xcode_dir.join("DerivedData/App-abcdef/Build/Products/Release");
+        fs::create_dir_all(&products_dir).unwrap();
+        std::os::unix::fs::symlink(&fixture, products_dir.join("libmozglue.dylib.dSYM")).unwrap();
+
+        let index = XcodeDsymIndex::for_xcode_dir(&xcode_dir);
+        let found = index.dwarf_file(uuid).map(Path::to_owned);
+        let missing = index.dwarf_file(Uuid::nil()).map(Path::to_owned);
+        fs::remove_dir_all(&xcode_dir).unwrap();
+
+        assert_eq!(
+            found,
+            Some(
+                products_dir
+                    .join("libmozglue.dylib.dSYM/Contents/Resources/DWARF/libmozglue.dylib")
+            )
+        );
+        assert_eq!(missing, None);
+    }
+}
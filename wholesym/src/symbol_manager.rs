@@ -5,7 +5,7 @@ use std::{future::Future, pin::Pin};
 use debugid::DebugId;
 use samply_symbols::{
     self, AddressInfo, Error, ExternalFileAddressRef, ExternalFileRef, ExternalFileSymbolMap,
-    FrameDebugInfo, LibraryInfo, MultiArchDisambiguator,
+    FrameDebugInfo, FunctionInfo, LibraryInfo, MultiArchDisambiguator,
 };
 use yoke::Yoke;
 use yoke_derive::Yokeable;
@@ -99,6 +99,15 @@ impl SymbolMap {
     pub fn iter_symbols(&self) -> Box<dyn Iterator<Item = (u32, Cow<'_, str>)> + '_> {
         self.0.iter_symbols()
     }
+
+    /// Iterate over all functions in this `SymbolMap`, sorted by address.
+    ///
+    /// Unlike [`iter_symbols`](SymbolMap::iter_symbols), this yields the function sizes
+    /// and demangled names. If `with_lines` is true, the line ranges of each function
+    /// are included, if the symbol map has line information (currently only for DWARF).
+    pub fn iter_functions(&self, with_lines: bool) -> Box<dyn Iterator<Item = FunctionInfo> + '_> {
+        self.0.iter_functions(with_lines)
+    }
 }
 
 /// Allows obtaining [`SymbolMap`]s.
@@ -109,9 +118,15 @@ pub struct SymbolManager {
 impl SymbolManager {
     /// Create a new `SymbolManager` with the given config.
     pub fn with_config(config: SymbolManagerConfig) -> Self {
+        let ignore_debug_id_mismatch = config.ignore_debug_id_mismatch;
+        let ignore_pdb_age = config.ignore_pdb_age;
+        let inline_frame_limit = config.inline_frame_limit;
         let helper = Helper::with_config(config);
         let helper_with_symbol_manager = Yoke::attach_to_cart(Box::new(helper), |helper| {
-            let symbol_manager = samply_symbols::SymbolManager::with_helper(helper);
+            let mut symbol_manager = samply_symbols::SymbolManager::with_helper(helper);
+            symbol_manager.set_ignore_debug_id_mismatch(ignore_debug_id_mismatch);
+            symbol_manager.set_ignore_pdb_age(ignore_pdb_age);
+            symbol_manager.set_inline_frame_limit(inline_frame_limit);
             SymbolManagerWrapperTypeErased(Box::new(SymbolManagerWrapper(symbol_manager)))
         });
         Self {
@@ -204,11 +219,20 @@ impl SymbolManager {
         debug_name: &str,
         debug_id: DebugId,
     ) -> Result<SymbolMap, Error> {
-        self.helper_with_symbol_manager
+        let symbol_map = self
+            .helper_with_symbol_manager
             .get()
             .0
             .load_symbol_map(debug_name, debug_id)
-            .await
+            .await?;
+        if symbol_map.debug_id().uuid() != debug_id.uuid() {
+            eprintln!(
+                "Warning: Using symbols for {debug_name} with mismatched debug ID {} (expected {})",
+                symbol_map.debug_id().breakpad(),
+                debug_id.breakpad()
+            );
+        }
+        Ok(symbol_map)
     }
 
     /// Resolve a debug info lookup for which `SymbolMap::lookup_*` returned
@@ -266,6 +290,15 @@ impl SymbolManager {
             .query_json_api(path, request_json)
             .await
     }
+
+    /// The number of bytes which were downloaded from symbol servers, debuginfod
+    /// servers and source file URLs so far. Files which were already cached on
+    /// disk don't count.
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.helper_with_symbol_manager
+            .backing_cart()
+            .downloaded_bytes()
+    }
 }
 
 // Do a trait dance to create a covariant wrapper.
@@ -325,8 +358,13 @@ impl<'h> SymbolManagerWrapper<'h> {
         self.load_symbol_map(library_info).await
     }
 
-    async fn load_symbol_map(&self, info: LibraryInfo) -> Result<SymbolMap, Error> {
-        Ok(SymbolMap(self.0.load_symbol_map(&info).await?))
+    async fn load_symbol_map(&self, mut info: LibraryInfo) -> Result<SymbolMap, Error> {
+        // The architecture of a known library is needed to pick the right member
+        // of a universal binary if the debug ID doesn't match.
+        self.0.helper().fill_in_library_info_details(&mut info);
+        let symbol_map = self.0.load_symbol_map(&info).await?;
+        self.0.helper().store_dyld_cache_symbols(&info, &symbol_map);
+        Ok(SymbolMap(symbol_map))
     }
 }
 
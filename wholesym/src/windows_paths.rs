@@ -0,0 +1,164 @@
+//! Image paths from Windows profiles which can't be opened as they are.
+//!
+//! Images which are loaded into protected processes, and images which are
+//! only known from kernel events, are often reported with their NT path, e.g.
+//! `\Device\HarddiskVolume3\Windows\System32\ntdll.dll`,
+//! `\SystemRoot\System32\drivers\tcpip.sys` or `\??\C:\Windows\explorer.exe`.
+//! These are translated to paths which the regular file APIs accept.
+//!
+//! Packaged (MSIX / UWP) apps are installed in `C:\Program Files\WindowsApps`,
+//! which regular users can't read. For images in there, the symbol lookup
+//! doesn't try to read the files, unless samply runs elevated, and uses the
+//! code ID and debug ID with the symbol server instead.
+
+/// Returns the path under which the image at the NT path `path` can be opened,
+/// or `None` if `path` isn't an NT path.
+pub fn win32_path_for_nt_path(path: &str) -> Option<String> {
+    if let Some(rest) = path
+        .strip_prefix(r"\??\")
+        .or_else(|| path.strip_prefix(r"\\?\"))
+    {
+        // `\??\C:\...` is the NT form of `C:\...`. Leave `\\?\UNC\...` and
+        // other device paths alone, they already work on Windows.
+        if has_drive_letter(rest) {
+            return Some(rest.to_string());
+        }
+        return None;
+    }
+    if let Some(rest) = strip_prefix_ignore_ascii_case(path, r"\SystemRoot\") {
+        let system_root = std::env::var("SystemRoot").unwrap_or_else(|_| r"C:\Windows".to_string());
+        return Some(format!(r"{}\{}", system_root.trim_end_matches('\\'), rest));
+    }
+    if cfg!(windows) {
+        if let Some(rest) = strip_prefix_ignore_ascii_case(path, r"\Device\") {
+            // The GLOBALROOT link gives Win32 paths access to the NT namespace.
+            return Some(format!(r"\\?\GLOBALROOT\Device\{rest}"));
+        }
+    }
+    None
+}
+
+/// Whether the symbol lookup should skip the file at `path`, because it's in
+/// the install directory of packaged apps and this process can't read it.
+pub fn is_unreadable_windows_apps_path(path: &str) -> bool {
+    is_in_windows_apps_dir(path) && !is_elevated()
+}
+
+/// Whether `path` is inside `<drive>:\Program Files\WindowsApps`, the install
+/// directory of packaged apps, in its Win32 or NT form.
+fn is_in_windows_apps_dir(path: &str) -> bool {
+    let path_after_volume = if has_drive_letter(path) {
+        &path[2..]
+    } else if let Some(rest) = path
+        .strip_prefix(r"\??\")
+        .or_else(|| path.strip_prefix(r"\\?\"))
+        .filter(|rest| has_drive_letter(rest))
+    {
+        &rest[2..]
+    } else if let Some(rest) = strip_prefix_ignore_ascii_case(path, r"\Device\") {
+        // \Device\HarddiskVolume3\Program Files\...
+        match rest.find('\\') {
+            Some(volume_end) => &rest[volume_end..],
+            None => return false,
+        }
+    } else {
+        return false;
+    };
+    strip_prefix_ignore_ascii_case(path_after_volume, r"\Program Files\WindowsApps\").is_some()
+}
+
+/// Whether this process runs elevated, so that it can read the files of
+/// packaged apps. Only administrators can list the WindowsApps directory,
+/// so that's what is checked, once per process.
+fn is_elevated() -> bool {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Once;
+
+    static CHECK: Once = Once::new();
+    static IS_ELEVATED: AtomicBool = AtomicBool::new(false);
+    if !cfg!(windows) {
+        return false;
+    }
+    CHECK.call_once(|| {
+        let program_files =
+            std::env::var("ProgramFiles").unwrap_or_else(|_| r"C:\Program Files".to_string());
+        let windows_apps = std::path::Path::new(&program_files).join("WindowsApps");
+        IS_ELEVATED.store(std::fs::read_dir(windows_apps).is_ok(), Ordering::Relaxed);
+    });
+    IS_ELEVATED.load(Ordering::Relaxed)
+}
+
+fn has_drive_letter(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'\\'
+}
+
+fn strip_prefix_ignore_ascii_case<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    if path.len() >= prefix.len()
+        && path.is_char_boundary(prefix.len())
+        && path[..prefix.len()].eq_ignore_ascii_case(prefix)
+    {
+        Some(&path[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nt_paths() {
+        assert_eq!(
+            win32_path_for_nt_path(r"\??\C:\Windows\explorer.exe").as_deref(),
+            Some(r"C:\Windows\explorer.exe")
+        );
+        assert_eq!(
+            win32_path_for_nt_path(r"\\?\D:\app\app.exe").as_deref(),
+            Some(r"D:\app\app.exe")
+        );
+        assert_eq!(
+            win32_path_for_nt_path(r"\\?\UNC\server\share\app.exe"),
+            None
+        );
+        let system_root = std::env::var("SystemRoot").unwrap_or_else(|_| r"C:\Windows".to_string());
+        assert_eq!(
+            win32_path_for_nt_path(r"\SystemRoot\System32\drivers\tcpip.sys"),
+            Some(format!(r"{system_root}\System32\drivers\tcpip.sys"))
+        );
+        assert_eq!(
+            win32_path_for_nt_path(r"\Device\HarddiskVolume3\Windows\System32\ntdll.dll")
+                .as_deref(),
+            if cfg!(windows) {
+                Some(r"\\?\GLOBALROOT\Device\HarddiskVolume3\Windows\System32\ntdll.dll")
+            } else {
+                None
+            }
+        );
+        assert_eq!(win32_path_for_nt_path(r"C:\Windows\explorer.exe"), None);
+        assert_eq!(win32_path_for_nt_path("/usr/lib/libc.so.6"), None);
+    }
+
+    #[test]
+    fn windows_apps_paths() {
+        for path in [
+            r"C:\Program Files\WindowsApps\App_1.0_x64__8wekyb3d8bbwe\App.exe",
+            r"d:\program files\windowsapps\App_1.0_x64__8wekyb3d8bbwe\App.dll",
+            r"\??\C:\Program Files\WindowsApps\App\App.exe",
+            r"\Device\HarddiskVolume3\Program Files\WindowsApps\App\App.exe",
+        ] {
+            assert!(is_in_windows_apps_dir(path), "{path}");
+        }
+        for path in [
+            r"C:\Users\me\WindowsApps\App.exe",
+            r"C:\Users\me\AppData\Local\Microsoft\WindowsApps\python.exe",
+            r"C:\Program Files\WindowsAppsBackup\App.exe",
+            r"C:\Program Files (x86)\WindowsApps\App.exe",
+            r"\Device\HarddiskVolume3",
+            "/usr/lib/WindowsApps/libc.so.6",
+        ] {
+            assert!(!is_in_windows_apps_dir(path), "{path}");
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Path translation between WSL2 and Windows.
+//!
+//! Profiles which are recorded inside WSL2 can refer to binaries on the
+//! Windows file system, as `/mnt/c/...`, or in the WSL file system, as
+//! `/usr/...`. Profiles which are recorded on Windows can refer to binaries
+//! in the WSL file system, as `\\wsl.localhost\<distro>\...`. Binaries and
+//! PDBs built on Windows also refer to their PDB as `C:\...`. These paths
+//! only work on one side, so the symbol lookup also tries the same file as
+//! seen from the side that the analysis runs on.
+
+use std::path::PathBuf;
+
+/// Returns the paths under which `path`, a path from the other side of the
+/// WSL boundary, can be opened on this machine. The list is empty if `path`
+/// is already a path for this side.
+///
+/// On Windows, paths in the WSL file system can be in any of the installed
+/// distributions, so there's one path per distribution.
+pub fn translated_paths(path: &str) -> Vec<PathBuf> {
+    if cfg!(windows) {
+        windows_paths_for_wsl_path(path, installed_distros)
+    } else {
+        wsl_path_for_windows_path(path).into_iter().collect()
+    }
+}
+
+/// `/mnt/c/Users/me/app.exe` becomes `C:\Users\me\app.exe`, and
+/// `/usr/lib/libc.so.6` becomes `\\wsl.localhost\<distro>\usr\lib\libc.so.6`
+/// for each of the distributions returned by `distros`.
+fn windows_paths_for_wsl_path(path: &str, distros: impl FnOnce() -> Vec<String>) -> Vec<PathBuf> {
+    if let Some(mnt_rest) = path.strip_prefix("/mnt/") {
+        return windows_path_for_mnt_path(mnt_rest).into_iter().collect();
+    }
+    let rest = match path.strip_prefix('/') {
+        Some(rest) if !rest.is_empty() => rest.replace('/', "\\"),
+        _ => return Vec::new(),
+    };
+    distros()
+        .into_iter()
+        .map(|distro| PathBuf::from(format!(r"\\wsl.localhost\{distro}\{rest}")))
+        .collect()
+}
+
+/// `c/Users/me/app.exe`, i.e. a path under `/mnt/`, becomes `C:\Users\me\app.exe`.
+fn windows_path_for_mnt_path(mnt_rest: &str) -> Option<PathBuf> {
+    let (drive, rest) = split_drive_letter(mnt_rest)?;
+    let rest = match rest.strip_prefix('/') {
+        Some(rest) => rest,
+        None if rest.is_empty() => rest,
+        None => return None,
+    };
+    Some(PathBuf::from(format!(
+        "{}:\\{}",
+        drive.to_ascii_uppercase(),
+        rest.replace('/', "\\")
+    )))
+}
+
+/// The names of the installed WSL distributions, which are the directories
+/// of the `\\wsl.localhost\` share. Older Windows versions only have `\\wsl$\`.
+fn installed_distros() -> Vec<String> {
+    let entries =
+        match std::fs::read_dir(r"\\wsl.localhost\").or_else(|_| std::fs::read_dir(r"\\wsl$\")) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect()
+}
+
+/// `C:\Users\me\app.exe` becomes `/mnt/c/Users/me/app.exe`, and
+/// `\\wsl.localhost\Ubuntu\usr\lib\libc.so.6` and `\\wsl$\Ubuntu\usr\lib\libc.so.6`
+/// become `/usr/lib/libc.so.6`.
+fn wsl_path_for_windows_path(path: &str) -> Option<PathBuf> {
+    let unc_rest = path
+        .strip_prefix(r"\\wsl.localhost\")
+        .or_else(|| path.strip_prefix(r"\\wsl$\"));
+    if let Some(unc_rest) = unc_rest {
+        // Skip the distribution name.
+        let (_distro, rest) = unc_rest.split_once('\\')?;
+        return Some(PathBuf::from(format!("/{}", rest.replace('\\', "/"))));
+    }
+
+    let (drive, rest) = split_drive_letter(path)?;
+    let rest = rest.strip_prefix(':')?;
+    let rest = rest.strip_prefix('\\').or_else(|| rest.strip_prefix('/'))?;
+    Some(PathBuf::from(format!(
+        "/mnt/{}/{}",
+        drive.to_ascii_lowercase(),
+        rest.replace('\\', "/")
+    )))
+}
+
+fn split_drive_letter(path: &str) -> Option<(char, &str)> {
+    let mut chars = path.chars();
+    let drive = chars.next().filter(char::is_ascii_alphabetic)?;
+    Some((drive, chars.as_str()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn distros() -> Vec<String> {
+        vec!["Ubuntu".to_string(), "Debian".to_string()]
+    }
+
+    fn no_distros() -> Vec<String> {
+        panic!("distros shouldn't be listed for this path")
+    }
+
+    #[test]
+    fn windows_paths_for_wsl_paths() {
+        assert_eq!(
+            windows_paths_for_wsl_path("/mnt/c/Users/me/app.exe", no_distros),
+            vec![PathBuf::from(r"C:\Users\me\app.exe")]
+        );
+        assert_eq!(
+            windows_paths_for_wsl_path("/mnt/d", no_distros),
+            vec![PathBuf::from(r"D:\")]
+        );
+        assert_eq!(
+            windows_paths_for_wsl_path("/mnt/wsl/file", no_distros),
+            Vec::<PathBuf>::new()
+        );
+        assert_eq!(
+            windows_paths_for_wsl_path("/usr/lib/libc.so.6", distros),
+            vec![
+                PathBuf::from(r"\\wsl.localhost\Ubuntu\usr\lib\libc.so.6"),
+                PathBuf::from(r"\\wsl.localhost\Debian\usr\lib\libc.so.6"),
+            ]
+        );
+        assert_eq!(
+            windows_paths_for_wsl_path(r"C:\Windows\System32\ntdll.dll", no_distros),
+            Vec::<PathBuf>::new()
+        );
+        assert_eq!(
+            windows_paths_for_wsl_path("/", no_distros),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    #[test]
+    fn wsl_paths_for_windows_paths() {
+        assert_eq!(
+            wsl_path_for_windows_path(r"C:\Users\me\app.pdb"),
+            Some(PathBuf::from("/mnt/c/Users/me/app.pdb"))
+        );
+        assert_eq!(
+            wsl_path_for_windows_path("d:/build/app.pdb"),
+            Some(PathBuf::from("/mnt/d/build/app.pdb"))
+        );
+        assert_eq!(
+            wsl_path_for_windows_path(r"\\wsl.localhost\Ubuntu\usr\lib\libc.so.6"),
+            Some(PathBuf::from("/usr/lib/libc.so.6"))
+        );
+        assert_eq!(
+            wsl_path_for_windows_path(r"\\wsl$\Ubuntu\home\me\app"),
+            Some(PathBuf::from("/home/me/app"))
+        );
+        assert_eq!(wsl_path_for_windows_path("/usr/lib/libc.so.6"), None);
+        assert_eq!(wsl_path_for_windows_path(r"\\server\share\app.pdb"), None);
+        assert_eq!(wsl_path_for_windows_path("C:app.pdb"), None);
+    }
+}